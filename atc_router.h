@@ -0,0 +1,1774 @@
+/* Generated by cbindgen.  Do NOT edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Chains with fewer operands are left alone.
+ */
+#define COMPACT_MIN_OPERANDS 8
+
+/**
+ * Contexts [`fuzz`] executes each expression against.
+ */
+#define FUZZ_CONTEXTS 16
+
+/**
+ * The newest format version this release reads and the one it writes.
+ */
+#define RouterDocument_VERSION 1
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ERR_BUF_MAX_LEN 4096
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Version of the C API, returned by [`atc_router_api_version`]. Bumped
+ * whenever a function is removed or changes its signature or the layout
+ * of its arguments, so hosts written against another version can refuse
+ * to use the library instead of crashing.
+ */
+#define ATC_ROUTER_API_VERSION 1
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Kinds of error reported by [`atc_router_last_error_kind`], one per
+ * [`Error`] variant. New kinds are only ever appended.
+ */
+#define ATC_ROUTER_ERROR_NONE 0
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_INVALID_ARGUMENT 1
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_PARSE 2
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_VALIDATION 3
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_DUPLICATE_UUID 4
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_LIMIT_EXCEEDED 5
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_PRIORITY_OUT_OF_BAND 6
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_PRIORITY_BAND_OVERLAP 7
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_INVALID_ROUTE 8
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_INVALID_SNAPSHOT 9
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_ERROR_QUOTA_EXCEEDED 10
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_EXPRESSION_VALIDATE_OK 0
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_EXPRESSION_VALIDATE_FAILED 1
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL 2
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Returned by [`router_execute_deadline`] when no matcher matched.
+ */
+#define ATC_ROUTER_EXECUTE_NO_MATCH 0
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Returned by [`router_execute_deadline`] when a matcher matched.
+ */
+#define ATC_ROUTER_EXECUTE_MATCH 1
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Returned by [`router_execute_deadline`] when the time ran out before
+ * every matcher was evaluated.
+ */
+#define ATC_ROUTER_EXECUTE_TIMED_OUT 2
+#endif
+
+/**
+ * Controls how the `lower()` transformation function lower-cases values.
+ */
+typedef enum LowerPolicy {
+  /**
+   * Full Unicode lower-casing, as done by [`str::to_lowercase`].
+   */
+  LowerPolicy_Unicode,
+  /**
+   * Only ASCII letters `A-Z` are lower-cased, every other character is kept
+   * as is. This matches the behavior of Lua's `string.lower`.
+   */
+  LowerPolicy_Ascii,
+  /**
+   * Full Unicode case folding, which also maps `ß` to `ss` and final
+   * sigmas to `σ`. `lower()` then makes comparisons case-insensitive: the
+   * string literals compared with a `lower()`'d field are folded as well,
+   * and its regexes and globs match ignoring case.
+   */
+  LowerPolicy_Fold,
+} LowerPolicy;
+
+/**
+ * The type of a schema field or [`Value`].
+ *
+ * The discriminants are part of the C ABI (see [`Type::tag`]): they are
+ * never changed or reused, new types get the next free one.
+ */
+typedef enum Type {
+  Type_String = 0,
+  Type_IpCidr = 1,
+  Type_IpAddr = 2,
+  Type_Int = 3,
+  Type_Regex = 4,
+  Type_Float = 5,
+  Type_List = 6,
+  Type_IntRange = 7,
+  Type_CidrList = 8,
+  /**
+   * Strings by string keys, such as the headers of a request by name.
+   * Read one key at a time with `field["key"]`, which is a `String`,
+   * and filled with [`Context::add_map_value`](crate::context::Context::add_map_value).
+   */
+  Type_Map = 9,
+} Type;
+
+/**
+ * The values a [`Router`](crate::router::Router) is executed against, and
+ * the result of the execution.
+ *
+ * A context is used by one thread at a time, but can be sent to another
+ * one: keep one per thread, or per worker, and [`Context::reset`] it
+ * between requests instead of building a new one each time.
+ */
+typedef struct Context Context;
+
+/**
+ * Contexts of one schema waiting to be reused, see the
+ * [module documentation](crate::context_pool).
+ */
+typedef struct ContextPool ContextPool;
+
+typedef struct Option_ContextProvider Option_ContextProvider;
+
+/**
+ * A set of matchers sharing one [`Schema`].
+ *
+ * # Evaluation order
+ *
+ * Matchers are evaluated by descending [`Priority`], comparing `major`
+ * first and `minor` second. Matchers with the same priority are evaluated
+ * by descending UUID, compared as 128 bit big-endian
+ * integers (which is also the order of their lowercase hex form). The order
+ * only depends on the `(priority, uuid)` pairs, never on insertion order,
+ * and every API returning more than one matcher (such as
+ * [`Router::matchers`]) uses it.
+ *
+ * A router created with another [`TieBreak`] first orders matchers with
+ * the same priority by that policy, and only falls back to the UUID when
+ * the policy ranks them equally.
+ *
+ * The order is part of the API and kept across releases, so hosts can
+ * check which of several overlapping matchers wins with
+ * [`Router::evaluation_order`].
+ *
+ * # Thread safety
+ *
+ * A router is `Send + Sync`: once built, any number of threads can
+ * [`execute`](Router::execute) it at the same time through a shared
+ * reference, each with its own [`Context`]. The state updated while
+ * executing (hit counters, quarantine, trace sampling and the lazily built
+ * regex index) only uses atomics, locks and [`OnceLock`]. Changing the
+ * matchers takes `&mut self`; to update a router other threads execute,
+ * build a new one and swap it in with a
+ * [`SharedRouter`](crate::shared_router::SharedRouter).
+ */
+typedef struct Router Router;
+
+/**
+ * The fields expressions may reference and their types.
+ *
+ * A [`Router`](crate::router::Router) borrows or shares its schema, see
+ * [`SchemaRef`], so fields can only be removed or retyped once every
+ * router using it is gone. Check the change against those routers first
+ * with
+ * [`Router::check_remove_field`](crate::router::Router::check_remove_field)
+ * and [`Router::check_replace_field_type`](crate::router::Router::check_replace_field_type).
+ */
+typedef struct Schema Schema;
+
+/**
+ * Holds the current [`Router`], see the
+ * [module documentation](crate::shared_router).
+ */
+typedef struct SharedRouter SharedRouter;
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * A context value passed in by the host.
+ *
+ * The implicit tags, in declaration order, are part of the C ABI and
+ * independent from [`Type::tag`] (there is no regex context value). New
+ * variants are only ever appended.
+ *
+ * [`Type::tag`]: crate::ast::Type::tag
+ */
+typedef enum CValue_Tag {
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  CValue_Str,
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  CValue_IpCidr,
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  CValue_IpAddr,
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  CValue_Int,
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  CValue_Float,
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+  /**
+   * Like `Str`, replacing invalid UTF-8 sequences with `U+FFFD` instead
+   * of failing. Meant for values the host does not control, such as
+   * header values.
+   */
+  CValue_StrLossy,
+#endif
+} CValue_Tag;
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+typedef struct CValue_Str_Body {
+  const uint8_t *_0;
+  size_t _1;
+} CValue_Str_Body;
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+typedef struct CValue_StrLossy_Body {
+  const uint8_t *_0;
+  size_t _1;
+} CValue_StrLossy_Body;
+#endif
+
+typedef struct CValue {
+  CValue_Tag tag;
+  union {
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    CValue_Str_Body str;
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    struct {
+      const uint8_t *ip_cidr;
+    };
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    struct {
+      const uint8_t *ip_addr;
+    };
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    struct {
+      int64_t int_;
+    };
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    struct {
+      double float_;
+    };
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+    CValue_StrLossy_Body str_lossy;
+#endif
+  };
+} CValue;
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+typedef struct BinaryOperatorFlags {
+  uint64_t bits;
+} BinaryOperatorFlags;
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_EQUALS (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 0) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_NOT_EQUALS (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 1) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_REGEX (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 2) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_PREFIX (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 3) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_POSTFIX (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 4) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_GREATER (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 5) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_GREATER_OR_EQUAL (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 6) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_LESS (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 7) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_LESS_OR_EQUAL (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 8) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_IN (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 9) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_NOT_IN (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 10) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_CONTAINS (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 11) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_GLOB (BinaryOperatorFlags){ .bits = (uint64_t)(1 << 12) }
+#endif
+#if defined(DEFINE_ATC_ROUTER_FFI)
+#define BinaryOperatorFlags_UNUSED (BinaryOperatorFlags){ .bits = (uint64_t)~(((((((((((((BinaryOperatorFlags_EQUALS).bits | (BinaryOperatorFlags_NOT_EQUALS).bits) | (BinaryOperatorFlags_REGEX).bits) | (BinaryOperatorFlags_PREFIX).bits) | (BinaryOperatorFlags_POSTFIX).bits) | (BinaryOperatorFlags_GREATER).bits) | (BinaryOperatorFlags_GREATER_OR_EQUAL).bits) | (BinaryOperatorFlags_LESS).bits) | (BinaryOperatorFlags_LESS_OR_EQUAL).bits) | (BinaryOperatorFlags_IN).bits) | (BinaryOperatorFlags_NOT_IN).bits) | (BinaryOperatorFlags_CONTAINS).bits) | (BinaryOperatorFlags_GLOB).bits) }
+#endif
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Returns the kind of the last error written to an error buffer on the
+ * calling thread, one of the `ATC_ROUTER_ERROR_*` constants, or
+ * `ATC_ROUTER_ERROR_NONE` if there was none yet.
+ *
+ * Lets the host branch on the kind of error without parsing the message.
+ */
+uint32_t atc_router_last_error_kind(void);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Gets where in the expression the last parse error written to an error
+ * buffer on the calling thread is, so editors can underline the offending
+ * part of the expression.
+ *
+ * # Arguments
+ *
+ * - `start`, `end`: byte offsets of the offending input, equal when the
+ *   parser stopped at a single position.
+ * - `line`, `column`: 1-based line and column of `start`. Columns count
+ *   characters, not bytes.
+ * - `end_line`, `end_column`: the same for `end`.
+ *
+ * # Returns
+ *
+ * Returns `true` and stores the location in every argument that is not
+ * `NULL` if the last error was a parse error, otherwise `false`, leaving
+ * the arguments untouched.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - Every argument must be `NULL` or valid to write for
+ *   `size_of::<usize>()` bytes, and it must be properly aligned.
+ */
+bool atc_router_last_error_location(size_t *start,
+                                    size_t *end,
+                                    size_t *line,
+                                    size_t *column,
+                                    size_t *end_line,
+                                    size_t *end_column);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Returns [`ATC_ROUTER_API_VERSION`] as compiled into the library.
+ *
+ * Hosts loading the library dynamically should call this first and
+ * compare it with the `ATC_ROUTER_API_VERSION` of the header they were
+ * built against, before calling any constructor such as
+ * [`router_new`](router::router_new).
+ */
+uint32_t atc_router_api_version(void);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Allocate a new context object associated with the schema.
+ *
+ * # Errors
+ *
+ * This function never returns an error, however, it can panic if memory allocation failed.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ */
+struct Context *context_new(const struct Schema *schema);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Deallocate the context object.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `context` must be a valid pointer returned by [`context_new`].
+ */
+void context_free(struct Context *context);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Allocate a new pool of contexts associated with the schema, see
+ * [`ContextPool`].
+ *
+ * Take a context with [`context_pool_get`] for each request and give it
+ * back with [`context_pool_put`] once its result was read, instead of
+ * allocating one with [`context_new`] and freeing it with
+ * [`context_free`]: pooled contexts are handed out again, keeping the
+ * value lists of their previous requests.
+ *
+ * # Arguments
+ *
+ * - `schema`: a pointer to the [`Schema`] object returned by [`schema_new`].
+ * - `max_idle`: how many contexts the pool keeps, contexts given back past
+ *   that are freed.
+ *
+ * # Errors
+ *
+ * This function never returns an error, however, it can panic if memory allocation failed.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ */
+struct ContextPool *context_pool_new(const struct Schema *schema, size_t max_idle);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Deallocate the pool and the contexts it holds.
+ *
+ * Contexts taken from the pool and not given back are left alone, free
+ * them with [`context_free`].
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `pool` must be a valid pointer returned by [`context_pool_new`].
+ * - No other thread may be using `pool`.
+ */
+void context_pool_free(struct ContextPool *pool);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Take a context from the pool, allocating a new one if it has none.
+ *
+ * The context holds no values, no result and no provider. Give it back
+ * with [`context_pool_put`], or free it with [`context_free`].
+ *
+ * # Errors
+ *
+ * This function never returns an error, however, it can panic if memory allocation failed.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `pool` must be a valid pointer returned by [`context_pool_new`].
+ */
+struct Context *context_pool_get(const struct ContextPool *pool);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Give a context back to the pool, which resets it.
+ *
+ * # Errors
+ *
+ * Returns `false`, leaving `context` to the caller, if `context` was
+ * created with another schema than `pool`.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `pool` must be a valid pointer returned by [`context_pool_new`].
+ * - `context` must be a valid pointer returned by [`context_pool_get`] or
+ *   [`context_new`].
+ * - If this function returns `true`, `context` must not be used, nor freed
+ *   by [`context_free`], afterwards.
+ */
+bool context_pool_put(const struct ContextPool *pool, struct Context *context);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a value associated with a field to the context.
+ * This is useful when you want to match a value against a field in the schema.
+ *
+ * # Arguments
+ *
+ * - `context`: a pointer to the [`Context`] object.
+ * - `field`: the C-style string representing the field name.
+ * - `value`: the value to be added to the context.
+ * - `errbuf`: a buffer to store the error message.
+ * - `errbuf_len`: a pointer to the length of the error message buffer.
+ *
+ * # Returns
+ *
+ * Returns `true` if the value was added successfully, otherwise `false`,
+ * and the error message will be stored in the `errbuf`,
+ * and the length of the error message will be stored in `errbuf_len`.
+ *
+ * # Errors
+ *
+ * This function will return `false` if the value could not be added to the context,
+ * such as when `field` or a [`CValue::Str`] value is not a valid UTF-8 string.
+ * Use [`CValue::StrLossy`] for values that may not be valid UTF-8.
+ *
+ * # Panics
+ *
+ * This function will panic if the provided value does not match the schema.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * * `context` must be a valid pointer returned by [`context_new`].
+ * * `field` must be a valid pointer to a C-style string,
+ *   must be properply aligned, and must not have '\0' in the middle.
+ * * `value` must be a valid pointer to a [`CValue`].
+ * * `errbuf` must be valid to read and write for `errbuf_len * size_of::<u8>()` bytes,
+ *   and it must be properly aligned.
+ * * `errbuf_len` must be vlaid to read and write for `size_of::<usize>()` bytes,
+ *   and it must be properly aligned.
+ */
+bool context_add_value(struct Context *context,
+                       const char *field,
+                       const struct CValue *value,
+                       uint8_t *errbuf,
+                       size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a value to the context like [`context_add_value`], borrowing the
+ * bytes of a [`CValue::Str`] instead of copying them.
+ *
+ * A [`CValue::StrLossy`] is borrowed too when it is valid UTF-8, and
+ * copied with the invalid sequences replaced otherwise. Other values are
+ * always copied.
+ *
+ * # Returns
+ *
+ * Returns `true` if the value was added, otherwise `false`, the error
+ * message is stored in `errbuf` and its length in `errbuf_len` like
+ * [`context_add_value`].
+ *
+ * # Errors
+ *
+ * Fails for the same reasons as [`context_add_value`].
+ *
+ * # Panics
+ *
+ * This function will panic if the provided value does not match the schema.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * * `context`, `field`, `value`, `errbuf` and `errbuf_len` must satisfy
+ *   the constraints of [`context_add_value`].
+ * * The bytes a string `value` points to must stay valid and unchanged
+ *   until the context is reset with [`context_reset`], given back with
+ *   [`context_pool_put`] or freed with [`context_free`].
+ */
+bool context_add_value_ref(struct Context *context,
+                           const char *field,
+                           const struct CValue *value,
+                           uint8_t *errbuf,
+                           size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add `len` values associated with a field to the context at once, like
+ * calling [`context_add_value`] for each of them in order, which saves
+ * crossing the FFI boundary for every value of multi-valued fields such
+ * as `http.headers.x_forwarded_for`.
+ *
+ * # Returns
+ *
+ * Returns `true` if every value was added, otherwise `false`, the error
+ * message is stored in `errbuf` and its length in `errbuf_len` like
+ * [`context_add_value`].
+ *
+ * # Errors
+ *
+ * Fails for the same reasons as [`context_add_value`]. Every value is
+ * checked before any is added, so on failure none of them is.
+ *
+ * # Panics
+ *
+ * This function will panic if the provided values do not match the schema.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * * `context`, `field`, `errbuf` and `errbuf_len` must satisfy the
+ *   constraints of [`context_add_value`].
+ * * `values` must be valid to read for `len * size_of::<CValue>()` bytes,
+ *   and it must be properly aligned. It may be `NULL` if `len` is `0`.
+ */
+bool context_add_values(struct Context *context,
+                        const char *field,
+                        const struct CValue *values,
+                        size_t len,
+                        uint8_t *errbuf,
+                        size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a value to the entry `key` of a [`Type::Map`] field, read by
+ * `field["key"]` in expressions, such as a header by its name.
+ *
+ * # Returns
+ *
+ * Returns `true` if the value was added, otherwise `false`, the error
+ * message is stored in `errbuf` and its length in `errbuf_len` like
+ * [`context_add_value`].
+ *
+ * # Errors
+ *
+ * This function will return `false` if `field` or `key` is not a valid
+ * UTF-8 string, or `value` is not a valid [`CValue::Str`] or a
+ * [`CValue::StrLossy`].
+ *
+ * # Panics
+ *
+ * This function will panic if `field` is not a map field of the schema.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * * `context`, `field`, `value`, `errbuf` and `errbuf_len` must satisfy
+ *   the constraints of [`context_add_value`].
+ * * `key` must be a valid pointer to a C-style string, like `field`.
+ *
+ * [`Type::Map`]: crate::ast::Type::Map
+ */
+bool context_add_map_value(struct Context *context,
+                           const char *field,
+                           const char *key,
+                           const struct CValue *value,
+                           uint8_t *errbuf,
+                           size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Set a callback supplying the value of fields when they are first read
+ * by a matcher, instead of adding every value upfront with
+ * [`context_add_value`]. Passing a `NULL` provider removes the current one.
+ *
+ * The provider is called at most once per field until [`context_reset`],
+ * and never for fields that already have values. Values that are not
+ * valid UTF-8 (see [`CValue::Str`]) or do not match the type of the field
+ * in the schema are treated as missing.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `context` must be a valid pointer returned by [`context_new`].
+ * - `provider` must be safe to call with `data` for as long as it is set,
+ *   from any thread the context is later used on.
+ * - Pointers the provider stores in `value` must stay valid until the call
+ *   that ran it, such as [`router_execute`](crate::ffi::router::router_execute),
+ *   returns.
+ */
+void context_set_provider(struct Context *context,
+                          struct Option_ContextProvider provider,
+                          void *data);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Reset the context so that it can be reused.
+ * This is useful when you want to reuse the same context for multiple matches.
+ * This will clear all the values that were added to the context,
+ * but keep the memory allocated for the context.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `context` must be a valid pointer returned by [`context_new`].
+ */
+void context_reset(struct Context *context);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the result of the context.
+ *
+ * # Arguments
+ *
+ * - `context`: a pointer to the [`Context`] object.
+ * - `uuid_hex`: If not `NULL`, the UUID of the matched matcher will be stored.
+ * - `matched_field`: If not `NULL`, the field name (C-style string) of the matched value will be stored.
+ * - `matched_value`: If the `matched_field` is not `NULL`, the value of the matched field will be stored.
+ * - `matched_value_len`: If the `matched_field` is not `NULL`, the length of the value of the matched field will be stored.
+ * - `capture_names`: A pointer to an array of pointers to the capture names, each element is a non-C-style string pointer.
+ * - `capture_names_len`: A pointer to an array of the length of each capture name.
+ * - `capture_values`: A pointer to an array of pointers to the capture values, each element is a non-C-style string pointer.
+ * - `capture_values_len`: A pointer to an array of the length of each capture value.
+ *
+ * # Returns
+ *
+ * Returns the number of captures that are stored in the context.
+ *
+ * # Lifetimes
+ *
+ * The string pointers stored in `matched_value`, `capture_names`, and `capture_values`
+ * might be invalidated if any of the following operations are happened:
+ *
+ * - The `context` was deallocated.
+ * - The `context` was reset by [`context_reset`].
+ *
+ * # Panics
+ *
+ * This function will panic if the `matched_field` is not a valid UTF-8 string.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   must be passed to [`router_execute`] before calling this function,
+ *   and must not be reset by [`context_reset`] before calling this function.
+ * - If `uuid_hex` is not `NULL`, `uuid_hex` must be valid to read and write for
+ *   `16 * size_of::<u8>()` bytes, and it must be properly aligned.
+ * - If `matched_field` is not `NULL`,
+ *   `matched_field` must be a vlaid pointer to a C-style string,
+ *   must be properly aligned, and must not have '\0' in the middle.
+ * - If `matched_value` is not `NULL`,
+ *   `matched_value` must be valid to read and write for
+ *   `mem::size_of::<*const u8>()` bytes, and it must be properly aligned.
+ * - If `matched_value` is not `NULL`, `matched_value_len` must be valid to read and write for
+ *   `size_of::<usize>()` bytes, and it must be properly aligned.
+ * - If `uuid_hex` is not `NULL`, `capture_names` must be valid to read and write for
+ *   `<captures> * size_of::<*const u8>()` bytes, and it must be properly aligned.
+ * - If `uuid_hex` is not `NULL`, `capture_names_len` must be valid to read and write for
+ *   `<captures> * size_of::<usize>()` bytes, and it must be properly aligned.
+ * - If `uuid_hex` is not `NULL`, `capture_values` must be valid to read and write for
+ *   `<captures> * size_of::<*const u8>()` bytes, and it must be properly aligned.
+ * - If `uuid_hex` is not `NULL`, `capture_values_len` must be valid to read and write for
+ *   `<captures> * size_of::<usize>()` bytes, and it must be properly aligned.
+ *
+ * Note: You should get the `<captures>` by calling this function and set every pointer
+ * except the `context` to `NULL` to get the number of captures.
+ */
+ptrdiff_t context_get_result(const struct Context *context,
+                             uint8_t *uuid_hex,
+                             const char *matched_field,
+                             const uint8_t **matched_value,
+                             size_t *matched_value_len,
+                             const uint8_t **capture_names,
+                             size_t *capture_names_len,
+                             const uint8_t **capture_values,
+                             size_t *capture_values_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the predicates that held for the matched matcher, see
+ * [`Match::evidence`](crate::context::Match::evidence). Unlike the single
+ * value per field of [`context_get_result`], this lists every predicate on
+ * a field.
+ *
+ * # Arguments
+ *
+ * - `context`: a pointer to the [`Context`] object.
+ * - `fields`: a pointer to an array of pointers to the field names (NOT
+ *   C-style strings) of the predicates. If `NULL`, only the number of
+ *   predicates is returned.
+ * - `fields_len`: a pointer to an array of the length of each field name.
+ *   Its first element must be the number of elements every array can hold.
+ * - `ops`: a pointer to an array of pointers to the operators, such as
+ *   `==` or `not in` (NOT C-style strings).
+ * - `ops_len`: a pointer to an array of the length of each operator.
+ * - `values`: a pointer to an array of pointers to the values that
+ *   satisfied the predicates. Only `String` values are returned, the
+ *   pointer is `NULL` for other types.
+ * - `values_len`: a pointer to an array of the length of each value.
+ *
+ * # Returns
+ *
+ * Returns the number of predicates, or `-1` if the context has no match.
+ *
+ * # Lifetimes
+ *
+ * The string pointers stored in `fields` and `values` are invalidated
+ * like the ones of [`context_get_result`], the ones in `ops` are static.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `context` must be a valid pointer returned by [`context_new`].
+ * - If `fields` is not `NULL`, `fields`, `fields_len`, `ops`, `ops_len`,
+ *   `values` and `values_len` must each be valid to read and write for
+ *   `*fields_len` elements, and be properly aligned.
+ */
+ptrdiff_t context_get_evidence(const struct Context *context,
+                               const uint8_t **fields,
+                               size_t *fields_len,
+                               const uint8_t **ops,
+                               size_t *ops_len,
+                               const uint8_t **values,
+                               size_t *values_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Validates an ATC expression against a schema and get its elements.
+ *
+ * # Arguments
+ *
+ * - `atc`: a C-style string representing the ATC expression.
+ * - `schema`: a valid pointer to a [`Schema`] object, as returned by [`schema_new`].
+ * - `fields_buf`: a buffer for storing the fields used in the expression.
+ * - `fields_buf_len`: a pointer to the length of `fields_buf`.
+ * - `fields_total`: a pointer for storing the total number of unique fields used in the expression.
+ * - `operators`: a pointer for storing the bitflags representing used operators.
+ * - `errbuf`: a buffer to store any error messages.
+ * - `errbuf_len`: a pointer to the length of the error message buffer.
+ *
+ * # Returns
+ *
+ * An integer indicating the validation result:
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_OK` (0): Validation succeeded.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED` (1): Validation failed; `errbuf` and `errbuf_len` will be updated with an error message.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL` (2): The provided `fields_buf` is too small.
+ *
+ * If `fields_buf_len` indicates that `fields_buf` is sufficient, this function writes the used fields to `fields_buf`, each field terminated by `\0`.
+ * It stores the total number of fields in `fields_total`.
+ *
+ * If `fields_buf_len` indicates that `fields_buf` is insufficient, it returns `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL`.
+ *
+ * It writes the used operators as bitflags to `operators`.
+ * Bitflags are defined by `BinaryOperatorFlags` and must exclude bits from `BinaryOperatorFlags::UNUSED`.
+ *
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints results in undefined behavior:
+ *
+ * - `atc` must be a valid pointer to a C-style string, properly aligned, and must not contain an internal `\0`.
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - `fields_buf`, must be valid for writing `fields_buf_len * size_of::<u8>()` bytes and properly aligned.
+ * - `fields_buf_len` must be a valid pointer to write `size_of::<usize>()` bytes and properly aligned.
+ * - `fields_total` must be a valid pointer to write `size_of::<usize>()` bytes and properly aligned.
+ * - `operators` must be a valid pointer to write `size_of::<u64>()` bytes and properly aligned.
+ * - `errbuf` must be valid for reading and writing `errbuf_len * size_of::<u8>()` bytes and properly aligned.
+ * - `errbuf_len` must be a valid pointer for reading and writing `size_of::<usize>()` bytes and properly aligned.
+ */
+int64_t expression_validate(const uint8_t *atc,
+                            const struct Schema *schema,
+                            uint8_t *fields_buf,
+                            size_t *fields_buf_len,
+                            size_t *fields_total,
+                            uint64_t *operators,
+                            uint8_t *errbuf,
+                            size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Validates `n` ATC expressions against a schema at once, and gets the
+ * elements of the valid ones, like calling [`expression_validate`] on
+ * each of them.
+ *
+ * # Arguments
+ *
+ * - `atcs`: an array of `n` C-style strings representing the ATC expressions.
+ * - `n`: the number of expressions.
+ * - `schema`: a valid pointer to a [`Schema`] object, as returned by [`schema_new`].
+ * - `statuses`: an array of `n` integers for storing the validation result of each expression,
+ *   `ATC_ROUTER_EXPRESSION_VALIDATE_OK` or `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED`.
+ * - `fields_buf`: a buffer for storing the fields used in the valid expressions.
+ * - `fields_buf_len`: a pointer to the length of `fields_buf`.
+ * - `fields_total`: a pointer for storing the total number of unique fields used in the valid expressions.
+ * - `operators`: a pointer for storing the bitflags representing the operators used in the valid expressions.
+ * - `errbuf`: a buffer to store the error message of the first invalid expression.
+ * - `errbuf_len`: a pointer to the length of the error message buffer.
+ *
+ * # Returns
+ *
+ * An integer indicating the validation result:
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_OK` (0): Every expression is valid.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED` (1): At least one expression is invalid, see `statuses`;
+ *   `errbuf` and `errbuf_len` will be updated with the error message of the first one.
+ *   Call [`expression_validate`] on the others to get their message.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL` (2): The provided `fields_buf` is too small;
+ *   `fields_buf_len` will be updated with the length needed. It takes precedence over
+ *   `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED`, `statuses` are filled in either way.
+ *
+ * Otherwise, this function writes the used fields to `fields_buf`, each one once and terminated
+ * by `\0`, and stores the number of bytes written in `fields_buf_len`.
+ * It stores the total number of fields in `fields_total`.
+ *
+ * It writes the used operators as bitflags to `operators`, see [`expression_validate`].
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints results in undefined behavior:
+ *
+ * - If `n` is not `0`, `atcs` must be valid to read for `n * size_of::<*const u8>()` bytes and properly aligned,
+ *   and each of its elements must satisfy the constraints of `atc` in [`expression_validate`].
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - If `n` is not `0`, `statuses` must be valid for writing `n * size_of::<i64>()` bytes and properly aligned.
+ * - `fields_buf`, `fields_buf_len`, `fields_total`, `operators`, `errbuf` and `errbuf_len`
+ *   must satisfy the same constraints as in [`expression_validate`].
+ */
+int64_t expression_validate_batch(const uint8_t *const *atcs,
+                                  size_t n,
+                                  const struct Schema *schema,
+                                  int64_t *statuses,
+                                  uint8_t *fields_buf,
+                                  size_t *fields_buf_len,
+                                  size_t *fields_total,
+                                  uint64_t *operators,
+                                  uint8_t *errbuf,
+                                  size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Lints an ATC expression valid against a schema, reporting parts of it
+ * that never or always hold, repeated predicates and regexes that are
+ * slower than they need to be, see [`lint`].
+ *
+ * # Arguments
+ *
+ * - `atc`: a C-style string representing the ATC expression.
+ * - `schema`: a valid pointer to a [`Schema`] object, as returned by [`schema_new`].
+ * - `warnings_buf`: a buffer for storing the warnings. Each one is written as its kind, such as
+ *   `always_false`, followed by `\0`, then as the part of the expression it is about and what is
+ *   wrong with it, followed by `\0`.
+ * - `warnings_buf_len`: a pointer to the length of `warnings_buf`.
+ * - `warnings_total`: a pointer for storing the number of warnings.
+ * - `errbuf`: a buffer to store any error messages.
+ * - `errbuf_len`: a pointer to the length of the error message buffer.
+ *
+ * # Returns
+ *
+ * An integer indicating the result:
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_OK` (0): The expression is valid, its warnings were written
+ *   to `warnings_buf` and the number of bytes written stored in `warnings_buf_len`. There may
+ *   be none.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED` (1): The expression is invalid; `errbuf` and
+ *   `errbuf_len` will be updated with an error message.
+ * - `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL` (2): The provided `warnings_buf` is too
+ *   small; `warnings_buf_len` will be updated with the length needed.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints results in undefined behavior:
+ *
+ * - `atc` must be a valid pointer to a C-style string, properly aligned, and must not contain an internal `\0`.
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - `warnings_buf` must be valid for writing `warnings_buf_len * size_of::<u8>()` bytes and properly aligned.
+ * - `warnings_buf_len` and `warnings_total` must be valid pointers to write `size_of::<usize>()` bytes and properly aligned.
+ * - `errbuf` and `errbuf_len` must satisfy the same constraints as in [`expression_validate`].
+ */
+int64_t expression_lint(const uint8_t *atc,
+                        const struct Schema *schema,
+                        uint8_t *warnings_buf,
+                        size_t *warnings_buf_len,
+                        size_t *warnings_total,
+                        uint8_t *errbuf,
+                        size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Create a new router object associated with the schema.
+ *
+ * # Arguments
+ *
+ * - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ */
+struct Router *router_new(const struct Schema *schema);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Deallocate the router object.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ */
+void router_free(struct Router *router);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Limit the number of matchers the router accepts.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `max_matchers`: the maximum number of matchers, `0` means unlimited.
+ *
+ * Once the limit is reached, [`router_add_matcher`] returns `false` with
+ * an error message in `errbuf` until some matchers are removed.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ */
+void router_set_max_matchers(struct Router *router, size_t max_matchers);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a new matcher to the router.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `priority`: the priority of the matcher, higher value means higher priority,
+ *   and the matcher with the highest priority will be executed first.
+ * - `uuid`: the C-style string representing the UUID of the matcher.
+ * - `atc`: the C-style string representing the ATC expression.
+ * - `errbuf`: a buffer to store the error message.
+ * - `errbuf_len`: a pointer to the length of the error message buffer.
+ *
+ * # Returns
+ *
+ * Returns `true` if the matcher was added successfully, otherwise `false`,
+ * and the error message will be stored in the `errbuf`,
+ * and the length of the error message will be stored in `errbuf_len`.
+ *
+ * # Errors
+ *
+ * This function will return `false` if the matcher could not be added to the router,
+ * such as duplicate UUID, invalid ATC expression, `uuid` not representing a valid
+ * 128-bit UUID or `atc` not being valid UTF-8.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ * - `atc` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ * - `errbuf` must be valid to read and write for `errbuf_len * size_of::<u8>()` bytes,
+ *   and it must be properly aligned.
+ * - `errbuf_len` must be valid to read and write for `size_of::<usize>()` bytes,
+ *   and it must be properly aligned.
+ */
+bool router_add_matcher(struct Router *router,
+                        size_t priority,
+                        const char *uuid,
+                        const char *atc,
+                        uint8_t *errbuf,
+                        size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a new matcher to the router with a two level priority.
+ *
+ * The same as [`router_add_matcher`], except that matchers are ordered by
+ * `priority` first and `minor` second, see [`Priority`].
+ * [`router_add_matcher`] is the same as passing a `minor` of `0`.
+ *
+ * # Safety
+ *
+ * The same constraints as for [`router_add_matcher`] apply.
+ */
+bool router_add_matcher_at(struct Router *router,
+                           size_t priority,
+                           uint32_t minor,
+                           const char *uuid,
+                           const char *atc,
+                           uint8_t *errbuf,
+                           size_t *errbuf_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Remove a matcher from the router.
+ *
+ * # Arguments
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `priority`: the priority of the matcher to be removed.
+ * - `uuid`: the C-style string representing the UUID of the matcher to be removed.
+ *
+ * # Returns
+ *
+ * Returns `true` if the matcher was removed successfully, otherwise `false`,
+ * such as when the matcher with the specified UUID doesn't exist or
+ * the priority doesn't match the UUID, or when `uuid` doesn't represent a valid
+ * 128-bit UUID.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ */
+bool router_remove_matcher(struct Router *router, size_t priority, const char *uuid);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Remove a matcher added with [`router_add_matcher_at`].
+ *
+ * # Safety
+ *
+ * The same constraints as for [`router_remove_matcher`] apply.
+ */
+bool router_remove_matcher_at(struct Router *router,
+                              size_t priority,
+                              uint32_t minor,
+                              const char *uuid);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Execute the router with the context.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `context`: a pointer to the [`Context`] object.
+ *
+ * # Returns
+ *
+ * Returns `true` if found a match, `false` means no match found.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   and must be reset by [`context_reset`] before calling this function
+ *   if you want to reuse the same context for multiple matches.
+ */
+bool router_execute(const struct Router *router, struct Context *context);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Execute the router with the context, only evaluating the matchers whose
+ * priority is between `min_priority` and `max_priority`, both included.
+ *
+ * See [`Router::execute_range`].
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `context`: a pointer to the [`Context`] object.
+ * - `min_priority`: the lowest priority evaluated.
+ * - `max_priority`: the highest priority evaluated.
+ *
+ * # Returns
+ *
+ * Returns `true` if found a match, `false` means no match found.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   and must be reset by [`context_reset`] before calling this function
+ *   if you want to reuse the same context for multiple matches.
+ */
+bool router_execute_range(const struct Router *router,
+                          struct Context *context,
+                          size_t min_priority,
+                          size_t max_priority);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Execute the router with the context, giving up if evaluating the matchers
+ * takes longer than `deadline_ns`.
+ *
+ * The time is checked before each matcher is evaluated, see
+ * [`Router::execute_deadline`].
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `context`: a pointer to the [`Context`] object.
+ * - `deadline_ns`: how long the evaluation may take, in nanoseconds from the
+ *   call.
+ *
+ * # Returns
+ *
+ * Returns one of [`ATC_ROUTER_EXECUTE_NO_MATCH`], [`ATC_ROUTER_EXECUTE_MATCH`]
+ * or [`ATC_ROUTER_EXECUTE_TIMED_OUT`]. After a timeout the context holds no
+ * result.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   and must be reset by [`context_reset`] before calling this function
+ *   if you want to reuse the same context for multiple matches.
+ */
+int64_t router_execute_deadline(const struct Router *router,
+                                struct Context *context,
+                                uint64_t deadline_ns);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Execute the router with the context, collecting every matching matcher
+ * instead of stopping at the first one.
+ *
+ * The first match, which is the one [`router_execute`] would have picked, is
+ * stored in the context and can be read with [`context_get_result`].
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `context`: a pointer to the [`Context`] object.
+ * - `uuids_hex`: a buffer the hyphenated UUIDs of the matching matchers will be
+ *   written to in evaluation order, back to back, each taking 36 bytes.
+ *   May be `NULL` if `len` is `0`.
+ * - `len`: the number of UUIDs `uuids_hex` can hold. Matches beyond it are counted
+ *   but not written.
+ *
+ * # Returns
+ *
+ * Returns the number of matching matchers, `0` means no match found.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   and must be reset by [`context_reset`] before calling this function
+ *   if you want to reuse the same context for multiple matches.
+ * - If `len` is not `0`, `uuids_hex` must be valid to read and write for
+ *   `len * 36 * size_of::<u8>()` bytes.
+ *
+ * [`context_get_result`]: crate::ffi::context::context_get_result
+ */
+size_t router_execute_all(const struct Router *router,
+                          struct Context *context,
+                          uint8_t *uuids_hex,
+                          size_t len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the de-duplicated fields that are actually used in the router.
+ * This is useful when you want to know what fields are actually used in the router,
+ * so you can generate their values on-demand.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `fields`: a pointer to an array of pointers to the field names
+ *   (NOT C-style strings) that are actually used in the router, which will be filled in.
+ *   if `fields` is `NULL`, this function will only return the number of fields used
+ *   in the router.
+ * - `fields_len`: a pointer to an array of the length of each field name.
+ *
+ * # Lifetimes
+ *
+ * The string pointers stored in `fields` might be invalidated if any of the following
+ * operations are happened:
+ *
+ * - The `router` was deallocated.
+ * - A new matcher was added to the `router`.
+ * - A matcher was removed from the `router`.
+ *
+ * # Returns
+ *
+ * Returns the number of fields that are actually used in the router. They
+ * are listed in the order the router first saw them, which adding and
+ * removing matchers does not change.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - If `fields` is not `NULL`, `fields` must be valid to read and write for
+ *   `fields_len * size_of::<*const u8>()` bytes, and it must be properly aligned.
+ * - If `fields` is not `NULL`, `fields_len` must be valid to read and write for
+ *   `size_of::<usize>()` bytes, and it must be properly aligned.
+ * - DO NOT write the memory pointed by the elements of `fields`.
+ * - DO NOT access the memory pointed by the elements of `fields`
+ *   after it becomes invalid, see the `Lifetimes` section.
+ */
+size_t router_get_fields(const struct Router *router, const uint8_t **fields, size_t *fields_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the de-duplicated fields used by a single matcher of the router, so
+ * the values a specific route needs can be generated on-demand.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `priority`: the priority of the matcher.
+ * - `uuid`: the C-style string representing the UUID of the matcher.
+ * - `fields`: a pointer to an array of pointers to the field names
+ *   (NOT C-style strings) used by the matcher, which will be filled in.
+ *   If `fields` is `NULL`, this function will only return the number of fields.
+ * - `fields_len`: a pointer to an array of the length of each field name,
+ *   see [`router_get_fields`].
+ *
+ * The string pointers stored in `fields` are invalidated like the ones of
+ * [`router_get_fields`].
+ *
+ * # Returns
+ *
+ * Returns the number of fields used by the matcher, or `-1` if there is no
+ * such matcher or `uuid` doesn't represent a valid 128-bit UUID.
+ *
+ * # Safety
+ *
+ * The same constraints as for [`router_get_fields`] apply, and:
+ *
+ * - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ */
+int64_t router_get_matcher_fields(const struct Router *router,
+                                  size_t priority,
+                                  const char *uuid,
+                                  const uint8_t **fields,
+                                  size_t *fields_len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the hit counters of the matchers in the router.
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+ * - `uuids_hex`: a buffer the hyphenated UUIDs of the matchers will be written to,
+ *   back to back, each taking 36 bytes. If `NULL`, only the number of matchers is returned.
+ * - `priorities`: a pointer to an array the priorities of the matchers will be written to.
+ * - `counts`: a pointer to an array the hit counts of the matchers will be written to.
+ * - `len`: the number of elements `priorities` and `counts` can hold.
+ *
+ * Matchers are reported in evaluation order.
+ *
+ * # Returns
+ *
+ * Returns the number of matchers in the router.
+ *
+ * # Panics
+ *
+ * This function will panic if `uuids_hex` is not `NULL` and `len` is smaller
+ * than the number of matchers in the router.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - If `uuids_hex` is not `NULL`, `uuids_hex` must be valid to read and write for
+ *   `len * 36 * size_of::<u8>()` bytes, `priorities` must be valid to read and write
+ *   for `len * size_of::<usize>()` bytes, and `counts` must be valid to read and write
+ *   for `len * size_of::<u64>()` bytes, all properly aligned.
+ */
+size_t router_get_hit_counts(const struct Router *router,
+                             uint8_t *uuids_hex,
+                             size_t *priorities,
+                             uint64_t *counts,
+                             size_t len);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the number of [`router_execute`] calls that did not match anything.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ */
+uint64_t router_get_miss_count(const struct Router *router);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Reset all hit and miss counters of the router to zero.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ */
+void router_reset_hit_counts(const struct Router *router);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+struct Schema *schema_new(void);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Deallocate the schema object.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ */
+void schema_free(struct Schema *schema);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Add a new field with the specified type to the schema.
+ *
+ * # Arguments
+ *
+ * - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+ * - `field`: the C-style string representing the field name.
+ * - `typ`: the [tag](Type::tag) of the type of the field.
+ *
+ * # Returns
+ *
+ * Returns `false`, leaving the schema unchanged, if the C-style string
+ * pointed by `field` is not a valid UTF-8 string or `typ` is not the tag
+ * of a type known to this version of the library or the type of
+ * literals only, see [`Type::is_field_type`]. The error kind
+ * `ATC_ROUTER_ERROR_INVALID_ARGUMENT` is then recorded for
+ * [`atc_router_last_error_kind`](crate::ffi::atc_router_last_error_kind).
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - `field` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ */
+bool schema_add_field(struct Schema *schema, const char *field, uint32_t typ);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Set the policy used by the `lower()` transformation function.
+ *
+ * # Arguments
+ *
+ * - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+ * - `policy`: the [tag](LowerPolicy::tag) of the lower-casing policy, see
+ *   [`LowerPolicy`].
+ *
+ * # Returns
+ *
+ * Returns `false`, leaving the schema unchanged, if `policy` is not the
+ * tag of a policy known to this version of the library. The error kind
+ * `ATC_ROUTER_ERROR_INVALID_ARGUMENT` is then recorded for
+ * [`atc_router_last_error_kind`](crate::ffi::atc_router_last_error_kind).
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ */
+bool schema_set_lower_policy(struct Schema *schema, uint32_t policy);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Designate the field holding the HTTP method of requests, see
+ * [`Schema::set_method_field`].
+ *
+ * # Arguments
+ *
+ * - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+ * - `field`: the C-style string representing the field name.
+ *
+ * # Returns
+ *
+ * Returns `false`, leaving the schema unchanged, if the C-style string
+ * pointed by `field` is not a valid UTF-8 string.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - `field` must be a valid pointer to a C-style string, must be properly aligned,
+ *   and must not have '\0' in the middle.
+ */
+bool schema_set_method_field(struct Schema *schema, const char *field);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Get the fields declared in the schema and their types, so hosts can
+ * dump a schema instead of tracking it on their own.
+ *
+ * # Arguments
+ *
+ * - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+ * - `fields`: a pointer to an array of pointers to the field names
+ *   (NOT C-style strings), which will be filled in. Wildcard fields are
+ *   named `prefix.*`. If `fields` is `NULL`, this function will only
+ *   return the number of fields.
+ * - `fields_len`: a pointer to an array of the length of each field name.
+ *   Its first element must be the number of elements every array can hold.
+ * - `types`: a pointer to an array of the type of each field.
+ *
+ * # Lifetimes
+ *
+ * The string pointers stored in `fields` might be invalidated if any of the following
+ * operations are happened:
+ *
+ * - The `schema` was deallocated.
+ * - A field was added to the `schema`.
+ *
+ * # Returns
+ *
+ * Returns the number of fields declared in the schema.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `schema` must be a valid pointer returned by [`schema_new`].
+ * - If `fields` is not `NULL`, `fields`, `fields_len` and `types` must each
+ *   be valid to read and write for `*fields_len` elements, and be properly
+ *   aligned.
+ * - DO NOT write the memory pointed by the elements of `fields`.
+ * - DO NOT access the memory pointed by the elements of `fields`
+ *   after it becomes invalid, see the `Lifetimes` section.
+ */
+size_t schema_get_fields(const struct Schema *schema,
+                         const uint8_t **fields,
+                         size_t *fields_len,
+                         enum Type *types);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Create a new shared router holding `router`, see [`SharedRouter`].
+ *
+ * # Arguments
+ *
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`],
+ *   which is owned by the shared router from now on.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `router` must be a valid pointer returned by [`router_new`].
+ * - `router` must not be used, nor freed by [`router_free`], afterwards.
+ *
+ * [`router_new`]: crate::ffi::router::router_new
+ * [`router_free`]: crate::ffi::router::router_free
+ */
+struct SharedRouter *shared_router_new(struct Router *router);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Deallocate the shared router object and its current router.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `shared` must be a valid pointer returned by [`shared_router_new`].
+ * - No other thread may be using `shared`.
+ */
+void shared_router_free(struct SharedRouter *shared);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Atomically make `router` the router `shared` executes.
+ *
+ * Build `router` in the background with [`router_new`] and
+ * [`router_add_matcher`], then swap it in: executions never observe a
+ * partially updated routing table. Executions already running, on other
+ * threads, finish with the previous router, which is freed afterwards.
+ *
+ * # Arguments
+ *
+ * - `shared`: a pointer to the [`SharedRouter`] object returned by
+ *   [`shared_router_new`].
+ * - `router`: a pointer to the [`Router`] object returned by [`router_new`],
+ *   which is owned by `shared` from now on.
+ *
+ * # Errors
+ *
+ * This function never fails.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `shared` must be a valid pointer returned by [`shared_router_new`].
+ * - `router` must be a valid pointer returned by [`router_new`], created
+ *   with the schema of the router `shared` was created with.
+ * - `router` must not be used, nor freed by [`router_free`], afterwards.
+ *
+ * [`router_new`]: crate::ffi::router::router_new
+ * [`router_add_matcher`]: crate::ffi::router::router_add_matcher
+ * [`router_free`]: crate::ffi::router::router_free
+ */
+void shared_router_replace(const struct SharedRouter *shared, struct Router *router);
+#endif
+
+#if defined(DEFINE_ATC_ROUTER_FFI)
+/**
+ * Execute the current router of `shared` with the context, see
+ * [`router_execute`].
+ *
+ * # Arguments
+ *
+ * - `shared`: a pointer to the [`SharedRouter`] object returned by
+ *   [`shared_router_new`].
+ * - `context`: a pointer to the [`Context`] object.
+ *
+ * # Returns
+ *
+ * Returns `true` if found a match, `false` means no match found.
+ *
+ * # Safety
+ *
+ * Violating any of the following constraints will result in undefined behavior:
+ *
+ * - `shared` must be a valid pointer returned by [`shared_router_new`].
+ * - `context` must be a valid pointer returned by [`context_new`],
+ *   and must be reset by [`context_reset`] before calling this function
+ *   if you want to reuse the same context for multiple matches.
+ *
+ * [`router_execute`]: crate::ffi::router::router_execute
+ * [`context_new`]: crate::ffi::context::context_new
+ * [`context_reset`]: crate::ffi::context::context_reset
+ */
+bool shared_router_execute(const struct SharedRouter *shared, struct Context *context);
+#endif
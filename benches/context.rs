@@ -0,0 +1,208 @@
+use atc_router::ast::{Type, Value};
+use atc_router::context::Context;
+use atc_router::context_pool::ContextPool;
+use atc_router::router::Router;
+use atc_router::schema::Schema;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use uuid::Uuid;
+
+/// Counts allocations, so the benchmarks can report how many each
+/// request takes next to its timing.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// Fields and values of a typical request, several of them multi-valued.
+const REQUEST: &[(&str, &str)] = &[
+    ("http.method", "GET"),
+    ("http.host", "svc1.api.example.com"),
+    ("http.path", "/v1/users/1234/orders"),
+    ("http.headers.accept", "application/json"),
+    ("http.headers.accept", "text/html"),
+    ("http.headers.x_forwarded_for", "10.0.0.1"),
+    ("http.headers.x_forwarded_for", "10.0.0.2"),
+    ("http.headers.x_forwarded_for", "10.0.0.3"),
+];
+
+fn schema() -> Schema {
+    let mut s = Schema::default();
+    s.add_field("http.method", Type::String);
+    s.add_field("http.host", Type::String);
+    s.add_field("http.path", Type::String);
+    s.add_field("http.headers.*", Type::String);
+    s.add_field("net.dst.port", Type::Int);
+    s
+}
+
+/// Benchmarks `add_request` and prints the allocations it makes once
+/// warmed up.
+fn bench<'a>(
+    c: &mut Criterion,
+    name: &str,
+    schema: &'a Schema,
+    add_request: impl Fn(&mut Context<'a>),
+) {
+    let mut ctx = Context::new(schema);
+    for _ in 0..16 {
+        add_request(&mut ctx);
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    add_request(&mut ctx);
+    println!(
+        "context/{}: {} allocations per request",
+        name,
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    );
+
+    c.bench_function(&format!("context/{}", name), |b| {
+        b.iter(|| add_request(&mut ctx))
+    });
+}
+
+fn bench_add_value(c: &mut Criterion) {
+    let schema = schema();
+    let mut group = c.benchmark_group("context");
+
+    let mut ctx = Context::new(&schema);
+    group.bench_function("add_value", |b| {
+        b.iter(|| {
+            ctx.reset();
+            for (field, value) in REQUEST {
+                ctx.add_value(field, Value::String(value.to_string().into()));
+            }
+            ctx.add_value("net.dst.port", Value::Int(443));
+        })
+    });
+
+    let mut ctx = Context::new(&schema);
+    group.bench_function("add_value_str", |b| {
+        b.iter(|| {
+            ctx.reset();
+            for (field, value) in REQUEST {
+                ctx.add_value_str(field, value);
+            }
+            ctx.add_value_int("net.dst.port", 443);
+        })
+    });
+
+    group.finish();
+
+    // the same request once more, printing the allocations of copying the
+    // strings against borrowing them
+    bench(c, "add_request_str", &schema, |ctx| {
+        ctx.reset();
+        for (field, value) in REQUEST {
+            ctx.add_value_str(field, value);
+        }
+    });
+    bench(c, "add_request_ref", &schema, |ctx| {
+        ctx.reset();
+        for (field, value) in REQUEST {
+            ctx.add_value_ref(field, value);
+        }
+    });
+}
+
+/// Matching a request, where the matched values and captures of the
+/// result are recorded.
+fn bench_execute(c: &mut Criterion) {
+    let schema = schema();
+
+    for (name, atc) in [
+        (
+            "no_captures",
+            r#"http.host == "svc1.api.example.com" && http.path ^= "/v1/""#,
+        ),
+        (
+            "one_capture",
+            r#"http.path ~ "^/v1/users/(?<user>[0-9]+)/""#,
+        ),
+        (
+            "two_captures",
+            r#"http.path ~ "^/(?<version>v[0-9]+)/users/(?<user>[0-9]+)/""#,
+        ),
+    ] {
+        let mut router = Router::new(&schema);
+        router.add_matcher(0, Uuid::default(), atc).unwrap();
+
+        bench(c, &format!("execute_{}", name), &schema, |ctx| {
+            ctx.reset();
+            for (field, value) in REQUEST {
+                ctx.add_value_str(field, value);
+            }
+            assert!(router.execute(ctx));
+        });
+    }
+}
+
+/// Executes taking a context from a [`ContextPool`] for each request,
+/// after printing how many allocations 100k of them take compared to
+/// building a new context for each.
+fn bench_pool(c: &mut Criterion) {
+    const EXECUTES: usize = 100_000;
+
+    let schema = schema();
+    let mut router = Router::new(&schema);
+    router
+        .add_matcher(
+            0,
+            Uuid::default(),
+            r#"http.path ~ "^/v1/users/(?<user>[0-9]+)/""#,
+        )
+        .unwrap();
+    let pool = ContextPool::new(&schema);
+
+    let request = |ctx: &mut Context| {
+        for (field, value) in REQUEST {
+            ctx.add_value_str(field, value);
+        }
+        assert!(router.execute(ctx));
+    };
+    let new = || request(&mut Context::new(&schema));
+    let pooled = || {
+        let mut ctx = pool.get();
+        request(&mut ctx);
+        pool.put(ctx);
+    };
+
+    let allocations = |execute: &dyn Fn()| {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        for _ in 0..EXECUTES {
+            execute();
+        }
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    };
+    println!(
+        "context/pool: {} allocations for {} executes with a new context each, {} with pooled ones",
+        allocations(&new),
+        EXECUTES,
+        allocations(&pooled)
+    );
+
+    c.bench_function("context/execute_new_context", |b| b.iter(new));
+    c.bench_function("context/execute_pooled", |b| b.iter(pooled));
+}
+
+criterion_group!(benches, bench_add_value, bench_execute, bench_pool);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+use atc_router::context::Context;
+use atc_router::corpus::{Corpus, Shape};
+use atc_router::router::Router;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use uuid::Uuid;
+
+const N: usize = 10_000;
+const SEED: u64 = 0x4b6f_6e67;
+
+fn bench_add_matchers(c: &mut Criterion) {
+    let schema = Corpus::schema();
+    let exprs = Corpus::new(SEED, Shape::default()).expressions(N);
+
+    c.bench_function("add 10k corpus matchers", |b| {
+        b.iter(|| {
+            let mut router = Router::new(&schema);
+            for (i, atc) in exprs.iter().enumerate() {
+                router
+                    .add_matcher(i, Uuid::from_u128(i as u128), atc)
+                    .unwrap();
+            }
+            router
+        })
+    });
+}
+
+fn bench_execute(c: &mut Criterion) {
+    let schema = Corpus::schema();
+    let mut corpus = Corpus::new(SEED, Shape::default());
+    let mut router = Router::new(&schema);
+    for (i, atc) in corpus.expressions(N).iter().enumerate() {
+        router
+            .add_matcher(i, Uuid::from_u128(i as u128), atc)
+            .unwrap();
+    }
+
+    c.bench_function("execute against 10k corpus matchers", |b| {
+        b.iter_batched(
+            || {
+                let mut ctx = Context::new(&schema);
+                corpus.fill_context(&mut ctx);
+                ctx
+            },
+            |mut ctx| router.execute(&mut ctx),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_add_matchers, bench_execute);
+criterion_main!(benches);
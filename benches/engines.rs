@@ -0,0 +1,48 @@
+use atc_router::context::Context;
+use atc_router::corpus::{Corpus, Shape};
+use atc_router::router::{Engine, Router};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use uuid::Uuid;
+
+const N: usize = 10_000;
+const SEED: u64 = 0x4b6f_6e67;
+
+/// The same corpus executed by every [`Engine`], to pick one per workload.
+fn bench_engines(c: &mut Criterion) {
+    let schema = Corpus::schema();
+    let exprs = Corpus::new(SEED, Shape::default()).expressions(N);
+    let mut group = c.benchmark_group("engines");
+
+    for engine in [
+        Engine::Ast,
+        Engine::Cir,
+        Engine::Lir,
+        Engine::Closure,
+        Engine::Dag,
+    ] {
+        let mut router = Router::builder(&schema).engine(engine).build();
+        for (i, atc) in exprs.iter().enumerate() {
+            router
+                .add_matcher(i, Uuid::from_u128(i as u128), atc)
+                .unwrap();
+        }
+
+        let mut corpus = Corpus::new(SEED, Shape::default());
+        group.bench_function(format!("{:?}", engine), |b| {
+            b.iter_batched(
+                || {
+                    let mut ctx = Context::new(&schema);
+                    corpus.fill_context(&mut ctx);
+                    ctx
+                },
+                |mut ctx| router.execute(&mut ctx),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_engines);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+use atc_router::corpus::{Corpus, Shape};
+use atc_router::parser::parse;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+const SEED: u64 = 0x4b6f_6e67;
+
+/// Joins corpus expressions with `||` until the result is at least `size`
+/// bytes long.
+fn huge_expression(size: usize) -> String {
+    let mut corpus = Corpus::new(SEED, Shape::default());
+    let mut atc = String::with_capacity(size + 1024);
+
+    while atc.len() < size {
+        if !atc.is_empty() {
+            atc.push_str(" || ");
+        }
+        atc.push('(');
+        atc.push_str(&corpus.next_expression());
+        atc.push(')');
+    }
+
+    atc
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+
+    for kb in [100, 500] {
+        let atc = huge_expression(kb * 1024);
+        group.throughput(Throughput::Bytes(atc.len() as u64));
+        group.bench_function(format!("{}KB of corpus expressions", kb), |b| {
+            b.iter(|| parse(&atc).unwrap())
+        });
+    }
+
+    let atc = format!(r#"http.path == "{}""#, r#"/a\"b"#.repeat(25_000));
+    group.throughput(Throughput::Bytes(atc.len() as u64));
+    group.bench_function("100KB string literal", |b| b.iter(|| parse(&atc).unwrap()));
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
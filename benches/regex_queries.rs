@@ -0,0 +1,52 @@
+use atc_router::context::Context;
+use atc_router::corpus::{Corpus, Shape};
+use atc_router::router::Router;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use uuid::Uuid;
+
+const N: usize = 2_000;
+const SEED: u64 = 0x7265_6765;
+
+/// Routers where every path is matched by a regex and hosts are few, so
+/// most matchers get evaluated.
+fn bench_regex_index(c: &mut Criterion) {
+    let schema = Corpus::schema();
+    let shape = Shape {
+        hosts: 2,
+        path_templates: 500,
+        regex_fraction: 1.0,
+        method_fraction: 0.0,
+        ..Default::default()
+    };
+    let mut corpus = Corpus::new(SEED, shape);
+    let mut router = Router::new(&schema);
+    for (i, atc) in corpus.expressions(N).iter().enumerate() {
+        router
+            .add_matcher(i, Uuid::from_u128(i as u128), atc)
+            .unwrap();
+    }
+
+    let mut group = c.benchmark_group("regex_queries");
+    for indexed in [false, true] {
+        if indexed {
+            router.enable_regex_index();
+        }
+
+        let name = if indexed { "regex index" } else { "no index" };
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || {
+                    let mut ctx = Context::new(&schema);
+                    corpus.fill_context(&mut ctx);
+                    ctx
+                },
+                |mut ctx| router.execute(&mut ctx),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_regex_index);
+criterion_main!(benches);
@@ -0,0 +1,20 @@
+//! Regenerates `atc_router.h`, the C header of the FFI, when built with the
+//! `header` feature. The header is committed so hosts do not need cbindgen.
+
+fn main() {
+    #[cfg(feature = "header")]
+    generate_header();
+}
+
+#[cfg(feature = "header")]
+fn generate_header() {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    // `cbindgen.toml` is picked up from the crate root
+    cbindgen::generate(&dir)
+        .expect("failed to generate the C header")
+        .write_to_file(format!("{}/atc_router.h", dir));
+
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=src/ffi");
+}
@@ -4,31 +4,47 @@ local ffi = require("ffi")
 -- generated from "cbindgen -l c", do not edit manually
 ffi.cdef([[
 typedef enum Type {
-  String,
-  IpCidr,
-  IpAddr,
-  Int,
-  Regex,
+  String = 0,
+  IpCidr = 1,
+  IpAddr = 2,
+  Int = 3,
+  Regex = 4,
+  Float = 5,
+  List = 6,
+  IntRange = 7,
+  CidrList = 8,
+  Map = 9,
 } Type;
 
 typedef struct Context Context;
 
+typedef struct ContextPool ContextPool;
+
 typedef struct Router Router;
 
 typedef struct Schema Schema;
 
+typedef struct SharedRouter SharedRouter;
+
 typedef enum CValue_Tag {
   CValue_Str,
   CValue_IpCidr,
   CValue_IpAddr,
   CValue_Int,
+  CValue_Float,
+  CValue_StrLossy,
 } CValue_Tag;
 
 typedef struct CValue_Str_Body {
   const uint8_t *_0;
-  uintptr_t _1;
+  size_t _1;
 } CValue_Str_Body;
 
+typedef struct CValue_StrLossy_Body {
+  const uint8_t *_0;
+  size_t _1;
+} CValue_StrLossy_Body;
+
 typedef struct CValue {
   CValue_Tag tag;
   union {
@@ -42,59 +58,150 @@ typedef struct CValue {
     struct {
       int64_t int_;
     };
+    struct {
+      double float_;
+    };
+    CValue_StrLossy_Body str_lossy;
   };
 } CValue;
 
+typedef bool (*ContextProvider)(void *data,
+                                const uint8_t *field,
+                                size_t field_len,
+                                struct CValue *value);
+
+uint32_t atc_router_last_error_kind(void);
+
+bool atc_router_last_error_location(size_t *start,
+                                    size_t *end,
+                                    size_t *line,
+                                    size_t *column,
+                                    size_t *end_line,
+                                    size_t *end_column);
+
+uint32_t atc_router_api_version(void);
+
 struct Schema *schema_new(void);
 
 void schema_free(struct Schema *schema);
 
-void schema_add_field(struct Schema *schema, const int8_t *field, enum Type typ);
+bool schema_add_field(struct Schema *schema, const char *field, uint32_t typ);
+
+size_t schema_get_fields(const struct Schema *schema,
+                         const uint8_t **fields,
+                         size_t *fields_len,
+                         enum Type *types);
 
 struct Router *router_new(const struct Schema *schema);
 
 void router_free(struct Router *router);
 
 bool router_add_matcher(struct Router *router,
-                        uintptr_t priority,
-                        const int8_t *uuid,
-                        const int8_t *atc,
+                        size_t priority,
+                        const char *uuid,
+                        const char *atc,
                         uint8_t *errbuf,
-                        uintptr_t *errbuf_len);
+                        size_t *errbuf_len);
 
-bool router_remove_matcher(struct Router *router, uintptr_t priority, const int8_t *uuid);
+bool router_remove_matcher(struct Router *router, size_t priority, const char *uuid);
 
 bool router_execute(const struct Router *router, struct Context *context);
 
-uintptr_t router_get_fields(const struct Router *router,
-                            const uint8_t **fields,
-                            uintptr_t *fields_len);
+bool router_execute_range(const struct Router *router,
+                          struct Context *context,
+                          size_t min_priority,
+                          size_t max_priority);
+
+struct SharedRouter *shared_router_new(struct Router *router);
+
+void shared_router_free(struct SharedRouter *shared);
+
+void shared_router_replace(const struct SharedRouter *shared, struct Router *router);
+
+bool shared_router_execute(const struct SharedRouter *shared, struct Context *context);
+
+int64_t router_execute_deadline(const struct Router *router,
+                                struct Context *context,
+                                uint64_t deadline_ns);
+
+size_t router_get_fields(const struct Router *router,
+                         const uint8_t **fields,
+                         size_t *fields_len);
+
+int64_t router_get_matcher_fields(const struct Router *router,
+                                  size_t priority,
+                                  const char *uuid,
+                                  const uint8_t **fields,
+                                  size_t *fields_len);
 
 struct Context *context_new(const struct Schema *schema);
 
 void context_free(struct Context *context);
 
+struct ContextPool *context_pool_new(const struct Schema *schema, size_t max_idle);
+
+void context_pool_free(struct ContextPool *pool);
+
+struct Context *context_pool_get(const struct ContextPool *pool);
+
+bool context_pool_put(const struct ContextPool *pool, struct Context *context);
+
 bool context_add_value(struct Context *context,
-                       const int8_t *field,
+                       const char *field,
                        const struct CValue *value,
                        uint8_t *errbuf,
-                       uintptr_t *errbuf_len);
+                       size_t *errbuf_len);
+
+bool context_add_value_ref(struct Context *context,
+                           const char *field,
+                           const struct CValue *value,
+                           uint8_t *errbuf,
+                           size_t *errbuf_len);
+
+bool context_add_values(struct Context *context,
+                        const char *field,
+                        const struct CValue *values,
+                        size_t len,
+                        uint8_t *errbuf,
+                        size_t *errbuf_len);
+
+bool context_add_map_value(struct Context *context,
+                           const char *field,
+                           const char *key,
+                           const struct CValue *value,
+                           uint8_t *errbuf,
+                           size_t *errbuf_len);
+
+void context_set_provider(struct Context *context, ContextProvider provider, void *data);
 
 void context_reset(struct Context *context);
 
-intptr_t context_get_result(const struct Context *context,
-                            uint8_t *uuid_hex,
-                            const int8_t *matched_field,
-                            const uint8_t **matched_value,
-                            uintptr_t *matched_value_len,
-                            const uint8_t **capture_names,
-                            uintptr_t *capture_names_len,
-                            const uint8_t **capture_values,
-                            uintptr_t *capture_values_len);
+ptrdiff_t context_get_result(const struct Context *context,
+                             uint8_t *uuid_hex,
+                             const char *matched_field,
+                             const uint8_t **matched_value,
+                             size_t *matched_value_len,
+                             const uint8_t **capture_names,
+                             size_t *capture_names_len,
+                             const uint8_t **capture_values,
+                             size_t *capture_values_len);
+
+ptrdiff_t context_get_evidence(const struct Context *context,
+                               const uint8_t **fields,
+                               size_t *fields_len,
+                               const uint8_t **ops,
+                               size_t *ops_len,
+                               const uint8_t **values,
+                               size_t *values_len);
 ]])
 
 
 local ERR_BUF_MAX_LEN = 4096
+-- the version of the C API the declarations above were generated from,
+-- see `ATC_ROUTER_API_VERSION` in atc_router.h
+local ATC_ROUTER_API_VERSION = 1
+local ATC_ROUTER_EXECUTE_MATCH = 1
+local ATC_ROUTER_EXECUTE_TIMED_OUT = 2
 
 
 -- From: https://github.com/openresty/lua-resty-signal/blob/master/lib/resty/signal.lua
@@ -140,10 +247,26 @@ if not clib then
           table.concat(tried_paths, "\n"), 2)
 end
 
+-- refuse to call into a library built from another version, whose
+-- functions may take other arguments than declared above. Libraries
+-- older than the version check do not export it at all
+do
+    local ok, version = pcall(function()
+        return clib.atc_router_api_version()
+    end)
+
+    if not ok or version ~= ATC_ROUTER_API_VERSION then
+        error(("%s provides C API version %s, but version %d is required"):format(
+              lib_name, ok and tostring(version) or "0", ATC_ROUTER_API_VERSION), 2)
+    end
+end
+
 
 return {
     clib = clib,
     ERR_BUF_MAX_LEN = ERR_BUF_MAX_LEN,
+    ATC_ROUTER_EXECUTE_MATCH = ATC_ROUTER_EXECUTE_MATCH,
+    ATC_ROUTER_EXECUTE_TIMED_OUT = ATC_ROUTER_EXECUTE_TIMED_OUT,
 
     context_free = function(c)
         clib.context_free(c)
@@ -15,6 +15,7 @@ local ffi_string = ffi.string
 local tonumber = tonumber
 local setmetatable = setmetatable
 local new_tab = require("table.new")
+local clear_tab = require("table.clear")
 local C = ffi.C
 
 
@@ -31,13 +32,41 @@ function _M.new(schema)
     local c = setmetatable({
         context = ffi_gc(context, context_free),
         schema = schema,
+        -- strings the context borrows, see add_value_ref
+        refs = {},
     }, _MT)
 
     return c
 end
 
 
-function _M:add_value(field, value)
+local function fill_value(cvalue, typ, value, lossy)
+    if typ == "String" and lossy then
+        cvalue.tag = C.CValue_StrLossy
+        cvalue.str_lossy._0 = value
+        cvalue.str_lossy._1 = #value
+
+    elseif typ == "String" then
+        cvalue.tag = C.CValue_Str
+        cvalue.str._0 = value
+        cvalue.str._1 = #value
+
+    elseif typ == "IpAddr" then
+        cvalue.tag = C.CValue_IpAddr
+        cvalue.ip_addr = value
+
+    elseif typ == "Int" then
+        cvalue.tag = C.CValue_Int
+        cvalue.int_ = value
+
+    elseif typ == "Float" then
+        cvalue.tag = C.CValue_Float
+        cvalue.float_ = value
+    end
+end
+
+
+function _M:add_value(field, value, lossy)
     if not value then
         return true
     end
@@ -47,25 +76,92 @@ function _M:add_value(field, value)
         return nil, err
     end
 
-    if typ == "String" then
-        CACHED_VALUE[0].tag = C.CValue_Str
-        CACHED_VALUE[0].str._0 = value
-        CACHED_VALUE[0].str._1 = #value
+    fill_value(CACHED_VALUE[0], typ, value, lossy)
 
-    elseif typ == "IpAddr" then
-        CACHED_VALUE[0].tag = C.CValue_IpAddr
-        CACHED_VALUE[0].ip_addr = value
+    local errbuf = get_string_buf(ERR_BUF_MAX_LEN)
+    local errbuf_len = get_size_ptr()
+    errbuf_len[0] = ERR_BUF_MAX_LEN
 
-    elseif typ == "Int" then
-        CACHED_VALUE[0].tag = C.CValue_Int
-        CACHED_VALUE[0].int_ = value
+    if clib.context_add_value(self.context, field, CACHED_VALUE, errbuf, errbuf_len) == false then
+        return nil, ffi_string(errbuf, errbuf_len[0])
     end
 
+    return true
+end
+
+
+-- like add_value, but the context borrows a string value instead of
+-- copying it, which keeps it alive until the next reset
+function _M:add_value_ref(field, value, lossy)
+    if not value then
+        return true
+    end
+
+    local typ, err = self.schema:get_field_type(field)
+    if not typ then
+        return nil, err
+    end
+
+    fill_value(CACHED_VALUE[0], typ, value, lossy)
+
     local errbuf = get_string_buf(ERR_BUF_MAX_LEN)
     local errbuf_len = get_size_ptr()
     errbuf_len[0] = ERR_BUF_MAX_LEN
 
-    if clib.context_add_value(self.context, field, CACHED_VALUE, errbuf, errbuf_len) == false then
+    if clib.context_add_value_ref(self.context, field, CACHED_VALUE, errbuf, errbuf_len) == false then
+        return nil, ffi_string(errbuf, errbuf_len[0])
+    end
+
+    local refs = self.refs
+    refs[#refs + 1] = value
+
+    return true
+end
+
+
+function _M:add_values(field, values, lossy)
+    local n = values and #values or 0
+    if n == 0 then
+        return true
+    end
+
+    local typ, err = self.schema:get_field_type(field)
+    if not typ then
+        return nil, err
+    end
+
+    -- `values` keeps the strings the array points to alive during the call
+    local cvalues = ffi_new("CValue[?]", n)
+    for i = 1, n do
+        fill_value(cvalues[i - 1], typ, values[i], lossy)
+    end
+
+    local errbuf = get_string_buf(ERR_BUF_MAX_LEN)
+    local errbuf_len = get_size_ptr()
+    errbuf_len[0] = ERR_BUF_MAX_LEN
+
+    if clib.context_add_values(self.context, field, cvalues, n, errbuf, errbuf_len) == false then
+        return nil, ffi_string(errbuf, errbuf_len[0])
+    end
+
+    return true
+end
+
+
+-- adds a value to the entry `key` of a Map field, read by field["key"]
+function _M:add_map_value(field, key, value, lossy)
+    if not value then
+        return true
+    end
+
+    fill_value(CACHED_VALUE[0], "String", value, lossy)
+
+    local errbuf = get_string_buf(ERR_BUF_MAX_LEN)
+    local errbuf_len = get_size_ptr()
+    errbuf_len[0] = ERR_BUF_MAX_LEN
+
+    if clib.context_add_map_value(self.context, field, key, CACHED_VALUE,
+                                  errbuf, errbuf_len) == false then
         return nil, ffi_string(errbuf, errbuf_len[0])
     end
 
@@ -132,8 +228,52 @@ function _M:get_result(matched_field)
 end
 
 
+-- returns a list of { field, op, value } tables, one per predicate that
+-- held for the matched route, value is nil for non-string values
+function _M:get_evidence()
+    local len = tonumber(clib.context_get_evidence(
+        self.context, nil, nil, nil, nil, nil, nil))
+    if len == -1 then
+        return nil
+    end
+
+    local evidence = new_tab(len, 0)
+    if len == 0 then
+        return evidence
+    end
+
+    local fields = ffi_new("const uint8_t *[?]", len)
+    local fields_len = ffi_new("size_t [?]", len)
+    local ops = ffi_new("const uint8_t *[?]", len)
+    local ops_len = ffi_new("size_t [?]", len)
+    local values = ffi_new("const uint8_t *[?]", len)
+    local values_len = ffi_new("size_t [?]", len)
+
+    fields_len[0] = len
+
+    clib.context_get_evidence(self.context, fields, fields_len, ops, ops_len,
+                              values, values_len)
+
+    for i = 0, len - 1 do
+        local value
+        if values[i] ~= nil then
+            value = ffi_string(values[i], values_len[i])
+        end
+
+        evidence[i + 1] = {
+            field = ffi_string(fields[i], fields_len[i]),
+            op = ffi_string(ops[i], ops_len[i]),
+            value = value,
+        }
+    end
+
+    return evidence
+end
+
+
 function _M:reset()
     clib.context_reset(self.context)
+    clear_tab(self.refs)
 end
 
 return _M
@@ -17,6 +17,8 @@ local setmetatable = setmetatable
 
 
 local ERR_BUF_MAX_LEN = cdefs.ERR_BUF_MAX_LEN
+local ATC_ROUTER_EXECUTE_MATCH = cdefs.ATC_ROUTER_EXECUTE_MATCH
+local ATC_ROUTER_EXECUTE_TIMED_OUT = cdefs.ATC_ROUTER_EXECUTE_TIMED_OUT
 local clib = cdefs.clib
 local router_free = cdefs.router_free
 
@@ -40,13 +42,33 @@ function _M.new(schema, routes_n)
 end
 
 
+-- where the last parse error is, nil if the last error was not a parse error
+local function last_error_location()
+    local loc = ffi_new("size_t [6]")
+    if clib.atc_router_last_error_location(loc, loc + 1, loc + 2, loc + 3,
+                                           loc + 4, loc + 5) == false then
+        return nil
+    end
+
+    return {
+        start = tonumber(loc[0]),
+        finish = tonumber(loc[1]),
+        line = tonumber(loc[2]),
+        column = tonumber(loc[3]),
+        end_line = tonumber(loc[4]),
+        end_column = tonumber(loc[5]),
+    }
+end
+
+
 function _M:add_matcher(priority, uuid, atc)
     local errbuf = get_string_buf(ERR_BUF_MAX_LEN)
     local errbuf_len = get_size_ptr()
     errbuf_len[0] = ERR_BUF_MAX_LEN
 
     if clib.router_add_matcher(self.router, priority, uuid, atc, errbuf, errbuf_len) == false then
-        return nil, ffi_string(errbuf, errbuf_len[0])
+        return nil, ffi_string(errbuf, errbuf_len[0]),
+               tonumber(clib.atc_router_last_error_kind()), last_error_location()
     end
 
     self.priorities[uuid] = priority
@@ -73,6 +95,29 @@ function _M:execute(context)
 end
 
 
+-- like execute, but only evaluates the matchers whose priority is
+-- between min_priority and max_priority, both included
+function _M:execute_range(context, min_priority, max_priority)
+    assert(context.schema == self.schema)
+    return clib.router_execute_range(self.router, context.context,
+                                     min_priority, max_priority) == true
+end
+
+
+-- like execute, but gives up after timeout_ns nanoseconds,
+-- returning nil and "timed out"
+function _M:execute_deadline(context, timeout_ns)
+    assert(context.schema == self.schema)
+
+    local rc = clib.router_execute_deadline(self.router, context.context, timeout_ns)
+    if rc == ATC_ROUTER_EXECUTE_TIMED_OUT then
+        return nil, "timed out"
+    end
+
+    return rc == ATC_ROUTER_EXECUTE_MATCH
+end
+
+
 function _M:get_fields()
     local out = {}
     local out_n = 0
@@ -98,6 +143,39 @@ function _M:get_fields()
 end
 
 
+-- fields read by the matcher with this uuid,
+-- nil if there is no such matcher
+function _M:get_matcher_fields(uuid)
+    local priority = self.priorities[uuid]
+    if not priority then
+        return nil
+    end
+
+    local out = {}
+    local router = self.router
+
+    local total = tonumber(clib.router_get_matcher_fields(router, priority, uuid, nil, nil))
+    if total < 0 then
+        return nil
+    end
+
+    if total == 0 then
+        return out
+    end
+
+    local fields = ffi_new("const uint8_t *[?]", total)
+    local fields_len = ffi_new("size_t [?]", total)
+    fields_len[0] = total
+
+    clib.router_get_matcher_fields(router, priority, uuid, fields, fields_len)
+
+    for i = 0, total - 1 do
+        out[i + 1] = ffi_string(fields[i], fields_len[i])
+    end
+
+    return out
+end
+
 do
     local ROUTERS = setmetatable({}, { __mode = "k" })
     local DEFAULT_UUID = "00000000-0000-0000-0000-000000000000"
@@ -7,11 +7,23 @@ local _MT = { __index = _M, }
 
 
 local setmetatable = setmetatable
+local tonumber = tonumber
 local ffi_gc = ffi.gc
+local ffi_new = ffi.new
+local ffi_string = ffi.string
 local clib = cdefs.clib
 local schema_free = cdefs.schema_free
 
 
+local TYPE_NAMES = {
+    [tonumber(clib.String)] = "String",
+    [tonumber(clib.IpCidr)] = "IpCidr",
+    [tonumber(clib.IpAddr)] = "IpAddr",
+    [tonumber(clib.Int)] = "Int",
+    [tonumber(clib.Float)] = "Float",
+}
+
+
 function _M.new()
     local schema = clib.schema_new()
     local s = setmetatable({
@@ -44,11 +56,16 @@ function _M:add_field(field, typ)
     elseif typ == "Int" then
         ctype = clib.Int
 
+    elseif typ == "Float" then
+        ctype = clib.Float
+
     else
         error("Unknown type: " .. typ, 2)
     end
 
-    clib.schema_add_field(self.schema, field, ctype)
+    if not clib.schema_add_field(self.schema, field, ctype) then
+        return nil, "field name is not a valid UTF-8 string"
+    end
 
     self.field_types[field] = typ
     self.field_ctypes[field] = ctype
@@ -74,4 +91,31 @@ function _M:get_field_type(field)
 end
 
 
+
+-- every field declared in the schema, as a table of field name to type
+function _M:get_fields()
+    local out = {}
+    local schema = self.schema
+
+    local total = tonumber(clib.schema_get_fields(schema, nil, nil, nil))
+    if total == 0 then
+        return out
+    end
+
+    local fields = ffi_new("const uint8_t *[?]", total)
+    local fields_len = ffi_new("size_t [?]", total)
+    local types = ffi_new("enum Type [?]", total)
+    fields_len[0] = total
+
+    clib.schema_get_fields(schema, fields, fields_len, types)
+
+    for i = 0, total - 1 do
+        local typ = tonumber(types[i])
+        out[ffi_string(fields[i], fields_len[i])] = TYPE_NAMES[typ] or typ
+    end
+
+    return out
+end
+
+
 return _M
@@ -1,20 +1,110 @@
+use crate::regex_engine::Regex;
 use crate::schema::Schema;
 use cidr::IpCidr;
-use regex::Regex;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
 use std::net::IpAddr;
+use std::ops::Deref;
+use std::ptr::NonNull;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expression {
     Logical(Box<LogicalExpression>),
     Predicate(Predicate),
+    FieldComparison(FieldComparison),
+    /// `true` or `false`.
+    Bool(bool),
+}
+
+impl Expression {
+    /// Number of nodes of the expression: predicates, field comparisons,
+    /// literals and logical operators each count one.
+    pub fn complexity(&self) -> usize {
+        match self {
+            Expression::Logical(l) => match l.as_ref() {
+                LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                    1 + l.complexity() + r.complexity()
+                }
+                LogicalExpression::Not(e) => 1 + e.complexity(),
+            },
+            Expression::Predicate(_) | Expression::FieldComparison(_) | Expression::Bool(_) => 1,
+        }
+    }
+
+    /// Sets the [`Lhs::var_index`] of every field to its id in `schema`,
+    /// so evaluating the expression reads the values of fields from the
+    /// context by id instead of by name.
+    pub fn intern_fields(&mut self, schema: &Schema) {
+        match self {
+            Expression::Logical(l) => match l.as_mut() {
+                LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                    l.intern_fields(schema);
+                    r.intern_fields(schema);
+                }
+                LogicalExpression::Not(e) => e.intern_fields(schema),
+            },
+            Expression::Predicate(p) => p.lhs.intern(schema),
+            Expression::FieldComparison(c) => {
+                c.lhs.intern(schema);
+                c.rhs.intern(schema);
+            }
+            Expression::Bool(_) => {}
+        }
+    }
+
+    /// Calls `f` with every predicate of the expression.
+    pub(crate) fn for_each_predicate(&self, f: &mut impl FnMut(&Predicate)) {
+        match self {
+            Expression::Logical(l) => match l.as_ref() {
+                LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                    l.for_each_predicate(f);
+                    r.for_each_predicate(f);
+                }
+                LogicalExpression::Not(e) => e.for_each_predicate(f),
+            },
+            Expression::Predicate(p) => f(p),
+            Expression::FieldComparison(_) | Expression::Bool(_) => {}
+        }
+    }
+
+    /// The CIDRs the expression checks IPs against, such as `10.0.0.0/8` in
+    /// `net.src.ip in 10.0.0.0/8`, in order and duplicates included. Feed
+    /// them to [`Context::arbitrary_with`](crate::context::Context::arbitrary_with)
+    /// so random contexts hold IPs on both sides of those predicates.
+    pub fn cidr_literals(&self) -> Vec<IpCidr> {
+        let mut cidrs = Vec::new();
+        self.for_each_predicate(&mut |p| match &p.rhs {
+            Value::IpCidr(c) => cidrs.push(*c),
+            Value::CidrList(l) => cidrs.extend_from_slice(l.cidrs()),
+            _ => {}
+        });
+        cidrs
+    }
+
+    /// Like [`Expression::for_each_predicate`], mutably.
+    pub(crate) fn for_each_predicate_mut(&mut self, f: &mut impl FnMut(&mut Predicate)) {
+        match self {
+            Expression::Logical(l) => match l.as_mut() {
+                LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                    l.for_each_predicate_mut(f);
+                    r.for_each_predicate_mut(f);
+                }
+                LogicalExpression::Not(e) => e.for_each_predicate_mut(f),
+            },
+            Expression::Predicate(p) => f(p),
+            Expression::FieldComparison(_) | Expression::Bool(_) => {}
+        }
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum LogicalExpression {
     And(Expression, Expression),
     Or(Expression, Expression),
@@ -22,14 +112,51 @@ pub enum LogicalExpression {
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LhsTransformations {
     Lower,
+    /// Some value of the field must satisfy the predicate, see
+    /// [`Quantifier::Any`].
     Any,
+    /// Every value of the field must satisfy the predicate, which is what
+    /// fields without `any()` do already, see [`Quantifier::All`].
+    All,
+    /// Strips leading and trailing whitespace.
+    Trim,
+    Upper,
+    /// Collapses runs of `/` and resolves `.` and `..` segments.
+    PathNormalize,
+    /// A function taking arguments after the field, such as
+    /// `substr(http.path, 0, 4)`. Which functions exist and the arguments
+    /// they take are checked by [`Validate`](crate::semantics::Validate).
+    Custom(String, Vec<TransformArg>),
+    // declared last so that snapshots written before keep their variant
+    // indexes
+    /// Decodes `%XX` escapes, see `url_decode()` in the README.
+    UrlDecode,
+    /// Decodes standard or URL-safe base64.
+    Base64Decode,
+}
+
+/// An argument of a [`LhsTransformations::Custom`] function.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransformArg {
+    Int(i64),
+    String(String),
+}
+
+impl TransformArg {
+    pub fn my_type(&self) -> Type {
+        match self {
+            TransformArg::Int(_) => Type::Int,
+            TransformArg::String(_) => Type::String,
+        }
+    }
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BinaryOperator {
     Equals,         // ==
     NotEquals,      // !=
@@ -43,17 +170,248 @@ pub enum BinaryOperator {
     In,             // in
     NotIn,          // not in
     Contains,       // contains
+    Glob,           // glob
 }
 
+impl BinaryOperator {
+    /// Every operator, in declaration order.
+    pub const ALL: &'static [BinaryOperator] = &[
+        BinaryOperator::Equals,
+        BinaryOperator::NotEquals,
+        BinaryOperator::Regex,
+        BinaryOperator::Prefix,
+        BinaryOperator::Postfix,
+        BinaryOperator::Greater,
+        BinaryOperator::GreaterOrEqual,
+        BinaryOperator::Less,
+        BinaryOperator::LessOrEqual,
+        BinaryOperator::In,
+        BinaryOperator::NotIn,
+        BinaryOperator::Contains,
+        BinaryOperator::Glob,
+    ];
+
+    /// The operator as written in expressions.
+    pub fn as_str(&self) -> &'static str {
+        use BinaryOperator::*;
+
+        match self {
+            Equals => "==",
+            NotEquals => "!=",
+            Regex => "~",
+            Prefix => "^=",
+            Postfix => "=^",
+            Greater => ">",
+            GreaterOrEqual => ">=",
+            Less => "<",
+            LessOrEqual => "<=",
+            In => "in",
+            NotIn => "not in",
+            Contains => "contains",
+            Glob => "glob",
+        }
+    }
+}
+
+/// The string of a [`Value::String`].
+///
+/// Strings are owned, except the values added to a context with
+/// [`Context::add_value_ref`](crate::context::Context::add_value_ref),
+/// which borrow the caller's buffer instead of copying it. Cloning always
+/// copies, so a borrowed string never leaves the context holding it.
+pub struct Str(StrRepr);
+
+enum StrRepr {
+    Owned(String),
+    /// Valid for as long as the `Str`, see [`Str::borrowed`].
+    Borrowed(NonNull<str>),
+}
+
+// SAFETY: a borrowed string is never written to, so it is shared and sent
+// like the `&str` it was made from
+unsafe impl Send for Str {}
+unsafe impl Sync for Str {}
+
+impl Str {
+    /// Borrows `s` without copying it.
+    ///
+    /// # Safety
+    ///
+    /// `s` must stay valid and unchanged for as long as the returned `Str`
+    /// exists, which only [`Context`](crate::context::Context) storage can
+    /// ensure: values are only ever read out of a context by reference or
+    /// cloned.
+    pub(crate) unsafe fn borrowed(s: &str) -> Str {
+        Str(StrRepr::Borrowed(NonNull::from(s)))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            StrRepr::Owned(s) => s,
+            // SAFETY: see `Str::borrowed`
+            StrRepr::Borrowed(s) => unsafe { s.as_ref() },
+        }
+    }
+
+    /// Whether the string borrows a buffer of the caller rather than
+    /// owning a copy.
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.0, StrRepr::Borrowed(_))
+    }
+
+    pub fn into_string(self) -> String {
+        match self.0 {
+            StrRepr::Owned(s) => s,
+            StrRepr::Borrowed(_) => self.as_str().to_string(),
+        }
+    }
+}
+
+impl Deref for Str {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for Str {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for Str {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Clone for Str {
+    fn clone(&self) -> Self {
+        Str(StrRepr::Owned(self.as_str().to_string()))
+    }
+}
+
+impl From<String> for Str {
+    fn from(s: String) -> Self {
+        Str(StrRepr::Owned(s))
+    }
+}
+
+impl From<&str> for Str {
+    fn from(s: &str) -> Self {
+        Str(StrRepr::Owned(s.to_string()))
+    }
+}
+
+impl From<Str> for String {
+    fn from(s: Str) -> Self {
+        s.into_string()
+    }
+}
+
+impl fmt::Debug for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl fmt::Display for Str {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self)
+    }
+}
+
+impl PartialEq for Str {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Str {}
+
+impl PartialEq<str> for Str {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Str {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Str {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialOrd for Str {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Str {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for Str {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Str {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Str {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Str::from)
+    }
+}
+
+/// A value in an expression or a [`Context`](crate::context::Context).
+///
+/// New variants are only ever appended, which fixes their serde variant
+/// index. The ones up to `List` are declared in [`Type::tag`] order.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Value {
-    String(String),
+    String(Str),
     IpCidr(IpCidr),
     IpAddr(IpAddr),
     Int(i64),
-    #[cfg_attr(feature = "serde", serde(with = "serde_regex"))]
+    #[cfg_attr(feature = "serde", serde(with = "crate::regex_cache::serde_cached"))]
     Regex(Regex),
+    Float(f64),
+    /// A sorted, deduplicated list of strings, the right hand side of
+    /// `in` / `not in` predicates on string fields.
+    List(Vec<String>),
+    /// The strings of a compacted `||` chain of `==` predicates, see
+    /// [`Expression::compact`]. Typed as a string, never a context value.
+    Set(crate::compact::StringSet),
+    /// The right hand side of an `==` or `in` predicate on the method
+    /// field, see [`Expression::compile_methods`]. Typed as the value it was
+    /// compiled from, never a context value.
+    Methods(crate::method::MethodSet),
+    /// An inclusive range of integers, the right hand side of `in` /
+    /// `not in` predicates on integer fields.
+    IntRange(i64, i64),
+    /// A list of CIDRs, the right hand side of `in` / `not in` predicates
+    /// on address fields.
+    CidrList(crate::cidr_list::CidrList),
 }
 
 impl PartialEq for Value {
@@ -66,176 +424,478 @@ impl PartialEq for Value {
             (Self::IpCidr(i1), Self::IpCidr(i2)) => i1 == i2,
             (Self::IpAddr(i1), Self::IpAddr(i2)) => i1 == i2,
             (Self::Int(i1), Self::Int(i2)) => i1 == i2,
+            (Self::Float(f1), Self::Float(f2)) => f1 == f2,
+            (Self::List(l1), Self::List(l2)) => l1 == l2,
+            (Self::Set(s1), Self::Set(s2)) => s1 == s2,
+            (Self::Methods(m1), Self::Methods(m2)) => m1 == m2,
+            (Self::IntRange(l1, h1), Self::IntRange(l2, h2)) => l1 == l2 && h1 == h2,
+            (Self::CidrList(l1), Self::CidrList(l2)) => l1 == l2,
             _ => false,
         }
     }
 }
 
 impl Value {
+    /// The [`Type::tag`] of this value's type.
+    pub fn tag(&self) -> u32 {
+        self.my_type().tag()
+    }
+
     pub fn my_type(&self) -> Type {
         match self {
-            Value::String(_) => Type::String,
+            Value::String(_) | Value::Set(_) => Type::String,
             Value::IpCidr(_) => Type::IpCidr,
             Value::IpAddr(_) => Type::IpAddr,
             Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::List(_) => Type::List,
             Value::Regex(_) => Type::Regex,
+            Value::Methods(m) => m.original().my_type(),
+            Value::IntRange(..) => Type::IntRange,
+            Value::CidrList(_) => Type::CidrList,
         }
     }
 }
 
 impl From<String> for Value {
     fn from(v: String) -> Self {
-        Value::String(v)
+        Value::String(v.into())
     }
 }
 
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.into())
+    }
+}
+
+/// The type of a schema field or [`Value`].
+///
+/// The discriminants are part of the C ABI (see [`Type::tag`]): they are
+/// never changed or reused, new types get the next free one.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 #[repr(C)]
+#[non_exhaustive]
 pub enum Type {
-    String,
-    IpCidr,
-    IpAddr,
-    Int,
-    Regex,
+    String = 0,
+    IpCidr = 1,
+    IpAddr = 2,
+    Int = 3,
+    Regex = 4,
+    Float = 5,
+    List = 6,
+    IntRange = 7,
+    CidrList = 8,
+    /// Strings by string keys, such as the headers of a request by name.
+    /// Read one key at a time with `field["key"]`, which is a `String`,
+    /// and filled with [`Context::add_map_value`](crate::context::Context::add_map_value).
+    Map = 9,
 }
 
-#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+impl Type {
+    /// Every type, in tag order.
+    pub const ALL: &'static [Type] = &[
+        Type::String,
+        Type::IpCidr,
+        Type::IpAddr,
+        Type::Int,
+        Type::Regex,
+        Type::Float,
+        Type::List,
+        Type::IntRange,
+        Type::CidrList,
+        Type::Map,
+    ];
+
+    /// The stable numeric tag of this type, as used by the FFI.
+    pub fn tag(self) -> u32 {
+        self as u32
+    }
+
+    /// The type with the given [`Type::tag`], `None` for tags unknown to
+    /// this version of the library.
+    pub fn from_tag(tag: u32) -> Option<Type> {
+        Type::ALL.get(tag as usize).copied()
+    }
+
+    /// Whether fields can be declared with this type. `List`, `IntRange`
+    /// and `CidrList` are only the types of literals, such as the
+    /// right-hand side of `in`.
+    pub fn is_field_type(self) -> bool {
+        !matches!(self, Type::List | Type::IntRange | Type::CidrList)
+    }
+}
+
+impl TryFrom<u32> for Type {
+    type Error = u32;
+
+    fn try_from(tag: u32) -> Result<Self, Self::Error> {
+        Type::from_tag(tag).ok_or(tag)
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[derive(Debug, Clone)]
 pub struct Lhs {
     pub var_name: String,
     pub transformations: Vec<LhsTransformations>,
+    /// The [id](Schema::field_id) of `var_name` in the schema the
+    /// expression was interned with, see [`Expression::intern_fields`].
+    /// Only a hint: contexts of a schema giving the id to another field
+    /// look `var_name` up by name.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub var_index: Option<usize>,
+    /// The key looked up in `var_name`, a [`Type::Map`] field, as in
+    /// `http.headers["x-request-id"]`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub key: Option<String>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Lhs {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        // documents only have a key when there is one, so they read the same
+        // as before keys existed. Binary snapshots can not leave fields out
+        let with_key = self.key.is_some() || !serializer.is_human_readable();
+        let mut s = serializer.serialize_struct("Lhs", 2 + with_key as usize)?;
+        s.serialize_field("var_name", &self.var_name)?;
+        s.serialize_field("transformations", &self.transformations)?;
+        if with_key {
+            s.serialize_field("key", &self.key)?;
+        }
+        s.end()
+    }
 }
 
 impl Lhs {
+    fn intern(&mut self, schema: &Schema) {
+        self.var_index = schema.field_id(&self.var_name);
+    }
+
+    /// The type of the values compared, the type of the field unless it
+    /// is a map looked up by key.
     pub fn my_type<'a>(&self, schema: &'a Schema) -> Option<&'a Type> {
-        schema.type_of(&self.var_name)
+        match schema.type_of(&self.var_name)? {
+            Type::Map if self.key.is_some() => Some(&Type::String),
+            typ => Some(typ),
+        }
+    }
+
+    /// The field, with the key looked up if any, as recorded in
+    /// [`Match::matches`](crate::context::Match::matches).
+    pub fn path(&self) -> Cow<'_, str> {
+        match &self.key {
+            Some(key) => Cow::Owned(format!(
+                "{}[{}]",
+                self.var_name,
+                Value::String(key.as_str().into())
+            )),
+            None => Cow::Borrowed(&self.var_name),
+        }
     }
 
     pub fn get_transformations(&self) -> (bool, bool) {
-        let mut lower = false;
-        let mut any = false;
+        let lower = self.transformations.contains(&LhsTransformations::Lower);
 
-        self.transformations.iter().for_each(|i| match i {
-            LhsTransformations::Any => any = true,
-            LhsTransformations::Lower => lower = true,
-        });
+        (lower, self.quantifier() == Quantifier::Any)
+    }
+
+    /// How many values of the field must satisfy the predicate. Validation
+    /// rejects fields with both `any()` and `all()`.
+    pub fn quantifier(&self) -> Quantifier {
+        if self.transformations.contains(&LhsTransformations::Any) {
+            Quantifier::Any
+        } else {
+            Quantifier::All
+        }
+    }
 
-        (lower, any)
+    /// Whether a function other than `any()` and `all()` changes the values
+    /// of the field before they are compared.
+    pub fn is_transformed(&self) -> bool {
+        self.transformations.iter().any(|t| !t.is_quantifier())
+    }
+
+    /// Whether the values of the field are compared as they are, each of
+    /// them having to satisfy the predicate: `field` or `all(field)`, but
+    /// not `field["key"]`.
+    pub fn is_plain(&self) -> bool {
+        self.key.is_none()
+            && self
+                .transformations
+                .iter()
+                .all(|t| *t == LhsTransformations::All)
+    }
+}
+
+/// How many of the values of a field must satisfy a predicate. Fields may
+/// have several values, such as a header sent more than once.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Quantifier {
+    /// Every value, and there must be at least one. The default, written
+    /// `all(field)` when made explicit.
+    #[default]
+    All,
+    /// At least one value, written `any(field)`.
+    Any,
+}
+
+impl LhsTransformations {
+    /// Whether this is `any()` or `all()`, which select a [`Quantifier`]
+    /// instead of transforming values.
+    pub fn is_quantifier(&self) -> bool {
+        matches!(self, LhsTransformations::Any | LhsTransformations::All)
     }
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Predicate {
     pub lhs: Lhs,
     pub rhs: Value,
     pub op: BinaryOperator,
+    /// Slot of the predicate in the memo table of
+    /// [`Context`](crate::context::Context), shared by every identical
+    /// predicate of a router so it is evaluated once per execution. Set by
+    /// the router the expression is added to, `None` evaluates the
+    /// predicate every time.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub memo: Option<usize>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::parser::parse;
-    use std::fmt;
-
-    impl fmt::Display for Expression {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "{}",
-                match self {
-                    Expression::Logical(logical) => logical.to_string(),
-                    Expression::Predicate(predicate) => predicate.to_string(),
-                }
-            )
+/// A predicate comparing the values of two fields, such as
+/// `http.host == tls.sni`.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct FieldComparison {
+    pub lhs: Lhs,
+    pub rhs: Lhs,
+    pub op: BinaryOperator,
+}
+
+impl Expression {
+    /// Returns the expression as ATC source, which [`parse`] turns back
+    /// into an equivalent expression. Same as the [`Display`] output.
+    ///
+    /// Every predicate and logical operator is parenthesised, so
+    /// `a == 1 && b == 2` becomes `((a == 1) && (b == 2))`.
+    ///
+    /// [`parse`]: crate::parser::parse
+    /// [`Display`]: fmt::Display
+    pub fn to_atc_string(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expression::Logical(logical) => write!(f, "{}", logical),
+            Expression::Predicate(predicate) => write!(f, "{}", predicate),
+            Expression::FieldComparison(cmp) => write!(f, "{}", cmp),
+            Expression::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl fmt::Display for LogicalExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogicalExpression::And(left, right) => write!(f, "({} && {})", left, right),
+            LogicalExpression::Or(left, right) => write!(f, "({} || {})", left, right),
+            LogicalExpression::Not(e) => write!(f, "!({})", e),
         }
     }
+}
+
+impl fmt::Display for LhsTransformations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LhsTransformations::Lower => "lower",
+            LhsTransformations::Any => "any",
+            LhsTransformations::All => "all",
+            LhsTransformations::Trim => "trim",
+            LhsTransformations::Upper => "upper",
+            LhsTransformations::PathNormalize => "path_normalize",
+            LhsTransformations::Custom(name, _) => name,
+            LhsTransformations::UrlDecode => "url_decode",
+            LhsTransformations::Base64Decode => "base64_decode",
+        })
+    }
+}
 
-    impl fmt::Display for LogicalExpression {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "{}",
-                match self {
-                    LogicalExpression::And(left, right) => {
-                        format!("({} && {})", left, right)
-                    }
-                    LogicalExpression::Or(left, right) => {
-                        format!("({} || {})", left, right)
-                    }
-                    LogicalExpression::Not(e) => {
-                        format!("!({})", e)
+/// Writes `s` as a string literal: a raw string when that avoids escaping
+/// backslashes or quotes (typically regexes), an escaped one otherwise.
+fn write_str_literal(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    if s.contains(['\\', '"']) && !s.contains("\"#") && !s.contains(['\n', '\r', '\t']) {
+        return write!(f, "r#\"{}\"#", s);
+    }
+
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => write_str_literal(f, s),
+            // `{:#}` keeps the `/32` of host CIDRs, which would otherwise
+            // read back as an address
+            Value::IpCidr(cidr) => write!(f, "{:#}", cidr),
+            Value::IpAddr(addr) => write!(f, "{}", addr),
+            Value::Int(i) => write!(f, "{}", i),
+            // `{:?}` keeps the `.0` of integral floats
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::List(l) => write_list_literal(f, l),
+            Value::Set(set) => write_list_literal(f, set.values()),
+            Value::Methods(m) => m.original().fmt(f),
+            Value::IntRange(lo, hi) => write!(f, "{}..{}", lo, hi),
+            Value::CidrList(l) => {
+                f.write_char('(')?;
+                for (i, cidr) in l.cidrs().iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
                     }
+                    write!(f, "{:#}", cidr)?;
                 }
-            )
+                f.write_char(')')
+            }
+            Value::Regex(re) => write_str_literal(f, re.as_str()),
         }
     }
+}
 
-    impl fmt::Display for LhsTransformations {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "{}",
-                match self {
-                    LhsTransformations::Lower => "lower".to_string(),
-                    LhsTransformations::Any => "any".to_string(),
-                }
-            )
+fn write_list_literal(f: &mut fmt::Formatter, l: &[String]) -> fmt::Result {
+    f.write_char('(')?;
+    for (i, s) in l.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
         }
+        write_str_literal(f, s)?;
     }
+    f.write_char(')')
+}
 
-    impl fmt::Display for Value {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            match self {
-                Value::String(s) => write!(f, "\"{}\"", s),
-                Value::IpCidr(cidr) => write!(f, "{}", cidr),
-                Value::IpAddr(addr) => write!(f, "{}", addr),
-                Value::Int(i) => write!(f, "{}", i),
-                Value::Regex(re) => write!(f, "\"{}\"", re),
+impl fmt::Display for Lhs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for transformation in self.transformations.iter().rev() {
+            write!(f, "{}(", transformation)?;
+        }
+        f.write_str(&self.path())?;
+        for transformation in &self.transformations {
+            if let LhsTransformations::Custom(_, args) = transformation {
+                for arg in args {
+                    write!(f, ", {}", arg)?;
+                }
             }
+            f.write_char(')')?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TransformArg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransformArg::Int(i) => write!(f, "{}", i),
+            TransformArg::String(s) => write_str_literal(f, s),
         }
     }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (BinaryOperator::Glob, Value::Regex(re)) = (self.op, &self.rhs) {
+            // a regex not translated from a glob matches the same with `~`
+            return match crate::glob::regex_to_glob(re.as_str()) {
+                Some(glob) => {
+                    write!(f, "({} glob ", self.lhs)?;
+                    write_str_literal(f, &glob)?;
+                    f.write_char(')')
+                }
+                None => write!(f, "({} ~ {})", self.lhs, self.rhs),
+            };
+        }
 
-    impl fmt::Display for Lhs {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            let mut s = self.var_name.to_string();
-            for transformation in &self.transformations {
-                s = format!("{}({})", transformation, s);
+        if let Value::Set(set) = &self.rhs {
+            // print the chain the set was compacted from
+            let values = set.values();
+            for _ in 1..values.len() {
+                f.write_char('(')?;
             }
-            write!(f, "{}", s)
-        }
-    }
-
-    impl fmt::Display for BinaryOperator {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            use BinaryOperator::*;
-
-            write!(
-                f,
-                "{}",
-                match self {
-                    Equals => "==",
-                    NotEquals => "!=",
-                    Regex => "~",
-                    Prefix => "^=",
-                    Postfix => "=^",
-                    Greater => ">",
-                    GreaterOrEqual => ">=",
-                    Less => "<",
-                    LessOrEqual => "<=",
-                    In => "in",
-                    NotIn => "not in",
-                    Contains => "contains",
+            for (i, v) in values.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(" || ")?;
                 }
-            )
+                write!(f, "({} {} ", self.lhs, self.op)?;
+                write_str_literal(f, v)?;
+                f.write_char(')')?;
+                if i > 0 {
+                    f.write_char(')')?;
+                }
+            }
+            return Ok(());
         }
+
+        write!(f, "({} {} {})", self.lhs, self.op, self.rhs)
+    }
+}
+
+impl fmt::Display for FieldComparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({} {} {})", self.lhs, self.op, self.rhs)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
 
-    impl fmt::Display for Predicate {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(f, "({} {} {})", self.lhs, self.op, self.rhs)
+    #[test]
+    fn type_tags() {
+        // part of the C ABI, must never change
+        let tags: Vec<_> = Type::ALL.iter().map(|t| (*t, t.tag())).collect();
+        assert_eq!(
+            tags,
+            [
+                (Type::String, 0),
+                (Type::IpCidr, 1),
+                (Type::IpAddr, 2),
+                (Type::Int, 3),
+                (Type::Regex, 4),
+                (Type::Float, 5),
+                (Type::List, 6),
+                (Type::IntRange, 7),
+                (Type::CidrList, 8),
+                (Type::Map, 9),
+            ]
+        );
+
+        for t in Type::ALL {
+            assert_eq!(Type::from_tag(t.tag()), Some(*t));
         }
+        assert_eq!(Type::try_from(10), Err(10));
+        assert_eq!(Value::Float(1.0).tag(), 5);
     }
 
     #[test]
@@ -303,6 +963,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn expr_map_key() {
+        let tests = vec![
+            (
+                r#"http.headers["x-request-id"] == "abc""#,
+                r#"(http.headers["x-request-id"] == "abc")"#,
+            ),
+            (
+                r#"http.headers [ "a\"b" ] ^= "/""#,
+                r##"(http.headers[r#"a"b"#] ^= "/")"##,
+            ),
+            (
+                r##"http.headers[r#"a"#] == "x""##,
+                r#"(http.headers["a"] == "x")"#,
+            ),
+            (
+                r#"http.headers["a"] == http.headers["b"]"#,
+                r#"(http.headers["a"] == http.headers["b"])"#,
+            ),
+        ];
+        for (input, expected) in tests {
+            let result = parse(input).unwrap();
+            assert_eq!(result.to_string(), expected);
+        }
+
+        let Expression::Predicate(p) = parse(r#"http.headers["x-id"] == "a""#).unwrap() else {
+            unreachable!();
+        };
+        assert_eq!(p.lhs.var_name, "http.headers");
+        assert_eq!(p.lhs.key.as_deref(), Some("x-id"));
+        assert_eq!(p.lhs.path(), r#"http.headers["x-id"]"#);
+
+        assert!(parse(r#"http.headers[] == "a""#).is_err());
+        assert!(parse(r#"http.headers[1] == "a""#).is_err());
+        assert!(parse(r#"http.headers["a"]["b"] == "a""#).is_err());
+    }
+
     #[test]
     fn expr_regex() {
         let tests = vec![
@@ -338,6 +1035,89 @@ mod tests {
             ("kong.foo.foo11 == -0x123", "(kong.foo.foo11 == -291)"),
             // oct negative literal
             ("kong.foo.foo12 == -0123", "(kong.foo.foo12 == -83)"),
+            // float literal
+            ("kong.foo.foo13 >= 12.5", "(kong.foo.foo13 >= 12.5)"),
+            // float negative literal
+            ("kong.foo.foo14 >= -0.25", "(kong.foo.foo14 >= -0.25)"),
+            // float exponent literal
+            ("kong.foo.foo15 < 1e3", "(kong.foo.foo15 < 1000.0)"),
+            ("kong.foo.foo16 < 2.5E-1", "(kong.foo.foo16 < 0.25)"),
+        ];
+        for (input, expected) in tests {
+            let result = parse(input).unwrap();
+            assert_eq!(result.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn expr_list() {
+        let tests = vec![
+            (r#"http.method in ("GET")"#, r#"(http.method in ("GET"))"#),
+            // sorted and deduplicated
+            (
+                r##"http.method in ("OPTIONS", "GET", r#"HEAD"#, "GET",)"##,
+                r#"(http.method in ("GET", "HEAD", "OPTIONS"))"#,
+            ),
+            (
+                r#"http.method not in ( "GET" , "HEAD" )"#,
+                r#"(http.method not in ("GET", "HEAD"))"#,
+            ),
+        ];
+        for (input, expected) in tests {
+            let result = parse(input).unwrap();
+            assert_eq!(result.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn expr_not() {
+        let tests = vec![
+            ("!(a == 1)", "!((a == 1))"),
+            ("!(!(a == 1))", "!(!((a == 1)))"),
+            (
+                "!(a == 1 || b == 2) && c == 3",
+                "(!(((a == 1) || (b == 2))) && (c == 3))",
+            ),
+        ];
+        for (input, expected) in tests {
+            let result = parse(input).unwrap();
+            assert_eq!(result.to_string(), expected);
+        }
+    }
+
+    // the serialized form is part of RouterDocument, it must not change
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_not() {
+        let expr = parse("!(a == 1)").unwrap();
+        let json = serde_json::to_string(&expr).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Logical":{"Not":{"Predicate":{"lhs":{"var_name":"a","transformations":[]},"rhs":{"Int":1},"op":"Equals"}}}}"#
+        );
+
+        let expr: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(expr.to_string(), "!((a == 1))");
+
+        let expr = parse(r#"h["k"] == 1"#).unwrap();
+        let json = serde_json::to_string(&expr).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Predicate":{"lhs":{"var_name":"h","transformations":[],"key":"k"},"rhs":{"Int":1},"op":"Equals"}}"#
+        );
+        let expr: Expression = serde_json::from_str(&json).unwrap();
+        assert_eq!(expr.to_string(), r#"(h["k"] == 1)"#);
+    }
+
+    #[test]
+    fn expr_field_comparison() {
+        let tests = vec![
+            ("http.host == tls.sni", "(http.host == tls.sni)"),
+            (
+                "lower(http.host) != any(tls.sni) && a == 1",
+                "((lower(http.host) != any(tls.sni)) && (a == 1))",
+            ),
+            ("a > b || c ^= d", "((a > b) || (c ^= d))"),
         ];
         for (input, expected) in tests {
             let result = parse(input).unwrap();
@@ -358,6 +1138,33 @@ mod tests {
                 "any(kong.foo.foo14) == \"foo\"",
                 "(any(kong.foo.foo14) == \"foo\")",
             ),
+            // all
+            (
+                "all(kong.foo.foo14) == \"foo\"",
+                "(all(kong.foo.foo14) == \"foo\")",
+            ),
+            (
+                "trim(upper(path_normalize(kong.foo.foo19))) == \"FOO\"",
+                "(trim(upper(path_normalize(kong.foo.foo19))) == \"FOO\")",
+            ),
+            (
+                "base64_decode(url_decode(kong.foo.foo22)) == \"foo\"",
+                "(base64_decode(url_decode(kong.foo.foo22)) == \"foo\")",
+            ),
+            // with arguments
+            (
+                "substr(header_decode(kong.foo.foo20, \"base64\"), 0, -1) == \"foo\"",
+                "(substr(base64_decode(kong.foo.foo20), 0, -1) == \"foo\")",
+            ),
+            (
+                "substr(lower(kong.foo.foo21),0x10) == \"foo\"",
+                "(substr(lower(kong.foo.foo21), 16) == \"foo\")",
+            ),
+            // map key
+            (
+                "any(lower(http.headers[\"x-foo\"])) == \"foo\"",
+                "(any(lower(http.headers[\"x-foo\"])) == \"foo\")",
+            ),
         ];
         for (input, expected) in tests {
             let result = parse(input).unwrap();
@@ -413,13 +1220,53 @@ mod tests {
     fn rawstr_test() {
         let tests = vec![
             // invalid escape sequence
-            (r##"a == r#"/path/to/\d+"#"##, r#"(a == "/path/to/\d+")"#),
+            (
+                r##"a == r#"/path/to/\d+"#"##,
+                r##"(a == r#"/path/to/\d+"#)"##,
+            ),
             // valid escape sequence
-            (r##"a == r#"/path/to/\n+"#"##, r#"(a == "/path/to/\n+")"#),
+            (
+                r##"a == r#"/path/to/\n+"#"##,
+                r##"(a == r#"/path/to/\n+"#)"##,
+            ),
         ];
         for (input, expected) in tests {
             let result = parse(input).unwrap();
             assert_eq!(result.to_string(), expected);
         }
     }
+
+    #[test]
+    fn to_atc_string_round_trip() {
+        let tests = vec![
+            (r#"a == "say \"hi\"""#, r##"(a == r#"say "hi""#)"##),
+            (r#"a == "tab\there""#, r#"(a == "tab\there")"#),
+            (
+                r#"a == "quote\" and\nnewline""#,
+                r#"(a == "quote\" and\nnewline")"#,
+            ),
+            // a raw string can not contain `"#`
+            (r##"a == "\\\"#""##, r##"(a == "\\\"#")"##),
+            (r#"a ~ "^/(?<id>\\d+)$""#, r##"(a ~ r#"^/(?<id>\d+)$"#)"##),
+            (
+                r##"a in ("x\"", r#"\y"#, "z")"##,
+                r##"(a in (r#"\y"#, r#"x""#, "z"))"##,
+            ),
+            (
+                r#"a glob "/api/*/\\*.json""#,
+                r##"(a glob r#"/api/*/\*.json"#)"##,
+            ),
+            ("a in 10.0.0.1/32", "(a in 10.0.0.1/32)"),
+            ("a == -2.0 || a > 1e3", "((a == -2.0) || (a > 1000.0))"),
+            (
+                "!(lower(any(a)) == \"x\") && true",
+                "(!((lower(any(a)) == \"x\")) && true)",
+            ),
+        ];
+        for (input, expected) in tests {
+            let atc = parse(input).unwrap().to_atc_string();
+            assert_eq!(atc, expected);
+            assert_eq!(parse(&atc).unwrap().to_atc_string(), expected);
+        }
+    }
 }
@@ -0,0 +1,371 @@
+//! `atc` - inspect ATC expressions and route sets from the command line.
+//!
+//! ```text
+//! atc parse EXPR
+//! atc validate --schema schema.json EXPR
+//! atc match --schema schema.json --context ctx.json routes.json
+//! atc lint --schema schema.json routes.json
+//! atc coverage --schema schema.json --context traffic.json routes.json
+//! atc operators
+//! ```
+//!
+//! `schema.json` maps field names to types (`{"http.path": "String"}`),
+//! `ctx.json` maps field names to a value or a list of values,
+//! `traffic.json` is a list of such contexts and `routes.json` is a list of
+//! [`RouteDoc`] objects. `operators` prints the operand types every
+//! operator accepts, see [`PREDICATE_RULES`].
+//!
+//! Exits with `0` on success, `1` when the checked expressions are invalid,
+//! nothing matched or some routes were never selected and `2` on usage or
+//! I/O errors.
+
+use atc_router::ast::{Type, Value};
+use atc_router::context::Context;
+use atc_router::parser::parse;
+use atc_router::router::{RouteDoc, Router};
+use atc_router::schema::Schema;
+use atc_router::semantics::{Validate, FIELD_COMPARISON_RULES, PREDICATE_RULES};
+use atc_router::Error;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::process::ExitCode;
+use uuid::Uuid;
+
+const USAGE: &str = "\
+usage: atc parse EXPR
+       atc validate --schema SCHEMA EXPR
+       atc match --schema SCHEMA --context CONTEXT ROUTES
+       atc lint --schema SCHEMA ROUTES
+       atc coverage --schema SCHEMA --context CONTEXTS ROUTES
+       atc operators";
+
+/// Outcome of a command that ran to completion.
+enum Outcome {
+    Ok,
+    Failed,
+}
+
+struct Args {
+    command: String,
+    schema: Option<String>,
+    context: Option<String>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let command = args.next().ok_or("missing command")?;
+        let mut parsed = Args {
+            command,
+            schema: None,
+            context: None,
+            positional: Vec::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--schema" => parsed.schema = Some(args.next().ok_or("--schema needs a path")?),
+                "--context" => parsed.context = Some(args.next().ok_or("--context needs a path")?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ => parsed.positional.push(arg),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn single_positional(&self, what: &str) -> Result<&str, String> {
+        match self.positional.as_slice() {
+            [p] => Ok(p),
+            _ => Err(format!("{} expects exactly one {}", self.command, what)),
+        }
+    }
+
+    fn schema(&self) -> Result<Schema, String> {
+        let path = self.schema.as_ref().ok_or("--schema is required")?;
+        let fields: HashMap<String, Type> = read_json(path)?;
+
+        let mut schema = Schema::default();
+        for (field, typ) in fields {
+            schema
+                .try_add_field(&field, typ)
+                .map_err(|e| format!("{}: {}: {}", path, field, e))?;
+        }
+
+        Ok(schema)
+    }
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(path: &str) -> Result<T, String> {
+    let data = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_json::from_str(&data).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Converts a JSON context value to the type the schema declares for `field`.
+fn to_value(schema: &Schema, field: &str, json: &serde_json::Value) -> Result<Value, String> {
+    let typ = schema
+        .type_of(field)
+        .ok_or_else(|| format!("unknown field {}", field))?;
+    let mismatch = || format!("{}: expected a {:?} value, got {}", field, typ, json);
+
+    Ok(match (typ, json) {
+        (Type::String, serde_json::Value::String(s)) => Value::String(s.as_str().into()),
+        (Type::Int, serde_json::Value::Number(n)) => Value::Int(n.as_i64().ok_or_else(mismatch)?),
+        (Type::Float, serde_json::Value::Number(n)) => {
+            Value::Float(n.as_f64().ok_or_else(mismatch)?)
+        }
+        (Type::IpAddr, serde_json::Value::String(s)) => {
+            Value::IpAddr(s.parse().map_err(|_| mismatch())?)
+        }
+        (Type::IpCidr, serde_json::Value::String(s)) => {
+            Value::IpCidr(s.parse().map_err(|_| mismatch())?)
+        }
+        _ => return Err(mismatch()),
+    })
+}
+
+fn context<'a>(schema: &'a Schema, path: &str) -> Result<Context<'a>, String> {
+    to_context(schema, &read_json(path)?)
+}
+
+fn to_context<'a>(
+    schema: &'a Schema,
+    fields: &BTreeMap<String, serde_json::Value>,
+) -> Result<Context<'a>, String> {
+    let mut ctx = Context::new(schema);
+    for (field, json) in fields {
+        match json {
+            serde_json::Value::Array(values) => {
+                for v in values {
+                    ctx.add_value(field, to_value(schema, field, v)?);
+                }
+            }
+            v => ctx.add_value(field, to_value(schema, field, v)?),
+        }
+    }
+
+    Ok(ctx)
+}
+
+fn cmd_parse(args: &Args) -> Result<Outcome, String> {
+    match parse(args.single_positional("expression")?) {
+        Ok(expr) => {
+            println!("{:#?}", expr);
+            Ok(Outcome::Ok)
+        }
+        Err(e) => {
+            println!("{}", e);
+            Ok(Outcome::Failed)
+        }
+    }
+}
+
+fn cmd_validate(args: &Args) -> Result<Outcome, String> {
+    let schema = args.schema()?;
+
+    let result = parse(args.single_positional("expression")?)
+        .map_err(Error::from)
+        .and_then(|expr| Ok(expr.validate(&schema)?));
+
+    match result {
+        Ok(()) => {
+            println!("ok");
+            Ok(Outcome::Ok)
+        }
+        Err(e) => {
+            println!("{}", e);
+            Ok(Outcome::Failed)
+        }
+    }
+}
+
+fn cmd_match(args: &Args) -> Result<Outcome, String> {
+    let schema = args.schema()?;
+    let routes: Vec<RouteDoc> = read_json(args.single_positional("routes file")?)?;
+    let mut ctx = context(
+        &schema,
+        args.context.as_ref().ok_or("--context is required")?,
+    )?;
+
+    let router = Router::from_routes(&schema, &routes).map_err(|e| e.to_string())?;
+
+    if !router.execute(&mut ctx) {
+        println!("no match");
+        return Ok(Outcome::Failed);
+    }
+
+    let m = ctx.result.unwrap();
+    let captures: BTreeMap<_, _> = m.captures.into_iter().collect();
+    let result = serde_json::json!({
+        "uuid": m.uuid,
+        "captures": captures,
+    });
+    println!("{}", serde_json::to_string_pretty(&result).unwrap());
+
+    Ok(Outcome::Ok)
+}
+
+fn cmd_lint(args: &Args) -> Result<Outcome, String> {
+    let schema = args.schema()?;
+    let routes: Vec<RouteDoc> = read_json(args.single_positional("routes file")?)?;
+
+    let mut problems = 0;
+    let mut report = |uuid: &Uuid, msg: String| {
+        println!("{}: {}", uuid, msg);
+        problems += 1;
+    };
+
+    let mut seen_uuids = HashMap::new();
+    // routes in evaluation order, to find the ones shadowed by an identical
+    // expression that is always evaluated first
+    let mut valid = Vec::new();
+
+    for r in routes.iter().filter(|r| r.enabled) {
+        if let Some(prev) = seen_uuids.insert(r.uuid, r.priority) {
+            report(
+                &r.uuid,
+                format!("duplicate uuid (priorities {} and {})", prev, r.priority),
+            );
+        }
+
+        match parse(&r.expression) {
+            Err(e) => report(&r.uuid, format!("parse error\n{}", e)),
+            Ok(expr) => match expr.validate(&schema) {
+                Err(e) => report(&r.uuid, e.to_string()),
+                Ok(()) => valid.push((r.priority, r.uuid, format!("{:?}", expr))),
+            },
+        }
+    }
+
+    valid.sort_by_key(|r| std::cmp::Reverse((r.0, r.1)));
+    let mut first_seen: HashMap<&str, Uuid> = HashMap::new();
+    for (_, uuid, expr) in &valid {
+        if let Some(winner) = first_seen.get(expr.as_str()) {
+            report(
+                uuid,
+                format!("never matches, {} has the same expression", winner),
+            );
+        } else {
+            first_seen.insert(expr, *uuid);
+        }
+    }
+
+    println!("{} route(s), {} problem(s)", routes.len(), problems);
+
+    Ok(if problems == 0 {
+        Outcome::Ok
+    } else {
+        Outcome::Failed
+    })
+}
+
+fn cmd_coverage(args: &Args) -> Result<Outcome, String> {
+    let schema = args.schema()?;
+    let routes: Vec<RouteDoc> = read_json(args.single_positional("routes file")?)?;
+    let path = args.context.as_ref().ok_or("--context is required")?;
+    let contexts: Vec<BTreeMap<String, serde_json::Value>> = read_json(path)?;
+
+    let mut contexts = contexts
+        .iter()
+        .map(|fields| to_context(&schema, fields))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let router = Router::from_routes(&schema, &routes).map_err(|e| e.to_string())?;
+    let report = router.coverage(&mut contexts);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    Ok(if report.unreached.is_empty() {
+        Outcome::Ok
+    } else {
+        Outcome::Failed
+    })
+}
+
+fn cmd_operators(args: &Args) -> Result<Outcome, String> {
+    if !args.positional.is_empty() {
+        return Err("operators takes no arguments".to_string());
+    }
+
+    let matrix = serde_json::json!({
+        "predicates": PREDICATE_RULES,
+        "field_comparisons": FIELD_COMPARISON_RULES,
+    });
+    println!("{}", serde_json::to_string_pretty(&matrix).unwrap());
+
+    Ok(Outcome::Ok)
+}
+
+fn main() -> ExitCode {
+    let result =
+        Args::parse(std::env::args().skip(1)).and_then(|args| match args.command.as_str() {
+            "parse" => cmd_parse(&args),
+            "validate" => cmd_validate(&args),
+            "match" => cmd_match(&args),
+            "lint" => cmd_lint(&args),
+            "coverage" => cmd_coverage(&args),
+            "operators" => cmd_operators(&args),
+            cmd => Err(format!("unknown command {}", cmd)),
+        });
+
+    match result {
+        Ok(Outcome::Ok) => ExitCode::SUCCESS,
+        Ok(Outcome::Failed) => ExitCode::from(1),
+        Err(e) => {
+            eprintln!("atc: {}\n\n{}", e, USAGE);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_value() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.port", Type::Int);
+        schema.add_field("net.src.ip", Type::IpAddr);
+
+        let json: serde_json::Value =
+            serde_json::from_str(r#"["/foo", 80, "10.0.0.1", 1.5, "not an ip"]"#).unwrap();
+
+        assert_eq!(
+            to_value(&schema, "http.path", &json[0]).unwrap(),
+            Value::String("/foo".into())
+        );
+        assert_eq!(
+            to_value(&schema, "http.headers.x_foo", &json[0]).unwrap(),
+            Value::String("/foo".into())
+        );
+        assert_eq!(
+            to_value(&schema, "net.port", &json[1]).unwrap(),
+            Value::Int(80)
+        );
+        assert_eq!(
+            to_value(&schema, "net.src.ip", &json[2]).unwrap(),
+            Value::IpAddr("10.0.0.1".parse().unwrap())
+        );
+
+        assert!(to_value(&schema, "net.port", &json[3]).is_err());
+        assert!(to_value(&schema, "net.src.ip", &json[4]).is_err());
+        assert!(to_value(&schema, "http.path", &json[1]).is_err());
+        assert!(to_value(&schema, "unknown", &json[0]).is_err());
+    }
+
+    #[test]
+    fn test_args() {
+        let args = |s: &str| Args::parse(s.split(' ').map(String::from));
+
+        let a = args("match --schema s.json --context c.json routes.json").unwrap();
+        assert_eq!(a.command, "match");
+        assert_eq!(a.schema.as_deref(), Some("s.json"));
+        assert_eq!(a.context.as_deref(), Some("c.json"));
+        assert_eq!(a.positional, ["routes.json"]);
+
+        assert!(args("lint --schema").is_err());
+        assert!(args("lint --verbose").is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! Lists of CIDRs, the right hand side of `net.src.ip in (10.0.0.0/8, ...)`.
+//!
+//! The CIDRs of a [`CidrList`] are merged into sorted, disjoint address
+//! ranges per IP family when the list is built, so checking an address
+//! takes one binary search however many CIDRs the list holds.
+
+use cidr::IpCidr;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// The right hand side of `in` / `not in` predicates on address fields,
+/// see the [module documentation](crate::cidr_list).
+///
+/// Serializes as the list of CIDRs in their original order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(from = "Vec<IpCidr>", into = "Vec<IpCidr>")
+)]
+pub struct CidrList {
+    /// As written, duplicates included.
+    cidrs: Vec<IpCidr>,
+    /// Sorted, disjoint and non adjacent inclusive ranges, IPv4 addresses
+    /// as their `u32` value.
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl CidrList {
+    pub fn new(cidrs: Vec<IpCidr>) -> Self {
+        let mut v4 = Vec::new();
+        let mut v6 = Vec::new();
+        for cidr in &cidrs {
+            match cidr {
+                IpCidr::V4(c) => v4.push((
+                    u32::from(c.first_address()).into(),
+                    u32::from(c.last_address()).into(),
+                )),
+                IpCidr::V6(c) => v6.push((c.first_address().into(), c.last_address().into())),
+            }
+        }
+
+        CidrList {
+            cidrs,
+            v4: merge(v4),
+            v6: merge(v6),
+        }
+    }
+
+    /// The CIDRs in their original order.
+    pub fn cidrs(&self) -> &[IpCidr] {
+        &self.cidrs
+    }
+
+    /// Whether any CIDR of the list contains `addr`.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match addr {
+            IpAddr::V4(a) => in_ranges(&self.v4, u32::from(*a).into()),
+            IpAddr::V6(a) => in_ranges(&self.v6, u128::from(*a)),
+        }
+    }
+}
+
+/// Sorts `ranges` and joins the overlapping or adjacent ones.
+fn merge(mut ranges: Vec<(u128, u128)>) -> Vec<(u128, u128)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if last.1.checked_add(1).is_none_or(|next| lo <= next) => {
+                last.1 = last.1.max(hi);
+            }
+            _ => merged.push((lo, hi)),
+        }
+    }
+
+    merged
+}
+
+fn in_ranges(ranges: &[(u128, u128)], addr: u128) -> bool {
+    let i = ranges.partition_point(|(lo, _)| *lo <= addr);
+    i > 0 && addr <= ranges[i - 1].1
+}
+
+impl From<Vec<IpCidr>> for CidrList {
+    fn from(cidrs: Vec<IpCidr>) -> Self {
+        CidrList::new(cidrs)
+    }
+}
+
+impl From<CidrList> for Vec<IpCidr> {
+    fn from(list: CidrList) -> Self {
+        list.cidrs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(cidrs: &[&str]) -> CidrList {
+        CidrList::new(cidrs.iter().map(|c| c.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_merge() {
+        let l = list(&[
+            "10.0.1.0/24",
+            "10.0.0.0/24",
+            "10.0.0.0/16",
+            "192.168.0.0/24",
+            "192.168.1.0/24",
+            "255.255.255.255/32",
+            "255.255.255.254/32",
+            "::/0",
+        ]);
+        assert_eq!(
+            l.v4,
+            [
+                (0x0a00_0000, 0x0a00_ffff),
+                (0xc0a8_0000, 0xc0a8_01ff),
+                (0xffff_fffe, 0xffff_ffff)
+            ]
+        );
+        assert_eq!(l.v6, [(0, u128::MAX)]);
+        assert_eq!(l.cidrs().len(), 8);
+    }
+
+    #[test]
+    fn test_contains() {
+        let l = list(&["10.0.0.0/8", "192.168.0.0/16", "172.16.0.1/32", "fd00::/8"]);
+
+        for (addr, expected) in [
+            ("10.1.2.3", true),
+            ("11.0.0.0", false),
+            ("9.255.255.255", false),
+            ("192.168.255.255", true),
+            ("172.16.0.1", true),
+            ("172.16.0.2", false),
+            ("0.0.0.0", false),
+            ("fd12::1", true),
+            ("fe00::", false),
+            // families are never mixed
+            ("::ffff:10.0.0.1", false),
+        ] {
+            assert_eq!(l.contains(&addr.parse().unwrap()), expected, "{}", addr);
+        }
+    }
+}
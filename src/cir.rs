@@ -0,0 +1,115 @@
+//! Compiled intermediate representation (CIR) of expressions.
+//!
+//! A [`CirProgram`] holds the nodes of an expression in a single vector,
+//! operands before the operator using them and the root last, instead of a
+//! tree of boxes. Evaluating it follows indices into that vector, which
+//! keeps a matcher's nodes together in memory. It is what
+//! [`Router`](crate::router::Router)s execute by default, see
+//! [`Engine`](crate::router::Engine).
+
+use crate::ast::{Expression, FieldComparison, LogicalExpression, Predicate};
+use crate::context::{Context, Match};
+use crate::error::EvalError;
+use crate::interpreter::Execute;
+
+#[derive(Debug, Clone)]
+pub enum CirInstruction {
+    /// Indices of the operands.
+    And(usize, usize),
+    Or(usize, usize),
+    Not(usize),
+    Predicate(Predicate),
+    FieldComparison(FieldComparison),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone)]
+pub struct CirProgram {
+    /// Operands before the operators using them, the root last.
+    instructions: Vec<CirInstruction>,
+}
+
+impl CirProgram {
+    pub fn instructions(&self) -> &[CirInstruction] {
+        &self.instructions
+    }
+
+    fn eval(&self, i: usize, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        Ok(match &self.instructions[i] {
+            CirInstruction::And(l, r) => self.eval(*l, ctx, m)? && self.eval(*r, ctx, m)?,
+            CirInstruction::Or(l, r) => self.eval(*l, ctx, m)? || self.eval(*r, ctx, m)?,
+            CirInstruction::Not(e) => !self.eval(*e, ctx, m)?,
+            CirInstruction::Predicate(p) => p.try_execute(ctx, m)?,
+            CirInstruction::FieldComparison(c) => c.try_execute(ctx, m)?,
+            CirInstruction::Bool(b) => *b,
+        })
+    }
+}
+
+impl From<&Expression> for CirProgram {
+    fn from(expr: &Expression) -> Self {
+        let mut program = CirProgram {
+            instructions: Vec::new(),
+        };
+        compile(expr, &mut program.instructions);
+        program
+    }
+}
+
+/// Appends the instructions of `expr` and returns the index of its root.
+fn compile(expr: &Expression, out: &mut Vec<CirInstruction>) -> usize {
+    let instruction = match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => CirInstruction::And(compile(l, out), compile(r, out)),
+            LogicalExpression::Or(l, r) => CirInstruction::Or(compile(l, out), compile(r, out)),
+            LogicalExpression::Not(e) => CirInstruction::Not(compile(e, out)),
+        },
+        Expression::Predicate(p) => CirInstruction::Predicate(p.clone()),
+        Expression::FieldComparison(c) => CirInstruction::FieldComparison(c.clone()),
+        Expression::Bool(b) => CirInstruction::Bool(*b),
+    };
+
+    out.push(instruction);
+    out.len() - 1
+}
+
+impl Execute for CirProgram {
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        self.eval(self.instructions.len() - 1, ctx, m)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_layout() {
+        let program = CirProgram::from(&parse(r#"a == "x" && !(b == 1 || true)"#).unwrap());
+        let layout: Vec<_> = program
+            .instructions()
+            .iter()
+            .map(|i| match i {
+                CirInstruction::And(l, r) => format!("and {} {}", l, r),
+                CirInstruction::Or(l, r) => format!("or {} {}", l, r),
+                CirInstruction::Not(e) => format!("not {}", e),
+                CirInstruction::Predicate(p) => p.to_string(),
+                CirInstruction::FieldComparison(c) => c.to_string(),
+                CirInstruction::Bool(b) => b.to_string(),
+            })
+            .collect();
+
+        assert_eq!(
+            layout,
+            [
+                r#"(a == "x")"#,
+                "(b == 1)",
+                "true",
+                "or 1 2",
+                "not 3",
+                "and 0 4"
+            ]
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! Expressions compiled ahead of time into closures.
+//!
+//! A [`ClosureProgram`] turns each predicate into a closure specialized for
+//! its operator and operand types when the matcher is added, so evaluating
+//! it no longer dispatches on the operator or unpacks the right hand side
+//! for every value. `&&`, `||` and `!` become closures calling those of
+//! their operands. Predicates with transformations other than `lower()`,
+//! `any()` and `all()`, compacted sets, method bitmasks and field
+//! comparisons are left to the interpreter, see
+//! [`ClosureProgram::interpreted`]. Selected with
+//! [`Engine::Closure`](crate::router::Engine::Closure).
+
+use crate::ast::{
+    BinaryOperator, Expression, LhsTransformations, LogicalExpression, Predicate, Type, Value,
+};
+use crate::context::{CaptureMode, Context, Match};
+use crate::error::EvalError;
+use crate::interpreter::{
+    compare, compare_lowered, fold_literal, held, lower_str, quantified, regex_match, Execute,
+};
+use crate::schema::LowerPolicy;
+use std::borrow::Cow;
+use std::fmt;
+
+type Closure = Box<dyn Fn(&mut Context, &mut Match) -> Result<bool, EvalError> + Send + Sync>;
+
+/// What value tests read from the context, looked up once per evaluation.
+struct Env {
+    capture_mode: CaptureMode,
+    lower_policy: LowerPolicy,
+}
+
+impl Env {
+    /// Whether `lower()` case folds values, and the literals they are
+    /// compared with.
+    fn folds(&self, lower: bool) -> bool {
+        lower && self.lower_policy == LowerPolicy::Fold
+    }
+}
+
+pub struct ClosureProgram {
+    root: Closure,
+    interpreted: usize,
+}
+
+impl ClosureProgram {
+    /// Number of predicates and field comparisons evaluated by the
+    /// interpreter instead of a specialized closure.
+    pub fn interpreted(&self) -> usize {
+        self.interpreted
+    }
+}
+
+impl fmt::Debug for ClosureProgram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClosureProgram")
+            .field("interpreted", &self.interpreted)
+            .finish_non_exhaustive()
+    }
+}
+
+impl From<&Expression> for ClosureProgram {
+    fn from(expr: &Expression) -> Self {
+        let mut interpreted = 0;
+        let root = compile(expr, &mut interpreted);
+        ClosureProgram { root, interpreted }
+    }
+}
+
+impl Execute for ClosureProgram {
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        (self.root)(ctx, m)
+    }
+}
+
+fn compile(expr: &Expression, interpreted: &mut usize) -> Closure {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => {
+                let (l, r) = (compile(l, interpreted), compile(r, interpreted));
+                Box::new(move |ctx, m| Ok(l(ctx, m)? && r(ctx, m)?))
+            }
+            LogicalExpression::Or(l, r) => {
+                let (l, r) = (compile(l, interpreted), compile(r, interpreted));
+                Box::new(move |ctx, m| Ok(l(ctx, m)? || r(ctx, m)?))
+            }
+            LogicalExpression::Not(e) => {
+                let e = compile(e, interpreted);
+                Box::new(move |ctx, m| Ok(!e(ctx, m)?))
+            }
+        },
+        Expression::Predicate(p) => match (specialize(p), p.memo) {
+            (Some(closure), Some(slot)) => {
+                Box::new(move |ctx, m| ctx.memoized(slot, m, |ctx, m| closure(ctx, m)))
+            }
+            (Some(closure), None) => closure,
+            // memoized by the interpreter itself
+            (None, _) => {
+                *interpreted += 1;
+                let p = p.clone();
+                Box::new(move |ctx, m| p.try_execute(ctx, m))
+            }
+        },
+        Expression::FieldComparison(c) => {
+            *interpreted += 1;
+            let c = c.clone();
+            Box::new(move |ctx, m| c.try_execute(ctx, m))
+        }
+        Expression::Bool(b) => {
+            let b = *b;
+            Box::new(move |_, _| Ok(b))
+        }
+    }
+}
+
+/// Evaluates `test` against the values of the field of `p` like
+/// [`Predicate::execute`] does, for the [`Quantifier`] of the field. `test`
+/// records what matched in [`Match::matches`] itself. Fails like the
+/// interpreter unless the values are of type `lhs`, when given.
+fn predicate<T>(p: &Predicate, lhs_type: Option<Type>, test: T) -> Closure
+where
+    T: Fn(&Value, &Env, &mut Match) -> bool + Send + Sync + 'static,
+{
+    let lhs = p.lhs.clone();
+    let field = p.lhs.path().into_owned();
+    let op = p.op;
+    let rhs_type = p.rhs.my_type();
+    let quantifier = p.lhs.quantifier();
+
+    Box::new(move |ctx, m| {
+        ctx.stats.predicates_evaluated += 1;
+        let env = Env {
+            capture_mode: ctx.capture_mode(),
+            lower_policy: ctx.schema().lower_policy(),
+        };
+        let Some(values) = ctx.resolve_lhs(&lhs) else {
+            return Ok(false);
+        };
+        // the values of a field all have its type
+        if lhs_type.is_some_and(|t| t != values[0].my_type()) {
+            return Err(EvalError {
+                op,
+                lhs: values[0].my_type(),
+                rhs: rhs_type,
+            });
+        }
+
+        Ok(
+            match quantified(values, quantifier, |v| Ok(test(v, &env, m)))? {
+                Some(v) => held(m, &field, op, v),
+                None => false,
+            },
+        )
+    })
+}
+
+/// `v`, a string as checked by [`predicate`].
+fn string(v: &Value) -> &str {
+    match v {
+        Value::String(s) => s,
+        _ => unreachable!(),
+    }
+}
+
+/// `lower(v)` if `lower`, `v` otherwise.
+fn lowered<'v>(v: &'v Value, lower: bool, env: &Env) -> Cow<'v, str> {
+    if lower {
+        lower_str(string(v), env.lower_policy)
+    } else {
+        Cow::Borrowed(string(v))
+    }
+}
+
+/// Compiles `p` into a closure, `None` if it is left to the interpreter.
+fn specialize(p: &Predicate) -> Option<Closure> {
+    let (lower, any) = p.lhs.get_transformations();
+    let lower_only = p
+        .lhs
+        .transformations
+        .iter()
+        .all(|t| *t == LhsTransformations::Lower || t.is_quantifier());
+    if !lower_only {
+        return None;
+    }
+
+    let field = p.lhs.path().into_owned();
+    let op = p.op;
+    let rhs = p.rhs.clone();
+
+    Some(match (op, &p.rhs) {
+        (_, Value::Set(_) | Value::Methods(_)) => return None,
+        (
+            BinaryOperator::Equals
+            | BinaryOperator::NotEquals
+            | BinaryOperator::Prefix
+            | BinaryOperator::Postfix
+            | BinaryOperator::Contains,
+            Value::String(r),
+        ) => {
+            let r = r.clone();
+            let folded = match fold_literal(&p.rhs) {
+                Some(Value::String(f)) if lower => f,
+                _ => r.clone(),
+            };
+            let cmp: fn(&str, &str) -> bool = match op {
+                BinaryOperator::Equals => |l, r| l == r,
+                BinaryOperator::NotEquals => |l, r| l != r,
+                BinaryOperator::Prefix => |l, r| l.starts_with(r),
+                BinaryOperator::Postfix => |l, r| l.ends_with(r),
+                _ => |l, r| l.contains(r),
+            };
+            // the matched part of the value, which is the literal
+            let records = matches!(
+                op,
+                BinaryOperator::Equals | BinaryOperator::Prefix | BinaryOperator::Postfix
+            );
+
+            let test = move |v: &Value, env: &Env, m: &mut Match| {
+                let s = string(v);
+                let held = if lower {
+                    let r = if env.folds(lower) { &folded } else { &r };
+                    compare_lowered(&op, s, r, env.lower_policy)
+                        .unwrap_or_else(|| cmp(&lower_str(s, env.lower_policy), r))
+                } else {
+                    cmp(s, &r)
+                };
+                if held && records {
+                    m.matches.insert(field.clone(), rhs.clone());
+                }
+                held
+            };
+
+            if op == BinaryOperator::Equals && any && !lower {
+                return Some(any_equals(p, predicate(p, Some(Type::String), test)));
+            }
+            predicate(p, Some(Type::String), test)
+        }
+        (BinaryOperator::Regex | BinaryOperator::Glob, Value::Regex(re)) => {
+            let re = re.clone();
+            predicate(p, Some(Type::String), move |v, env, m| {
+                // globs have no groups, only the matched value is kept
+                let mode = if op == BinaryOperator::Glob {
+                    CaptureMode::None
+                } else {
+                    env.capture_mode
+                };
+                let re = if env.folds(lower) {
+                    re.ignore_case()
+                } else {
+                    &re
+                };
+                regex_match(re, &lowered(v, lower, env), mode, &field, m)
+            })
+        }
+        (BinaryOperator::In | BinaryOperator::NotIn, Value::List(list)) => {
+            let list = list.clone();
+            let folded = match fold_literal(&p.rhs) {
+                Some(Value::List(f)) if lower => f,
+                _ => list.clone(),
+            };
+            let negated = op == BinaryOperator::NotIn;
+            predicate(p, Some(Type::String), move |v, env, m| {
+                let s = lowered(v, lower, env);
+                let list = if env.folds(lower) { &folded } else { &list };
+                let found = list.binary_search_by(|e| e.as_str().cmp(&*s)).is_ok();
+                if found && !negated {
+                    m.matches
+                        .insert(field.clone(), Value::String(s.into_owned().into()));
+                }
+                found != negated
+            })
+        }
+        // `lower()` only applies to the string operators above
+        _ if lower => return None,
+        (BinaryOperator::Equals, _) => {
+            let test = move |v: &Value, _: &Env, m: &mut Match| {
+                let held = *v == rhs;
+                if held {
+                    m.matches.insert(field.clone(), rhs.clone());
+                }
+                held
+            };
+            if any {
+                return Some(any_equals(p, predicate(p, None, test)));
+            }
+            predicate(p, None, test)
+        }
+        (BinaryOperator::NotEquals, _) => predicate(p, None, move |v, _, _| *v != rhs),
+        (
+            BinaryOperator::Greater
+            | BinaryOperator::GreaterOrEqual
+            | BinaryOperator::Less
+            | BinaryOperator::LessOrEqual,
+            Value::Int(_) | Value::Float(_) | Value::IpAddr(_),
+        ) => {
+            // both sides are of the same ordered type, `compare` can not fail
+            predicate(p, Some(rhs.my_type()), move |v, _, _| {
+                compare(op, v, &rhs) == Ok(true)
+            })
+        }
+        (BinaryOperator::In | BinaryOperator::NotIn, _) => {
+            let negated = op == BinaryOperator::NotIn;
+            let lhs_type = match rhs {
+                Value::IntRange(..) => Type::Int,
+                _ => Type::IpAddr,
+            };
+            let contains: Box<dyn Fn(&Value) -> bool + Send + Sync> = match rhs {
+                Value::IpCidr(c) => {
+                    Box::new(move |v| matches!(v, Value::IpAddr(a) if c.contains(a)))
+                }
+                Value::CidrList(l) => {
+                    Box::new(move |v| matches!(v, Value::IpAddr(a) if l.contains(a)))
+                }
+                Value::IntRange(lo, hi) => {
+                    Box::new(move |v| matches!(v, Value::Int(i) if (lo..=hi).contains(i)))
+                }
+                _ => return None,
+            };
+            predicate(p, Some(lhs_type), move |v, _, _| contains(v) != negated)
+        }
+        (BinaryOperator::Contains, Value::IpAddr(a)) => {
+            let a = *a;
+            predicate(
+                p,
+                Some(Type::IpCidr),
+                move |v, _, _| matches!(v, Value::IpCidr(c) if c.contains(&a)),
+            )
+        }
+        _ => return None,
+    })
+}
+
+/// Answers `any(field) == value` from [`Context::any_value_equals`] on
+/// fields with many values, falling back to `scan`.
+fn any_equals(p: &Predicate, scan: Closure) -> Closure {
+    let lhs = p.lhs.clone();
+    let field = p.lhs.path().into_owned();
+    let rhs = p.rhs.clone();
+
+    Box::new(move |ctx, m| {
+        ctx.resolve_lhs(&lhs);
+        match ctx.any_value_equals(&lhs, &rhs) {
+            // `scan` counts itself
+            Some(true) => {
+                ctx.stats.predicates_evaluated += 1;
+                m.matches.insert(field.clone(), rhs.clone());
+                Ok(held(m, &field, BinaryOperator::Equals, &rhs))
+            }
+            Some(false) => {
+                ctx.stats.predicates_evaluated += 1;
+                Ok(false)
+            }
+            None => scan(ctx, m),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_interpreted() {
+        for (atc, interpreted) in [
+            (r#"a == "x" && !(b == 1 || true)"#, 0),
+            (r#"lower(a) ^= "x" || any(a) ~ "^y""#, 0),
+            (r#"all(lower(a)) ^= "x" || all(a) == "y""#, 0),
+            (r#"trim(a) == "x" || a == c"#, 2),
+            (r#"a == "x" || a == "y""#, 0),
+        ] {
+            let program = ClosureProgram::from(&parse(atc).unwrap());
+            assert_eq!(program.interpreted(), interpreted, "{}", atc);
+        }
+
+        let chain: Vec<_> = (0..crate::compact::COMPACT_MIN_OPERANDS)
+            .map(|i| format!(r#"a == "{}""#, i))
+            .collect();
+        let compacted = parse(&chain.join(" || ")).unwrap().compact();
+        assert_eq!(ClosureProgram::from(&compacted).interpreted(), 1);
+    }
+}
@@ -0,0 +1,301 @@
+//! Compact storage of long `||` chains of string equality.
+//!
+//! Generated routes often list dozens of hosts or paths as
+//! `http.host == "a" || http.host == "b" || ...`. [`Expression::compact`]
+//! replaces such chains with a single predicate whose right hand side is a
+//! [`StringSet`], which takes one lookup to evaluate instead of one
+//! comparison per operand. The compacted predicate still prints, and
+//! [`Expression::expand`]s, back to the original chain.
+
+use crate::ast::{BinaryOperator, Expression, Lhs, LogicalExpression, Predicate, Value};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Chains with fewer operands are left alone.
+pub const COMPACT_MIN_OPERANDS: usize = 8;
+
+/// The right hand sides of a compacted `||` chain, see the
+/// [module documentation](crate::compact).
+///
+/// Serializes as the list of values in their original order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(from = "Vec<String>", into = "Vec<String>")
+)]
+pub struct StringSet {
+    /// In chain order, duplicates included.
+    values: Vec<String>,
+    /// Indices into `values`, sorted by value.
+    sorted: Vec<u32>,
+}
+
+impl StringSet {
+    pub fn new(values: Vec<String>) -> Self {
+        let mut sorted: Vec<u32> = (0..values.len() as u32).collect();
+        sorted.sort_unstable_by(|a, b| values[*a as usize].cmp(&values[*b as usize]));
+
+        StringSet { values, sorted }
+    }
+
+    /// The values in their original order.
+    pub fn values(&self) -> &[String] {
+        &self.values
+    }
+
+    pub fn contains(&self, s: &str) -> bool {
+        self.sorted
+            .binary_search_by(|i| self.values[*i as usize].as_str().cmp(s))
+            .is_ok()
+    }
+}
+
+impl From<Vec<String>> for StringSet {
+    fn from(values: Vec<String>) -> Self {
+        StringSet::new(values)
+    }
+}
+
+impl From<StringSet> for Vec<String> {
+    fn from(set: StringSet) -> Self {
+        set.values
+    }
+}
+
+impl Expression {
+    /// Replaces every left associative `||` chain of at least
+    /// [`COMPACT_MIN_OPERANDS`] `field == "..."` predicates on the same
+    /// field by a single predicate on a [`StringSet`], see the
+    /// [module documentation](crate::compact).
+    ///
+    /// Chains using `lower()` are kept as they are. When a field has several
+    /// values, [`Match::matches`](crate::context::Match::matches) may hold
+    /// another one of them than the chain would have recorded.
+    pub fn compact(self) -> Expression {
+        let mut values = Vec::new();
+        if let Some(lhs) = equality_chain(&self, &mut values) {
+            if values.len() >= COMPACT_MIN_OPERANDS {
+                return Expression::Predicate(Predicate {
+                    lhs: lhs.clone(),
+                    op: BinaryOperator::Equals,
+                    rhs: Value::Set(StringSet::new(
+                        values.into_iter().map(str::to_string).collect(),
+                    )),
+                    memo: None,
+                });
+            }
+        }
+
+        match self {
+            Expression::Logical(l) => Expression::Logical(Box::new(match *l {
+                LogicalExpression::And(l, r) => LogicalExpression::And(l.compact(), r.compact()),
+                LogicalExpression::Or(l, r) => LogicalExpression::Or(l.compact(), r.compact()),
+                LogicalExpression::Not(e) => LogicalExpression::Not(e.compact()),
+            })),
+            e => e,
+        }
+    }
+
+    /// Undoes [`Expression::compact`] and
+    /// [`Expression::compile_methods`], turning predicates on a
+    /// [`StringSet`] back into the `||` chain they were made of.
+    pub fn expand(self) -> Expression {
+        match self {
+            Expression::Logical(l) => Expression::Logical(Box::new(match *l {
+                LogicalExpression::And(l, r) => LogicalExpression::And(l.expand(), r.expand()),
+                LogicalExpression::Or(l, r) => LogicalExpression::Or(l.expand(), r.expand()),
+                LogicalExpression::Not(e) => LogicalExpression::Not(e.expand()),
+            })),
+            Expression::Predicate(Predicate {
+                lhs,
+                op,
+                rhs: Value::Methods(methods),
+                ..
+            }) => Expression::Predicate(Predicate {
+                lhs,
+                op,
+                rhs: methods.into_original(),
+                memo: None,
+            }),
+            Expression::Predicate(Predicate {
+                lhs,
+                op,
+                rhs: Value::Set(set),
+                ..
+            }) => {
+                let mut operands = Vec::from(set).into_iter().map(|v| {
+                    Expression::Predicate(Predicate {
+                        lhs: lhs.clone(),
+                        op,
+                        rhs: Value::String(v.into()),
+                        memo: None,
+                    })
+                });
+                let first = operands.next().expect("sets come from chains");
+                operands.fold(first, |acc, e| {
+                    Expression::Logical(Box::new(LogicalExpression::Or(acc, e)))
+                })
+            }
+            e => e,
+        }
+    }
+}
+
+/// If `e` is a left associative `||` chain of `==` predicates comparing
+/// the same field, without `lower()`, to strings, appends the strings to
+/// `out` in order and returns the field.
+fn equality_chain<'e>(e: &'e Expression, out: &mut Vec<&'e str>) -> Option<&'e Lhs> {
+    match e {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::Or(l, r) => {
+                let lhs = equality_chain(l, out)?;
+                let (r_lhs, value) = equality(r)?;
+                if r_lhs.var_name != lhs.var_name
+                    || r_lhs.key != lhs.key
+                    || r_lhs.transformations != lhs.transformations
+                {
+                    return None;
+                }
+
+                out.push(value);
+                Some(lhs)
+            }
+            _ => None,
+        },
+        e => {
+            let (lhs, value) = equality(e)?;
+            out.push(value);
+            Some(lhs)
+        }
+    }
+}
+
+fn equality(e: &Expression) -> Option<(&Lhs, &str)> {
+    match e {
+        Expression::Predicate(Predicate {
+            lhs,
+            op: BinaryOperator::Equals,
+            rhs: Value::String(s),
+            ..
+        }) if !lhs.is_transformed() => Some((lhs, s)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::context::{Context, Match};
+    use crate::interpreter::Execute;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    fn chain(field: &str, n: usize) -> String {
+        (0..n)
+            .map(|i| format!(r#"{} == "v{}""#, field, (i * 7) % n))
+            .collect::<Vec<_>>()
+            .join(" || ")
+    }
+
+    fn is_set(e: &Expression) -> bool {
+        matches!(
+            e,
+            Expression::Predicate(Predicate {
+                rhs: Value::Set(_),
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn test_compact() {
+        let atc = chain("a", COMPACT_MIN_OPERANDS);
+        let original = parse(&atc).unwrap();
+        let compacted = original.clone().compact();
+        assert!(is_set(&compacted));
+        assert_eq!(compacted.to_string(), original.to_string());
+        assert_eq!(compacted.clone().expand().to_string(), original.to_string());
+        assert_eq!(
+            parse(&compacted.to_string()).unwrap().to_string(),
+            atc_string(&atc)
+        );
+
+        // too short, mixed fields, lower() or not left associative
+        for atc in [
+            chain("a", COMPACT_MIN_OPERANDS - 1),
+            format!(r#"{} || b == "x""#, chain("a", 4)) + &format!(" || {}", chain("a", 4)),
+            chain("lower(a)", COMPACT_MIN_OPERANDS),
+            format!("a == \"x\" || ({})", chain("a", COMPACT_MIN_OPERANDS)),
+        ] {
+            let e = parse(&atc).unwrap().compact();
+            assert!(!is_set(&e), "{}", atc);
+            assert_eq!(e.to_string(), atc_string(&atc));
+        }
+
+        // chains inside other expressions
+        let atc = format!(
+            "b == \"x\" && !({}) || ({})",
+            chain("any(a)", 10),
+            chain("a", 9)
+        );
+        let e = parse(&atc).unwrap().compact();
+        assert_eq!(e.to_string(), atc_string(&atc));
+        assert_eq!(e.complexity(), 6);
+    }
+
+    fn atc_string(atc: &str) -> String {
+        parse(atc).unwrap().to_string()
+    }
+
+    /// Compacted chains match exactly the same contexts.
+    #[test]
+    fn test_equivalence() {
+        let mut schema = Schema::default();
+        schema.add_field("a", Type::String);
+
+        let n = COMPACT_MIN_OPERANDS + 2;
+        let exprs: Vec<_> = [chain("a", n), chain("any(a)", n)]
+            .iter()
+            .map(|atc| {
+                let e = parse(atc).unwrap();
+                (e.clone().compact(), e)
+            })
+            .collect();
+
+        let values: &[&[&str]] = &[
+            &[],
+            &["v1"],
+            &["x"],
+            &["v1", "v1"],
+            &["v1", "v2"],
+            &["v1", "x"],
+            &["x", "v3"],
+        ];
+        for values in values {
+            let mut ctx = Context::new(&schema);
+            for v in *values {
+                ctx.add_value_str("a", v);
+            }
+
+            for (compacted, original) in &exprs {
+                assert!(is_set(compacted));
+                let mut m1 = Match::new();
+                let mut m2 = Match::new();
+                let matched = compacted.execute(&mut ctx, &mut m1);
+                assert_eq!(
+                    matched,
+                    original.execute(&mut ctx, &mut m2),
+                    "{} {:?}",
+                    original,
+                    values
+                );
+                // a failed chain may leave the match of an earlier operand
+                if matched {
+                    assert_eq!(m1.matches, m2.matches);
+                }
+            }
+        }
+    }
+}
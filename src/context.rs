@@ -1,21 +1,76 @@
-use crate::ast::Value;
+use crate::ast::{BinaryOperator, Lhs, Str, Type, Value};
+use crate::corpus::Rng;
+use crate::error::EvalError;
+use crate::method::method_bit;
 use crate::schema::Schema;
-use fnv::FnvHashMap;
+use crate::small_map::SmallMap;
+use cidr::IpCidr;
+use fnv::{FnvHashMap, FnvHashSet};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
 use uuid::Uuid;
 
+const ARBITRARY_STRINGS: &[&str] = &[
+    "",
+    "/",
+    "/foo",
+    "/foo/bar",
+    "/FOO/",
+    "GET",
+    "POST",
+    "http",
+    "https",
+    "example.com",
+    "Ünïcödé",
+    "你好",
+];
+
+/// A predicate that held while matching, see [`Match::evidence`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchEvidence {
+    pub field: String,
+    pub op: BinaryOperator,
+    /// The value of `field` that satisfied the predicate, as it was added
+    /// to the context. Without `any()` every value did, this is the first.
+    pub value: Value,
+}
+
 pub struct Match {
     pub uuid: Uuid,
-    pub matches: FnvHashMap<String, Value>,
-    pub captures: FnvHashMap<String, String>,
+    pub matches: SmallMap<Value>,
+    pub captures: SmallMap<String>,
+    /// Every predicate and field comparison that held, in evaluation
+    /// order, including several on the same field. Like [`Match::matches`]
+    /// it may include predicates of `||` branches that did not match in
+    /// the end.
+    pub evidence: Vec<MatchEvidence>,
+    /// Label of the priority band the matcher belongs to, see
+    /// [`Router::add_priority_band`](crate::router::Router::add_priority_band).
+    pub band: Option<Arc<str>>,
 }
 
 impl Match {
     pub fn new() -> Self {
         Match {
             uuid: Uuid::default(),
-            matches: FnvHashMap::default(),
-            captures: FnvHashMap::default(),
+            matches: SmallMap::new(),
+            captures: SmallMap::new(),
+            evidence: Vec::new(),
+            band: None,
+        }
+    }
+
+    /// Records what evaluating a predicate into `effects` recorded, as if
+    /// it had been evaluated into `self`.
+    fn replay(&mut self, effects: &Match) {
+        for (field, value) in &effects.matches {
+            self.matches.insert(field.clone(), value.clone());
+        }
+        for (group, text) in &effects.captures {
+            self.captures.insert(group.clone(), text.clone());
         }
+        self.evidence.extend(effects.evidence.iter().cloned());
     }
 }
 
@@ -25,38 +80,923 @@ impl Default for Match {
     }
 }
 
+/// Counters describing the work done by [`Router::execute`](crate::router::Router::execute).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExecutionStats {
+    /// Matchers whose expression was evaluated.
+    pub matchers_evaluated: usize,
+    /// Matchers skipped because a field they require has no value.
+    pub matchers_skipped: usize,
+    /// Of `matchers_skipped`, those requiring the field of the router's
+    /// [prefilter](crate::router::Router::enable_prefilter) when it has no
+    /// value.
+    pub matchers_missing_prefilter_field: usize,
+    /// Matchers skipped because the router's prefilter or regex index ruled
+    /// them out.
+    pub matchers_prefiltered: usize,
+    /// Matchers skipped because they are quarantined, see
+    /// [`Router::set_quarantine_after`](crate::router::Router::set_quarantine_after).
+    pub matchers_quarantined: usize,
+    /// Matchers whose evaluation failed, they are counted as evaluated.
+    pub eval_errors: usize,
+    /// Predicates answered from the memo table because an identical
+    /// predicate of another matcher was already evaluated, see
+    /// [`Predicate::memo`](crate::ast::Predicate::memo).
+    pub predicates_memoized: usize,
+    /// Predicates and field comparisons evaluated, not counting those
+    /// answered from the memo table.
+    pub predicates_evaluated: usize,
+}
+
+/// Which regex capture groups are copied to [`Match::captures`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureMode {
+    /// Every group, by index, and named groups by name as well.
+    #[default]
+    All,
+    /// Named groups only.
+    NamedOnly,
+    /// No groups. Regexes are only searched for the matched text, which is
+    /// still stored in [`Match::matches`].
+    None,
+}
+
+impl CaptureMode {
+    /// The mode capturing only what both `self` and `other` capture.
+    pub fn restrict(self, other: CaptureMode) -> CaptureMode {
+        match (self, other) {
+            (CaptureMode::None, _) | (_, CaptureMode::None) => CaptureMode::None,
+            (CaptureMode::NamedOnly, _) | (_, CaptureMode::NamedOnly) => CaptureMode::NamedOnly,
+            (CaptureMode::All, CaptureMode::All) => CaptureMode::All,
+        }
+    }
+}
+
+/// Fields with fewer values than this are scanned linearly by
+/// [`Context::any_value_equals`], building a hash set is not worth it.
+const INDEX_MIN_VALUES: usize = 8;
+
+/// Hash sets of the values of one field, see [`Context::any_value_equals`].
+#[derive(Default)]
+struct ValueIndex {
+    strings: FnvHashSet<String>,
+    ints: FnvHashSet<i64>,
+}
+
+/// Set of [field ids](Schema::field_id), one bit each.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct FieldSet(Vec<u64>);
+
+impl FieldSet {
+    pub(crate) fn insert(&mut self, id: usize) {
+        let (word, bit) = (id / 64, id % 64);
+        if self.0.len() <= word {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= 1 << bit;
+    }
+
+    pub(crate) fn contains(&self, id: usize) -> bool {
+        self.word(id / 64) & 1 << (id % 64) != 0
+    }
+
+    fn word(&self, i: usize) -> u64 {
+        self.0.get(i).copied().unwrap_or(0)
+    }
+
+    /// Empties the set, keeping its words.
+    fn clear(&mut self) {
+        self.0.fill(0);
+    }
+}
+
+/// Supplies the value of a field on first use, see [`Context::set_provider`].
+type Provider = Box<dyn Fn(&str) -> Option<Value> + Send>;
+
+/// The values a [`Router`](crate::router::Router) is executed against, and
+/// the result of the execution.
+///
+/// A context is used by one thread at a time, but can be sent to another
+/// one: keep one per thread, or per worker, and [`Context::reset`] it
+/// between requests instead of building a new one each time.
 pub struct Context<'a> {
     schema: &'a Schema,
-    values: FnvHashMap<String, Vec<Value>>,
+    /// Values of the fields declared in the schema, by
+    /// [id](Schema::field_id). Fields without values have an empty list.
+    values: Vec<Vec<Value>>,
+    /// Values of the fields only matched by a wildcard field, such as
+    /// `http.headers.host`, which have no id.
+    wildcard_values: FnvHashMap<String, Vec<Value>>,
+    /// Entries of the [`Type::Map`] fields, by field and key. Fields keep
+    /// their empty map across resets.
+    maps: FnvHashMap<String, FnvHashMap<String, Vec<Value>>>,
+    /// Built lazily, entries are dropped whenever their field changes.
+    index: FnvHashMap<String, ValueIndex>,
     pub result: Option<Match>,
+    pub stats: ExecutionStats,
+    capture_mode: CaptureMode,
+    provider: Option<Provider>,
+    /// Fields the provider was already asked for.
+    provided: FnvHashSet<String>,
+    /// Declared fields with a value, or an entry for map fields, so
+    /// [`Context::has_fields`] does not look them up by name.
+    present: FieldSet,
+    /// Declared fields of `provided`.
+    provided_ids: FieldSet,
+    /// [`method_bit`] of the value of the schema's method field, `None`
+    /// unless it has exactly one value.
+    method: Option<u16>,
+    /// Outcome of the predicates evaluated by the router execution in
+    /// progress, by [memo slot](crate::ast::Predicate::memo). Entries of
+    /// another generation are stale.
+    memo: Vec<Option<MemoEntry>>,
+    memo_generation: u64,
+    /// Predicates evaluated outside of [`Context::with_memo`] are never
+    /// memoized, as their slots may belong to another router.
+    memo_active: bool,
+}
+
+/// Outcome of a predicate, see [`Context::memoized`].
+struct MemoEntry {
+    generation: u64,
+    capture_mode: CaptureMode,
+    held: bool,
+    /// What the predicate recorded, replayed on every hit.
+    effects: Match,
 }
 
+// Contexts are kept per thread, and may be built on another one.
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<Context<'static>>();
+};
+
 impl<'a> Context<'a> {
     pub fn new(schema: &'a Schema) -> Self {
         Context {
             schema,
-            values: FnvHashMap::with_hasher(Default::default()),
+            values: (0..schema.field_id_bound()).map(|_| Vec::new()).collect(),
+            wildcard_values: FnvHashMap::default(),
+            maps: FnvHashMap::default(),
+            index: FnvHashMap::default(),
             result: None,
+            stats: ExecutionStats::default(),
+            capture_mode: CaptureMode::All,
+            provider: None,
+            provided: FnvHashSet::default(),
+            present: FieldSet::default(),
+            provided_ids: FieldSet::default(),
+            method: None,
+            memo: Vec::new(),
+            memo_generation: 0,
+            memo_active: false,
         }
     }
 
+    pub fn capture_mode(&self) -> CaptureMode {
+        self.capture_mode
+    }
+
+    /// Selects which regex capture groups matching populates, the mode is
+    /// kept across [`Context::reset`].
+    pub fn set_capture_mode(&mut self, mode: CaptureMode) {
+        self.capture_mode = mode;
+    }
+
+    /// Shorthand for `set_capture_mode(CaptureMode::None)`, for callers that
+    /// never read [`Match::captures`].
+    pub fn disable_captures(&mut self) {
+        self.set_capture_mode(CaptureMode::None);
+    }
+
     pub fn add_value(&mut self, field: &str, value: Value) {
         if &value.my_type() != self.schema.type_of(field).unwrap() {
             panic!("value provided does not match schema");
         }
 
-        self.values
-            .entry(field.to_string())
-            .or_default()
-            .push(value);
+        self.push_value(field, value);
+    }
+
+    /// Adds a `String` value, the same as
+    /// `add_value(field, Value::String(value.into()))`.
+    pub fn add_value_str(&mut self, field: &str, value: &str) {
+        self.expect_type(field, Type::String);
+        self.push_value(field, Value::String(value.into()));
+    }
+
+    /// Adds a `String` value without copying it, unlike
+    /// [`Context::add_value_str`]: the context borrows `value` for as long
+    /// as it holds it. Matches and captures still copy the values they
+    /// record.
+    pub fn add_value_ref(&mut self, field: &str, value: &'a str) {
+        self.expect_type(field, Type::String);
+        // SAFETY: `value` outlives the context, and values only ever leave
+        // it by reference or cloned
+        self.push_value(field, Value::String(unsafe { Str::borrowed(value) }));
+    }
+
+    /// Adds an `Int` value, the same as `add_value(field, Value::Int(value))`.
+    pub fn add_value_int(&mut self, field: &str, value: i64) {
+        self.expect_type(field, Type::Int);
+        self.push_value(field, Value::Int(value));
+    }
+
+    /// Adds `value` to the entry `key` of the [`Type::Map`] field `field`,
+    /// which `field["key"]` reads. Keys are compared as they are, hosts
+    /// should lower-case header names like routes do.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `field` is not a map field of the schema.
+    pub fn add_map_value(&mut self, field: &str, key: &str, value: &str) {
+        self.expect_type(field, Type::Map);
+        // values may be provided while a router executes
+        self.memo_generation += 1;
+
+        if let Some(id) = self.schema.field_id(field) {
+            self.present.insert(id);
+        }
+        if !self.maps.contains_key(field) {
+            self.maps.insert(field.to_string(), FnvHashMap::default());
+        }
+        let value = Value::String(value.into());
+        let map = self.maps.get_mut(field).unwrap();
+        match map.get_mut(key) {
+            Some(values) => values.push(value),
+            None => {
+                map.insert(key.to_string(), vec![value]);
+            }
+        }
+    }
+
+    fn expect_type(&self, field: &str, typ: Type) {
+        if self.schema.type_of(field).unwrap() != &typ {
+            panic!("value provided does not match schema");
+        }
+    }
+
+    fn push_value(&mut self, field: &str, value: Value) {
+        // values may be provided while a router executes
+        self.memo_generation += 1;
+        self.index.remove(field);
+        if self.schema.method_field() == Some(field) {
+            self.method = match (&value, self.value_of(field)) {
+                (Value::String(s), None) => Some(method_bit(s)),
+                _ => None,
+            };
+        }
+
+        if let Some(id) = self.schema.field_id(field) {
+            self.values[id].push(value);
+            self.present.insert(id);
+            return;
+        }
+
+        // only allocate the field name for the first value of a field
+        match self.wildcard_values.get_mut(field) {
+            Some(values) => values.push(value),
+            None => {
+                self.wildcard_values.insert(field.to_string(), vec![value]);
+            }
+        }
+    }
+
+    /// Creates a context holding random, type-correct values for the fields
+    /// of `schema`.
+    ///
+    /// Every field gets between zero and two values so missing and
+    /// multi-valued fields are covered as well, and wildcard fields
+    /// (`http.headers.*`) are given a random concrete name. Fields of type
+    /// `Regex`, `List`, `IntRange`, `CidrList` or `Map` never receive values. The
+    /// same `rng` state always produces the same context.
+    ///
+    /// IPs are drawn from the whole address space, see
+    /// [`Context::arbitrary_with`] to aim them at the CIDRs of expressions.
+    pub fn arbitrary_for(schema: &'a Schema, rng: &mut Rng) -> Self {
+        Self::arbitrary_with(schema, rng, &[])
+    }
+
+    /// Like [`Context::arbitrary_for`], with half of the IPs and CIDRs
+    /// drawn from inside one of `cidrs`, so predicates such as
+    /// `net.src.ip in 10.0.0.0/8` see both matching and non-matching IPs.
+    /// Take `cidrs` from the expressions under test with
+    /// [`Expression::cidr_literals`](crate::ast::Expression::cidr_literals).
+    pub fn arbitrary_with(schema: &'a Schema, rng: &mut Rng, cidrs: &[IpCidr]) -> Self {
+        let mut ctx = Context::new(schema);
+
+        let mut fields: Vec<_> = schema.fields().collect();
+        // schema fields are kept in a HashMap, sort for reproducibility
+        fields.sort_unstable_by_key(|(name, _)| *name);
+
+        for (name, typ) in fields {
+            let name = match name.strip_suffix('*') {
+                Some(prefix) => format!("{}{}", prefix, arbitrary_name(rng)),
+                None => name.to_string(),
+            };
+
+            for _ in 0..rng.below(3) {
+                if let Some(value) = arbitrary_value(typ, rng, cidrs) {
+                    ctx.add_value(&name, value);
+                }
+            }
+        }
+
+        ctx
+    }
+
+    pub fn schema(&self) -> &'a Schema {
+        self.schema
     }
 
     pub fn value_of(&self, field: &str) -> Option<&[Value]> {
-        self.values.get(field).map(|v| v.as_slice())
+        match self.schema.field_id(field) {
+            Some(id) => self.value_of_id(id),
+            None => self.wildcard_values.get(field).map(|v| v.as_slice()),
+        }
+    }
+
+    /// The values of the entry `key` of the [`Type::Map`] field `field`.
+    pub fn map_value_of(&self, field: &str, key: &str) -> Option<&[Value]> {
+        self.maps.get(field)?.get(key).map(|v| v.as_slice())
+    }
+
+    /// Whether `field` has a value, or an entry if it is a map field.
+    pub(crate) fn has_values(&self, field: &str) -> bool {
+        self.value_of(field).is_some() || self.maps.get(field).is_some_and(|m| !m.is_empty())
+    }
+
+    /// The values of the declared field with the id `id`, see
+    /// [`Schema::field_id`].
+    pub fn value_of_id(&self, id: usize) -> Option<&[Value]> {
+        self.values
+            .get(id)
+            .filter(|v| !v.is_empty())
+            .map(|v| v.as_slice())
+    }
+
+    /// The values of the field of `lhs`, read by id when it was interned,
+    /// see [`Expression::intern_fields`](crate::ast::Expression::intern_fields),
+    /// or of the map entry it looks up.
+    pub(crate) fn value_of_lhs(&self, lhs: &Lhs) -> Option<&[Value]> {
+        if let Some(key) = &lhs.key {
+            return self.map_value_of(&lhs.var_name, key);
+        }
+
+        match lhs.var_index {
+            // the name check guards against expressions interned with
+            // another schema, and costs less than hashing the name
+            Some(id) if self.schema.field_name(id) == Some(lhs.var_name.as_str()) => {
+                self.value_of_id(id)
+            }
+            _ => self.value_of(&lhs.var_name),
+        }
+    }
+
+    /// Every field with at least one value, in no particular order.
+    pub(crate) fn fields(&self) -> impl Iterator<Item = (&str, &[Value])> {
+        let declared = self
+            .values
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| !v.is_empty())
+            .map(|(id, v)| (self.schema.field_name(id).unwrap(), v.as_slice()));
+        let wildcards = self
+            .wildcard_values
+            .iter()
+            .map(|(f, v)| (f.as_str(), v.as_slice()));
+
+        declared.chain(wildcards)
+    }
+
+    /// Lets `provider` supply the value of fields that have none when they
+    /// are first read by a matcher, so hosts only compute the values that
+    /// are actually needed.
+    ///
+    /// The provider is asked at most once per field until
+    /// [`Context::reset`], and never for fields that already have values.
+    /// It is kept across resets. Map fields are never provided.
+    ///
+    /// # Panics
+    ///
+    /// Reading the field panics if the provided value does not match the
+    /// schema, like [`Context::add_value`].
+    pub fn set_provider(&mut self, provider: impl Fn(&str) -> Option<Value> + Send + 'static) {
+        self.provider = Some(Box::new(provider));
+    }
+
+    /// Removes the provider set by [`Context::set_provider`].
+    pub fn clear_provider(&mut self) {
+        self.provider = None;
+    }
+
+    /// Like [`Context::value_of`], asking the provider first if `field` has
+    /// no value yet, see [`Context::set_provider`].
+    pub fn resolve(&mut self, field: &str) -> Option<&[Value]> {
+        if self.value_of(field).is_none() && self.may_provide(field) {
+            self.provided.insert(field.to_string());
+            if let Some(id) = self.schema.field_id(field) {
+                self.provided_ids.insert(id);
+            }
+            let value = self.provider.as_ref().and_then(|p| p(field));
+            if let Some(value) = value {
+                self.add_value(field, value);
+            }
+        }
+
+        self.value_of(field)
+    }
+
+    /// Like [`Context::resolve`], reading the values like
+    /// [`Context::value_of_lhs`].
+    pub(crate) fn resolve_lhs(&mut self, lhs: &Lhs) -> Option<&[Value]> {
+        if self.provider.is_some() && lhs.key.is_none() && self.value_of_lhs(lhs).is_none() {
+            self.resolve(&lhs.var_name);
+        }
+
+        self.value_of_lhs(lhs)
+    }
+
+    /// Whether [`Context::resolve`] would ask the provider for `field`.
+    pub(crate) fn may_provide(&self, field: &str) -> bool {
+        self.provider.is_some() && !self.provided.contains(field)
+    }
+
+    /// Whether every field of `fields` has values, or may get some from the
+    /// provider, as [`Context::has_values`] and [`Context::may_provide`]
+    /// tell field by field.
+    pub(crate) fn has_fields(&self, fields: &FieldSet) -> bool {
+        fields.0.iter().enumerate().all(|(i, w)| {
+            let missing = w & !self.present.word(i);
+            match self.provider {
+                Some(_) => missing & self.provided_ids.word(i) == 0,
+                None => missing == 0,
+            }
+        })
+    }
+
+    /// Whether any value of `field` equals `rhs`, answered from a hash set
+    /// built on first use.
+    ///
+    /// Returns `None` when the field has too few values for an index to pay
+    /// off, `rhs` is neither a string nor an integer or `lhs` looks a map
+    /// entry up, the caller should then scan [`Context::value_of`] itself.
+    pub(crate) fn any_value_equals(&mut self, lhs: &Lhs, rhs: &Value) -> Option<bool> {
+        if lhs.key.is_some() || !matches!(rhs, Value::String(_) | Value::Int(_)) {
+            return None;
+        }
+
+        // checked first so fields with few values are never hashed
+        let values = self.value_of_lhs(lhs)?;
+        if values.len() < INDEX_MIN_VALUES {
+            return None;
+        }
+
+        let field = lhs.var_name.as_str();
+        if !self.index.contains_key(field) {
+            let mut index = ValueIndex::default();
+            for v in self.value_of_lhs(lhs).unwrap() {
+                match v {
+                    Value::String(s) => {
+                        index.strings.insert(s.to_string());
+                    }
+                    Value::Int(i) => {
+                        index.ints.insert(*i);
+                    }
+                    _ => {}
+                }
+            }
+            self.index.insert(field.to_string(), index);
+        }
+
+        let index = &self.index[field];
+        Some(match rhs {
+            Value::String(s) => index.strings.contains(s.as_str()),
+            Value::Int(i) => index.ints.contains(i),
+            _ => unreachable!(),
+        })
+    }
+
+    /// The [`method_bit`] of the single value of the schema's
+    /// [method field](Schema::method_field), `None` if it has none or
+    /// several.
+    pub(crate) fn method_bit(&self) -> Option<u16> {
+        self.method
+    }
+
+    /// Runs `f`, a router execution, memoizing the predicates it evaluates
+    /// from scratch.
+    pub(crate) fn with_memo<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        self.memo_generation += 1;
+        let active = mem::replace(&mut self.memo_active, true);
+        let result = f(self);
+        self.memo_active = active;
+
+        result
+    }
+
+    /// Evaluates the predicate with the memo slot `slot` with `eval`, or
+    /// replays its outcome if an identical predicate was already evaluated
+    /// in the same capture mode since the values last changed. Failed
+    /// evaluations are not memoized.
+    pub(crate) fn memoized(
+        &mut self,
+        slot: usize,
+        m: &mut Match,
+        eval: impl FnOnce(&mut Self, &mut Match) -> Result<bool, EvalError>,
+    ) -> Result<bool, EvalError> {
+        if !self.memo_active {
+            return eval(self, m);
+        }
+
+        let fresh = matches!(
+            self.memo.get(slot),
+            Some(Some(e)) if e.generation == self.memo_generation
+                && e.capture_mode == self.capture_mode
+        );
+        if fresh {
+            self.stats.predicates_memoized += 1;
+        } else {
+            let mut effects = Match::new();
+            let held = eval(self, &mut effects)?;
+            if self.memo.len() <= slot {
+                self.memo.resize_with(slot + 1, || None);
+            }
+            self.memo[slot] = Some(MemoEntry {
+                generation: self.memo_generation,
+                capture_mode: self.capture_mode,
+                held,
+                effects,
+            });
+        }
+
+        let entry = self.memo[slot].as_ref().unwrap();
+        m.replay(&entry.effects);
+        Ok(entry.held)
     }
 
     pub fn reset(&mut self) {
-        self.values.clear();
+        self.wildcard_values.clear();
+        for values in &mut self.values {
+            values.clear();
+        }
+        for map in self.maps.values_mut() {
+            map.clear();
+        }
+        self.index.clear();
+        self.provided.clear();
+        self.present.clear();
+        self.provided_ids.clear();
+        self.method = None;
         self.result = None;
+        self.stats = ExecutionStats::default();
+    }
+}
+
+/// Returns a name usable as the last component of a field.
+fn arbitrary_name(rng: &mut Rng) -> String {
+    if rng.chance(0.3) {
+        return "x_api".to_string();
+    }
+
+    (0..1 + rng.below(8))
+        .map(|_| (b'a' + rng.below(26) as u8) as char)
+        .collect()
+}
+
+fn arbitrary_string(rng: &mut Rng) -> String {
+    if rng.chance(0.5) {
+        return rng.pick(ARBITRARY_STRINGS).to_string();
+    }
+
+    (0..rng.below(12))
+        .map(|_| (b'a' + rng.below(26) as u8) as char)
+        .collect()
+}
+
+fn arbitrary_ip(rng: &mut Rng, cidrs: &[IpCidr]) -> IpAddr {
+    let bits = rng.next_u64() as u128 | (rng.next_u64() as u128) << 64;
+
+    if !cidrs.is_empty() && rng.chance(0.5) {
+        let cidr = rng.pick(cidrs);
+        return mask_ip(cidr.first_address(), cidr.network_length(), bits);
+    }
+
+    if rng.chance(0.5) {
+        IpAddr::V4(Ipv4Addr::from(bits as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(bits))
+    }
+}
+
+/// Keeps the first `len` bits of `network` and takes the rest from `bits`.
+fn mask_ip(network: IpAddr, len: u8, bits: u128) -> IpAddr {
+    match network {
+        IpAddr::V4(addr) => {
+            let host = u32::MAX.checked_shr(len as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(addr) & !host | bits as u32 & host))
+        }
+        IpAddr::V6(addr) => {
+            let host = u128::MAX.checked_shr(len as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(addr) & !host | bits & host))
+        }
+    }
+}
+
+fn arbitrary_value(typ: &Type, rng: &mut Rng, cidrs: &[IpCidr]) -> Option<Value> {
+    Some(match typ {
+        Type::String => Value::String(arbitrary_string(rng).into()),
+        Type::Int => Value::Int(match rng.below(4) {
+            0 => rng.below(65536) as i64,
+            1 => -(rng.below(1000) as i64),
+            2 => *rng.pick(&[0, 80, 443, i64::MIN, i64::MAX]),
+            _ => rng.next_u64() as i64,
+        }),
+        Type::Float => Value::Float(match rng.below(3) {
+            0 => rng.below(1000) as f64 / 4.0,
+            1 => *rng.pick(&[0.0, -0.0, 12.5, f64::MIN, f64::MAX]),
+            _ => f64::from_bits(rng.next_u64()),
+        }),
+        Type::IpAddr => Value::IpAddr(arbitrary_ip(rng, cidrs)),
+        Type::IpCidr => {
+            let ip = arbitrary_ip(rng, cidrs);
+            let len = rng.below(if ip.is_ipv4() { 33 } else { 129 }) as u8;
+            Value::IpCidr(IpCidr::new(mask_ip(ip, len, 0), len).unwrap())
+        }
+        Type::Regex | Type::List | Type::IntRange | Type::CidrList | Type::Map => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_value_equals() {
+        let mut schema = Schema::default();
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.port", Type::Int);
+        let lhs = |name: &str| Lhs {
+            var_name: name.to_string(),
+            transformations: vec![],
+            var_index: schema.field_id(name),
+            key: None,
+        };
+
+        let mut ctx = Context::new(&schema);
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"x".to_string().into()),
+            None
+        );
+
+        // too few values to be worth indexing
+        ctx.add_value("http.headers.a", "x".to_string().into());
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"x".to_string().into()),
+            None
+        );
+
+        for i in 0..INDEX_MIN_VALUES {
+            ctx.add_value("http.headers.a", i.to_string().into());
+            ctx.add_value("net.port", Value::Int(i as i64));
+        }
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"x".to_string().into()),
+            Some(true)
+        );
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"y".to_string().into()),
+            Some(false)
+        );
+        assert_eq!(
+            ctx.any_value_equals(&lhs("net.port"), &Value::Int(3)),
+            Some(true)
+        );
+        assert_eq!(
+            ctx.any_value_equals(&lhs("net.port"), &Value::Int(-3)),
+            Some(false)
+        );
+
+        // the index follows new values
+        ctx.add_value("http.headers.a", "y".to_string().into());
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"y".to_string().into()),
+            Some(true)
+        );
+
+        ctx.reset();
+        assert_eq!(
+            ctx.any_value_equals(&lhs("http.headers.a"), &"y".to_string().into()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_typed_setters() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/a");
+        ctx.add_value("http.path", "/b".to_string().into());
+        ctx.add_value_int("net.port", 80);
+
+        assert_eq!(
+            ctx.value_of("http.path").unwrap(),
+            [Value::String("/a".into()), Value::String("/b".into())]
+        );
+        assert_eq!(ctx.value_of("net.port").unwrap(), [Value::Int(80)]);
+    }
+
+    #[test]
+    fn test_add_value_ref() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+
+        let mut router = crate::router::Router::new(&schema);
+        router
+            .add_matcher(
+                0,
+                uuid::Uuid::default(),
+                r#"http.path ^= "/users/" && http.headers.x_user == "1""#,
+            )
+            .unwrap();
+
+        let path = String::from("/users/1");
+        let user = String::from("1");
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_ref("http.path", &path);
+        ctx.add_value_ref("http.headers.x_user", &user);
+
+        let Value::String(s) = &ctx.value_of("http.path").unwrap()[0] else {
+            unreachable!()
+        };
+        assert!(s.is_borrowed());
+        assert_eq!(s.as_ptr(), path.as_ptr());
+        assert!(!s.clone().is_borrowed());
+
+        assert!(router.execute(&mut ctx));
+
+        ctx.reset();
+        assert_eq!(ctx.value_of("http.path"), None);
+    }
+
+    #[test]
+    fn test_value_of_lhs() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/a");
+        ctx.add_value_str("http.headers.host", "a.com");
+        let id = schema.field_id("http.path").unwrap();
+        assert_eq!(ctx.value_of_id(id).unwrap(), [Value::String("/a".into())]);
+
+        let lhs = |name: &str, var_index| Lhs {
+            var_name: name.to_string(),
+            transformations: vec![],
+            var_index,
+            key: None,
+        };
+        assert_eq!(
+            ctx.value_of_lhs(&lhs("http.path", Some(id))).unwrap(),
+            [Value::String("/a".into())]
+        );
+        assert_eq!(
+            ctx.value_of_lhs(&lhs("http.headers.host", None)).unwrap(),
+            [Value::String("a.com".into())]
+        );
+        // ids of another schema fall back to the name
+        assert_eq!(
+            ctx.value_of_lhs(&lhs("http.headers.host", Some(id)))
+                .unwrap(),
+            [Value::String("a.com".into())]
+        );
+        assert!(ctx.value_of_lhs(&lhs("http.path", Some(7))).is_some());
+    }
+
+    #[test]
+    fn test_map_values() {
+        let mut schema = Schema::default();
+        schema.add_field("http.headers", Type::Map);
+        let lhs = |key: &str| Lhs {
+            var_name: "http.headers".to_string(),
+            transformations: vec![],
+            var_index: schema.field_id("http.headers"),
+            key: Some(key.to_string()),
+        };
+
+        let mut ctx = Context::new(&schema);
+        assert!(!ctx.has_values("http.headers"));
+        ctx.add_map_value("http.headers", "a", "1");
+        ctx.add_map_value("http.headers", "a", "2");
+        ctx.add_map_value("http.headers", "b", "3");
+        assert!(ctx.has_values("http.headers"));
+        // the map itself has no value
+        assert!(ctx.value_of("http.headers").is_none());
+        assert_eq!(
+            ctx.map_value_of("http.headers", "a").unwrap(),
+            [Value::String("1".into()), Value::String("2".into())]
+        );
+        assert_eq!(
+            ctx.value_of_lhs(&lhs("b")).unwrap(),
+            [Value::String("3".into())]
+        );
+        assert!(ctx.value_of_lhs(&lhs("A")).is_none());
+
+        ctx.reset();
+        assert!(!ctx.has_values("http.headers"));
+        assert!(ctx.map_value_of("http.headers", "a").is_none());
+    }
+
+    #[test]
+    fn test_arbitrary_with() {
+        let mut schema = Schema::default();
+        schema.add_field("net.src.ip", Type::IpAddr);
+        let expr = crate::parser::parse("net.src.ip in (10.0.0.0/8, fd00::/8)").unwrap();
+        let cidrs = expr.cidr_literals();
+        assert_eq!(cidrs.len(), 2);
+
+        let mut rng = Rng::new(1);
+        let (mut inside, mut outside) = (0, 0);
+        for _ in 0..200 {
+            let ctx = Context::arbitrary_with(&schema, &mut rng, &cidrs);
+            for value in ctx.value_of("net.src.ip").unwrap_or_default() {
+                let Value::IpAddr(ip) = value else {
+                    unreachable!()
+                };
+                match cidrs.iter().any(|c| c.contains(ip)) {
+                    true => inside += 1,
+                    false => outside += 1,
+                }
+            }
+        }
+        assert!(inside > 50 && outside > 50, "{} {}", inside, outside);
+    }
+
+    #[test]
+    fn test_has_fields() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers", Type::Map);
+        schema.add_field("net.port", Type::Int);
+        // ids beyond the first word
+        for i in 0..100 {
+            schema.add_field(&format!("f{}", i), Type::Int);
+        }
+        let set = |fields: &[&str]| {
+            let mut set = FieldSet::default();
+            for f in fields {
+                set.insert(schema.field_id(f).unwrap());
+            }
+            set
+        };
+        let all = set(&["http.path", "http.headers", "f99"]);
+
+        let mut ctx = Context::new(&schema);
+        assert!(ctx.has_fields(&FieldSet::default()));
+        assert!(!ctx.has_fields(&set(&["http.path"])));
+
+        ctx.add_value_str("http.path", "/");
+        ctx.add_map_value("http.headers", "a", "1");
+        assert!(ctx.has_fields(&set(&["http.path", "http.headers"])));
+        assert!(!ctx.has_fields(&all));
+        ctx.add_value_int("f99", 1);
+        assert!(ctx.has_fields(&all));
+
+        ctx.reset();
+        assert!(!ctx.has_fields(&set(&["http.path"])));
+        assert!(!ctx.has_fields(&set(&["f99"])));
+
+        // fields the provider was not asked for yet may still get values
+        ctx.set_provider(|field| (field == "net.port").then_some(Value::Int(80)));
+        assert!(ctx.has_fields(&set(&["net.port", "f99"])));
+        ctx.resolve("net.port");
+        ctx.resolve("f99");
+        assert!(ctx.has_fields(&set(&["net.port", "http.path"])));
+        assert!(!ctx.has_fields(&set(&["f99"])));
+
+        ctx.reset();
+        assert!(ctx.has_fields(&set(&["f99"])));
+    }
+
+    #[test]
+    #[should_panic(expected = "value provided does not match schema")]
+    fn test_map_value_mismatch() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        Context::new(&schema).add_map_value("http.path", "a", "1");
+    }
+
+    #[test]
+    #[should_panic(expected = "value provided does not match schema")]
+    fn test_typed_setter_mismatch() {
+        let mut schema = Schema::default();
+        schema.add_field("net.port", Type::Int);
+
+        Context::new(&schema).add_value_str("net.port", "80");
     }
 }
@@ -0,0 +1,172 @@
+//! Contexts recycled across requests.
+//!
+//! Building a [`Context`] allocates the value list of every field of the
+//! schema, and the first values added to it grow those lists. A context
+//! [reset](Context::reset) between requests keeps the lists and their
+//! capacity, so hosts should reuse contexts rather than build one per
+//! request. When requests are not tied to a thread, or a worker handles
+//! several at once, take contexts from a [`ContextPool`] and give them back
+//! once the match was read:
+//!
+//! ```
+//! use atc_router::ast::Type;
+//! use atc_router::context_pool::ContextPool;
+//! use atc_router::router::Router;
+//! use atc_router::schema::Schema;
+//! use uuid::Uuid;
+//!
+//! let mut schema = Schema::default();
+//! schema.add_field("http.path", Type::String);
+//!
+//! let mut router = Router::new(&schema);
+//! router.add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/""#).unwrap();
+//!
+//! let pool = ContextPool::new(&schema);
+//! for path in ["/a", "/b"] {
+//!     let mut ctx = pool.get();
+//!     ctx.add_value_str("http.path", path);
+//!     assert!(router.execute(&mut ctx));
+//!     pool.put(ctx);
+//! }
+//! assert_eq!(pool.idle(), 1);
+//! ```
+//!
+//! Contexts are pooled boxed, so bindings can hand the same pointers out
+//! again. `benches/context.rs` compares the allocations of 100k executes
+//! with pooled contexts and with new ones.
+
+use crate::context::{CaptureMode, Context};
+use crate::schema::Schema;
+use std::sync::{Mutex, PoisonError};
+
+/// Idle contexts kept by [`ContextPool::new`].
+const DEFAULT_MAX_IDLE: usize = 64;
+
+/// Contexts of one schema waiting to be reused, see the
+/// [module documentation](crate::context_pool).
+pub struct ContextPool<'a> {
+    schema: &'a Schema,
+    /// Only locked to push or pop a context.
+    #[allow(clippy::vec_box)] // boxed so their addresses survive the pool
+    idle: Mutex<Vec<Box<Context<'a>>>>,
+    max_idle: usize,
+}
+
+// Pools are shared by the threads handling requests.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ContextPool<'static>>();
+};
+
+impl<'a> ContextPool<'a> {
+    /// A pool keeping up to 64 idle contexts.
+    pub fn new(schema: &'a Schema) -> Self {
+        Self::with_max_idle(schema, DEFAULT_MAX_IDLE)
+    }
+
+    /// A pool keeping up to `max_idle` idle contexts, contexts given back
+    /// past that are dropped. Size it to the number of requests matched at
+    /// the same time.
+    pub fn with_max_idle(schema: &'a Schema, max_idle: usize) -> Self {
+        ContextPool {
+            schema,
+            idle: Mutex::new(Vec::new()),
+            max_idle,
+        }
+    }
+
+    pub fn schema(&self) -> &'a Schema {
+        self.schema
+    }
+
+    /// Returns an idle context, or a new one if there is none. Either way
+    /// it holds no values, no result and no provider, and captures every
+    /// group, like one built by [`Context::new`].
+    pub fn get(&self) -> Box<Context<'a>> {
+        self.lock()
+            .pop()
+            .unwrap_or_else(|| Box::new(Context::new(self.schema)))
+    }
+
+    /// Gives `ctx` back to the pool, resetting it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ctx` was built for another schema, see
+    /// [`ContextPool::owns`].
+    pub fn put(&self, mut ctx: Box<Context<'a>>) {
+        assert!(self.owns(&ctx), "context of another schema");
+
+        ctx.reset();
+        ctx.clear_provider();
+        ctx.set_capture_mode(CaptureMode::All);
+
+        let mut idle = self.lock();
+        if idle.len() < self.max_idle {
+            idle.push(ctx);
+        }
+    }
+
+    /// Whether `ctx` was built for the schema of the pool, and so can be
+    /// given back to it.
+    pub fn owns(&self, ctx: &Context) -> bool {
+        std::ptr::eq(ctx.schema(), self.schema)
+    }
+
+    /// Number of contexts waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    #[allow(clippy::vec_box)] // boxed so their addresses survive the pool
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Box<Context<'a>>>> {
+        // nothing panics while the lock is held
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Type, Value};
+
+    #[test]
+    fn test_pool() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let pool = ContextPool::with_max_idle(&schema, 1);
+        assert_eq!(pool.idle(), 0);
+
+        let mut a = pool.get();
+        a.add_value_str("http.path", "/a");
+        a.set_provider(|_| Some(Value::String("/p".into())));
+        a.disable_captures();
+        let b = pool.get();
+        let ptr: *const Context = &*a;
+        pool.put(a);
+        // past `max_idle`
+        pool.put(b);
+        assert_eq!(pool.idle(), 1);
+
+        let mut a = pool.get();
+        assert_eq!(pool.idle(), 0);
+        // the same allocation
+        assert!(std::ptr::eq(&*a, ptr));
+        assert!(a.value_of("http.path").is_none());
+        assert!(a.resolve("http.path").is_none());
+        assert_eq!(a.capture_mode(), CaptureMode::All);
+        assert!(a.result.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "context of another schema")]
+    fn test_put_other_schema() {
+        let schema = Schema::default();
+        let other = Schema::default();
+
+        let pool = ContextPool::new(&schema);
+        assert!(!pool.owns(&Context::new(&other)));
+        pool.put(Box::new(Context::new(&other)));
+    }
+}
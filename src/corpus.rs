@@ -0,0 +1,237 @@
+//! Reproducible generator of realistic ATC expressions.
+//!
+//! The generated routes look like the ones Kong produces from its traditional
+//! route attributes: a set of hosts, a path (either as a prefix or as an
+//! anchored regex), an optional set of methods and a few header checks.
+//! The same `seed` and [`Shape`] always produce the same corpus, so benchmarks
+//! comparing different revisions of the router run against the same workload.
+
+use crate::ast::{Type, Value};
+use crate::context::Context;
+use crate::schema::Schema;
+
+const WORDS: &[&str] = &[
+    "api", "v1", "v2", "users", "orders", "items", "accounts", "search", "repos", "issues",
+    "comments", "teams", "billing", "status", "events", "files",
+];
+const METHODS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE", "HEAD", "OPTIONS"];
+const PARAM: &str = "{id}";
+
+/// A small, fast and seedable pseudo random number generator (SplitMix64).
+///
+/// It is not suitable for anything security related, but its output is
+/// stable across platforms and crate versions.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a value in `0..n`, `n` must not be `0`.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// Returns `true` with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    pub fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Parameters controlling what the generated corpus looks like.
+#[derive(Debug, Clone)]
+pub struct Shape {
+    /// Number of distinct hosts routes are spread over.
+    pub hosts: usize,
+    /// Number of distinct path templates.
+    pub path_templates: usize,
+    /// Maximum number of header predicates per expression.
+    pub header_checks: usize,
+    /// Fraction (`0.0..=1.0`) of paths expressed as regexes instead of prefixes.
+    pub regex_fraction: f64,
+    /// Fraction (`0.0..=1.0`) of routes that also restrict the method.
+    pub method_fraction: f64,
+}
+
+impl Default for Shape {
+    fn default() -> Self {
+        Shape {
+            hosts: 50,
+            path_templates: 200,
+            header_checks: 1,
+            regex_fraction: 0.2,
+            method_fraction: 0.5,
+        }
+    }
+}
+
+/// Expression generator for a fixed seed and [`Shape`].
+pub struct Corpus {
+    rng: Rng,
+    shape: Shape,
+    hosts: Vec<String>,
+    templates: Vec<String>,
+}
+
+impl Corpus {
+    pub fn new(seed: u64, shape: Shape) -> Self {
+        let mut rng = Rng::new(seed);
+
+        let hosts = (0..shape.hosts.max(1))
+            .map(|i| format!("svc{}.{}.example.com", i, rng.pick(WORDS)))
+            .collect();
+
+        let templates = (0..shape.path_templates.max(1))
+            .map(|_| {
+                let depth = 1 + rng.below(5);
+                let mut path = String::new();
+                for _ in 0..depth {
+                    path.push('/');
+                    let segment = if rng.chance(0.3) {
+                        PARAM
+                    } else {
+                        rng.pick(WORDS)
+                    };
+                    path.push_str(segment);
+                }
+                path
+            })
+            .collect();
+
+        Corpus {
+            rng,
+            shape,
+            hosts,
+            templates,
+        }
+    }
+
+    /// Returns a schema containing every field the generated expressions use.
+    pub fn schema() -> Schema {
+        let mut s = Schema::default();
+        s.add_field("http.method", Type::String);
+        s.add_field("http.host", Type::String);
+        s.add_field("http.path", Type::String);
+        s.add_field("http.headers.*", Type::String);
+        s
+    }
+
+    /// Generates the next expression of the corpus.
+    pub fn next_expression(&mut self) -> String {
+        let mut clauses = Vec::new();
+
+        if self.rng.chance(self.shape.method_fraction) {
+            let n = 1 + self.rng.below(3);
+            let methods: Vec<_> = (0..n)
+                .map(|_| format!(r#"http.method == "{}""#, self.rng.pick(METHODS)))
+                .collect();
+            clauses.push(format!("({})", methods.join(" || ")));
+        }
+
+        let host = self.rng.pick(&self.hosts).clone();
+        clauses.push(if self.rng.chance(0.1) {
+            // wildcard host
+            format!(r#"http.host =^ "{}""#, &host[host.find('.').unwrap()..])
+        } else {
+            format!(r#"http.host == "{}""#, host)
+        });
+
+        let template = self.rng.pick(&self.templates).clone();
+        clauses.push(if self.rng.chance(self.shape.regex_fraction) {
+            // the first parameter is captured by name, like Kong's
+            // path handling plugins expect
+            let re = template
+                .replacen(PARAM, r"(?<id>\d+)", 1)
+                .replace(PARAM, r"\d+");
+            format!(r##"http.path ~ r#"^{}$"#"##, re)
+        } else {
+            let prefix = match template.find(PARAM) {
+                Some(idx) => &template[..idx],
+                None => &template,
+            };
+            format!(r#"http.path ^= "{}""#, prefix)
+        });
+
+        for _ in 0..self.rng.below(self.shape.header_checks + 1) {
+            clauses.push(format!(
+                r#"http.headers.x_{} == "{}""#,
+                self.rng.pick(WORDS),
+                self.rng.pick(WORDS)
+            ));
+        }
+
+        clauses.join(" && ")
+    }
+
+    /// Generates `n` expressions.
+    pub fn expressions(&mut self, n: usize) -> Vec<String> {
+        (0..n).map(|_| self.next_expression()).collect()
+    }
+
+    /// Fills `ctx` with the values of a plausible request against the corpus.
+    ///
+    /// `ctx` must have been created from [`Corpus::schema`].
+    pub fn fill_context(&mut self, ctx: &mut Context) {
+        let method = self.rng.pick(METHODS).to_string();
+        let host = self.rng.pick(&self.hosts).clone();
+        let template = self.rng.pick(&self.templates).clone();
+
+        let mut path = String::new();
+        for (i, segment) in template.split(PARAM).enumerate() {
+            if i > 0 {
+                path.push_str(&self.rng.below(10_000).to_string());
+            }
+            path.push_str(segment);
+        }
+
+        ctx.add_value("http.method", Value::String(method.into()));
+        ctx.add_value("http.host", Value::String(host.into()));
+        ctx.add_value("http.path", Value::String(path.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::semantics::Validate;
+
+    #[test]
+    fn test_reproducible() {
+        let a = Corpus::new(42, Shape::default()).expressions(100);
+        let b = Corpus::new(42, Shape::default()).expressions(100);
+        let c = Corpus::new(43, Shape::default()).expressions(100);
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn test_expressions_are_valid() {
+        let schema = Corpus::schema();
+        let shape = Shape {
+            regex_fraction: 0.5,
+            header_checks: 3,
+            ..Default::default()
+        };
+
+        for atc in Corpus::new(7, shape).expressions(1000) {
+            let expr = parse(&atc).unwrap_or_else(|e| panic!("{}: {}", atc, e));
+            expr.validate(&schema).unwrap();
+        }
+    }
+}
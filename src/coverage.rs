@@ -0,0 +1,208 @@
+//! Route coverage of a set of contexts, e.g. replayed integration test
+//! traffic.
+
+use crate::ast::{Expression, LogicalExpression};
+use crate::context::{Context, Match};
+use crate::interpreter::Execute;
+use crate::router::Router;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use uuid::Uuid;
+
+/// Coverage of one matcher, see [`Router::coverage`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct MatcherCoverage {
+    pub priority: usize,
+    pub uuid: Uuid,
+    /// Contexts the matcher matched, whether or not it was selected.
+    pub matched: usize,
+    /// Contexts the matcher was selected for by [`Router::execute`].
+    pub selected: usize,
+    /// Predicates of the expression that were false for every context,
+    /// in the order they appear in the expression.
+    pub dead_predicates: Vec<Expression>,
+}
+
+/// Result of [`Router::coverage`].
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CoverageReport {
+    /// Number of contexts executed.
+    pub contexts: usize,
+    /// Every matcher, in evaluation order.
+    pub matchers: Vec<MatcherCoverage>,
+    /// Matchers that were never selected, in evaluation order.
+    pub unreached: Vec<Uuid>,
+}
+
+/// Appends the predicates and field comparisons of `expr` to `out`,
+/// leaving out `true` and `false`.
+fn leaves<'e>(expr: &'e Expression, out: &mut Vec<&'e Expression>) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                leaves(l, out);
+                leaves(r, out);
+            }
+            LogicalExpression::Not(e) => leaves(e, out),
+        },
+        Expression::Bool(_) => {}
+        e => out.push(e),
+    }
+}
+
+impl Router<'_> {
+    /// Executes every context in `contexts` and reports, for each matcher,
+    /// how many contexts it matched and was selected for, and which of its
+    /// predicates never held.
+    ///
+    /// Predicates are checked on their own, regardless of short-circuiting
+    /// and negation, so a dead predicate is one no context satisfies. With
+    /// the **serde** feature the report serializes to JSON.
+    ///
+    /// [`Context::result`] and [`Context::stats`] are updated like
+    /// [`Router::execute`] does, hit counters are not.
+    pub fn coverage(&self, contexts: &mut [Context]) -> CoverageReport {
+        // every matcher with its predicates and how often each one held
+        let mut matchers: Vec<(MatcherCoverage, Vec<(&Expression, usize)>)> = self
+            .matchers()
+            .map(|(priority, uuid, expr)| {
+                let mut predicates = Vec::new();
+                leaves(expr, &mut predicates);
+
+                let coverage = MatcherCoverage {
+                    priority,
+                    uuid,
+                    matched: 0,
+                    selected: 0,
+                    dead_predicates: Vec::new(),
+                };
+                (coverage, predicates.into_iter().map(|p| (p, 0)).collect())
+            })
+            .collect();
+
+        for ctx in contexts.iter_mut() {
+            let matches = self.execute_all(ctx);
+
+            for (i, mat) in matches.iter().enumerate() {
+                let (coverage, _) = matchers
+                    .iter_mut()
+                    .find(|(c, _)| c.uuid == mat.uuid)
+                    .expect("matches come from the router");
+                coverage.matched += 1;
+                if i == 0 {
+                    coverage.selected += 1;
+                }
+            }
+
+            for (_, predicates) in &mut matchers {
+                for (p, hits) in predicates {
+                    if p.execute(ctx, &mut Match::new()) {
+                        *hits += 1;
+                    }
+                }
+            }
+
+            ctx.result = matches.into_iter().next();
+        }
+
+        let matchers: Vec<_> = matchers
+            .into_iter()
+            .map(|(mut coverage, predicates)| {
+                coverage.dead_predicates = predicates
+                    .into_iter()
+                    .filter(|(_, hits)| *hits == 0)
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                coverage
+            })
+            .collect();
+
+        CoverageReport {
+            contexts: contexts.len(),
+            unreached: matchers
+                .iter()
+                .filter(|m| m.selected == 0)
+                .map(|m| m.uuid)
+                .collect(),
+            matchers,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Type, Value};
+    use crate::schema::Schema;
+
+    #[test]
+    fn test_coverage() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.method", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(3, Uuid::from_u128(1), r#"http.path ^= "/api""#)
+            .unwrap();
+        router
+            .add_matcher(
+                2,
+                Uuid::from_u128(2),
+                r#"http.path ^= "/" && (http.method == "GET" || http.method == "TRACE")"#,
+            )
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(3), r#"http.path == "/admin""#)
+            .unwrap();
+
+        let mut contexts: Vec<_> = [("/api/x", "GET"), ("/a", "GET"), ("/api", "POST")]
+            .iter()
+            .map(|&(path, method)| {
+                let mut ctx = Context::new(&schema);
+                ctx.add_value("http.path", Value::String(path.into()));
+                ctx.add_value("http.method", Value::String(method.into()));
+                ctx
+            })
+            .collect();
+
+        let report = router.coverage(&mut contexts);
+        assert_eq!(report.contexts, 3);
+        assert_eq!(
+            contexts[1].result.as_ref().unwrap().uuid,
+            Uuid::from_u128(2)
+        );
+
+        let summary: Vec<_> = report
+            .matchers
+            .iter()
+            .map(|m| (m.uuid.as_u128(), m.matched, m.selected))
+            .collect();
+        assert_eq!(summary, [(1, 2, 2), (2, 2, 1), (3, 0, 0)]);
+
+        let dead: Vec<Vec<String>> = report
+            .matchers
+            .iter()
+            .map(|m| m.dead_predicates.iter().map(|p| p.to_string()).collect())
+            .collect();
+        assert_eq!(
+            dead,
+            [
+                vec![],
+                vec![r#"(http.method == "TRACE")"#],
+                vec![r#"(http.path == "/admin")"#],
+            ]
+        );
+
+        assert_eq!(report.unreached, [Uuid::from_u128(3)]);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&report).unwrap();
+            assert_eq!(json["unreached"][0], Uuid::from_u128(3).to_string());
+            assert_eq!(json["matchers"][1]["selected"], 1);
+        }
+    }
+}
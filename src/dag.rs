@@ -0,0 +1,219 @@
+//! Matchers factored into a shared decision DAG.
+//!
+//! A [`Dag`] holds the expressions of every matcher of a router as a single
+//! graph where identical subexpressions, from a single predicate to a whole
+//! expression, are one node. Matchers are terminals pointing at the node of
+//! their expression, so matchers with the same expression share one. While
+//! a router executes, the outcome of every node used more than once is kept
+//! in the context, and a predicate such as `net.protocol == "http"` found
+//! in thousands of matchers is evaluated once per request, the way
+//! firewalls factor their rule sets. Selected with
+//! [`Engine::Dag`](crate::router::Engine::Dag).
+
+use crate::ast::{Expression, FieldComparison, LogicalExpression, Predicate};
+use crate::context::{Context, Match};
+use crate::error::EvalError;
+use crate::interpreter::Execute;
+use std::collections::HashMap;
+
+/// Index of a node in its [`Dag`].
+pub type NodeId = usize;
+
+#[derive(Debug, Clone)]
+pub enum Node {
+    And(NodeId, NodeId),
+    Or(NodeId, NodeId),
+    Not(NodeId),
+    Predicate(Predicate),
+    FieldComparison(FieldComparison),
+    Bool(bool),
+}
+
+/// What makes two nodes identical. Leaves are compared as printed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum NodeKey {
+    And(NodeId, NodeId),
+    Or(NodeId, NodeId),
+    Not(NodeId),
+    Leaf(String),
+}
+
+#[derive(Debug, Default)]
+pub struct Dag {
+    /// `None` for the ids of removed nodes, until they are reused.
+    nodes: Vec<Option<(NodeKey, Node)>>,
+    ids: HashMap<NodeKey, NodeId>,
+    /// Number of parent nodes and terminals using each node.
+    uses: Vec<usize>,
+    free: Vec<NodeId>,
+}
+
+impl Dag {
+    /// Adds a terminal for `expr`, returning the id of its root node.
+    pub fn insert(&mut self, expr: &Expression) -> NodeId {
+        match expr {
+            Expression::Logical(l) => match l.as_ref() {
+                LogicalExpression::And(l, r) => {
+                    let (l, r) = (self.insert(l), self.insert(r));
+                    self.intern(NodeKey::And(l, r), || Node::And(l, r))
+                }
+                LogicalExpression::Or(l, r) => {
+                    let (l, r) = (self.insert(l), self.insert(r));
+                    self.intern(NodeKey::Or(l, r), || Node::Or(l, r))
+                }
+                LogicalExpression::Not(e) => {
+                    let e = self.insert(e);
+                    self.intern(NodeKey::Not(e), || Node::Not(e))
+                }
+            },
+            Expression::Predicate(p) => self.intern(NodeKey::Leaf(p.to_string()), || {
+                // the node is memoized as a whole, see `Dag::execute`
+                Node::Predicate(Predicate {
+                    memo: None,
+                    ..p.clone()
+                })
+            }),
+            Expression::FieldComparison(c) => self.intern(NodeKey::Leaf(c.to_string()), || {
+                Node::FieldComparison(c.clone())
+            }),
+            Expression::Bool(b) => self.intern(NodeKey::Leaf(b.to_string()), || Node::Bool(*b)),
+        }
+    }
+
+    /// Returns the id of the node `key`, adding it with `node` if there is
+    /// none yet. The operands of `key` were just used once more for it.
+    fn intern(&mut self, key: NodeKey, node: impl FnOnce() -> Node) -> NodeId {
+        if let Some(&id) = self.ids.get(&key) {
+            // the existing node already uses its operands
+            for operand in operands(&key) {
+                self.remove(operand);
+            }
+            self.uses[id] += 1;
+            return id;
+        }
+
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.nodes.push(None);
+                self.uses.push(0);
+                self.nodes.len() - 1
+            }
+        };
+        self.ids.insert(key.clone(), id);
+        self.nodes[id] = Some((key, node()));
+        self.uses[id] = 1;
+
+        id
+    }
+
+    /// Drops a terminal or parent using the node `id`, removing the nodes
+    /// no longer used by anything.
+    pub fn remove(&mut self, id: NodeId) {
+        self.uses[id] -= 1;
+        if self.uses[id] > 0 {
+            return;
+        }
+
+        let (key, _) = self.nodes[id].take().unwrap();
+        self.ids.remove(&key);
+        self.free.push(id);
+        for operand in operands(&key) {
+            self.remove(operand);
+        }
+    }
+
+    /// The node `id`, `None` if it was removed.
+    pub fn node(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id)?.as_ref().map(|(_, node)| node)
+    }
+
+    /// Number of parent nodes and terminals using the node `id`.
+    pub fn uses(&self, id: NodeId) -> usize {
+        self.uses.get(id).copied().unwrap_or(0)
+    }
+
+    /// Number of nodes.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Evaluates the node `id`. Within a router execution, nodes used more
+    /// than once are evaluated at most once, later evaluations replay what
+    /// the first one recorded in `m`. Fails like
+    /// [`Execute::try_execute`].
+    pub fn execute(&self, id: NodeId, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        // a node used once is only reached again through its single user,
+        // which is memoized itself if it is reached more than once
+        if self.uses[id] > 1 {
+            ctx.memoized(id, m, |ctx, m| self.evaluate(id, ctx, m))
+        } else {
+            self.evaluate(id, ctx, m)
+        }
+    }
+
+    fn evaluate(&self, id: NodeId, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        Ok(match self.node(id).unwrap() {
+            Node::And(l, r) => self.execute(*l, ctx, m)? && self.execute(*r, ctx, m)?,
+            Node::Or(l, r) => self.execute(*l, ctx, m)? || self.execute(*r, ctx, m)?,
+            Node::Not(e) => !self.execute(*e, ctx, m)?,
+            Node::Predicate(p) => p.try_execute(ctx, m)?,
+            Node::FieldComparison(c) => c.try_execute(ctx, m)?,
+            Node::Bool(b) => *b,
+        })
+    }
+}
+
+fn operands(key: &NodeKey) -> Vec<NodeId> {
+    match *key {
+        NodeKey::And(l, r) | NodeKey::Or(l, r) => vec![l, r],
+        NodeKey::Not(e) => vec![e],
+        NodeKey::Leaf(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_sharing() {
+        let mut dag = Dag::default();
+        let a = dag.insert(&parse(r#"a == "x" && b == 1"#).unwrap());
+        let b = dag.insert(&parse(r#"a == "x" && b == 2"#).unwrap());
+        // `a == "x"`, `b == 1`, `b == 2` and both `&&`
+        assert_eq!(dag.len(), 5);
+        assert_ne!(a, b);
+        let Some(&Node::And(x, _)) = dag.node(a) else {
+            unreachable!();
+        };
+        assert_eq!(dag.uses(x), 2);
+
+        // the same expression is the same terminal node
+        assert_eq!(dag.insert(&parse(r#"a == "x" && b == 1"#).unwrap()), a);
+        assert_eq!(dag.len(), 5);
+        assert_eq!(dag.uses(a), 2);
+        assert_eq!(dag.uses(x), 2);
+
+        dag.remove(a);
+        assert_eq!(dag.len(), 5);
+        dag.remove(a);
+        assert_eq!(dag.len(), 3);
+        assert!(dag.node(a).is_none());
+        assert_eq!(dag.uses(x), 1);
+
+        // ids of removed nodes are reused
+        let c = dag.insert(&parse(r#"!(c == "y")"#).unwrap());
+        assert!(c < 5);
+        assert_eq!(dag.len(), 5);
+
+        dag.remove(b);
+        dag.remove(c);
+        assert!(dag.is_empty());
+    }
+}
@@ -0,0 +1,386 @@
+//! Differential testing of the expression engines.
+//!
+//! [`expression`] generates random expressions over
+//! [`fuzz_schema`](crate::fuzzing::fuzz_schema), with values drawn from the
+//! ones [`Context::arbitrary_with`] puts in contexts, aimed at the CIDRs of
+//! the expressions, so that predicates hold about as often as not. Every [`Engine`] must then agree with the AST
+//! interpreter on every random context, not only on the result but on the
+//! matches, captures and evidence recorded along the way, which depend on
+//! which operands `&&` and `||` evaluated. The expressions of a [`Corpus`]
+//! are checked the same way, all compiled together as a router would.
+
+use crate::ast::{Expression, Type, Value};
+use crate::cir::CirProgram;
+use crate::closure::ClosureProgram;
+use crate::context::{Context, Match, MatchEvidence};
+use crate::corpus::{Corpus, Rng, Shape};
+use crate::dag::{Dag, NodeId};
+use crate::error::EvalError;
+use crate::fuzzing::{fuzz_schema, FUZZ_CONTEXTS};
+use crate::interpreter::Execute;
+use crate::lir::LirProgram;
+use crate::parser::parse;
+use crate::router::Engine;
+use crate::semantics::Validate;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+fn string_literal() -> impl Strategy<Value = String> {
+    prop_oneof![
+        select(
+            &[
+                "",
+                "/",
+                "/foo",
+                "/FOO/",
+                "GET",
+                "http",
+                "example.com",
+                "Ünïcödé"
+            ][..]
+        )
+        .prop_map(str::to_string),
+        "[a-z]{0,4}",
+    ]
+    .prop_map(|s| format!("{:?}", s))
+}
+
+fn string_lhs() -> impl Strategy<Value = String> {
+    let field = select(
+        &[
+            "http.method",
+            "http.host",
+            "http.path",
+            "http.headers.x_api",
+        ][..],
+    );
+    let quantifier = select(&["", "any", "all"][..]);
+    (field, any::<bool>(), quantifier).prop_map(|(field, lower, quantifier)| {
+        let lhs = match lower {
+            true => format!("lower({})", field),
+            false => field.to_string(),
+        };
+        match quantifier {
+            "" => lhs,
+            q => format!("{}({})", q, lhs),
+        }
+    })
+}
+
+fn string_predicate() -> impl Strategy<Value = String> {
+    let rhs = prop_oneof![
+        (
+            select(&["==", "!=", "^=", "=^", "contains"][..]),
+            string_literal()
+        )
+            .prop_map(|(op, s)| format!("{} {}", op, s)),
+        select(
+            &[
+                r##"~ r#"^/(?<seg>[a-z]+)"#"##,
+                r##"~ r#"(?<all>.*)"#"##,
+                r##"~ r#"^(?<first>[a-z])(?<second>[a-z])?"#"##,
+                r#"~ "o+""#,
+                r#"glob "/f*""#,
+                r#"glob "*.com""#,
+            ][..]
+        )
+        .prop_map(str::to_string),
+        (
+            select(&["in", "not in"][..]),
+            prop::collection::vec(string_literal(), 1..4)
+        )
+            .prop_map(|(op, items)| format!("{} ({})", op, items.join(", "))),
+    ];
+    (string_lhs(), rhs).prop_map(|(lhs, rhs)| format!("{} {}", lhs, rhs))
+}
+
+fn int_predicate() -> impl Strategy<Value = String> {
+    let int = prop_oneof![
+        select(&[0, 80, 443, -1, i64::MIN + 1, i64::MAX][..]),
+        -1000i64..65536,
+    ];
+    prop_oneof![
+        (select(&["==", "!=", ">", ">=", "<", "<="][..]), int.clone())
+            .prop_map(|(op, i)| format!("net.port {} {}", op, i)),
+        (select(&["in", "not in"][..]), int.clone(), int).prop_map(|(op, a, b)| format!(
+            "net.port {} {}..{}",
+            op,
+            a.min(b),
+            a.max(b)
+        )),
+    ]
+}
+
+fn float_predicate() -> impl Strategy<Value = String> {
+    (
+        select(&["==", "!=", ">", ">=", "<", "<="][..]),
+        select(&["0.0", "-0.0", "12.5", "1.2", "-1e3", "250.0"][..]),
+    )
+        .prop_map(|(op, f)| format!("tls.version {} {}", op, f))
+}
+
+fn ip_predicate() -> impl Strategy<Value = String> {
+    let addr = select(&["10.0.0.1", "192.168.1.1", "0.0.0.0", "::1", "fd00::1"][..]);
+    let cidr = select(&["10.0.0.0/8", "192.168.0.0/16", "fd00::/8", "0.0.0.0/0"][..]);
+    prop_oneof![
+        (
+            select(&["==", "!=", ">", ">=", "<", "<="][..]),
+            addr.clone()
+        )
+            .prop_map(|(op, ip)| format!("net.src.ip {} {}", op, ip)),
+        (select(&["in", "not in"][..]), cidr.clone())
+            .prop_map(|(op, c)| format!("net.src.ip {} {}", op, c)),
+        (
+            select(&["in", "not in"][..]),
+            prop::collection::vec(cidr.clone(), 1..3)
+        )
+            .prop_map(|(op, cs)| format!("net.src.ip {} ({})", op, cs.join(", "))),
+        (select(&["==", "!="][..]), cidr).prop_map(|(op, c)| format!("net.src.cidr {} {}", op, c)),
+        addr.prop_map(|ip| format!("net.src.cidr contains {}", ip)),
+    ]
+}
+
+fn field_comparison() -> impl Strategy<Value = String> {
+    prop_oneof![
+        (
+            string_lhs(),
+            select(&["==", "!=", "^=", "=^", "contains"][..]),
+            string_lhs()
+        )
+            .prop_map(|(l, op, r)| format!("{} {} {}", l, op, r)),
+        select(&["==", "!=", ">", "<="][..]).prop_map(|op| format!("net.port {} net.port", op)),
+        select(&["==", "<"][..]).prop_map(|op| format!("net.src.ip {} net.src.ip", op)),
+    ]
+}
+
+/// Random expressions over [`fuzz_schema`], most of which validate.
+fn expression() -> impl Strategy<Value = String> {
+    let leaf = prop_oneof![
+        4 => string_predicate(),
+        2 => int_predicate(),
+        1 => float_predicate(),
+        2 => ip_predicate(),
+        1 => field_comparison(),
+        1 => any::<bool>().prop_map(|b| b.to_string()),
+    ];
+
+    leaf.prop_recursive(4, 24, 2, |inner| {
+        prop_oneof![
+            (inner.clone(), inner.clone()).prop_map(|(l, r)| format!("({}) && ({})", l, r)),
+            (inner.clone(), inner.clone()).prop_map(|(l, r)| format!("({}) || ({})", l, r)),
+            inner.prop_map(|e| format!("!({})", e)),
+        ]
+    })
+}
+
+/// Whether `a` and `b` record the same predicates, a `NaN` value being
+/// the same as another `NaN` as both came from the same context.
+fn same_evidence(a: &[MatchEvidence], b: &[MatchEvidence]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|(a, b)| {
+            a.field == b.field
+                && a.op == b.op
+                && match (&a.value, &b.value) {
+                    (Value::Float(x), Value::Float(y)) => x == y || x.is_nan() && y.is_nan(),
+                    (x, y) => x == y,
+                }
+        })
+}
+
+const ENGINES: [Engine; 5] = [
+    Engine::Ast,
+    Engine::Cir,
+    Engine::Lir,
+    Engine::Closure,
+    Engine::Dag,
+];
+
+/// Expressions compiled for one [`Engine`].
+struct Compiled {
+    engine: Engine,
+    /// Shared by every expression, for [`Engine::Dag`].
+    dag: Dag,
+    programs: Vec<Program>,
+}
+
+enum Program {
+    Boxed(Box<dyn Execute>),
+    Dag(NodeId),
+}
+
+impl Compiled {
+    fn new(engine: Engine, exprs: &[Expression]) -> Self {
+        let mut dag = Dag::default();
+        let programs = exprs
+            .iter()
+            .map(|expr| match engine {
+                Engine::Ast => Program::Boxed(Box::new(expr.clone())),
+                Engine::Cir => Program::Boxed(Box::new(CirProgram::from(expr))),
+                Engine::Lir => Program::Boxed(Box::new(LirProgram::from(expr))),
+                Engine::Closure => Program::Boxed(Box::new(ClosureProgram::from(expr))),
+                Engine::Dag => Program::Dag(dag.insert(expr)),
+            })
+            .collect();
+
+        Compiled {
+            engine,
+            dag,
+            programs,
+        }
+    }
+
+    /// Executes the `i`th expression on `ctx`, returning the result and what
+    /// it recorded.
+    fn run(&self, i: usize, ctx: &mut Context) -> (Result<bool, EvalError>, Match) {
+        let mut m = Match::new();
+        let result = match &self.programs[i] {
+            Program::Boxed(program) => program.try_execute(ctx, &mut m),
+            Program::Dag(root) => self.dag.execute(*root, ctx, &mut m),
+        };
+        (result, m)
+    }
+}
+
+/// Checks that every engine agrees with the AST interpreter on `exprs` for
+/// each of `contexts`. Each engine evaluates all of `exprs` in one
+/// execution, so predicates are memoized across them as in a router.
+fn check_engines_agree<'a>(
+    exprs: &[Expression],
+    contexts: impl IntoIterator<Item = Context<'a>>,
+) -> Result<(), TestCaseError> {
+    let engines = ENGINES.map(|engine| Compiled::new(engine, exprs));
+
+    for mut ctx in contexts {
+        let expected: Vec<_> = exprs
+            .iter()
+            .map(|expr| {
+                let mut m = Match::new();
+                (expr.try_execute(&mut ctx, &mut m), m)
+            })
+            .collect();
+
+        for engine in &engines {
+            let name = engine.engine;
+            let actual: Vec<_> =
+                ctx.with_memo(|ctx| (0..exprs.len()).map(|i| engine.run(i, ctx)).collect());
+
+            for ((expr, (expected, expected_m)), (actual, m)) in
+                exprs.iter().zip(&expected).zip(actual)
+            {
+                prop_assert_eq!(&actual, expected, "{:?} result of {}", name, expr);
+                prop_assert_eq!(&m.matches, &expected_m.matches, "{:?} matches", name);
+                prop_assert_eq!(&m.captures, &expected_m.captures, "{:?} captures", name);
+                prop_assert!(
+                    same_evidence(&m.evidence, &expected_m.evidence),
+                    "{:?} evidence: {:?} != {:?}",
+                    name,
+                    m.evidence,
+                    expected_m.evidence
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks [`check_engines_agree`] on random contexts drawn from `seed`.
+fn check_random_contexts(expr: &Expression, seed: u64) -> Result<(), TestCaseError> {
+    let schema = fuzz_schema();
+    let cidrs = expr.cidr_literals();
+    let mut rng = Rng::new(seed);
+    let contexts = (0..FUZZ_CONTEXTS).map(|_| Context::arbitrary_with(&schema, &mut rng, &cidrs));
+
+    check_engines_agree(std::slice::from_ref(expr), contexts)
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn engines_agree(atc in expression(), seed in any::<u64>()) {
+        let expr = parse(&atc).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        prop_assume!(expr.validate(&fuzz_schema()).is_ok());
+        check_random_contexts(&expr, seed)?;
+    }
+}
+
+/// Expressions with captures and evidence on both sides of `&&` and `||`,
+/// where an evaluator skipping or not skipping the right operand wrongly
+/// would record different effects.
+#[test]
+fn short_circuits() {
+    for atc in [
+        r##"http.path ~ r#"^/(?<seg>[a-z]+)"# || http.host ~ r#"(?<all>.*)"#"##,
+        r##"!(http.path ~ r#"^/(?<seg>[a-z]+)"#) && http.host ~ r#"(?<all>.*)"#"##,
+        r##"(net.port > 0 || http.path ~ r#"(?<all>.*)"#) && !(false || http.host == "")"##,
+        r##"!(!(http.path ~ r#"(?<all>.*)"# && false) || net.port < 0) || true"##,
+    ] {
+        let expr = parse(atc).unwrap();
+        for seed in 0..32 {
+            check_random_contexts(&expr, seed).unwrap();
+        }
+    }
+}
+
+/// The expressions of a corpus, many sharing predicates, and ones with
+/// what the engines compile specially, on contexts that often match them.
+#[test]
+fn corpus_expressions() {
+    let mut schema = Corpus::schema();
+    schema.add_field("net.port", Type::Int);
+
+    let shape = Shape {
+        header_checks: 2,
+        ..Default::default()
+    };
+    let mut corpus = Corpus::new(5, shape);
+    let mut atcs = corpus.expressions(200);
+    atcs.extend(
+        [
+            r#"!(net.port == 80) || http.path ~ "^/(?<v>v[0-9])/""#,
+            r#"http.path ~ "^/(?<v>v[0-9])/" && net.port == 80"#,
+            "http.host == http.headers.host || false",
+            "!(!(net.port > 1 && (net.port < 3 || false)) || !(true))",
+            "(net.port == 1 || net.port == 2) && (net.port != 2 || true) && !(false)",
+            "(net.port == 1 || net.port == 2) && true",
+            "net.port in 1..1000 && net.port not in 20..30 && net.port >= 2",
+            r#"lower(http.path) ^= "/a" || lower(http.host) in ("a.com", "b.com")"#,
+            r#"any(lower(http.path)) glob "/*/x" || http.host =^ ".com""#,
+            r#"http.host not in ("a.com") && http.path contains "a""#,
+            r#"any(http.host) == "a.com" || trim(http.path) != "/""#,
+        ]
+        .map(str::to_string),
+    );
+    let exprs: Vec<_> = atcs.iter().map(|atc| parse(atc).unwrap()).collect();
+
+    let cidrs: Vec<_> = exprs.iter().flat_map(Expression::cidr_literals).collect();
+    let mut rng = Rng::new(6);
+    let contexts = (0..200).map(|i| {
+        let mut ctx = Context::arbitrary_with(&schema, &mut rng, &cidrs);
+        if i % 2 == 0 {
+            corpus.fill_context(&mut ctx);
+        }
+        ctx
+    });
+
+    check_engines_agree(&exprs, contexts).unwrap();
+}
+
+#[test]
+fn generated_expressions_validate() {
+    use proptest::strategy::ValueTree;
+    use proptest::test_runner::TestRunner;
+
+    let schema = fuzz_schema();
+    let mut runner = TestRunner::deterministic();
+    let strategy = expression();
+    let valid = (0..200)
+        .filter(|_| {
+            let atc = strategy.new_tree(&mut runner).unwrap().current();
+            parse(&atc).unwrap().validate(&schema).is_ok()
+        })
+        .count();
+    // few cases are rejected by `prop_assume!`
+    assert!(valid > 180, "{} of 200 valid", valid);
+}
@@ -0,0 +1,178 @@
+//! Graphviz renderings of expressions and routers, for looking at why a
+//! route matched or not.
+//!
+//! Logical operators are drawn as ellipses with their operands below them,
+//! predicates, field comparisons and booleans as boxes. Render the output
+//! with e.g. `dot -Tsvg`.
+
+use crate::ast::{Expression, LogicalExpression};
+use crate::router::Router;
+use std::fmt::Write;
+
+impl Expression {
+    /// Returns a graphviz `digraph` of the logical tree of the expression,
+    /// see the [module documentation](crate::dot).
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph {\n");
+        write_tree(self, "n", &mut 0, "    ", &mut out);
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl Router<'_> {
+    /// Returns a graphviz `digraph` of every matcher, in evaluation order,
+    /// with one cluster per priority. Each matcher is a node labelled with
+    /// its UUID above the tree of its expression, see
+    /// [`Expression::to_dot`].
+    ///
+    /// Expressions are drawn as the router stores them, so `||` chains it
+    /// compacted appear as a single predicate.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph {\n");
+        let mut current = None;
+
+        for (i, (priority, uuid, expr)) in self.matchers().enumerate() {
+            if current != Some(priority) {
+                if current.is_some() {
+                    out.push_str("    }\n");
+                }
+                current = Some(priority);
+
+                writeln!(out, "    subgraph cluster_{} {{", i).unwrap();
+                writeln!(out, "        label=\"priority {}\";", priority).unwrap();
+            }
+
+            let prefix = format!("m{}_", i);
+            writeln!(out, "        m{} [label=\"{}\", shape=note];", i, uuid).unwrap();
+            let root = write_tree(expr, &prefix, &mut 0, "        ", &mut out);
+            writeln!(out, "        m{} -> {};", i, root).unwrap();
+        }
+
+        if current.is_some() {
+            out.push_str("    }\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Writes the nodes and edges of `expr`, naming nodes `prefix` followed by
+/// a number taken from `next`, and returns the name of its root.
+fn write_tree(
+    expr: &Expression,
+    prefix: &str,
+    next: &mut usize,
+    indent: &str,
+    out: &mut String,
+) -> String {
+    let id = format!("{}{}", prefix, next);
+    *next += 1;
+
+    let (label, operands) = match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => ("&&", vec![l, r]),
+            LogicalExpression::Or(l, r) => ("||", vec![l, r]),
+            LogicalExpression::Not(e) => ("!", vec![e]),
+        },
+        leaf => {
+            writeln!(
+                out,
+                "{}{} [label=\"{}\", shape=box];",
+                indent,
+                id,
+                escape(&leaf.to_string())
+            )
+            .unwrap();
+            return id;
+        }
+    };
+
+    writeln!(out, "{}{} [label=\"{}\"];", indent, id, label).unwrap();
+    for e in operands {
+        let child = write_tree(e, prefix, next, indent, out);
+        writeln!(out, "{}{} -> {};", indent, id, child).unwrap();
+    }
+
+    id
+}
+
+/// Escapes `s` for use inside a double quoted DOT string, where a
+/// backslash also starts label escapes such as `\n`.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_expression_to_dot() {
+        let e = parse(r#"http.path ^= "/a\\b" && !(net.port == 80 || true)"#).unwrap();
+        assert_eq!(
+            e.to_dot(),
+            r##"digraph {
+    n0 [label="&&"];
+    n1 [label="(http.path ^= r#\"/a\\b\"#)", shape=box];
+    n0 -> n1;
+    n2 [label="!"];
+    n3 [label="||"];
+    n4 [label="(net.port == 80)", shape=box];
+    n3 -> n4;
+    n5 [label="true", shape=box];
+    n3 -> n5;
+    n2 -> n3;
+    n0 -> n2;
+}
+"##
+        );
+    }
+
+    #[test]
+    fn test_router_to_dot() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        assert_eq!(router.to_dot(), "digraph {\n}\n");
+
+        for (priority, uuid, atc) in [
+            (2, 1, r#"http.path == "/a""#),
+            (2, 2, r#"http.path == "/b""#),
+            (1, 3, r#"!(http.path == "/c")"#),
+        ] {
+            router
+                .add_matcher(priority, Uuid::from_u128(uuid), atc)
+                .unwrap();
+        }
+
+        assert_eq!(
+            router.to_dot(),
+            r#"digraph {
+    subgraph cluster_0 {
+        label="priority 2";
+        m0 [label="00000000-0000-0000-0000-000000000002", shape=note];
+        m0_0 [label="(http.path == \"/b\")", shape=box];
+        m0 -> m0_0;
+        m1 [label="00000000-0000-0000-0000-000000000001", shape=note];
+        m1_0 [label="(http.path == \"/a\")", shape=box];
+        m1 -> m1_0;
+    }
+    subgraph cluster_2 {
+        label="priority 1";
+        m2 [label="00000000-0000-0000-0000-000000000003", shape=note];
+        m2_0 [label="!"];
+        m2_1 [label="(http.path == \"/c\")", shape=box];
+        m2_0 -> m2_1;
+        m2 -> m2_0;
+    }
+}
+"#
+        );
+    }
+}
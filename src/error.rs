@@ -0,0 +1,230 @@
+//! The error type shared by the parser, the validator, [`Router`] and the
+//! FFI layer.
+//!
+//! The [`Display`](fmt::Display) output of every error is what the FFI layer
+//! hands back to the host, so existing messages must stay stable. Callers
+//! that need to branch on the kind of error match on [`Error`] instead.
+//!
+//! [`Router`]: crate::router::Router
+
+use crate::ast::{BinaryOperator, Type, Value};
+use crate::parser::Rule;
+use pest::error::{InputLocation, LineColLocation};
+use std::fmt;
+use uuid::Uuid;
+
+/// An ATC expression that is not syntactically valid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The rendered parser error, pointing at the offending input.
+    pub message: String,
+    /// Byte range of the offending input, empty when the parser stopped at
+    /// a single position.
+    pub span: (usize, usize),
+    /// 1-based line and column of the start of `span`. Columns count
+    /// characters, not bytes.
+    pub line_col: (usize, usize),
+    /// 1-based line and column of the end of `span`, the same as
+    /// `line_col` when it is empty.
+    pub end_line_col: (usize, usize),
+}
+
+impl From<pest::error::Error<Rule>> for ParseError {
+    fn from(e: pest::error::Error<Rule>) -> Self {
+        let span = match e.location {
+            InputLocation::Pos(p) => (p, p),
+            InputLocation::Span(s) => s,
+        };
+        let (line_col, end_line_col) = match e.line_col {
+            LineColLocation::Pos(lc) => (lc, lc),
+            LineColLocation::Span(start, end) => (start, end),
+        };
+
+        ParseError {
+            message: e.to_string(),
+            span,
+            line_col,
+            end_line_col,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An expression that does not type check against a schema.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub message: String,
+    /// The field the failing predicate or field comparison is about, the
+    /// unknown one if that is the problem.
+    pub field: Option<String>,
+    /// The failing predicate or field comparison, see
+    /// [`Expression::to_atc_string`](crate::ast::Expression::to_atc_string).
+    pub predicate: Option<String>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// A predicate or field comparison whose operator does not apply to the
+/// values it compares. Only expressions that were not validated against the
+/// schema of the context fail to evaluate, see
+/// [`Execute::try_execute`](crate::interpreter::Execute::try_execute).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalError {
+    pub op: BinaryOperator,
+    pub lhs: Type,
+    pub rhs: Type,
+}
+
+impl EvalError {
+    pub(crate) fn new(op: BinaryOperator, lhs: &Value, rhs: &Value) -> Self {
+        EvalError {
+            op,
+            lhs: lhs.my_type(),
+            rhs: rhs.my_type(),
+        }
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "operator {} does not apply to {:?} and {:?}",
+            self.op, self.lhs, self.rhs
+        )
+    }
+}
+
+impl std::error::Error for EvalError {}
+
+/// Every error this library returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The ATC expression is not syntactically valid.
+    ParseError(ParseError),
+    /// The expression does not type check against the router's schema.
+    ValidationError(ValidationError),
+    /// A matcher with the same priority and UUID already exists.
+    DuplicateUuid(Uuid),
+    /// The router already holds
+    /// [`Router::max_matchers`](crate::router::Router::max_matchers) matchers.
+    LimitExceeded(usize),
+    /// Priority bands are declared but the priority is not inside any of them.
+    PriorityOutOfBand(usize),
+    /// The new priority band overlaps with the named existing band.
+    PriorityBandOverlap(String),
+    /// The route with this UUID could not be added, see
+    /// [`Router::from_routes`](crate::router::Router::from_routes).
+    InvalidRoute(Uuid, Box<Error>),
+    /// The expression at this index of a group could not be added, see
+    /// [`Router::add_matcher_multi`](crate::router::Router::add_matcher_multi).
+    InvalidExpression(usize, Box<Error>),
+    /// The bytes are not a snapshot this release can read, see
+    /// [`Router::deserialize`](crate::router::Router::deserialize).
+    InvalidSnapshot(String),
+    /// Adding the matcher would take the named tenant over its
+    /// [`TenantQuota`](crate::router::TenantQuota).
+    QuotaExceeded(String),
+    /// An argument handed over by a binding is malformed, such as a UUID
+    /// that does not parse or a string that is not valid UTF-8.
+    InvalidArgument(String),
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::ParseError(e)
+    }
+}
+
+impl From<pest::error::Error<Rule>> for Error {
+    fn from(e: pest::error::Error<Rule>) -> Self {
+        Error::ParseError(e.into())
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(e: ValidationError) -> Self {
+        Error::ValidationError(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ParseError(e) => write!(f, "{}", e),
+            Error::ValidationError(e) => write!(f, "{}", e),
+            Error::DuplicateUuid(_) => write!(f, "UUID already exists"),
+            Error::LimitExceeded(max) => {
+                write!(f, "maximum number of matchers ({}) reached", max)
+            }
+            Error::PriorityOutOfBand(p) => {
+                write!(f, "priority {} is not inside any declared priority band", p)
+            }
+            Error::PriorityBandOverlap(label) => {
+                write!(f, "priority band overlaps with existing band \"{}\"", label)
+            }
+            Error::InvalidRoute(uuid, e) => write!(f, "route {}: {}", uuid, e),
+            Error::InvalidExpression(i, e) => write!(f, "expression {}: {}", i, e),
+            Error::InvalidSnapshot(e) => write!(f, "invalid router snapshot: {}", e),
+            Error::QuotaExceeded(tenant) => {
+                write!(f, "quota of tenant \"{}\" exceeded", tenant)
+            }
+            Error::InvalidArgument(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ParseError(e) => Some(e),
+            Error::ValidationError(e) => Some(e),
+            Error::InvalidRoute(_, e) | Error::InvalidExpression(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_parse_error_location() {
+        let e = ParseError::from(parse("a == 1 &&").unwrap_err());
+        assert_eq!(e.span, (9, 9));
+        assert_eq!(e.line_col, (1, 10));
+        assert_eq!(e.end_line_col, (1, 10));
+        assert_eq!(e.to_string(), parse("a == 1 &&").unwrap_err().to_string());
+
+        let e = ParseError::from(parse("a ~ \"(\"").unwrap_err());
+        assert_eq!(e.span, (4, 7));
+        assert_eq!(e.line_col, (1, 5));
+        assert_eq!(e.end_line_col, (1, 8));
+
+        // columns count characters, offsets bytes
+        let e = ParseError::from(parse("a == \"éé\" && b ~ \"(\"").unwrap_err());
+        assert_eq!(e.span, (19, 22));
+        assert_eq!(e.line_col, (1, 18));
+        assert_eq!(e.end_line_col, (1, 21));
+
+        let e = ParseError::from(parse("a == 1 &&\n  b ~ \"(\"").unwrap_err());
+        assert_eq!(e.span, (16, 19));
+        assert_eq!(e.line_col, (2, 7));
+        assert_eq!(e.end_line_col, (2, 10));
+    }
+}
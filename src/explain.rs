@@ -0,0 +1,236 @@
+//! Step by step evaluation of a single expression, to answer why a request
+//! did or did not match a route.
+
+use crate::ast::{Expression, LogicalExpression, Value};
+use crate::context::{Context, Match};
+use crate::interpreter::Execute;
+use crate::router::Router;
+use std::fmt;
+use uuid::Uuid;
+
+/// One node of an evaluated expression, see [`Explanation::steps`].
+#[derive(Debug, Clone)]
+pub enum ExplainStep {
+    /// An `&&`, `||` or `!` and its result. The steps of its operands
+    /// follow, one level deeper.
+    Logical {
+        depth: usize,
+        op: &'static str,
+        result: bool,
+    },
+    /// A predicate, field comparison or boolean, with the values of the
+    /// fields it read.
+    Leaf {
+        depth: usize,
+        expression: Expression,
+        values: Vec<(String, Vec<Value>)>,
+        result: bool,
+    },
+    /// An operand that was not evaluated because the one before it already
+    /// decided the result of its `&&` or `||`.
+    Skipped {
+        depth: usize,
+        expression: Expression,
+    },
+}
+
+/// How an expression evaluated against a context, see [`Router::explain`].
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub result: bool,
+    /// Every node of the expression, depth first in evaluation order.
+    pub steps: Vec<ExplainStep>,
+}
+
+impl Expression {
+    /// Evaluates the expression against `ctx` like
+    /// [`Execute::execute`] does, recording every step, see
+    /// [`Router::explain`].
+    pub fn explain(&self, ctx: &mut Context) -> Explanation {
+        let mut steps = Vec::new();
+        let result = explain(self, 0, ctx, &mut Match::new(), &mut steps);
+
+        Explanation { result, steps }
+    }
+}
+
+impl Router<'_> {
+    /// Evaluates the matcher with this `priority` and `uuid` against `ctx`
+    /// and returns every step of it, `None` if there is no such matcher.
+    ///
+    /// The matcher is evaluated on its own, whether or not
+    /// [`Router::execute`] would have reached it. [`Context::result`] is
+    /// left untouched, missing values are still asked from the context's
+    /// provider.
+    pub fn explain(&self, ctx: &mut Context, priority: usize, uuid: Uuid) -> Option<Explanation> {
+        let (_, _, expr) = self
+            .matchers()
+            .find(|(p, u, _)| *p == priority && *u == uuid)?;
+
+        Some(expr.explain(ctx))
+    }
+}
+
+fn explain(
+    expr: &Expression,
+    depth: usize,
+    ctx: &mut Context,
+    m: &mut Match,
+    steps: &mut Vec<ExplainStep>,
+) -> bool {
+    let logical = match expr {
+        Expression::Logical(l) => l,
+        leaf => {
+            let result = leaf.execute(ctx, m);
+            let mut fields = Vec::new();
+            match leaf {
+                Expression::Predicate(p) => fields.push(&p.lhs.var_name),
+                Expression::FieldComparison(c) => {
+                    fields.push(&c.lhs.var_name);
+                    fields.push(&c.rhs.var_name);
+                }
+                _ => {}
+            }
+
+            steps.push(ExplainStep::Leaf {
+                depth,
+                expression: leaf.clone(),
+                values: fields
+                    .into_iter()
+                    .map(|f| (f.clone(), ctx.value_of(f).unwrap_or_default().to_vec()))
+                    .collect(),
+                result,
+            });
+            return result;
+        }
+    };
+
+    let at = steps.len();
+    steps.push(ExplainStep::Logical {
+        depth,
+        op: "",
+        result: false,
+    });
+
+    let (op, result) = match logical.as_ref() {
+        LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+            let and = matches!(logical.as_ref(), LogicalExpression::And(..));
+            let left = explain(l, depth + 1, ctx, m, steps);
+            let result = if left == and {
+                explain(r, depth + 1, ctx, m, steps)
+            } else {
+                steps.push(ExplainStep::Skipped {
+                    depth: depth + 1,
+                    expression: r.clone(),
+                });
+                left
+            };
+
+            (if and { "&&" } else { "||" }, result)
+        }
+        LogicalExpression::Not(e) => ("!", !explain(e, depth + 1, ctx, m, steps)),
+    };
+
+    steps[at] = ExplainStep::Logical { depth, op, result };
+    result
+}
+
+/// One step per line, indented by depth, with the values read by each leaf.
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            match step {
+                ExplainStep::Logical { depth, op, result } => {
+                    writeln!(f, "{:indent$}{} => {}", "", op, result, indent = depth * 2)?
+                }
+                ExplainStep::Leaf {
+                    depth,
+                    expression,
+                    values,
+                    result,
+                } => {
+                    write!(
+                        f,
+                        "{:indent$}{} => {}",
+                        "",
+                        expression,
+                        result,
+                        indent = depth * 2
+                    )?;
+                    for (field, values) in values {
+                        let values: Vec<_> = values.iter().map(|v| v.to_string()).collect();
+                        write!(f, " [{}: {}]", field, values.join(", "))?;
+                    }
+                    writeln!(f)?;
+                }
+                ExplainStep::Skipped { depth, expression } => writeln!(
+                    f,
+                    "{:indent$}{} skipped",
+                    "",
+                    expression,
+                    indent = depth * 2
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::schema::Schema;
+
+    #[test]
+    fn test_explain() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(
+                1,
+                Uuid::from_u128(1),
+                r#"(http.path ^= "/a" || http.path ^= "/b") && !(net.port == 80) && http.host == "x""#,
+            )
+            .unwrap();
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/a/1");
+        ctx.add_value_str("http.host", "y");
+        ctx.add_value_int("net.port", 8080);
+
+        assert!(router.explain(&mut ctx, 1, Uuid::from_u128(2)).is_none());
+        let explanation = router.explain(&mut ctx, 1, Uuid::from_u128(1)).unwrap();
+        assert!(!explanation.result);
+        assert!(ctx.result.is_none());
+        assert_eq!(
+            explanation.to_string(),
+            r#"&& => false
+  && => true
+    || => true
+      (http.path ^= "/a") => true [http.path: "/a/1"]
+      (http.path ^= "/b") skipped
+    ! => true
+      (net.port == 80) => false [net.port: 8080]
+  (http.host == "x") => false [http.host: "y"]
+"#
+        );
+
+        // a missing field reads as no value
+        let mut ctx = Context::new(&schema);
+        let explanation = router.explain(&mut ctx, 1, Uuid::from_u128(1)).unwrap();
+        assert!(matches!(
+            &explanation.steps[3],
+            ExplainStep::Leaf { values, result: false, .. } if values == &[("http.path".to_string(), vec![])]
+        ));
+        assert!(matches!(
+            &explanation.steps[6],
+            ExplainStep::Skipped { depth: 1, .. }
+        ));
+    }
+}
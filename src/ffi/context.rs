@@ -1,11 +1,13 @@
-use crate::ast::Value;
+use crate::ast::{Str, Value};
 use crate::context::Context;
-use crate::ffi::{CValue, ERR_BUF_MAX_LEN};
+use crate::context_pool::ContextPool;
+use crate::error::Error;
+use crate::ffi::{c_str, write_errbuf, CValue};
 use crate::schema::Schema;
-use std::cmp::min;
-use std::ffi;
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
 use std::os::raw::c_char;
-use std::slice::from_raw_parts_mut;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 use uuid::fmt::Hyphenated;
 
 /// Allocate a new context object associated with the schema.
@@ -20,7 +22,7 @@ use uuid::fmt::Hyphenated;
 ///
 /// - `schema` must be a valid pointer returned by [`schema_new`].
 #[no_mangle]
-pub unsafe extern "C" fn context_new(schema: &Schema) -> *mut Context {
+pub unsafe extern "C" fn context_new(schema: &Schema) -> *mut Context<'_> {
     Box::into_raw(Box::new(Context::new(schema)))
 }
 
@@ -40,6 +42,106 @@ pub unsafe extern "C" fn context_free(context: *mut Context) {
     drop(Box::from_raw(context));
 }
 
+/// Allocate a new pool of contexts associated with the schema, see
+/// [`ContextPool`].
+///
+/// Take a context with [`context_pool_get`] for each request and give it
+/// back with [`context_pool_put`] once its result was read, instead of
+/// allocating one with [`context_new`] and freeing it with
+/// [`context_free`]: pooled contexts are handed out again, keeping the
+/// value lists of their previous requests.
+///
+/// # Arguments
+///
+/// - `schema`: a pointer to the [`Schema`] object returned by [`schema_new`].
+/// - `max_idle`: how many contexts the pool keeps, contexts given back past
+///   that are freed.
+///
+/// # Errors
+///
+/// This function never returns an error, however, it can panic if memory allocation failed.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+#[no_mangle]
+pub unsafe extern "C" fn context_pool_new(
+    schema: &Schema,
+    max_idle: usize,
+) -> *mut ContextPool<'_> {
+    Box::into_raw(Box::new(ContextPool::with_max_idle(schema, max_idle)))
+}
+
+/// Deallocate the pool and the contexts it holds.
+///
+/// Contexts taken from the pool and not given back are left alone, free
+/// them with [`context_free`].
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `pool` must be a valid pointer returned by [`context_pool_new`].
+/// - No other thread may be using `pool`.
+#[no_mangle]
+pub unsafe extern "C" fn context_pool_free(pool: *mut ContextPool) {
+    drop(Box::from_raw(pool));
+}
+
+/// Take a context from the pool, allocating a new one if it has none.
+///
+/// The context holds no values, no result and no provider. Give it back
+/// with [`context_pool_put`], or free it with [`context_free`].
+///
+/// # Errors
+///
+/// This function never returns an error, however, it can panic if memory allocation failed.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `pool` must be a valid pointer returned by [`context_pool_new`].
+#[no_mangle]
+pub unsafe extern "C" fn context_pool_get<'a>(pool: &ContextPool<'a>) -> *mut Context<'a> {
+    Box::into_raw(pool.get())
+}
+
+/// Give a context back to the pool, which resets it.
+///
+/// # Errors
+///
+/// Returns `false`, leaving `context` to the caller, if `context` was
+/// created with another schema than `pool`.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `pool` must be a valid pointer returned by [`context_pool_new`].
+/// - `context` must be a valid pointer returned by [`context_pool_get`] or
+///   [`context_new`].
+/// - If this function returns `true`, `context` must not be used, nor freed
+///   by [`context_free`], afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn context_pool_put<'a>(
+    pool: &ContextPool<'a>,
+    context: *mut Context<'a>,
+) -> bool {
+    if !pool.owns(&*context) {
+        return false;
+    }
+
+    pool.put(Box::from_raw(context));
+    true
+}
+
 /// Add a value associated with a field to the context.
 /// This is useful when you want to match a value against a field in the schema.
 ///
@@ -60,7 +162,8 @@ pub unsafe extern "C" fn context_free(context: *mut Context) {
 /// # Errors
 ///
 /// This function will return `false` if the value could not be added to the context,
-/// such as when a String value is not a valid UTF-8 string.
+/// such as when `field` or a [`CValue::Str`] value is not a valid UTF-8 string.
+/// Use [`CValue::StrLossy`] for values that may not be valid UTF-8.
 ///
 /// # Panics
 ///
@@ -81,27 +184,296 @@ pub unsafe extern "C" fn context_free(context: *mut Context) {
 #[no_mangle]
 pub unsafe extern "C" fn context_add_value(
     context: &mut Context,
-    field: *const i8,
+    field: *const c_char,
     value: &CValue,
     errbuf: *mut u8,
     errbuf_len: *mut usize,
 ) -> bool {
-    let field = ffi::CStr::from_ptr(field as *const c_char)
-        .to_str()
-        .unwrap();
-    let errbuf = from_raw_parts_mut(errbuf, ERR_BUF_MAX_LEN);
-
-    let value: Result<Value, _> = value.try_into();
-    if let Err(e) = value {
-        let errlen = min(e.len(), *errbuf_len);
-        errbuf[..errlen].copy_from_slice(&e.as_bytes()[..errlen]);
-        *errbuf_len = errlen;
-        return false;
+    let result = c_str(field, "field").and_then(|field| {
+        let value: Value = value.try_into()?;
+        Ok((field, value))
+    });
+
+    match result {
+        Ok((field, value)) => {
+            context.add_value(field, value);
+            true
+        }
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            false
+        }
     }
+}
 
-    context.add_value(field, value.unwrap());
+/// Add a value to the context like [`context_add_value`], borrowing the
+/// bytes of a [`CValue::Str`] instead of copying them.
+///
+/// A [`CValue::StrLossy`] is borrowed too when it is valid UTF-8, and
+/// copied with the invalid sequences replaced otherwise. Other values are
+/// always copied.
+///
+/// # Returns
+///
+/// Returns `true` if the value was added, otherwise `false`, the error
+/// message is stored in `errbuf` and its length in `errbuf_len` like
+/// [`context_add_value`].
+///
+/// # Errors
+///
+/// Fails for the same reasons as [`context_add_value`].
+///
+/// # Panics
+///
+/// This function will panic if the provided value does not match the schema.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// * `context`, `field`, `value`, `errbuf` and `errbuf_len` must satisfy
+///   the constraints of [`context_add_value`].
+/// * The bytes a string `value` points to must stay valid and unchanged
+///   until the context is reset with [`context_reset`], given back with
+///   [`context_pool_put`] or freed with [`context_free`].
+#[no_mangle]
+pub unsafe extern "C" fn context_add_value_ref(
+    context: &mut Context,
+    field: *const c_char,
+    value: &CValue,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> bool {
+    let result = c_str(field, "field").and_then(|field| {
+        let value = match value {
+            CValue::Str(s, len) => std::str::from_utf8(from_raw_parts(*s, *len))
+                .map(|s| Value::String(Str::borrowed(s)))
+                .map_err(|e| Error::InvalidArgument(e.to_string()))?,
+            CValue::StrLossy(s, len) => match std::str::from_utf8(from_raw_parts(*s, *len)) {
+                Ok(s) => Value::String(Str::borrowed(s)),
+                Err(_) => value.try_into()?,
+            },
+            _ => value.try_into()?,
+        };
+        Ok((field, value))
+    });
 
-    true
+    match result {
+        Ok((field, value)) => {
+            context.add_value(field, value);
+            true
+        }
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            false
+        }
+    }
+}
+
+/// Add `len` values associated with a field to the context at once, like
+/// calling [`context_add_value`] for each of them in order, which saves
+/// crossing the FFI boundary for every value of multi-valued fields such
+/// as `http.headers.x_forwarded_for`.
+///
+/// # Returns
+///
+/// Returns `true` if every value was added, otherwise `false`, the error
+/// message is stored in `errbuf` and its length in `errbuf_len` like
+/// [`context_add_value`].
+///
+/// # Errors
+///
+/// Fails for the same reasons as [`context_add_value`]. Every value is
+/// checked before any is added, so on failure none of them is.
+///
+/// # Panics
+///
+/// This function will panic if the provided values do not match the schema.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// * `context`, `field`, `errbuf` and `errbuf_len` must satisfy the
+///   constraints of [`context_add_value`].
+/// * `values` must be valid to read for `len * size_of::<CValue>()` bytes,
+///   and it must be properly aligned. It may be `NULL` if `len` is `0`.
+#[no_mangle]
+pub unsafe extern "C" fn context_add_values(
+    context: &mut Context,
+    field: *const c_char,
+    values: *const CValue,
+    len: usize,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> bool {
+    let values = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(values, len)
+    };
+
+    let result = c_str(field, "field").and_then(|field| {
+        let checked = values
+            .iter()
+            .map(Value::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for value in checked {
+            context.add_value(field, value);
+        }
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            false
+        }
+    }
+}
+
+/// Add a value to the entry `key` of a [`Type::Map`] field, read by
+/// `field["key"]` in expressions, such as a header by its name.
+///
+/// # Returns
+///
+/// Returns `true` if the value was added, otherwise `false`, the error
+/// message is stored in `errbuf` and its length in `errbuf_len` like
+/// [`context_add_value`].
+///
+/// # Errors
+///
+/// This function will return `false` if `field` or `key` is not a valid
+/// UTF-8 string, or `value` is not a valid [`CValue::Str`] or a
+/// [`CValue::StrLossy`].
+///
+/// # Panics
+///
+/// This function will panic if `field` is not a map field of the schema.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// * `context`, `field`, `value`, `errbuf` and `errbuf_len` must satisfy
+///   the constraints of [`context_add_value`].
+/// * `key` must be a valid pointer to a C-style string, like `field`.
+///
+/// [`Type::Map`]: crate::ast::Type::Map
+#[no_mangle]
+pub unsafe extern "C" fn context_add_map_value(
+    context: &mut Context,
+    field: *const c_char,
+    key: *const c_char,
+    value: &CValue,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> bool {
+    let result = c_str(field, "field").and_then(|field| {
+        let key = c_str(key, "key")?;
+        let value = match Value::try_from(value)? {
+            Value::String(s) => s,
+            _ => {
+                return Err(Error::InvalidArgument(
+                    "map values must be strings".to_string(),
+                ))
+            }
+        };
+        context.add_map_value(field, key, &value);
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            false
+        }
+    }
+}
+
+/// Supplies the value of a field on demand, see [`context_set_provider`].
+///
+/// Called with the `data` pointer given to [`context_set_provider`] and the
+/// field name, which is not NUL terminated. Returns `false` if the field has
+/// no value, otherwise stores it in `value` and returns `true`.
+pub type ContextProvider = unsafe extern "C" fn(
+    data: *mut c_void,
+    field: *const u8,
+    field_len: usize,
+    value: *mut CValue,
+) -> bool;
+
+/// Set a callback supplying the value of fields when they are first read
+/// by a matcher, instead of adding every value upfront with
+/// [`context_add_value`]. Passing a `NULL` provider removes the current one.
+///
+/// The provider is called at most once per field until [`context_reset`],
+/// and never for fields that already have values. Values that are not
+/// valid UTF-8 (see [`CValue::Str`]) or do not match the type of the field
+/// in the schema are treated as missing.
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `context` must be a valid pointer returned by [`context_new`].
+/// - `provider` must be safe to call with `data` for as long as it is set,
+///   from any thread the context is later used on.
+/// - Pointers the provider stores in `value` must stay valid until the call
+///   that ran it, such as [`router_execute`](crate::ffi::router::router_execute),
+///   returns.
+#[no_mangle]
+pub unsafe extern "C" fn context_set_provider(
+    context: &mut Context,
+    provider: Option<ContextProvider>,
+    data: *mut c_void,
+) {
+    let Some(provider) = provider else {
+        context.clear_provider();
+        return;
+    };
+
+    let provider = ForeignProvider {
+        provider,
+        data,
+        schema: context.schema(),
+    };
+    context.set_provider(move |field| provider.get(field));
+}
+
+/// The state of a provider set by [`context_set_provider`].
+struct ForeignProvider {
+    provider: ContextProvider,
+    data: *mut c_void,
+    /// The schema outlives the context, and so the provider.
+    schema: *const Schema,
+}
+
+// SAFETY: callers of `context_set_provider` guarantee `provider` can be
+// called with `data` from whichever thread uses the context, and the
+// schema is only read.
+unsafe impl Send for ForeignProvider {}
+
+impl ForeignProvider {
+    fn get(&self, field: &str) -> Option<Value> {
+        let mut value = MaybeUninit::<CValue>::uninit();
+        // SAFETY: see `context_set_provider`
+        unsafe {
+            if !(self.provider)(self.data, field.as_ptr(), field.len(), value.as_mut_ptr()) {
+                return None;
+            }
+
+            let value = Value::try_from(&value.assume_init()).ok()?;
+            ((*self.schema).type_of(field) == Some(&value.my_type())).then_some(value)
+        }
+    }
 }
 
 /// Reset the context so that it can be reused.
@@ -158,8 +530,8 @@ pub unsafe extern "C" fn context_reset(context: &mut Context) {
 /// Violating any of the following constraints will result in undefined behavior:
 ///
 /// - `context` must be a valid pointer returned by [`context_new`],
-///    must be passed to [`router_execute`] before calling this function,
-///    and must not be reset by [`context_reset`] before calling this function.
+///   must be passed to [`router_execute`] before calling this function,
+///   and must not be reset by [`context_reset`] before calling this function.
 /// - If `uuid_hex` is not `NULL`, `uuid_hex` must be valid to read and write for
 ///   `16 * size_of::<u8>()` bytes, and it must be properly aligned.
 /// - If `matched_field` is not `NULL`,
@@ -185,7 +557,7 @@ pub unsafe extern "C" fn context_reset(context: &mut Context) {
 pub unsafe extern "C" fn context_get_result(
     context: &Context,
     uuid_hex: *mut u8,
-    matched_field: *const i8,
+    matched_field: *const c_char,
     matched_value: *mut *const u8,
     matched_value_len: *mut usize,
     capture_names: *mut *const u8,
@@ -204,12 +576,13 @@ pub unsafe extern "C" fn context_get_result(
         res.uuid.as_hyphenated().encode_lower(uuid_hex);
 
         if !matched_field.is_null() {
-            let matched_field = ffi::CStr::from_ptr(matched_field as *const c_char)
-                .to_str()
-                .unwrap();
             assert!(!matched_value.is_null());
             assert!(!matched_value_len.is_null());
-            if let Some(Value::String(v)) = res.matches.get(matched_field) {
+            // a field name that is not valid UTF-8 can not have matched
+            let matched = c_str(matched_field, "matched_field")
+                .ok()
+                .and_then(|f| res.matches.get(f));
+            if let Some(Value::String(v)) = matched {
                 *matched_value = v.as_bytes().as_ptr();
                 *matched_value_len = v.len();
             } else {
@@ -249,3 +622,117 @@ pub unsafe extern "C" fn context_get_result(
         .try_into()
         .unwrap()
 }
+
+/// Get the predicates that held for the matched matcher, see
+/// [`Match::evidence`](crate::context::Match::evidence). Unlike the single
+/// value per field of [`context_get_result`], this lists every predicate on
+/// a field.
+///
+/// # Arguments
+///
+/// - `context`: a pointer to the [`Context`] object.
+/// - `fields`: a pointer to an array of pointers to the field names (NOT
+///   C-style strings) of the predicates. If `NULL`, only the number of
+///   predicates is returned.
+/// - `fields_len`: a pointer to an array of the length of each field name.
+///   Its first element must be the number of elements every array can hold.
+/// - `ops`: a pointer to an array of pointers to the operators, such as
+///   `==` or `not in` (NOT C-style strings).
+/// - `ops_len`: a pointer to an array of the length of each operator.
+/// - `values`: a pointer to an array of pointers to the values that
+///   satisfied the predicates. Only `String` values are returned, the
+///   pointer is `NULL` for other types.
+/// - `values_len`: a pointer to an array of the length of each value.
+///
+/// # Returns
+///
+/// Returns the number of predicates, or `-1` if the context has no match.
+///
+/// # Lifetimes
+///
+/// The string pointers stored in `fields` and `values` are invalidated
+/// like the ones of [`context_get_result`], the ones in `ops` are static.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `context` must be a valid pointer returned by [`context_new`].
+/// - If `fields` is not `NULL`, `fields`, `fields_len`, `ops`, `ops_len`,
+///   `values` and `values_len` must each be valid to read and write for
+///   `*fields_len` elements, and be properly aligned.
+#[no_mangle]
+pub unsafe extern "C" fn context_get_evidence(
+    context: &Context,
+    fields: *mut *const u8,
+    fields_len: *mut usize,
+    ops: *mut *const u8,
+    ops_len: *mut usize,
+    values: *mut *const u8,
+    values_len: *mut usize,
+) -> isize {
+    let Some(res) = &context.result else {
+        return -1;
+    };
+
+    if !fields.is_null() {
+        let len = *fields_len;
+        assert!(len >= res.evidence.len());
+        assert!(!ops.is_null() && !ops_len.is_null());
+        assert!(!values.is_null() && !values_len.is_null());
+
+        let fields = from_raw_parts_mut(fields, len);
+        let fields_len = from_raw_parts_mut(fields_len, len);
+        let ops = from_raw_parts_mut(ops, len);
+        let ops_len = from_raw_parts_mut(ops_len, len);
+        let values = from_raw_parts_mut(values, len);
+        let values_len = from_raw_parts_mut(values_len, len);
+
+        for (i, e) in res.evidence.iter().enumerate() {
+            fields[i] = e.field.as_ptr();
+            fields_len[i] = e.field.len();
+            ops[i] = e.op.as_str().as_ptr();
+            ops_len[i] = e.op.as_str().len();
+            (values[i], values_len[i]) = match &e.value {
+                Value::String(v) => (v.as_ptr(), v.len()),
+                _ => (std::ptr::null(), 0),
+            };
+        }
+    }
+
+    res.evidence.len().try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+
+    #[test]
+    fn test_context_pool() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        unsafe {
+            let pool = context_pool_new(&schema, 4);
+            let context = context_pool_get(&*pool);
+            (*context).add_value_str("http.path", "/a");
+            assert!(context_pool_put(&*pool, context));
+            assert_eq!((*pool).idle(), 1);
+
+            // the same context, not a copy
+            assert_eq!(context_pool_get(&*pool), context);
+            assert!((*context).value_of("http.path").is_none());
+            assert_eq!((*pool).idle(), 0);
+
+            let other = Schema::default();
+            let foreign = context_new(&other);
+            assert!(!context_pool_put(&*pool, foreign));
+            assert_eq!((*pool).idle(), 0);
+            context_free(foreign);
+
+            context_free(context);
+            context_pool_free(pool);
+        }
+    }
+}
@@ -1,28 +1,41 @@
-use crate::ast::{BinaryOperator, Expression, LogicalExpression, Predicate};
-use crate::ffi::ERR_BUF_MAX_LEN;
+use crate::ast::{BinaryOperator, Expression, LogicalExpression};
+use crate::error::Error;
+use crate::ffi::{c_str, write_errbuf};
+use crate::lint::lint;
+use crate::parser::parse;
 use crate::schema::Schema;
+use crate::semantics::Validate;
 use bitflags::bitflags;
-use std::cmp::min;
+use std::collections::HashSet;
 use std::ffi;
-use std::os::raw::c_char;
-use std::slice::from_raw_parts_mut;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 use std::iter::Iterator;
 
+/// Yields the operator and field of every predicate, twice for field
+/// comparisons (once per field).
 struct PredicateIterator<'a> {
     stack: Vec<&'a Expression>,
+    pending: Option<(&'a BinaryOperator, &'a str)>,
 }
 
 impl<'a> PredicateIterator<'a> {
     fn new(expr: &'a Expression) -> Self {
-        Self { stack: vec![expr] }
+        Self {
+            stack: vec![expr],
+            pending: None,
+        }
     }
 }
 
 impl<'a> Iterator for PredicateIterator<'a> {
-    type Item = &'a Predicate;
+    type Item = (&'a BinaryOperator, &'a str);
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(item) = self.pending.take() {
+            return Some(item);
+        }
+
         while let Some(expr) = self.stack.pop() {
             match expr {
                 Expression::Logical(l) => match l.as_ref() {
@@ -34,7 +47,12 @@ impl<'a> Iterator for PredicateIterator<'a> {
                         self.stack.push(r);
                     }
                 },
-                Expression::Predicate(p) => return Some(p),
+                Expression::Predicate(p) => return Some((&p.op, &p.lhs.var_name)),
+                Expression::FieldComparison(c) => {
+                    self.pending = Some((&c.op, &c.rhs.var_name));
+                    return Some((&c.op, &c.lhs.var_name));
+                }
+                Expression::Bool(_) => {}
             }
         }
         None
@@ -42,7 +60,7 @@ impl<'a> Iterator for PredicateIterator<'a> {
 }
 
 impl Expression {
-    fn iter_predicates(&self) -> PredicateIterator {
+    fn iter_predicates(&self) -> PredicateIterator<'_> {
         PredicateIterator::new(self)
     }
 }
@@ -63,6 +81,7 @@ bitflags! {
         const IN = 1 << 9;
         const NOT_IN = 1 << 10;
         const CONTAINS = 1 << 11;
+        const GLOB = 1 << 12;
 
         const UNUSED = !(Self::EQUALS.bits()
             | Self::NOT_EQUALS.bits()
@@ -75,7 +94,8 @@ bitflags! {
             | Self::LESS_OR_EQUAL.bits()
             | Self::IN.bits()
             | Self::NOT_IN.bits()
-            | Self::CONTAINS.bits());
+            | Self::CONTAINS.bits()
+            | Self::GLOB.bits());
     }
 }
 
@@ -94,6 +114,7 @@ impl From<&BinaryOperator> for BinaryOperatorFlags {
             BinaryOperator::In => Self::IN,
             BinaryOperator::NotIn => Self::NOT_IN,
             BinaryOperator::Contains => Self::CONTAINS,
+            BinaryOperator::Glob => Self::GLOB,
         }
     }
 }
@@ -143,7 +164,6 @@ pub const ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL: i64 = 2;
 /// - `operators` must be a valid pointer to write `size_of::<u64>()` bytes and properly aligned.
 /// - `errbuf` must be valid for reading and writing `errbuf_len * size_of::<u8>()` bytes and properly aligned.
 /// - `errbuf_len` must be a valid pointer for reading and writing `size_of::<usize>()` bytes and properly aligned.
-
 #[no_mangle]
 pub unsafe extern "C" fn expression_validate(
     atc: *const u8,
@@ -155,32 +175,13 @@ pub unsafe extern "C" fn expression_validate(
     errbuf: *mut u8,
     errbuf_len: *mut usize,
 ) -> i64 {
-    use std::collections::HashSet;
-
-    use crate::parser::parse;
-    use crate::semantics::Validate;
-
-    let atc = ffi::CStr::from_ptr(atc as *const c_char).to_str().unwrap();
-    let errbuf = from_raw_parts_mut(errbuf, ERR_BUF_MAX_LEN);
-
-    // Parse the expression
-    let result = parse(atc).map_err(|e| e.to_string());
-    if let Err(e) = result {
-        let errlen = min(e.len(), *errbuf_len);
-        errbuf[..errlen].copy_from_slice(&e.as_bytes()[..errlen]);
-        *errbuf_len = errlen;
-        return ATC_ROUTER_EXPRESSION_VALIDATE_FAILED;
-    }
-    // Unwrap is safe since we've already checked for error
-    let ast = result.unwrap();
-
-    // Validate expression with schema
-    if let Err(e) = ast.validate(schema).map_err(|e| e.to_string()) {
-        let errlen = min(e.len(), *errbuf_len);
-        errbuf[..errlen].copy_from_slice(&e.as_bytes()[..errlen]);
-        *errbuf_len = errlen;
-        return ATC_ROUTER_EXPRESSION_VALIDATE_FAILED;
-    }
+    let ast = match parse_and_validate(atc, schema) {
+        Ok(ast) => ast,
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            return ATC_ROUTER_EXPRESSION_VALIDATE_FAILED;
+        }
+    };
 
     // Iterate over predicates to get fields and operators
     let mut ops = BinaryOperatorFlags::empty();
@@ -189,10 +190,8 @@ pub unsafe extern "C" fn expression_validate(
     let mut fields_buf_ptr = fields_buf;
     *fields_total = 0;
 
-    for pred in ast.iter_predicates() {
-        ops |= BinaryOperatorFlags::from(&pred.op);
-
-        let field = pred.lhs.var_name.as_str();
+    for (op, field) in ast.iter_predicates() {
+        ops |= BinaryOperatorFlags::from(op);
 
         if existed_fields.insert(field) {
             // Fields is not existed yet.
@@ -219,10 +218,216 @@ pub unsafe extern "C" fn expression_validate(
     ATC_ROUTER_EXPRESSION_VALIDATE_OK
 }
 
+/// # Safety
+///
+/// `atc` must be a valid pointer to a C-style string.
+unsafe fn parse_and_validate(atc: *const u8, schema: &Schema) -> Result<Expression, Error> {
+    let ast = parse(c_str(atc.cast(), "atc")?)?;
+    ast.validate(schema)?;
+    Ok(ast)
+}
+
+/// Validates `n` ATC expressions against a schema at once, and gets the
+/// elements of the valid ones, like calling [`expression_validate`] on
+/// each of them.
+///
+/// # Arguments
+///
+/// - `atcs`: an array of `n` C-style strings representing the ATC expressions.
+/// - `n`: the number of expressions.
+/// - `schema`: a valid pointer to a [`Schema`] object, as returned by [`schema_new`].
+/// - `statuses`: an array of `n` integers for storing the validation result of each expression,
+///   `ATC_ROUTER_EXPRESSION_VALIDATE_OK` or `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED`.
+/// - `fields_buf`: a buffer for storing the fields used in the valid expressions.
+/// - `fields_buf_len`: a pointer to the length of `fields_buf`.
+/// - `fields_total`: a pointer for storing the total number of unique fields used in the valid expressions.
+/// - `operators`: a pointer for storing the bitflags representing the operators used in the valid expressions.
+/// - `errbuf`: a buffer to store the error message of the first invalid expression.
+/// - `errbuf_len`: a pointer to the length of the error message buffer.
+///
+/// # Returns
+///
+/// An integer indicating the validation result:
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_OK` (0): Every expression is valid.
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED` (1): At least one expression is invalid, see `statuses`;
+///   `errbuf` and `errbuf_len` will be updated with the error message of the first one.
+///   Call [`expression_validate`] on the others to get their message.
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL` (2): The provided `fields_buf` is too small;
+///   `fields_buf_len` will be updated with the length needed. It takes precedence over
+///   `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED`, `statuses` are filled in either way.
+///
+/// Otherwise, this function writes the used fields to `fields_buf`, each one once and terminated
+/// by `\0`, and stores the number of bytes written in `fields_buf_len`.
+/// It stores the total number of fields in `fields_total`.
+///
+/// It writes the used operators as bitflags to `operators`, see [`expression_validate`].
+///
+/// # Safety
+///
+/// Violating any of the following constraints results in undefined behavior:
+///
+/// - If `n` is not `0`, `atcs` must be valid to read for `n * size_of::<*const u8>()` bytes and properly aligned,
+///   and each of its elements must satisfy the constraints of `atc` in [`expression_validate`].
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+/// - If `n` is not `0`, `statuses` must be valid for writing `n * size_of::<i64>()` bytes and properly aligned.
+/// - `fields_buf`, `fields_buf_len`, `fields_total`, `operators`, `errbuf` and `errbuf_len`
+///   must satisfy the same constraints as in [`expression_validate`].
+#[no_mangle]
+pub unsafe extern "C" fn expression_validate_batch(
+    atcs: *const *const u8,
+    n: usize,
+    schema: &Schema,
+    statuses: *mut i64,
+    fields_buf: *mut u8,
+    fields_buf_len: *mut usize,
+    fields_total: *mut usize,
+    operators: *mut u64,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> i64 {
+    let (atcs, statuses) = if n == 0 {
+        (&[][..], &mut [][..])
+    } else {
+        (from_raw_parts(atcs, n), from_raw_parts_mut(statuses, n))
+    };
+
+    let mut asts = Vec::with_capacity(n);
+    let mut failed = false;
+    for (&atc, status) in atcs.iter().zip(statuses.iter_mut()) {
+        match parse_and_validate(atc, schema) {
+            Ok(ast) => {
+                asts.push(ast);
+                *status = ATC_ROUTER_EXPRESSION_VALIDATE_OK;
+            }
+            Err(e) => {
+                if !failed {
+                    write_errbuf(&e, errbuf, errbuf_len);
+                    failed = true;
+                }
+                *status = ATC_ROUTER_EXPRESSION_VALIDATE_FAILED;
+            }
+        }
+    }
+
+    let mut ops = BinaryOperatorFlags::empty();
+    let mut fields = Vec::new();
+    let mut seen = HashSet::new();
+    for (op, field) in asts.iter().flat_map(|ast| ast.iter_predicates()) {
+        ops |= BinaryOperatorFlags::from(op);
+        if seen.insert(field) {
+            fields.push(field);
+        }
+    }
+    *operators = ops.bits();
+    *fields_total = fields.len();
+
+    let needed = fields.iter().map(|f| f.len() + 1).sum();
+    if *fields_buf_len < needed {
+        *fields_buf_len = needed;
+        return ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL;
+    }
+
+    if needed > 0 {
+        let mut buf = from_raw_parts_mut(fields_buf, needed);
+        for field in fields {
+            buf[..field.len()].copy_from_slice(field.as_bytes());
+            buf[field.len()] = b'\0';
+            buf = &mut buf[field.len() + 1..];
+        }
+    }
+    *fields_buf_len = needed;
+
+    if failed {
+        ATC_ROUTER_EXPRESSION_VALIDATE_FAILED
+    } else {
+        ATC_ROUTER_EXPRESSION_VALIDATE_OK
+    }
+}
+
+/// Lints an ATC expression valid against a schema, reporting parts of it
+/// that never or always hold, repeated predicates and regexes that are
+/// slower than they need to be, see [`lint`].
+///
+/// # Arguments
+///
+/// - `atc`: a C-style string representing the ATC expression.
+/// - `schema`: a valid pointer to a [`Schema`] object, as returned by [`schema_new`].
+/// - `warnings_buf`: a buffer for storing the warnings. Each one is written as its kind, such as
+///   `always_false`, followed by `\0`, then as the part of the expression it is about and what is
+///   wrong with it, followed by `\0`.
+/// - `warnings_buf_len`: a pointer to the length of `warnings_buf`.
+/// - `warnings_total`: a pointer for storing the number of warnings.
+/// - `errbuf`: a buffer to store any error messages.
+/// - `errbuf_len`: a pointer to the length of the error message buffer.
+///
+/// # Returns
+///
+/// An integer indicating the result:
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_OK` (0): The expression is valid, its warnings were written
+///   to `warnings_buf` and the number of bytes written stored in `warnings_buf_len`. There may
+///   be none.
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_FAILED` (1): The expression is invalid; `errbuf` and
+///   `errbuf_len` will be updated with an error message.
+/// - `ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL` (2): The provided `warnings_buf` is too
+///   small; `warnings_buf_len` will be updated with the length needed.
+///
+/// # Safety
+///
+/// Violating any of the following constraints results in undefined behavior:
+///
+/// - `atc` must be a valid pointer to a C-style string, properly aligned, and must not contain an internal `\0`.
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+/// - `warnings_buf` must be valid for writing `warnings_buf_len * size_of::<u8>()` bytes and properly aligned.
+/// - `warnings_buf_len` and `warnings_total` must be valid pointers to write `size_of::<usize>()` bytes and properly aligned.
+/// - `errbuf` and `errbuf_len` must satisfy the same constraints as in [`expression_validate`].
+#[no_mangle]
+pub unsafe extern "C" fn expression_lint(
+    atc: *const u8,
+    schema: &Schema,
+    warnings_buf: *mut u8,
+    warnings_buf_len: *mut usize,
+    warnings_total: *mut usize,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> i64 {
+    let ast = match parse_and_validate(atc, schema) {
+        Ok(ast) => ast,
+        Err(e) => {
+            write_errbuf(&e, errbuf, errbuf_len);
+            return ATC_ROUTER_EXPRESSION_VALIDATE_FAILED;
+        }
+    };
+
+    let warnings: Vec<_> = lint(&ast, schema)
+        .iter()
+        .flat_map(|w| [w.kind.as_str().to_string(), w.to_string()])
+        .collect();
+    *warnings_total = warnings.len() / 2;
+
+    let needed = warnings.iter().map(|w| w.len() + 1).sum();
+    if *warnings_buf_len < needed {
+        *warnings_buf_len = needed;
+        return ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL;
+    }
+
+    if needed > 0 {
+        let mut buf = from_raw_parts_mut(warnings_buf, needed);
+        for w in warnings {
+            buf[..w.len()].copy_from_slice(w.as_bytes());
+            buf[w.len()] = b'\0';
+            buf = &mut buf[w.len() + 1..];
+        }
+    }
+    *warnings_buf_len = needed;
+
+    ATC_ROUTER_EXPRESSION_VALIDATE_OK
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ast::Type;
+    use crate::ffi::ERR_BUF_MAX_LEN;
 
     fn expr_validate_on(
         schema: &Schema,
@@ -241,7 +446,7 @@ mod tests {
         let result = unsafe {
             expression_validate(
                 atc.as_bytes().as_ptr(),
-                &schema,
+                schema,
                 fields_buf.as_mut_ptr(),
                 &mut fields_buf_len,
                 &mut fields_total,
@@ -311,6 +516,23 @@ mod tests {
         assert_eq!(fields_buf_len, 47, "Fields buffer length mismatch");
     }
 
+    #[test]
+    fn test_expression_validate_field_comparison() {
+        let atc = r##"http.host == tls.sni && http.path ^= "/""##;
+
+        let mut schema = Schema::default();
+        schema.add_field("http.host", Type::String);
+        schema.add_field("http.path", Type::String);
+        schema.add_field("tls.sni", Type::String);
+
+        let (fields, _, ops) = expr_validate_on(&schema, atc, 28).unwrap();
+        assert_eq!(
+            ops,
+            (BinaryOperatorFlags::EQUALS | BinaryOperatorFlags::PREFIX).bits()
+        );
+        assert_eq!(fields, ["http.host", "http.path", "tls.sni"]);
+    }
+
     #[test]
     fn test_expression_validate_failed_parse() {
         let atc = r##"net.protocol ~ "^https?$" && net.dst.port == 80 && (net.src.ip not in 10.0.0.0/16 || net.src.ip in 10.0.1.0) && http.path contains "hello""##;
@@ -331,7 +553,7 @@ mod tests {
         );
         assert_eq!(
             err_message,
-            "In/NotIn operators only supports IP in CIDR".to_string(),
+            "In/NotIn operators only support String in List, IpAddr in IpCidr, IpAddr in CidrList and Int in IntRange".to_string(),
             "Error message mismatch"
         );
     }
@@ -360,6 +582,94 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_expression_validate_batch() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.dst.port", Type::Int);
+
+        let atcs = [
+            r#"http.path ^= "/a" && net.dst.port == 80"#,
+            r#"http.host == "a.com""#,
+            r#"http.path ~ "^/b""#,
+            "net.dst.port ==",
+        ]
+        .map(|atc| ffi::CString::new(atc).unwrap());
+        let ptrs: Vec<_> = atcs.iter().map(|atc| atc.as_ptr().cast()).collect();
+
+        let validate = |n: usize, fields_buf: &mut Vec<u8>| {
+            let mut statuses = vec![-1; n];
+            let mut fields_buf_len = fields_buf.len();
+            let mut fields_total = 0;
+            let mut operators = 0u64;
+            let mut errbuf = vec![0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = ERR_BUF_MAX_LEN;
+
+            let result = unsafe {
+                expression_validate_batch(
+                    ptrs.as_ptr(),
+                    n,
+                    &schema,
+                    statuses.as_mut_ptr(),
+                    fields_buf.as_mut_ptr(),
+                    &mut fields_buf_len,
+                    &mut fields_total,
+                    &mut operators,
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                )
+            };
+            let err = String::from_utf8(errbuf[..errbuf_len].to_vec()).unwrap();
+            (
+                result,
+                statuses,
+                fields_buf_len,
+                fields_total,
+                operators,
+                err,
+            )
+        };
+
+        let mut fields_buf = vec![0u8; 64];
+        let (result, statuses, fields_buf_len, fields_total, operators, err) =
+            validate(4, &mut fields_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_FAILED);
+        assert_eq!(
+            statuses,
+            [
+                ATC_ROUTER_EXPRESSION_VALIDATE_OK,
+                ATC_ROUTER_EXPRESSION_VALIDATE_FAILED,
+                ATC_ROUTER_EXPRESSION_VALIDATE_OK,
+                ATC_ROUTER_EXPRESSION_VALIDATE_FAILED,
+            ]
+        );
+        // only the fields and operators of the valid expressions
+        assert_eq!(fields_total, 2);
+        assert_eq!(&fields_buf[..fields_buf_len], b"net.dst.port\0http.path\0");
+        assert_eq!(
+            operators,
+            (BinaryOperatorFlags::EQUALS
+                | BinaryOperatorFlags::PREFIX
+                | BinaryOperatorFlags::REGEX)
+                .bits()
+        );
+        assert_eq!(err, "Unknown LHS field");
+
+        let mut fields_buf = vec![0u8; 8];
+        let (result, statuses, fields_buf_len, fields_total, _, _) = validate(1, &mut fields_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL);
+        assert_eq!(statuses, [ATC_ROUTER_EXPRESSION_VALIDATE_OK]);
+        assert_eq!(fields_total, 2);
+        // the length needed
+        assert_eq!(fields_buf_len, 23);
+
+        let mut fields_buf = Vec::new();
+        let (result, statuses, _, fields_total, operators, _) = validate(0, &mut fields_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_OK);
+        assert!(statuses.is_empty());
+        assert_eq!((fields_total, operators), (0, 0));
+    }
+
     #[test]
     fn test_expression_validate_buf_too_small() {
         let atc = r##"net.protocol ~ "^https?$" && net.dst.port == 80 && (net.src.ip not in 10.0.0.0/16 || net.src.ip in 10.0.1.0/24) && http.path contains "hello""##;
@@ -379,4 +689,67 @@ mod tests {
             "Error code mismatch"
         );
     }
+
+    #[test]
+    fn test_expression_lint() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.dst.port", Type::Int);
+
+        let lint_on = |atc: &str, warnings_buf: &mut Vec<u8>| {
+            let atc = ffi::CString::new(atc).unwrap();
+            let mut warnings_buf_len = warnings_buf.len();
+            let mut warnings_total = 0;
+            let mut errbuf = vec![0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = ERR_BUF_MAX_LEN;
+
+            let result = unsafe {
+                expression_lint(
+                    atc.as_bytes().as_ptr(),
+                    &schema,
+                    warnings_buf.as_mut_ptr(),
+                    &mut warnings_buf_len,
+                    &mut warnings_total,
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                )
+            };
+            let err = String::from_utf8(errbuf[..errbuf_len].to_vec()).unwrap();
+            (result, warnings_buf_len, warnings_total, err)
+        };
+
+        let atc = r#"http.path ~ "^/a" && net.dst.port == 1 && net.dst.port == 1"#;
+        let mut warnings_buf = vec![0u8; 256];
+        let (result, len, total, _) = lint_on(atc, &mut warnings_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_OK);
+        assert_eq!(total, 2);
+        let warnings: Vec<_> = warnings_buf[..len]
+            .split(|b| *b == b'\0')
+            .map(|w| std::str::from_utf8(w).unwrap())
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "simplifiable_regex",
+                r#"(http.path ~ "^/a"): the regex only matches literal text, `http.path ^= "/a"` is cheaper"#,
+                "duplicate_predicate",
+                "(net.dst.port == 1): appears more than once in the same `&&` chain",
+                "",
+            ]
+        );
+
+        let mut warnings_buf = vec![0u8; 8];
+        let (result, needed, _, _) = lint_on(atc, &mut warnings_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_BUF_TOO_SMALL);
+        assert_eq!(needed, len);
+
+        let mut warnings_buf = Vec::new();
+        let (result, len, total, _) = lint_on(r#"http.path ^= "/a""#, &mut warnings_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_OK);
+        assert_eq!((len, total), (0, 0));
+
+        let (result, _, _, err) = lint_on("http.host == 1", &mut warnings_buf);
+        assert_eq!(result, ATC_ROUTER_EXPRESSION_VALIDATE_FAILED);
+        assert_eq!(err, "Unknown LHS field");
+    }
 }
@@ -2,57 +2,974 @@ pub mod context;
 pub mod expression;
 pub mod router;
 pub mod schema;
+pub mod shared_router;
 
 use crate::ast::Value;
+use crate::error::{Error, ParseError};
 use cidr::IpCidr;
+use std::cell::Cell;
+use std::cmp::min;
 use std::convert::TryFrom;
 use std::ffi;
 use std::net::IpAddr;
 use std::os::raw::c_char;
-use std::slice::from_raw_parts;
+use std::slice::{from_raw_parts, from_raw_parts_mut};
 
 pub const ERR_BUF_MAX_LEN: usize = 4096;
 
+/// Version of the C API, returned by [`atc_router_api_version`]. Bumped
+/// whenever a function is removed or changes its signature or the layout
+/// of its arguments, so hosts written against another version can refuse
+/// to use the library instead of crashing.
+pub const ATC_ROUTER_API_VERSION: u32 = 1;
+
+/// Kinds of error reported by [`atc_router_last_error_kind`], one per
+/// [`Error`] variant. New kinds are only ever appended.
+pub const ATC_ROUTER_ERROR_NONE: u32 = 0;
+pub const ATC_ROUTER_ERROR_INVALID_ARGUMENT: u32 = 1;
+pub const ATC_ROUTER_ERROR_PARSE: u32 = 2;
+pub const ATC_ROUTER_ERROR_VALIDATION: u32 = 3;
+pub const ATC_ROUTER_ERROR_DUPLICATE_UUID: u32 = 4;
+pub const ATC_ROUTER_ERROR_LIMIT_EXCEEDED: u32 = 5;
+pub const ATC_ROUTER_ERROR_PRIORITY_OUT_OF_BAND: u32 = 6;
+pub const ATC_ROUTER_ERROR_PRIORITY_BAND_OVERLAP: u32 = 7;
+pub const ATC_ROUTER_ERROR_INVALID_ROUTE: u32 = 8;
+pub const ATC_ROUTER_ERROR_INVALID_SNAPSHOT: u32 = 9;
+pub const ATC_ROUTER_ERROR_QUOTA_EXCEEDED: u32 = 10;
+
+thread_local! {
+    static LAST_ERROR_KIND: Cell<u32> = const { Cell::new(ATC_ROUTER_ERROR_NONE) };
+    /// `(span, line_col, end_line_col)` of the last error if it was a
+    /// [`ParseError`].
+    static LAST_ERROR_LOCATION: Cell<Option<ErrorLocation>> = const { Cell::new(None) };
+}
+
+type ErrorLocation = ((usize, usize), (usize, usize), (usize, usize));
+
+/// The parse error `err` is, or was caused by.
+fn parse_error(err: &Error) -> Option<&ParseError> {
+    match err {
+        Error::ParseError(e) => Some(e),
+        Error::InvalidRoute(_, e) | Error::InvalidExpression(_, e) => parse_error(e),
+        _ => None,
+    }
+}
+
+fn error_kind(err: &Error) -> u32 {
+    match err {
+        Error::InvalidArgument(_) => ATC_ROUTER_ERROR_INVALID_ARGUMENT,
+        Error::ParseError(_) => ATC_ROUTER_ERROR_PARSE,
+        Error::ValidationError(_) => ATC_ROUTER_ERROR_VALIDATION,
+        Error::DuplicateUuid(_) => ATC_ROUTER_ERROR_DUPLICATE_UUID,
+        Error::LimitExceeded(_) => ATC_ROUTER_ERROR_LIMIT_EXCEEDED,
+        Error::PriorityOutOfBand(_) => ATC_ROUTER_ERROR_PRIORITY_OUT_OF_BAND,
+        Error::PriorityBandOverlap(_) => ATC_ROUTER_ERROR_PRIORITY_BAND_OVERLAP,
+        Error::InvalidRoute(..) => ATC_ROUTER_ERROR_INVALID_ROUTE,
+        // the index is in the message
+        Error::InvalidExpression(_, e) => error_kind(e),
+        Error::InvalidSnapshot(_) => ATC_ROUTER_ERROR_INVALID_SNAPSHOT,
+        Error::QuotaExceeded(_) => ATC_ROUTER_ERROR_QUOTA_EXCEEDED,
+    }
+}
+
+/// Returns the kind of the last error written to an error buffer on the
+/// calling thread, one of the `ATC_ROUTER_ERROR_*` constants, or
+/// `ATC_ROUTER_ERROR_NONE` if there was none yet.
+///
+/// Lets the host branch on the kind of error without parsing the message.
+#[no_mangle]
+pub extern "C" fn atc_router_last_error_kind() -> u32 {
+    LAST_ERROR_KIND.with(Cell::get)
+}
+
+/// Gets where in the expression the last parse error written to an error
+/// buffer on the calling thread is, so editors can underline the offending
+/// part of the expression.
+///
+/// # Arguments
+///
+/// - `start`, `end`: byte offsets of the offending input, equal when the
+///   parser stopped at a single position.
+/// - `line`, `column`: 1-based line and column of `start`. Columns count
+///   characters, not bytes.
+/// - `end_line`, `end_column`: the same for `end`.
+///
+/// # Returns
+///
+/// Returns `true` and stores the location in every argument that is not
+/// `NULL` if the last error was a parse error, otherwise `false`, leaving
+/// the arguments untouched.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - Every argument must be `NULL` or valid to write for
+///   `size_of::<usize>()` bytes, and it must be properly aligned.
+#[no_mangle]
+pub unsafe extern "C" fn atc_router_last_error_location(
+    start: *mut usize,
+    end: *mut usize,
+    line: *mut usize,
+    column: *mut usize,
+    end_line: *mut usize,
+    end_column: *mut usize,
+) -> bool {
+    let Some(((s, e), (l, c), (el, ec))) = LAST_ERROR_LOCATION.with(Cell::get) else {
+        return false;
+    };
+
+    for (out, value) in [
+        (start, s),
+        (end, e),
+        (line, l),
+        (column, c),
+        (end_line, el),
+        (end_column, ec),
+    ] {
+        if !out.is_null() {
+            *out = value;
+        }
+    }
+
+    true
+}
+
+/// Returns [`ATC_ROUTER_API_VERSION`] as compiled into the library.
+///
+/// Hosts loading the library dynamically should call this first and
+/// compare it with the `ATC_ROUTER_API_VERSION` of the header they were
+/// built against, before calling any constructor such as
+/// [`router_new`](router::router_new).
+#[no_mangle]
+pub extern "C" fn atc_router_api_version() -> u32 {
+    ATC_ROUTER_API_VERSION
+}
+
+/// Copies the message of `err` into the host supplied error buffer, truncated
+/// to `*errbuf_len` bytes, and stores the number of bytes written back into
+/// `errbuf_len`. The kind of `err` is recorded for
+/// [`atc_router_last_error_kind`], and its location for
+/// [`atc_router_last_error_location`].
+///
+/// # Safety
+///
+/// - `errbuf` must be valid to write for `*errbuf_len * size_of::<u8>()` bytes.
+/// - `errbuf_len` must be valid to read and write for `size_of::<usize>()` bytes,
+///   and it must be properly aligned.
+pub(crate) unsafe fn write_errbuf(err: &Error, errbuf: *mut u8, errbuf_len: *mut usize) {
+    record_error(err);
+
+    let err = err.to_string();
+    let errlen = min(err.len(), *errbuf_len);
+    from_raw_parts_mut(errbuf, errlen).copy_from_slice(&err.as_bytes()[..errlen]);
+    *errbuf_len = errlen;
+}
+
+/// Records the kind of `err` for [`atc_router_last_error_kind`], and its
+/// location for [`atc_router_last_error_location`], for functions without
+/// an error buffer.
+pub(crate) fn record_error(err: &Error) {
+    LAST_ERROR_KIND.with(|kind| kind.set(error_kind(err)));
+    LAST_ERROR_LOCATION.with(|location| {
+        location.set(parse_error(err).map(|e| (e.span, e.line_col, e.end_line_col)))
+    });
+}
+
+/// Borrows the C-style string `s`, failing with a message naming it as
+/// `what` if it is not valid UTF-8.
+///
+/// # Safety
+///
+/// - `s` must be a valid pointer to a C-style string that lives for `'a`.
+pub(crate) unsafe fn c_str<'a>(s: *const c_char, what: &str) -> Result<&'a str, Error> {
+    ffi::CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| Error::InvalidArgument(format!("{} is not a valid UTF-8 string: {}", what, e)))
+}
+
+/// A context value passed in by the host.
+///
+/// The implicit tags, in declaration order, are part of the C ABI and
+/// independent from [`Type::tag`] (there is no regex context value). New
+/// variants are only ever appended.
+///
+/// [`Type::tag`]: crate::ast::Type::tag
 #[derive(Debug)]
 #[repr(C)]
+#[non_exhaustive]
 pub enum CValue {
     Str(*const u8, usize),
     IpCidr(*const u8),
     IpAddr(*const u8),
     Int(i64),
+    Float(f64),
+    /// Like `Str`, replacing invalid UTF-8 sequences with `U+FFFD` instead
+    /// of failing. Meant for values the host does not control, such as
+    /// header values.
+    StrLossy(*const u8, usize),
 }
 
 impl TryFrom<&CValue> for Value {
-    type Error = String;
+    type Error = Error;
 
     fn try_from(v: &CValue) -> Result<Self, Self::Error> {
+        let invalid = |e: &dyn std::fmt::Display| Error::InvalidArgument(e.to_string());
+
         Ok(match v {
             CValue::Str(s, len) => Self::String(unsafe {
                 std::str::from_utf8(from_raw_parts(*s, *len))
-                    .map_err(|e| e.to_string())?
-                    .to_string()
+                    .map_err(|e| invalid(&e))?
+                    .into()
             }),
             CValue::IpCidr(s) => Self::IpCidr(
-                unsafe {
-                    ffi::CStr::from_ptr(*s as *const c_char)
-                        .to_str()
-                        .map_err(|e| e.to_string())?
-                        .to_string()
-                }
-                .parse::<IpCidr>()
-                .map_err(|e| e.to_string())?,
+                unsafe { c_str(*s as *const c_char, "IpCidr value")? }
+                    .parse::<IpCidr>()
+                    .map_err(|e| invalid(&e))?,
             ),
             CValue::IpAddr(s) => Self::IpAddr(
-                unsafe {
-                    ffi::CStr::from_ptr(*s as *const c_char)
-                        .to_str()
-                        .map_err(|e| e.to_string())?
-                        .to_string()
-                }
-                .parse::<IpAddr>()
-                .map_err(|e| e.to_string())?,
+                unsafe { c_str(*s as *const c_char, "IpAddr value")? }
+                    .parse::<IpAddr>()
+                    .map_err(|e| invalid(&e))?,
             ),
             CValue::Int(i) => Self::Int(*i),
+            CValue::Float(f) => Self::Float(*f),
+            CValue::StrLossy(s, len) => Self::String(unsafe {
+                String::from_utf8_lossy(from_raw_parts(*s, *len))
+                    .into_owned()
+                    .into()
+            }),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::context::*;
+    use super::router::*;
+    use super::schema::*;
+    use super::*;
+    use crate::ast::Type;
+    use crate::schema::LowerPolicy;
+    use std::ffi::CString;
+    use uuid::fmt::Hyphenated;
+
+    #[test]
+    fn test_api_version() {
+        assert_eq!(atc_router_api_version(), ATC_ROUTER_API_VERSION);
+
+        // the committed header is regenerated with the `header` feature
+        let header = include_str!("../../atc_router.h");
+        assert!(header.contains(&format!(
+            "#define ATC_ROUTER_API_VERSION {}",
+            ATC_ROUTER_API_VERSION
+        )));
+        assert!(header.contains("uint32_t atc_router_api_version(void);"));
+    }
+
+    // Exercises the whole FFI surface with the pointer types a C host
+    // passes in, without any casts, so signatures stay portable to targets
+    // where `c_char` is unsigned or `usize` is 32-bit.
+    #[test]
+    fn test_portable_ffi_types() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.path").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::String.tag());
+
+            let router = router_new(&*schema);
+            let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc = CString::new(r#"http.path ~ "^/(?<p>foo)""#).unwrap();
+            let mut errbuf = [0u8; 16];
+            let mut errbuf_len: usize = errbuf.len();
+            assert!(router_add_matcher(
+                &mut *router,
+                usize::MAX,
+                uuid.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+
+            let context = context_new(&*schema);
+            let value = "/foo/bar";
+            let value = CValue::Str(value.as_ptr(), value.len());
+            assert!(context_add_value(
+                &mut *context,
+                field.as_ptr(),
+                &value,
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert!(router_execute(&*router, &mut *context));
+
+            let mut uuid_hex = [0u8; Hyphenated::LENGTH];
+            let mut matched_value: *const u8 = std::ptr::null();
+            let mut matched_value_len: usize = 0;
+            // captures "0", "1" and "p"
+            let mut names = [std::ptr::null(); 3];
+            let mut names_len = [3usize; 3];
+            let mut values = [std::ptr::null(); 3];
+            let mut values_len = [3usize; 3];
+            let captures = context_get_result(
+                &*context,
+                uuid_hex.as_mut_ptr(),
+                field.as_ptr(),
+                &mut matched_value,
+                &mut matched_value_len,
+                names.as_mut_ptr(),
+                names_len.as_mut_ptr(),
+                values.as_mut_ptr(),
+                values_len.as_mut_ptr(),
+            );
+            assert_eq!(captures, 3);
+            assert_eq!(&uuid_hex[..], uuid.as_bytes());
+            assert_eq!(
+                std::slice::from_raw_parts(matched_value, matched_value_len),
+                b"/foo"
+            );
+
+            assert!(router_remove_matcher(
+                &mut *router,
+                usize::MAX,
+                uuid.as_ptr()
+            ));
+
+            context_free(context);
+            router_free(router);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_context_provider() {
+        unsafe extern "C" fn provider(
+            data: *mut ffi::c_void,
+            field: *const u8,
+            field_len: usize,
+            value: *mut CValue,
+        ) -> bool {
+            *(data as *mut usize) += 1;
+            match from_raw_parts(field, field_len) {
+                b"http.path" => {
+                    let path = "/foo";
+                    value.write(CValue::Str(path.as_ptr(), path.len()));
+                    true
+                }
+                // does not match the schema
+                b"net.port" => {
+                    value.write(CValue::Float(1.0));
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        unsafe {
+            let schema = schema_new();
+            for (field, typ) in [("http.path", Type::String), ("net.port", Type::Int)] {
+                let field = CString::new(field).unwrap();
+                schema_add_field(&mut *schema, field.as_ptr(), typ.tag());
+            }
+
+            let router = router_new(&*schema);
+            let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc = CString::new(r#"net.port == 1 || http.path == "/foo""#).unwrap();
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+            assert!(router_add_matcher(
+                &mut *router,
+                1,
+                uuid.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+
+            let mut calls = 0usize;
+            let context = context_new(&*schema);
+            context_set_provider(
+                &mut *context,
+                Some(provider),
+                (&mut calls as *mut usize).cast(),
+            );
+            assert!(router_execute(&*router, &mut *context));
+            assert!((*context).value_of("net.port").is_none());
+            assert_eq!(calls, 2);
+
+            context_set_provider(&mut *context, None, std::ptr::null_mut());
+            context_reset(&mut *context);
+            assert!(!router_execute(&*router, &mut *context));
+            assert_eq!(calls, 2);
+
+            context_free(context);
+            router_free(router);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_context_add_value_ref() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.path").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::String.tag());
+
+            let context = context_new(&*schema);
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+
+            let (a, b, c) = ("/a", b"/b", b"/c\xff");
+            for (value, borrowed) in [
+                (CValue::Str(a.as_ptr(), a.len()), true),
+                (CValue::StrLossy(b.as_ptr(), b.len()), true),
+                (CValue::StrLossy(c.as_ptr(), c.len()), false),
+            ] {
+                assert!(context_add_value_ref(
+                    &mut *context,
+                    field.as_ptr(),
+                    &value,
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                ));
+                let Some(Value::String(s)) = (*context).value_of("http.path").unwrap().last()
+                else {
+                    unreachable!()
+                };
+                assert_eq!(s.is_borrowed(), borrowed, "{:?}", s);
+            }
+            assert_eq!(
+                (*context).value_of("http.path").unwrap(),
+                [
+                    Value::String("/a".into()),
+                    Value::String("/b".into()),
+                    Value::String("/c\u{fffd}".into())
+                ]
+            );
+
+            let bad = b"/\xff";
+            assert!(!context_add_value_ref(
+                &mut *context,
+                field.as_ptr(),
+                &CValue::Str(bad.as_ptr(), bad.len()),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert_eq!((*context).value_of("http.path").unwrap().len(), 3);
+
+            context_reset(&mut *context);
+            assert!((*context).value_of("http.path").is_none());
+
+            context_free(context);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_context_add_values() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.headers.x_forwarded_for").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::String.tag());
+            let ip_field = CString::new("net.ip").unwrap();
+            schema_add_field(&mut *schema, ip_field.as_ptr(), Type::IpAddr.tag());
+
+            let context = context_new(&*schema);
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+
+            let (a, b) = ("10.0.0.1", b"10.0.0.2\xff");
+            let values = [
+                CValue::Str(a.as_ptr(), a.len()),
+                CValue::StrLossy(b.as_ptr(), b.len()),
+            ];
+            assert!(context_add_values(
+                &mut *context,
+                field.as_ptr(),
+                values.as_ptr(),
+                values.len(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert_eq!(
+                (*context).value_of("http.headers.x_forwarded_for").unwrap(),
+                [
+                    Value::String("10.0.0.1".into()),
+                    Value::String("10.0.0.2\u{fffd}".into())
+                ]
+            );
+
+            // nothing is added unless every value is valid
+            let ip = CString::new("10.0.0.1").unwrap();
+            let bad = CString::new(vec![b'1', 0xff]).unwrap();
+            let values = [
+                CValue::IpAddr(ip.as_ptr().cast()),
+                CValue::IpAddr(bad.as_ptr().cast()),
+            ];
+            assert!(!context_add_values(
+                &mut *context,
+                ip_field.as_ptr(),
+                values.as_ptr(),
+                values.len(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert!(std::str::from_utf8(&errbuf[..errbuf_len])
+                .unwrap()
+                .starts_with("IpAddr value is not a valid UTF-8 string"));
+            assert!((*context).value_of("net.ip").is_none());
+
+            errbuf_len = errbuf.len();
+            assert!(context_add_values(
+                &mut *context,
+                ip_field.as_ptr(),
+                std::ptr::null(),
+                0,
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert!((*context).value_of("net.ip").is_none());
+
+            context_free(context);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_context_add_map_value() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.headers").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::Map.tag());
+
+            let context = context_new(&*schema);
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+
+            let key = CString::new("x-request-id").unwrap();
+            let (a, b) = ("abc", b"def\xff");
+            for value in [
+                CValue::Str(a.as_ptr(), a.len()),
+                CValue::StrLossy(b.as_ptr(), b.len()),
+            ] {
+                assert!(context_add_map_value(
+                    &mut *context,
+                    field.as_ptr(),
+                    key.as_ptr(),
+                    &value,
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                ));
+            }
+            assert_eq!(
+                (*context)
+                    .map_value_of("http.headers", "x-request-id")
+                    .unwrap(),
+                [
+                    Value::String("abc".into()),
+                    Value::String("def\u{fffd}".into())
+                ]
+            );
+
+            assert!(!context_add_map_value(
+                &mut *context,
+                field.as_ptr(),
+                key.as_ptr(),
+                &CValue::Int(1),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert_eq!(&errbuf[..errbuf_len], b"map values must be strings");
+
+            context_free(context);
+            schema_free(schema);
+        }
+    }
+
+    // Tags of types this version does not know are rejected, never read
+    // as a `Type`.
+    #[test]
+    fn test_unknown_type_tag() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.headers.x").unwrap();
+            let unknown = Type::ALL.len() as u32;
+            assert!(!schema_add_field(&mut *schema, field.as_ptr(), unknown));
+            assert_eq!(
+                atc_router_last_error_kind(),
+                ATC_ROUTER_ERROR_INVALID_ARGUMENT
+            );
+            // literals only
+            let list = Type::List.tag();
+            assert!(!schema_add_field(&mut *schema, field.as_ptr(), list));
+            assert!((*schema).type_of("http.headers.x").is_none());
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_unknown_lower_policy_tag() {
+        unsafe {
+            let schema = schema_new();
+            assert!(schema_set_lower_policy(
+                &mut *schema,
+                LowerPolicy::Ascii.tag()
+            ));
+            let unknown = LowerPolicy::ALL.len() as u32;
+            assert!(!schema_set_lower_policy(&mut *schema, unknown));
+            assert_eq!(
+                atc_router_last_error_kind(),
+                ATC_ROUTER_ERROR_INVALID_ARGUMENT
+            );
+            assert_eq!((*schema).lower_policy(), LowerPolicy::Ascii);
+            schema_free(schema);
+        }
+    }
+
+    // Invalid UTF-8 from the host is reported, never a panic.
+    #[test]
+    fn test_invalid_utf8() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.headers.x").unwrap();
+            assert!(schema_add_field(
+                &mut *schema,
+                field.as_ptr(),
+                Type::String.tag()
+            ));
+            let bad = CString::new(vec![b'a', 0xff, b'b']).unwrap();
+            assert!(!schema_add_field(
+                &mut *schema,
+                bad.as_ptr(),
+                Type::String.tag()
+            ));
+            let ip_field = CString::new("net.ip").unwrap();
+            schema_add_field(&mut *schema, ip_field.as_ptr(), Type::IpAddr.tag());
+
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+            let mut add_matcher = |uuid: &CString, atc: &CString| {
+                errbuf_len = errbuf.len();
+                let router = router_new(&*schema);
+                let added = router_add_matcher(
+                    &mut *router,
+                    1,
+                    uuid.as_ptr(),
+                    atc.as_ptr(),
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                );
+                router_free(router);
+                assert!(!added);
+                std::str::from_utf8(&errbuf[..errbuf_len])
+                    .unwrap()
+                    .to_string()
+            };
+
+            let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc = CString::new(r#"http.headers.x == "a""#).unwrap();
+            assert!(add_matcher(&uuid, &bad).starts_with("atc is not a valid UTF-8 string"));
+            assert!(add_matcher(&bad, &atc).starts_with("uuid is not a valid UTF-8 string"));
+            let not_uuid = CString::new("not-a-uuid").unwrap();
+            assert!(add_matcher(&not_uuid, &atc).starts_with("invalid UUID format"));
+            assert_eq!(
+                atc_router_last_error_kind(),
+                ATC_ROUTER_ERROR_INVALID_ARGUMENT
+            );
+
+            let router = router_new(&*schema);
+            assert!(!router_remove_matcher(&mut *router, 1, bad.as_ptr()));
+            assert!(!router_remove_matcher(&mut *router, 1, not_uuid.as_ptr()));
+            router_free(router);
+
+            let context = context_new(&*schema);
+            let value = b"a\xffb";
+            let mut add_value = |field: &CString, value: &CValue| {
+                errbuf_len = errbuf.len();
+                let added = context_add_value(
+                    &mut *context,
+                    field.as_ptr(),
+                    value,
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                );
+                added.then_some(()).ok_or_else(|| {
+                    std::str::from_utf8(&errbuf[..errbuf_len])
+                        .unwrap()
+                        .to_string()
+                })
+            };
+
+            let strict = CValue::Str(value.as_ptr(), value.len());
+            assert!(add_value(&field, &strict)
+                .unwrap_err()
+                .starts_with("invalid utf-8"));
+            let lossy = CValue::StrLossy(value.as_ptr(), value.len());
+            assert!(add_value(&bad, &lossy)
+                .unwrap_err()
+                .starts_with("field is not a valid UTF-8 string"));
+            assert_eq!(add_value(&field, &lossy), Ok(()));
+            assert_eq!(
+                (*context).value_of("http.headers.x").unwrap(),
+                [Value::String("a\u{fffd}b".into())]
+            );
+            // embedded NULs are fine in length delimited strings
+            let nul = b"a\0b";
+            assert_eq!(
+                add_value(&field, &CValue::Str(nul.as_ptr(), nul.len())),
+                Ok(())
+            );
+
+            let ip = CValue::IpAddr(bad.as_ptr().cast());
+            assert!(add_value(&ip_field, &ip)
+                .unwrap_err()
+                .starts_with("IpAddr value is not a valid UTF-8 string"));
+
+            context_free(context);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_shared_router() {
+        use super::shared_router::*;
+
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.path").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::String.tag());
+
+            let shared = shared_router_new(router_new(&*schema));
+            let context = context_new(&*schema);
+            (*context).add_value_str("http.path", "/a");
+            assert!(!shared_router_execute(&*shared, &mut *context));
+
+            let router = router_new(&*schema);
+            let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc = CString::new(r#"http.path ^= "/""#).unwrap();
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+            assert!(router_add_matcher(
+                &mut *router,
+                1,
+                uuid.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            shared_router_replace(&*shared, router);
+
+            context_reset(&mut *context);
+            (*context).add_value_str("http.path", "/a");
+            assert!(shared_router_execute(&*shared, &mut *context));
+
+            context_free(context);
+            shared_router_free(shared);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_schema_get_fields() {
+        unsafe {
+            let schema = schema_new();
+            let null = std::ptr::null_mut();
+            assert_eq!(
+                schema_get_fields(&*schema, null, std::ptr::null_mut(), std::ptr::null_mut()),
+                0
+            );
+
+            for (field, typ) in [("http.path", Type::String), ("net.src.ip", Type::IpAddr)] {
+                let field = CString::new(field).unwrap();
+                schema_add_field(&mut *schema, field.as_ptr(), typ.tag());
+            }
+            assert_eq!(
+                schema_get_fields(&*schema, null, std::ptr::null_mut(), std::ptr::null_mut()),
+                2
+            );
+
+            let mut fields = [std::ptr::null(); 2];
+            let mut fields_len = [2usize; 2];
+            let mut types = [Type::Int; 2];
+            assert_eq!(
+                schema_get_fields(
+                    &*schema,
+                    fields.as_mut_ptr(),
+                    fields_len.as_mut_ptr(),
+                    types.as_mut_ptr(),
+                ),
+                2
+            );
+
+            let mut got: Vec<_> = (0..2)
+                .map(|i| {
+                    let name = from_raw_parts(fields[i], fields_len[i]);
+                    (std::str::from_utf8(name).unwrap(), types[i])
+                })
+                .collect();
+            got.sort_unstable_by_key(|(name, _)| *name);
+            assert_eq!(
+                got,
+                [("http.path", Type::String), ("net.src.ip", Type::IpAddr)]
+            );
+
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_context_get_evidence() {
+        unsafe {
+            let schema = schema_new();
+            for (field, typ) in [("http.path", Type::String), ("net.port", Type::Int)] {
+                let field = CString::new(field).unwrap();
+                schema_add_field(&mut *schema, field.as_ptr(), typ.tag());
+            }
+
+            let router = router_new(&*schema);
+            let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc =
+                CString::new(r#"http.path ^= "/a" && http.path =^ "b" && net.port > 1"#).unwrap();
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = errbuf.len();
+            assert!(router_add_matcher(
+                &mut *router,
+                1,
+                uuid.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+
+            let context = context_new(&*schema);
+            let count = |context: &crate::context::Context| {
+                let null = std::ptr::null_mut();
+                context_get_evidence(
+                    context,
+                    null,
+                    std::ptr::null_mut(),
+                    null,
+                    std::ptr::null_mut(),
+                    null,
+                    std::ptr::null_mut(),
+                )
+            };
+            assert_eq!(count(&*context), -1);
+
+            (*context).add_value_str("http.path", "/a/b");
+            (*context).add_value_int("net.port", 80);
+            assert!(router_execute(&*router, &mut *context));
+            assert_eq!(count(&*context), 3);
+
+            let mut fields = [std::ptr::null(); 3];
+            let mut fields_len = [3usize; 3];
+            let mut ops = [std::ptr::null(); 3];
+            let mut ops_len = [0usize; 3];
+            let mut values = [std::ptr::null(); 3];
+            let mut values_len = [0usize; 3];
+            assert_eq!(
+                context_get_evidence(
+                    &*context,
+                    fields.as_mut_ptr(),
+                    fields_len.as_mut_ptr(),
+                    ops.as_mut_ptr(),
+                    ops_len.as_mut_ptr(),
+                    values.as_mut_ptr(),
+                    values_len.as_mut_ptr(),
+                ),
+                3
+            );
+
+            let string = |p: *const u8, len| {
+                (!p.is_null()).then(|| std::str::from_utf8(from_raw_parts(p, len)).unwrap())
+            };
+            let evidence: Vec<_> = (0..3)
+                .map(|i| {
+                    (
+                        string(fields[i], fields_len[i]).unwrap(),
+                        string(ops[i], ops_len[i]).unwrap(),
+                        string(values[i], values_len[i]),
+                    )
+                })
+                .collect();
+            assert_eq!(
+                evidence,
+                [
+                    ("http.path", "^=", Some("/a/b")),
+                    ("http.path", "=^", Some("/a/b")),
+                    ("net.port", ">", None),
+                ]
+            );
+
+            context_free(context);
+            router_free(router);
+            schema_free(schema);
+        }
+    }
+
+    #[test]
+    fn test_errbuf_smaller_than_max_len() {
+        let mut errbuf = [b'X'; 8];
+        let mut errbuf_len = 4;
+
+        let err = Error::InvalidArgument("some error".to_string());
+        unsafe { write_errbuf(&err, errbuf.as_mut_ptr(), &mut errbuf_len) };
+
+        assert_eq!(errbuf_len, 4);
+        assert_eq!(&errbuf, b"someXXXX");
+        assert_eq!(
+            atc_router_last_error_kind(),
+            ATC_ROUTER_ERROR_INVALID_ARGUMENT
+        );
+    }
+
+    #[test]
+    fn test_last_error_location() {
+        unsafe {
+            let schema = schema_new();
+            let field = CString::new("http.path").unwrap();
+            schema_add_field(&mut *schema, field.as_ptr(), Type::String.tag());
+            let router = router_new(&*schema);
+
+            let mut errbuf = [0u8; ERR_BUF_MAX_LEN];
+            let mut add_matcher = |atc: &str| {
+                let uuid = CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+                let atc = CString::new(atc).unwrap();
+                let mut errbuf_len = errbuf.len();
+                router_add_matcher(
+                    &mut *router,
+                    1,
+                    uuid.as_ptr(),
+                    atc.as_ptr(),
+                    errbuf.as_mut_ptr(),
+                    &mut errbuf_len,
+                )
+            };
+            let location = || {
+                let mut out = [0; 6];
+                let [s, e, l, c, el, ec] = &mut out;
+                atc_router_last_error_location(s, e, l, c, el, ec).then_some(out)
+            };
+
+            assert!(!add_matcher("http.path == \"/\" &&\n  http.path ~ \"(\""));
+            assert_eq!(atc_router_last_error_kind(), ATC_ROUTER_ERROR_PARSE);
+            assert_eq!(location(), Some([34, 37, 2, 15, 2, 18]));
+
+            // only the arguments that are not `NULL` are written
+            let mut line = 0;
+            let null = std::ptr::null_mut();
+            assert!(atc_router_last_error_location(
+                null, null, &mut line, null, null, null
+            ));
+            assert_eq!(line, 2);
+
+            // other errors have no location
+            assert!(!add_matcher("http.path == 1"));
+            assert_eq!(atc_router_last_error_kind(), ATC_ROUTER_ERROR_VALIDATION);
+            assert_eq!(location(), None);
+
+            router_free(router);
+            schema_free(schema);
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use crate::context::Context;
-use crate::ffi::ERR_BUF_MAX_LEN;
-use crate::router::Router;
+use crate::error::Error;
+use crate::ffi::{c_str, write_errbuf};
+use crate::router::{Priority, Router};
 use crate::schema::Schema;
-use std::cmp::min;
-use std::ffi;
 use std::os::raw::c_char;
 use std::slice::from_raw_parts_mut;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
 /// Create a new router object associated with the schema.
@@ -24,7 +24,7 @@ use uuid::Uuid;
 ///
 /// - `schema` must be a valid pointer returned by [`schema_new`].
 #[no_mangle]
-pub unsafe extern "C" fn router_new(schema: &Schema) -> *mut Router {
+pub unsafe extern "C" fn router_new(schema: &Schema) -> *mut Router<'_> {
     Box::into_raw(Box::new(Router::new(schema)))
 }
 
@@ -44,6 +44,44 @@ pub unsafe extern "C" fn router_free(router: *mut Router) {
     drop(Box::from_raw(router));
 }
 
+/// Limit the number of matchers the router accepts.
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `max_matchers`: the maximum number of matchers, `0` means unlimited.
+///
+/// Once the limit is reached, [`router_add_matcher`] returns `false` with
+/// an error message in `errbuf` until some matchers are removed.
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+#[no_mangle]
+pub unsafe extern "C" fn router_set_max_matchers(router: &mut Router, max_matchers: usize) {
+    router.set_max_matchers(if max_matchers == 0 {
+        None
+    } else {
+        Some(max_matchers)
+    });
+}
+
+/// Parses the C-style string `uuid` as a UUID.
+///
+/// # Safety
+///
+/// - `uuid` must be a valid pointer to a C-style string.
+unsafe fn c_uuid(uuid: *const c_char) -> Result<Uuid, Error> {
+    let uuid = c_str(uuid, "uuid")?;
+    Uuid::try_parse(uuid).map_err(|e| Error::InvalidArgument(format!("invalid UUID format: {}", e)))
+}
+
 /// Add a new matcher to the router.
 ///
 /// # Arguments
@@ -65,14 +103,8 @@ pub unsafe extern "C" fn router_free(router: *mut Router) {
 /// # Errors
 ///
 /// This function will return `false` if the matcher could not be added to the router,
-/// such as duplicate UUID, and invalid ATC expression.
-///
-/// # Panics
-///
-/// This function will panic when:
-///
-/// - `uuid` doesn't point to a ASCII sequence representing a valid 128-bit UUID.
-/// - `atc` doesn't point to a valid C-style string.
+/// such as duplicate UUID, invalid ATC expression, `uuid` not representing a valid
+/// 128-bit UUID or `atc` not being valid UTF-8.
 ///
 /// # Safety
 ///
@@ -80,32 +112,61 @@ pub unsafe extern "C" fn router_free(router: *mut Router) {
 ///
 /// - `router` must be a valid pointer returned by [`router_new`].
 /// - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
-///    and must not have '\0' in the middle.
+///   and must not have '\0' in the middle.
 /// - `atc` must be a valid pointer to a C-style string, must be properly aligned,
-///    and must not have '\0' in the middle.
+///   and must not have '\0' in the middle.
 /// - `errbuf` must be valid to read and write for `errbuf_len * size_of::<u8>()` bytes,
-///    and it must be properly aligned.
+///   and it must be properly aligned.
 /// - `errbuf_len` must be valid to read and write for `size_of::<usize>()` bytes,
-///    and it must be properly aligned.
+///   and it must be properly aligned.
 #[no_mangle]
 pub unsafe extern "C" fn router_add_matcher(
     router: &mut Router,
     priority: usize,
-    uuid: *const i8,
-    atc: *const i8,
+    uuid: *const c_char,
+    atc: *const c_char,
     errbuf: *mut u8,
     errbuf_len: *mut usize,
 ) -> bool {
-    let uuid = ffi::CStr::from_ptr(uuid as *const c_char).to_str().unwrap();
-    let atc = ffi::CStr::from_ptr(atc as *const c_char).to_str().unwrap();
-    let errbuf = from_raw_parts_mut(errbuf, ERR_BUF_MAX_LEN);
+    let result = c_uuid(uuid).and_then(|uuid| {
+        let atc = c_str(atc, "atc")?;
+        router.add_matcher(priority, uuid, atc)
+    });
 
-    let uuid = Uuid::try_parse(uuid).expect("invalid UUID format");
+    if let Err(e) = result {
+        write_errbuf(&e, errbuf, errbuf_len);
+        return false;
+    }
+
+    true
+}
 
-    if let Err(e) = router.add_matcher(priority, uuid, atc) {
-        let errlen = min(e.len(), *errbuf_len);
-        errbuf[..errlen].copy_from_slice(&e.as_bytes()[..errlen]);
-        *errbuf_len = errlen;
+/// Add a new matcher to the router with a two level priority.
+///
+/// The same as [`router_add_matcher`], except that matchers are ordered by
+/// `priority` first and `minor` second, see [`Priority`].
+/// [`router_add_matcher`] is the same as passing a `minor` of `0`.
+///
+/// # Safety
+///
+/// The same constraints as for [`router_add_matcher`] apply.
+#[no_mangle]
+pub unsafe extern "C" fn router_add_matcher_at(
+    router: &mut Router,
+    priority: usize,
+    minor: u32,
+    uuid: *const c_char,
+    atc: *const c_char,
+    errbuf: *mut u8,
+    errbuf_len: *mut usize,
+) -> bool {
+    let result = c_uuid(uuid).and_then(|uuid| {
+        let atc = c_str(atc, "atc")?;
+        router.add_matcher_at(Priority::new(priority, minor), uuid, atc)
+    });
+
+    if let Err(e) = result {
+        write_errbuf(&e, errbuf, errbuf_len);
         return false;
     }
 
@@ -123,11 +184,8 @@ pub unsafe extern "C" fn router_add_matcher(
 ///
 /// Returns `true` if the matcher was removed successfully, otherwise `false`,
 /// such as when the matcher with the specified UUID doesn't exist or
-/// the priority doesn't match the UUID.
-///
-/// # Panics
-///
-/// This function will panic when `uuid` doesn't point to a ASCII sequence
+/// the priority doesn't match the UUID, or when `uuid` doesn't represent a valid
+/// 128-bit UUID.
 ///
 /// # Safety
 ///
@@ -135,17 +193,35 @@ pub unsafe extern "C" fn router_add_matcher(
 ///
 /// - `router` must be a valid pointer returned by [`router_new`].
 /// - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
-///    and must not have '\0' in the middle.
+///   and must not have '\0' in the middle.
 #[no_mangle]
 pub unsafe extern "C" fn router_remove_matcher(
     router: &mut Router,
     priority: usize,
-    uuid: *const i8,
+    uuid: *const c_char,
 ) -> bool {
-    let uuid = ffi::CStr::from_ptr(uuid as *const c_char).to_str().unwrap();
-    let uuid = Uuid::try_parse(uuid).expect("invalid UUID format");
+    match c_uuid(uuid) {
+        Ok(uuid) => router.remove_matcher(priority, uuid),
+        Err(_) => false,
+    }
+}
 
-    router.remove_matcher(priority, uuid)
+/// Remove a matcher added with [`router_add_matcher_at`].
+///
+/// # Safety
+///
+/// The same constraints as for [`router_remove_matcher`] apply.
+#[no_mangle]
+pub unsafe extern "C" fn router_remove_matcher_at(
+    router: &mut Router,
+    priority: usize,
+    minor: u32,
+    uuid: *const c_char,
+) -> bool {
+    match c_uuid(uuid) {
+        Ok(uuid) => router.remove_matcher_at(Priority::new(priority, minor), uuid),
+        Err(_) => false,
+    }
 }
 
 /// Execute the router with the context.
@@ -165,13 +241,159 @@ pub unsafe extern "C" fn router_remove_matcher(
 ///
 /// - `router` must be a valid pointer returned by [`router_new`].
 /// - `context` must be a valid pointer returned by [`context_new`],
-///    and must be reset by [`context_reset`] before calling this function
-///    if you want to reuse the same context for multiple matches.
+///   and must be reset by [`context_reset`] before calling this function
+///   if you want to reuse the same context for multiple matches.
 #[no_mangle]
 pub unsafe extern "C" fn router_execute(router: &Router, context: &mut Context) -> bool {
     router.execute(context)
 }
 
+/// Execute the router with the context, only evaluating the matchers whose
+/// priority is between `min_priority` and `max_priority`, both included.
+///
+/// See [`Router::execute_range`].
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `context`: a pointer to the [`Context`] object.
+/// - `min_priority`: the lowest priority evaluated.
+/// - `max_priority`: the highest priority evaluated.
+///
+/// # Returns
+///
+/// Returns `true` if found a match, `false` means no match found.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+/// - `context` must be a valid pointer returned by [`context_new`],
+///   and must be reset by [`context_reset`] before calling this function
+///   if you want to reuse the same context for multiple matches.
+#[no_mangle]
+pub unsafe extern "C" fn router_execute_range(
+    router: &Router,
+    context: &mut Context,
+    min_priority: usize,
+    max_priority: usize,
+) -> bool {
+    router.execute_range(context, min_priority..=max_priority)
+}
+
+/// Returned by [`router_execute_deadline`] when no matcher matched.
+pub const ATC_ROUTER_EXECUTE_NO_MATCH: i64 = 0;
+/// Returned by [`router_execute_deadline`] when a matcher matched.
+pub const ATC_ROUTER_EXECUTE_MATCH: i64 = 1;
+/// Returned by [`router_execute_deadline`] when the time ran out before
+/// every matcher was evaluated.
+pub const ATC_ROUTER_EXECUTE_TIMED_OUT: i64 = 2;
+
+/// Execute the router with the context, giving up if evaluating the matchers
+/// takes longer than `deadline_ns`.
+///
+/// The time is checked before each matcher is evaluated, see
+/// [`Router::execute_deadline`].
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `context`: a pointer to the [`Context`] object.
+/// - `deadline_ns`: how long the evaluation may take, in nanoseconds from the
+///   call.
+///
+/// # Returns
+///
+/// Returns one of [`ATC_ROUTER_EXECUTE_NO_MATCH`], [`ATC_ROUTER_EXECUTE_MATCH`]
+/// or [`ATC_ROUTER_EXECUTE_TIMED_OUT`]. After a timeout the context holds no
+/// result.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+/// - `context` must be a valid pointer returned by [`context_new`],
+///   and must be reset by [`context_reset`] before calling this function
+///   if you want to reuse the same context for multiple matches.
+#[no_mangle]
+pub unsafe extern "C" fn router_execute_deadline(
+    router: &Router,
+    context: &mut Context,
+    deadline_ns: u64,
+) -> i64 {
+    // a deadline too far away to represent is no deadline at all
+    let result = match Instant::now().checked_add(Duration::from_nanos(deadline_ns)) {
+        Some(deadline) => router.execute_deadline(context, deadline),
+        None => Ok(router.execute(context)),
+    };
+
+    match result {
+        Ok(true) => ATC_ROUTER_EXECUTE_MATCH,
+        Ok(false) => ATC_ROUTER_EXECUTE_NO_MATCH,
+        Err(_) => ATC_ROUTER_EXECUTE_TIMED_OUT,
+    }
+}
+
+/// Execute the router with the context, collecting every matching matcher
+/// instead of stopping at the first one.
+///
+/// The first match, which is the one [`router_execute`] would have picked, is
+/// stored in the context and can be read with [`context_get_result`].
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `context`: a pointer to the [`Context`] object.
+/// - `uuids_hex`: a buffer the hyphenated UUIDs of the matching matchers will be
+///   written to in evaluation order, back to back, each taking 36 bytes.
+///   May be `NULL` if `len` is `0`.
+/// - `len`: the number of UUIDs `uuids_hex` can hold. Matches beyond it are counted
+///   but not written.
+///
+/// # Returns
+///
+/// Returns the number of matching matchers, `0` means no match found.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+/// - `context` must be a valid pointer returned by [`context_new`],
+///   and must be reset by [`context_reset`] before calling this function
+///   if you want to reuse the same context for multiple matches.
+/// - If `len` is not `0`, `uuids_hex` must be valid to read and write for
+///   `len * 36 * size_of::<u8>()` bytes.
+///
+/// [`context_get_result`]: crate::ffi::context::context_get_result
+#[no_mangle]
+pub unsafe extern "C" fn router_execute_all(
+    router: &Router,
+    context: &mut Context,
+    uuids_hex: *mut u8,
+    len: usize,
+) -> usize {
+    use uuid::fmt::Hyphenated;
+
+    let matches = router.execute_all(context);
+
+    if len > 0 {
+        let uuids_hex = from_raw_parts_mut(uuids_hex, len * Hyphenated::LENGTH);
+        for (i, m) in matches.iter().take(len).enumerate() {
+            m.uuid
+                .as_hyphenated()
+                .encode_lower(&mut uuids_hex[i * Hyphenated::LENGTH..]);
+        }
+    }
+
+    let n = matches.len();
+    context.result = matches.into_iter().next();
+
+    n
+}
+
 /// Get the de-duplicated fields that are actually used in the router.
 /// This is useful when you want to know what fields are actually used in the router,
 /// so you can generate their values on-demand.
@@ -180,9 +402,9 @@ pub unsafe extern "C" fn router_execute(router: &Router, context: &mut Context)
 ///
 /// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
 /// - `fields`: a pointer to an array of pointers to the field names
-///    (NOT C-style strings) that are actually used in the router, which will be filled in.
-///    if `fields` is `NULL`, this function will only return the number of fields used
-///    in the router.
+///   (NOT C-style strings) that are actually used in the router, which will be filled in.
+///   if `fields` is `NULL`, this function will only return the number of fields used
+///   in the router.
 /// - `fields_len`: a pointer to an array of the length of each field name.
 ///
 /// # Lifetimes
@@ -196,7 +418,9 @@ pub unsafe extern "C" fn router_execute(router: &Router, context: &mut Context)
 ///
 /// # Returns
 ///
-/// Returns the number of fields that are actually used in the router.
+/// Returns the number of fields that are actually used in the router. They
+/// are listed in the order the router first saw them, which adding and
+/// removing matchers does not change.
 ///
 /// # Errors
 ///
@@ -227,7 +451,7 @@ pub unsafe extern "C" fn router_get_fields(
         let fields = from_raw_parts_mut(fields, *fields_len);
         let fields_len = from_raw_parts_mut(fields_len, *fields_len);
 
-        for (i, k) in router.fields.keys().enumerate() {
+        for (i, (k, _)) in router.fields.iter().enumerate() {
             fields[i] = k.as_bytes().as_ptr();
             fields_len[i] = k.len()
         }
@@ -236,9 +460,207 @@ pub unsafe extern "C" fn router_get_fields(
     router.fields.len()
 }
 
+/// Get the de-duplicated fields used by a single matcher of the router, so
+/// the values a specific route needs can be generated on-demand.
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `priority`: the priority of the matcher.
+/// - `uuid`: the C-style string representing the UUID of the matcher.
+/// - `fields`: a pointer to an array of pointers to the field names
+///   (NOT C-style strings) used by the matcher, which will be filled in.
+///   If `fields` is `NULL`, this function will only return the number of fields.
+/// - `fields_len`: a pointer to an array of the length of each field name,
+///   see [`router_get_fields`].
+///
+/// The string pointers stored in `fields` are invalidated like the ones of
+/// [`router_get_fields`].
+///
+/// # Returns
+///
+/// Returns the number of fields used by the matcher, or `-1` if there is no
+/// such matcher or `uuid` doesn't represent a valid 128-bit UUID.
+///
+/// # Safety
+///
+/// The same constraints as for [`router_get_fields`] apply, and:
+///
+/// - `uuid` must be a valid pointer to a C-style string, must be properly aligned,
+///   and must not have '\0' in the middle.
+#[no_mangle]
+pub unsafe extern "C" fn router_get_matcher_fields(
+    router: &Router,
+    priority: usize,
+    uuid: *const c_char,
+    fields: *mut *const u8,
+    fields_len: *mut usize,
+) -> i64 {
+    let matcher_fields = match c_uuid(uuid) {
+        Ok(uuid) => match router.fields_of(priority, uuid) {
+            Some(f) => f,
+            None => return -1,
+        },
+        Err(_) => return -1,
+    };
+
+    if !fields.is_null() {
+        assert!(!fields_len.is_null());
+        assert!(*fields_len >= matcher_fields.len());
+
+        let fields = from_raw_parts_mut(fields, *fields_len);
+        let fields_len = from_raw_parts_mut(fields_len, *fields_len);
+
+        for (i, k) in matcher_fields.iter().enumerate() {
+            fields[i] = k.as_bytes().as_ptr();
+            fields_len[i] = k.len()
+        }
+    }
+
+    matcher_fields.len() as i64
+}
+
+/// Get the hit counters of the matchers in the router.
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`].
+/// - `uuids_hex`: a buffer the hyphenated UUIDs of the matchers will be written to,
+///   back to back, each taking 36 bytes. If `NULL`, only the number of matchers is returned.
+/// - `priorities`: a pointer to an array the priorities of the matchers will be written to.
+/// - `counts`: a pointer to an array the hit counts of the matchers will be written to.
+/// - `len`: the number of elements `priorities` and `counts` can hold.
+///
+/// Matchers are reported in evaluation order.
+///
+/// # Returns
+///
+/// Returns the number of matchers in the router.
+///
+/// # Panics
+///
+/// This function will panic if `uuids_hex` is not `NULL` and `len` is smaller
+/// than the number of matchers in the router.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+/// - If `uuids_hex` is not `NULL`, `uuids_hex` must be valid to read and write for
+///   `len * 36 * size_of::<u8>()` bytes, `priorities` must be valid to read and write
+///   for `len * size_of::<usize>()` bytes, and `counts` must be valid to read and write
+///   for `len * size_of::<u64>()` bytes, all properly aligned.
+#[cfg(feature = "hit-counters")]
+#[no_mangle]
+pub unsafe extern "C" fn router_get_hit_counts(
+    router: &Router,
+    uuids_hex: *mut u8,
+    priorities: *mut usize,
+    counts: *mut u64,
+    len: usize,
+) -> usize {
+    use uuid::fmt::Hyphenated;
+
+    let hits = router.hit_counts();
+
+    if !uuids_hex.is_null() {
+        assert!(len >= hits.len());
+
+        let uuids_hex = from_raw_parts_mut(uuids_hex, len * Hyphenated::LENGTH);
+        let priorities = from_raw_parts_mut(priorities, len);
+        let counts = from_raw_parts_mut(counts, len);
+
+        for (i, (priority, uuid, count)) in hits.iter().enumerate() {
+            uuid.as_hyphenated()
+                .encode_lower(&mut uuids_hex[i * Hyphenated::LENGTH..]);
+            priorities[i] = *priority;
+            counts[i] = *count;
+        }
+    }
+
+    hits.len()
+}
+
+/// Get the number of [`router_execute`] calls that did not match anything.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+#[cfg(feature = "hit-counters")]
+#[no_mangle]
+pub unsafe extern "C" fn router_get_miss_count(router: &Router) -> u64 {
+    router.miss_count()
+}
+
+/// Reset all hit and miss counters of the router to zero.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+#[cfg(feature = "hit-counters")]
+#[no_mangle]
+pub unsafe extern "C" fn router_reset_hit_counts(router: &Router) {
+    router.reset_hit_counts()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ffi::ERR_BUF_MAX_LEN;
+    use std::ffi;
+
+    #[test]
+    fn test_execute_deadline() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", crate::ast::Type::String);
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(0, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+
+        let mut context = Context::new(&schema);
+        context.add_value("http.path", "/a".to_string().into());
+
+        unsafe {
+            assert_eq!(
+                router_execute_deadline(&router, &mut context, 0),
+                ATC_ROUTER_EXECUTE_TIMED_OUT
+            );
+            assert_eq!(
+                router_execute_deadline(&router, &mut context, u64::MAX),
+                ATC_ROUTER_EXECUTE_MATCH
+            );
+
+            context.reset();
+            assert_eq!(
+                router_execute_deadline(&router, &mut context, u64::MAX),
+                ATC_ROUTER_EXECUTE_NO_MATCH
+            );
+        }
+    }
+
+    #[test]
+    fn test_execute_range() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", crate::ast::Type::String);
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(5, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+
+        let mut context = Context::new(&schema);
+        context.add_value("http.path", "/a".to_string().into());
+
+        unsafe {
+            assert!(!router_execute_range(&router, &mut context, 6, usize::MAX));
+            assert!(router_execute_range(&router, &mut context, 0, 5));
+        }
+    }
 
     #[test]
     fn test_long_error_message() {
@@ -253,12 +675,12 @@ mod tests {
             let result = router_add_matcher(
                 &mut router,
                 1,
-                uuid.as_ptr() as *const i8,
-                junk.as_ptr() as *const i8,
+                uuid.as_ptr(),
+                junk.as_ptr(),
                 errbuf.as_mut_ptr(),
                 &mut errbuf_len,
             );
-            assert_eq!(result, false);
+            assert!(!result);
             assert_eq!(errbuf_len, ERR_BUF_MAX_LEN);
         }
     }
@@ -276,13 +698,154 @@ mod tests {
             let result = router_add_matcher(
                 &mut router,
                 1,
-                uuid.as_ptr() as *const i8,
-                junk.as_ptr() as *const i8,
+                uuid.as_ptr(),
+                junk.as_ptr(),
                 errbuf.as_mut_ptr(),
                 &mut errbuf_len,
             );
-            assert_eq!(result, false);
+            assert!(!result);
             assert!(errbuf_len < ERR_BUF_MAX_LEN);
         }
     }
+
+    #[test]
+    fn test_max_matchers() {
+        unsafe {
+            let mut schema = Schema::default();
+            schema.add_field("http.path", crate::ast::Type::String);
+            let mut router = Router::new(&schema);
+            router_set_max_matchers(&mut router, 1);
+
+            let uuid1 = ffi::CString::new("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let uuid2 = ffi::CString::new("b921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap();
+            let atc = ffi::CString::new(r#"http.path == "/foo""#).unwrap();
+            let mut errbuf = vec![b'X'; ERR_BUF_MAX_LEN];
+            let mut errbuf_len = ERR_BUF_MAX_LEN;
+
+            assert!(router_add_matcher(
+                &mut router,
+                1,
+                uuid1.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert!(!router_add_matcher(
+                &mut router,
+                1,
+                uuid2.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+            assert_eq!(
+                &errbuf[..errbuf_len],
+                b"maximum number of matchers (1) reached"
+            );
+
+            errbuf_len = ERR_BUF_MAX_LEN;
+            router_set_max_matchers(&mut router, 0);
+            assert!(router_add_matcher(
+                &mut router,
+                1,
+                uuid2.as_ptr(),
+                atc.as_ptr(),
+                errbuf.as_mut_ptr(),
+                &mut errbuf_len,
+            ));
+        }
+    }
+
+    #[cfg(feature = "hit-counters")]
+    #[test]
+    fn test_hit_counts() {
+        unsafe {
+            let mut schema = Schema::default();
+            schema.add_field("http.path", crate::ast::Type::String);
+            let mut router = Router::new(&schema);
+            router
+                .add_matcher(
+                    3,
+                    Uuid::parse_str("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c").unwrap(),
+                    r#"http.path == "/foo""#,
+                )
+                .unwrap();
+
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", "/foo".to_string().into());
+            assert!(router_execute(&router, &mut ctx));
+
+            assert_eq!(
+                router_get_hit_counts(
+                    &router,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    0
+                ),
+                1
+            );
+
+            let mut uuids = [0u8; 36];
+            let mut priorities = [0usize; 1];
+            let mut counts = [0u64; 1];
+            assert_eq!(
+                router_get_hit_counts(
+                    &router,
+                    uuids.as_mut_ptr(),
+                    priorities.as_mut_ptr(),
+                    counts.as_mut_ptr(),
+                    1
+                ),
+                1
+            );
+            assert_eq!(&uuids, b"a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c");
+            assert_eq!(priorities, [3]);
+            assert_eq!(counts, [1]);
+            assert_eq!(router_get_miss_count(&router), 0);
+
+            router_reset_hit_counts(&router);
+            assert_eq!(router.hit_counts()[0].2, 0);
+        }
+    }
+
+    #[test]
+    fn test_execute_all() {
+        unsafe {
+            let mut schema = Schema::default();
+            schema.add_field("http.path", crate::ast::Type::String);
+            let mut router = Router::new(&schema);
+            for (priority, uuid, atc) in [
+                (1, 1, r#"http.path ^= "/""#),
+                (2, 2, r#"http.path ^= "/foo""#),
+                (3, 3, r#"http.path ^= "/bar""#),
+            ] {
+                router
+                    .add_matcher(priority, Uuid::from_u128(uuid), atc)
+                    .unwrap();
+            }
+
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", "/foo".to_string().into());
+
+            let mut uuids_hex = [0u8; 36];
+            assert_eq!(
+                router_execute_all(&router, &mut ctx, uuids_hex.as_mut_ptr(), 1),
+                2
+            );
+            assert_eq!(
+                &uuids_hex[..],
+                Uuid::from_u128(2).as_hyphenated().to_string().as_bytes()
+            );
+            assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(2));
+
+            ctx.reset();
+            ctx.add_value("http.path", "/baz".to_string().into());
+            assert_eq!(
+                router_execute_all(&router, &mut ctx, std::ptr::null_mut(), 0),
+                1
+            );
+            assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(1));
+        }
+    }
 }
@@ -1,7 +1,9 @@
 use crate::ast::Type;
-use crate::schema::Schema;
-use std::ffi;
+use crate::error::Error;
+use crate::ffi::{c_str, record_error};
+use crate::schema::{LowerPolicy, Schema};
 use std::os::raw::c_char;
+use std::slice::from_raw_parts_mut;
 
 #[no_mangle]
 pub extern "C" fn schema_new() -> *mut Schema {
@@ -30,11 +32,93 @@ pub unsafe extern "C" fn schema_free(schema: *mut Schema) {
 ///
 /// - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
 /// - `field`: the C-style string representing the field name.
-/// - `typ`: the type of the field.
+/// - `typ`: the [tag](Type::tag) of the type of the field.
 ///
-/// # Panics
+/// # Returns
 ///
-/// This function will panic if the C-style string
+/// Returns `false`, leaving the schema unchanged, if the C-style string
+/// pointed by `field` is not a valid UTF-8 string or `typ` is not the tag
+/// of a type known to this version of the library or the type of
+/// literals only, see [`Type::is_field_type`]. The error kind
+/// `ATC_ROUTER_ERROR_INVALID_ARGUMENT` is then recorded for
+/// [`atc_router_last_error_kind`](crate::ffi::atc_router_last_error_kind).
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+/// - `field` must be a valid pointer to a C-style string, must be properly aligned,
+///   and must not have '\0' in the middle.
+#[no_mangle]
+pub unsafe extern "C" fn schema_add_field(
+    schema: &mut Schema,
+    field: *const c_char,
+    typ: u32,
+) -> bool {
+    let added = c_str(field, "field").and_then(|field| {
+        let typ = Type::from_tag(typ)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown type tag {}", typ)))?;
+        schema.try_add_field(field, typ)
+    });
+
+    match added {
+        Ok(()) => true,
+        Err(e) => {
+            record_error(&e);
+            false
+        }
+    }
+}
+
+/// Set the policy used by the `lower()` transformation function.
+///
+/// # Arguments
+///
+/// - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+/// - `policy`: the [tag](LowerPolicy::tag) of the lower-casing policy, see
+///   [`LowerPolicy`].
+///
+/// # Returns
+///
+/// Returns `false`, leaving the schema unchanged, if `policy` is not the
+/// tag of a policy known to this version of the library. The error kind
+/// `ATC_ROUTER_ERROR_INVALID_ARGUMENT` is then recorded for
+/// [`atc_router_last_error_kind`](crate::ffi::atc_router_last_error_kind).
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+#[no_mangle]
+pub unsafe extern "C" fn schema_set_lower_policy(schema: &mut Schema, policy: u32) -> bool {
+    match LowerPolicy::from_tag(policy) {
+        Some(policy) => {
+            schema.set_lower_policy(policy);
+            true
+        }
+        None => {
+            record_error(&Error::InvalidArgument(format!(
+                "unknown lower policy tag {}",
+                policy
+            )));
+            false
+        }
+    }
+}
+
+/// Designate the field holding the HTTP method of requests, see
+/// [`Schema::set_method_field`].
+///
+/// # Arguments
+///
+/// - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+/// - `field`: the C-style string representing the field name.
+///
+/// # Returns
+///
+/// Returns `false`, leaving the schema unchanged, if the C-style string
 /// pointed by `field` is not a valid UTF-8 string.
 ///
 /// # Safety
@@ -45,10 +129,82 @@ pub unsafe extern "C" fn schema_free(schema: *mut Schema) {
 /// - `field` must be a valid pointer to a C-style string, must be properly aligned,
 ///   and must not have '\0' in the middle.
 #[no_mangle]
-pub unsafe extern "C" fn schema_add_field(schema: &mut Schema, field: *const i8, typ: Type) {
-    let field = ffi::CStr::from_ptr(field as *const c_char)
-        .to_str()
-        .unwrap();
+pub unsafe extern "C" fn schema_set_method_field(
+    schema: &mut Schema,
+    field: *const c_char,
+) -> bool {
+    match c_str(field, "field") {
+        Ok(field) => {
+            schema.set_method_field(field);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Get the fields declared in the schema and their types, so hosts can
+/// dump a schema instead of tracking it on their own.
+///
+/// # Arguments
+///
+/// - `schema`: a valid pointer to the [`Schema`] object returned by [`schema_new`].
+/// - `fields`: a pointer to an array of pointers to the field names
+///   (NOT C-style strings), which will be filled in. Wildcard fields are
+///   named `prefix.*`. If `fields` is `NULL`, this function will only
+///   return the number of fields.
+/// - `fields_len`: a pointer to an array of the length of each field name.
+///   Its first element must be the number of elements every array can hold.
+/// - `types`: a pointer to an array of the type of each field.
+///
+/// # Lifetimes
+///
+/// The string pointers stored in `fields` might be invalidated if any of the following
+/// operations are happened:
+///
+/// - The `schema` was deallocated.
+/// - A field was added to the `schema`.
+///
+/// # Returns
+///
+/// Returns the number of fields declared in the schema.
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `schema` must be a valid pointer returned by [`schema_new`].
+/// - If `fields` is not `NULL`, `fields`, `fields_len` and `types` must each
+///   be valid to read and write for `*fields_len` elements, and be properly
+///   aligned.
+/// - DO NOT write the memory pointed by the elements of `fields`.
+/// - DO NOT access the memory pointed by the elements of `fields`
+///   after it becomes invalid, see the `Lifetimes` section.
+#[no_mangle]
+pub unsafe extern "C" fn schema_get_fields(
+    schema: &Schema,
+    fields: *mut *const u8,
+    fields_len: *mut usize,
+    types: *mut Type,
+) -> usize {
+    if !fields.is_null() {
+        assert!(!fields_len.is_null() && !types.is_null());
+        assert!(*fields_len >= schema.len());
+
+        let len = *fields_len;
+        let fields = from_raw_parts_mut(fields, len);
+        let fields_len = from_raw_parts_mut(fields_len, len);
+        let types = from_raw_parts_mut(types, len);
+
+        for (i, (name, typ)) in schema.fields().enumerate() {
+            fields[i] = name.as_ptr();
+            fields_len[i] = name.len();
+            types[i] = *typ;
+        }
+    }
 
-    schema.add_field(field, typ)
+    schema.len()
 }
@@ -0,0 +1,112 @@
+use crate::context::Context;
+use crate::router::Router;
+use crate::shared_router::SharedRouter;
+
+/// Create a new shared router holding `router`, see [`SharedRouter`].
+///
+/// # Arguments
+///
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`],
+///   which is owned by the shared router from now on.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `router` must be a valid pointer returned by [`router_new`].
+/// - `router` must not be used, nor freed by [`router_free`], afterwards.
+///
+/// [`router_new`]: crate::ffi::router::router_new
+/// [`router_free`]: crate::ffi::router::router_free
+#[no_mangle]
+pub unsafe extern "C" fn shared_router_new<'a>(router: *mut Router<'a>) -> *mut SharedRouter<'a> {
+    Box::into_raw(Box::new(SharedRouter::new(*Box::from_raw(router))))
+}
+
+/// Deallocate the shared router object and its current router.
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `shared` must be a valid pointer returned by [`shared_router_new`].
+/// - No other thread may be using `shared`.
+#[no_mangle]
+pub unsafe extern "C" fn shared_router_free(shared: *mut SharedRouter) {
+    drop(Box::from_raw(shared));
+}
+
+/// Atomically make `router` the router `shared` executes.
+///
+/// Build `router` in the background with [`router_new`] and
+/// [`router_add_matcher`], then swap it in: executions never observe a
+/// partially updated routing table. Executions already running, on other
+/// threads, finish with the previous router, which is freed afterwards.
+///
+/// # Arguments
+///
+/// - `shared`: a pointer to the [`SharedRouter`] object returned by
+///   [`shared_router_new`].
+/// - `router`: a pointer to the [`Router`] object returned by [`router_new`],
+///   which is owned by `shared` from now on.
+///
+/// # Errors
+///
+/// This function never fails.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `shared` must be a valid pointer returned by [`shared_router_new`].
+/// - `router` must be a valid pointer returned by [`router_new`], created
+///   with the schema of the router `shared` was created with.
+/// - `router` must not be used, nor freed by [`router_free`], afterwards.
+///
+/// [`router_new`]: crate::ffi::router::router_new
+/// [`router_add_matcher`]: crate::ffi::router::router_add_matcher
+/// [`router_free`]: crate::ffi::router::router_free
+#[no_mangle]
+pub unsafe extern "C" fn shared_router_replace<'a>(
+    shared: &SharedRouter<'a>,
+    router: *mut Router<'a>,
+) {
+    shared.replace(*Box::from_raw(router));
+}
+
+/// Execute the current router of `shared` with the context, see
+/// [`router_execute`].
+///
+/// # Arguments
+///
+/// - `shared`: a pointer to the [`SharedRouter`] object returned by
+///   [`shared_router_new`].
+/// - `context`: a pointer to the [`Context`] object.
+///
+/// # Returns
+///
+/// Returns `true` if found a match, `false` means no match found.
+///
+/// # Safety
+///
+/// Violating any of the following constraints will result in undefined behavior:
+///
+/// - `shared` must be a valid pointer returned by [`shared_router_new`].
+/// - `context` must be a valid pointer returned by [`context_new`],
+///   and must be reset by [`context_reset`] before calling this function
+///   if you want to reuse the same context for multiple matches.
+///
+/// [`router_execute`]: crate::ffi::router::router_execute
+/// [`context_new`]: crate::ffi::context::context_new
+/// [`context_reset`]: crate::ffi::context::context_reset
+#[no_mangle]
+pub unsafe extern "C" fn shared_router_execute(
+    shared: &SharedRouter,
+    context: &mut Context,
+) -> bool {
+    shared.execute(context)
+}
@@ -0,0 +1,171 @@
+//! Property checks for fuzz targets and external property testing.
+//!
+//! Each check returns `Err` with a description of the first violation it
+//! finds. Inputs that do not parse, or do not validate against the schema,
+//! trivially pass. Every check is deterministic: the same input and seed
+//! always exercise the same contexts, so failures can be replayed.
+//!
+//! [`fuzz`] runs all of them on raw bytes, the way a `libfuzzer` or `afl`
+//! target would:
+//!
+//! ```
+//! atc_router::fuzzing::fuzz(br#"http.path ^= "/foo" && net.port in 80..90"#);
+//! ```
+
+use crate::ast::Type;
+use crate::context::{Context, Match};
+use crate::corpus::Rng;
+use crate::interpreter::Execute;
+use crate::parser::parse;
+use crate::router::Router;
+use crate::schema::Schema;
+use crate::semantics::Validate;
+use uuid::Uuid;
+
+/// Contexts [`fuzz`] executes each expression against.
+pub const FUZZ_CONTEXTS: usize = 16;
+
+/// A schema with a field of every type context values can have, used by
+/// [`fuzz`]. `http.method` is the schema's method field.
+pub fn fuzz_schema() -> Schema {
+    let mut s = Schema::default();
+    s.add_field("http.method", Type::String);
+    s.add_field("http.host", Type::String);
+    s.add_field("http.path", Type::String);
+    s.add_field("http.headers.*", Type::String);
+    s.add_field("net.port", Type::Int);
+    s.add_field("net.src.ip", Type::IpAddr);
+    s.add_field("net.src.cidr", Type::IpCidr);
+    s.add_field("tls.version", Type::Float);
+    s.set_method_field("http.method");
+    s
+}
+
+/// Printing a parsed expression gives an expression that parses and prints
+/// the same.
+pub fn check_display_roundtrip(atc: &str) -> Result<(), String> {
+    let Ok(expr) = parse(atc) else {
+        return Ok(());
+    };
+
+    let printed = expr.to_string();
+    let reparsed =
+        parse(&printed).map_err(|e| format!("{:?} printed as {:?}: {}", atc, printed, e))?;
+    let reprinted = reparsed.to_string();
+    if reprinted != printed {
+        return Err(format!(
+            "{:?} printed as {:?}, then as {:?}",
+            atc, printed, reprinted
+        ));
+    }
+
+    Ok(())
+}
+
+/// The expression a [`Router`] compiles `atc` into (optimized, with
+/// compacted `||` chains and method bitmasks) and the router itself match
+/// the same random contexts as the parsed expression.
+///
+/// `contexts` contexts are generated with [`Context::arbitrary_with`] from
+/// `seed`, aimed at the CIDRs of `atc`.
+pub fn check_execution_equivalence(
+    schema: &Schema,
+    atc: &str,
+    seed: u64,
+    contexts: usize,
+) -> Result<(), String> {
+    let Ok(expr) = parse(atc) else {
+        return Ok(());
+    };
+    if expr.validate(schema).is_err() {
+        return Ok(());
+    }
+
+    let uuid = Uuid::from_u128(1);
+    let mut router = Router::builder(schema).optimize(true).build();
+    router
+        .add_matcher(0, uuid, atc)
+        .map_err(|e| format!("{:?} validates but is rejected: {}", atc, e))?;
+    let (_, _, compiled) = router.matchers().next().expect("just added");
+
+    let cidrs = expr.cidr_literals();
+    let mut rng = Rng::new(seed);
+    for _ in 0..contexts {
+        let mut ctx = Context::arbitrary_with(schema, &mut rng, &cidrs);
+        let expected = expr.execute(&mut ctx, &mut Match::new());
+
+        let actual = compiled.execute(&mut ctx, &mut Match::new());
+        if actual != expected {
+            return Err(format!(
+                "{:?} compiled to {} gives {} instead of {} (seed {})",
+                atc, compiled, actual, expected, seed
+            ));
+        }
+
+        router.execute(&mut ctx);
+        let actual = ctx.result.is_some();
+        if actual != expected {
+            return Err(format!(
+                "router with {:?} gives {} instead of {} (seed {})",
+                atc, actual, expected, seed
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs every check on `data` against [`fuzz_schema`], seeding the
+/// contexts from `data` itself.
+///
+/// # Panics
+///
+/// Panics with the description of the violation when a check fails.
+/// Input that is not UTF-8 is ignored.
+pub fn fuzz(data: &[u8]) {
+    let Ok(atc) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    // FNV-1a, the seed must not depend on the platform or the build
+    let seed = data.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ *b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+
+    let schema = fuzz_schema();
+    if let Err(e) = check_display_roundtrip(atc)
+        .and_then(|_| check_execution_equivalence(&schema, atc, seed, FUZZ_CONTEXTS))
+    {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::{Corpus, Shape};
+
+    #[test]
+    fn test_fuzz_corpus() {
+        let mut corpus = Corpus::new(7, Shape::default());
+        let mut atcs = corpus.expressions(50);
+        atcs.extend(
+            [
+                r#"!(http.path == "/a") || net.port in 80..90 && net.src.ip in 10.0.0.0/8"#,
+                r#"lower(http.host) =^ ".COM" && any(http.headers.x) glob "a*""#,
+                r#"http.method in ("GET", "HEAD") || http.method == "PROPFIND""#,
+                r#"tls.version >= 1.2 && net.port != 443 && true"#,
+                "net.src.ip in (10.0.0.0/8, fd00::/8) || any(net.src.ip) > 192.168.0.1",
+                "net.src.cidr contains 10.0.0.1 && net.src.ip not in (10.0.0.1, ::1)",
+                "not an expression",
+                "http.path == 1",
+            ]
+            .map(str::to_string),
+        );
+
+        for atc in atcs {
+            fuzz(atc.as_bytes());
+        }
+        fuzz(b"\xff\xfe");
+    }
+}
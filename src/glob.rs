@@ -0,0 +1,144 @@
+//! Translation of the patterns of the `glob` operator to regexes.
+//!
+//! A glob matches the whole value. `*` matches any run of characters other
+//! than `/`, `**` any run of characters at all and `?` any single character
+//! other than `/`. `\` makes the next character literal, every other
+//! character matches itself. `http.path glob "/api/*/users/**"` thus matches
+//! `/api/v1/users/42/roles` but not `/api/v1/admin/users`.
+//!
+//! Globs are compiled to regexes when parsed. The translation can be
+//! reversed, which is how expressions are printed back with their glob.
+
+/// Returns the anchored regex matching the same values as `glob`.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::with_capacity(glob.len() + 2);
+    re.push('^');
+
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                re.push_str("(?s:.*)");
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            // a trailing `\` stands for itself
+            '\\' => push_literal(&mut re, chars.next().unwrap_or('\\')),
+            c => push_literal(&mut re, c),
+        }
+    }
+
+    re.push('$');
+    re
+}
+
+fn push_literal(re: &mut String, c: char) {
+    if is_meta(c) {
+        re.push('\\');
+    }
+    re.push(c);
+}
+
+/// Whether `regex::escape` escapes `c`.
+fn is_meta(c: char) -> bool {
+    let mut buf = [0; 4];
+    regex::escape(c.encode_utf8(&mut buf)).len() > c.len_utf8()
+}
+
+/// Returns the glob [`glob_to_regex`] translated to `re`, `None` if `re`
+/// is not such a translation.
+pub fn regex_to_glob(re: &str) -> Option<String> {
+    let mut rest = re.strip_prefix('^')?.strip_suffix('$')?;
+    let mut glob = String::with_capacity(rest.len());
+
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix("(?s:.*)") {
+            glob.push_str("**");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("[^/]*") {
+            glob.push('*');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("[^/]") {
+            glob.push('?');
+            rest = r;
+        } else {
+            let mut chars = rest.chars();
+            let c = match chars.next()? {
+                '\\' => {
+                    // only meta characters are escaped, `\d` is a class
+                    let c = chars.next().filter(|c| is_meta(*c))?;
+                    if matches!(c, '*' | '?' | '\\') {
+                        glob.push('\\');
+                    }
+                    c
+                }
+                c if is_meta(c) => return None,
+                c => c,
+            };
+            glob.push(c);
+            rest = chars.as_str();
+        }
+    }
+
+    Some(glob)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn matches(glob: &str, value: &str) -> bool {
+        Regex::new(&glob_to_regex(glob)).unwrap().is_match(value)
+    }
+
+    #[test]
+    fn test_glob_matching() {
+        let glob = "/api/*/users/**";
+        assert!(matches(glob, "/api/v1/users/42/roles"));
+        assert!(matches(glob, "/api/v1/users/"));
+        assert!(!matches(glob, "/api/v1/admin/users/42"));
+        assert!(!matches(glob, "/api/v1/users"));
+        assert!(!matches(glob, "/prefix/api/v1/users/42"));
+
+        assert!(matches("/a?c", "/abc"));
+        assert!(!matches("/a?c", "/a/c"));
+        assert!(matches("**.json", "/a/b\n/c.json"));
+        assert!(!matches("*.json", "/a.json"));
+
+        // escaped and regex meta characters are literal
+        assert!(matches(r"/a\*b.(c)", "/a*b.(c)"));
+        assert!(!matches(r"/a\*b.(c)", "/axb.(c)"));
+        assert!(!matches(r"/a\*b.(c)", "/a*bx(c)"));
+    }
+
+    #[test]
+    fn test_regex_to_glob() {
+        for glob in [
+            "/api/*/users/**",
+            "/a?c",
+            r"/a\*b.(c)\?",
+            r"\\x",
+            "",
+            "é/**/*",
+        ] {
+            assert_eq!(
+                regex_to_glob(&glob_to_regex(glob)).as_deref(),
+                Some(glob),
+                "{}",
+                glob
+            );
+        }
+
+        // `\` before an ordinary character is dropped
+        assert_eq!(
+            regex_to_glob(&glob_to_regex(r"\a\.")).as_deref(),
+            Some("a.")
+        );
+
+        for re in ["/a", "^/a", "^/a+$", "^/(a)$", r"^\d$"] {
+            assert_eq!(regex_to_glob(re), None, "{}", re);
+        }
+    }
+}
@@ -1,273 +1,790 @@
-use crate::ast::{BinaryOperator, Expression, LogicalExpression, Predicate, Value};
-use crate::context::{Context, Match};
+use crate::ast::{
+    BinaryOperator, Expression, FieldComparison, Lhs, LhsTransformations, LogicalExpression,
+    Predicate, Quantifier, Type, Value,
+};
+use crate::compact::StringSet;
+use crate::context::{CaptureMode, Context, Match, MatchEvidence};
+use crate::error::EvalError;
+use crate::regex_engine::Regex;
+use crate::schema::LowerPolicy;
+use std::borrow::Cow;
 
 pub trait Execute {
-    fn execute(&self, ctx: &mut Context, m: &mut Match) -> bool;
+    /// Evaluates the expression against `ctx`, recording what matched in
+    /// `m`. Only fails for expressions that were not validated against the
+    /// schema of `ctx`.
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError>;
+
+    /// Like [`Execute::try_execute`], panicking if the evaluation fails.
+    fn execute(&self, ctx: &mut Context, m: &mut Match) -> bool {
+        self.try_execute(ctx, m).unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
 impl Execute for Expression {
-    fn execute(&self, ctx: &mut Context, m: &mut Match) -> bool {
-        match self {
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        Ok(match self {
             Expression::Logical(l) => match l.as_ref() {
-                LogicalExpression::And(l, r) => l.execute(ctx, m) && r.execute(ctx, m),
-                LogicalExpression::Or(l, r) => l.execute(ctx, m) || r.execute(ctx, m),
-                LogicalExpression::Not(r) => !r.execute(ctx, m),
+                LogicalExpression::And(l, r) => l.try_execute(ctx, m)? && r.try_execute(ctx, m)?,
+                LogicalExpression::Or(l, r) => l.try_execute(ctx, m)? || r.try_execute(ctx, m)?,
+                LogicalExpression::Not(r) => !r.try_execute(ctx, m)?,
             },
-            Expression::Predicate(p) => p.execute(ctx, m),
+            Expression::Predicate(p) => p.try_execute(ctx, m)?,
+            Expression::FieldComparison(c) => c.try_execute(ctx, m)?,
+            Expression::Bool(b) => *b,
+        })
+    }
+}
+
+/// Lower-cases, or case folds, `s` according to `policy`, only allocating
+/// when `s` actually changes.
+pub(crate) fn lower_str(s: &str, policy: LowerPolicy) -> Cow<'_, str> {
+    if s.is_ascii() || policy == LowerPolicy::Ascii {
+        if s.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(s.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(s)
         }
+    } else if policy == LowerPolicy::Fold {
+        Cow::Owned(caseless::default_case_fold_str(s))
+    } else {
+        Cow::Owned(s.to_lowercase())
     }
 }
 
-impl Execute for Predicate {
-    fn execute(&self, ctx: &mut Context, m: &mut Match) -> bool {
-        let lhs_values = match ctx.value_of(&self.lhs.var_name) {
-            None => return false,
-            Some(v) => v,
+/// The literal `rhs` of a predicate on a `lower()`'d field, case folded
+/// like the values of the field under [`LowerPolicy::Fold`]. `None` if
+/// folding leaves it unchanged, as it does for ASCII literals without
+/// upper-case letters.
+pub(crate) fn fold_literal(rhs: &Value) -> Option<Value> {
+    let fold = |s| lower_str(s, LowerPolicy::Fold);
+    match rhs {
+        Value::String(s) => match fold(s) {
+            Cow::Owned(s) => Some(Value::String(s.into())),
+            Cow::Borrowed(_) => None,
+        },
+        Value::List(list) if list.iter().any(|s| matches!(fold(s), Cow::Owned(_))) => {
+            let mut list: Vec<_> = list.iter().map(|s| fold(s).into_owned()).collect();
+            list.sort_unstable();
+            list.dedup();
+            Some(Value::List(list))
+        }
+        _ => None,
+    }
+}
+
+/// Upper-cases `s` like [`lower_str`] lower-cases it.
+fn upper_str(s: &str, policy: LowerPolicy) -> Cow<'_, str> {
+    if s.is_ascii() || policy == LowerPolicy::Ascii {
+        if s.bytes().any(|b| b.is_ascii_lowercase()) {
+            Cow::Owned(s.to_ascii_uppercase())
+        } else {
+            Cow::Borrowed(s)
+        }
+    } else {
+        Cow::Owned(s.to_uppercase())
+    }
+}
+
+/// Collapses runs of `/` and resolves `.` and `..` segments like the
+/// `remove_dot_segments` of RFC 3986, except that runs of `/` are collapsed
+/// too and `..` never goes above the root. Percent-encoded characters are
+/// left as they are. Only allocates when `path` changes.
+fn normalize_path(path: &str) -> Cow<'_, str> {
+    let last = path.split('/').count() - 1;
+    let normalized = path
+        .split('/')
+        .enumerate()
+        .all(|(i, segment)| match segment {
+            "." | ".." => false,
+            // the leading or trailing `/`
+            "" => i == 0 || i == last,
+            _ => true,
+        });
+    if normalized {
+        return Cow::Borrowed(path);
+    }
+
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        trailing_slash = true;
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    if path.starts_with('/') {
+        normalized.push('/');
+    }
+    normalized.push_str(&segments.join("/"));
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    Cow::Owned(normalized)
+}
+
+/// The `len` characters of `s` starting at character `start`, fewer if `s`
+/// ends before.
+fn substr(s: &str, start: usize, len: Option<usize>) -> &str {
+    let offset = |n| s.char_indices().nth(n).map_or(s.len(), |(i, _)| i);
+    let s = &s[offset(start)..];
+    match len {
+        Some(len) => &s[..s.char_indices().nth(len).map_or(s.len(), |(i, _)| i)],
+        None => s,
+    }
+}
+
+/// Decodes standard or URL-safe base64, with or without padding. The bits
+/// past the last byte must be zero, as encoders leave them.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(s.len() / 4 * 3 + 2);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in s.trim_end_matches('=').bytes() {
+        let sextet = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
         };
+        acc = (acc << 6 | sextet as u32) & 0xfff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+        }
+    }
 
-        let (lower, any) = self.lhs.get_transformations();
+    // a lone trailing character can not encode a byte
+    (bits < 6 && acc & ((1 << bits) - 1) == 0).then_some(decoded)
+}
 
-        // can only be "all" or "any" mode.
-        // - all: all values must match (default)
-        // - any: ok if any any matched
-        for mut lhs_value in lhs_values.iter() {
-            let lhs_value_transformed;
+/// Decodes `%XX` escapes, `+` is left as is. Only allocates when `s`
+/// contains escapes.
+fn decode_percent(s: &str) -> Option<Cow<'_, [u8]>> {
+    if !s.contains('%') {
+        return Some(Cow::Borrowed(s.as_bytes()));
+    }
 
-            if lower {
-                match lhs_value {
-                    Value::String(s) => {
-                        lhs_value_transformed = Value::String(s.to_lowercase());
-                        lhs_value = &lhs_value_transformed;
-                    }
-                    _ => unreachable!(),
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hi = hex(bytes.next()?)?;
+            let lo = hex(bytes.next()?)?;
+            decoded.push((hi << 4 | lo) as u8);
+        } else {
+            decoded.push(b);
+        }
+    }
+
+    Some(Cow::Owned(decoded))
+}
+
+/// `url_decode(s)`: `s` with its `%XX` escapes decoded. `None` if an escape
+/// is malformed or `s` does not decode to UTF-8.
+fn url_decode(s: &str) -> Option<Cow<'_, str>> {
+    Some(match decode_percent(s)? {
+        Cow::Borrowed(_) => Cow::Borrowed(s),
+        Cow::Owned(d) => Cow::Owned(String::from_utf8(d).ok()?),
+    })
+}
+
+/// `base64_decode(s)`, `None` if `s` is not valid base64 or does not decode
+/// to UTF-8.
+fn base64_decode(s: &str) -> Option<Cow<'_, str>> {
+    Some(Cow::Owned(String::from_utf8(decode_base64(s)?).ok()?))
+}
+
+/// Decodes `s` with one of the encodings `header_decode()` accepts. `None`
+/// if `s` is not validly encoded or does not decode to UTF-8. Parsed
+/// expressions hold [`LhsTransformations::UrlDecode`] or
+/// [`LhsTransformations::Base64Decode`] instead, only expressions built by
+/// hand use it.
+fn header_decode<'s>(s: &'s str, encoding: &str) -> Option<Cow<'s, str>> {
+    match encoding {
+        "base64" => base64_decode(s),
+        "percent" => url_decode(s),
+        _ => unreachable!(),
+    }
+}
+
+/// Applies one transformation to `s`, `None` if `s` can not be transformed,
+/// see [`header_decode`].
+fn transform_once<'s>(
+    t: &LhsTransformations,
+    s: &'s str,
+    policy: LowerPolicy,
+) -> Option<Cow<'s, str>> {
+    use crate::ast::TransformArg::{Int, String as Str};
+
+    Some(match t {
+        LhsTransformations::Lower => lower_str(s, policy),
+        LhsTransformations::Upper => upper_str(s, policy),
+        LhsTransformations::Trim => Cow::Borrowed(s.trim()),
+        LhsTransformations::PathNormalize => normalize_path(s),
+        LhsTransformations::UrlDecode => url_decode(s)?,
+        LhsTransformations::Base64Decode => base64_decode(s)?,
+        LhsTransformations::Any | LhsTransformations::All => Cow::Borrowed(s),
+        // arguments were checked by validation
+        LhsTransformations::Custom(name, args) => match (name.as_str(), args.as_slice()) {
+            ("substr", [Int(start)]) => Cow::Borrowed(substr(s, *start as usize, None)),
+            ("substr", [Int(start), Int(len)]) => {
+                Cow::Borrowed(substr(s, *start as usize, Some(*len as usize)))
+            }
+            ("header_decode", [Str(encoding)]) => header_decode(s, encoding)?,
+            _ => unreachable!(),
+        },
+    })
+}
+
+/// Applies the transformations of `lhs` to `s`, innermost first. Only
+/// allocates when `s` actually changes. `None` if some transformation
+/// could not be applied, such values never match.
+fn transform_str<'s>(lhs: &Lhs, s: &'s str, policy: LowerPolicy) -> Option<Cow<'s, str>> {
+    let mut s = Cow::Borrowed(s);
+    for t in &lhs.transformations {
+        s = match s {
+            Cow::Borrowed(s) => transform_once(t, s, policy)?,
+            Cow::Owned(s) => {
+                let changed = match transform_once(t, &s, policy)? {
+                    Cow::Borrowed(t) if t.len() == s.len() => None,
+                    t => Some(t.into_owned()),
+                };
+                Cow::Owned(changed.unwrap_or(s))
+            }
+        };
+    }
+
+    Some(s)
+}
+
+/// Evaluates `lower(lhs) <op> rhs` for the string operators without
+/// allocating the lower-cased `lhs`. Returns `None` when this is not
+/// possible: for other operators, or when `policy` would lower-case or fold
+/// non-ASCII characters of `lhs`. Under [`LowerPolicy::Fold`], `rhs` must
+/// already be folded, see [`fold_literal`].
+pub(crate) fn compare_lowered(
+    op: &BinaryOperator,
+    lhs: &str,
+    rhs: &str,
+    policy: LowerPolicy,
+) -> Option<bool> {
+    if policy != LowerPolicy::Ascii && !lhs.is_ascii() {
+        return None;
+    }
+
+    // `rhs` is compared as is, an upper-case letter in it never matches
+    let eq = |l: &[u8], r: &[u8]| l.iter().zip(r).all(|(l, r)| l.to_ascii_lowercase() == *r);
+    let (l, r) = (lhs.as_bytes(), rhs.as_bytes());
+
+    Some(match op {
+        BinaryOperator::Equals => l.len() == r.len() && eq(l, r),
+        BinaryOperator::NotEquals => !(l.len() == r.len() && eq(l, r)),
+        BinaryOperator::Prefix => l.len() >= r.len() && eq(&l[..r.len()], r),
+        BinaryOperator::Postfix => l.len() >= r.len() && eq(&l[l.len() - r.len()..], r),
+        BinaryOperator::Contains => r.is_empty() || l.windows(r.len()).any(|w| eq(w, r)),
+        _ => return None,
+    })
+}
+
+/// Evaluates `lhs op rhs` for the ordering operators on two numbers or
+/// addresses of the same type, `false` if either is a NaN or the addresses
+/// are of different IP families.
+pub(crate) fn compare(op: BinaryOperator, lhs: &Value, rhs: &Value) -> Result<bool, EvalError> {
+    let ordering = match (lhs, rhs) {
+        (Value::Int(l), Value::Int(r)) => Some(l.cmp(r)),
+        (Value::Float(l), Value::Float(r)) => l.partial_cmp(r),
+        (Value::IpAddr(l), Value::IpAddr(r)) => (l.is_ipv4() == r.is_ipv4()).then(|| l.cmp(r)),
+        _ => return Err(EvalError::new(op, lhs, rhs)),
+    };
+
+    Ok(ordering.is_some_and(|o| match op {
+        BinaryOperator::Greater => o.is_gt(),
+        BinaryOperator::GreaterOrEqual => o.is_ge(),
+        BinaryOperator::Less => o.is_lt(),
+        _ => o.is_le(),
+    }))
+}
+
+/// The first value `test` holds for with [`Quantifier::Any`], the first
+/// value if it holds for all of them with [`Quantifier::All`]. Stops at the
+/// first error.
+pub(crate) fn quantified(
+    values: &[Value],
+    quantifier: Quantifier,
+    mut test: impl FnMut(&Value) -> Result<bool, EvalError>,
+) -> Result<Option<&Value>, EvalError> {
+    match quantifier {
+        Quantifier::Any => {
+            for v in values {
+                if test(v)? {
+                    return Ok(Some(v));
                 }
             }
+            Ok(None)
+        }
+        Quantifier::All => {
+            for v in values {
+                if !test(v)? {
+                    return Ok(None);
+                }
+            }
+            Ok(values.first())
+        }
+    }
+}
 
-            let mut matched = false;
-            match self.op {
-                BinaryOperator::Equals => {
-                    if lhs_value == &self.rhs {
-                        m.matches
-                            .insert(self.lhs.var_name.clone(), self.rhs.clone());
+/// Applies the transformations of `lhs`, such as `lower()`, to a value of
+/// its field, see [`transform_str`].
+fn transform_value<'v>(lhs: &Lhs, v: &'v Value, policy: LowerPolicy) -> Option<Cow<'v, Value>> {
+    Some(match v {
+        Value::String(s) if lhs.is_transformed() => match transform_str(lhs, s, policy)? {
+            Cow::Borrowed(t) if t.len() == s.len() => Cow::Borrowed(v),
+            t => Cow::Owned(Value::String(t.into_owned().into())),
+        },
+        _ => Cow::Borrowed(v),
+    })
+}
 
-                        if any {
-                            return true;
-                        }
+/// Evaluates `lhs op rhs` for two values of the same type, for the operators
+/// [`FieldComparison`] supports.
+fn compare_values(op: BinaryOperator, lhs: &Value, rhs: &Value) -> Result<bool, EvalError> {
+    Ok(match (op, lhs, rhs) {
+        (BinaryOperator::Equals, l, r) => l == r,
+        (BinaryOperator::NotEquals, l, r) => l != r,
+        (BinaryOperator::Prefix, Value::String(l), Value::String(r)) => l.starts_with(r.as_str()),
+        (BinaryOperator::Postfix, Value::String(l), Value::String(r)) => l.ends_with(r.as_str()),
+        (BinaryOperator::Contains, Value::String(l), Value::String(r)) => l.contains(r.as_str()),
+        (
+            BinaryOperator::Greater
+            | BinaryOperator::GreaterOrEqual
+            | BinaryOperator::Less
+            | BinaryOperator::LessOrEqual,
+            l,
+            r,
+        ) => compare(op, l, r)?,
+        _ => return Err(EvalError::new(op, lhs, rhs)),
+    })
+}
 
-                        matched = true;
-                    }
+impl Execute for FieldComparison {
+    // `any()` and `all()` apply to each side separately: by default every
+    // value of the LHS field must compare true against every value of the
+    // RHS field
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        ctx.stats.predicates_evaluated += 1;
+        let policy = ctx.schema().lower_policy();
+
+        ctx.resolve_lhs(&self.lhs);
+        ctx.resolve_lhs(&self.rhs);
+        let (lhs_values, rhs_values) =
+            match (ctx.value_of_lhs(&self.lhs), ctx.value_of_lhs(&self.rhs)) {
+                (Some(l), Some(r)) => (l, r),
+                _ => return Ok(false),
+            };
+
+        let lhs_matches = |l: &Value| {
+            let Some(l) = transform_value(&self.lhs, l, policy) else {
+                return Ok(false);
+            };
+            // settled by the first value that holds for `any()`, or the
+            // first one that does not for `all()`
+            let all = self.rhs.quantifier() == Quantifier::All;
+            for r in rhs_values {
+                let holds = match transform_value(&self.rhs, r, policy) {
+                    Some(r) => compare_values(self.op, &l, &r)?,
+                    None => false,
+                };
+                if holds != all {
+                    return Ok(holds);
                 }
-                BinaryOperator::NotEquals => {
-                    if lhs_value != &self.rhs {
-                        if any {
-                            return true;
-                        }
+            }
 
-                        matched = true;
+            Ok(all)
+        };
+
+        Ok(
+            match quantified(lhs_values, self.lhs.quantifier(), lhs_matches)? {
+                Some(v) => {
+                    if matches!(
+                        self.op,
+                        BinaryOperator::Equals | BinaryOperator::Prefix | BinaryOperator::Postfix
+                    ) {
+                        m.matches.insert(self.lhs.path().into_owned(), v.clone());
                     }
+
+                    held(m, &self.lhs.path(), self.op, v)
                 }
-                BinaryOperator::Regex => {
-                    let rhs = match &self.rhs {
-                        Value::Regex(r) => r,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-
-                    if rhs.is_match(lhs) {
-                        let reg_cap = rhs.captures(lhs).unwrap();
-
-                        m.matches.insert(
-                            self.lhs.var_name.clone(),
-                            Value::String(reg_cap.get(0).unwrap().as_str().to_string()),
-                        );
-
-                        for (i, c) in reg_cap.iter().enumerate() {
-                            if let Some(c) = c {
-                                m.captures.insert(i.to_string(), c.as_str().to_string());
-                            }
-                        }
+                None => false,
+            },
+        )
+    }
+}
 
-                        // named captures
-                        for n in rhs.capture_names().flatten() {
-                            if let Some(value) = reg_cap.name(n) {
-                                m.captures.insert(n.to_string(), value.as_str().to_string());
-                            }
-                        }
+/// Records in [`Match::evidence`] that `field <op> ...` held for `value`,
+/// returns `true`.
+pub(crate) fn held(m: &mut Match, field: &str, op: BinaryOperator, value: &Value) -> bool {
+    m.evidence.push(MatchEvidence {
+        field: field.to_string(),
+        op,
+        value: value.clone(),
+    });
+    true
+}
 
-                        if any {
-                            return true;
-                        }
+/// Runs `re` once against `haystack`, recording the matched text in
+/// `m.matches` and, depending on `mode`, its capture groups in `m.captures`.
+pub(crate) fn regex_match(
+    re: &Regex,
+    haystack: &str,
+    mode: CaptureMode,
+    field: &str,
+    m: &mut Match,
+) -> bool {
+    if mode == CaptureMode::None {
+        return match re.find(haystack) {
+            Some(found) => {
+                m.matches
+                    .insert(field.to_string(), Value::String(haystack[found].into()));
+                true
+            }
+            None => false,
+        };
+    }
 
-                        matched = true;
-                    }
-                }
-                BinaryOperator::Prefix => {
-                    let rhs = match &self.rhs {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs.starts_with(rhs) {
-                        m.matches
-                            .insert(self.lhs.var_name.clone(), self.rhs.clone());
-                        if any {
-                            return true;
+    let groups = match re.captures(haystack) {
+        Some(c) => c,
+        None => return false,
+    };
+    let group = |i: usize| groups.get(i).cloned().flatten().map(|r| &haystack[r]);
+
+    m.matches
+        .insert(field.to_string(), Value::String(group(0).unwrap().into()));
+
+    if mode == CaptureMode::All {
+        for i in 0..groups.len() {
+            if let Some(c) = group(i) {
+                m.captures.insert(i.to_string(), c.to_string());
+            }
+        }
+    }
+
+    // named captures
+    for (i, n) in re.capture_names() {
+        if let Some(value) = group(i) {
+            m.captures.insert(n.to_string(), value.to_string());
+        }
+    }
+
+    true
+}
+
+/// Equivalent of the `||` chain of `==` predicates `set` was compacted from,
+/// see [`Expression::compact`](crate::ast::Expression::compact).
+fn execute_set(
+    set: &StringSet,
+    lhs: &Lhs,
+    quantifier: Quantifier,
+    ctx: &Context,
+    m: &mut Match,
+) -> Result<bool, EvalError> {
+    let lower_policy = ctx.schema().lower_policy();
+    let lhs_values = match ctx.value_of_lhs(lhs) {
+        None => return Ok(false),
+        Some(v) => v,
+    };
+    // the values of a field all have its type
+    if !matches!(lhs_values[0], Value::String(_)) {
+        return Err(EvalError {
+            op: BinaryOperator::Equals,
+            lhs: lhs_values[0].my_type(),
+            rhs: Type::String,
+        });
+    }
+    let as_str = |v| set_operand(lhs, v, lower_policy);
+
+    let matched = match quantifier {
+        // some value is in the set
+        Quantifier::Any => lhs_values
+            .iter()
+            .find_map(|v| as_str(v).filter(|s| set.contains(s)).map(|s| (v, s))),
+        // every value is the same one from the set
+        Quantifier::All => as_str(&lhs_values[0])
+            .filter(|first| {
+                set.contains(first)
+                    && lhs_values[1..]
+                        .iter()
+                        .all(|v| as_str(v).as_ref() == Some(first))
+            })
+            .map(|first| (&lhs_values[0], first)),
+    };
+
+    Ok(match matched {
+        Some((v, s)) => {
+            let field = lhs.path();
+            m.matches
+                .insert(field.to_string(), Value::String(s.into_owned().into()));
+            held(m, &field, BinaryOperator::Equals, v)
+        }
+        None => false,
+    })
+}
+
+/// `v` transformed, `None` if it can not be. `v` is a string, see
+/// [`execute_set`].
+fn set_operand<'v>(lhs: &Lhs, v: &'v Value, lower_policy: LowerPolicy) -> Option<Cow<'v, str>> {
+    match v {
+        Value::String(s) => transform_str(lhs, s, lower_policy),
+        _ => None,
+    }
+}
+
+impl Execute for Predicate {
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        match self.memo {
+            Some(slot) => ctx.memoized(slot, m, |ctx, m| self.evaluate(ctx, m)),
+            None => self.evaluate(ctx, m),
+        }
+    }
+}
+
+impl Predicate {
+    fn evaluate(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        ctx.stats.predicates_evaluated += 1;
+        let quantifier = self.lhs.quantifier();
+        ctx.resolve_lhs(&self.lhs);
+        let rhs = &self.rhs;
+
+        if let Value::Set(set) = rhs {
+            return execute_set(set, &self.lhs, quantifier, ctx, m);
+        }
+
+        let rhs = match rhs {
+            Value::Methods(methods) => {
+                if let Some(bit) = ctx.method_bit() {
+                    if ctx.schema().method_field() == Some(self.lhs.var_name.as_str()) {
+                        if bit & methods.bits() == 0 {
+                            return Ok(false);
                         }
 
-                        matched = true;
+                        let method = &ctx.value_of_lhs(&self.lhs).unwrap()[0];
+                        m.matches.insert(self.lhs.var_name.clone(), method.clone());
+                        return Ok(held(m, &self.lhs.var_name, self.op, method));
                     }
                 }
-                BinaryOperator::Postfix => {
-                    let rhs = match &self.rhs {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs.ends_with(rhs) {
-                        m.matches
-                            .insert(self.lhs.var_name.clone(), self.rhs.clone());
-                        if any {
-                            return true;
-                        }
 
-                        matched = true;
-                    }
+                // several methods, compared one by one
+                methods.original()
+            }
+            rhs => rhs,
+        };
+
+        // membership style `any(field) == value` on fields with many values
+        // is answered from a hash set instead of a linear scan
+        if quantifier == Quantifier::Any
+            && !self.lhs.is_transformed()
+            && self.op == BinaryOperator::Equals
+        {
+            if let Some(found) = ctx.any_value_equals(&self.lhs, rhs) {
+                if !found {
+                    return Ok(false);
                 }
-                BinaryOperator::Greater => {
-                    let rhs = match &self.rhs {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs > rhs {
-                        if any {
-                            return true;
-                        }
 
-                        matched = true;
-                    }
+                m.matches.insert(self.lhs.var_name.clone(), rhs.clone());
+                return Ok(held(m, &self.lhs.var_name, self.op, rhs));
+            }
+        }
+
+        let lhs_values = match ctx.value_of_lhs(&self.lhs) {
+            None => return Ok(false),
+            Some(v) => v,
+        };
+        let (lower, _) = self.lhs.get_transformations();
+        let lower_policy = ctx.schema().lower_policy();
+        let env = ValueTest {
+            lower,
+            folded: match lower && lower_policy == LowerPolicy::Fold {
+                true => fold_literal(rhs),
+                false => None,
+            },
+            // `lower()` alone is applied without allocating, see
+            // `compare_lowered`
+            transformed: self.lhs.transformations.iter().any(|t| {
+                !matches!(
+                    t,
+                    LhsTransformations::Lower | LhsTransformations::Any | LhsTransformations::All
+                )
+            }),
+            lower_policy,
+            capture_mode: ctx.capture_mode(),
+        };
+        let test = |v: &Value| self.test_value(v, rhs, &env, m);
+
+        Ok(match quantified(lhs_values, quantifier, test)? {
+            Some(v) => held(m, &self.lhs.path(), self.op, v),
+            None => false,
+        })
+    }
+
+    /// Whether the value `value` of the field satisfies the predicate, with
+    /// `rhs` standing for the right hand side. Records what matched in
+    /// [`Match::matches`].
+    fn test_value(
+        &self,
+        value: &Value,
+        literal: &Value,
+        env: &ValueTest,
+        m: &mut Match,
+    ) -> Result<bool, EvalError> {
+        // compared with the value, while `literal` is what gets recorded
+        let rhs = env.folded.as_ref().unwrap_or(literal);
+        let mismatch = || EvalError::new(self.op, value, literal);
+        let mut lhs_value = value;
+        let lhs_value_transformed;
+        // result of the comparison when done without lower-casing
+        let mut lowered = None;
+
+        if env.transformed {
+            match transform_value(&self.lhs, lhs_value, env.lower_policy) {
+                Some(Cow::Owned(v)) => {
+                    lhs_value_transformed = v;
+                    lhs_value = &lhs_value_transformed;
                 }
-                BinaryOperator::GreaterOrEqual => {
-                    let rhs = match &self.rhs {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs >= rhs {
-                        if any {
-                            return true;
-                        }
+                Some(Cow::Borrowed(_)) => {}
+                // values that can not be transformed never match
+                None => return Ok(false),
+            }
+        } else if env.lower {
+            match lhs_value {
+                Value::String(s) => {
+                    if let Value::String(rhs) = rhs {
+                        lowered = compare_lowered(&self.op, s, rhs, env.lower_policy);
+                    }
 
-                        matched = true;
+                    if lowered.is_some() {
+                        // `lhs_value` is compared as is
+                    } else if let Cow::Owned(s) = lower_str(s, env.lower_policy) {
+                        lhs_value_transformed = Value::String(s.into());
+                        lhs_value = &lhs_value_transformed;
                     }
                 }
-                BinaryOperator::Less => {
-                    let rhs = match &self.rhs {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs < rhs {
-                        if any {
-                            return true;
-                        }
+                _ => return Err(mismatch()),
+            }
+        }
 
-                        matched = true;
-                    }
+        // only built for what matched, as it allocates for map entries
+        let field = || self.lhs.path();
+        Ok(match self.op {
+            BinaryOperator::Equals => {
+                let matched = lowered.unwrap_or_else(|| lhs_value == rhs);
+                if matched {
+                    m.matches.insert(field().into_owned(), literal.clone());
                 }
-                BinaryOperator::LessOrEqual => {
-                    let rhs = match &self.rhs {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::Int(i) => i,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs <= rhs {
-                        if any {
-                            return true;
-                        }
+                matched
+            }
+            BinaryOperator::NotEquals => lowered.unwrap_or_else(|| lhs_value != rhs),
+            BinaryOperator::Regex => {
+                let (Value::String(lhs), Value::Regex(rhs)) = (lhs_value, rhs) else {
+                    return Err(mismatch());
+                };
+
+                regex_match(env.regex(rhs), lhs, env.capture_mode, &field(), m)
+            }
+            BinaryOperator::Prefix | BinaryOperator::Postfix => {
+                let (Value::String(lhs), Value::String(rhs)) = (lhs_value, rhs) else {
+                    return Err(mismatch());
+                };
 
-                        matched = true;
+                let matched = lowered.unwrap_or_else(|| {
+                    if self.op == BinaryOperator::Prefix {
+                        lhs.starts_with(rhs.as_str())
+                    } else {
+                        lhs.ends_with(rhs.as_str())
                     }
+                });
+                if matched {
+                    m.matches.insert(field().into_owned(), self.rhs.clone());
                 }
-                BinaryOperator::In => match (lhs_value, &self.rhs) {
-                    (Value::IpAddr(l), Value::IpCidr(r)) => {
-                        if r.contains(l) {
-                            matched = true;
-                            if any {
-                                return true;
-                            }
-                        }
-                    }
-                    _ => unreachable!(),
-                },
-                BinaryOperator::NotIn => match (lhs_value, &self.rhs) {
-                    (Value::IpAddr(l), Value::IpCidr(r)) => {
-                        if !r.contains(l) {
-                            matched = true;
-                            if any {
-                                return true;
-                            }
-                        }
-                    }
-                    _ => unreachable!(),
-                },
-                BinaryOperator::Contains => {
-                    let rhs = match &self.rhs {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-                    let lhs = match lhs_value {
-                        Value::String(s) => s,
-                        _ => unreachable!(),
-                    };
-
-                    if lhs.contains(rhs) {
-                        if any {
-                            return true;
-                        }
-
-                        matched = true;
+                matched
+            }
+            BinaryOperator::Greater
+            | BinaryOperator::GreaterOrEqual
+            | BinaryOperator::Less
+            | BinaryOperator::LessOrEqual => compare(self.op, lhs_value, rhs)?,
+            BinaryOperator::In => match (lhs_value, rhs) {
+                (Value::IpAddr(l), Value::IpCidr(r)) => r.contains(l),
+                (Value::IpAddr(l), Value::CidrList(r)) => r.contains(l),
+                (Value::Int(l), Value::IntRange(lo, hi)) => (lo..=hi).contains(&l),
+                (Value::String(l), Value::List(r)) => {
+                    let matched = r.binary_search_by(|v| v.as_str().cmp(l)).is_ok();
+                    if matched {
+                        m.matches.insert(field().into_owned(), lhs_value.clone());
                     }
+                    matched
                 }
-            } // match
+                _ => return Err(mismatch()),
+            },
+            BinaryOperator::NotIn => match (lhs_value, rhs) {
+                (Value::IpAddr(l), Value::IpCidr(r)) => !r.contains(l),
+                (Value::IpAddr(l), Value::CidrList(r)) => !r.contains(l),
+                (Value::Int(l), Value::IntRange(lo, hi)) => !(lo..=hi).contains(&l),
+                (Value::String(l), Value::List(r)) => {
+                    r.binary_search_by(|v| v.as_str().cmp(l)).is_err()
+                }
+                _ => return Err(mismatch()),
+            },
+            BinaryOperator::Contains => match (lhs_value, rhs) {
+                (Value::String(l), Value::String(r)) => {
+                    lowered.unwrap_or_else(|| l.contains(r.as_str()))
+                }
+                (Value::IpCidr(l), Value::IpAddr(r)) => l.contains(r),
+                _ => return Err(mismatch()),
+            },
+            BinaryOperator::Glob => {
+                let (Value::String(lhs), Value::Regex(rhs)) = (lhs_value, rhs) else {
+                    return Err(mismatch());
+                };
 
-            if !any && !matched {
-                // all and nothing matched
-                return false;
+                // globs have no groups, only the matched value is kept
+                regex_match(env.regex(rhs), lhs, CaptureMode::None, &field(), m)
             }
-        } // for iter
+        })
+    }
+}
+
+/// What [`Predicate::test_value`] needs to know besides the value, looked
+/// up once per evaluation.
+struct ValueTest {
+    lower: bool,
+    /// The right hand side case folded, when `lower()` folds values and
+    /// folding changes it, see [`fold_literal`].
+    folded: Option<Value>,
+    /// Whether functions other than `lower()` transform the values.
+    transformed: bool,
+    lower_policy: LowerPolicy,
+    capture_mode: CaptureMode,
+}
 
-        // if we reached here, it means that `any` did not find a match,
-        // or we passed all matches for `all`. So we simply need to return
-        // !any && lhs_values.len() > 0 to cover both cases
-        !any && !lhs_values.is_empty()
+impl ValueTest {
+    /// `re`, matching ignoring case when `lower()` folds values.
+    fn regex<'r>(&self, re: &'r Regex) -> &'r Regex {
+        match self.lower && self.lower_policy == LowerPolicy::Fold {
+            true => re.ignore_case(),
+            false => re,
+        }
     }
 }
 
@@ -287,31 +804,37 @@ fn test_predicate() {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Prefix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), false);
+    assert!(!p.execute(&mut ctx, &mut mat));
 
     // check if any value matches starts_with foo -- should be false
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Prefix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), false);
+    assert!(!p.execute(&mut ctx, &mut mat));
 
     // test any mode
     let lhs_values = vec![
-        Value::String("foofoo".to_string()),
-        Value::String("foobar".to_string()),
-        Value::String("foocar".to_string()),
-        Value::String("fooban".to_string()),
+        Value::String("foofoo".into()),
+        Value::String("foobar".into()),
+        Value::String("foocar".into()),
+        Value::String("fooban".into()),
     ];
 
     for v in lhs_values {
@@ -323,106 +846,913 @@ fn test_predicate() {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Prefix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if all values match ends_with foo -- should be false
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Postfix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), false);
+    assert!(!p.execute(&mut ctx, &mut mat));
 
     // check if any value matches ends_with foo -- should be true
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Postfix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if any value matches starts_with foo -- should be true
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("foo".to_string()),
+        rhs: Value::String("foo".into()),
         op: BinaryOperator::Prefix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if any value matches ends_with nar -- should be false
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("nar".to_string()),
+        rhs: Value::String("nar".into()),
         op: BinaryOperator::Postfix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), false);
+    assert!(!p.execute(&mut ctx, &mut mat));
 
     // check if any value matches ends_with empty string -- should be true
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("".to_string()),
+        rhs: Value::String("".into()),
         op: BinaryOperator::Postfix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if any value matches starts_with empty string -- should be true
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("".to_string()),
+        rhs: Value::String("".into()),
         op: BinaryOperator::Prefix,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if any value matches contains `ob` -- should be true
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("ob".to_string()),
+        rhs: Value::String("ob".into()),
         op: BinaryOperator::Contains,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), true);
+    assert!(p.execute(&mut ctx, &mut mat));
 
     // check if any value matches contains `ok` -- should be false
     let p = Predicate {
         lhs: ast::Lhs {
             var_name: "my_key".to_string(),
             transformations: vec![ast::LhsTransformations::Any],
+            var_index: None,
+            key: None,
         },
-        rhs: Value::String("ok".to_string()),
+        rhs: Value::String("ok".into()),
         op: BinaryOperator::Contains,
+        memo: None,
+    };
+
+    assert!(!p.execute(&mut ctx, &mut mat));
+}
+
+#[test]
+fn test_lower_policy() {
+    use crate::ast;
+    use crate::schema;
+
+    assert!(matches!(
+        lower_str("foo", LowerPolicy::Ascii),
+        Cow::Borrowed("foo")
+    ));
+    assert!(matches!(
+        lower_str("foo", LowerPolicy::Unicode),
+        Cow::Borrowed("foo")
+    ));
+    assert_eq!(lower_str("FoO", LowerPolicy::Ascii), "foo");
+    assert_eq!(lower_str("FoO", LowerPolicy::Unicode), "foo");
+    assert_eq!(lower_str("ÄBC", LowerPolicy::Ascii), "Äbc");
+    assert_eq!(lower_str("ÄBC", LowerPolicy::Unicode), "äbc");
+
+    let p = Predicate {
+        lhs: ast::Lhs {
+            var_name: "my_key".to_string(),
+            transformations: vec![ast::LhsTransformations::Lower],
+            var_index: None,
+            key: None,
+        },
+        rhs: Value::String("äbc".into()),
+        op: BinaryOperator::Equals,
+        memo: None,
     };
 
-    assert_eq!(p.execute(&mut ctx, &mut mat), false);
+    let mut schema = schema::Schema::default();
+    schema.add_field("my_key", ast::Type::String);
+
+    let mut mat = Match::new();
+    let mut ctx = Context::new(&schema);
+    ctx.add_value("my_key", Value::String("ÄBC".into()));
+    assert!(p.execute(&mut ctx, &mut mat));
+
+    schema.set_lower_policy(LowerPolicy::Ascii);
+    let mut ctx = Context::new(&schema);
+    ctx.add_value("my_key", Value::String("ÄBC".into()));
+    assert!(!p.execute(&mut ctx, &mut mat));
+}
+
+#[test]
+fn test_lower_string_operators() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let tests = [
+        (r#"lower(http.host) == "example.com""#, "Example.COM", true),
+        (
+            r#"lower(http.host) == "example.com""#,
+            "Example.COM.",
+            false,
+        ),
+        (r#"lower(http.host) == "Example.com""#, "Example.com", false),
+        (r#"lower(http.host) != "example.com""#, "EXAMPLE.com", false),
+        (r#"lower(http.host) ^= "api.""#, "API.example.com", true),
+        (r#"lower(http.host) ^= "api.""#, "AP", false),
+        (r#"lower(http.host) =^ ".com""#, "example.COM", true),
+        (r#"lower(http.host) =^ ".com""#, "COM", false),
+        (r#"lower(http.host) contains "ample""#, "EXAMPLE.com", true),
+        (r#"lower(http.host) contains """#, "X", true),
+        (r#"lower(http.host) contains "amplex""#, "EXAMPLE", false),
+        (r#"lower(http.host) == "ä.com""#, "Ä.COM", true),
+        (r#"lower(http.host) ^= "ä""#, "ÄX", true),
+    ];
+
+    for policy in [LowerPolicy::Unicode, LowerPolicy::Ascii] {
+        let mut schema = Schema::default();
+        schema.add_field("http.host", Type::String);
+        schema.set_lower_policy(policy);
+
+        for (atc, value, expected) in tests {
+            // ASCII lower-casing keeps `Ä`
+            let expected = expected && (policy == LowerPolicy::Unicode || value.is_ascii());
+
+            let expr = parse(atc).unwrap();
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.host", Value::String(value.into()));
+
+            let mut mat = Match::new();
+            assert_eq!(
+                expr.execute(&mut ctx, &mut mat),
+                expected,
+                "{} {:?}",
+                atc,
+                policy
+            );
+        }
+    }
+}
+
+#[test]
+fn test_lower_fold() {
+    use crate::ast::Type;
+    use crate::closure::ClosureProgram;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    assert_eq!(lower_str("StraßE", LowerPolicy::Fold), "strasse");
+    assert_eq!(lower_str("ÄBC", LowerPolicy::Fold), "äbc");
+
+    let mut schema = Schema::default();
+    schema.add_field("http.host", Type::String);
+    schema.add_field("http.path", Type::String);
+    schema.set_lower_policy(LowerPolicy::Fold);
+
+    let tests = [
+        (r#"lower(http.host) == "Example.COM""#, "example.com", true),
+        (r#"lower(http.host) == "straße.de""#, "STRASSE.de", true),
+        (r#"lower(http.host) == "strasse.de""#, "Straße.de", true),
+        (r#"lower(http.host) != "EXAMPLE.com""#, "example.com", false),
+        (r#"lower(http.host) ^= "API.""#, "api.example.com", true),
+        (r#"lower(http.host) =^ ".ÉTÉ""#, "a.été", true),
+        (r#"lower(http.host) contains "SSE""#, "Straße", true),
+        (r#"lower(http.host) in ("A.com", "b.com")"#, "a.COM", true),
+        (r#"lower(http.host) not in ("A.com")"#, "a.com", false),
+        (r#"lower(http.path) ~ "^/API/V[0-9]$""#, "/api/v1", true),
+        (r#"lower(http.path) glob "/API/*""#, "/Api/x", true),
+        // only `lower()` folds
+        (r#"http.host == "Example.COM""#, "example.com", false),
+        (r#"http.path ~ "^/API""#, "/api", false),
+    ];
+
+    for (atc, value, expected) in tests {
+        let expr = parse(atc).unwrap();
+        let program = ClosureProgram::from(&expr);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.host", Value::String(value.into()));
+        ctx.add_value("http.path", Value::String(value.into()));
+        let mut m1 = Match::new();
+        let mut m2 = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut m1), expected, "{}", atc);
+        assert_eq!(program.execute(&mut ctx, &mut m2), expected, "{}", atc);
+        assert_eq!(m1.matches, m2.matches, "{}", atc);
+    }
+
+    // regexes match the folded value, captures included
+    let expr = parse(r#"lower(http.path) ~ "^/(?<V>V[0-9])/STRASSE$""#).unwrap();
+    let mut ctx = Context::new(&schema);
+    ctx.add_value("http.path", Value::String("/V2/Straße".into()));
+    let mut m = Match::new();
+    assert!(expr.execute(&mut ctx, &mut m));
+    assert_eq!(m.captures["V"], "v2");
+    assert_eq!(m.matches["http.path"], Value::String("/v2/strasse".into()));
+}
+
+#[test]
+fn test_normalize_path() {
+    for path in [
+        "/",
+        "/foo/bar",
+        "/foo/bar/",
+        "",
+        "foo/bar",
+        "/foo.bar/..baz",
+    ] {
+        assert!(
+            matches!(normalize_path(path), Cow::Borrowed(p) if p == path),
+            "{}",
+            path
+        );
+    }
+
+    for (path, expected) in [
+        ("//foo///bar", "/foo/bar"),
+        ("/foo/./bar/.", "/foo/bar/"),
+        ("/foo/../bar", "/bar"),
+        ("/foo/bar/..", "/foo/"),
+        ("/../../etc/passwd", "/etc/passwd"),
+        ("/foo/%2e%2e/bar", "/foo/%2e%2e/bar"),
+        ("//", "/"),
+        ("/..", "/"),
+        ("foo/../../bar", "bar"),
+    ] {
+        assert_eq!(normalize_path(path), expected, "{}", path);
+    }
+}
+
+#[test]
+fn test_header_decode() {
+    for (encoded, decoded) in [
+        ("", Some("")),
+        ("Zg", Some("f")),
+        ("Zg==", Some("f")),
+        ("Zm8", Some("fo")),
+        ("Zm9v", Some("foo")),
+        ("Pz8_", Some("???")),
+        ("Pz8/", Some("???")),
+        ("Zm9vY", None),
+        ("Zm 9v", None),
+        // not UTF-8
+        ("/w==", None),
+    ] {
+        assert_eq!(
+            header_decode(encoded, "base64").as_deref(),
+            decoded,
+            "{}",
+            encoded
+        );
+    }
+
+    assert!(matches!(
+        header_decode("a+b", "percent"),
+        Some(Cow::Borrowed("a+b"))
+    ));
+    for (encoded, decoded) in [
+        ("%41%62c", Some("Abc")),
+        ("%C3%A4", Some("ä")),
+        ("100%", None),
+        ("%4", None),
+        ("%g1", None),
+        ("%ff", None),
+    ] {
+        assert_eq!(
+            header_decode(encoded, "percent").as_deref(),
+            decoded,
+            "{}",
+            encoded
+        );
+    }
+}
+
+#[test]
+fn test_string_transformations() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.path", Type::String);
+    schema.add_field("http.host", Type::String);
+
+    let tests = [
+        (
+            r#"trim(http.host) == "example.com""#,
+            " example.com\t",
+            true,
+        ),
+        (r#"http.host == "example.com""#, " example.com\t", false),
+        (r#"upper(http.host) == "EXAMPLE.COM""#, "Example.com", true),
+        (r#"upper(http.host) == "ÄX""#, "äx", true),
+        (
+            r#"path_normalize(http.path) ^= "/admin""#,
+            "/public/../admin/x",
+            true,
+        ),
+        (r#"path_normalize(http.path) == "/a/b""#, "//a/./b", true),
+        (
+            r#"path_normalize(http.path) ^= "/admin""#,
+            "/public/admin",
+            false,
+        ),
+        (
+            r#"path_normalize(http.path) ~ "^/api/(?<id>\\d+)$""#,
+            "/api//v/../42",
+            true,
+        ),
+        (
+            r#"path_normalize(http.path) glob "/a/*""#,
+            "/a/b/../c",
+            true,
+        ),
+        // innermost first
+        (
+            r#"lower(trim(http.host)) == "example.com""#,
+            " EXAMPLE.com ",
+            true,
+        ),
+        (
+            r#"upper(lower(http.host)) == "EXAMPLE.COM""#,
+            "example.com",
+            true,
+        ),
+        (
+            r#"lower(upper(http.host)) == "EXAMPLE.COM""#,
+            "example.com",
+            false,
+        ),
+        (r#"trim(http.host) in ("a.com", "b.com")"#, " b.com", true),
+        (r#"substr(http.path, 0, 4) == "/api""#, "/api/v1", true),
+        (r#"substr(http.path, 5) == "v1""#, "/api/v1", true),
+        (r#"substr(http.path, 1, 2) == "äö""#, "üäöx", true),
+        (r#"substr(http.path, 9) == """#, "/api", true),
+        (
+            r#"header_decode(http.host, "base64") == "user:pass""#,
+            "dXNlcjpwYXNz",
+            true,
+        ),
+        (
+            r#"lower(header_decode(http.host, "percent")) == "a b/c""#,
+            "A%20b%2Fc",
+            true,
+        ),
+        (
+            r#"header_decode(http.host, "percent") in ("a b", "c")"#,
+            "a%20b",
+            true,
+        ),
+        // values that do not decode never match, even with `!=`
+        (r#"header_decode(http.host, "base64") != "x""#, "!!", false),
+        (
+            r#"header_decode(http.host, "percent") != "x""#,
+            "%zz",
+            false,
+        ),
+        (
+            r#"header_decode(http.host, "percent") in ("a", "b")"#,
+            "%ff",
+            false,
+        ),
+        (
+            r#"url_decode(http.path) == "/a b/ä""#,
+            "/a%20b/%C3%A4",
+            true,
+        ),
+        (r#"url_decode(http.path) == "/a+b""#, "/a+b", true),
+        (
+            r#"path_normalize(url_decode(http.path)) ^= "/admin""#,
+            "/x/%2E%2E/admin",
+            true,
+        ),
+        (r#"url_decode(http.path) != "/x""#, "/%4", false),
+        (r#"url_decode(http.path) ^= "/""#, "/%C3", false),
+        (
+            r#"base64_decode(http.host) == "user:pass""#,
+            "dXNlcjpwYXNz",
+            true,
+        ),
+        (r#"base64_decode(http.host) == "a?""#, "YT8", true),
+        (r#"base64_decode(http.host) == "a?""#, "YT_", false),
+        (r#"base64_decode(http.host) != "x""#, "a", false),
+        (r#"base64_decode(http.host) ~ "x""#, "/w==", false),
+        (
+            r#"any(trim(http.host)) == "a.com" || any(trim(http.host)) == "b.com""#,
+            " b.com",
+            true,
+        ),
+    ];
+
+    for (atc, value, expected) in tests {
+        let expr = parse(atc).unwrap();
+        let field = if atc.contains("http.path") {
+            "http.path"
+        } else {
+            "http.host"
+        };
+        let mut ctx = Context::new(&schema);
+        ctx.add_value(field, Value::String(value.into()));
+
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+        // compacted `||` chains of `==` give the same result
+        let mut mat = Match::new();
+        assert_eq!(
+            expr.clone().compact().execute(&mut ctx, &mut mat),
+            expected,
+            "{}",
+            atc
+        );
+    }
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value_str("http.path", "/api//v/../42");
+    let mut mat = Match::new();
+    let expr = parse(r#"path_normalize(http.path) ~ "^/api/(?<id>\\d+)$""#).unwrap();
+    assert!(expr.execute(&mut ctx, &mut mat));
+    assert_eq!(mat.captures["id"], "42");
+}
+
+#[test]
+fn test_float_predicate() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("latency.ms", Type::Float);
+
+    let tests = [
+        ("latency.ms >= 12.5", 12.5, true),
+        ("latency.ms > 12.5", 12.5, false),
+        ("latency.ms < 1e2", 99.9, true),
+        ("latency.ms <= -1.0", -0.5, false),
+        ("latency.ms == 0.5", 0.5, true),
+        ("latency.ms != 0.5", 0.5, false),
+        ("latency.ms >= 0.0", f64::NAN, false),
+        ("latency.ms < 0.0", f64::NAN, false),
+    ];
+
+    for (atc, value, expected) in tests {
+        let expr = parse(atc).unwrap();
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("latency.ms", Value::Float(value));
+
+        assert_eq!(
+            expr.execute(&mut ctx, &mut Match::new()),
+            expected,
+            "{}",
+            atc
+        );
+    }
+}
+
+#[test]
+fn test_list_predicate() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.method", Type::String);
+
+    let tests = [
+        (r#"http.method in ("GET", "HEAD", "OPTIONS")"#, "HEAD", true),
+        (
+            r#"http.method in ("GET", "HEAD", "OPTIONS")"#,
+            "POST",
+            false,
+        ),
+        (r#"http.method in ("GET", "HEAD")"#, "get", false),
+        (r#"lower(http.method) in ("get", "head")"#, "GET", true),
+        (r#"http.method not in ("GET", "HEAD")"#, "POST", true),
+        (r#"http.method not in ("GET", "HEAD")"#, "GET", false),
+    ];
+
+    for (atc, value, expected) in tests {
+        let expr = parse(atc).unwrap();
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.method", Value::String(value.into()));
+
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+        if expected && !atc.contains("not in") {
+            assert!(mat.matches.contains_key("http.method"));
+        }
+    }
+}
+
+#[test]
+fn test_any_equals_indexed() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.headers.x_tag", Type::String);
+
+    let mut ctx = Context::new(&schema);
+    for i in 0..100 {
+        ctx.add_value("http.headers.x_tag", format!("tag{}", i).into());
+    }
+
+    for (atc, expected) in [
+        (r#"any(http.headers.x_tag) == "tag42""#, true),
+        (r#"any(http.headers.x_tag) == "tag100""#, false),
+        (r#"http.headers.x_tag == "tag42""#, false),
+    ] {
+        let mut mat = Match::new();
+        assert_eq!(
+            parse(atc).unwrap().execute(&mut ctx, &mut mat),
+            expected,
+            "{}",
+            atc
+        );
+        assert_eq!(mat.matches.contains_key("http.headers.x_tag"), expected);
+    }
+}
+
+#[test]
+fn test_quantifiers() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.headers.*", Type::String);
+    schema.add_field("net.dst.port", Type::Int);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value("http.headers.x_tag", "a1".to_string().into());
+    ctx.add_value("http.headers.x_tag", "A2".to_string().into());
+    ctx.add_value("net.dst.port", Value::Int(80));
+    ctx.add_value("net.dst.port", Value::Int(8080));
+
+    for (atc, expected, evidence) in [
+        (r#"all(http.headers.x_tag) ^= "a""#, false, None),
+        (r#"all(lower(http.headers.x_tag)) ^= "a""#, true, Some("a1")),
+        (r#"lower(http.headers.x_tag) ^= "a""#, true, Some("a1")),
+        (r#"any(http.headers.x_tag) =^ "2""#, true, Some("A2")),
+        (
+            r#"all(http.headers.x_tag) in ("a1", "A2")"#,
+            true,
+            Some("a1"),
+        ),
+        (r#"all(http.headers.x_tag) == "a1""#, false, None),
+        (r#"any(http.headers.x_tag) == "a1""#, true, Some("a1")),
+        (r#"all(http.headers.x_missing) != "a""#, false, None),
+        (r#"any(http.headers.x_missing) != "a""#, false, None),
+        ("all(net.dst.port) > 79", true, None),
+        ("all(net.dst.port) > 80", false, None),
+        ("any(net.dst.port) > 80", true, None),
+        ("all(net.dst.port) == any(net.dst.port)", true, None),
+        ("all(net.dst.port) == all(net.dst.port)", false, None),
+        ("all(net.dst.port) >= any(net.dst.port)", true, None),
+    ] {
+        let mut mat = Match::new();
+        assert_eq!(
+            parse(atc).unwrap().execute(&mut ctx, &mut mat),
+            expected,
+            "{}",
+            atc
+        );
+        if let Some(value) = evidence {
+            assert_eq!(mat.evidence[0].value, value.to_string().into(), "{}", atc);
+        }
+    }
+}
+
+#[test]
+fn test_capture_mode() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.path", Type::String);
+
+    let expr = parse(r#"http.path ~ "^/users/(?P<id>\\d+)/(\\w+)$""#).unwrap();
+    let captures = |mode| {
+        let mut ctx = Context::new(&schema);
+        ctx.set_capture_mode(mode);
+        ctx.add_value_str("http.path", "/users/42/orders");
+
+        let mut mat = Match::new();
+        assert!(expr.execute(&mut ctx, &mut mat));
+        assert_eq!(
+            mat.matches["http.path"],
+            Value::String("/users/42/orders".into())
+        );
+
+        let mut captures: Vec<_> = mat.captures.into_iter().collect();
+        captures.sort();
+        captures
+    };
+
+    let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+    assert_eq!(
+        captures(CaptureMode::All),
+        [
+            pair("0", "/users/42/orders"),
+            pair("1", "42"),
+            pair("2", "orders"),
+            pair("id", "42"),
+        ]
+    );
+    assert_eq!(captures(CaptureMode::NamedOnly), [pair("id", "42")]);
+    assert_eq!(captures(CaptureMode::None), []);
+}
+
+#[test]
+fn test_field_comparison() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.host", Type::String);
+    schema.add_field("tls.sni", Type::String);
+    schema.add_field("net.src.port", Type::Int);
+    schema.add_field("net.dst.port", Type::Int);
+    schema.add_field("http.path", Type::String);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value_str("http.host", "Example.com");
+    ctx.add_value_str("tls.sni", "example.com");
+    ctx.add_value_str("tls.sni", "other.com");
+    ctx.add_value_int("net.src.port", 1024);
+    ctx.add_value_int("net.dst.port", 443);
+
+    let tests = [
+        ("http.host == tls.sni", false),
+        ("lower(http.host) == any(tls.sni)", true),
+        ("lower(http.host) != any(tls.sni)", true),
+        ("http.host != tls.sni", true),
+        ("net.src.port > net.dst.port", true),
+        ("net.src.port <= net.dst.port", false),
+        ("net.src.port == net.src.port", true),
+        // no value for the RHS field
+        ("http.host == http.path", false),
+    ];
+
+    for (atc, expected) in tests {
+        let expr = parse(atc).unwrap();
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+    }
+
+    let mut mat = Match::new();
+    let expr = parse("lower(http.host) == any(tls.sni)").unwrap();
+    assert!(expr.execute(&mut ctx, &mut mat));
+    assert_eq!(
+        mat.matches["http.host"],
+        Value::String("Example.com".into())
+    );
+}
+
+#[test]
+fn test_glob() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+    use crate::semantics::Validate;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.path", Type::String);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value_str("http.path", "/API/v1/users/42");
+
+    let tests = [
+        (r#"http.path glob "/API/*/users/**""#, true),
+        (r#"http.path glob "/API/*""#, false),
+        (r#"http.path glob "/api/**""#, false),
+        (r#"lower(http.path) glob "/api/v?/**""#, true),
+        (r#"http.path glob "/API/v1/users/4""#, false),
+    ];
+
+    for (atc, expected) in tests {
+        let expr = parse(atc).unwrap();
+        expr.validate(&schema).unwrap();
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+    }
+
+    let mut mat = Match::new();
+    let expr = parse(r#"http.path glob "/API/**""#).unwrap();
+    assert!(expr.execute(&mut ctx, &mut mat));
+    assert_eq!(
+        mat.matches["http.path"],
+        Value::String("/API/v1/users/42".into())
+    );
+    assert!(mat.captures.is_empty());
+}
+
+#[test]
+fn test_int_range() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+    use crate::semantics::Validate;
+
+    let mut schema = Schema::default();
+    schema.add_field("net.dst.port", Type::Int);
+    schema.add_field("http.path", Type::String);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value_int("net.dst.port", 8000);
+    ctx.add_value_int("net.dst.port", 9001);
+
+    let tests = [
+        ("net.dst.port in 8000..9000", false),
+        ("any(net.dst.port) in 8000..9000", true),
+        ("net.dst.port in 8000..9001", true),
+        ("net.dst.port not in 1..7999", true),
+        ("any(net.dst.port) not in 8000..9000", true),
+        ("net.dst.port not in 8000..8000", false),
+    ];
+
+    for (atc, expected) in tests {
+        let expr = parse(atc).unwrap();
+        expr.validate(&schema).unwrap();
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+    }
+
+    assert!(parse("http.path in 1..2")
+        .unwrap()
+        .validate(&schema)
+        .is_err());
+}
+
+#[test]
+fn test_evidence() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    let mut schema = Schema::default();
+    schema.add_field("http.path", Type::String);
+    schema.add_field("http.host", Type::String);
+    schema.add_field("net.dst.port", Type::Int);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value_str("http.path", "/API/v1");
+    ctx.add_value_str("http.host", "a.com");
+    ctx.add_value_str("http.host", "b.com");
+    ctx.add_value_int("net.dst.port", 8443);
+
+    let expr = parse(
+        r#"lower(http.path) ^= "/api" && http.path ~ "v1$" && any(http.host) =^ "b.com"
+           && net.dst.port in 8000..9000 && !(http.path contains "x")"#,
+    )
+    .unwrap();
+    let mut mat = Match::new();
+    assert!(expr.execute(&mut ctx, &mut mat));
+
+    let evidence: Vec<_> = mat
+        .evidence
+        .iter()
+        .map(|e| format!("{} {} {}", e.field, e.op, e.value))
+        .collect();
+    assert_eq!(
+        evidence,
+        [
+            r#"http.path ^= "/API/v1""#,
+            r#"http.path ~ "/API/v1""#,
+            r#"http.host =^ "b.com""#,
+            "net.dst.port in 8443",
+        ]
+    );
+    // one field, one entry
+    assert_eq!(mat.matches["http.path"], Value::String("v1".into()));
+}
+
+#[test]
+fn test_ip_operators() {
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+    use crate::semantics::Validate;
+
+    let mut schema = Schema::default();
+    schema.add_field("net.src.ip", Type::IpAddr);
+    schema.add_field("net.dst.ip", Type::IpAddr);
+    schema.add_field("net.src.cidr", Type::IpCidr);
+    schema.add_field("net.port", Type::Int);
+
+    let mut ctx = Context::new(&schema);
+    ctx.add_value("net.src.ip", Value::IpAddr("10.1.2.3".parse().unwrap()));
+    ctx.add_value("net.src.ip", Value::IpAddr("fd00::1".parse().unwrap()));
+    ctx.add_value("net.dst.ip", Value::IpAddr("10.1.2.4".parse().unwrap()));
+    ctx.add_value("net.src.cidr", Value::IpCidr("10.0.0.0/8".parse().unwrap()));
+
+    let tests = [
+        ("net.src.ip in (10.0.0.0/8, fd00::/8)", true),
+        ("net.src.ip in (10.0.0.0/8, 192.168.0.0/16)", false),
+        ("any(net.src.ip) in (10.0.0.0/8, 192.168.0.0/16)", true),
+        ("net.src.ip not in (192.168.0.0/16, 10.1.2.4)", true),
+        ("any(net.src.ip) not in (10.0.0.0/8, fd00::1)", false),
+        ("net.dst.ip in (10.1.2.4,)", true),
+        ("net.src.ip >= 10.0.0.0", false),
+        ("any(net.src.ip) >= 10.0.0.0", true),
+        ("any(net.src.ip) < ::1", false),
+        ("net.dst.ip > 10.1.2.3 && net.dst.ip <= 10.1.2.4", true),
+        ("net.src.cidr contains 10.255.0.1", true),
+        ("net.src.cidr contains 11.0.0.1", false),
+    ];
+
+    for (atc, expected) in tests {
+        let expr = parse(atc).unwrap();
+        expr.validate(&schema).unwrap();
+        let mut mat = Match::new();
+        assert_eq!(expr.execute(&mut ctx, &mut mat), expected, "{}", atc);
+    }
+
+    for atc in [
+        "net.port in (10.0.0.0/8)",
+        "net.src.cidr in (10.0.0.0/8)",
+        "net.src.ip contains 10.0.0.1",
+        "net.src.cidr > 10.0.0.1",
+    ] {
+        assert!(parse(atc).unwrap().validate(&schema).is_err(), "{}", atc);
+    }
 }
@@ -0,0 +1,570 @@
+//! A documented, versioned JSON representation of expressions, so policy
+//! tools can generate routes without writing ATC source, see
+//! [`Expression::to_json_v1`] and [`Expression::from_json_v1`].
+//!
+//! The serde representation of [`Expression`] mirrors its Rust types and
+//! changes along with them. Version 1 of this format does not: a future
+//! format gets a new version and new functions.
+//!
+//! # Format
+//!
+//! A document holds the version and the expression:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "expression": {"and": [
+//!     {"predicate": {"lhs": {"field": "http.path"}, "op": "^=", "value": {"string": "/api/"}}},
+//!     {"not": {"predicate": {
+//!       "lhs": {"field": "http.headers", "key": "x-debug", "transformations": ["lower"]},
+//!       "op": "==",
+//!       "value": {"string": "1"}
+//!     }}}
+//!   ]}
+//! }
+//! ```
+//!
+//! is `http.path ^= "/api/" && !(lower(http.headers["x-debug"]) == "1")`.
+//! Every expression is an object with a single key:
+//!
+//! - `{"and": [e, ...]}` and `{"or": [e, ...]}`, with at least one operand.
+//!   Chains of the same operator are written as one list.
+//! - `{"not": e}`.
+//! - `{"bool": true}` or `{"bool": false}`.
+//! - `{"predicate": {"lhs": lhs, "op": op, "value": value}}`, comparing a
+//!   field with a literal.
+//! - `{"comparison": {"lhs": lhs, "op": op, "rhs": lhs}}`, comparing two
+//!   fields, as in `http.host == tls.sni`.
+//!
+//! A `lhs` is `{"field": name}`, with an optional `"key"` looked up in a map
+//! field and optional `"transformations"`, innermost first. A transformation
+//! is the name of the function, or `{"name": name, "args": [...]}` for
+//! functions taking integer or string arguments after the field:
+//! `substr(lower(http.path), 0, 4)` is
+//! `{"field": "http.path", "transformations": ["lower", {"name": "substr", "args": [0, 4]}]}`.
+//!
+//! An `op` is the operator as written in ATC: `==`, `!=`, `~`, `^=`, `=^`,
+//! `>`, `>=`, `<`, `<=`, `in`, `not in`, `contains` or `glob`.
+//!
+//! A `value` is an object whose single key names its type:
+//!
+//! | Value | Example |
+//! |-------|---------|
+//! | string, and the pattern of `~` and `glob` | `{"string": "/a"}` |
+//! | integer | `{"int": 1}` |
+//! | float | `{"float": 1.5}` |
+//! | address | `{"ip_addr": "10.0.0.1"}` |
+//! | CIDR | `{"ip_cidr": "10.0.0.0/8"}` |
+//! | list of strings | `{"list": ["GET", "HEAD"]}` |
+//! | range of integers | `{"int_range": [8000, 8999]}` |
+//! | list of CIDRs | `{"cidr_list": ["10.0.0.0/8", "fd00::/8"]}` |
+//!
+//! Unknown keys are rejected. Like parsed expressions, expressions read from
+//! JSON are only type checked once [validated](crate::semantics::Validate)
+//! against a schema.
+
+use crate::ast::{
+    BinaryOperator, Expression, FieldComparison, Lhs, LhsTransformations, LogicalExpression,
+    Predicate, TransformArg, Value,
+};
+use crate::cidr_list::CidrList;
+use crate::glob::{glob_to_regex, regex_to_glob};
+use crate::parser::transformation;
+use cidr::IpCidr;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    version: u32,
+    expression: Node,
+}
+
+/// Only the version of a document, read before the rest since other
+/// versions may not have the same shape.
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Node {
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Not(Box<Node>),
+    Bool(bool),
+    Predicate(PredicateNode),
+    Comparison(ComparisonNode),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PredicateNode {
+    lhs: LhsNode,
+    #[serde(with = "op")]
+    op: BinaryOperator,
+    value: ValueNode,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComparisonNode {
+    lhs: LhsNode,
+    #[serde(with = "op")]
+    op: BinaryOperator,
+    rhs: LhsNode,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LhsNode {
+    field: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    transformations: Vec<TransformationNode>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TransformationNode {
+    Name(String),
+    Call { name: String, args: Vec<ArgNode> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ArgNode {
+    Int(i64),
+    String(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ValueNode {
+    String(String),
+    Int(i64),
+    Float(f64),
+    IpAddr(IpAddr),
+    IpCidr(String),
+    List(Vec<String>),
+    IntRange(i64, i64),
+    CidrList(Vec<String>),
+}
+
+/// Operators as written in ATC.
+mod op {
+    use crate::ast::BinaryOperator;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::borrow::Cow;
+
+    pub fn serialize<S: Serializer>(op: &BinaryOperator, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(op.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BinaryOperator, D::Error> {
+        let op = Cow::<str>::deserialize(deserializer)?;
+        BinaryOperator::ALL
+            .iter()
+            .find(|o| o.as_str() == op)
+            .copied()
+            .ok_or_else(|| D::Error::custom(format!("unknown operator: {}", op)))
+    }
+}
+
+impl Expression {
+    /// Returns the expression as a version 1 JSON document, see the
+    /// [module documentation](crate::json).
+    ///
+    /// Predicates [compacted](Expression::compact) or
+    /// [compiled](Expression::compile_methods) by a router are written back
+    /// as the predicates they were made of.
+    pub fn to_json_v1(&self) -> String {
+        let doc = Document {
+            version: VERSION,
+            expression: node(&self.clone().expand()),
+        };
+        serde_json::to_string(&doc).expect("expressions serialize to JSON")
+    }
+
+    /// Reads an expression from a version 1 JSON document, see the
+    /// [module documentation](crate::json). Regexes are compiled through
+    /// the shared [`regex_cache`](crate::regex_cache).
+    pub fn from_json_v1(json: &str) -> Result<Expression, serde_json::Error> {
+        use serde::de::Error;
+
+        let Version { version } = serde_json::from_str(json)?;
+        if version != VERSION {
+            return Err(serde_json::Error::custom(format!(
+                "unsupported version {}, expected {}",
+                version, VERSION
+            )));
+        }
+
+        let doc: Document = serde_json::from_str(json)?;
+        expression(doc.expression).map_err(serde_json::Error::custom)
+    }
+}
+
+fn node(e: &Expression) -> Node {
+    match e {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(..) => Node::And(chain(e, true)),
+            LogicalExpression::Or(..) => Node::Or(chain(e, false)),
+            LogicalExpression::Not(e) => Node::Not(Box::new(node(e))),
+        },
+        Expression::Predicate(p) => {
+            let (op, value) = match (p.op, &p.rhs) {
+                (BinaryOperator::Glob, Value::Regex(re)) => match regex_to_glob(re.as_str()) {
+                    Some(glob) => (BinaryOperator::Glob, ValueNode::String(glob)),
+                    // a regex not translated from a glob matches the same
+                    // with `~`
+                    None => (BinaryOperator::Regex, ValueNode::String(re.as_str().into())),
+                },
+                (op, rhs) => (op, value_node(rhs)),
+            };
+            Node::Predicate(PredicateNode {
+                lhs: lhs_node(&p.lhs),
+                op,
+                value,
+            })
+        }
+        Expression::FieldComparison(c) => Node::Comparison(ComparisonNode {
+            lhs: lhs_node(&c.lhs),
+            op: c.op,
+            rhs: lhs_node(&c.rhs),
+        }),
+        Expression::Bool(b) => Node::Bool(*b),
+    }
+}
+
+/// The operands of the `&&` (`and`) or `||` chain `e`.
+fn chain(e: &Expression, and: bool) -> Vec<Node> {
+    if let Expression::Logical(logical) = e {
+        if let (LogicalExpression::And(l, r), true) | (LogicalExpression::Or(l, r), false) =
+            (logical.as_ref(), and)
+        {
+            let mut operands = chain(l, and);
+            operands.extend(chain(r, and));
+            return operands;
+        }
+    }
+
+    vec![node(e)]
+}
+
+fn lhs_node(lhs: &Lhs) -> LhsNode {
+    LhsNode {
+        field: lhs.var_name.clone(),
+        key: lhs.key.clone(),
+        transformations: lhs
+            .transformations
+            .iter()
+            .map(|t| match t {
+                LhsTransformations::Custom(name, args) => TransformationNode::Call {
+                    name: name.clone(),
+                    args: args
+                        .iter()
+                        .map(|a| match a {
+                            TransformArg::Int(i) => ArgNode::Int(*i),
+                            TransformArg::String(s) => ArgNode::String(s.clone()),
+                        })
+                        .collect(),
+                },
+                t => TransformationNode::Name(t.to_string()),
+            })
+            .collect(),
+    }
+}
+
+fn value_node(value: &Value) -> ValueNode {
+    match value {
+        Value::String(s) => ValueNode::String(s.to_string()),
+        Value::Regex(re) => ValueNode::String(re.as_str().to_string()),
+        Value::Int(i) => ValueNode::Int(*i),
+        Value::Float(f) => ValueNode::Float(*f),
+        Value::IpAddr(addr) => ValueNode::IpAddr(*addr),
+        // `{:#}` keeps the `/32` of host CIDRs
+        Value::IpCidr(cidr) => ValueNode::IpCidr(format!("{:#}", cidr)),
+        Value::List(l) => ValueNode::List(l.clone()),
+        Value::IntRange(lo, hi) => ValueNode::IntRange(*lo, *hi),
+        Value::CidrList(l) => {
+            ValueNode::CidrList(l.cidrs().iter().map(|c| format!("{:#}", c)).collect())
+        }
+        Value::Set(_) | Value::Methods(_) => unreachable!("expanded before"),
+    }
+}
+
+fn expression(node: Node) -> Result<Expression, String> {
+    Ok(match node {
+        Node::And(operands) => fold(operands, "and", LogicalExpression::And)?,
+        Node::Or(operands) => fold(operands, "or", LogicalExpression::Or)?,
+        Node::Not(e) => Expression::Logical(Box::new(LogicalExpression::Not(expression(*e)?))),
+        Node::Bool(b) => Expression::Bool(b),
+        Node::Predicate(p) => {
+            let rhs = match (p.op, p.value) {
+                (BinaryOperator::Regex, ValueNode::String(s)) => regex(&s)?,
+                (BinaryOperator::Glob, ValueNode::String(s)) => regex(&glob_to_regex(&s))?,
+                (op @ (BinaryOperator::Regex | BinaryOperator::Glob), _) => {
+                    return Err(format!("{} needs a string pattern", op))
+                }
+                (_, value) => value_of(value)?,
+            };
+            Expression::Predicate(Predicate {
+                lhs: lhs(p.lhs)?,
+                rhs,
+                op: p.op,
+                memo: None,
+            })
+        }
+        Node::Comparison(c) => Expression::FieldComparison(FieldComparison {
+            lhs: lhs(c.lhs)?,
+            op: c.op,
+            rhs: lhs(c.rhs)?,
+        }),
+    })
+}
+
+/// The left associative chain of `operands`, as the parser builds it.
+fn fold(
+    operands: Vec<Node>,
+    name: &str,
+    op: fn(Expression, Expression) -> LogicalExpression,
+) -> Result<Expression, String> {
+    let mut operands = operands.into_iter();
+    let first = operands
+        .next()
+        .ok_or_else(|| format!("{} needs at least one operand", name))?;
+
+    operands.try_fold(expression(first)?, |acc, e| {
+        Ok(Expression::Logical(Box::new(op(acc, expression(e)?))))
+    })
+}
+
+fn lhs(node: LhsNode) -> Result<Lhs, String> {
+    let transformations = node
+        .transformations
+        .into_iter()
+        .map(|t| {
+            let (name, args) = match t {
+                TransformationNode::Name(name) => (name, Vec::new()),
+                TransformationNode::Call { name, args } => (name, args),
+            };
+            let args = args
+                .into_iter()
+                .map(|a| match a {
+                    ArgNode::Int(i) => TransformArg::Int(i),
+                    ArgNode::String(s) => TransformArg::String(s),
+                })
+                .collect();
+            transformation(&name, args)
+                .ok_or_else(|| format!("unknown transformation function: {}", name))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Lhs {
+        var_name: node.field,
+        transformations,
+        var_index: None,
+        key: node.key,
+    })
+}
+
+fn regex(pattern: &str) -> Result<Value, String> {
+    crate::regex_cache::get_or_compile(pattern)
+        .map(Value::Regex)
+        .map_err(|e| format!("invalid regex {:?}: {}", pattern, e))
+}
+
+fn cidr(s: &str) -> Result<IpCidr, String> {
+    s.parse()
+        .map_err(|e| format!("invalid CIDR {:?}: {}", s, e))
+}
+
+fn value_of(node: ValueNode) -> Result<Value, String> {
+    Ok(match node {
+        ValueNode::String(s) => Value::String(s.into()),
+        ValueNode::Int(i) => Value::Int(i),
+        ValueNode::Float(f) => {
+            if !f.is_finite() {
+                return Err("float literal is out of range".to_string());
+            }
+            Value::Float(f)
+        }
+        ValueNode::IpAddr(addr) => Value::IpAddr(addr),
+        ValueNode::IpCidr(s) => Value::IpCidr(cidr(&s)?),
+        ValueNode::List(mut l) => {
+            // kept sorted so the interpreter can binary search it
+            l.sort_unstable();
+            l.dedup();
+            Value::List(l)
+        }
+        ValueNode::IntRange(lo, hi) => {
+            if lo > hi {
+                return Err("range start is greater than its end".to_string());
+            }
+            Value::IntRange(lo, hi)
+        }
+        ValueNode::CidrList(l) => Value::CidrList(CidrList::new(
+            l.iter().map(|s| cidr(s)).collect::<Result<_, _>>()?,
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_round_trip() {
+        for atc in [
+            r#"(http.path ^= "/api/")"#,
+            r#"((((a == 1) && (b != 2.5)) && !((c ~ "^/x$"))) || false)"#,
+            r#"((a == 1) && ((b == 2) || (c == 3)))"#,
+            r#"(lower(http.headers["x-debug"]) == "1")"#,
+            r#"(substr(lower(http.path), 0, 4) == "/api")"#,
+            r#"(base64_decode(http.headers.x) contains "a")"#,
+            r#"(any(http.headers.x) in ("a", "b"))"#,
+            r#"(net.src.ip in 10.0.0.0/8)"#,
+            r#"(net.src.ip in 10.0.0.1/32)"#,
+            r#"(net.src.ip == ::1)"#,
+            r#"(net.src.ip in (10.0.0.0/8, fd00::/8))"#,
+            r#"(net.dst.port in 8000..8999)"#,
+            r#"(http.host glob "*.example.com")"#,
+            r#"(http.host == tls.sni)"#,
+            r#"(lower(http.host) != any(tls.sni))"#,
+            "true",
+        ] {
+            let expr = parse(atc).unwrap();
+            let json = expr.to_json_v1();
+            let read = Expression::from_json_v1(&json).unwrap();
+            assert_eq!(read.to_string(), atc, "{}", json);
+            assert_eq!(read.to_json_v1(), json);
+        }
+    }
+
+    // the format is documented, it must not change
+    #[test]
+    fn test_format() {
+        let expr = parse(
+            r#"http.path ^= "/api/" && !(lower(http.headers["x-debug"]) == "1") && substr(http.path, 0, 4) glob "/a*""#,
+        )
+        .unwrap();
+        assert_eq!(
+            expr.to_json_v1(),
+            concat!(
+                r#"{"version":1,"expression":{"and":["#,
+                r#"{"predicate":{"lhs":{"field":"http.path"},"op":"^=","value":{"string":"/api/"}}},"#,
+                r#"{"not":{"predicate":{"lhs":{"field":"http.headers","key":"x-debug","transformations":["lower"]},"op":"==","value":{"string":"1"}}}},"#,
+                r#"{"predicate":{"lhs":{"field":"http.path","transformations":[{"name":"substr","args":[0,4]}]},"op":"glob","value":{"string":"/a*"}}}"#,
+                r#"]}}"#
+            )
+        );
+
+        let expr = parse(
+            r#"a == 1.5 || a in 1..2 || a == 10.0.0.1 || a in (10.0.0.0/8) || a in ("x") || a == tls.sni"#,
+        )
+        .unwrap();
+        assert_eq!(
+            expr.to_json_v1(),
+            concat!(
+                r#"{"version":1,"expression":{"or":["#,
+                r#"{"predicate":{"lhs":{"field":"a"},"op":"==","value":{"float":1.5}}},"#,
+                r#"{"predicate":{"lhs":{"field":"a"},"op":"in","value":{"int_range":[1,2]}}},"#,
+                r#"{"predicate":{"lhs":{"field":"a"},"op":"==","value":{"ip_addr":"10.0.0.1"}}},"#,
+                r#"{"predicate":{"lhs":{"field":"a"},"op":"in","value":{"cidr_list":["10.0.0.0/8"]}}},"#,
+                r#"{"predicate":{"lhs":{"field":"a"},"op":"in","value":{"list":["x"]}}},"#,
+                r#"{"comparison":{"lhs":{"field":"a"},"op":"==","rhs":{"field":"tls.sni"}}}"#,
+                r#"]}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_json() {
+        let read = |json: &str| Expression::from_json_v1(json).map(|e| e.to_string());
+
+        // chains are read left associative, lists sorted
+        assert_eq!(
+            read(
+                r#"{"version":1,"expression":{"or":[
+                    {"bool":false},
+                    {"predicate":{"lhs":{"field":"m"},"op":"not in","value":{"list":["b","a","b"]}}},
+                    {"and":[{"bool":true}]}
+                ]}}"#
+            )
+            .unwrap(),
+            r#"((false || (m not in ("a", "b"))) || true)"#
+        );
+
+        // compacted and compiled predicates are written as they were parsed
+        let expr = parse(r#"a == "x" || a == "y" || a == "z""#)
+            .unwrap()
+            .compact();
+        assert!(expr
+            .to_json_v1()
+            .starts_with(r#"{"version":1,"expression":{"or":[{"predicate""#));
+        assert_eq!(
+            read(&expr.to_json_v1()).unwrap(),
+            r#"(((a == "x") || (a == "y")) || (a == "z"))"#
+        );
+        let expr = parse(r#"m in ("GET", "POST")"#)
+            .unwrap()
+            .compile_methods("m");
+        assert_eq!(
+            read(&expr.to_json_v1()).unwrap(),
+            r#"(m in ("GET", "POST"))"#
+        );
+
+        for (json, error) in [
+            (
+                r#"{"version":2,"expression":{"new":1}}"#,
+                "unsupported version 2, expected 1",
+            ),
+            (r#"{"expression":{"bool":true}}"#, "missing field `version`"),
+            (
+                r#"{"version":1,"expression":{"and":[]}}"#,
+                "and needs at least one operand",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a"},"op":"=~","value":{"int":1}}}}"#,
+                "unknown operator: =~",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a","transformations":["nope"]},"op":"==","value":{"int":1}}}}"#,
+                "unknown transformation function: nope",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a"},"op":"~","value":{"int":1}}}}"#,
+                "~ needs a string pattern",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a"},"op":"in","value":{"int_range":[2,1]}}}}"#,
+                "range start is greater than its end",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a"},"op":"in","value":{"ip_cidr":"10.0.0.1/40"}}}}"#,
+                "invalid CIDR",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a"},"op":"~","value":{"string":"("}}}}"#,
+                "invalid regex \"(\"",
+            ),
+            (
+                r#"{"version":1,"expression":{"predicate":{"lhs":{"field":"a","typo":1},"op":"==","value":{"int":1}}}}"#,
+                "unknown field `typo`",
+            ),
+        ] {
+            let err = read(json).unwrap_err().to_string();
+            assert!(err.contains(error), "{}: {}", json, err);
+        }
+    }
+}
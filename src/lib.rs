@@ -15,18 +15,122 @@ for more detailed explainations of the concepts and APIs.
   C or LuaJIT. This feature is on by default.
 * **serde** -
   Enable serde integration which allows data structures to be serializable/deserializable.
+  Regexes are compiled through the shared `regex_cache` when deserialized, and routers can
+  be saved and restored as a `RouterDocument`, or as a binary snapshot with
+  `Router::serialize` and `Router::deserialize`. Expressions can be written and read in
+  the documented, versioned JSON format of the `json` module.
+* **rayon** -
+  Compile the regexes of a deserialized `RouterDocument` in parallel. Implies **serde**.
+* **cli** -
+  Builds the `atc` command line tool for parsing, validating, matching and linting
+  expressions and route sets without writing Rust. Implies **serde**.
+* **hit-counters** -
+  Keep per-matcher hit counters on the router, see `Router::hit_counts`, and execution
+  latency histograms per priority band, see `Router::tier_latencies`.
+* **wasm** -
+  Builds the `wasm` module of JavaScript bindings. Only has an effect when targeting
+  `wasm32`, so server builds never pull in `wasm-bindgen`.
+* **header** -
+  Regenerates `atc_router.h`, the committed C header of the FFI, with cbindgen when
+  building. Implies **ffi**.
+* **regex-lite** -
+  Compile regexes with `regex-lite` instead of `regex` by default, see `regex_engine`.
+  The `regex` crate is still used to index regexes compiled by it.
+
+The features a build was compiled with can be checked at runtime with [`build_info`].
 */
 
 pub mod ast;
+pub mod cidr_list;
+pub mod cir;
+pub mod closure;
+pub mod compact;
 pub mod context;
+pub mod context_pool;
+pub mod corpus;
+pub mod coverage;
+pub mod dag;
+#[cfg(test)]
+mod differential;
+pub mod dot;
+pub mod error;
+pub mod explain;
+pub mod fuzzing;
+pub mod glob;
 pub mod interpreter;
+#[cfg(feature = "serde")]
+pub mod json;
+pub mod lint;
+pub mod lir;
+pub mod method;
+pub mod optimizer;
 pub mod parser;
+pub mod prefilter;
+#[cfg(feature = "serde")]
+pub mod regex_cache;
+pub mod regex_engine;
 pub mod router;
 pub mod schema;
 pub mod semantics;
+pub mod shared_router;
+pub mod simple_route;
+pub mod small_map;
+pub mod trace;
 
 #[cfg(feature = "ffi")]
 pub mod ffi;
 
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub mod wasm;
+
+pub use error::Error;
+
 #[macro_use]
 extern crate pest_derive;
+
+/// How this library was built, see [`build_info`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// The crate version.
+    pub version: &'static str,
+    /// Enabled crate features, sorted by name.
+    pub features: &'static [&'static str],
+}
+
+/// Returns the version and the crate features of this build, so hosts
+/// loading the library dynamically can check it provides what they need.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        features: &[
+            #[cfg(feature = "cli")]
+            "cli",
+            #[cfg(feature = "ffi")]
+            "ffi",
+            #[cfg(feature = "hit-counters")]
+            "hit-counters",
+            #[cfg(feature = "rayon")]
+            "rayon",
+            #[cfg(feature = "regex-lite")]
+            "regex-lite",
+            #[cfg(feature = "serde")]
+            "serde",
+            #[cfg(feature = "wasm")]
+            "wasm",
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(info.features.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(info.features.contains(&"ffi"), cfg!(feature = "ffi"));
+        assert_eq!(info.features.contains(&"serde"), cfg!(feature = "serde"));
+    }
+}
@@ -0,0 +1,407 @@
+//! Warnings about valid expressions that are most likely mistakes, or that
+//! are slower to evaluate than they need to be, for route editors to show
+//! while routes are being written.
+//!
+//! [`lint`] only looks at one expression and the schema, routes shadowing
+//! each other are found by the `atc lint` command instead.
+
+use crate::ast::{
+    BinaryOperator, Expression, LhsTransformations, LogicalExpression, Predicate, Value,
+};
+use crate::interpreter::lower_str;
+use crate::prefilter::{regex_literal, regex_prefix, regex_suffix};
+use crate::schema::{LowerPolicy, Schema};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// What a [`LintWarning`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintKind {
+    /// Part of the expression never holds, as in `a == 1 && a == 2`.
+    AlwaysFalse,
+    /// Part of the expression always holds, as in `a == 1 || !(a == 1)`.
+    AlwaysTrue,
+    /// The same operand appears twice in an `&&` or `||` chain.
+    DuplicatePredicate,
+    /// A `~` predicate anchored neither with `^` nor with a literal suffix
+    /// before `$`, which the prefix and suffix filters of routers can not
+    /// use.
+    UnanchoredRegex,
+    /// A `~` predicate matching nothing but literal text, which `==`, `^=`,
+    /// `=^` or `contains` check faster.
+    SimplifiableRegex,
+}
+
+impl LintKind {
+    /// The name of the kind, as the FFI and wasm bindings report it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LintKind::AlwaysFalse => "always_false",
+            LintKind::AlwaysTrue => "always_true",
+            LintKind::DuplicatePredicate => "duplicate_predicate",
+            LintKind::UnanchoredRegex => "unanchored_regex",
+            LintKind::SimplifiableRegex => "simplifiable_regex",
+        }
+    }
+}
+
+impl fmt::Display for LintKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A problem found by [`lint`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintWarning {
+    pub kind: LintKind,
+    /// The part of the expression the warning is about, in canonical form.
+    pub expression: String,
+    /// What is wrong, and the cheaper way to write it if there is one.
+    pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.expression, self.message)
+    }
+}
+
+/// Returns warnings about `expr`, which must be valid against `schema`,
+/// outer parts of the expression first.
+pub fn lint(expr: &Expression, schema: &Schema) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    lint_expression(expr, schema, &mut warnings);
+    warnings
+}
+
+fn warn(
+    out: &mut Vec<LintWarning>,
+    kind: LintKind,
+    expression: &dyn fmt::Display,
+    message: String,
+) {
+    out.push(LintWarning {
+        kind,
+        expression: expression.to_string(),
+        message,
+    });
+}
+
+fn lint_expression(expr: &Expression, schema: &Schema, out: &mut Vec<LintWarning>) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(..) => lint_chain(true, expr, schema, out),
+            LogicalExpression::Or(..) => lint_chain(false, expr, schema, out),
+            LogicalExpression::Not(e) => lint_expression(e, schema, out),
+        },
+        Expression::Predicate(p) => lint_predicate(p, schema, out),
+        Expression::FieldComparison(_) | Expression::Bool(_) => {}
+    }
+}
+
+/// Appends the operands of the `&&` chain rooted at `e` (`||` chain unless
+/// `and`) to `out`.
+fn operands<'e>(and: bool, e: &'e Expression, out: &mut Vec<&'e Expression>) {
+    if let Expression::Logical(l) = e {
+        match l.as_ref() {
+            LogicalExpression::And(l, r) if and => {
+                operands(and, l, out);
+                operands(and, r, out);
+                return;
+            }
+            LogicalExpression::Or(l, r) if !and => {
+                operands(and, l, out);
+                operands(and, r, out);
+                return;
+            }
+            _ => {}
+        }
+    }
+    out.push(e);
+}
+
+/// Identity of an operand, the `Debug` output of the AST is structural.
+fn key(e: &Expression) -> String {
+    format!("{:?}", e)
+}
+
+fn lint_chain(and: bool, chain: &Expression, schema: &Schema, out: &mut Vec<LintWarning>) {
+    let mut ops = Vec::new();
+    operands(and, chain, &mut ops);
+    let (name, decided, kind) = match and {
+        true => ("&&", "false", LintKind::AlwaysFalse),
+        false => ("||", "true", LintKind::AlwaysTrue),
+    };
+
+    let keys: HashSet<_> = ops.iter().map(|e| key(e)).collect();
+    for e in &ops {
+        match e {
+            Expression::Bool(b) if *b != and => warn(
+                out,
+                kind,
+                chain,
+                format!("`{}` makes the whole `{}` chain {}", b, name, decided),
+            ),
+            Expression::Logical(l) => {
+                if let LogicalExpression::Not(inner) = l.as_ref() {
+                    if keys.contains(&key(inner)) {
+                        warn(
+                            out,
+                            kind,
+                            chain,
+                            format!(
+                                "{} is in the same `{}` chain as its negation, which is always {}",
+                                inner, name, decided
+                            ),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if and {
+        conflicting_equalities(&ops, chain, out);
+    }
+
+    let mut seen = HashSet::new();
+    for e in ops {
+        if seen.insert(key(e)) {
+            lint_expression(e, schema, out);
+        } else {
+            warn(
+                out,
+                LintKind::DuplicatePredicate,
+                e,
+                format!("appears more than once in the same `{}` chain", name),
+            );
+        }
+    }
+}
+
+/// Finds `==` and `!=` predicates of an `&&` chain that can not hold
+/// together: `a == 1 && a == 2`, or `a == 1 && a != 1`. Without `any()`,
+/// every value of the field must be equal to both sides.
+fn conflicting_equalities(ops: &[&Expression], chain: &Expression, out: &mut Vec<LintWarning>) {
+    // field, transformations included, to the first `==` on it
+    let mut equal: HashMap<String, &Predicate> = HashMap::new();
+    let mut not_equal = Vec::new();
+
+    for e in ops {
+        let Expression::Predicate(p) = e else {
+            continue;
+        };
+        if p.lhs.get_transformations().1
+            || !matches!(p.rhs, Value::String(_) | Value::Int(_) | Value::IpAddr(_))
+        {
+            continue;
+        }
+
+        match p.op {
+            BinaryOperator::Equals => match equal.get(&p.lhs.to_string()) {
+                Some(first) if first.rhs.to_string() != p.rhs.to_string() => {
+                    warn(
+                        out,
+                        LintKind::AlwaysFalse,
+                        chain,
+                        format!("{} and {} can not both hold", first, p),
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    equal.insert(p.lhs.to_string(), p);
+                }
+            },
+            BinaryOperator::NotEquals => not_equal.push(p),
+            _ => {}
+        }
+    }
+
+    for p in not_equal {
+        if let Some(first) = equal.get(&p.lhs.to_string()) {
+            if first.rhs.to_string() == p.rhs.to_string() {
+                warn(
+                    out,
+                    LintKind::AlwaysFalse,
+                    chain,
+                    format!("{} and {} can not both hold", first, p),
+                );
+            }
+        }
+    }
+}
+
+fn lint_predicate(p: &Predicate, schema: &Schema, out: &mut Vec<LintWarning>) {
+    // the transformation applied last
+    let outermost = p
+        .lhs
+        .transformations
+        .iter()
+        .rev()
+        .find(|t| !t.is_quantifier());
+    let policy = schema.lower_policy();
+    // literals are folded along with the values otherwise
+    if outermost == Some(&LhsTransformations::Lower) && policy != LowerPolicy::Fold {
+        let has_upper = |s: &str| matches!(lower_str(s, policy), Cow::Owned(_));
+        let never = match (&p.op, &p.rhs) {
+            (
+                BinaryOperator::Equals
+                | BinaryOperator::Prefix
+                | BinaryOperator::Postfix
+                | BinaryOperator::Contains,
+                Value::String(s),
+            ) => has_upper(s),
+            (BinaryOperator::In, Value::List(l)) => l.iter().all(|s| has_upper(s)),
+            _ => false,
+        };
+        if never {
+            warn(
+                out,
+                LintKind::AlwaysFalse,
+                p,
+                "lower() values have no upper-case letters, the literal must be in lower case"
+                    .to_string(),
+            );
+        }
+    }
+
+    if let (BinaryOperator::Regex, Value::Regex(re)) = (&p.op, &p.rhs) {
+        let pattern = re.as_str();
+        if let Some((op, literal)) = regex_literal(pattern) {
+            warn(
+                out,
+                LintKind::SimplifiableRegex,
+                p,
+                format!(
+                    "the regex only matches literal text, `{} {} {}` is cheaper",
+                    p.lhs,
+                    op,
+                    Value::String(literal.into())
+                ),
+            );
+        } else if !p.lhs.is_transformed()
+            && regex_prefix(pattern).is_none()
+            && regex_suffix(pattern).is_none_or(|s| s.is_empty())
+        {
+            warn(
+                out,
+                LintKind::UnanchoredRegex,
+                p,
+                "the regex is not anchored with `^` or a literal suffix before `$`, \
+                 so routers can not filter requests on it before evaluating it"
+                    .to_string(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::parser::parse;
+    use crate::semantics::Validate;
+
+    fn schema() -> Schema {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+        schema.add_field("net.port", Type::Int);
+        schema
+    }
+
+    fn kinds(atc: &str, schema: &Schema) -> Vec<LintKind> {
+        let expr = parse(atc).unwrap();
+        expr.validate(schema).unwrap();
+        lint(&expr, schema).into_iter().map(|w| w.kind).collect()
+    }
+
+    #[test]
+    fn test_lint() {
+        use LintKind::*;
+
+        let schema = schema();
+        for (atc, expected) in [
+            (r#"http.path ^= "/a" && net.port == 80"#, vec![]),
+            ("net.port == 1 && net.port == 2", vec![AlwaysFalse]),
+            ("net.port == 1 && net.port == 1", vec![DuplicatePredicate]),
+            ("net.port == 1 && net.port != 1", vec![AlwaysFalse]),
+            ("net.port == 1 && net.port != 2", vec![]),
+            ("net.port == 1 || net.port == 2", vec![]),
+            // some value is 1, another one 2
+            ("any(net.port) == 1 && any(net.port) == 2", vec![]),
+            (
+                r#"http.host == "a" && (net.port == 1 || !(net.port == 1))"#,
+                vec![AlwaysTrue],
+            ),
+            (
+                r#"!(http.host == "a") && net.port > 0 && http.host == "a""#,
+                vec![AlwaysFalse],
+            ),
+            ("net.port > 0 && false", vec![AlwaysFalse]),
+            ("net.port > 0 || true", vec![AlwaysTrue]),
+            ("true", vec![]),
+            (
+                r#"(http.host == "a" || net.port == 1) && (net.port == 1 || http.host == "a")"#,
+                vec![],
+            ),
+            (
+                r#"http.host == "a" && (net.port == 1 || net.port == 1)"#,
+                vec![DuplicatePredicate],
+            ),
+            (r#"http.path ~ "^/api/v[0-9]+/""#, vec![]),
+            (r#"http.host ~ "^[a-z]+\\.example\\.com$""#, vec![]),
+            (r#"http.host ~ "[a-z]+\\.example\\.com$""#, vec![]),
+            (
+                r#"http.host ~ "\\.example\\.[a-z]+$""#,
+                vec![UnanchoredRegex],
+            ),
+            (r#"http.path ~ "/v[0-9]+/""#, vec![UnanchoredRegex]),
+            (r#"lower(http.path) ~ "/v[0-9]+/""#, vec![]),
+            (r#"http.path ~ "^/api/""#, vec![SimplifiableRegex]),
+            (r#"http.path ~ "admin""#, vec![SimplifiableRegex]),
+            (r#"lower(http.host) == "Example.com""#, vec![AlwaysFalse]),
+            (r#"lower(http.host) in ("A.com", "b.com")"#, vec![]),
+            (r#"lower(http.host) != "Example.com""#, vec![]),
+            (r#"upper(lower(http.host)) == "EXAMPLE""#, vec![]),
+        ] {
+            assert_eq!(kinds(atc, &schema), expected, "{}", atc);
+        }
+    }
+
+    #[test]
+    fn test_lint_messages() {
+        let schema = schema();
+        let expr = parse(r#"http.path ~ "^/api$" && net.port == 1 && net.port == 2"#).unwrap();
+        let warnings: Vec<_> = lint(&expr, &schema)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "(((http.path ~ \"^/api$\") && (net.port == 1)) && (net.port == 2)): \
+                 (net.port == 1) and (net.port == 2) can not both hold",
+                "(http.path ~ \"^/api$\"): \
+                 the regex only matches literal text, `http.path == \"/api\"` is cheaper",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_lower_policy() {
+        let mut schema = schema();
+        let atc = r#"lower(http.host) == "ÄB""#;
+        assert_eq!(kinds(atc, &schema), [LintKind::AlwaysFalse]);
+        // `Ä` is kept by ASCII lower-casing
+        schema.set_lower_policy(LowerPolicy::Ascii);
+        assert_eq!(kinds(atc, &schema), [LintKind::AlwaysFalse]);
+        assert_eq!(kinds(r#"lower(http.host) == "Äb""#, &schema), []);
+        // and the literal is folded along with the values
+        schema.set_lower_policy(LowerPolicy::Fold);
+        assert_eq!(kinds(atc, &schema), []);
+    }
+}
@@ -0,0 +1,138 @@
+//! Linear intermediate representation (LIR) of expressions.
+//!
+//! A [`LirProgram`] is a flat list of instructions run one after the other
+//! against a single boolean register. `&&` and `||` become conditional
+//! jumps over their right operand, so evaluating a matcher is a loop
+//! without recursion. Selected with
+//! [`Engine::Lir`](crate::router::Engine::Lir).
+
+use crate::ast::{Expression, FieldComparison, LogicalExpression, Predicate};
+use crate::context::{Context, Match};
+use crate::error::EvalError;
+use crate::interpreter::Execute;
+
+#[derive(Debug, Clone)]
+pub enum LirInstruction {
+    /// Sets the register to the result of the predicate.
+    Predicate(Predicate),
+    /// Sets the register to the result of the field comparison.
+    FieldComparison(FieldComparison),
+    /// Sets the register.
+    Const(bool),
+    /// Negates the register.
+    Not,
+    /// Continues at the given instruction if the register is `false`.
+    JumpIfFalse(usize),
+    /// Continues at the given instruction if the register is `true`.
+    JumpIfTrue(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct LirProgram {
+    instructions: Vec<LirInstruction>,
+}
+
+impl LirProgram {
+    pub fn instructions(&self) -> &[LirInstruction] {
+        &self.instructions
+    }
+}
+
+impl From<&Expression> for LirProgram {
+    fn from(expr: &Expression) -> Self {
+        let mut program = LirProgram {
+            instructions: Vec::new(),
+        };
+        compile(expr, &mut program.instructions);
+        program
+    }
+}
+
+fn compile(expr: &Expression, out: &mut Vec<LirInstruction>) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => short_circuit(true, l, r, out),
+            LogicalExpression::Or(l, r) => short_circuit(false, l, r, out),
+            LogicalExpression::Not(e) => {
+                compile(e, out);
+                out.push(LirInstruction::Not);
+            }
+        },
+        Expression::Predicate(p) => out.push(LirInstruction::Predicate(p.clone())),
+        Expression::FieldComparison(c) => out.push(LirInstruction::FieldComparison(c.clone())),
+        Expression::Bool(b) => out.push(LirInstruction::Const(*b)),
+    }
+}
+
+/// Compiles `l && r`, or `l || r` when `and` is `false`. When `r` is
+/// skipped the register already holds the result.
+fn short_circuit(and: bool, l: &Expression, r: &Expression, out: &mut Vec<LirInstruction>) {
+    compile(l, out);
+    let jump = out.len();
+    // patched once the end of `r` is known
+    out.push(LirInstruction::Not);
+    compile(r, out);
+
+    out[jump] = if and {
+        LirInstruction::JumpIfFalse(out.len())
+    } else {
+        LirInstruction::JumpIfTrue(out.len())
+    };
+}
+
+impl Execute for LirProgram {
+    fn try_execute(&self, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        let mut register = false;
+        let mut pc = 0;
+
+        while let Some(instruction) = self.instructions.get(pc) {
+            pc += 1;
+            match instruction {
+                LirInstruction::Predicate(p) => register = p.try_execute(ctx, m)?,
+                LirInstruction::FieldComparison(c) => register = c.try_execute(ctx, m)?,
+                LirInstruction::Const(b) => register = *b,
+                LirInstruction::Not => register = !register,
+                LirInstruction::JumpIfFalse(target) if !register => pc = *target,
+                LirInstruction::JumpIfTrue(target) if register => pc = *target,
+                LirInstruction::JumpIfFalse(_) | LirInstruction::JumpIfTrue(_) => {}
+            }
+        }
+
+        Ok(register)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn test_layout() {
+        let program = LirProgram::from(&parse(r#"a == "x" && !(b == 1 || true)"#).unwrap());
+        let layout: Vec<_> = program
+            .instructions()
+            .iter()
+            .map(|i| match i {
+                LirInstruction::Predicate(p) => p.to_string(),
+                LirInstruction::FieldComparison(c) => c.to_string(),
+                LirInstruction::Const(b) => b.to_string(),
+                LirInstruction::Not => "not".to_string(),
+                LirInstruction::JumpIfFalse(t) => format!("jump if false {}", t),
+                LirInstruction::JumpIfTrue(t) => format!("jump if true {}", t),
+            })
+            .collect();
+
+        assert_eq!(
+            layout,
+            [
+                r#"(a == "x")"#,
+                "jump if false 6",
+                "(b == 1)",
+                "jump if true 5",
+                "true",
+                "not",
+            ]
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Bitmask matching of HTTP methods.
+//!
+//! Nearly every route constrains the request method, usually with
+//! `http.method == "GET"` or `http.method in ("GET", "HEAD")`. Once a field
+//! is designated with [`Schema::set_method_field`], routers compile such
+//! predicates on it into a [`MethodSet`], one bit per standard method, and
+//! contexts turn the method of the request into the same bits when it is
+//! added. Matching then takes a single `&` instead of string comparisons.
+//!
+//! [`Schema::set_method_field`]: crate::schema::Schema::set_method_field
+
+use crate::ast::{BinaryOperator, Expression, LogicalExpression, Predicate, Value};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// The methods that have a bit, bit `i` standing for `METHODS[i]`.
+pub const METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// The bit of `method`, `0` for methods not in [`METHODS`]. Methods are case
+/// sensitive.
+pub fn method_bit(method: &str) -> u16 {
+    METHODS
+        .iter()
+        .position(|m| *m == method)
+        .map_or(0, |i| 1 << i)
+}
+
+/// The right hand side of a compiled `==` or `in` predicate on the method
+/// field, see the [module documentation](crate::method).
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MethodSet {
+    bits: u16,
+    /// The right hand side as written, a string or a list.
+    original: Box<Value>,
+}
+
+impl MethodSet {
+    /// Returns `None` unless `rhs` is a string or a list made of
+    /// [`METHODS`] only.
+    pub fn new(rhs: &Value) -> Option<Self> {
+        let bits = match rhs {
+            Value::String(s) => method_bit(s),
+            Value::List(l) => l.iter().try_fold(0, |bits, m| match method_bit(m) {
+                0 => None,
+                bit => Some(bits | bit),
+            })?,
+            _ => return None,
+        };
+
+        (bits != 0).then(|| MethodSet {
+            bits,
+            original: Box::new(rhs.clone()),
+        })
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.bits
+    }
+
+    pub fn original(&self) -> &Value {
+        &self.original
+    }
+
+    pub fn into_original(self) -> Value {
+        *self.original
+    }
+}
+
+impl PartialEq for MethodSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.original == other.original
+    }
+}
+
+impl Expression {
+    /// Compiles every `field == "..."` and `field in (...)` predicate whose
+    /// values are all in [`METHODS`] into a [`MethodSet`] lookup, see the
+    /// [module documentation](crate::method). Predicates using `lower()` or
+    /// `any()` are kept as they are.
+    pub fn compile_methods(self, field: &str) -> Expression {
+        match self {
+            Expression::Logical(l) => Expression::Logical(Box::new(match *l {
+                LogicalExpression::And(l, r) => {
+                    LogicalExpression::And(l.compile_methods(field), r.compile_methods(field))
+                }
+                LogicalExpression::Or(l, r) => {
+                    LogicalExpression::Or(l.compile_methods(field), r.compile_methods(field))
+                }
+                LogicalExpression::Not(e) => LogicalExpression::Not(e.compile_methods(field)),
+            })),
+            Expression::Predicate(p)
+                if p.lhs.var_name == field
+                    && p.lhs.is_plain()
+                    && matches!(p.op, BinaryOperator::Equals | BinaryOperator::In) =>
+            {
+                match MethodSet::new(&p.rhs) {
+                    Some(methods) => Expression::Predicate(Predicate {
+                        rhs: Value::Methods(methods),
+                        ..p
+                    }),
+                    None => Expression::Predicate(p),
+                }
+            }
+            e => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::context::{Context, Match};
+    use crate::interpreter::Execute;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    #[test]
+    fn test_method_set() {
+        assert_eq!(method_bit("GET"), 1);
+        assert_eq!(method_bit("PATCH"), 1 << 8);
+        assert_eq!(method_bit("get"), 0);
+        assert_eq!(method_bit("PROPFIND"), 0);
+
+        let list = Value::List(vec!["GET".to_string(), "POST".to_string()]);
+        assert_eq!(MethodSet::new(&list).unwrap().bits(), 0b101);
+        assert!(MethodSet::new(&Value::String("PROPFIND".into())).is_none());
+        assert!(MethodSet::new(&Value::List(vec![
+            "GET".to_string(),
+            "PROPFIND".to_string()
+        ]))
+        .is_none());
+    }
+
+    /// Compiled predicates print and match like the original ones.
+    #[test]
+    fn test_compile_methods() {
+        let mut schema = Schema::default();
+        schema.add_field("http.method", Type::String);
+        schema.add_field("http.path", Type::String);
+        schema.set_method_field("http.method");
+
+        let exprs: Vec<_> = [
+            r#"http.method == "GET""#,
+            r#"http.method in ("GET", "HEAD") && http.path == "/""#,
+            r#"!(http.method == "DELETE") || http.method == "PROPFIND""#,
+            r#"http.method in ("PUT", "PROPFIND")"#,
+            r#"any(http.method) == "POST""#,
+        ]
+        .iter()
+        .map(|atc| {
+            let e = parse(atc).unwrap();
+            let compiled = e.clone().compile_methods("http.method");
+            assert_eq!(compiled.to_string(), e.to_string());
+            assert_eq!(compiled.clone().expand().to_string(), e.to_string());
+            (compiled, e)
+        })
+        .collect();
+        assert!(matches!(
+            &exprs[0].0,
+            Expression::Predicate(Predicate {
+                rhs: Value::Methods(_),
+                ..
+            })
+        ));
+
+        let values: &[&[&str]] = &[
+            &[],
+            &["GET"],
+            &["HEAD"],
+            &["DELETE"],
+            &["PROPFIND"],
+            &["get"],
+            &["GET", "GET"],
+            &["GET", "POST"],
+        ];
+        for values in values {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", "/");
+            for v in *values {
+                ctx.add_value_str("http.method", v);
+            }
+
+            for (compiled, original) in &exprs {
+                let mut m1 = Match::new();
+                let mut m2 = Match::new();
+                let matched = compiled.execute(&mut ctx, &mut m1);
+                assert_eq!(
+                    matched,
+                    original.execute(&mut ctx, &mut m2),
+                    "{} {:?}",
+                    original,
+                    values
+                );
+                if matched {
+                    assert_eq!(m1.matches, m2.matches);
+                }
+            }
+        }
+    }
+}
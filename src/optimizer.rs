@@ -0,0 +1,439 @@
+//! Rewrites expressions into cheaper, equivalent ones.
+//!
+//! [`Expression::optimize`] removes double negations, folds `true` and
+//! `false`, flattens `&&` and `||` chains, drops predicates repeated within a
+//! chain and hoists predicates every branch of an `||` shares, so
+//! `(a && b) || (a && c)` becomes `a && (b || c)`.
+//!
+//! The optimized expression matches exactly the same contexts as the
+//! original one. Since fewer predicates are evaluated, [`Match::matches`]
+//! and [`Match::captures`] may differ when several predicates write the
+//! same key.
+//!
+//! [`Expression::rewrite_regexes`] is a separate pass replacing `~`
+//! predicates whose regex is nothing but literal text by the string operator
+//! checking the same thing: `http.path ~ "^/api/v1/.*"` becomes
+//! `http.path ^= "/api/v1/"`. The regexes it rewrites have no capture
+//! groups, but their whole match is no longer recorded as capture `0`, and
+//! [`Match::matches`] holds the literal instead of the matched text.
+//!
+//! [`Match::matches`]: crate::context::Match::matches
+//! [`Match::captures`]: crate::context::Match::captures
+
+use crate::ast::{BinaryOperator, Expression, LogicalExpression, Value};
+use crate::prefilter::regex_literal;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Chain {
+    And,
+    Or,
+}
+
+impl Expression {
+    /// Returns an equivalent expression that is cheaper to evaluate, see the
+    /// [module documentation](crate::optimizer).
+    pub fn optimize(self) -> Expression {
+        match self {
+            Expression::Logical(l) => match *l {
+                LogicalExpression::Not(e) => match e.optimize() {
+                    Expression::Bool(b) => Expression::Bool(!b),
+                    Expression::Logical(inner) => match *inner {
+                        LogicalExpression::Not(e) => e,
+                        inner => not(Expression::Logical(Box::new(inner))),
+                    },
+                    e => not(e),
+                },
+                LogicalExpression::And(l, r) => {
+                    match fold(Chain::And, optimize_chain(Chain::And, l, r)) {
+                        Ok(operands) => build(Chain::And, operands),
+                        Err(b) => Expression::Bool(b),
+                    }
+                }
+                LogicalExpression::Or(l, r) => {
+                    match fold(Chain::Or, optimize_chain(Chain::Or, l, r)) {
+                        Ok(operands) => hoist(operands),
+                        Err(b) => Expression::Bool(b),
+                    }
+                }
+            },
+            e => e,
+        }
+    }
+
+    /// Rewrites `~` predicates matching nothing but literal text into `==`,
+    /// `^=`, `=^` or `contains`, see the
+    /// [module documentation](crate::optimizer). Predicates on `lower()`'d
+    /// fields are left alone, [`LowerPolicy::Fold`] compares their regexes
+    /// and literals differently.
+    ///
+    /// [`LowerPolicy::Fold`]: crate::schema::LowerPolicy::Fold
+    pub fn rewrite_regexes(mut self) -> Expression {
+        self.for_each_predicate_mut(&mut |p| {
+            if p.op != BinaryOperator::Regex || p.lhs.get_transformations().0 {
+                return;
+            }
+            if let Value::Regex(re) = &p.rhs {
+                if let Some((op, literal)) = regex_literal(re.as_str()) {
+                    p.op = op;
+                    p.rhs = Value::String(literal.into());
+                }
+            }
+        });
+        self
+    }
+}
+
+fn not(e: Expression) -> Expression {
+    Expression::Logical(Box::new(LogicalExpression::Not(e)))
+}
+
+/// Identity of an expression for deduplication. The `Debug` output of the
+/// AST is structural, regexes included.
+fn key(e: &Expression) -> String {
+    format!("{:?}", e)
+}
+
+/// Appends the operands of the `chain` rooted at `e` to `out`.
+fn flatten(chain: Chain, e: Expression, out: &mut Vec<Expression>) {
+    match e {
+        Expression::Logical(l) => match *l {
+            LogicalExpression::And(l, r) if chain == Chain::And => {
+                flatten(chain, l, out);
+                flatten(chain, r, out);
+            }
+            LogicalExpression::Or(l, r) if chain == Chain::Or => {
+                flatten(chain, l, out);
+                flatten(chain, r, out);
+            }
+            l => out.push(Expression::Logical(Box::new(l))),
+        },
+        e => out.push(e),
+    }
+}
+
+/// Optimizes the operands of `l <chain> r`, returning them flattened and
+/// without duplicates, in their original order.
+fn optimize_chain(chain: Chain, l: Expression, r: Expression) -> Vec<Expression> {
+    let mut raw = Vec::new();
+    flatten(chain, l, &mut raw);
+    flatten(chain, r, &mut raw);
+
+    let mut operands = Vec::with_capacity(raw.len());
+    for e in raw {
+        // optimizing an operand can turn it into a chain of the same kind
+        flatten(chain, e.optimize(), &mut operands);
+    }
+
+    dedup(operands)
+}
+
+/// Drops the `true` operands of an `&&` chain (`false` ones of an `||`
+/// chain), or returns the constant the whole chain evaluates to.
+fn fold(chain: Chain, operands: Vec<Expression>) -> Result<Vec<Expression>, bool> {
+    let neutral = chain == Chain::And;
+    let mut kept = Vec::with_capacity(operands.len());
+
+    for e in operands {
+        match e {
+            Expression::Bool(b) if b == neutral => {}
+            Expression::Bool(b) => return Err(b),
+            e => kept.push(e),
+        }
+    }
+
+    if kept.is_empty() {
+        Err(neutral)
+    } else {
+        Ok(kept)
+    }
+}
+
+fn dedup(operands: Vec<Expression>) -> Vec<Expression> {
+    let mut seen = Vec::with_capacity(operands.len());
+    let mut unique = Vec::with_capacity(operands.len());
+
+    for e in operands {
+        let k = key(&e);
+        if !seen.contains(&k) {
+            seen.push(k);
+            unique.push(e);
+        }
+    }
+
+    unique
+}
+
+/// Joins `operands` into a left associative chain, like the parser does.
+fn build(chain: Chain, operands: Vec<Expression>) -> Expression {
+    let mut operands = operands.into_iter();
+    let first = operands.next().expect("chains have at least one operand");
+
+    operands.fold(first, |acc, e| {
+        Expression::Logical(Box::new(match chain {
+            Chain::And => LogicalExpression::And(acc, e),
+            Chain::Or => LogicalExpression::Or(acc, e),
+        }))
+    })
+}
+
+/// Builds the `||` of `branches`, moving the `&&` operands shared by every
+/// branch in front of it.
+fn hoist(branches: Vec<Expression>) -> Expression {
+    if branches.len() < 2 {
+        return build(Chain::Or, branches);
+    }
+
+    let mut conjunctions: Vec<Vec<Expression>> = branches
+        .into_iter()
+        .map(|b| {
+            let mut operands = Vec::new();
+            flatten(Chain::And, b, &mut operands);
+            operands
+        })
+        .collect();
+
+    let keys: Vec<Vec<String>> = conjunctions
+        .iter()
+        .map(|c| c.iter().map(key).collect())
+        .collect();
+    let common: Vec<String> = keys[0]
+        .iter()
+        .filter(|k| keys[1..].iter().all(|other| other.contains(k)))
+        .cloned()
+        .collect();
+
+    if common.is_empty() {
+        return build(
+            Chain::Or,
+            conjunctions
+                .into_iter()
+                .map(|c| build(Chain::And, c))
+                .collect(),
+        );
+    }
+
+    let mut hoisted = Vec::with_capacity(common.len() + 1);
+    let mut rest = Vec::with_capacity(conjunctions.len());
+    let mut always_true = false;
+
+    for (i, c) in conjunctions.drain(..).enumerate() {
+        let mut remaining = Vec::new();
+        for e in c {
+            if common.contains(&key(&e)) {
+                if i == 0 {
+                    hoisted.push(e);
+                }
+            } else {
+                remaining.push(e);
+            }
+        }
+
+        // a branch made only of common operands is true whenever they are
+        always_true |= remaining.is_empty();
+        rest.push(remaining);
+    }
+
+    if !always_true {
+        let branches = rest.into_iter().map(|r| build(Chain::And, r)).collect();
+        hoisted.push(build(Chain::Or, branches));
+    }
+
+    build(Chain::And, hoisted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::context::{Context, Match};
+    use crate::corpus::{Corpus, Rng, Shape};
+    use crate::interpreter::Execute;
+    use crate::parser::parse;
+    use crate::schema::Schema;
+
+    fn optimize(atc: &str) -> String {
+        parse(atc).unwrap().optimize().to_string()
+    }
+
+    #[test]
+    fn test_double_negation() {
+        assert_eq!(optimize("!(!(a == 1))"), "(a == 1)");
+        assert_eq!(optimize("!(!(!(a == 1)))"), "!((a == 1))");
+        assert_eq!(optimize("!(!(a == 1 && b == 2))"), "((a == 1) && (b == 2))");
+    }
+
+    #[test]
+    fn test_flatten_and_dedup() {
+        assert_eq!(
+            optimize("a == 1 && (b == 2 && (a == 1 && c == 3))"),
+            "(((a == 1) && (b == 2)) && (c == 3))"
+        );
+        assert_eq!(
+            optimize("a == 1 || (b == 2 || !(!(a == 1)))"),
+            "((a == 1) || (b == 2))"
+        );
+        assert_eq!(
+            optimize(r#"a ~ "x" && a ~ "y" && a ~ "x""#),
+            r#"((a ~ "x") && (a ~ "y"))"#
+        );
+        // different operators are different predicates
+        assert_eq!(optimize("a == 1 && a != 1"), "((a == 1) && (a != 1))");
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(optimize("a == 1 && true"), "(a == 1)");
+        assert_eq!(optimize("true && a == 1 && true"), "(a == 1)");
+        assert_eq!(
+            optimize("a == 1 && (b == 2 || false)"),
+            "((a == 1) && (b == 2))"
+        );
+        assert_eq!(optimize("a == 1 && false"), "false");
+        assert_eq!(optimize("a == 1 || true"), "true");
+        assert_eq!(optimize("true && true"), "true");
+        assert_eq!(optimize("false || false"), "false");
+        assert_eq!(optimize("!(false) && a == 1"), "(a == 1)");
+        assert_eq!(optimize("!(a == 1 || true) || b == 2"), "(b == 2)");
+    }
+
+    #[test]
+    fn test_hoist() {
+        assert_eq!(
+            optimize("(a == 1 && b == 2) || (c == 3 && a == 1)"),
+            "((a == 1) && ((b == 2) || (c == 3)))"
+        );
+        // a branch reduced to nothing makes the rest of the || irrelevant
+        assert_eq!(optimize("(a == 1 && b == 2) || a == 1"), "(a == 1)");
+        assert_eq!(
+            optimize("(a == 1 && b == 2) || c == 3"),
+            "(((a == 1) && (b == 2)) || (c == 3))"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_regexes() {
+        let rewrite = |atc: &str| parse(atc).unwrap().rewrite_regexes().to_string();
+
+        assert_eq!(
+            rewrite(r#"http.path ~ "^/api/v1/.*""#),
+            r#"(http.path ^= "/api/v1/")"#
+        );
+        assert_eq!(
+            rewrite(r#"http.host ~ "^api\\.example\\.com$" || !(http.host ~ "\\.local$")"#),
+            r#"((http.host == "api.example.com") || !((http.host =^ ".local")))"#
+        );
+        assert_eq!(
+            rewrite(r#"any(http.headers.x) ~ "admin""#),
+            r#"(any(http.headers.x) contains "admin")"#
+        );
+        // groups, regex syntax and lower() are kept
+        for atc in [
+            r#"(http.path ~ "^/(?P<v>v1)/")"#,
+            r#"(http.path ~ "^/v[12]/")"#,
+            r#"(lower(http.path) ~ "^/api")"#,
+        ] {
+            assert_eq!(rewrite(atc), atc);
+        }
+    }
+
+    /// Rewritten regexes must match exactly the same values.
+    #[test]
+    fn test_rewrite_regexes_equivalence() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let patterns = [
+            r"^/api/v1/.*",
+            r".*/v1/",
+            r".*\.json$",
+            r"^/api$",
+            r"/api.*",
+            r"^.*",
+            r"\?",
+        ];
+        let paths = [
+            "",
+            "/",
+            "/api",
+            "/api/",
+            "/api/v1/",
+            "/api/v1/x",
+            "/x/api/v1/",
+            "/a.json",
+            "/a.json\n",
+            "/api\n/v1/",
+            "/?",
+            "/API/V1/",
+        ];
+
+        for pattern in patterns {
+            let original =
+                parse(&format!("http.path ~ {}", Value::String(pattern.into()))).unwrap();
+            let rewritten = original.clone().rewrite_regexes();
+            assert!(
+                matches!(rewritten, Expression::Predicate(ref p) if p.op != BinaryOperator::Regex)
+            );
+
+            for path in paths {
+                let mut ctx = Context::new(&schema);
+                ctx.add_value_str("http.path", path);
+                assert_eq!(
+                    rewritten.execute(&mut ctx, &mut Match::new()),
+                    original.execute(&mut ctx, &mut Match::new()),
+                    "{} {:?}",
+                    pattern,
+                    path
+                );
+            }
+        }
+    }
+
+    /// Optimized expressions must match exactly the same contexts.
+    #[test]
+    fn test_equivalence() {
+        let mut schema = Corpus::schema();
+        schema.add_field("net.dst.port", Type::Int);
+
+        let shape = Shape {
+            hosts: 3,
+            path_templates: 5,
+            header_checks: 2,
+            ..Default::default()
+        };
+        let mut corpus = Corpus::new(3, shape);
+        let atcs = corpus.expressions(30);
+
+        // combine corpus expressions into ones with shared predicates
+        let mut rng = Rng::new(4);
+        let mut exprs = Vec::new();
+        for _ in 0..100 {
+            let a = rng.pick(&atcs);
+            let b = rng.pick(&atcs);
+            let c = rng.pick(&atcs);
+            let atc = match rng.below(4) {
+                0 => format!("({a}) || ({b}) || !(!({a}))"),
+                1 => format!("(({a}) && ({b})) || (({a}) && ({c}))"),
+                2 => format!("(({a}) && true) || (({b}) && false) || !(true || ({c}))"),
+                _ => format!("(({a}) || ({c})) && !(({b}) && ({b}))"),
+            };
+            let expr = parse(&atc).unwrap();
+            exprs.push((expr.clone().optimize(), expr));
+        }
+
+        for i in 0..200 {
+            let mut ctx = Context::arbitrary_for(&schema, &mut rng);
+            if i % 2 == 0 {
+                corpus.fill_context(&mut ctx);
+            }
+
+            for (optimized, original) in &exprs {
+                assert_eq!(
+                    optimized.execute(&mut ctx, &mut Match::new()),
+                    original.execute(&mut ctx, &mut Match::new()),
+                    "{}",
+                    original
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,13 @@
 extern crate pest;
 
 use crate::ast::{
-    BinaryOperator, Expression, Lhs, LhsTransformations, LogicalExpression, Predicate, Value,
+    BinaryOperator, Expression, FieldComparison, Lhs, LhsTransformations, LogicalExpression,
+    Predicate, TransformArg, Value,
 };
+use crate::cidr_list::CidrList;
+use crate::glob::glob_to_regex;
+use crate::regex_engine::{DefaultEngine, Regex, RegexEngine};
+use crate::semantics::TRANSFORM_FUNCTIONS;
 use cidr::{IpCidr, Ipv4Cidr, Ipv6Cidr};
 use pest::error::Error as ParseError;
 use pest::error::ErrorVariant;
@@ -10,13 +15,11 @@ use pest::iterators::Pair;
 use pest::pratt_parser::Assoc as AssocNew;
 use pest::pratt_parser::{Op, PrattParser};
 use pest::Parser;
-use regex::Regex;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 type ParseResult<T> = Result<T, ParseError<Rule>>;
 /// cbindgen:ignore
 // Bug: https://github.com/eqrion/cbindgen/issues/286
-
 trait IntoParseResult<T> {
     #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
     fn into_parse_result(self, pair: &Pair<Rule>) -> ParseResult<T>;
@@ -61,12 +64,12 @@ impl ATCParser {
     }
     // matcher = { SOI ~ expression ~ EOI }
     #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
-    fn parse_matcher(&mut self, source: &str) -> ParseResult<Expression> {
+    fn parse_matcher(&mut self, source: &str, engine: &dyn RegexEngine) -> ParseResult<Expression> {
         let pairs = ATCParser::parse(Rule::matcher, source)?;
         let expr_pair = pairs.peek().unwrap().into_inner().peek().unwrap();
         let rule = expr_pair.as_rule();
         match rule {
-            Rule::expression => parse_expression(expr_pair, &self.pratt_parser),
+            Rule::expression => parse_expression(expr_pair, &self.pratt_parser, engine),
             _ => unreachable!(),
         }
     }
@@ -79,35 +82,53 @@ fn parse_ident(pair: Pair<Rule>) -> ParseResult<String> {
 
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 fn parse_lhs(pair: Pair<Rule>) -> ParseResult<Lhs> {
-    let pairs = pair.into_inner();
-    let pair = pairs.peek().unwrap();
+    let mut pairs = pair.into_inner();
+    let pair = pairs.next().unwrap();
     let rule = pair.as_rule();
     Ok(match rule {
         Rule::transform_func => parse_transform_func(pair)?,
         Rule::ident => {
             let var = parse_ident(pair)?;
+            // map_key = { "[" ~ ( str_literal | rawstr_literal ) ~ "]" }
+            let key = match pairs.next().map(|key| key.into_inner().next().unwrap()) {
+                Some(key) if key.as_rule() == Rule::rawstr_literal => {
+                    Some(parse_rawstr_literal(key)?)
+                }
+                Some(key) => Some(parse_str_literal(key)?),
+                None => None,
+            };
             Lhs {
                 var_name: var,
                 transformations: Vec::new(),
+                var_index: None,
+                key,
             }
         }
         _ => unreachable!(),
     })
 }
 
-// rhs = { str_literal | ip_literal | int_literal }
+// rhs = { str_literal | rawstr_literal | list_literal | cidr_list_literal | ip_literal |
+//         int_range_literal | float_literal | int_literal }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 fn parse_rhs(pair: Pair<Rule>) -> ParseResult<Value> {
     let pairs = pair.into_inner();
     let pair = pairs.peek().unwrap();
     let rule = pair.as_rule();
     Ok(match rule {
-        Rule::str_literal => Value::String(parse_str_literal(pair)?),
-        Rule::rawstr_literal => Value::String(parse_rawstr_literal(pair)?),
+        Rule::str_literal => Value::String(parse_str_literal(pair)?.into()),
+        Rule::rawstr_literal => Value::String(parse_rawstr_literal(pair)?.into()),
+        Rule::list_literal => Value::List(parse_list_literal(pair)?),
+        Rule::cidr_list_literal => Value::CidrList(parse_cidr_list_literal(pair)?),
         Rule::ipv4_cidr_literal => Value::IpCidr(IpCidr::V4(parse_ipv4_cidr_literal(pair)?)),
         Rule::ipv6_cidr_literal => Value::IpCidr(IpCidr::V6(parse_ipv6_cidr_literal(pair)?)),
         Rule::ipv4_literal => Value::IpAddr(IpAddr::V4(parse_ipv4_literal(pair)?)),
         Rule::ipv6_literal => Value::IpAddr(IpAddr::V6(parse_ipv6_literal(pair)?)),
+        Rule::int_range_literal => {
+            let (lo, hi) = parse_int_range_literal(pair)?;
+            Value::IntRange(lo, hi)
+        }
+        Rule::float_literal => Value::Float(parse_float_literal(pair)?),
         Rule::int_literal => Value::Int(parse_int_literal(pair)?),
         _ => unreachable!(),
     })
@@ -116,8 +137,9 @@ fn parse_rhs(pair: Pair<Rule>) -> ParseResult<Value> {
 // str_literal = ${ "\"" ~ str_inner ~ "\"" }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 fn parse_str_literal(pair: Pair<Rule>) -> ParseResult<String> {
+    // escapes only ever shrink the literal, so this never reallocates
+    let mut s = String::with_capacity(pair.as_str().len());
     let char_pairs = pair.into_inner();
-    let mut s = String::new();
     for char_pair in char_pairs {
         let rule = char_pair.as_rule();
         match rule {
@@ -133,8 +155,8 @@ fn parse_str_literal(pair: Pair<Rule>) -> ParseResult<String> {
 // rawstr_char = { !"\"#" ~ ANY }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 fn parse_rawstr_literal(pair: Pair<Rule>) -> ParseResult<String> {
+    let mut s = String::with_capacity(pair.as_str().len());
     let char_pairs = pair.into_inner();
-    let mut s = String::new();
     for char_pair in char_pairs {
         let rule = char_pair.as_rule();
         match rule {
@@ -145,6 +167,45 @@ fn parse_rawstr_literal(pair: Pair<Rule>) -> ParseResult<String> {
     Ok(s)
 }
 
+// list_literal = { "(" ~ list_item ~ ( "," ~ list_item )* ~ ","? ~ ")" }
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+fn parse_list_literal(pair: Pair<Rule>) -> ParseResult<Vec<String>> {
+    let mut items = pair
+        .into_inner()
+        .map(|item| match item.as_rule() {
+            Rule::str_literal => parse_str_literal(item),
+            Rule::rawstr_literal => parse_rawstr_literal(item),
+            _ => unreachable!(),
+        })
+        .collect::<ParseResult<Vec<_>>>()?;
+
+    // kept sorted so the interpreter can binary search it
+    items.sort_unstable();
+    items.dedup();
+
+    Ok(items)
+}
+
+// cidr_list_literal = { "(" ~ ip_literal ~ ( "," ~ ip_literal )* ~ ","? ~ ")" }
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+fn parse_cidr_list_literal(pair: Pair<Rule>) -> ParseResult<CidrList> {
+    let cidrs = pair
+        .into_inner()
+        .map(|item| {
+            Ok(match item.as_rule() {
+                Rule::ipv4_cidr_literal => IpCidr::V4(parse_ipv4_cidr_literal(item)?),
+                Rule::ipv6_cidr_literal => IpCidr::V6(parse_ipv6_cidr_literal(item)?),
+                // an address is the CIDR of that single host
+                Rule::ipv4_literal => IpCidr::new_host(IpAddr::V4(parse_ipv4_literal(item)?)),
+                Rule::ipv6_literal => IpCidr::new_host(IpAddr::V6(parse_ipv6_literal(item)?)),
+                _ => unreachable!(),
+            })
+        })
+        .collect::<ParseResult<Vec<_>>>()?;
+
+    Ok(CidrList::new(cidrs))
+}
+
 fn parse_str_esc(pair: Pair<Rule>) -> char {
     match pair.as_str() {
         r#"\""# => '"',
@@ -157,7 +218,7 @@ fn parse_str_esc(pair: Pair<Rule>) -> char {
     }
 }
 fn parse_str_char(pair: Pair<Rule>) -> char {
-    return pair.as_str().chars().next().unwrap();
+    pair.as_str().chars().next().unwrap()
 }
 
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
@@ -202,42 +263,85 @@ fn parse_int_literal(pair: Pair<Rule>) -> ParseResult<i64> {
     Ok(num)
 }
 
+// int_range_literal = { int_literal ~ ".." ~ int_literal }
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+fn parse_int_range_literal(pair: Pair<Rule>) -> ParseResult<(i64, i64)> {
+    let mut bounds = pair.clone().into_inner();
+    let lo = parse_int_literal(bounds.next().unwrap())?;
+    let hi = parse_int_literal(bounds.next().unwrap())?;
+
+    if lo > hi {
+        return Err("range start is greater than its end").into_parse_result(&pair);
+    }
+
+    Ok((lo, hi))
+}
+
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+fn parse_float_literal(pair: Pair<Rule>) -> ParseResult<f64> {
+    let num: f64 = pair.as_str().parse().into_parse_result(&pair)?;
+
+    if !num.is_finite() {
+        return Err("float literal is out of range").into_parse_result(&pair);
+    }
+
+    Ok(num)
+}
+
 // predicate = { lhs ~ binary_operator ~ rhs }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
-fn parse_predicate(pair: Pair<Rule>) -> ParseResult<Predicate> {
+fn parse_predicate(pair: Pair<Rule>, engine: &dyn RegexEngine) -> ParseResult<Predicate> {
     let mut pairs = pair.into_inner();
     let lhs = parse_lhs(pairs.next().unwrap())?;
     let op = parse_binary_operator(pairs.next().unwrap());
     let rhs_pair = pairs.next().unwrap();
-    let rhs = parse_rhs(rhs_pair.clone())?;
+    let rhs_span = rhs_pair.as_span();
+    let rhs = parse_rhs(rhs_pair)?;
     Ok(Predicate {
         lhs,
-        rhs: if op == BinaryOperator::Regex {
+        rhs: if op == BinaryOperator::Regex || op == BinaryOperator::Glob {
+            let name = if op == BinaryOperator::Glob {
+                "glob"
+            } else {
+                "regex"
+            };
+
             if let Value::String(s) = rhs {
-                let r = Regex::new(&s).map_err(|e| {
-                    ParseError::new_from_span(
-                        ErrorVariant::CustomError {
-                            message: e.to_string(),
-                        },
-                        rhs_pair.as_span(),
-                    )
+                let s = if op == BinaryOperator::Glob {
+                    glob_to_regex(&s)
+                } else {
+                    s.into_string()
+                };
+                let r = Regex::with_engine(&s, engine).map_err(|message| {
+                    ParseError::new_from_span(ErrorVariant::CustomError { message }, rhs_span)
                 })?;
 
                 Value::Regex(r)
             } else {
                 return Err(ParseError::new_from_span(
                     ErrorVariant::CustomError {
-                        message: "regex operator can only be used with String operands".to_string(),
+                        message: format!("{} operator can only be used with String operands", name),
                     },
-                    rhs_pair.as_span(),
+                    rhs_span,
                 ));
             }
         } else {
             rhs
         },
         op,
+        memo: None,
     })
 }
+// field_comparison = { lhs ~ binary_operator ~ lhs }
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+fn parse_field_comparison(pair: Pair<Rule>) -> ParseResult<FieldComparison> {
+    let mut pairs = pair.into_inner();
+    let lhs = parse_lhs(pairs.next().unwrap())?;
+    let op = parse_binary_operator(pairs.next().unwrap());
+    let rhs = parse_lhs(pairs.next().unwrap())?;
+
+    Ok(FieldComparison { lhs, rhs, op })
+}
 // transform_func = { ident ~ "(" ~ lhs ~ ")" }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 fn parse_transform_func(pair: Pair<Rule>) -> ParseResult<Lhs> {
@@ -246,24 +350,83 @@ fn parse_transform_func(pair: Pair<Rule>) -> ParseResult<Lhs> {
     let mut pairs = pairs.peekable();
     let func_name = pairs.next().unwrap().as_str().to_string();
     let mut lhs = parse_lhs(pairs.next().unwrap())?;
-    lhs.transformations.push(match func_name.as_str() {
-        "lower" => LhsTransformations::Lower,
-        "any" => LhsTransformations::Any,
-        unknown => {
+    let args = pairs
+        .map(parse_transform_arg)
+        .collect::<ParseResult<Vec<_>>>()?;
+    match transformation(&func_name, args) {
+        Some(t) => lhs.transformations.push(t),
+        None => {
             return Err(ParseError::new_from_span(
                 ErrorVariant::CustomError {
-                    message: format!("unknown transformation function: {}", unknown),
+                    message: format!("unknown transformation function: {}", func_name),
                 },
                 span,
             ));
         }
-    });
+    }
 
     Ok(lhs)
 }
 
+/// The transformation `name(field, args...)` applies, `None` if there is no
+/// such function. Arity and argument types are checked by validation.
+///
+/// `header_decode(field, "percent")` and `header_decode(field, "base64")`
+/// are other spellings of `url_decode(field)` and `base64_decode(field)`,
+/// and give the same transformation.
+pub(crate) fn transformation(name: &str, args: Vec<TransformArg>) -> Option<LhsTransformations> {
+    if name == "header_decode" {
+        match args.as_slice() {
+            [TransformArg::String(e)] if e == "percent" => {
+                return Some(LhsTransformations::UrlDecode)
+            }
+            [TransformArg::String(e)] if e == "base64" => {
+                return Some(LhsTransformations::Base64Decode)
+            }
+            _ => {}
+        }
+    }
+
+    Some(match (name, args.is_empty()) {
+        ("lower", true) => LhsTransformations::Lower,
+        ("any", true) => LhsTransformations::Any,
+        ("all", true) => LhsTransformations::All,
+        ("trim", true) => LhsTransformations::Trim,
+        ("upper", true) => LhsTransformations::Upper,
+        ("path_normalize", true) => LhsTransformations::PathNormalize,
+        ("url_decode", true) => LhsTransformations::UrlDecode,
+        ("base64_decode", true) => LhsTransformations::Base64Decode,
+        (name, _) if is_transform_func(name) => LhsTransformations::Custom(name.to_string(), args),
+        _ => return None,
+    })
+}
+
+fn is_transform_func(name: &str) -> bool {
+    matches!(
+        name,
+        "lower"
+            | "any"
+            | "all"
+            | "trim"
+            | "upper"
+            | "path_normalize"
+            | "url_decode"
+            | "base64_decode"
+    ) || TRANSFORM_FUNCTIONS.iter().any(|f| f.name == name)
+}
+
+fn parse_transform_arg(pair: Pair<Rule>) -> ParseResult<TransformArg> {
+    let pair = pair.into_inner().next().unwrap();
+    Ok(match pair.as_rule() {
+        Rule::str_literal => TransformArg::String(parse_str_literal(pair)?),
+        Rule::rawstr_literal => TransformArg::String(parse_rawstr_literal(pair)?),
+        Rule::int_literal => TransformArg::Int(parse_int_literal(pair)?),
+        _ => unreachable!(),
+    })
+}
+
 // binary_operator = { "==" | "!=" | "~" | "^=" | "=^" | ">=" |
-//                     ">" | "<=" | "<" | "in" | "not" ~ "in" | "contains" }
+//                     ">" | "<=" | "<" | "in" | "not" ~ "in" | "contains" | "glob" }
 fn parse_binary_operator(pair: Pair<Rule>) -> BinaryOperator {
     let rule = pair.as_str();
     use BinaryOperator as BinaryOp;
@@ -280,6 +443,7 @@ fn parse_binary_operator(pair: Pair<Rule>) -> BinaryOperator {
         "in" => BinaryOp::In,
         "not in" => BinaryOp::NotIn,
         "contains" => BinaryOp::Contains,
+        "glob" => BinaryOp::Glob,
         _ => unreachable!(),
     }
 }
@@ -289,39 +453,52 @@ fn parse_binary_operator(pair: Pair<Rule>) -> BinaryOperator {
 fn parse_parenthesised_expression(
     pair: Pair<Rule>,
     pratt: &PrattParser<Rule>,
+    engine: &dyn RegexEngine,
 ) -> ParseResult<Expression> {
     let mut pairs = pair.into_inner();
     let pair = pairs.next().unwrap();
     let rule = pair.as_rule();
     match rule {
-        Rule::expression => parse_expression(pair, pratt),
+        Rule::expression => parse_expression(pair, pratt, engine),
         Rule::not_op => Ok(Expression::Logical(Box::new(LogicalExpression::Not(
-            parse_expression(pairs.next().unwrap(), pratt)?,
+            parse_expression(pairs.next().unwrap(), pratt, engine)?,
         )))),
         _ => unreachable!(),
     }
 }
 
-// term = { predicate | parenthesised_expression }
+// term = { predicate | field_comparison | parenthesised_expression }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
-fn parse_term(pair: Pair<Rule>, pratt: &PrattParser<Rule>) -> ParseResult<Expression> {
+fn parse_term(
+    pair: Pair<Rule>,
+    pratt: &PrattParser<Rule>,
+    engine: &dyn RegexEngine,
+) -> ParseResult<Expression> {
     let pairs = pair.into_inner();
     let inner_rule = pairs.peek().unwrap();
     let rule = inner_rule.as_rule();
     match rule {
-        Rule::predicate => Ok(Expression::Predicate(parse_predicate(inner_rule)?)),
-        Rule::parenthesised_expression => parse_parenthesised_expression(inner_rule, pratt),
+        Rule::predicate => Ok(Expression::Predicate(parse_predicate(inner_rule, engine)?)),
+        Rule::field_comparison => Ok(Expression::FieldComparison(parse_field_comparison(
+            inner_rule,
+        )?)),
+        Rule::parenthesised_expression => parse_parenthesised_expression(inner_rule, pratt, engine),
+        Rule::bool_literal => Ok(Expression::Bool(inner_rule.as_str() == "true")),
         _ => unreachable!(),
     }
 }
 
 // expression = { term ~ ( logical_operator ~ term )* }
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
-fn parse_expression(pair: Pair<Rule>, pratt: &PrattParser<Rule>) -> ParseResult<Expression> {
+fn parse_expression(
+    pair: Pair<Rule>,
+    pratt: &PrattParser<Rule>,
+    engine: &dyn RegexEngine,
+) -> ParseResult<Expression> {
     let pairs = pair.into_inner();
     pratt
         .map_primary(|operand| match operand.as_rule() {
-            Rule::term => parse_term(operand, pratt),
+            Rule::term => parse_term(operand, pratt, engine),
             _ => unreachable!(),
         })
         .map_infix(|lhs, op, rhs| {
@@ -336,7 +513,14 @@ fn parse_expression(pair: Pair<Rule>, pratt: &PrattParser<Rule>) -> ParseResult<
 
 #[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
 pub fn parse(source: &str) -> ParseResult<Expression> {
-    ATCParser::new().parse_matcher(source)
+    parse_with_engine(source, &DefaultEngine::default())
+}
+
+/// Like [`parse`], compiling regexes with `engine` instead of
+/// [`DefaultEngine`].
+#[allow(clippy::result_large_err)] // it's fine as parsing is not the hot path
+pub fn parse_with_engine(source: &str, engine: &dyn RegexEngine) -> ParseResult<Expression> {
+    ATCParser::new().parse_matcher(source, engine)
 }
 
 #[cfg(test)]
@@ -360,4 +544,156 @@ mod tests {
                 " --> 1:23\n  |\n1 | (a == 1 || b == 2) && ! c == 3\n  |                       ^---\n  |\n  = expected term"
         );
     }
+
+    #[test]
+    fn test_huge_expression() {
+        let literal = r#"a\"b"#.repeat(40_000);
+        let atc = (0..1_000)
+            .map(|i| format!(r#"http.path == "/{}" || net.port == {}"#, i, i))
+            .chain(std::iter::once(format!(r#"http.path == "{}""#, literal)))
+            .collect::<Vec<_>>()
+            .join(" || ");
+        assert!(atc.len() > 100 * 1024);
+
+        // the tree is left associative, the last predicate is the
+        // right hand side of the outermost `||`
+        let Expression::Logical(logical) = parse(&atc).unwrap() else {
+            panic!("expected a logical expression");
+        };
+        let LogicalExpression::Or(_, rhs) = *logical else {
+            panic!("expected `||`");
+        };
+
+        match rhs {
+            Expression::Predicate(Predicate {
+                rhs: Value::String(s),
+                ..
+            }) => assert_eq!(s, "a\"b".repeat(40_000)),
+            _ => panic!("expected a string predicate"),
+        }
+    }
+
+    #[test]
+    fn test_bool_literal() {
+        assert!(matches!(parse("true"), Ok(Expression::Bool(true))));
+        assert!(matches!(parse(" false "), Ok(Expression::Bool(false))));
+        assert_eq!(
+            parse(r#"http.path == "/" && true || !(false)"#)
+                .unwrap()
+                .to_string(),
+            r#"((http.path == "/") && (true || !(false)))"#
+        );
+
+        // fields may still be named like the literals
+        assert!(matches!(parse("true == 1"), Ok(Expression::Predicate(_))));
+        assert!(matches!(
+            parse("truely == 1 && false.x == 2"),
+            Ok(Expression::Logical(_))
+        ));
+        assert!(parse("truex").is_err());
+        assert!(parse("!true").is_err());
+    }
+
+    #[test]
+    fn test_regex_error_span() {
+        assert_eq!(
+            parse(r#"a ~ "(""#).unwrap_err().line_col,
+            pest::error::LineColLocation::Span((1, 5), (1, 8))
+        );
+        assert_eq!(
+            parse("a ~ 1").unwrap_err().line_col,
+            pest::error::LineColLocation::Span((1, 5), (1, 6))
+        );
+    }
+
+    #[test]
+    fn test_transform_args() {
+        let expr = parse(r#"substr(lower(http.path), 1, 0x2) == "ab""#).unwrap();
+        let Expression::Predicate(p) = expr else {
+            panic!("not a predicate");
+        };
+        assert_eq!(
+            p.lhs.transformations,
+            vec![
+                LhsTransformations::Lower,
+                LhsTransformations::Custom(
+                    "substr".to_string(),
+                    vec![TransformArg::Int(1), TransformArg::Int(2)]
+                ),
+            ]
+        );
+
+        let expr = parse(r##"header_decode(http.headers.x, r#"base64"#) == "a""##).unwrap();
+        let Expression::Predicate(p) = expr else {
+            panic!("not a predicate");
+        };
+        assert_eq!(
+            p.lhs.transformations,
+            vec![LhsTransformations::Base64Decode]
+        );
+        let expr = parse(r#"header_decode(http.headers.x, "percent") == "a""#).unwrap();
+        assert_eq!(expr.to_string(), r#"(url_decode(http.headers.x) == "a")"#);
+        // left to validation
+        let expr = parse(r#"header_decode(http.headers.x, "hex") == "a""#).unwrap();
+        let Expression::Predicate(p) = expr else {
+            panic!("not a predicate");
+        };
+        assert_eq!(
+            p.lhs.transformations,
+            vec![LhsTransformations::Custom(
+                "header_decode".to_string(),
+                vec![TransformArg::String("hex".to_string())]
+            )]
+        );
+
+        // checked by validation rather than the parser
+        assert!(parse(r#"lower(http.path, 1) == "a""#).is_ok());
+        assert!(parse(r#"substr(http.path) == "a""#).is_ok());
+
+        assert!(parse(r#"foo(http.path, 1) == "a""#)
+            .unwrap_err()
+            .to_string()
+            .contains("unknown transformation function: foo"));
+        assert!(parse(r#"substr(http.path, 1.5) == "a""#).is_err());
+        assert!(parse(r#"substr(http.path, http.host) == "a""#).is_err());
+    }
+
+    #[test]
+    fn test_int_range() {
+        for (atc, expected) in [
+            ("a in 8000..9000", "(a in 8000..9000)"),
+            ("a not in -0x10 .. 010", "(a not in -16..8)"),
+            ("a in 1..1", "(a in 1..1)"),
+        ] {
+            assert_eq!(parse(atc).unwrap().to_string(), expected);
+        }
+
+        let err = parse("a in 9000..8000").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("range start is greater than its end"));
+        assert!(parse("a in 1..").is_err());
+    }
+
+    #[test]
+    fn test_cidr_list() {
+        for (atc, expected) in [
+            (
+                "a in (10.0.0.0/8, 192.168.0.0/16)",
+                "(a in (10.0.0.0/8, 192.168.0.0/16))",
+            ),
+            (
+                "a not in (10.0.0.1, fd00::/8,)",
+                "(a not in (10.0.0.1/32, fd00::/8))",
+            ),
+            ("a in (::1)", "(a in (::1/128))"),
+        ] {
+            let printed = parse(atc).unwrap().to_string();
+            assert_eq!(printed, expected);
+            assert_eq!(parse(&printed).unwrap().to_string(), expected);
+        }
+
+        assert!(parse("a in (10.0.0.0/8, \"b\")").is_err());
+        assert!(parse("a in (10.0.0.1/8)").is_err());
+    }
 }
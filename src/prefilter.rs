@@ -0,0 +1,1505 @@
+//! Literal prefix, suffix, equality and CIDR indexes used to narrow down the matchers that
+//! can possibly match a value before evaluating them, and the analyses
+//! telling what an expression requires from a context to match.
+
+use crate::ast::{BinaryOperator, Expression, LogicalExpression, Value};
+use crate::glob::regex_to_glob;
+use crate::regex_engine::{DefaultEngine, Regex};
+use cidr::IpCidr;
+use std::any::TypeId;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::IpAddr;
+
+#[derive(Debug)]
+struct Node<K> {
+    /// Children sorted by byte.
+    children: Vec<(u8, usize)>,
+    /// Keys whose prefix ends at this node.
+    keys: BTreeSet<K>,
+    /// Length of the prefix ending at this node.
+    depth: usize,
+}
+
+impl<K> Node<K> {
+    fn new(depth: usize) -> Self {
+        Node {
+            children: Vec::new(),
+            keys: BTreeSet::new(),
+            depth,
+        }
+    }
+}
+
+/// Maps keys to sets of literal prefixes and finds every key with a prefix
+/// of a given value.
+///
+/// A key may be inserted with any number of prefixes, and prefixes may be
+/// prefixes of each other, equal to each other or empty (matching every
+/// value). Overlaps are resolved when looking values up, so inserting never
+/// fails.
+#[derive(Debug)]
+pub struct InnerPrefilter<K> {
+    /// Byte trie, `nodes[0]` is the root.
+    nodes: Vec<Node<K>>,
+    /// Nodes each key was inserted at.
+    keys: BTreeMap<K, Vec<usize>>,
+    /// Sum of the depths of the nodes in `keys`, which bounds the number of
+    /// nodes still leading to a key.
+    depths: usize,
+}
+
+impl<K: Ord + Clone> Default for InnerPrefilter<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone> InnerPrefilter<K> {
+    pub fn new() -> Self {
+        InnerPrefilter {
+            nodes: vec![Node::new(0)],
+            keys: BTreeMap::new(),
+            depths: 0,
+        }
+    }
+
+    /// Number of keys in the index.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&byte, |(b, _)| *b)
+            .ok()
+            .map(|i| children[i].1)
+    }
+
+    fn node_for(&mut self, prefix: &[u8]) -> usize {
+        let mut node = 0;
+
+        for &byte in prefix {
+            node = match self.nodes[node]
+                .children
+                .binary_search_by_key(&byte, |(b, _)| *b)
+            {
+                Ok(i) => self.nodes[node].children[i].1,
+                Err(i) => {
+                    let child = self.nodes.len();
+                    let depth = self.nodes[node].depth + 1;
+                    self.nodes.push(Node::new(depth));
+                    self.nodes[node].children.insert(i, (byte, child));
+                    child
+                }
+            };
+        }
+
+        node
+    }
+
+    /// Adds `key`, which can only match values starting with one of
+    /// `prefixes`.
+    ///
+    /// Adding a key that is already present adds its new prefixes to the
+    /// existing ones. A key added without any prefix never matches.
+    pub fn insert<I, P>(&mut self, key: K, prefixes: I)
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<[u8]>,
+    {
+        let mut nodes = self.keys.remove(&key).unwrap_or_default();
+
+        for prefix in prefixes {
+            let node = self.node_for(prefix.as_ref());
+            if self.nodes[node].keys.insert(key.clone()) {
+                self.depths += self.nodes[node].depth;
+                nodes.push(node);
+            }
+        }
+
+        self.keys.insert(key, nodes);
+    }
+
+    /// Removes `key` and all of its prefixes.
+    ///
+    /// Emptied trie nodes are reused by later insertions of the same
+    /// prefixes, and dropped once they outnumber the nodes still in use, so
+    /// the trie stays proportional to the prefixes of the remaining keys.
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.keys.remove(key) {
+            Some(nodes) => {
+                for node in nodes {
+                    self.nodes[node].keys.remove(key);
+                    self.depths -= self.nodes[node].depth;
+                }
+                if self.nodes.len() > 2 * (self.depths + 1) {
+                    self.compact();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the nodes that no longer lead to any key.
+    ///
+    /// Nodes keep their relative order, so children still come after their
+    /// parent as [`Lookup`] expects.
+    fn compact(&mut self) {
+        let len = self.nodes.len();
+        // children come after their parent, so a backward pass sees every
+        // child before its parent
+        let mut live = vec![false; len];
+        for i in (0..len).rev() {
+            let node = &self.nodes[i];
+            live[i] = i == 0
+                || !node.keys.is_empty()
+                || node.children.iter().any(|&(_, child)| live[child]);
+        }
+
+        let mut ids = vec![usize::MAX; len];
+        let mut nodes = Vec::with_capacity(live.iter().filter(|&&l| l).count());
+        for (i, node) in std::mem::take(&mut self.nodes).into_iter().enumerate() {
+            if live[i] {
+                ids[i] = nodes.len();
+                nodes.push(node);
+            }
+        }
+
+        for node in &mut nodes {
+            node.children.retain(|&(_, child)| live[child]);
+            for (_, child) in &mut node.children {
+                *child = ids[*child];
+            }
+        }
+        for key_nodes in self.keys.values_mut() {
+            for node in key_nodes {
+                *node = ids[*node];
+            }
+        }
+
+        self.nodes = nodes;
+    }
+
+    /// Returns every key that has a prefix of `value`.
+    pub fn check(&self, value: &[u8]) -> BTreeSet<K> {
+        let mut keys = BTreeSet::new();
+        self.check_into(value, &mut keys);
+        keys
+    }
+
+    /// Starts a [`Lookup`], which tells the keys that have a prefix of the
+    /// values [added](Lookup::add) to it without collecting them.
+    pub fn lookup(&self) -> Lookup<'_, K> {
+        Lookup {
+            prefilter: self,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Like [`InnerPrefilter::check`], adding the keys to `keys`.
+    pub fn check_into(&self, value: &[u8], keys: &mut BTreeSet<K>) {
+        let mut node = 0;
+        keys.extend(self.nodes[node].keys.iter().cloned());
+
+        for &byte in value {
+            match self.child(node, byte) {
+                Some(child) => node = child,
+                None => return,
+            }
+            keys.extend(self.nodes[node].keys.iter().cloned());
+        }
+    }
+}
+
+/// The keys of an [`InnerPrefilter`] that have a prefix of some values,
+/// see [`InnerPrefilter::lookup`].
+///
+/// Only the trie nodes along the values are kept, at most one per byte, so
+/// checking a few keys costs the same however many keys share a prefix of
+/// the values, such as the empty one.
+#[derive(Debug, Clone)]
+pub struct Lookup<'p, K> {
+    prefilter: &'p InnerPrefilter<K>,
+    /// Sorted and without duplicates. Children are always created after
+    /// their parent, so the nodes along one value are already sorted.
+    nodes: Vec<usize>,
+}
+
+impl<'p, K: Ord + Clone> Lookup<'p, K> {
+    /// Adds the keys that have a prefix of `value`.
+    pub fn add(&mut self, value: &[u8]) {
+        let mut path = vec![0];
+        for &byte in value {
+            match self.prefilter.child(*path.last().unwrap(), byte) {
+                Some(child) => path.push(child),
+                None => break,
+            }
+        }
+
+        if self.nodes.is_empty() {
+            self.nodes = path;
+        } else {
+            self.nodes.extend(path);
+            self.nodes.sort_unstable();
+            self.nodes.dedup();
+        }
+    }
+
+    fn has_node(&self, node: usize) -> bool {
+        self.nodes.binary_search(&node).is_ok()
+    }
+
+    /// Whether `key` has a prefix of one of the values.
+    pub fn contains(&self, key: &K) -> bool {
+        self.prefilter
+            .keys
+            .get(key)
+            .is_some_and(|nodes| nodes.iter().any(|n| self.has_node(*n)))
+    }
+
+    /// Every key that has a prefix of one of the values, once each and in
+    /// no particular order. Keys are found as the iterator advances.
+    pub fn keys(&self) -> impl Iterator<Item = &'p K> + '_ {
+        self.nodes.iter().flat_map(move |&node| {
+            self.prefilter.nodes[node].keys.iter().filter(move |key| {
+                // only at the first node of the key along the values
+                self.prefilter.keys[*key]
+                    .iter()
+                    .all(|&n| n >= node || !self.has_node(n))
+            })
+        })
+    }
+}
+
+/// Maps keys to sets of CIDRs and finds every key with a CIDR containing a
+/// given address, whatever the number of keys.
+///
+/// A binary trie over the bits of the addresses: a CIDR is a path as long
+/// as its network length, and looking an address up walks at most 33 (IPv4)
+/// or 129 (IPv6) nodes, collecting the keys of every CIDR along the way.
+#[derive(Debug)]
+pub struct CidrIndex<K> {
+    /// Keyed by the family of the address, then one byte per bit.
+    inner: InnerPrefilter<K>,
+}
+
+impl<K: Ord + Clone> Default for CidrIndex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone> CidrIndex<K> {
+    pub fn new() -> Self {
+        CidrIndex {
+            inner: InnerPrefilter::new(),
+        }
+    }
+
+    /// Number of keys in the index.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.inner.contains(key)
+    }
+
+    /// Adds `key` with `cidrs`, like [`InnerPrefilter::insert`].
+    pub fn insert<'c, I>(&mut self, key: K, cidrs: I)
+    where
+        I: IntoIterator<Item = &'c IpCidr>,
+    {
+        let paths = cidrs.into_iter().map(|c| {
+            let (addr, len) = match c {
+                IpCidr::V4(c) => (IpAddr::V4(c.first_address()), c.network_length()),
+                IpCidr::V6(c) => (IpAddr::V6(c.first_address()), c.network_length()),
+            };
+            path(addr, len)
+        });
+        self.inner.insert(key, paths);
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        self.inner.remove(key)
+    }
+
+    /// Returns every key that has a CIDR containing `addr`.
+    pub fn check(&self, addr: IpAddr) -> BTreeSet<K> {
+        let mut keys = BTreeSet::new();
+        self.check_into(addr, &mut keys);
+        keys
+    }
+
+    /// Like [`CidrIndex::check`], adding the keys to `keys`.
+    pub fn check_into(&self, addr: IpAddr, keys: &mut BTreeSet<K>) {
+        self.inner.check_into(&full_path(addr), keys);
+    }
+
+    /// Starts a [`CidrLookup`], like [`InnerPrefilter::lookup`].
+    pub fn lookup(&self) -> CidrLookup<'_, K> {
+        CidrLookup {
+            inner: self.inner.lookup(),
+        }
+    }
+}
+
+/// The keys of a [`CidrIndex`] that have a CIDR containing some addresses,
+/// see [`CidrIndex::lookup`] and [`Lookup`].
+#[derive(Debug, Clone)]
+pub struct CidrLookup<'p, K> {
+    inner: Lookup<'p, K>,
+}
+
+impl<'p, K: Ord + Clone> CidrLookup<'p, K> {
+    /// Adds the keys that have a CIDR containing `addr`.
+    pub fn add(&mut self, addr: IpAddr) {
+        self.inner.add(&full_path(addr));
+    }
+
+    /// Whether `key` has a CIDR containing one of the addresses.
+    pub fn contains(&self, key: &K) -> bool {
+        self.inner.contains(key)
+    }
+
+    /// Like [`Lookup::keys`].
+    pub fn keys(&self) -> impl Iterator<Item = &'p K> + '_ {
+        self.inner.keys()
+    }
+}
+
+fn full_path(addr: IpAddr) -> Vec<u8> {
+    path(addr, if addr.is_ipv4() { 32 } else { 128 })
+}
+
+/// Maps keys to sets of values and finds every key with a given value in a
+/// single hash lookup, whatever the number of keys.
+#[derive(Debug)]
+pub struct EqualityIndex<K> {
+    values: HashMap<String, BTreeSet<K>>,
+    /// Values each key was inserted with.
+    keys: BTreeMap<K, Vec<String>>,
+}
+
+impl<K: Ord + Clone> Default for EqualityIndex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + Clone> EqualityIndex<K> {
+    pub fn new() -> Self {
+        EqualityIndex {
+            values: HashMap::new(),
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// Number of keys in the index.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// Adds `key`, which can only match one of `values`, like
+    /// [`InnerPrefilter::insert`].
+    pub fn insert<I>(&mut self, key: K, values: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut inserted = self.keys.remove(&key).unwrap_or_default();
+
+        for value in values {
+            if self
+                .values
+                .entry(value.clone())
+                .or_default()
+                .insert(key.clone())
+            {
+                inserted.push(value);
+            }
+        }
+
+        self.keys.insert(key, inserted);
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool {
+        let Some(values) = self.keys.remove(key) else {
+            return false;
+        };
+
+        for value in values {
+            if let Some(keys) = self.values.get_mut(&value) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.values.remove(&value);
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Returns every key with `value`.
+    pub fn check(&self, value: &str) -> BTreeSet<K> {
+        self.values.get(value).cloned().unwrap_or_default()
+    }
+
+    /// Starts an [`EqualityLookup`], like [`InnerPrefilter::lookup`].
+    pub fn lookup(&self) -> EqualityLookup<'_, K> {
+        EqualityLookup {
+            index: self,
+            found: Vec::new(),
+        }
+    }
+}
+
+/// The keys of an [`EqualityIndex`] with one of some values, see
+/// [`EqualityIndex::lookup`] and [`Lookup`].
+#[derive(Debug, Clone)]
+pub struct EqualityLookup<'p, K> {
+    index: &'p EqualityIndex<K>,
+    /// The keys of each value added that has any.
+    found: Vec<&'p BTreeSet<K>>,
+}
+
+impl<'p, K: Ord + Clone> EqualityLookup<'p, K> {
+    /// Adds the keys with `value`.
+    pub fn add(&mut self, value: &str) {
+        if let Some(keys) = self.index.values.get(value) {
+            self.found.push(keys);
+        }
+    }
+
+    /// Whether `key` has one of the values.
+    pub fn contains(&self, key: &K) -> bool {
+        self.found.iter().any(|keys| keys.contains(key))
+    }
+
+    /// Like [`Lookup::keys`].
+    pub fn keys(&self) -> impl Iterator<Item = &'p K> + '_ {
+        self.found.iter().enumerate().flat_map(move |(i, keys)| {
+            keys.iter()
+                .filter(move |key| !self.found[..i].iter().any(|k| k.contains(*key)))
+        })
+    }
+}
+
+/// The family of `addr` followed by its first `len` bits, one byte each.
+fn path(addr: IpAddr, len: u8) -> Vec<u8> {
+    let (family, bits, width) = match addr {
+        IpAddr::V4(a) => (4, u128::from(u32::from(a)), 32),
+        IpAddr::V6(a) => (6, u128::from(a), 128),
+    };
+
+    let mut path = Vec::with_capacity(len as usize + 1);
+    path.push(family);
+    path.extend((0..len).map(|i| (bits >> (width - 1 - i)) as u8 & 1));
+    path
+}
+
+/// Returns CIDRs such that `expr` can only match when some address of
+/// `field` is in one of them, or `None` when no such set is known.
+///
+/// CIDRs come from `in` predicates against a CIDR or a list of CIDRs and
+/// from `==` predicates against an address. `And`, `Or` and `Not` combine
+/// like in [`literal_prefixes`].
+pub fn required_cidrs(expr: &Expression, field: &str) -> Option<Vec<IpCidr>> {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => {
+                required_cidrs(l, field).or_else(|| required_cidrs(r, field))
+            }
+            LogicalExpression::Or(l, r) => {
+                let mut cidrs = required_cidrs(l, field)?;
+                cidrs.extend(required_cidrs(r, field)?);
+                Some(cidrs)
+            }
+            LogicalExpression::Not(_) => None,
+        },
+        Expression::FieldComparison(_) | Expression::Bool(true) => None,
+        // never matches, whatever the value
+        Expression::Bool(false) => Some(Vec::new()),
+        // with or without `any()`, some value must be in the CIDRs
+        Expression::Predicate(p) if p.lhs.var_name == field && p.lhs.key.is_none() => {
+            match (&p.op, &p.rhs) {
+                (BinaryOperator::In, Value::IpCidr(c)) => Some(vec![*c]),
+                (BinaryOperator::In, Value::CidrList(l)) => Some(l.cidrs().to_vec()),
+                (BinaryOperator::Equals, Value::IpAddr(a)) => Some(vec![IpCidr::new_host(*a)]),
+                _ => None,
+            }
+        }
+        Expression::Predicate(_) => None,
+    }
+}
+
+/// Returns CIDRs such that `expr` can not match when any address of
+/// `field` is in one of them.
+///
+/// CIDRs come from `not in` predicates against a CIDR or a list of CIDRs
+/// and from `!=` predicates against an address, without `any()`. `And`
+/// takes the CIDRs of both sides, `Or` and `Not` have none.
+pub fn excluded_cidrs(expr: &Expression, field: &str) -> Vec<IpCidr> {
+    let mut out = Vec::new();
+    collect_excluded_cidrs(expr, field, &mut out);
+    out
+}
+
+fn collect_excluded_cidrs(expr: &Expression, field: &str, out: &mut Vec<IpCidr>) {
+    match expr {
+        Expression::Logical(l) => {
+            if let LogicalExpression::And(l, r) = l.as_ref() {
+                collect_excluded_cidrs(l, field, out);
+                collect_excluded_cidrs(r, field, out);
+            }
+        }
+        Expression::FieldComparison(_) | Expression::Bool(_) => {}
+        Expression::Predicate(p) => {
+            if p.lhs.var_name != field || !p.lhs.is_plain() {
+                return;
+            }
+
+            match (&p.op, &p.rhs) {
+                (BinaryOperator::NotIn, Value::IpCidr(c)) => out.push(*c),
+                (BinaryOperator::NotIn, Value::CidrList(l)) => out.extend(l.cidrs()),
+                (BinaryOperator::NotEquals, Value::IpAddr(a)) => out.push(IpCidr::new_host(*a)),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns literal prefixes such that `expr` can only match when some value
+/// of `field` starts with one of them, or `None` when no such set is known.
+///
+/// Prefixes come from `==`, `^=` and `in` predicates on the untransformed
+/// field and from regexes anchored with `^` that begin with literal text,
+/// which includes globs starting with literal text. Only patterns in the
+/// `regex` crate's syntax are looked into, as other engines may read them
+/// differently. `And` takes the prefixes of either side, `Or` needs prefixes
+/// on both sides and `Not` never has any.
+pub fn literal_prefixes(expr: &Expression, field: &str) -> Option<Vec<String>> {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => {
+                literal_prefixes(l, field).or_else(|| literal_prefixes(r, field))
+            }
+            LogicalExpression::Or(l, r) => {
+                let mut prefixes = literal_prefixes(l, field)?;
+                prefixes.extend(literal_prefixes(r, field)?);
+                Some(prefixes)
+            }
+            LogicalExpression::Not(_) => None,
+        },
+        Expression::FieldComparison(_) | Expression::Bool(true) => None,
+        // never matches, whatever the value
+        Expression::Bool(false) => Some(Vec::new()),
+        Expression::Predicate(p) => {
+            if p.lhs.var_name != field || !p.lhs.is_plain() {
+                return None;
+            }
+
+            let rhs = match &p.rhs {
+                Value::Methods(m) => m.original(),
+                rhs => rhs,
+            };
+            match (&p.op, rhs) {
+                (BinaryOperator::Equals | BinaryOperator::Prefix, Value::String(s)) => {
+                    Some(vec![s.to_string()])
+                }
+                (BinaryOperator::In, Value::List(l)) => Some(l.clone()),
+                (BinaryOperator::Equals, Value::Set(set)) => Some(set.values().to_vec()),
+                (BinaryOperator::Regex | BinaryOperator::Glob, Value::Regex(re)) => {
+                    regex_prefix(regex_crate_pattern(re)?).map(|p| vec![p])
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Returns values such that `expr` can only match when some value of
+/// `field` is one of them, or `None` when no such set is known.
+///
+/// Values come from `==` and `in` predicates on the untransformed field.
+/// `And`, `Or` and `Not` combine like in [`literal_prefixes`].
+pub fn literal_values(expr: &Expression, field: &str) -> Option<Vec<String>> {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => {
+                literal_values(l, field).or_else(|| literal_values(r, field))
+            }
+            LogicalExpression::Or(l, r) => {
+                let mut values = literal_values(l, field)?;
+                values.extend(literal_values(r, field)?);
+                Some(values)
+            }
+            LogicalExpression::Not(_) => None,
+        },
+        Expression::FieldComparison(_) | Expression::Bool(true) => None,
+        // never matches, whatever the value
+        Expression::Bool(false) => Some(Vec::new()),
+        Expression::Predicate(p) => {
+            if p.lhs.var_name != field || !p.lhs.is_plain() {
+                return None;
+            }
+
+            let rhs = match &p.rhs {
+                Value::Methods(m) => m.original(),
+                rhs => rhs,
+            };
+            match (&p.op, rhs) {
+                (BinaryOperator::Equals, Value::String(s)) => Some(vec![s.to_string()]),
+                (BinaryOperator::In, Value::List(l)) => Some(l.clone()),
+                (BinaryOperator::Equals, Value::Set(set)) => Some(set.values().to_vec()),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Returns literal suffixes such that `expr` can only match when some value
+/// of `field` ends with one of them, or `None` when no such set is known.
+///
+/// Suffixes come from `==`, `=^` and `in` predicates on the untransformed
+/// field, from regexes anchored with `$` that end with literal text, such
+/// as `\.example\.com$`, and from globs ending with literal text, such as
+/// `*.example.com`, as long as they are in the `regex` crate's syntax. `And`,
+/// `Or` and `Not` combine like in [`literal_prefixes`].
+pub fn literal_suffixes(expr: &Expression, field: &str) -> Option<Vec<String>> {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) => {
+                literal_suffixes(l, field).or_else(|| literal_suffixes(r, field))
+            }
+            LogicalExpression::Or(l, r) => {
+                let mut suffixes = literal_suffixes(l, field)?;
+                suffixes.extend(literal_suffixes(r, field)?);
+                Some(suffixes)
+            }
+            LogicalExpression::Not(_) => None,
+        },
+        Expression::FieldComparison(_) | Expression::Bool(true) => None,
+        // never matches, whatever the value
+        Expression::Bool(false) => Some(Vec::new()),
+        Expression::Predicate(p) => {
+            if p.lhs.var_name != field || !p.lhs.is_plain() {
+                return None;
+            }
+
+            let rhs = match &p.rhs {
+                Value::Methods(m) => m.original(),
+                rhs => rhs,
+            };
+            match (&p.op, rhs) {
+                (BinaryOperator::Equals | BinaryOperator::Postfix, Value::String(s)) => {
+                    Some(vec![s.to_string()])
+                }
+                (BinaryOperator::In, Value::List(l)) => Some(l.clone()),
+                (BinaryOperator::Equals, Value::Set(set)) => Some(set.values().to_vec()),
+                (BinaryOperator::Regex, Value::Regex(re)) => {
+                    regex_suffix(regex_crate_pattern(re)?).map(|s| vec![s])
+                }
+                (BinaryOperator::Glob, Value::Regex(re)) => {
+                    regex_to_glob(regex_crate_pattern(re)?).map(|g| vec![glob_suffix(&g)])
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Pattern of `re` if it has the `regex` crate's syntax, like the regexes
+/// [`Router::enable_regex_index`](crate::router::Router::enable_regex_index)
+/// indexes. `regex-lite` patterns are a subset of that syntax.
+fn regex_crate_pattern(re: &Regex) -> Option<&str> {
+    let regex_syntax =
+        re.as_regex_crate().is_some() || re.engine() == TypeId::of::<DefaultEngine>();
+    regex_syntax.then(|| re.as_str())
+}
+
+/// Literal text every value matching `glob` ends with.
+fn glob_suffix(glob: &str) -> String {
+    let mut suffix = String::new();
+    let mut chars = glob.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' => suffix.clear(),
+            '\\' => suffix.push(chars.next().unwrap_or('\\')),
+            c => suffix.push(c),
+        }
+    }
+
+    suffix
+}
+
+/// Returns `(field, regex)` pairs such that `expr` can only match when some
+/// value of `field` matches `regex`, for every pair.
+///
+/// Pairs come from `~` and `glob` predicates without `lower()`. `And` takes the pairs
+/// of both sides, `Or` and `Not` have none.
+pub fn required_regexes(expr: &Expression) -> Vec<(&str, &Regex)> {
+    let mut out = Vec::new();
+    collect_required_regexes(expr, &mut out);
+    out
+}
+
+fn collect_required_regexes<'e>(expr: &'e Expression, out: &mut Vec<(&'e str, &'e Regex)>) {
+    match expr {
+        Expression::Logical(l) => {
+            if let LogicalExpression::And(l, r) = l.as_ref() {
+                collect_required_regexes(l, out);
+                collect_required_regexes(r, out);
+            }
+        }
+        Expression::FieldComparison(_) | Expression::Bool(_) => {}
+        Expression::Predicate(p) => {
+            if let (BinaryOperator::Regex | BinaryOperator::Glob, Value::Regex(re), false, None) =
+                (&p.op, &p.rhs, p.lhs.is_transformed(), &p.lhs.key)
+            {
+                out.push((&p.lhs.var_name, re));
+            }
+        }
+    }
+}
+
+/// Literal text every match of `pattern` starts with, if it is anchored at
+/// the start of the haystack.
+pub(crate) fn regex_prefix(pattern: &str) -> Option<String> {
+    let rest = pattern.strip_prefix('^')?;
+    if has_top_level_alternation(rest) {
+        return None;
+    }
+
+    let mut prefix = String::new();
+    let mut chars = rest.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next() {
+                Some(e) if e.is_ascii_punctuation() => e,
+                // classes such as `\d` or assertions such as `\b`
+                _ => break,
+            },
+            '.' | '[' | ']' | '{' | '}' | '(' | ')' | '*' | '+' | '?' | '|' | '^' | '$' => break,
+            c => c,
+        };
+
+        match chars.peek() {
+            // the literal is optional or repeated, stop before it
+            Some('?' | '*' | '{') => break,
+            // the literal appears at least once
+            Some('+') => {
+                prefix.push(literal);
+                break;
+            }
+            _ => prefix.push(literal),
+        }
+    }
+
+    Some(prefix)
+}
+
+/// Literal text every match of `pattern` ends with, if it is anchored at
+/// the end of the haystack.
+pub(crate) fn regex_suffix(pattern: &str) -> Option<String> {
+    let rest = pattern.strip_suffix('$')?;
+    // `\$` is a literal dollar, `\\$` an escaped backslash and the anchor
+    let backslashes = rest.chars().rev().take_while(|c| *c == '\\').count();
+    if backslashes % 2 == 1 || has_top_level_alternation(rest) {
+        return None;
+    }
+
+    let mut suffix = String::new();
+    let mut chars = rest.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let literal = match c {
+            '\\' => match chars.next()? {
+                e if e.is_ascii_punctuation() => Some(e),
+                // escapes taking arguments, such as `\x41` or `\p{L}`
+                'x' | 'u' | 'U' | 'p' | 'P' => return None,
+                // classes such as `\d` or assertions such as `\b`
+                _ => None,
+            },
+            // flags such as `(?m)`, which lets `$` match before a newline,
+            // or `(?i)`
+            '(' if chars.peek() == Some(&'?') => {
+                chars.next();
+                match chars.peek() {
+                    Some(':' | '<' | 'P') => None,
+                    _ => return None,
+                }
+            }
+            '[' => {
+                skip_class(&mut chars);
+                None
+            }
+            '.' | '(' | ')' | '^' | '$' | '|' => None,
+            '*' | '?' | '{' => {
+                // what precedes may be missing or repeated
+                if c == '{' {
+                    chars.find(|c| *c == '}');
+                }
+                suffix.clear();
+                continue;
+            }
+            '+' => {
+                // only the last character is certain to end the match
+                let last = suffix.pop();
+                suffix.clear();
+                suffix.extend(last);
+                continue;
+            }
+            c => Some(c),
+        };
+
+        match literal {
+            Some(c) => suffix.push(c),
+            None => suffix.clear(),
+        }
+    }
+
+    Some(suffix)
+}
+
+/// The string operator and literal matching the same values as `pattern`,
+/// if it is nothing but literal text between optional `^` and `$` anchors:
+/// `^/a$` is `== "/a"`, `^/a` is `^= "/a"`, `/a$` is `=^ "/a"` and `/a` is
+/// `contains "/a"`. A `.*` on an unanchored side matches nothing the
+/// literal does not, so `^/a/.*` is `^= "/a/"` too.
+pub(crate) fn regex_literal(pattern: &str) -> Option<(BinaryOperator, String)> {
+    let (start, rest) = match pattern.strip_prefix('^') {
+        Some(rest) => (true, rest),
+        None => (false, pattern.strip_prefix(".*").unwrap_or(pattern)),
+    };
+    // `\$` is a literal dollar, `\\$` an escaped backslash and the anchor
+    let backslashes = |s: &str| s.chars().rev().take_while(|c| *c == '\\').count();
+    let (end, rest) = match rest.strip_suffix('$') {
+        Some(r) if backslashes(r) % 2 == 0 => (true, r),
+        _ => match rest.strip_suffix(".*") {
+            Some(r) if backslashes(r) % 2 == 0 => (false, r),
+            _ => (false, rest),
+        },
+    };
+
+    let mut literal = String::with_capacity(rest.len());
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                e if e.is_ascii_punctuation() => literal.push(e),
+                _ => return None,
+            },
+            '.' | '[' | ']' | '{' | '}' | '(' | ')' | '*' | '+' | '?' | '|' | '^' | '$' => {
+                return None
+            }
+            c => literal.push(c),
+        }
+    }
+
+    let op = match (start, end) {
+        (true, true) => BinaryOperator::Equals,
+        (true, false) => BinaryOperator::Prefix,
+        (false, true) => BinaryOperator::Postfix,
+        (false, false) => BinaryOperator::Contains,
+    };
+    Some((op, literal))
+}
+
+/// Skips the rest of a character class whose `[` was just read, nested
+/// classes included.
+fn skip_class(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    let mut depth = 1usize;
+    chars.next_if_eq(&'^');
+    // a `]` right after the opening bracket is literal
+    chars.next_if_eq(&']');
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => {
+                depth += 1;
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+            }
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `pattern` has a `|` outside of any group or class, which would
+/// leave all but its first branch unanchored.
+fn has_top_level_alternation(pattern: &str) -> bool {
+    let mut depth = 0usize;
+    let mut in_class = false;
+    let mut chars = pattern.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class => depth += 1,
+            ')' if !in_class => depth = depth.saturating_sub(1),
+            '|' if !in_class && depth == 0 => return true,
+            _ => {}
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::regex_engine::{CompiledRegex, RegexEngine};
+    use std::ops::Range;
+
+    fn check(p: &InnerPrefilter<u32>, value: &str) -> Vec<u32> {
+        p.check(value.as_bytes()).into_iter().collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let mut p = InnerPrefilter::new();
+        p.insert(1, ["/foo"]);
+        p.insert(2, ["/bar", "/baz"]);
+        p.insert(3, ["/"]);
+        assert_eq!(p.len(), 3);
+
+        assert_eq!(check(&p, "/foo/bar"), [1, 3]);
+        assert_eq!(check(&p, "/baz"), [2, 3]);
+        assert_eq!(check(&p, "/ba"), [3]);
+        assert_eq!(check(&p, "nope"), [0u32; 0]);
+        assert_eq!(check(&p, ""), [0u32; 0]);
+    }
+
+    // inputs that violate the "no prefix is a prefix of another" rule the
+    // index used to require
+    #[test]
+    fn test_overlapping_prefixes() {
+        let mut p = InnerPrefilter::new();
+        // nested prefixes of one key
+        p.insert(1, ["/a", "/a/b", "/a/b/c"]);
+        // duplicate prefixes
+        p.insert(2, ["/a/b", "/a/b"]);
+        // empty prefix matches everything
+        p.insert(3, [""]);
+        // inserting an existing key again merges its prefixes
+        p.insert(4, ["/x"]);
+        p.insert(4, ["/a/b/c/d"]);
+        assert_eq!(p.len(), 4);
+
+        assert_eq!(check(&p, "/a/b/c/d/e"), [1, 2, 3, 4]);
+        assert_eq!(check(&p, "/a/b"), [1, 2, 3]);
+        assert_eq!(check(&p, "/x"), [3, 4]);
+        assert_eq!(check(&p, ""), [3]);
+
+        assert!(p.remove(&1));
+        assert!(!p.remove(&1));
+        assert!(!p.contains(&1));
+        assert_eq!(p.len(), 3);
+        assert_eq!(check(&p, "/a/b/c/d/e"), [2, 3, 4]);
+
+        // nodes left behind by removals are reused
+        let nodes = p.nodes.len();
+        p.insert(1, ["/a/b/c"]);
+        assert_eq!(p.nodes.len(), nodes);
+        assert_eq!(check(&p, "/a/b/c"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut p = InnerPrefilter::new();
+        p.insert(1, ["/a", "/a/b", "/a/b/c"]);
+        p.insert(2, ["/a/b", "/x"]);
+        p.insert(3, [""]);
+        p.insert(4, ["/x/y"]);
+
+        let keys = |lookup: &Lookup<u32>| {
+            let mut keys: Vec<u32> = lookup.keys().cloned().collect();
+            keys.sort();
+            keys
+        };
+
+        let mut lookup = p.lookup();
+        assert_eq!(keys(&lookup), [0u32; 0]);
+        assert!(!lookup.contains(&3));
+
+        lookup.add(b"/a/b/c/d");
+        // once each, however many of their prefixes the value has
+        assert_eq!(keys(&lookup), [1, 2, 3]);
+        for (key, expected) in [(1, true), (2, true), (3, true), (4, false), (5, false)] {
+            assert_eq!(lookup.contains(&key), expected, "{}", key);
+        }
+
+        lookup.add(b"/x/y");
+        assert_eq!(keys(&lookup), [1, 2, 3, 4]);
+        assert!(lookup.contains(&4));
+
+        for value in ["/a/b/c", "/a", "/x", "", "nope"] {
+            let mut lookup = p.lookup();
+            lookup.add(value.as_bytes());
+            assert_eq!(keys(&lookup), check(&p, value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_churn() {
+        let mut p = InnerPrefilter::new();
+        p.insert(0, ["/stable/a", "/stable/b"]);
+
+        let mut max_nodes = 0;
+        for i in 1..2000u32 {
+            p.insert(i, [format!("/churn/{}/{}", i, i * 7919)]);
+            if i > 10 {
+                assert!(p.remove(&(i - 10)));
+            }
+            max_nodes = max_nodes.max(p.nodes.len());
+        }
+        // ten live keys of at most 20 bytes each, plus the stable one
+        assert!(max_nodes < 2 * (10 * 20 + 20), "{}", max_nodes);
+
+        assert_eq!(check(&p, "/stable/a/x"), [0]);
+        assert_eq!(check(&p, "/churn/1999/15830081"), [1999]);
+        assert_eq!(check(&p, "/churn/1/7919"), [0u32; 0]);
+        let mut lookup = p.lookup();
+        lookup.add(b"/churn/1995/15798405");
+        lookup.add(b"/stable/b");
+        assert_eq!(
+            lookup.keys().cloned().collect::<BTreeSet<_>>(),
+            [0, 1995].into()
+        );
+
+        for i in 1990..2000 {
+            assert!(p.remove(&i));
+        }
+        assert!(p.remove(&0));
+        assert_eq!(p.nodes.len(), 1);
+        assert_eq!(p.depths, 0);
+    }
+
+    #[test]
+    fn test_non_ascii() {
+        let mut p = InnerPrefilter::new();
+        p.insert(1, ["/ä"]);
+        p.insert(2, ["/\u{e4}x"]);
+
+        assert_eq!(check(&p, "/äx"), [1, 2]);
+        // shares the first byte of `ä`
+        assert_eq!(check(&p, "/ã"), [0u32; 0]);
+    }
+
+    fn prefixes(atc: &str) -> Option<Vec<String>> {
+        literal_prefixes(&crate::parser::parse(atc).unwrap(), "http.path")
+    }
+
+    #[test]
+    fn test_literal_prefixes() {
+        assert_eq!(
+            prefixes(r#"http.path ^= "/a" && http.method == "GET""#).unwrap(),
+            ["/a"]
+        );
+        assert_eq!(
+            prefixes(r#"http.method == "GET" && http.path == "/a/b""#).unwrap(),
+            ["/a/b"]
+        );
+        assert_eq!(
+            prefixes(r#"http.path ^= "/a" || http.path in ("/b", "/c")"#).unwrap(),
+            ["/a", "/b", "/c"]
+        );
+
+        assert_eq!(prefixes(r#"http.path ^= "/a" || http.host == "x""#), None);
+        assert_eq!(prefixes(r#"!(http.path ^= "/a")"#), None);
+        assert_eq!(prefixes(r#"lower(http.path) ^= "/a""#), None);
+        assert_eq!(prefixes(r#"http.path =^ "/a""#), None);
+        assert_eq!(prefixes(r#"http.path != "/a""#), None);
+    }
+
+    #[test]
+    fn test_equality_index() {
+        let mut index = EqualityIndex::new();
+        index.insert(1, ["a.com".to_string()]);
+        index.insert(2, ["a.com".to_string(), "b.com".to_string()]);
+        index.insert(3, []);
+        // inserting an existing key again merges its values
+        index.insert(3, ["c.com".to_string(), "c.com".to_string()]);
+        assert_eq!(index.len(), 3);
+
+        assert_eq!(index.check("a.com").into_iter().collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(index.check("c.com").into_iter().collect::<Vec<_>>(), [3]);
+        assert!(index.check("d.com").is_empty());
+
+        let mut lookup = index.lookup();
+        lookup.add("a.com");
+        lookup.add("b.com");
+        lookup.add("d.com");
+        let mut keys: Vec<u32> = lookup.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [1, 2]);
+        assert!(lookup.contains(&2));
+        assert!(!lookup.contains(&3));
+
+        assert!(index.remove(&2));
+        assert!(!index.remove(&2));
+        assert!(!index.contains(&2));
+        assert!(!index.values.contains_key("b.com"));
+        assert_eq!(index.check("a.com").into_iter().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn test_literal_values() {
+        let values = |atc: &str| literal_values(&crate::parser::parse(atc).unwrap(), "http.host");
+
+        assert_eq!(
+            values(r#"http.host == "a.com" && http.path ^= "/""#).unwrap(),
+            ["a.com"]
+        );
+        assert_eq!(
+            values(r#"http.host == "a.com" || http.host in ("b.com", "c.com")"#).unwrap(),
+            ["a.com", "b.com", "c.com"]
+        );
+        assert_eq!(values(r#"http.host =^ ".com""#), None);
+        assert_eq!(values(r#"lower(http.host) == "a.com""#), None);
+        assert_eq!(values(r#"http.host == "a.com" || http.path == "/""#), None);
+        assert_eq!(values(r#"!(http.host == "a.com")"#), None);
+    }
+
+    fn suffixes(atc: &str) -> Option<Vec<String>> {
+        literal_suffixes(&crate::parser::parse(atc).unwrap(), "http.host")
+    }
+
+    #[test]
+    fn test_literal_suffixes() {
+        assert_eq!(
+            suffixes(r#"http.host =^ ".example.com""#).unwrap(),
+            [".example.com"]
+        );
+        assert_eq!(
+            suffixes(r#"http.host == "a.com" || http.host in ("b.com", "c.com")"#).unwrap(),
+            ["a.com", "b.com", "c.com"]
+        );
+        assert_eq!(
+            suffixes(r#"http.host glob "*.example.com" && http.path ^= "/""#).unwrap(),
+            [".example.com"]
+        );
+        assert_eq!(suffixes(r#"http.host glob "api.*.com""#).unwrap(), [".com"]);
+        // escaped wildcards are literal
+        assert_eq!(suffixes(r#"http.host glob "*.a\\*b""#).unwrap(), [".a*b"]);
+        assert_eq!(
+            suffixes(r##"http.host ~ r#"^(?<sub>[a-z]+)\.example\.com$"#"##).unwrap(),
+            [".example.com"]
+        );
+        assert_eq!(suffixes(r#"http.host glob "api.*""#).unwrap(), [""]);
+
+        assert_eq!(suffixes(r#"http.host ^= "api.""#), None);
+        assert_eq!(suffixes(r#"lower(http.host) =^ ".com""#), None);
+        assert_eq!(suffixes(r#"http.host =^ ".com" || http.path == "/""#), None);
+        assert_eq!(suffixes(r#"!(http.host =^ ".com")"#), None);
+    }
+
+    /// The `regex` crate behind an engine that does not say so.
+    struct OpaqueEngine;
+
+    struct OpaqueRegex(regex::Regex);
+
+    impl RegexEngine for OpaqueEngine {
+        fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String> {
+            let re = regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+            Ok(Box::new(OpaqueRegex(re)))
+        }
+    }
+
+    impl CompiledRegex for OpaqueRegex {
+        fn find(&self, haystack: &str) -> Option<Range<usize>> {
+            self.0.find(haystack).map(|m| m.range())
+        }
+
+        fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+            self.0
+                .captures(haystack)
+                .map(|c| c.iter().map(|g| g.map(|g| g.range())).collect())
+        }
+
+        fn capture_names(&self) -> Vec<Option<String>> {
+            self.0
+                .capture_names()
+                .map(|n| n.map(str::to_string))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_literal_affixes_other_engines() {
+        let parse = |atc: &str| crate::parser::parse_with_engine(atc, &OpaqueEngine).unwrap();
+
+        // the pattern may mean something else to the engine
+        assert_eq!(
+            literal_prefixes(&parse(r#"http.path ~ "^/a""#), "http.path"),
+            None
+        );
+        assert_eq!(
+            literal_prefixes(&parse(r#"http.path glob "/a*""#), "http.path"),
+            None
+        );
+        assert_eq!(
+            literal_suffixes(&parse(r#"http.host ~ "[.]com$""#), "http.host"),
+            None
+        );
+        assert_eq!(
+            literal_suffixes(&parse(r#"http.host glob "*.com""#), "http.host"),
+            None
+        );
+        // other predicates are still looked into
+        assert_eq!(
+            literal_prefixes(
+                &parse(r#"http.path ~ "^/a" && http.path ^= "/b""#),
+                "http.path"
+            ),
+            Some(vec!["/b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_required_regexes() {
+        let regexes = |atc: &str| {
+            let expr = crate::parser::parse(atc).unwrap();
+            required_regexes(&expr)
+                .iter()
+                .map(|(f, re)| format!("{} {}", f, re))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            regexes(r#"http.path ~ "^/a" && (any(http.host) ~ "b" && http.method == "GET")"#),
+            ["http.path ^/a", "http.host b"]
+        );
+        assert_eq!(
+            regexes(r#"http.path ~ "^/a" || http.path ~ "^/b""#),
+            [""; 0]
+        );
+        assert_eq!(regexes(r#"!(http.path ~ "^/a")"#), [""; 0]);
+        assert_eq!(regexes(r#"lower(http.path) ~ "^/a""#), [""; 0]);
+    }
+
+    #[test]
+    fn test_cidr_index() {
+        let cidr = |c: &str| c.parse::<IpCidr>().unwrap();
+        let check = |index: &CidrIndex<u32>, addr: &str| {
+            index
+                .check(addr.parse().unwrap())
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        let mut index = CidrIndex::new();
+        index.insert(1, &[cidr("10.0.0.0/8"), cidr("192.168.0.0/16")]);
+        index.insert(2, &[cidr("10.1.0.0/16")]);
+        index.insert(3, &[cidr("0.0.0.0/0")]);
+        index.insert(4, &[cidr("10.1.2.3/32"), cidr("fd00::/8")]);
+        index.insert(5, &[cidr("::/0")]);
+        assert_eq!(index.len(), 5);
+
+        assert_eq!(check(&index, "10.1.2.3"), [1, 2, 3, 4]);
+        assert_eq!(check(&index, "10.1.2.4"), [1, 2, 3]);
+        assert_eq!(check(&index, "192.168.1.1"), [1, 3]);
+        assert_eq!(check(&index, "11.0.0.0"), [3]);
+        assert_eq!(check(&index, "fd00::1"), [4, 5]);
+        // families are never mixed
+        assert_eq!(check(&index, "::ffff:10.1.2.3"), [5]);
+
+        let mut lookup = index.lookup();
+        lookup.add("10.1.2.4".parse().unwrap());
+        lookup.add("fd00::1".parse().unwrap());
+        let mut keys: Vec<u32> = lookup.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, [1, 2, 3, 4, 5]);
+        assert!(lookup.contains(&4));
+
+        assert!(index.remove(&3));
+        assert!(!index.contains(&3));
+        assert_eq!(check(&index, "11.0.0.0"), [0u32; 0]);
+
+        // replacing /32s keeps the trie as small as the live CIDRs need
+        let mut index = CidrIndex::new();
+        let mut max_nodes = 0;
+        for i in 0..5000u32 {
+            let addr = std::net::Ipv4Addr::from(i.wrapping_mul(2654435761));
+            index.insert(i, &[IpCidr::new_host(addr.into())]);
+            if i >= 4 {
+                assert!(index.remove(&(i - 4)));
+            }
+            max_nodes = max_nodes.max(index.inner.nodes.len());
+        }
+        assert!(max_nodes < 2 * 4 * 33, "{}", max_nodes);
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_required_and_excluded_cidrs() {
+        let required = |atc: &str| {
+            required_cidrs(&crate::parser::parse(atc).unwrap(), "net.src.ip")
+                .map(|cidrs| cidrs.iter().map(|c| format!("{:#}", c)).collect::<Vec<_>>())
+        };
+        let excluded = |atc: &str| {
+            excluded_cidrs(&crate::parser::parse(atc).unwrap(), "net.src.ip")
+                .iter()
+                .map(|c| format!("{:#}", c))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            required("net.src.ip in (10.0.0.0/8, fd00::/8) && net.port == 80").unwrap(),
+            ["10.0.0.0/8", "fd00::/8"]
+        );
+        assert_eq!(
+            required("any(net.src.ip) in 10.0.0.0/8 || net.src.ip == 10.0.0.1").unwrap(),
+            ["10.0.0.0/8", "10.0.0.1/32"]
+        );
+        assert_eq!(required("net.src.ip in 10.0.0.0/8 || net.port == 80"), None);
+        assert_eq!(required("!(net.src.ip in 10.0.0.0/8)"), None);
+        assert_eq!(required("net.src.ip not in 10.0.0.0/8"), None);
+        assert_eq!(required("net.dst.ip in 10.0.0.0/8"), None);
+
+        assert_eq!(
+            excluded("net.src.ip not in (10.0.0.0/8,) && (net.port == 1 && net.src.ip != ::1)"),
+            ["10.0.0.0/8", "::1/128"]
+        );
+        assert_eq!(excluded("any(net.src.ip) not in 10.0.0.0/8"), [""; 0]);
+        assert_eq!(excluded("net.src.ip not in 10.0.0.0/8 || true"), [""; 0]);
+        assert_eq!(excluded("!(net.src.ip not in 10.0.0.0/8)"), [""; 0]);
+    }
+
+    #[test]
+    fn test_regex_prefix() {
+        assert_eq!(regex_prefix(r"^/users/(?<id>\d+)$").unwrap(), "/users/");
+        assert_eq!(regex_prefix(r"^/a\.b\d").unwrap(), "/a.b");
+        assert_eq!(regex_prefix(r"^/ab?c").unwrap(), "/a");
+        assert_eq!(regex_prefix(r"^/ab*").unwrap(), "/a");
+        assert_eq!(regex_prefix(r"^/ab{2}").unwrap(), "/a");
+        assert_eq!(regex_prefix(r"^/ab+").unwrap(), "/ab");
+        assert_eq!(regex_prefix(r"^/(a|b)").unwrap(), "/");
+        assert_eq!(regex_prefix(r"^/[|]x").unwrap(), "/");
+        assert_eq!(regex_prefix(r"^.*").unwrap(), "");
+
+        // not anchored, or only the first branch is
+        assert_eq!(regex_prefix(r"/a"), None);
+        assert_eq!(regex_prefix(r"(?i)^/a"), None);
+        assert_eq!(regex_prefix(r"^/a|/b"), None);
+        assert_eq!(regex_prefix(r"^/a\|b|c"), None);
+    }
+
+    #[test]
+    fn test_regex_suffix() {
+        for (pattern, suffix) in [
+            (r"\.example\.com$", ".example.com"),
+            (r"^(?<sub>[a-z]+)\.example\.com$", ".example.com"),
+            (r"^api\.(a|b)\.com$", ".com"),
+            (r"[$\]]x\$$", "x$"),
+            (r"[^]a-z]\.com$", ".com"),
+            (r"[[:alpha:]x]com$", "com"),
+            (r"ab?c$", "c"),
+            (r"ab*$", ""),
+            (r"ab{2,3}$", ""),
+            (r"ab+$", "b"),
+            (r"a\d\.b$", ".b"),
+            (r"\\$", "\\"),
+            (r"(?:\.com)$", ""),
+            (r".*$", ""),
+        ] {
+            assert_eq!(regex_suffix(pattern).unwrap(), suffix, "{}", pattern);
+
+            let re = regex::Regex::new(pattern).unwrap();
+            for haystack in [
+                "a.example.com",
+                "x.com",
+                "]com",
+                "$x$",
+                "abc",
+                "abb",
+                "a9.b",
+            ] {
+                if re.is_match(haystack) {
+                    assert!(haystack.ends_with(suffix), "{} {}", pattern, haystack);
+                }
+            }
+        }
+
+        // not anchored, or only the last branch is
+        assert_eq!(regex_suffix(r"\.com"), None);
+        assert_eq!(regex_suffix(r"\.com\$"), None);
+        assert_eq!(regex_suffix(r"a\.com|b\.com$"), None);
+        // flags change what literals and `$` match
+        assert_eq!(regex_suffix(r"(?m)\.com$"), None);
+        assert_eq!(regex_suffix(r"(?i:a)\.com$"), None);
+        assert_eq!(regex_suffix(r"\x41\.com$"), None);
+    }
+
+    #[test]
+    fn test_regex_literal() {
+        use BinaryOperator::*;
+
+        for (pattern, op, literal) in [
+            (r"^/api$", Equals, "/api"),
+            (r"^/api/v1\.0", Prefix, "/api/v1.0"),
+            (r"\.example\.com$", Postfix, ".example.com"),
+            (r"admin", Contains, "admin"),
+            (r"^$", Equals, ""),
+            (r"a\$", Contains, "a$"),
+            (r"a\\$", Postfix, "a\\"),
+            (r"^ä/ö$", Equals, "ä/ö"),
+            (r"^/api/v1/.*", Prefix, "/api/v1/"),
+            (r".*\.example\.com$", Postfix, ".example.com"),
+            (r".*admin.*", Contains, "admin"),
+            (r"^.*", Prefix, ""),
+        ] {
+            assert_eq!(
+                regex_literal(pattern),
+                Some((op, literal.to_string())),
+                "{}",
+                pattern
+            );
+        }
+
+        for pattern in [
+            r"^/a.b$", r"^/a+", r"(?i)/a", r"^/a|/b", r"\d", r"^/[a]", r"\x41",
+            // `.` does not match newlines
+            r"^/a.*$", r"^.*/a", r"/a\.*",
+        ] {
+            assert_eq!(regex_literal(pattern), None, "{}", pattern);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Process wide cache of compiled regexes, keyed by pattern.
+//!
+//! Deserializing an [`Expression`](crate::ast::Expression) compiles every
+//! regex it contains. Large routers tend to repeat the same patterns over and
+//! over, so deserialization goes through this cache and only compiles each
+//! distinct pattern once. [`Regex`] is reference counted internally, handing
+//! out clones of a cached entry is cheap. Patterns are compiled with
+//! [`DefaultEngine`](crate::regex_engine::DefaultEngine).
+//!
+//! The cache keeps at most [`capacity`] patterns and evicts the least
+//! recently used one beyond that, so a process deserializing routers whose
+//! patterns keep changing does not grow it without bound.
+
+use crate::regex_engine::Regex;
+use lazy_static::lazy_static;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Number of patterns cached until [`set_capacity`] is called.
+const DEFAULT_CAPACITY: usize = 4096;
+
+lazy_static! {
+    static ref CACHE: Mutex<RegexCache> = Mutex::new(RegexCache::new(
+        NonZeroUsize::new(DEFAULT_CAPACITY).unwrap()
+    ));
+}
+
+fn cache() -> MutexGuard<'static, RegexCache> {
+    // the lock is never held while anything can panic
+    CACHE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Compiled regexes by pattern, the least recently used evicted first.
+struct RegexCache(LruCache<Box<str>, Regex>);
+
+impl RegexCache {
+    fn new(capacity: NonZeroUsize) -> Self {
+        RegexCache(LruCache::new(capacity))
+    }
+
+    fn get(&mut self, pattern: &str) -> Option<Regex> {
+        self.0.get(pattern).cloned()
+    }
+
+    fn contains(&self, pattern: &str) -> bool {
+        self.0.contains(pattern)
+    }
+
+    /// Caches `re` unless `pattern` already is, returning the cached one.
+    fn insert(&mut self, pattern: &str, re: Regex) -> Regex {
+        self.0.get_or_insert(pattern.into(), || re).clone()
+    }
+}
+
+/// Returns the compiled regex for `pattern`, compiling and caching it on
+/// first use.
+pub fn get_or_compile(pattern: &str) -> Result<Regex, String> {
+    if let Some(re) = cache().get(pattern) {
+        return Ok(re);
+    }
+
+    // compiled without holding the lock
+    let re = Regex::new(pattern)?;
+    Ok(cache().insert(pattern, re))
+}
+
+/// Compiles every pattern that is not cached yet.
+///
+/// With the `rayon` feature the patterns are compiled in parallel. Fails with
+/// the first invalid pattern found; the valid ones are cached regardless.
+/// Beyond [`capacity`] distinct patterns, the first ones are evicted again.
+pub fn precompile<'p, I>(patterns: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = &'p str>,
+{
+    let missing: Vec<&str> = {
+        let cache = cache();
+        let mut missing: Vec<_> = patterns
+            .into_iter()
+            .filter(|p| !cache.contains(p))
+            .collect();
+        missing.sort_unstable();
+        missing.dedup();
+        missing
+    };
+
+    #[cfg(feature = "rayon")]
+    let compiled: Vec<_> = {
+        use rayon::prelude::*;
+        missing.par_iter().map(|p| (*p, Regex::new(p))).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let compiled: Vec<_> = missing.iter().map(|p| (*p, Regex::new(p))).collect();
+
+    let mut cache = cache();
+    let mut result = Ok(());
+    for (pattern, re) in compiled {
+        match re {
+            Ok(re) => {
+                cache.insert(pattern, re);
+            }
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(_) => {}
+        }
+    }
+
+    result
+}
+
+/// Number of cached patterns.
+pub fn len() -> usize {
+    cache().0.len()
+}
+
+/// Maximum number of cached patterns.
+pub fn capacity() -> usize {
+    cache().0.cap().get()
+}
+
+/// Changes the maximum number of cached patterns, 4096 by default,
+/// evicting the least recently used ones if more are cached.
+pub fn set_capacity(capacity: NonZeroUsize) {
+    cache().0.resize(capacity);
+}
+
+/// Drops every cached regex. Regexes already handed out stay valid.
+pub fn clear() {
+    cache().0.clear();
+}
+
+/// `#[serde(with)]` helper serializing a [`Regex`] as its pattern, in the
+/// same format `serde_regex` uses.
+pub(crate) mod serde_cached {
+    use crate::regex_engine::Regex;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::borrow::Cow;
+
+    pub fn serialize<S: Serializer>(re: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(re.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let pattern = Cow::<str>::deserialize(deserializer)?;
+        super::get_or_compile(&pattern).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the cache is shared by every test in the process, use patterns no
+    // other test uses
+    #[test]
+    fn test_get_or_compile() {
+        let a = get_or_compile("^/regex_cache/(a|b)$").unwrap();
+        let b = get_or_compile("^/regex_cache/(a|b)$").unwrap();
+        assert_eq!(a.as_str(), b.as_str());
+        assert!(a.is_match("/regex_cache/a"));
+
+        assert!(get_or_compile("^/regex_cache/(").is_err());
+    }
+
+    #[test]
+    fn test_precompile() {
+        let patterns = [
+            "^/regex_cache/p1$",
+            "^/regex_cache/p2$",
+            "^/regex_cache/p1$",
+        ];
+        precompile(patterns).unwrap();
+        {
+            let cache = cache();
+            assert!(cache.contains("^/regex_cache/p1$"));
+            assert!(cache.contains("^/regex_cache/p2$"));
+        }
+
+        assert!(precompile(["^/regex_cache/p3$", "^/regex_cache/(", "^/regex_cache/p4$"]).is_err());
+        let cache = cache();
+        assert!(cache.contains("^/regex_cache/p3$"));
+        assert!(cache.contains("^/regex_cache/p4$"));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut cache = RegexCache::new(NonZeroUsize::new(2).unwrap());
+        for p in ["a", "b"] {
+            cache.insert(p, Regex::new(p).unwrap());
+        }
+        // "a" is now more recently used than "b"
+        assert!(cache.get("a").is_some());
+        cache.insert("c", Regex::new("c").unwrap());
+        assert_eq!(cache.0.len(), 2);
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+
+        // a pattern already cached is kept, not replaced
+        let a = cache.insert("a", Regex::new("a").unwrap());
+        assert_eq!(a.as_str(), "a");
+        assert_eq!(cache.0.len(), 2);
+    }
+}
@@ -0,0 +1,353 @@
+//! Regex backends.
+//!
+//! The patterns of `~` and `glob` predicates are compiled by a
+//! [`RegexEngine`] when the expression is parsed. [`DefaultEngine`] is the
+//! `regex` crate, or `regex-lite` with the `regex-lite` crate feature, which
+//! compiles faster and keeps binaries such as wasm modules smaller at the
+//! cost of matching speed and Unicode support. Embedders can plug in their
+//! own engine, e.g. a PCRE compatible one, with
+//! [`parse_with_engine`](crate::parser::parse_with_engine) or
+//! [`RouterBuilder::regex_engine`](crate::router::RouterBuilder::regex_engine).
+//!
+//! Whatever the engine, matching must run in time linear in the haystack, as
+//! route expressions and request values are both untrusted.
+
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, OnceLock};
+
+/// Compiles patterns into [`CompiledRegex`]es.
+///
+/// Engines are told apart by their type, see [`Regex::engine`]: two engines
+/// of the same type must compile a pattern into regexes matching the same.
+pub trait RegexEngine: Any + Send + Sync {
+    /// Compiles `pattern`, or describes why it is invalid.
+    fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String>;
+}
+
+/// A pattern compiled by a [`RegexEngine`].
+pub trait CompiledRegex: Send + Sync {
+    /// Byte range of the leftmost-first match in `haystack`.
+    fn find(&self, haystack: &str) -> Option<Range<usize>>;
+
+    /// Byte ranges of every group of the leftmost-first match in `haystack`,
+    /// the whole match first. Groups that did not participate are `None`.
+    fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>>;
+
+    /// Name of every group, the whole match first.
+    fn capture_names(&self) -> Vec<Option<String>>;
+
+    fn is_match(&self, haystack: &str) -> bool {
+        self.find(haystack).is_some()
+    }
+
+    /// The same regex compiled by the `regex` crate, if it was. Only such
+    /// regexes are indexed by
+    /// [`Router::enable_regex_index`](crate::router::Router::enable_regex_index),
+    /// as the index must agree with the engine on what matches.
+    fn as_regex_crate(&self) -> Option<&regex::Regex> {
+        None
+    }
+
+    /// The same pattern compiled to match ignoring case, as with a leading
+    /// `(?i)`, for `lower()`'d fields under
+    /// [`LowerPolicy::Fold`](crate::schema::LowerPolicy::Fold). Engines
+    /// returning `None` keep matching such fields case-sensitively.
+    fn ignore_case(&self) -> Option<Box<dyn CompiledRegex>> {
+        None
+    }
+}
+
+/// The `regex` crate.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RegexCrateEngine;
+
+impl RegexEngine for RegexCrateEngine {
+    fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String> {
+        regex::Regex::new(pattern)
+            .map(|re| Box::new(re) as Box<dyn CompiledRegex>)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl CompiledRegex for regex::Regex {
+    fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        regex::Regex::find(self, haystack).map(|m| m.range())
+    }
+
+    fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+        regex::Regex::captures(self, haystack)
+            .map(|c| c.iter().map(|g| g.map(|g| g.range())).collect())
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        regex::Regex::capture_names(self)
+            .map(|n| n.map(str::to_string))
+            .collect()
+    }
+
+    fn is_match(&self, haystack: &str) -> bool {
+        regex::Regex::is_match(self, haystack)
+    }
+
+    fn as_regex_crate(&self) -> Option<&regex::Regex> {
+        Some(self)
+    }
+
+    fn ignore_case(&self) -> Option<Box<dyn CompiledRegex>> {
+        let re = regex::RegexBuilder::new(self.as_str())
+            .case_insensitive(true)
+            .build()
+            .ok()?;
+        Some(Box::new(re))
+    }
+}
+
+/// The `regex-lite` crate, see the [module documentation](self).
+#[cfg(feature = "regex-lite")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RegexLiteEngine;
+
+#[cfg(feature = "regex-lite")]
+impl RegexEngine for RegexLiteEngine {
+    fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String> {
+        regex_lite::Regex::new(pattern)
+            .map(|re| Box::new(re) as Box<dyn CompiledRegex>)
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "regex-lite")]
+impl CompiledRegex for regex_lite::Regex {
+    fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        regex_lite::Regex::find(self, haystack).map(|m| m.range())
+    }
+
+    fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+        regex_lite::Regex::captures(self, haystack)
+            .map(|c| c.iter().map(|g| g.map(|g| g.range())).collect())
+    }
+
+    fn capture_names(&self) -> Vec<Option<String>> {
+        regex_lite::Regex::capture_names(self)
+            .map(|n| n.map(str::to_string))
+            .collect()
+    }
+
+    fn is_match(&self, haystack: &str) -> bool {
+        regex_lite::Regex::is_match(self, haystack)
+    }
+
+    fn ignore_case(&self) -> Option<Box<dyn CompiledRegex>> {
+        let re = regex_lite::RegexBuilder::new(self.as_str())
+            .case_insensitive(true)
+            .build()
+            .ok()?;
+        Some(Box::new(re))
+    }
+}
+
+/// The engine used unless another one is given.
+#[cfg(not(feature = "regex-lite"))]
+pub type DefaultEngine = RegexCrateEngine;
+/// The engine used unless another one is given.
+#[cfg(feature = "regex-lite")]
+pub type DefaultEngine = RegexLiteEngine;
+
+/// A compiled regex of [`Value::Regex`](crate::ast::Value::Regex), along with
+/// its pattern. Cloning is cheap.
+#[derive(Clone)]
+pub struct Regex {
+    pattern: Arc<str>,
+    compiled: Arc<dyn CompiledRegex>,
+    /// Named groups with their index, looked up once.
+    names: Arc<[(usize, Box<str>)]>,
+    /// See [`Regex::ignore_case`], compiled on first use and shared by clones.
+    ignore_case: Arc<OnceLock<Option<Regex>>>,
+    /// See [`Regex::engine`].
+    engine: TypeId,
+}
+
+impl Regex {
+    /// Compiles `pattern` with [`DefaultEngine`].
+    pub fn new(pattern: &str) -> Result<Self, String> {
+        Self::with_engine(pattern, &DefaultEngine::default())
+    }
+
+    pub fn with_engine(pattern: &str, engine: &dyn RegexEngine) -> Result<Self, String> {
+        let compiled = engine.compile(pattern)?;
+        let names = compiled
+            .capture_names()
+            .into_iter()
+            .enumerate()
+            .filter_map(|(i, n)| Some((i, n?.into_boxed_str())))
+            .collect();
+
+        Ok(Self {
+            pattern: pattern.into(),
+            compiled: compiled.into(),
+            names,
+            ignore_case: Arc::default(),
+            engine: Any::type_id(engine),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.compiled.is_match(haystack)
+    }
+
+    /// See [`CompiledRegex::find`].
+    pub fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        self.compiled.find(haystack)
+    }
+
+    /// See [`CompiledRegex::captures`].
+    pub fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+        self.compiled.captures(haystack)
+    }
+
+    /// Named groups with their index.
+    pub fn capture_names(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.names.iter().map(|(i, n)| (*i, n.as_ref()))
+    }
+
+    /// The type of the [`RegexEngine`] that compiled this regex. The same
+    /// pattern compiled by another engine may match other haystacks.
+    pub fn engine(&self) -> TypeId {
+        self.engine
+    }
+
+    /// See [`CompiledRegex::as_regex_crate`].
+    pub fn as_regex_crate(&self) -> Option<&regex::Regex> {
+        self.compiled.as_regex_crate()
+    }
+
+    /// This regex matching ignoring case, see [`CompiledRegex::ignore_case`].
+    /// Returns `self` if the engine can not ignore case. Compiled the first
+    /// time it is needed.
+    pub fn ignore_case(&self) -> &Regex {
+        let insensitive = self.ignore_case.get_or_init(|| {
+            let compiled: Arc<dyn CompiledRegex> = self.compiled.ignore_case()?.into();
+            Some(Regex {
+                pattern: self.pattern.clone(),
+                compiled,
+                names: self.names.clone(),
+                ignore_case: Arc::default(),
+                engine: self.engine,
+            })
+        });
+        insensitive.as_ref().unwrap_or(self)
+    }
+}
+
+impl fmt::Display for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Regex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Regex").field(&self.as_str()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Matches the pattern literally, standing in for a foreign engine.
+    struct LiteralEngine;
+
+    struct Literal(String);
+
+    impl RegexEngine for LiteralEngine {
+        fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String> {
+            if pattern.is_empty() {
+                return Err("empty pattern".to_string());
+            }
+            Ok(Box::new(Literal(pattern.to_string())))
+        }
+    }
+
+    impl CompiledRegex for Literal {
+        fn find(&self, haystack: &str) -> Option<Range<usize>> {
+            haystack
+                .find(&self.0)
+                .map(|start| start..start + self.0.len())
+        }
+
+        fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+            self.find(haystack).map(|m| vec![Some(m)])
+        }
+
+        fn capture_names(&self) -> Vec<Option<String>> {
+            vec![None]
+        }
+    }
+
+    #[test]
+    fn test_default_engine() {
+        let re = Regex::new(r"^/(?<a>\w+)/(x)?(?<b>\d+)$").unwrap();
+        assert_eq!(re.as_str(), r"^/(?<a>\w+)/(x)?(?<b>\d+)$");
+        assert!(re.is_match("/foo/42"));
+        assert_eq!(re.find("/foo/42"), Some(0..7));
+        assert_eq!(
+            re.captures("/foo/42"),
+            Some(vec![Some(0..7), Some(1..4), None, Some(5..7)])
+        );
+        assert_eq!(
+            re.capture_names().collect::<Vec<_>>(),
+            vec![(1, "a"), (3, "b")]
+        );
+        assert_eq!(
+            re.as_regex_crate().is_some(),
+            cfg!(not(feature = "regex-lite"))
+        );
+        assert_eq!(
+            format!("{:?}", re),
+            r#"Regex("^/(?<a>\\w+)/(x)?(?<b>\\d+)$")"#
+        );
+
+        assert!(Regex::new("(").is_err());
+
+        let re = Regex::new(r"^/Foo(?<id>\d+)$").unwrap();
+        assert!(!re.is_match("/foo1"));
+        assert!(re.ignore_case().is_match("/foo1"));
+        assert_eq!(re.ignore_case().as_str(), re.as_str());
+        assert_eq!(re.ignore_case().capture_names().count(), 1);
+    }
+
+    #[test]
+    fn test_custom_engine() {
+        let re = Regex::with_engine("a.b", &LiteralEngine).unwrap();
+        assert!(re.is_match("xa.b"));
+        assert!(!re.is_match("axb"));
+        assert_eq!(re.captures("xa.b"), Some(vec![Some(1..4)]));
+        assert_eq!(re.capture_names().count(), 0);
+        assert!(re.as_regex_crate().is_none());
+        assert_eq!(re.engine(), TypeId::of::<LiteralEngine>());
+        assert_ne!(re.engine(), Regex::new("a.b").unwrap().engine());
+        // the engine can not ignore case
+        assert!(!re.ignore_case().is_match("xA.B"));
+
+        assert_eq!(
+            Regex::with_engine("", &LiteralEngine).unwrap_err(),
+            "empty pattern"
+        );
+    }
+
+    #[cfg(feature = "regex-lite")]
+    #[test]
+    fn test_regex_lite_engine() {
+        let re = Regex::with_engine(r"^/(?<id>\d+)$", &RegexLiteEngine).unwrap();
+        assert_eq!(re.captures("/42"), Some(vec![Some(0..3), Some(1..3)]));
+        assert_eq!(re.capture_names().collect::<Vec<_>>(), vec![(1, "id")]);
+        assert!(re.as_regex_crate().is_none());
+    }
+}
@@ -1,69 +1,5455 @@
-use crate::ast::Expression;
-use crate::context::{Context, Match};
+use crate::ast::{BinaryOperator, Expression, LogicalExpression, Predicate, Type, Value};
+use crate::cir::CirProgram;
+use crate::closure::ClosureProgram;
+use crate::context::{CaptureMode, Context, FieldSet, Match};
+use crate::dag::{Dag, NodeId};
+use crate::error::{EvalError, ValidationError};
 use crate::interpreter::Execute;
-use crate::parser::parse;
-use crate::schema::Schema;
-use crate::semantics::{FieldCounter, Validate};
-use std::collections::{BTreeMap, HashMap};
+use crate::lir::LirProgram;
+use crate::parser::parse_with_engine;
+use crate::prefilter::{
+    excluded_cidrs, literal_prefixes, literal_suffixes, literal_values, required_cidrs,
+    required_regexes, CidrIndex, CidrLookup, EqualityIndex, EqualityLookup, InnerPrefilter, Lookup,
+};
+use crate::regex_engine::{DefaultEngine, RegexEngine};
+use crate::schema::{Schema, SchemaRef};
+use crate::semantics::{FieldCounter, FieldId, RequiredFields, Validate};
+use crate::trace::{ExecutionTrace, TraceOutcome, TraceSampler, TraceStep};
+use regex::RegexSet;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize};
+use std::any::TypeId;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::ops::RangeInclusive;
+use std::ptr;
+#[cfg(feature = "hit-counters")]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
-struct MatcherKey(usize, Uuid);
+/// Errors returned when a matcher can not be added to a [`Router`].
+///
+/// This is the crate wide [`Error`](crate::error::Error), under its
+/// original name.
+pub type RouterError = crate::error::Error;
 
+/// A two level matcher priority, higher is evaluated first.
+///
+/// `major` is the priority the user asked for, `minor` breaks ties between
+/// matchers with the same `major` (e.g. derived from how specific a route
+/// is). Plain `usize` priorities are the same as a `major` with a `minor`
+/// of `0`. Do not reorder the fields, the derived ordering relies on it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Priority {
+    pub major: usize,
+    pub minor: u32,
+}
+
+impl Priority {
+    pub fn new(major: usize, minor: u32) -> Self {
+        Priority { major, minor }
+    }
+}
+
+impl From<usize> for Priority {
+    fn from(major: usize) -> Self {
+        Priority::new(major, 0)
+    }
+}
+
+/// Matchers are kept sorted by this key and evaluated in reverse, which is
+/// what gives [`Router`] its evaluation order. The middle field is the rank
+/// given by the router's [`TieBreak`]. Do not reorder the fields.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+struct MatcherKey(Priority, u64, Uuid);
+
+/// How a [`Router`] orders matchers with the same [`Priority`], see
+/// [`RouterBuilder::tie_break`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum TieBreak {
+    /// By descending UUID.
+    #[default]
+    Uuid,
+    /// Matchers added first are evaluated first.
+    InsertionOrder,
+    /// Matchers requiring a longer literal prefix of the field (from `==`,
+    /// `^=`, `in` or the literal start of a regex) are evaluated first,
+    /// matchers requiring none last.
+    LongestPrefix(String),
+    /// Matchers with a higher [`Expression::complexity`], as written, are
+    /// evaluated first.
+    MostSpecific,
+}
+
+/// How a [`Router`] evaluates the expressions of its matchers, see
+/// [`RouterBuilder::engine`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Compiled into a [`CirProgram`] when added.
+    #[default]
+    Cir,
+    /// Compiled into a [`LirProgram`] when added.
+    Lir,
+    /// Walks the parsed expression tree, as [`Router::matchers`] returns it.
+    Ast,
+    /// Compiled into a [`ClosureProgram`] when added, specializing each
+    /// predicate for its operator and operands.
+    Closure,
+    /// Factored into the router's [`Dag`] when added, so subexpressions
+    /// shared by several matchers are evaluated once per execution.
+    Dag,
+}
+
+/// A matcher's expression compiled for its router's [`Engine`].
+enum Program {
+    Cir(CirProgram),
+    Lir(LirProgram),
+    Closure(ClosureProgram),
+    /// Root node in the router's [`Dag`].
+    Dag(NodeId),
+}
+
+/// Configures a [`Router`] before any matcher is added, see
+/// [`Router::builder`].
+pub struct RouterBuilder<'a> {
+    router: Router<'a>,
+}
+
+impl<'a> RouterBuilder<'a> {
+    /// Orders matchers with the same [`Priority`] by `tie_break` instead of
+    /// by UUID.
+    pub fn tie_break(mut self, tie_break: TieBreak) -> Self {
+        self.router.tie_break = tie_break;
+        self
+    }
+
+    /// Evaluates matchers with `engine` instead of [`Engine::Cir`]. Every
+    /// engine matches the same requests, [`Engine::Ast`] saves the memory
+    /// of the compiled programs. `benches/engines.rs` compares their speed.
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.router.engine = engine;
+        self
+    }
+
+    /// See [`Router::set_max_matchers`].
+    pub fn max_matchers(mut self, max: Option<usize>) -> Self {
+        self.router.set_max_matchers(max);
+        self
+    }
+
+    /// See [`Router::set_optimize`].
+    pub fn optimize(mut self, optimize: bool) -> Self {
+        self.router.set_optimize(optimize);
+        self
+    }
+
+    /// See [`Router::set_rewrite_regexes`].
+    pub fn rewrite_regexes(mut self, rewrite: bool) -> Self {
+        self.router.set_rewrite_regexes(rewrite);
+        self
+    }
+
+    /// Compiles the regexes of matchers added by their ATC source with
+    /// `engine` instead of [`DefaultEngine`]. Expressions added already
+    /// parsed keep the regexes they were parsed with.
+    ///
+    /// [`Router::enable_regex_index`] only indexes regexes compiled by the
+    /// `regex` crate, the others are always left to the matchers.
+    pub fn regex_engine(mut self, engine: Arc<dyn RegexEngine>) -> Self {
+        self.router.regex_engine = engine;
+        self
+    }
+
+    pub fn build(self) -> Router<'a> {
+        self.router
+    }
+}
+
+/// What makes predicates identical, sharing a memo slot.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct MemoKey {
+    field: FieldId,
+    /// The left hand side as printed, for its transformations and map key.
+    lhs: String,
+    op: BinaryOperator,
+    /// The right hand side as printed.
+    rhs: String,
+    /// [`Regex::engine`](crate::regex_engine::Regex::engine) of a regex
+    /// right hand side, as expressions added already parsed may have been
+    /// parsed with another engine than the router's.
+    engine: Option<TypeId>,
+}
+
+impl MemoKey {
+    fn new(p: &Predicate, fields: &mut FieldCounter) -> Self {
+        MemoKey {
+            field: fields.intern(&p.lhs.var_name),
+            lhs: p.lhs.to_string(),
+            op: p.op,
+            rhs: p.rhs.to_string(),
+            engine: match &p.rhs {
+                Value::Regex(re) => Some(re.engine()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Memo slots of the distinct predicates of a router's matchers, see
+/// [`Predicate::memo`].
+#[derive(Debug, Default)]
+struct MemoSlots {
+    /// Slot of every distinct predicate and the number of matcher
+    /// predicates sharing it.
+    slots: HashMap<MemoKey, (usize, usize)>,
+    /// Slots of predicates no matcher has anymore.
+    free: Vec<usize>,
+}
+
+impl MemoSlots {
+    fn acquire(&mut self, key: MemoKey) -> usize {
+        let MemoSlots { slots, free } = self;
+        let next = slots.len();
+        let (slot, uses) = slots
+            .entry(key)
+            .or_insert_with(|| (free.pop().unwrap_or(next), 0));
+        *uses += 1;
+        *slot
+    }
+
+    fn release(&mut self, key: MemoKey) {
+        let (slot, uses) = self.slots.get_mut(&key).unwrap();
+        *uses -= 1;
+        if *uses == 0 {
+            self.free.push(*slot);
+            self.slots.remove(&key);
+        }
+    }
+}
+
+struct Matcher {
+    /// Kept for introspection, and evaluated when there is no `program`.
+    expr: Expression,
+    /// `expr` compiled, `None` for [`Engine::Ast`].
+    program: Option<Program>,
+    /// Fields that must be present in the context for `expr` to match,
+    /// see [`RequiredFields`], by [id](Schema::field_id).
+    required_fields: FieldSet,
+    /// The required fields only matched by a wildcard field, which have no
+    /// id.
+    required_wildcards: Vec<String>,
+    /// Overrides [`Router::default_capture_mode`] for this matcher.
+    capture_mode: Option<CaptureMode>,
+    /// Failed evaluations, see [`Router::set_quarantine_after`].
+    errors: AtomicU32,
+    /// Tenant the matcher is accounted to and the complexity it was
+    /// charged, see [`Router::add_tenant_matcher`].
+    tenant: Option<TenantCharge>,
+    #[cfg(feature = "hit-counters")]
+    hits: AtomicU64,
+}
+
+impl Matcher {
+    fn execute(&self, dag: &Dag, ctx: &mut Context, m: &mut Match) -> Result<bool, EvalError> {
+        match &self.program {
+            Some(Program::Cir(program)) => program.try_execute(ctx, m),
+            Some(Program::Lir(program)) => program.try_execute(ctx, m),
+            Some(Program::Closure(program)) => program.try_execute(ctx, m),
+            Some(Program::Dag(root)) => dag.execute(*root, ctx, m),
+            None => self.expr.try_execute(ctx, m),
+        }
+    }
+}
+
+/// Index of the literal prefixes matchers require on one field, see
+/// [`Router::enable_prefilter`], or of their suffixes, see
+/// [`Router::enable_suffix_filter`].
+struct RouterPrefilter {
+    field: String,
+    /// Whether the index holds suffixes, reversed, and values are looked up
+    /// reversed too.
+    suffixes: bool,
+    /// Matchers without known prefixes are not in the index and are always
+    /// candidates.
+    index: InnerPrefilter<MatcherKey>,
+}
+
+impl RouterPrefilter {
+    fn insert(&mut self, key: MatcherKey, expr: &Expression) {
+        if !self.suffixes {
+            if let Some(prefixes) = literal_prefixes(expr, &self.field) {
+                self.index.insert(key, prefixes);
+            }
+        } else if let Some(suffixes) = literal_suffixes(expr, &self.field) {
+            let reversed = suffixes
+                .iter()
+                .map(|s| s.bytes().rev().collect::<Vec<u8>>());
+            self.index.insert(key, reversed);
+        }
+    }
+
+    /// Returns the indexed matchers that can match `context`.
+    fn candidates(&self, context: &Context) -> Lookup<'_, MatcherKey> {
+        let mut lookup = self.index.lookup();
+        for v in context.value_of(&self.field).unwrap_or_default() {
+            match v {
+                Value::String(s) if self.suffixes => {
+                    lookup.add(&s.bytes().rev().collect::<Vec<u8>>());
+                }
+                Value::String(s) => lookup.add(s.as_bytes()),
+                _ => {}
+            }
+        }
+
+        lookup
+    }
+
+    fn skips(&self, key: &MatcherKey, candidates: &Lookup<MatcherKey>) -> bool {
+        self.index.contains(key) && !candidates.contains(key)
+    }
+}
+
+/// Index of the values matchers require on one field, see
+/// [`Router::enable_equality_index`].
+struct RouterEqualityIndex {
+    field: String,
+    /// Matchers without required values are not in the index and are
+    /// always candidates.
+    index: EqualityIndex<MatcherKey>,
+}
+
+impl RouterEqualityIndex {
+    fn insert(&mut self, key: MatcherKey, expr: &Expression) {
+        if let Some(values) = literal_values(expr, &self.field) {
+            self.index.insert(key, values);
+        }
+    }
+
+    /// Returns the indexed matchers that can match `context`.
+    fn candidates(&self, context: &Context) -> EqualityLookup<'_, MatcherKey> {
+        let mut lookup = self.index.lookup();
+        for v in context.value_of(&self.field).unwrap_or_default() {
+            if let Value::String(s) = v {
+                lookup.add(s);
+            }
+        }
+
+        lookup
+    }
+
+    fn skips(&self, key: &MatcherKey, candidates: &EqualityLookup<MatcherKey>) -> bool {
+        self.index.contains(key) && !candidates.contains(key)
+    }
+}
+
+/// Index of the CIDRs matchers require, or exclude, on one address field,
+/// see [`Router::enable_cidr_index`].
+struct RouterCidrIndex {
+    field: String,
+    /// Matchers without required CIDRs are not in the index and are always
+    /// candidates.
+    required: CidrIndex<MatcherKey>,
+    /// Matchers ruled out by an address in one of their CIDRs.
+    excluded: CidrIndex<MatcherKey>,
+}
+
+impl RouterCidrIndex {
+    fn insert(&mut self, key: MatcherKey, expr: &Expression) {
+        if let Some(cidrs) = required_cidrs(expr, &self.field) {
+            self.required.insert(key, &cidrs);
+        }
+
+        let excluded = excluded_cidrs(expr, &self.field);
+        if !excluded.is_empty() {
+            self.excluded.insert(key, &excluded);
+        }
+    }
+
+    fn remove(&mut self, key: &MatcherKey) {
+        self.required.remove(key);
+        self.excluded.remove(key);
+    }
+
+    /// Returns the matchers with a required CIDR containing an address of
+    /// `context`, and the ones with an excluded CIDR that does.
+    fn candidates(
+        &self,
+        context: &Context,
+    ) -> (CidrLookup<'_, MatcherKey>, CidrLookup<'_, MatcherKey>) {
+        let mut required = self.required.lookup();
+        let mut excluded = self.excluded.lookup();
+        for v in context.value_of(&self.field).unwrap_or_default() {
+            if let Value::IpAddr(addr) = v {
+                required.add(*addr);
+                excluded.add(*addr);
+            }
+        }
+
+        (required, excluded)
+    }
+
+    fn skips(&self, key: &MatcherKey, candidates: &Candidates) -> bool {
+        let has = |lookup: &Option<CidrLookup<MatcherKey>>| {
+            lookup.as_ref().is_some_and(|l| l.contains(key))
+        };
+        (self.required.contains(key) && !has(&candidates.cidrs)) || has(&candidates.excluded_cidrs)
+    }
+}
+
+/// One [`RegexSet`] per field over the regexes matchers require, see
+/// [`Router::enable_regex_index`].
+struct RegexIndex {
+    /// Each field with its set and the id of every pattern of the set.
+    sets: Vec<(String, RegexSet, Vec<usize>)>,
+    /// Ids of the patterns each matcher requires. Matchers without any are
+    /// not in the map.
+    required: BTreeMap<MatcherKey, Vec<usize>>,
+    patterns: usize,
+}
+
+impl RegexIndex {
+    fn build(matchers: &BTreeMap<MatcherKey, Matcher>) -> Self {
+        let mut builder = RegexIndexBuilder::default();
+        while !builder.step(matchers) {}
+        builder.finish()
+    }
+
+    /// Returns, by pattern id, whether any value of its field matches it.
+    fn matched(&self, context: &Context) -> Vec<bool> {
+        let mut matched = vec![false; self.patterns];
+
+        for (field, set, ids) in &self.sets {
+            for v in context.value_of(field).unwrap_or_default() {
+                if let Value::String(s) = v {
+                    for i in set.matches(s).iter() {
+                        matched[ids[i]] = true;
+                    }
+                }
+            }
+        }
+
+        matched
+    }
+
+    fn skips(&self, key: &MatcherKey, matched: &[bool]) -> bool {
+        self.required
+            .get(key)
+            .is_some_and(|ids| ids.iter().any(|id| !matched[*id]))
+    }
+}
+
+/// A [`RegexIndex`] built a step at a time, see [`Router::maintenance`].
+///
+/// The matchers must not change between steps.
+#[derive(Default)]
+struct RegexIndexBuilder {
+    /// Last matcher whose regexes were collected.
+    cursor: Option<MatcherKey>,
+    collected: bool,
+    ids: HashMap<(String, String), usize>,
+    /// Patterns of each field with their ids, the fields left to compile
+    /// once collected.
+    fields: BTreeMap<String, Vec<(String, usize)>>,
+    required: BTreeMap<MatcherKey, Vec<usize>>,
+    sets: Vec<(String, RegexSet, Vec<usize>)>,
+}
+
+impl RegexIndexBuilder {
+    /// Collects the regexes of one matcher or compiles the set of one field,
+    /// returns `true` once there is nothing left to do.
+    fn step(&mut self, matchers: &BTreeMap<MatcherKey, Matcher>) -> bool {
+        if !self.collected {
+            let next = match &self.cursor {
+                None => matchers.iter().next(),
+                Some(cursor) => matchers.range((Excluded(cursor), Unbounded)).next(),
+            };
+            let Some((key, m)) = next else {
+                self.collected = true;
+                return false;
+            };
+
+            let mut pattern_ids = Vec::new();
+            // the set would not agree with other engines on what matches
+            let required = required_regexes(&m.expr)
+                .into_iter()
+                .filter(|(_, re)| re.as_regex_crate().is_some());
+            for (field, re) in required {
+                let next = self.ids.len();
+                let id = *self
+                    .ids
+                    .entry((field.to_string(), re.as_str().to_string()))
+                    .or_insert_with(|| {
+                        self.fields
+                            .entry(field.to_string())
+                            .or_default()
+                            .push((re.as_str().to_string(), next));
+                        next
+                    });
+                pattern_ids.push(id);
+            }
+
+            if !pattern_ids.is_empty() {
+                self.required.insert(*key, pattern_ids);
+            }
+            self.cursor = Some(*key);
+            return false;
+        }
+
+        let Some((field, patterns)) = self.fields.pop_first() else {
+            return true;
+        };
+        match RegexSet::new(patterns.iter().map(|(p, _)| p)) {
+            Ok(set) => self
+                .sets
+                .push((field, set, patterns.iter().map(|(_, id)| *id).collect())),
+            // too large to compile as a set, the regexes of this field
+            // are left to the matchers
+            Err(_) => {
+                let unindexed: HashSet<usize> = patterns.iter().map(|(_, id)| *id).collect();
+                for ids in self.required.values_mut() {
+                    ids.retain(|id| !unindexed.contains(id));
+                }
+            }
+        }
+
+        false
+    }
+
+    fn finish(self) -> RegexIndex {
+        RegexIndex {
+            sets: self.sets,
+            required: self.required,
+            patterns: self.ids.len(),
+        }
+    }
+}
+
+/// What [`Router::maintenance`] left to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceProgress {
+    /// Every index is up to date.
+    Done,
+    /// The budget ran out, call [`Router::maintenance`] again.
+    Pending,
+}
+
+/// What the indexes of a router tell about one context, checked matcher
+/// by matcher so nothing is collected for those never evaluated.
+#[derive(Default)]
+struct Candidates<'r> {
+    /// `None` when the context has no value for the prefilter field.
+    prefixes: Option<Lookup<'r, MatcherKey>>,
+    /// The prefilter field, when the context has no value for it.
+    missing_field: Option<&'r str>,
+    suffixes: Option<Lookup<'r, MatcherKey>>,
+    values: Option<EqualityLookup<'r, MatcherKey>>,
+    cidrs: Option<CidrLookup<'r, MatcherKey>>,
+    excluded_cidrs: Option<CidrLookup<'r, MatcherKey>>,
+    regexes: Vec<bool>,
+}
+
+/// A named, inclusive range of priorities, such as `"override"` for
+/// `1_000_000..=usize::MAX`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityBand {
+    pub label: Arc<str>,
+    pub range: RangeInclusive<usize>,
+}
+
+/// Limits on the matchers one tenant may add, see
+/// [`Router::set_tenant_quota`]. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantQuota {
+    pub max_matchers: Option<usize>,
+    /// Limit on the sum of the [`Expression::complexity`] of the tenant's
+    /// matchers.
+    pub max_complexity: Option<usize>,
+}
+
+/// What the matchers of one tenant account for, see
+/// [`Router::tenant_usage`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantUsage {
+    pub matchers: usize,
+    pub complexity: usize,
+}
+
+/// Time a router spent parsing the expressions it was given, see
+/// [`Router::compile_report`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompileReport {
+    /// Expressions parsed, including those that failed to.
+    pub expressions: usize,
+    /// Their total length in bytes.
+    pub bytes: usize,
+    /// Wall-clock time spent parsing them, summed over every parse,
+    /// including those that failed. Validating and indexing the parsed
+    /// expressions is not included.
+    pub parse_time: Duration,
+}
+
+/// Upper bounds, in nanoseconds, of the buckets of a [`LatencyHistogram`].
+/// Slower executions fall into one more, unbounded, bucket.
+#[cfg(feature = "hit-counters")]
+pub const LATENCY_BUCKETS_NS: [u64; 10] = [
+    1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000,
+];
+
+/// How long [`Router::execute`] calls took, see [`Router::tier_latencies`].
+#[cfg(feature = "hit-counters")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Number of executions per bucket of [`LATENCY_BUCKETS_NS`], the last
+    /// one counting those slower than every bound.
+    pub counts: [u64; LATENCY_BUCKETS_NS.len() + 1],
+    /// Sum of the recorded durations.
+    pub total_ns: u64,
+}
+
+#[cfg(feature = "hit-counters")]
+impl LatencyHistogram {
+    /// Number of recorded executions.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Mean duration of the recorded executions, `0` if there were none.
+    pub fn mean_ns(&self) -> u64 {
+        self.total_ns.checked_div(self.count()).unwrap_or(0)
+    }
+}
+
+#[cfg(feature = "hit-counters")]
+#[derive(Default)]
+struct AtomicHistogram {
+    counts: [AtomicU64; LATENCY_BUCKETS_NS.len() + 1],
+    total_ns: AtomicU64,
+}
+
+#[cfg(feature = "hit-counters")]
+impl AtomicHistogram {
+    fn record(&self, started: Instant) {
+        let ns = u64::try_from(started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_NS.partition_point(|&bound| bound < ns);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> LatencyHistogram {
+        LatencyHistogram {
+            counts: std::array::from_fn(|i| self.counts[i].load(Ordering::Relaxed)),
+            total_ns: self.total_ns.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        for c in &self.counts {
+            c.store(0, Ordering::Relaxed);
+        }
+        self.total_ns.store(0, Ordering::Relaxed);
+    }
+}
+
+/// A serializable snapshot of the matchers of a [`Router`], see
+/// [`Router::to_document`] and [`Router::add_document`].
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RouterDocument {
+    /// Format version, [`RouterDocument::VERSION`] for documents written by
+    /// this release.
+    ///
+    /// Documents without a version predate versioning and are read as
+    /// version `0`, which has the same layout as version `1`. Documents
+    /// with a version newer than [`RouterDocument::VERSION`] are rejected
+    /// instead of being misread.
+    #[serde(default, deserialize_with = "deserialize_version")]
+    pub version: u32,
+    /// Every distinct regex pattern used by `matchers`.
+    ///
+    /// It is serialized before `matchers` and compiled into the
+    /// [`regex_cache`](crate::regex_cache) as soon as it is deserialized, in
+    /// parallel with the `rayon` feature, so the regexes inside `matchers`
+    /// are then cache hits.
+    #[serde(deserialize_with = "deserialize_regexes")]
+    pub regexes: Vec<String>,
+    pub matchers: Vec<MatcherDocument>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MatcherDocument {
+    pub priority: usize,
+    /// See [`Priority::minor`].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub minor: u32,
+    pub uuid: Uuid,
+    pub expression: Expression,
+}
+
+/// A route as written in a configuration file.
+///
+/// Unlike [`MatcherDocument`], the expression is kept as ATC source, which
+/// makes it the format to use for hand written or generated configs.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteDoc {
+    pub uuid: Uuid,
+    pub priority: usize,
+    /// See [`Priority::minor`].
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub minor: u32,
+    pub expression: String,
+    /// Disabled routes are skipped by [`Router::from_routes`].
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Free form data for the host, ignored by the router.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "serde")]
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+#[cfg(feature = "serde")]
+fn default_enabled() -> bool {
+    true
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_regexes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    let regexes = Vec::<String>::deserialize(deserializer)?;
+    crate::regex_cache::precompile(regexes.iter().map(String::as_str))
+        .map_err(serde::de::Error::custom)?;
+    Ok(regexes)
+}
+
+#[cfg(feature = "serde")]
+impl RouterDocument {
+    /// The newest format version this release reads and the one it writes.
+    pub const VERSION: u32 = 1;
+}
+
+/// Body of the binary snapshot written by [`Router::serialize`], after the
+/// format version.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct RouterSnapshot {
+    /// Field counters, sorted by field.
+    fields: Vec<(String, usize)>,
+    /// `(major, minor, uuid, expression)` in evaluation order.
+    matchers: Vec<(usize, u32, Uuid, Expression)>,
+}
+
+#[cfg(feature = "serde")]
+impl RouterSnapshot {
+    const VERSION: u32 = 2;
+}
+
+#[cfg(feature = "serde")]
+fn deserialize_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let version = u32::deserialize(deserializer)?;
+    if version > RouterDocument::VERSION {
+        return Err(serde::de::Error::custom(format!(
+            "unsupported router document version {}, expected at most {}",
+            version,
+            RouterDocument::VERSION
+        )));
+    }
+
+    Ok(version)
+}
+
+fn collect_fields<'e>(expr: &'e Expression, out: &mut HashSet<&'e str>) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(a, b) | LogicalExpression::Or(a, b) => {
+                collect_fields(a, out);
+                collect_fields(b, out);
+            }
+            LogicalExpression::Not(e) => collect_fields(e, out),
+        },
+        Expression::Predicate(p) => {
+            out.insert(&p.lhs.var_name);
+        }
+        Expression::FieldComparison(c) => {
+            out.insert(&c.lhs.var_name);
+            out.insert(&c.rhs.var_name);
+        }
+        Expression::Bool(_) => {}
+    }
+}
+
+#[cfg(feature = "serde")]
+fn collect_regexes<'e>(expr: &'e Expression, out: &mut Vec<&'e str>) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(a, b) | LogicalExpression::Or(a, b) => {
+                collect_regexes(a, out);
+                collect_regexes(b, out);
+            }
+            LogicalExpression::Not(e) => collect_regexes(e, out),
+        },
+        Expression::Predicate(p) => {
+            if let Value::Regex(re) = &p.rhs {
+                out.push(re.as_str());
+            }
+        }
+        Expression::FieldComparison(_) | Expression::Bool(_) => {}
+    }
+}
+
+/// Returned by [`Router::execute_deadline`] when the deadline passed before
+/// every matcher was evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineExceeded;
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "router execution deadline exceeded")
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// Called with the UUID of a matcher and the error its evaluation failed
+/// with, see [`Router::set_eval_error_hook`].
+pub type ErrorHook = Box<dyn Fn(Uuid, &EvalError) + Send + Sync>;
+
+/// Receives measurements of [`Router::execute`] calls, so embedders can feed
+/// their own metrics, e.g. Prometheus counters and histograms, see
+/// [`Router::set_metrics_sink`].
+///
+/// It is called on the thread executing the router, while the request
+/// waits, and should only update counters.
+pub trait MetricsSink: Send + Sync {
+    /// Called once per [`Router::execute`] call, with how long it took,
+    /// whether a matcher matched and how many predicates and field
+    /// comparisons were evaluated, see
+    /// [`ExecutionStats::predicates_evaluated`](crate::context::ExecutionStats::predicates_evaluated).
+    fn on_execute(&self, duration: Duration, matched: bool, predicates_evaluated: usize);
+}
+
+/// Lets the embedder keep a handle on the sink it gives the router.
+impl<T: MetricsSink + ?Sized> MetricsSink for Arc<T> {
+    fn on_execute(&self, duration: Duration, matched: bool, predicates_evaluated: usize) {
+        (**self).on_execute(duration, matched, predicates_evaluated)
+    }
+}
+
+/// A set of matchers sharing one [`Schema`].
+///
+/// # Evaluation order
+///
+/// Matchers are evaluated by descending [`Priority`], comparing `major`
+/// first and `minor` second. Matchers with the same priority are evaluated
+/// by descending UUID, compared as 128 bit big-endian
+/// integers (which is also the order of their lowercase hex form). The order
+/// only depends on the `(priority, uuid)` pairs, never on insertion order,
+/// and every API returning more than one matcher (such as
+/// [`Router::matchers`]) uses it.
+///
+/// A router created with another [`TieBreak`] first orders matchers with
+/// the same priority by that policy, and only falls back to the UUID when
+/// the policy ranks them equally.
+///
+/// The order is part of the API and kept across releases, so hosts can
+/// check which of several overlapping matchers wins with
+/// [`Router::evaluation_order`].
+///
+/// # Thread safety
+///
+/// A router is `Send + Sync`: once built, any number of threads can
+/// [`execute`](Router::execute) it at the same time through a shared
+/// reference, each with its own [`Context`]. The state updated while
+/// executing (hit counters, quarantine, trace sampling and the lazily built
+/// regex index) only uses atomics, locks and [`OnceLock`]. Changing the
+/// matchers takes `&mut self`; to update a router other threads execute,
+/// build a new one and swap it in with a
+/// [`SharedRouter`](crate::shared_router::SharedRouter).
 pub struct Router<'a> {
-    schema: &'a Schema,
-    matchers: BTreeMap<MatcherKey, Expression>,
-    pub fields: HashMap<String, usize>,
+    schema: SchemaRef<'a>,
+    matchers: BTreeMap<MatcherKey, Matcher>,
+    /// How many times the matchers reference each field.
+    pub fields: FieldCounter,
+    memo_slots: MemoSlots,
+    /// Expressions of the matchers for [`Engine::Dag`], empty otherwise.
+    dag: Dag,
+    max_matchers: Option<usize>,
+    priority_bands: Vec<PriorityBand>,
+    prefilter: Option<RouterPrefilter>,
+    suffix_filter: Option<RouterPrefilter>,
+    equality_index: Option<RouterEqualityIndex>,
+    cidr_index: Option<RouterCidrIndex>,
+    /// Built on first use after the matchers change.
+    regex_index: Option<OnceLock<RegexIndex>>,
+    /// Partial rebuild of `regex_index`, see [`Router::maintenance`].
+    regex_index_builder: Option<RegexIndexBuilder>,
+    default_capture_mode: CaptureMode,
+    optimize: bool,
+    rewrite_regexes: bool,
+    tie_break: TieBreak,
+    engine: Engine,
+    regex_engine: Arc<dyn RegexEngine>,
+    /// Non-zero [`TieBreak`] ranks of the matchers, see [`Router::key`].
+    ranks: HashMap<(Priority, Uuid), u64>,
+    /// Matchers added so far, for [`TieBreak::InsertionOrder`].
+    insertions: u64,
+    quarantine_after: Option<u32>,
+    error_hook: Option<ErrorHook>,
+    metrics_sink: Option<Box<dyn MetricsSink>>,
+    trace_sampler: Option<TraceSampler>,
+    tenant_quotas: HashMap<String, TenantQuota>,
+    /// Usage of every tenant with at least one matcher.
+    tenant_usage: BTreeMap<Arc<str>, TenantUsage>,
+    compile_report: CompileReport,
+    #[cfg(feature = "hit-counters")]
+    misses: AtomicU64,
+    /// Execution latency of matches in each of `priority_bands`, then of
+    /// matches outside every band, then of misses.
+    #[cfg(feature = "hit-counters")]
+    latencies: Vec<AtomicHistogram>,
 }
 
+// Routers are shared between threads, see "Thread safety" above.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Router<'static>>();
+    assert_send_sync::<crate::shared_router::SharedRouter<'static>>();
+};
+
 impl<'a> Router<'a> {
     pub fn new(schema: &'a Schema) -> Self {
+        Self::with_schema(schema.into())
+    }
+
+    /// Like [`Router::new`], with a borrowed or shared schema. A router
+    /// sharing its schema through an [`Arc`] borrows nothing, so it can be a
+    /// `Router<'static>`.
+    pub fn with_schema(schema: SchemaRef<'a>) -> Self {
         Self {
             schema,
             matchers: BTreeMap::new(),
-            fields: HashMap::new(),
+            fields: FieldCounter::new(),
+            memo_slots: MemoSlots::default(),
+            dag: Dag::default(),
+            max_matchers: None,
+            priority_bands: Vec::new(),
+            prefilter: None,
+            suffix_filter: None,
+            equality_index: None,
+            cidr_index: None,
+            regex_index: None,
+            regex_index_builder: None,
+            default_capture_mode: CaptureMode::All,
+            optimize: false,
+            rewrite_regexes: false,
+            tie_break: TieBreak::Uuid,
+            engine: Engine::Cir,
+            regex_engine: Arc::new(DefaultEngine::default()),
+            ranks: HashMap::new(),
+            insertions: 0,
+            quarantine_after: None,
+            error_hook: None,
+            metrics_sink: None,
+            trace_sampler: None,
+            tenant_quotas: HashMap::new(),
+            tenant_usage: BTreeMap::new(),
+            compile_report: CompileReport::default(),
+            #[cfg(feature = "hit-counters")]
+            misses: AtomicU64::new(0),
+            #[cfg(feature = "hit-counters")]
+            latencies: vec![AtomicHistogram::default(), AtomicHistogram::default()],
         }
     }
 
-    pub fn add_matcher(&mut self, priority: usize, uuid: Uuid, atc: &str) -> Result<(), String> {
-        let key = MatcherKey(priority, uuid);
-
-        if self.matchers.contains_key(&key) {
-            return Err("UUID already exists".to_string());
+    /// Starts configuring a router, for settings that can not change once
+    /// matchers are added, such as [`RouterBuilder::tie_break`].
+    pub fn builder(schema: &'a Schema) -> RouterBuilder<'a> {
+        RouterBuilder {
+            router: Router::new(schema),
         }
+    }
 
-        let ast = parse(atc).map_err(|e| e.to_string())?;
+    /// How matchers with the same priority are ordered, see
+    /// [`RouterBuilder::tie_break`].
+    pub fn tie_break(&self) -> &TieBreak {
+        &self.tie_break
+    }
 
-        ast.validate(self.schema)?;
-        ast.add_to_counter(&mut self.fields);
+    /// How matchers are evaluated, see [`RouterBuilder::engine`].
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
 
-        assert!(self.matchers.insert(key, ast).is_none());
+    /// The schema matchers are validated against, which contexts executed
+    /// by the router must use.
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
 
-        Ok(())
+    /// How long parsing the expressions the router was given as text took,
+    /// to tell whether huge generated expressions are worth splitting.
+    /// Expressions added already parsed, such as with
+    /// [`Router::add_matcher_expr`], are not counted.
+    pub fn compile_report(&self) -> CompileReport {
+        self.compile_report
     }
 
-    pub fn remove_matcher(&mut self, priority: usize, uuid: Uuid) -> bool {
-        let key = MatcherKey(priority, uuid);
+    /// Parses `atc`, compiling its regexes with the router's
+    /// [`RouterBuilder::regex_engine`].
+    fn parse(&mut self, atc: &str) -> Result<Expression, RouterError> {
+        let started = Instant::now();
+        let result = parse_with_engine(atc, self.regex_engine.as_ref());
 
-        if let Some(ast) = self.matchers.remove(&key) {
-            ast.remove_from_counter(&mut self.fields);
-            return true;
+        let report = &mut self.compile_report;
+        report.expressions += 1;
+        report.bytes += atc.len();
+        report.parse_time += started.elapsed();
+
+        result.map_err(RouterError::from)
+    }
+
+    /// Creates a router holding every enabled route of `routes`.
+    ///
+    /// Fails with [`RouterError::InvalidRoute`] on the first route that can
+    /// not be added.
+    #[cfg(feature = "serde")]
+    pub fn from_routes(schema: &'a Schema, routes: &[RouteDoc]) -> Result<Self, RouterError> {
+        let mut router = Router::new(schema);
+
+        for r in routes.iter().filter(|r| r.enabled) {
+            router
+                .add_matcher_at(Priority::new(r.priority, r.minor), r.uuid, &r.expression)
+                .map_err(|e| RouterError::InvalidRoute(r.uuid, Box::new(e)))?;
         }
 
-        false
+        Ok(router)
     }
 
-    pub fn execute(&self, context: &mut Context) -> bool {
-        for (MatcherKey(_, id), m) in self.matchers.iter().rev() {
-            let mut mat = Match::new();
-            if m.execute(context, &mut mat) {
-                mat.uuid = *id;
-                context.result = Some(mat);
+    /// Limits the number of matchers this router accepts.
+    ///
+    /// Once the limit is reached, [`Router::add_matcher`] rejects new matchers
+    /// until some are removed. `None` (the default) means unlimited.
+    /// Lowering the limit below the current number of matchers does not
+    /// evict anything, it only prevents further additions.
+    pub fn set_max_matchers(&mut self, max: Option<usize>) {
+        self.max_matchers = max;
+    }
 
-                return true;
+    /// Whether matchers added from now on are rewritten with
+    /// [`Expression::optimize`] before being stored. Off by default.
+    ///
+    /// Optimized matchers match the same requests, but
+    /// [`Router::matchers`] and [`Router::to_document`] return the
+    /// optimized expressions.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn optimize(&self) -> bool {
+        self.optimize
+    }
+
+    /// Whether `~` predicates of matchers added from now on are rewritten
+    /// with [`Expression::rewrite_regexes`] before being stored, so
+    /// `http.path ~ "^/api/v1/.*"` is checked as `http.path ^= "/api/v1/"`.
+    /// Off by default.
+    ///
+    /// [`CaptureMode::All`] records the whole match of every regex as
+    /// capture `0`, which the rewritten predicates can not, so nothing is
+    /// rewritten while [`Router::default_capture_mode`] is `All`. Matchers
+    /// later switched to `All` with [`Router::set_capture_mode`] stay
+    /// rewritten. [`Match::matches`] holds the literal the rewritten
+    /// predicate compared with instead of the text the regex matched.
+    pub fn set_rewrite_regexes(&mut self, rewrite: bool) {
+        self.rewrite_regexes = rewrite;
+    }
+
+    pub fn rewrite_regexes(&self) -> bool {
+        self.rewrite_regexes
+    }
+
+    /// Quarantines matchers whose evaluation keeps failing.
+    ///
+    /// Only expressions that were not validated against the schema fail to
+    /// evaluate, see [`Execute::try_execute`]. A matcher whose evaluation
+    /// fails does not match, and the error is reported to the
+    /// [`Router::set_eval_error_hook`] hook and counted in
+    /// [`ExecutionStats::eval_errors`]. With `Some(n)`, after `n` failures
+    /// the matcher is quarantined: it is skipped (see
+    /// [`ExecutionStats::matchers_quarantined`]) until
+    /// [`Router::release_quarantine`] is called. `None`, the default, keeps
+    /// evaluating failing matchers.
+    ///
+    /// [`ExecutionStats::eval_errors`]: crate::context::ExecutionStats::eval_errors
+    /// [`ExecutionStats::matchers_quarantined`]: crate::context::ExecutionStats::matchers_quarantined
+    pub fn set_quarantine_after(&mut self, errors: Option<u32>) {
+        self.quarantine_after = errors;
+    }
+
+    pub fn set_eval_error_hook(&mut self, hook: impl Fn(Uuid, &EvalError) + Send + Sync + 'static) {
+        self.error_hook = Some(Box::new(hook));
+    }
+
+    /// Reports every [`Router::execute`] and [`Router::execute_deadline`]
+    /// call that completes to `sink`, replacing the previous sink. Calls
+    /// that exceed their deadline and [`Router::execute_all`] calls are not
+    /// reported.
+    ///
+    /// Without a sink, executing the router does not read the clock.
+    pub fn set_metrics_sink(&mut self, sink: impl MetricsSink + 'static) {
+        self.metrics_sink = Some(Box::new(sink));
+    }
+
+    pub fn clear_metrics_sink(&mut self) {
+        self.metrics_sink = None;
+    }
+
+    /// Records how the matchers were tried for a `rate` fraction of
+    /// [`Router::execute`] calls, so matching decisions can be debugged in
+    /// production. The latest `capacity` traces are kept until
+    /// [`Router::drain_traces`], older ones are dropped.
+    ///
+    /// Sampled calls are evenly spread, a `rate` of `0.01` traces every
+    /// hundredth call. Replaces the traces kept so far.
+    pub fn set_trace_sampling(&mut self, rate: f64, capacity: usize) {
+        self.trace_sampler = Some(TraceSampler::new(rate, capacity));
+    }
+
+    /// Stops tracing and drops the traces kept so far.
+    pub fn disable_trace_sampling(&mut self) {
+        self.trace_sampler = None;
+    }
+
+    /// Returns and forgets the traces kept so far, oldest first, see
+    /// [`Router::set_trace_sampling`].
+    pub fn drain_traces(&self) -> Vec<ExecutionTrace> {
+        self.trace_sampler
+            .as_ref()
+            .map(TraceSampler::drain)
+            .unwrap_or_default()
+    }
+
+    /// Returns every quarantined matcher as `(priority, uuid)`, in
+    /// evaluation order.
+    pub fn quarantined(&self) -> Vec<(usize, Uuid)> {
+        self.matchers
+            .iter()
+            .rev()
+            .filter(|(_, m)| self.is_quarantined(m))
+            .map(|(k, _)| (k.0.major, k.2))
+            .collect()
+    }
+
+    /// Resets the error count of a matcher, taking it out of quarantine.
+    /// Returns `false` if there is no such matcher.
+    pub fn release_quarantine(&self, priority: usize, uuid: Uuid) -> bool {
+        match self.matchers.get(&self.key(priority.into(), uuid)) {
+            Some(m) => {
+                m.errors.store(0, Ordering::Relaxed);
+                true
             }
+            None => false,
         }
+    }
 
-        false
+    fn is_quarantined(&self, m: &Matcher) -> bool {
+        self.quarantine_after
+            .is_some_and(|limit| m.errors.load(Ordering::Relaxed) >= limit)
+    }
+
+    pub fn max_matchers(&self) -> Option<usize> {
+        self.max_matchers
+    }
+
+    /// Declares a named priority band.
+    ///
+    /// Once at least one band is declared, [`Router::add_matcher`] only accepts
+    /// priorities that fall inside a declared band, and successful matches
+    /// carry the label of the band the winning matcher belongs to in
+    /// [`Match::band`]. Matchers added before the first band was declared
+    /// are kept as they are.
+    pub fn add_priority_band(
+        &mut self,
+        label: &str,
+        range: RangeInclusive<usize>,
+    ) -> Result<(), RouterError> {
+        if let Some(b) = self
+            .priority_bands
+            .iter()
+            .find(|b| b.range.start() <= range.end() && range.start() <= b.range.end())
+        {
+            return Err(RouterError::PriorityBandOverlap(b.label.to_string()));
+        }
+
+        self.priority_bands.push(PriorityBand {
+            label: label.into(),
+            range,
+        });
+        #[cfg(feature = "hit-counters")]
+        self.latencies
+            .insert(self.priority_bands.len() - 1, AtomicHistogram::default());
+
+        Ok(())
+    }
+
+    /// Selects which regex capture groups matchers without their own
+    /// [`Router::set_capture_mode`] populate. Defaults to
+    /// [`CaptureMode::All`].
+    ///
+    /// The router can only restrict captures further than the context's
+    /// [`Context::capture_mode`], never re-enable them.
+    pub fn set_default_capture_mode(&mut self, mode: CaptureMode) {
+        self.default_capture_mode = mode;
+    }
+
+    pub fn default_capture_mode(&self) -> CaptureMode {
+        self.default_capture_mode
+    }
+
+    /// Overrides the capture mode of one matcher, `None` goes back to
+    /// [`Router::default_capture_mode`]. Matchers whose captures nobody
+    /// reads can be set to [`CaptureMode::None`] so their regexes never
+    /// extract capture groups.
+    ///
+    /// Returns `false` if there is no such matcher.
+    pub fn set_capture_mode(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        mode: Option<CaptureMode>,
+    ) -> bool {
+        let key = self.key(priority.into(), uuid);
+        match self.matchers.get_mut(&key) {
+            Some(m) => {
+                m.capture_mode = mode;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn priority_bands(&self) -> &[PriorityBand] {
+        &self.priority_bands
+    }
+
+    /// Returns the band `priority` belongs to, if any.
+    pub fn band_of(&self, priority: usize) -> Option<&PriorityBand> {
+        self.priority_bands
+            .iter()
+            .find(|b| b.range.contains(&priority))
+    }
+
+    /// Returns the number of matchers currently in the router.
+    pub fn len(&self) -> usize {
+        self.matchers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matchers.is_empty()
+    }
+
+    pub fn add_matcher(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.add_matcher_at(priority.into(), uuid, atc)
+    }
+
+    /// Like [`Router::add_matcher`], with a two level [`Priority`].
+    pub fn add_matcher_at(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.check_can_add(priority, uuid)?;
+
+        let ast = self.parse(atc)?;
+
+        self.add_matcher_expr_at(priority, uuid, ast)
+    }
+
+    /// Adds a matcher matching when any of `atcs` does, such as a route
+    /// made of several expressions, without joining them into one string.
+    ///
+    /// The expressions are combined with `||` and tried in order, so the
+    /// captures of the match are those of the first expression that
+    /// matches. Each one is parsed and validated on its own, the first one
+    /// that fails is reported as [`RouterError::InvalidExpression`] with
+    /// its index. Fails with [`RouterError::InvalidArgument`] if `atcs` is
+    /// empty.
+    pub fn add_matcher_multi(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        atcs: &[&str],
+    ) -> Result<(), RouterError> {
+        self.add_matcher_multi_at(priority.into(), uuid, atcs)
+    }
+
+    /// Like [`Router::add_matcher_multi`], with a two level [`Priority`].
+    pub fn add_matcher_multi_at(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        atcs: &[&str],
+    ) -> Result<(), RouterError> {
+        self.check_can_add(priority, uuid)?;
+
+        let mut group = None;
+        for (i, atc) in atcs.iter().enumerate() {
+            let ast = self
+                .parse(atc)
+                .and_then(|ast| Ok(ast.validate(&self.schema).map(|_| ast)?))
+                .map_err(|e| RouterError::InvalidExpression(i, Box::new(e)))?;
+
+            group = Some(match group {
+                Some(group) => Expression::Logical(Box::new(LogicalExpression::Or(group, ast))),
+                None => ast,
+            });
+        }
+        let group = group.ok_or_else(|| {
+            RouterError::InvalidArgument("a matcher needs at least one expression".to_string())
+        })?;
+
+        self.insert_matcher(priority, uuid, group);
+
+        Ok(())
+    }
+
+    /// Adds an already parsed expression as a matcher.
+    ///
+    /// The expression is validated against the router's schema
+    /// exactly like [`Router::add_matcher`] does.
+    pub fn add_matcher_expr(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        ast: Expression,
+    ) -> Result<(), RouterError> {
+        self.add_matcher_expr_at(priority.into(), uuid, ast)
+    }
+
+    /// Like [`Router::add_matcher_expr`], with a two level [`Priority`].
+    pub fn add_matcher_expr_at(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        ast: Expression,
+    ) -> Result<(), RouterError> {
+        self.check_can_add(priority, uuid)?;
+
+        ast.validate(&self.schema)
+            .map_err(RouterError::ValidationError)?;
+
+        self.insert_matcher(priority, uuid, ast);
+
+        Ok(())
+    }
+
+    /// The key of the matcher with this `priority` and `uuid`, whether it
+    /// exists or not.
+    fn key(&self, priority: Priority, uuid: Uuid) -> MatcherKey {
+        let rank = self.ranks.get(&(priority, uuid)).copied().unwrap_or(0);
+        MatcherKey(priority, rank, uuid)
+    }
+
+    /// The rank [`TieBreak`] gives a new matcher, `0` is evaluated last.
+    fn rank(&mut self, ast: &Expression) -> u64 {
+        match &self.tie_break {
+            TieBreak::Uuid => 0,
+            TieBreak::InsertionOrder => {
+                self.insertions += 1;
+                u64::MAX - self.insertions
+            }
+            TieBreak::LongestPrefix(field) => literal_prefixes(ast, field)
+                .and_then(|prefixes| prefixes.iter().map(String::len).max())
+                .unwrap_or(0) as u64,
+            TieBreak::MostSpecific => ast.complexity() as u64,
+        }
+    }
+
+    /// Inserts an already checked and validated matcher, returning its key.
+    fn insert_matcher(&mut self, priority: Priority, uuid: Uuid, ast: Expression) -> MatcherKey {
+        let (key, mut matcher) = self.build_matcher(priority, uuid, ast);
+        if self.engine == Engine::Dag {
+            matcher.program = Some(Program::Dag(self.dag.insert(&matcher.expr)));
+        }
+
+        if let Some(prefilter) = &mut self.prefilter {
+            prefilter.insert(key, &matcher.expr);
+        }
+        if let Some(filter) = &mut self.suffix_filter {
+            filter.insert(key, &matcher.expr);
+        }
+        if let Some(index) = &mut self.equality_index {
+            index.insert(key, &matcher.expr);
+        }
+        if let Some(index) = &mut self.cidr_index {
+            index.insert(key, &matcher.expr);
+        }
+        self.invalidate_regex_index();
+
+        assert!(self.matchers.insert(key, matcher).is_none());
+
+        key
+    }
+
+    /// Builds the matcher of an already checked and validated expression,
+    /// accounting its fields and predicates but neither indexing it nor
+    /// adding it to the DAG, which is left to the caller.
+    fn build_matcher(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        ast: Expression,
+    ) -> (MatcherKey, Matcher) {
+        let rank = self.rank(&ast);
+        if rank != 0 {
+            self.ranks.insert((priority, uuid), rank);
+        }
+        let key = MatcherKey(priority, rank, uuid);
+
+        let ast = if self.rewrite_regexes && self.default_capture_mode != CaptureMode::All {
+            ast.rewrite_regexes()
+        } else {
+            ast
+        };
+        let ast = if self.optimize { ast.optimize() } else { ast };
+        // long `||` chains of `==` become a single set lookup, printed back
+        // as written
+        let ast = ast.compact();
+        let mut ast = match self.schema.method_field() {
+            Some(field) => ast.compile_methods(field),
+            None => ast,
+        };
+        // fields are read from contexts by id from now on
+        ast.intern_fields(&self.schema);
+        self.fields.add(&ast);
+        // identical predicates of different matchers are evaluated once per
+        // execution
+        ast.for_each_predicate_mut(&mut |p| {
+            let key = MemoKey::new(p, &mut self.fields);
+            p.memo = Some(self.memo_slots.acquire(key));
+        });
+
+        let mut required_fields = FieldSet::default();
+        let mut required_wildcards = Vec::new();
+        for f in ast.required_fields() {
+            match self.schema.field_id(&f) {
+                Some(id) => required_fields.insert(id),
+                None => required_wildcards.push(f),
+            }
+        }
+
+        let matcher = Matcher {
+            required_fields,
+            required_wildcards,
+            program: match self.engine {
+                Engine::Cir => Some(Program::Cir(CirProgram::from(&ast))),
+                Engine::Lir => Some(Program::Lir(LirProgram::from(&ast))),
+                Engine::Closure => Some(Program::Closure(ClosureProgram::from(&ast))),
+                Engine::Dag | Engine::Ast => None,
+            },
+            expr: ast,
+            capture_mode: None,
+            errors: AtomicU32::new(0),
+            tenant: None,
+            #[cfg(feature = "hit-counters")]
+            hits: AtomicU64::new(0),
+        };
+
+        (key, matcher)
+    }
+
+    /// Adds a matcher accounted to `tenant`, such as the namespace of the
+    /// customer who wrote the expression.
+    ///
+    /// Besides the checks of [`Router::add_matcher`], fails with
+    /// [`RouterError::QuotaExceeded`] if the matcher would take the tenant
+    /// over its [`Router::set_tenant_quota`]. The complexity charged is that
+    /// of the expression as written, before [`Router::set_optimize`]
+    /// rewrites it. Removing the matcher credits the tenant back.
+    pub fn add_tenant_matcher(
+        &mut self,
+        tenant: &str,
+        priority: usize,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.add_tenant_matcher_at(tenant, priority.into(), uuid, atc)
+    }
+
+    /// Like [`Router::add_tenant_matcher`], with a two level [`Priority`].
+    pub fn add_tenant_matcher_at(
+        &mut self,
+        tenant: &str,
+        priority: Priority,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.check_can_add(priority, uuid)?;
+
+        let ast = self.parse(atc)?;
+        ast.validate(&self.schema)
+            .map_err(RouterError::ValidationError)?;
+
+        let complexity = ast.complexity();
+        let mut usage = self.tenant_usage(tenant);
+        usage.matchers += 1;
+        usage.complexity += complexity;
+        self.check_quota(tenant, usage)?;
+
+        let key = self.insert_matcher(priority, uuid, ast);
+        self.charge_tenant(key, tenant.into(), complexity);
+
+        Ok(())
+    }
+
+    fn check_quota(&self, tenant: &str, usage: TenantUsage) -> Result<(), RouterError> {
+        let quota = self.tenant_quota(tenant);
+        if quota.max_matchers.is_some_and(|max| usage.matchers > max)
+            || quota
+                .max_complexity
+                .is_some_and(|max| usage.complexity > max)
+        {
+            return Err(RouterError::QuotaExceeded(tenant.to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Accounts the matcher `key` to `tenant`, which the quota was already
+    /// checked for.
+    fn charge_tenant(&mut self, key: MatcherKey, tenant: Arc<str>, complexity: usize) {
+        let usage = self.tenant_usage.entry(tenant.clone()).or_default();
+        usage.matchers += 1;
+        usage.complexity += complexity;
+        self.matchers.get_mut(&key).unwrap().tenant = Some((tenant, complexity));
+    }
+
+    /// Sets the quota [`Router::add_tenant_matcher`] enforces for `tenant`,
+    /// tenants without one are unlimited. Lowering a quota below the
+    /// tenant's usage does not evict anything, it only prevents further
+    /// additions.
+    pub fn set_tenant_quota(&mut self, tenant: &str, quota: TenantQuota) {
+        self.tenant_quotas.insert(tenant.to_string(), quota);
+    }
+
+    pub fn tenant_quota(&self, tenant: &str) -> TenantQuota {
+        self.tenant_quotas.get(tenant).copied().unwrap_or_default()
+    }
+
+    pub fn tenant_usage(&self, tenant: &str) -> TenantUsage {
+        self.tenant_usage.get(tenant).copied().unwrap_or_default()
+    }
+
+    /// Returns the usage of every tenant with at least one matcher, sorted
+    /// by tenant.
+    pub fn tenants(&self) -> impl Iterator<Item = (&str, TenantUsage)> + '_ {
+        self.tenant_usage.iter().map(|(t, u)| (t.as_ref(), *u))
+    }
+
+    /// Returns the tenant a matcher is accounted to, `None` if it was not
+    /// added with [`Router::add_tenant_matcher`] or does not exist.
+    pub fn tenant_of(&self, priority: usize, uuid: Uuid) -> Option<&str> {
+        self.matchers
+            .get(&self.key(priority.into(), uuid))
+            .and_then(|m| m.tenant.as_ref())
+            .map(|(t, _)| t.as_ref())
+    }
+
+    /// Starts a batch of changes that are checked as they are staged and
+    /// applied all at once by [`RouterUpdate::commit`].
+    ///
+    /// The router is not modified until then, so a failed
+    /// [`RouterUpdate::add_matcher`] can be handled by calling
+    /// [`RouterUpdate::rollback`] (or dropping the update) without leaving a
+    /// half applied batch behind.
+    pub fn begin_update(&mut self) -> RouterUpdate<'_, 'a> {
+        RouterUpdate {
+            router: self,
+            ops: Vec::new(),
+            added: HashSet::new(),
+            removed: HashSet::new(),
+            tenants: HashMap::new(),
+            tenant_usage: HashMap::new(),
+        }
+    }
+
+    fn check_can_add(&self, priority: Priority, uuid: Uuid) -> Result<(), RouterError> {
+        if self.contains_at(priority, uuid) {
+            return Err(RouterError::DuplicateUuid(uuid));
+        }
+
+        if let Some(max) = self.max_matchers {
+            if self.matchers.len() >= max {
+                return Err(RouterError::LimitExceeded(max));
+            }
+        }
+
+        if !self.priority_bands.is_empty() && self.band_of(priority.major).is_none() {
+            return Err(RouterError::PriorityOutOfBand(priority.major));
+        }
+
+        Ok(())
+    }
+
+    pub fn remove_matcher(&mut self, priority: usize, uuid: Uuid) -> bool {
+        self.remove_matcher_at(priority.into(), uuid)
+    }
+
+    pub fn remove_matcher_at(&mut self, priority: Priority, uuid: Uuid) -> bool {
+        if let Some((key, m)) = self.take_matcher(priority, uuid) {
+            if let Some(Program::Dag(root)) = m.program {
+                self.dag.remove(root);
+            }
+            if let Some(prefilter) = &mut self.prefilter {
+                prefilter.index.remove(&key);
+            }
+            if let Some(filter) = &mut self.suffix_filter {
+                filter.index.remove(&key);
+            }
+            if let Some(index) = &mut self.equality_index {
+                index.index.remove(&key);
+            }
+            if let Some(index) = &mut self.cidr_index {
+                index.remove(&key);
+            }
+            self.invalidate_regex_index();
+            return true;
+        }
+
+        false
+    }
+
+    /// Removes a matcher, giving back what it accounted for like
+    /// [`Router::build_matcher`] took it, but leaving it in the indexes and
+    /// the DAG.
+    fn take_matcher(&mut self, priority: Priority, uuid: Uuid) -> Option<(MatcherKey, Matcher)> {
+        let key = self.key(priority, uuid);
+        let m = self.matchers.remove(&key)?;
+
+        self.ranks.remove(&(priority, uuid));
+        self.fields.remove(&m.expr);
+        m.expr.for_each_predicate(&mut |p| {
+            self.memo_slots.release(MemoKey::new(p, &mut self.fields))
+        });
+        if let Some((tenant, complexity)) = &m.tenant {
+            let usage = self.tenant_usage.get_mut(tenant).unwrap();
+            usage.matchers -= 1;
+            usage.complexity -= complexity;
+            if usage.matchers == 0 {
+                self.tenant_usage.remove(tenant);
+            }
+        }
+
+        Some((key, m))
+    }
+
+    /// Indexes the literal prefixes matchers require on the `String` field
+    /// `field` (such as `http.path`), replacing any previous prefilter.
+    ///
+    /// [`Router::execute`] and [`Router::execute_all`] then skip matchers
+    /// that can not match the field's values without evaluating them.
+    /// Prefixes are taken from `==`, `^=` and `in` predicates and from
+    /// regexes starting with `^` and literal text, see
+    /// [`literal_prefixes`]. Matchers without such predicates are always
+    /// evaluated. The evaluation order is not affected.
+    ///
+    /// Contexts without a value for `field`, such as non-HTTP traffic through
+    /// a router prefiltering `http.path`, only evaluate the matchers that do
+    /// not require the field, see [`RequiredFields`], and without the
+    /// prefilter. The others are counted in
+    /// [`ExecutionStats::matchers_missing_prefilter_field`](crate::context::ExecutionStats::matchers_missing_prefilter_field).
+    pub fn enable_prefilter(&mut self, field: &str) {
+        let mut prefilter = RouterPrefilter {
+            field: field.to_string(),
+            suffixes: false,
+            index: InnerPrefilter::new(),
+        };
+        for (key, m) in &self.matchers {
+            prefilter.insert(*key, &m.expr);
+        }
+
+        self.prefilter = Some(prefilter);
+    }
+
+    pub fn disable_prefilter(&mut self) {
+        self.prefilter = None;
+    }
+
+    /// The field passed to [`Router::enable_prefilter`], if enabled.
+    pub fn prefilter_field(&self) -> Option<&str> {
+        self.prefilter.as_ref().map(|p| p.field.as_str())
+    }
+
+    /// Like [`Router::enable_prefilter`], indexing the literal suffixes
+    /// matchers require on `field` (such as `http.host`) instead, replacing
+    /// any previous suffix filter.
+    ///
+    /// Suffixes are taken from `==`, `=^` and `in` predicates, from regexes
+    /// anchored with `$` and from globs ending with literal text, such as
+    /// `*.example.com`, see [`literal_suffixes`]. The suffix filter and the prefilter can be
+    /// enabled at the same time, on the same field or not.
+    pub fn enable_suffix_filter(&mut self, field: &str) {
+        let mut filter = RouterPrefilter {
+            field: field.to_string(),
+            suffixes: true,
+            index: InnerPrefilter::new(),
+        };
+        for (key, m) in &self.matchers {
+            filter.insert(*key, &m.expr);
+        }
+
+        self.suffix_filter = Some(filter);
+    }
+
+    pub fn disable_suffix_filter(&mut self) {
+        self.suffix_filter = None;
+    }
+
+    /// The field passed to [`Router::enable_suffix_filter`], if enabled.
+    pub fn suffix_filter_field(&self) -> Option<&str> {
+        self.suffix_filter.as_ref().map(|f| f.field.as_str())
+    }
+
+    /// Indexes the values matchers require on the `String` field `field`
+    /// (such as `http.host`) in a hash map, replacing any previous equality
+    /// index.
+    ///
+    /// [`Router::execute`] and [`Router::execute_all`] then look each of
+    /// the field's values up once and skip the matchers requiring other
+    /// values without evaluating them. Values are taken from `==` and `in`
+    /// predicates, see [`literal_values`]. Matchers without such predicates
+    /// are always evaluated. The evaluation order is not affected.
+    ///
+    /// Unlike the [prefilter](Router::enable_prefilter), the cost of a
+    /// lookup does not depend on the length of the values, which suits
+    /// fields with thousands of distinct exact values.
+    pub fn enable_equality_index(&mut self, field: &str) {
+        let mut index = RouterEqualityIndex {
+            field: field.to_string(),
+            index: EqualityIndex::new(),
+        };
+        for (key, m) in &self.matchers {
+            index.insert(*key, &m.expr);
+        }
+
+        self.equality_index = Some(index);
+    }
+
+    pub fn disable_equality_index(&mut self) {
+        self.equality_index = None;
+    }
+
+    /// The field passed to [`Router::enable_equality_index`], if enabled.
+    pub fn equality_index_field(&self) -> Option<&str> {
+        self.equality_index.as_ref().map(|i| i.field.as_str())
+    }
+
+    /// Indexes the CIDRs matchers require on the `IpAddr` field `field`
+    /// (such as `net.src.ip`) in a binary trie, replacing any previous CIDR
+    /// index.
+    ///
+    /// [`Router::execute`] and [`Router::execute_all`] then look the
+    /// field's addresses up once and skip the matchers that can not match
+    /// them without evaluating them: the ones requiring an address in CIDRs
+    /// none of them is in, see [`required_cidrs`], and the ones excluding
+    /// CIDRs one of them is in, see [`excluded_cidrs`]. Matchers without
+    /// such predicates are always evaluated. The evaluation order is not
+    /// affected.
+    pub fn enable_cidr_index(&mut self, field: &str) {
+        let mut index = RouterCidrIndex {
+            field: field.to_string(),
+            required: CidrIndex::new(),
+            excluded: CidrIndex::new(),
+        };
+        for (key, m) in &self.matchers {
+            index.insert(*key, &m.expr);
+        }
+
+        self.cidr_index = Some(index);
+    }
+
+    pub fn disable_cidr_index(&mut self) {
+        self.cidr_index = None;
+    }
+
+    /// The field passed to [`Router::enable_cidr_index`], if enabled.
+    pub fn cidr_index_field(&self) -> Option<&str> {
+        self.cidr_index.as_ref().map(|i| i.field.as_str())
+    }
+
+    /// Matches the values of a context against all the regexes matchers
+    /// require at once, using one [`RegexSet`] per field.
+    ///
+    /// [`Router::execute`] and [`Router::execute_all`] then skip matchers
+    /// requiring a regex no value of its field matches, only evaluating
+    /// (and extracting captures for) the others. A regex is required when
+    /// it is a `~` predicate without `lower()` that is not under `||` or
+    /// `!`, see [`required_regexes`]. This pays off for routers with many
+    /// such matchers on the same field. The index is rebuilt on the first
+    /// execution after matchers are added or removed, unless
+    /// [`Router::maintenance`] rebuilt it before.
+    pub fn enable_regex_index(&mut self) {
+        self.regex_index = Some(OnceLock::new());
+        self.regex_index_builder = None;
+    }
+
+    pub fn disable_regex_index(&mut self) {
+        self.regex_index = None;
+        self.regex_index_builder = None;
+    }
+
+    fn invalidate_regex_index(&mut self) {
+        if self.regex_index.is_some() {
+            self.regex_index = Some(OnceLock::new());
+            self.regex_index_builder = None;
+        }
+    }
+
+    /// Rebuilds the indexes invalidated by changes to the matchers, working
+    /// for about `budget` at most, so that neither the caller nor the next
+    /// [`Router::execute`] pauses for a full rebuild.
+    ///
+    /// Hosts call this periodically, e.g. from a timer after each
+    /// configuration push, until it returns [`MaintenanceProgress::Done`].
+    /// Work done so far is kept between calls and discarded when matchers
+    /// change. An execution finding an index not yet rebuilt still builds it
+    /// at once. At least one step is done whatever the budget, a single step
+    /// compiles the regexes of one field.
+    ///
+    /// Only the [regex index](Router::enable_regex_index) needs rebuilding.
+    /// The prefilter, the suffix filter, the equality index, the CIDR index
+    /// and the DAG are updated matcher by matcher as matchers are added and
+    /// removed, including by [`RouterUpdate::commit`].
+    pub fn maintenance(&mut self, budget: Duration) -> MaintenanceProgress {
+        let Some(index) = &self.regex_index else {
+            return MaintenanceProgress::Done;
+        };
+        if index.get().is_some() {
+            self.regex_index_builder = None;
+            return MaintenanceProgress::Done;
+        }
+
+        // a budget too large to represent is no budget at all
+        let deadline = Instant::now().checked_add(budget);
+        let builder = self
+            .regex_index_builder
+            .get_or_insert_with(Default::default);
+        loop {
+            if builder.step(&self.matchers) {
+                let builder = self.regex_index_builder.take().unwrap();
+                // can not fail, `index` was empty and `self` is borrowed
+                // mutably
+                let _ = index.set(builder.finish());
+                return MaintenanceProgress::Done;
+            }
+
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return MaintenanceProgress::Pending;
+            }
+        }
+    }
+
+    pub fn regex_index_enabled(&self) -> bool {
+        self.regex_index.is_some()
+    }
+
+    /// Returns a snapshot of every matcher, in evaluation order.
+    #[cfg(feature = "serde")]
+    pub fn to_document(&self) -> RouterDocument {
+        let mut regexes = Vec::new();
+        for m in self.matchers.values() {
+            collect_regexes(&m.expr, &mut regexes);
+        }
+        regexes.sort_unstable();
+        regexes.dedup();
+
+        RouterDocument {
+            version: RouterDocument::VERSION,
+            regexes: regexes.into_iter().map(String::from).collect(),
+            matchers: self
+                .matchers
+                .iter()
+                .rev()
+                .map(|(k, m)| MatcherDocument {
+                    priority: k.0.major,
+                    minor: k.0.minor,
+                    uuid: k.2,
+                    expression: m.expr.clone().expand(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds every matcher of `doc` with [`Router::add_matcher_expr`].
+    ///
+    /// Stops at the first matcher that can not be added, the ones before it
+    /// stay in the router.
+    #[cfg(feature = "serde")]
+    pub fn add_document(&mut self, doc: RouterDocument) -> Result<(), RouterError> {
+        for m in doc.matchers {
+            self.add_matcher_expr_at(Priority::new(m.priority, m.minor), m.uuid, m.expression)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a binary snapshot of every matcher, including the parsed
+    /// expressions, and of the field counters, see [`Router::deserialize`].
+    ///
+    /// Unlike [`RouterDocument`], the format is only meant to be read back
+    /// by the same release of this library.
+    #[cfg(feature = "serde")]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut fields: Vec<_> = self
+            .fields
+            .iter()
+            .map(|(f, n)| (f.to_string(), n))
+            .collect();
+        fields.sort_unstable();
+
+        let snapshot = RouterSnapshot {
+            fields,
+            matchers: self
+                .matchers
+                .iter()
+                .rev()
+                .map(|(k, m)| (k.0.major, k.0.minor, k.2, m.expr.clone()))
+                .collect(),
+        };
+
+        let mut bytes = bincode::serialize(&RouterSnapshot::VERSION).unwrap();
+        bincode::serialize_into(&mut bytes, &snapshot).unwrap();
+        bytes
+    }
+
+    /// Rebuilds a router from the output of [`Router::serialize`] without
+    /// parsing any ATC expression.
+    ///
+    /// The expressions are still validated against `schema` and their
+    /// regexes compiled, through the [`regex_cache`](crate::regex_cache).
+    /// Fails with [`RouterError::InvalidSnapshot`] if `bytes` were written
+    /// by another format version, are corrupted, or describe field counters
+    /// that do not match the matchers.
+    #[cfg(feature = "serde")]
+    pub fn deserialize(schema: &'a Schema, bytes: &[u8]) -> Result<Self, RouterError> {
+        let invalid = |e: bincode::Error| RouterError::InvalidSnapshot(e.to_string());
+
+        let mut reader = bytes;
+        let version: u32 = bincode::deserialize_from(&mut reader).map_err(invalid)?;
+        if version != RouterSnapshot::VERSION {
+            return Err(RouterError::InvalidSnapshot(format!(
+                "unsupported version {}, expected {}",
+                version,
+                RouterSnapshot::VERSION
+            )));
+        }
+        let snapshot: RouterSnapshot = bincode::deserialize_from(&mut reader).map_err(invalid)?;
+        if !reader.is_empty() {
+            return Err(RouterError::InvalidSnapshot("trailing bytes".to_string()));
+        }
+
+        let mut router = Router::new(schema);
+        for (major, minor, uuid, expr) in snapshot.matchers {
+            router.add_matcher_expr_at(Priority::new(major, minor), uuid, expr)?;
+        }
+
+        if router.fields.len() != snapshot.fields.len()
+            || snapshot
+                .fields
+                .iter()
+                .any(|(f, n)| router.fields.get(f) != *n)
+        {
+            return Err(RouterError::InvalidSnapshot(
+                "field counters do not match the matchers".to_string(),
+            ));
+        }
+
+        Ok(router)
+    }
+
+    /// Returns every matcher as `(priority, uuid, expression)`, in
+    /// evaluation order. `priority` is the [`Priority::major`] part, see
+    /// [`Router::priority_of`] for the full priority.
+    pub fn matchers(&self) -> impl Iterator<Item = (usize, Uuid, &Expression)> + '_ {
+        self.matchers
+            .iter()
+            .rev()
+            .map(|(k, m)| (k.0.major, k.2, &m.expr))
+    }
+
+    /// Returns the `(priority, uuid)` of every matcher, in the order
+    /// [`Router::execute`] evaluates them, see "Evaluation order" above.
+    /// `priority` is the [`Priority::major`] part.
+    pub fn evaluation_order(&self) -> Vec<(usize, Uuid)> {
+        self.matchers
+            .keys()
+            .rev()
+            .map(|k| (k.0.major, k.2))
+            .collect()
+    }
+
+    /// Returns the matchers that do not validate against `schema`, as
+    /// `(priority, uuid, error)` in evaluation order.
+    ///
+    /// An empty result means the router could be rebuilt against `schema`
+    /// unchanged, see [`Router::check_remove_field`].
+    pub fn breaking_matchers(&self, schema: &Schema) -> Vec<(usize, Uuid, ValidationError)> {
+        self.matchers()
+            .filter_map(|(priority, uuid, expr)| {
+                expr.validate(schema).err().map(|e| (priority, uuid, e))
+            })
+            .collect()
+    }
+
+    /// Returns the matchers, as `(priority, uuid)` in evaluation order, that
+    /// would stop validating if `field` was removed from the router's
+    /// schema with [`Schema::remove_field`]. Refuse the change unless it is
+    /// empty.
+    pub fn check_remove_field(&self, field: &str) -> Vec<(usize, Uuid)> {
+        let mut schema = Schema::clone(&self.schema);
+        schema.remove_field(field);
+        self.broken_keys(&schema)
+    }
+
+    /// Like [`Router::check_remove_field`], for changing the type of
+    /// `field` with [`Schema::replace_field_type`].
+    pub fn check_replace_field_type(&self, field: &str, typ: Type) -> Vec<(usize, Uuid)> {
+        let mut schema = Schema::clone(&self.schema);
+        schema.replace_field_type(field, typ);
+        self.broken_keys(&schema)
+    }
+
+    fn broken_keys(&self, schema: &Schema) -> Vec<(usize, Uuid)> {
+        self.breaking_matchers(schema)
+            .into_iter()
+            .map(|(priority, uuid, _)| (priority, uuid))
+            .collect()
+    }
+
+    /// Returns every field the matcher with this `priority` and `uuid`
+    /// reads, `None` if there is no such matcher.
+    ///
+    /// Unlike [`Router::fields`], which counts the fields of all matchers,
+    /// this is what a host needs to generate the context values of a single
+    /// route on demand.
+    pub fn fields_of(&self, priority: usize, uuid: Uuid) -> Option<HashSet<&str>> {
+        let m = self.matchers.get(&self.key(priority.into(), uuid))?;
+
+        let mut fields = HashSet::new();
+        collect_fields(&m.expr, &mut fields);
+        Some(fields)
+    }
+
+    /// Returns the priority of every matcher with this `uuid`, in
+    /// evaluation order.
+    pub fn priority_of(&self, uuid: Uuid) -> impl Iterator<Item = Priority> + '_ {
+        self.matchers
+            .keys()
+            .rev()
+            .filter(move |k| k.2 == uuid)
+            .map(|k| k.0)
+    }
+
+    /// Whether a matcher with this `priority` and `uuid` exists.
+    pub fn contains(&self, priority: usize, uuid: Uuid) -> bool {
+        self.contains_at(priority.into(), uuid)
+    }
+
+    pub fn contains_at(&self, priority: Priority, uuid: Uuid) -> bool {
+        self.matchers.contains_key(&self.key(priority, uuid))
+    }
+
+    /// Executes the router against `context`, storing the first match found
+    /// in [`Context::result`].
+    ///
+    /// Matchers that reference a field the context has no value for in a
+    /// way that can never be satisfied (see [`RequiredFields`]) are skipped
+    /// without being evaluated, which keeps routers shared between protocols
+    /// cheap for requests that lack e.g. `http.*` fields entirely. The
+    /// number of evaluated and skipped matchers is recorded in
+    /// [`Context::stats`]. Matchers ruled out by the prefilter, the suffix
+    /// filter, the equality index or the CIDR index, see
+    /// [`Router::enable_prefilter`], [`Router::enable_suffix_filter`],
+    /// [`Router::enable_equality_index`] and [`Router::enable_cidr_index`],
+    /// are skipped as well. Predicates shared by several matchers, such as
+    /// `net.protocol == "http"`, are evaluated at most once, see
+    /// [`ExecutionStats::predicates_memoized`](crate::context::ExecutionStats::predicates_memoized).
+    pub fn execute(&self, context: &mut Context) -> bool {
+        self.execute_until(context, None, 0..=usize::MAX)
+            .expect("no deadline to exceed")
+    }
+
+    /// Like [`Router::execute`], only evaluating the matchers whose major
+    /// [`Priority`] is within `priorities`, such as one tenant's slice of
+    /// the table or the matchers of a staged rollout. The others are neither
+    /// evaluated nor counted in [`Context::stats`].
+    ///
+    /// Hit counters and the [`Router::set_metrics_sink`] see the call as any
+    /// other execution, so a miss within the range counts as a miss.
+    pub fn execute_range(&self, context: &mut Context, priorities: RangeInclusive<usize>) -> bool {
+        self.execute_until(context, None, priorities)
+            .expect("no deadline to exceed")
+    }
+
+    /// Like [`Router::execute`], giving up once `deadline` has passed.
+    ///
+    /// The deadline is checked before each matcher is evaluated, so a single
+    /// slow matcher can still overrun it. On [`DeadlineExceeded`],
+    /// [`Context::result`] is left untouched and the miss is not counted by
+    /// [`Router::miss_count`].
+    pub fn execute_deadline(
+        &self,
+        context: &mut Context,
+        deadline: Instant,
+    ) -> Result<bool, DeadlineExceeded> {
+        self.execute_until(context, Some(deadline), 0..=usize::MAX)
+    }
+
+    fn execute_until(
+        &self,
+        context: &mut Context,
+        deadline: Option<Instant>,
+        priorities: RangeInclusive<usize>,
+    ) -> Result<bool, DeadlineExceeded> {
+        context.with_memo(|context| self.execute_memoized(context, deadline, priorities))
+    }
+
+    fn execute_memoized(
+        &self,
+        context: &mut Context,
+        deadline: Option<Instant>,
+        priorities: RangeInclusive<usize>,
+    ) -> Result<bool, DeadlineExceeded> {
+        #[cfg(feature = "hit-counters")]
+        let started = Instant::now();
+        let measured = self
+            .metrics_sink
+            .as_ref()
+            .map(|_| (Instant::now(), context.stats.predicates_evaluated));
+        let candidates = self.candidates(context);
+        let mut trace = self
+            .trace_sampler
+            .as_ref()
+            .filter(|s| s.sample())
+            .map(|_| Vec::new());
+
+        for (key, m) in self.matchers_in(priorities).rev() {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(DeadlineExceeded);
+            }
+
+            if let Some(mat) = self.try_match(key, m, &candidates, context, trace.as_mut()) {
+                context.result = Some(mat);
+                self.record_trace(context, trace);
+                self.report_metrics(context, measured, true);
+
+                #[cfg(feature = "hit-counters")]
+                {
+                    m.hits.fetch_add(1, Ordering::Relaxed);
+                    let tier = self
+                        .priority_bands
+                        .iter()
+                        .position(|b| b.range.contains(&key.0.major))
+                        .unwrap_or(self.priority_bands.len());
+                    self.latencies[tier].record(started);
+                }
+
+                return Ok(true);
+            }
+        }
+
+        #[cfg(feature = "hit-counters")]
+        {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            self.latencies[self.priority_bands.len() + 1].record(started);
+        }
+        self.record_trace(context, trace);
+        self.report_metrics(context, measured, false);
+
+        Ok(false)
+    }
+
+    /// The matchers whose major priority is within `priorities`, in key
+    /// order.
+    fn matchers_in(
+        &self,
+        priorities: RangeInclusive<usize>,
+    ) -> btree_map::Range<'_, MatcherKey, Matcher> {
+        let key = |major| MatcherKey(Priority::new(major, 0), 0, Uuid::nil());
+        let start = key(*priorities.start());
+        if priorities.is_empty() {
+            return self.matchers.range((Included(start), Excluded(start)));
+        }
+
+        let end = match priorities.end().checked_add(1) {
+            Some(major) => Excluded(key(major)),
+            None => Unbounded,
+        };
+        self.matchers.range((Included(start), end))
+    }
+
+    /// Reports an execution to the metrics sink, `measured` being when it
+    /// started and the predicates evaluated by then.
+    fn report_metrics(&self, context: &Context, measured: Option<(Instant, usize)>, matched: bool) {
+        if let (Some(sink), Some((started, predicates))) = (&self.metrics_sink, measured) {
+            sink.on_execute(
+                started.elapsed(),
+                matched,
+                context.stats.predicates_evaluated - predicates,
+            );
+        }
+    }
+
+    fn record_trace(&self, context: &Context, steps: Option<Vec<TraceStep>>) {
+        if let (Some(sampler), Some(steps)) = (&self.trace_sampler, steps) {
+            sampler.record(ExecutionTrace::new(context, steps));
+        }
+    }
+
+    /// Executes the router against `context` without stopping at the first
+    /// match, returning every matching matcher in evaluation order (see
+    /// [`Router`]).
+    ///
+    /// [`Context::result`] is left untouched and hit counters are not
+    /// updated, only [`Context::stats`] is.
+    pub fn execute_all(&self, context: &mut Context) -> Vec<Match> {
+        context.with_memo(|context| {
+            let candidates = self.candidates(context);
+
+            self.matchers
+                .iter()
+                .rev()
+                .filter_map(|(key, m)| self.try_match(key, m, &candidates, context, None))
+                .collect()
+        })
+    }
+
+    /// Returns, in evaluation order, the `(priority, uuid)` of the matchers
+    /// the indexes do not rule out for `context`, see
+    /// [`Router::enable_prefilter`] and the other indexes. They are checked
+    /// as the iterator advances, so stopping early costs nothing for the
+    /// remaining ones, however many candidates there are.
+    ///
+    /// Nothing is evaluated: candidates may still lack a required field, be
+    /// quarantined or not match. Without any index every matcher is one.
+    pub fn candidate_keys(
+        &self,
+        context: &mut Context,
+    ) -> impl Iterator<Item = (usize, Uuid)> + '_ {
+        let candidates = self.candidates(context);
+
+        self.matchers
+            .keys()
+            .rev()
+            .filter(move |key| !self.prefiltered(key, &candidates))
+            .map(|key| (key.0.major, key.2))
+    }
+
+    /// Whether `context` has values, or may get some from its provider, for
+    /// every field `m` requires.
+    fn has_required_fields(&self, m: &Matcher, context: &Context) -> bool {
+        let has = |f: &String| context.has_values(f) || context.may_provide(f);
+        if !ptr::eq(context.schema(), &*self.schema) {
+            // field ids are only the same within one schema
+            return m.expr.required_fields().iter().all(has);
+        }
+
+        context.has_fields(&m.required_fields) && m.required_wildcards.iter().all(has)
+    }
+
+    /// Whether `m` requires `field`, see [`RequiredFields`].
+    fn requires(&self, m: &Matcher, field: &str) -> bool {
+        match self.schema.field_id(field) {
+            Some(id) => m.required_fields.contains(id),
+            None => m.required_wildcards.iter().any(|f| f == field),
+        }
+    }
+
+    fn candidates(&self, context: &mut Context) -> Candidates<'_> {
+        // indexes need the values of their fields up front
+        if let Some(prefilter) = &self.prefilter {
+            context.resolve(&prefilter.field);
+        }
+        if let Some(filter) = &self.suffix_filter {
+            context.resolve(&filter.field);
+        }
+        if let Some(index) = &self.equality_index {
+            context.resolve(&index.field);
+        }
+        if let Some(index) = &self.cidr_index {
+            context.resolve(&index.field);
+        }
+        if let Some(index) = self.regex_index() {
+            for (field, _, _) in &index.sets {
+                context.resolve(field);
+            }
+        }
+
+        let (cidrs, excluded_cidrs) = self
+            .cidr_index
+            .as_ref()
+            .map(|i| i.candidates(context))
+            .unzip();
+
+        let (prefixes, missing_field) = match &self.prefilter {
+            Some(p) if context.value_of(&p.field).is_none() => (None, Some(p.field.as_str())),
+            Some(p) => (Some(p.candidates(context)), None),
+            None => (None, None),
+        };
+
+        Candidates {
+            prefixes,
+            missing_field,
+            suffixes: self.suffix_filter.as_ref().map(|f| f.candidates(context)),
+            values: self.equality_index.as_ref().map(|i| i.candidates(context)),
+            cidrs,
+            excluded_cidrs,
+            regexes: self
+                .regex_index()
+                .map(|index| index.matched(context))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether one of the indexes rules the matcher out.
+    fn prefiltered(&self, key: &MatcherKey, candidates: &Candidates) -> bool {
+        if let (Some(prefilter), Some(prefixes)) = (&self.prefilter, &candidates.prefixes) {
+            if prefilter.skips(key, prefixes) {
+                return true;
+            }
+        }
+
+        if let (Some(filter), Some(suffixes)) = (&self.suffix_filter, &candidates.suffixes) {
+            if filter.skips(key, suffixes) {
+                return true;
+            }
+        }
+
+        if let (Some(index), Some(values)) = (&self.equality_index, &candidates.values) {
+            if index.skips(key, values) {
+                return true;
+            }
+        }
+
+        if let Some(index) = &self.cidr_index {
+            if index.skips(key, candidates) {
+                return true;
+            }
+        }
+
+        self.regex_index()
+            .is_some_and(|index| index.skips(key, &candidates.regexes))
+    }
+
+    fn regex_index(&self) -> Option<&RegexIndex> {
+        self.regex_index
+            .as_ref()
+            .map(|index| index.get_or_init(|| RegexIndex::build(&self.matchers)))
+    }
+
+    /// Evaluates one matcher, appending what happened to it to `trace`.
+    fn try_match(
+        &self,
+        key: &MatcherKey,
+        m: &Matcher,
+        candidates: &Candidates,
+        context: &mut Context,
+        trace: Option<&mut Vec<TraceStep>>,
+    ) -> Option<Match> {
+        let result = self.evaluate(key, m, candidates, context);
+
+        if let Some(trace) = trace {
+            trace.push(TraceStep {
+                priority: key.0,
+                uuid: key.2,
+                outcome: match &result {
+                    Ok(_) => TraceOutcome::Matched,
+                    Err(outcome) => *outcome,
+                },
+            });
+        }
+
+        result.ok()
+    }
+
+    fn evaluate(
+        &self,
+        key: &MatcherKey,
+        m: &Matcher,
+        candidates: &Candidates,
+        context: &mut Context,
+    ) -> Result<Match, TraceOutcome> {
+        if candidates
+            .missing_field
+            .is_some_and(|field| self.requires(m, field))
+        {
+            context.stats.matchers_skipped += 1;
+            context.stats.matchers_missing_prefilter_field += 1;
+            return Err(TraceOutcome::MissingField);
+        }
+
+        if !self.has_required_fields(m, context) {
+            context.stats.matchers_skipped += 1;
+            return Err(TraceOutcome::MissingField);
+        }
+
+        if self.prefiltered(key, candidates) {
+            context.stats.matchers_prefiltered += 1;
+            return Err(TraceOutcome::Prefiltered);
+        }
+
+        if self.is_quarantined(m) {
+            context.stats.matchers_quarantined += 1;
+            return Err(TraceOutcome::Quarantined);
+        }
+
+        context.stats.matchers_evaluated += 1;
+
+        let context_mode = context.capture_mode();
+        let mode = m.capture_mode.unwrap_or(self.default_capture_mode);
+        context.set_capture_mode(context_mode.restrict(mode));
+
+        let mut mat = Match::new();
+        let matched = m.execute(&self.dag, context, &mut mat).map_err(|e| {
+            m.errors.fetch_add(1, Ordering::Relaxed);
+            context.stats.eval_errors += 1;
+            if let Some(hook) = &self.error_hook {
+                hook(key.2, &e);
+            }
+            TraceOutcome::Failed
+        });
+        context.set_capture_mode(context_mode);
+        if !matched? {
+            return Err(TraceOutcome::NoMatch);
+        }
+
+        mat.uuid = key.2;
+        mat.band = self.band_of(key.0.major).map(|b| b.label.clone());
+        Ok(mat)
+    }
+
+    /// Returns how many times each matcher won an [`Router::execute`] call
+    /// since it was added or since the last [`Router::reset_hit_counts`],
+    /// as `(priority, uuid, hits)` in evaluation order.
+    #[cfg(feature = "hit-counters")]
+    pub fn hit_counts(&self) -> Vec<(usize, Uuid, u64)> {
+        self.matchers
+            .iter()
+            .rev()
+            .map(|(k, m)| (k.0.major, k.2, m.hits.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Returns how many [`Router::execute`] calls did not match anything.
+    #[cfg(feature = "hit-counters")]
+    pub fn miss_count(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long the [`Router::execute`] calls that matched took,
+    /// bucketed by the priority tier of the winning matcher: one entry per
+    /// declared band, in [`Router::priority_bands`] order, then one labelled
+    /// `None` for matchers outside every band.
+    ///
+    /// Together with [`Router::miss_latency`] this shows whether time goes
+    /// to high priority matches or to falling through to catch-all routes.
+    #[cfg(feature = "hit-counters")]
+    pub fn tier_latencies(&self) -> Vec<(Option<Arc<str>>, LatencyHistogram)> {
+        self.priority_bands
+            .iter()
+            .map(|b| Some(b.label.clone()))
+            .chain([None])
+            .zip(&self.latencies)
+            .map(|(label, h)| (label, h.load()))
+            .collect()
+    }
+
+    /// Returns how long the [`Router::execute`] calls that did not match
+    /// anything took.
+    #[cfg(feature = "hit-counters")]
+    pub fn miss_latency(&self) -> LatencyHistogram {
+        self.latencies[self.priority_bands.len() + 1].load()
+    }
+
+    /// Resets hit and miss counters as well as latency histograms.
+    #[cfg(feature = "hit-counters")]
+    pub fn reset_hit_counts(&self) {
+        for m in self.matchers.values() {
+            m.hits.store(0, Ordering::Relaxed);
+        }
+        self.misses.store(0, Ordering::Relaxed);
+        for h in &self.latencies {
+            h.reset();
+        }
+    }
+}
+
+/// A tenant and the complexity charged to it for one matcher.
+type TenantCharge = (Arc<str>, usize);
+
+enum UpdateOp {
+    Add(Priority, Uuid, Expression),
+    Remove(Priority, Uuid),
+}
+
+/// A batch of changes to a [`Router`], see [`Router::begin_update`].
+///
+/// Every change is checked against the router as it will be once the
+/// changes staged before it are applied, so [`RouterUpdate::commit`] can not
+/// fail: the update borrows the router mutably, nothing else can change it
+/// in between.
+pub struct RouterUpdate<'r, 'a> {
+    router: &'r mut Router<'a>,
+    ops: Vec<UpdateOp>,
+    /// Matchers the batch adds and does not remove again.
+    added: HashSet<(Priority, Uuid)>,
+    /// Existing matchers the batch removes.
+    removed: HashSet<(Priority, Uuid)>,
+    /// Tenant of every matcher the batch changes, as of its last change.
+    tenants: HashMap<(Priority, Uuid), Option<TenantCharge>>,
+    /// Usage of the tenants the batch changes, once committed.
+    tenant_usage: HashMap<Arc<str>, TenantUsage>,
+}
+
+impl RouterUpdate<'_, '_> {
+    fn contains(&self, id: &(Priority, Uuid)) -> bool {
+        self.added.contains(id)
+            || (self.router.contains_at(id.0, id.1) && !self.removed.contains(id))
+    }
+
+    /// Number of matchers the router will hold once committed.
+    pub fn len(&self) -> usize {
+        self.router.matchers.len() - self.removed.len() + self.added.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Stages a matcher, failing for the same reasons
+    /// [`Router::add_matcher`] would.
+    pub fn add_matcher(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.add_matcher_at(priority.into(), uuid, atc)
+    }
+
+    /// Like [`RouterUpdate::add_matcher`], with a two level [`Priority`].
+    pub fn add_matcher_at(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        let ast = self.router.parse(atc)?;
+
+        self.add_matcher_expr_at(priority, uuid, ast)
+    }
+
+    /// Stages an already parsed matcher, see [`Router::add_matcher_expr`].
+    pub fn add_matcher_expr(
+        &mut self,
+        priority: usize,
+        uuid: Uuid,
+        ast: Expression,
+    ) -> Result<(), RouterError> {
+        self.add_matcher_expr_at(priority.into(), uuid, ast)
+    }
+
+    /// Like [`RouterUpdate::add_matcher_expr`], with a two level [`Priority`].
+    pub fn add_matcher_expr_at(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        ast: Expression,
+    ) -> Result<(), RouterError> {
+        self.stage_add(priority, uuid, ast, None)
+    }
+
+    /// Stages a matcher accounted to `tenant`, failing for the same reasons
+    /// [`Router::add_tenant_matcher`] would. The quota is checked against
+    /// the tenant's usage once the changes staged so far are applied.
+    pub fn add_tenant_matcher(
+        &mut self,
+        tenant: &str,
+        priority: usize,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        self.add_tenant_matcher_at(tenant, priority.into(), uuid, atc)
+    }
+
+    /// Like [`RouterUpdate::add_tenant_matcher`], with a two level
+    /// [`Priority`].
+    pub fn add_tenant_matcher_at(
+        &mut self,
+        tenant: &str,
+        priority: Priority,
+        uuid: Uuid,
+        atc: &str,
+    ) -> Result<(), RouterError> {
+        let ast = self.router.parse(atc)?;
+
+        self.stage_add(priority, uuid, ast, Some(tenant))
+    }
+
+    /// The tenant a matcher is accounted to once the changes staged so far
+    /// are applied.
+    fn staged_tenant(&self, id: &(Priority, Uuid)) -> Option<TenantCharge> {
+        match self.tenants.get(id) {
+            Some(tenant) => tenant.clone(),
+            None => self
+                .router
+                .matchers
+                .get(&self.router.key(id.0, id.1))
+                .and_then(|m| m.tenant.clone()),
+        }
+    }
+
+    fn staged_usage(&self, tenant: &str) -> TenantUsage {
+        self.tenant_usage
+            .get(tenant)
+            .copied()
+            .unwrap_or_else(|| self.router.tenant_usage(tenant))
+    }
+
+    /// Records `tenant` as the tenant of `id`, crediting back the tenant it
+    /// was accounted to before.
+    fn set_staged_tenant(&mut self, id: (Priority, Uuid), tenant: Option<TenantCharge>) {
+        if let Some((old, complexity)) = self.staged_tenant(&id) {
+            let mut usage = self.staged_usage(&old);
+            usage.matchers -= 1;
+            usage.complexity -= complexity;
+            self.tenant_usage.insert(old, usage);
+        }
+        if let Some((new, complexity)) = &tenant {
+            let mut usage = self.staged_usage(new);
+            usage.matchers += 1;
+            usage.complexity += complexity;
+            self.tenant_usage.insert(new.clone(), usage);
+        }
+        self.tenants.insert(id, tenant);
+    }
+
+    fn stage_add(
+        &mut self,
+        priority: Priority,
+        uuid: Uuid,
+        ast: Expression,
+        tenant: Option<&str>,
+    ) -> Result<(), RouterError> {
+        let id = (priority, uuid);
+
+        if self.contains(&id) {
+            return Err(RouterError::DuplicateUuid(uuid));
+        }
+
+        if let Some(max) = self.router.max_matchers {
+            if self.len() >= max {
+                return Err(RouterError::LimitExceeded(max));
+            }
+        }
+
+        if !self.router.priority_bands.is_empty() && self.router.band_of(priority.major).is_none() {
+            return Err(RouterError::PriorityOutOfBand(priority.major));
+        }
+
+        ast.validate(&self.router.schema)
+            .map_err(RouterError::ValidationError)?;
+
+        let tenant = match tenant {
+            Some(tenant) => {
+                let complexity = ast.complexity();
+                let mut usage = self.staged_usage(tenant);
+                usage.matchers += 1;
+                usage.complexity += complexity;
+                self.router.check_quota(tenant, usage)?;
+                Some((Arc::from(tenant), complexity))
+            }
+            None => None,
+        };
+
+        self.removed.remove(&id);
+        if !self.router.contains_at(priority, uuid) {
+            self.added.insert(id);
+        }
+        self.set_staged_tenant(id, tenant);
+        self.ops.push(UpdateOp::Add(priority, uuid, ast));
+
+        Ok(())
+    }
+
+    /// Stages the removal of a matcher, returns `false` if it does not
+    /// exist (taking the changes staged so far into account).
+    pub fn remove_matcher(&mut self, priority: usize, uuid: Uuid) -> bool {
+        self.remove_matcher_at(priority.into(), uuid)
+    }
+
+    pub fn remove_matcher_at(&mut self, priority: Priority, uuid: Uuid) -> bool {
+        let id = (priority, uuid);
+
+        if !self.contains(&id) {
+            return false;
+        }
+
+        if !self.added.remove(&id) {
+            self.removed.insert(id);
+        }
+        self.set_staged_tenant(id, None);
+        self.ops.push(UpdateOp::Remove(priority, uuid));
+
+        true
+    }
+
+    /// Applies every staged change to the router.
+    ///
+    /// Only the last change staged for a matcher is applied, updating the
+    /// prefilter, the suffix filter, the equality and CIDR indexes and the
+    /// DAG like [`Router::remove_matcher`] and [`Router::add_matcher`] do.
+    /// The regex index is left for [`Router::maintenance`] to rebuild.
+    pub fn commit(self) {
+        let router = self.router;
+        let mut tenants = self.tenants;
+
+        // matchers in the order they were first staged, with their last
+        // change
+        let mut order = Vec::new();
+        let mut last = HashMap::new();
+        for op in self.ops {
+            let (id, ast) = match op {
+                UpdateOp::Add(priority, uuid, ast) => ((priority, uuid), Some(ast)),
+                UpdateOp::Remove(priority, uuid) => ((priority, uuid), None),
+            };
+            if last.insert(id, ast).is_none() {
+                order.push(id);
+            }
+        }
+
+        for (priority, uuid) in &order {
+            router.remove_matcher_at(*priority, *uuid);
+        }
+        for id in order {
+            if let Some(ast) = last.remove(&id).unwrap() {
+                let key = router.insert_matcher(id.0, id.1, ast);
+                // the tenant of the last change too
+                if let Some((tenant, complexity)) = tenants.remove(&id).flatten() {
+                    router.charge_tenant(key, tenant, complexity);
+                }
+            }
+        }
+    }
+
+    /// Discards every staged change, the same as dropping the update.
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::{Type, Value};
+    use crate::parser::parse;
+    use crate::regex_engine::{CompiledRegex, RegexCrateEngine};
+    use std::ops::Range;
+
+    #[test]
+    fn test_max_matchers() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.set_max_matchers(Some(2));
+        assert_eq!(router.max_matchers(), Some(2));
+
+        let uuids = [Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3)];
+        router
+            .add_matcher(0, uuids[0], r#"http.path == "/a""#)
+            .unwrap();
+        router
+            .add_matcher(1, uuids[1], r#"http.path == "/b""#)
+            .unwrap();
+
+        let err = router
+            .add_matcher(2, uuids[2], r#"http.path == "/c""#)
+            .unwrap_err();
+        assert_eq!(err, RouterError::LimitExceeded(2));
+        assert_eq!(err.to_string(), "maximum number of matchers (2) reached");
+        assert_eq!(router.len(), 2);
+
+        // removing a matcher frees up capacity again
+        assert!(router.remove_matcher(0, uuids[0]));
+        router
+            .add_matcher(2, uuids[2], r#"http.path == "/c""#)
+            .unwrap();
+
+        // lifting the limit allows further additions
+        router.set_max_matchers(None);
+        router
+            .add_matcher(0, uuids[0], r#"http.path == "/a""#)
+            .unwrap();
+        assert_eq!(router.len(), 3);
+    }
+
+    #[test]
+    fn test_router_errors() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        let uuid = Uuid::from_u128(1);
+
+        assert!(matches!(
+            router.add_matcher(0, uuid, "http.path ==").unwrap_err(),
+            RouterError::ParseError(_)
+        ));
+        match router
+            .add_matcher(0, uuid, "http.host == \"foo\"")
+            .unwrap_err()
+        {
+            RouterError::ValidationError(e) => {
+                assert_eq!(e.message, "Unknown LHS field");
+                assert_eq!(e.field.as_deref(), Some("http.host"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        router.add_matcher(0, uuid, r#"http.path == "/a""#).unwrap();
+        let err = router
+            .add_matcher(0, uuid, r#"http.path == "/a""#)
+            .unwrap_err();
+        assert_eq!(err, RouterError::DuplicateUuid(uuid));
+        assert_eq!(err.to_string(), "UUID already exists");
+    }
+
+    #[test]
+    fn test_priority_bands() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.add_priority_band("default", 0..=999_999).unwrap();
+        router
+            .add_priority_band("override", 1_000_000..=usize::MAX)
+            .unwrap();
+        assert_eq!(
+            router.add_priority_band("bad", 10..=20).unwrap_err(),
+            RouterError::PriorityBandOverlap("default".to_string())
+        );
+
+        router
+            .add_matcher(10, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        router
+            .add_matcher(1_000_000, Uuid::from_u128(2), r#"http.path == "/a""#)
+            .unwrap();
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/a".to_string().into());
+        assert!(router.execute(&mut ctx));
+        let res = ctx.result.as_ref().unwrap();
+        assert_eq!(res.uuid, Uuid::from_u128(2));
+        assert_eq!(res.band.as_deref(), Some("override"));
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/b".to_string().into());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().band.as_deref(), Some("default"));
+    }
+
+    #[test]
+    fn test_priority_out_of_band() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.add_priority_band("default", 0..=99).unwrap();
+
+        let err = router
+            .add_matcher(100, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap_err();
+        assert_eq!(err, RouterError::PriorityOutOfBand(100));
+        assert!(router.is_empty());
+    }
+
+    #[test]
+    fn test_shared_schema() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        let schema = Arc::new(schema);
+
+        // borrows nothing, so it can outlive this scope
+        let router: Router<'static> = {
+            let mut router = Router::with_schema(schema.clone().into());
+            router
+                .add_matcher(0, Uuid::from_u128(1), r#"http.path == "/a""#)
+                .unwrap();
+            router
+        };
+        drop(schema);
+
+        let mut ctx = Context::new(router.schema());
+        ctx.add_value_str("http.path", "/a");
+        assert!(router.execute(&mut ctx));
+    }
+
+    #[test]
+    fn test_schema_changes() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(2, Uuid::from_u128(1), r#"http.path == "/a""#)
+            .unwrap();
+        router
+            .add_matcher(
+                1,
+                Uuid::from_u128(2),
+                r#"http.headers.x == "a" || net.port == 80"#,
+            )
+            .unwrap();
+
+        assert!(router.check_remove_field("tls.sni").is_empty());
+        assert_eq!(
+            router.check_remove_field("http.headers.*"),
+            [(1, Uuid::from_u128(2))]
+        );
+        assert_eq!(
+            router.check_replace_field_type("http.path", Type::IpAddr),
+            [(2, Uuid::from_u128(1))]
+        );
+        assert!(router
+            .check_replace_field_type("net.port", Type::Int)
+            .is_empty());
+
+        let mut changed = schema.clone();
+        assert_eq!(changed.remove_field("net.port"), Some(Type::Int));
+        assert_eq!(changed.remove_field("net.port"), None);
+        assert_eq!(changed.replace_field_type("net.port", Type::String), None);
+        assert!(changed.type_of("net.port").is_none());
+        let broken = router.breaking_matchers(&changed);
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].1, Uuid::from_u128(2));
+        assert_eq!(broken[0].2.field.as_deref(), Some("net.port"));
+    }
+
+    #[cfg(feature = "hit-counters")]
+    #[test]
+    fn test_hit_counts() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(0, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(2), r#"http.path == "/a""#)
+            .unwrap();
+
+        for path in ["/a", "/a", "/b", "c"] {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", path.to_string().into());
+            router.execute(&mut ctx);
+        }
+
+        assert_eq!(
+            router.hit_counts(),
+            vec![(1, Uuid::from_u128(2), 2), (0, Uuid::from_u128(1), 1)]
+        );
+        assert_eq!(router.miss_count(), 1);
+
+        router.reset_hit_counts();
+        assert_eq!(
+            router.hit_counts(),
+            vec![(1, Uuid::from_u128(2), 0), (0, Uuid::from_u128(1), 0)]
+        );
+        assert_eq!(router.miss_count(), 0);
+
+        assert!(router.remove_matcher(1, Uuid::from_u128(2)));
+        assert_eq!(router.hit_counts(), vec![(0, Uuid::from_u128(1), 0)]);
+    }
+
+    #[cfg(feature = "hit-counters")]
+    #[test]
+    fn test_tier_latencies() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(5, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        router.add_priority_band("catch-all", 0..=9).unwrap();
+        router.add_priority_band("override", 100..=199).unwrap();
+        router
+            .add_matcher(100, Uuid::from_u128(2), r#"http.path == "/a""#)
+            .unwrap();
+
+        for path in ["/a", "/b", "/c", "c"] {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", path.to_string().into());
+            router.execute(&mut ctx);
+        }
+
+        let counts: Vec<_> = router
+            .tier_latencies()
+            .into_iter()
+            .map(|(label, h)| (label.map(|l| l.to_string()), h.count()))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                (Some("catch-all".to_string()), 2),
+                (Some("override".to_string()), 1),
+                (None, 0),
+            ]
+        );
+        let miss = router.miss_latency();
+        assert_eq!(miss.count(), 1);
+        assert!(miss.mean_ns() > 0);
+
+        router.reset_hit_counts();
+        assert!(router.tier_latencies().iter().all(|(_, h)| h.count() == 0));
+        assert_eq!(router.miss_latency(), LatencyHistogram::default());
+    }
+
+    #[test]
+    fn test_skip_missing_fields() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.dst.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(3, Uuid::from_u128(1), r#"http.path ^= "/a""#)
+            .unwrap();
+        router
+            .add_matcher(
+                2,
+                Uuid::from_u128(2),
+                r#"http.path == "/b" || net.dst.port == 80"#,
+            )
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(3), r#"!(http.path == "/c")"#)
+            .unwrap();
+        router
+            .add_matcher(0, Uuid::from_u128(4), r#"net.dst.port == 443"#)
+            .unwrap();
+
+        // no HTTP fields at all, only the matchers not requiring them run
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("net.dst.port", Value::Int(80));
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(2));
+        assert_eq!(ctx.stats.matchers_skipped, 1);
+        assert_eq!(ctx.stats.matchers_evaluated, 1);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("net.dst.port", Value::Int(443));
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(3));
+        assert_eq!(ctx.stats.matchers_skipped, 1);
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/c".to_string().into());
+        assert!(!router.execute(&mut ctx));
+        assert_eq!(ctx.stats.matchers_skipped, 1);
+        assert_eq!(ctx.stats.matchers_evaluated, 3);
+
+        // fields without an id, and the fields of contexts of another
+        // schema, are looked up by name
+        let mut schema = Schema::default();
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.dst.port", Type::Int);
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(
+                0,
+                Uuid::from_u128(5),
+                r#"http.headers.x == "1" && net.dst.port == 80"#,
+            )
+            .unwrap();
+        let other = schema.clone();
+        for schema in [&schema, &other] {
+            let mut ctx = Context::new(schema);
+            ctx.add_value("net.dst.port", Value::Int(80));
+            assert!(!router.execute(&mut ctx));
+            assert_eq!(ctx.stats.matchers_skipped, 1);
+
+            ctx.add_value("http.headers.x", "1".to_string().into());
+            assert!(router.execute(&mut ctx));
+            assert_eq!(ctx.stats.matchers_evaluated, 1);
+        }
+    }
+
+    /// Differential self-check: [`Router::execute`] must pick the same
+    /// matcher as naively evaluating every expression in priority order.
+    #[test]
+    fn test_execute_matches_naive_evaluation() {
+        use crate::corpus::{Corpus, Rng, Shape};
+        use crate::interpreter::Execute;
+
+        let mut schema = Corpus::schema();
+        schema.add_field("net.src.ip", Type::IpAddr);
+        schema.add_field("net.dst.port", Type::Int);
+
+        let mut corpus = Corpus::new(1, Shape::default());
+        let mut router = Router::new(&schema);
+        for (i, atc) in corpus.expressions(500).iter().enumerate() {
+            router
+                .add_matcher(i % 7, Uuid::from_u128(i as u128), atc)
+                .unwrap();
+        }
+        for (i, atc) in [
+            "net.src.ip in 10.0.0.0/8",
+            "net.src.ip in fd00::/8 && net.dst.port < 1024",
+            r#"net.dst.port == 443 || http.path ^= "/""#,
+            r#"!(http.method == "GET") && http.headers.x_api != """#,
+            "net.src.ip in (10.0.0.0/8, 192.168.0.0/16, fd00::/8)",
+            "net.src.ip not in (10.0.0.0/8, ::/0) && net.dst.port > 1000",
+            "any(net.src.ip) == 10.1.2.3 || net.src.ip in 172.16.0.0/12",
+            "net.src.ip != 10.1.2.3 && !(net.src.ip in 10.0.0.0/24)",
+            r#"http.host glob "*.example.com" && net.dst.port == 443"#,
+        ]
+        .iter()
+        .enumerate()
+        {
+            router
+                .add_matcher(i, Uuid::from_u128(1000 + i as u128), atc)
+                .unwrap();
+        }
+
+        let cidrs: Vec<_> = router
+            .matchers()
+            .flat_map(|(_, _, expr)| expr.cidr_literals())
+            .collect();
+
+        // and again with the prefilter on fields using every kind of
+        // predicate it extracts prefixes from, with the suffix filter, with
+        // the equality index, with the regex index and with the CIDR index
+        for (prefilter, suffix_filter, equality_index, regex_index, cidr_index) in [
+            (None, false, false, false, false),
+            (Some("http.path"), false, false, false, false),
+            (Some("http.host"), false, false, false, false),
+            (None, true, false, false, false),
+            (None, false, true, false, false),
+            (None, false, false, true, false),
+            (Some("http.path"), false, false, true, false),
+            (None, false, false, false, true),
+            (Some("http.path"), true, true, true, true),
+        ] {
+            match prefilter {
+                Some(field) => router.enable_prefilter(field),
+                None => router.disable_prefilter(),
+            }
+            if suffix_filter {
+                router.enable_suffix_filter("http.host");
+            } else {
+                router.disable_suffix_filter();
+            }
+            if equality_index {
+                router.enable_equality_index("http.host");
+            } else {
+                router.disable_equality_index();
+            }
+            if cidr_index {
+                router.enable_cidr_index("net.src.ip");
+            } else {
+                router.disable_cidr_index();
+            }
+            if regex_index {
+                router.enable_regex_index();
+            } else {
+                router.disable_regex_index();
+            }
+
+            let mut rng = Rng::new(2);
+            for i in 0..1000 {
+                let mut ctx = Context::arbitrary_with(&schema, &mut rng, &cidrs);
+                if i % 2 == 0 {
+                    corpus.fill_context(&mut ctx);
+                }
+
+                let expected = router.matchers.iter().rev().find_map(|(key, m)| {
+                    let mut mat = Match::new();
+                    m.expr
+                        .execute(&mut ctx, &mut mat)
+                        .then_some((key.2, mat.captures))
+                });
+
+                router.execute(&mut ctx);
+                let actual = ctx.result.map(|m| (m.uuid, m.captures));
+
+                assert_eq!(
+                    actual, expected,
+                    "prefilter on {:?}, suffix filter {}, equality index {}, \
+                     regex index {}, CIDR index {}",
+                    prefilter, suffix_filter, equality_index, regex_index, cidr_index
+                );
+            }
+        }
+    }
+
+    fn keys_of(router: &Router) -> Vec<(usize, Uuid)> {
+        router.matchers().map(|(p, u, _)| (p, u)).collect()
+    }
+
+    #[test]
+    fn test_evaluation_order() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let keys = [
+            (1, 0x10),
+            (2, 0x01),
+            (1, 0xff << 64),
+            (2, 0x02),
+            (0, u128::MAX),
+            (1, 0x0f),
+        ];
+
+        let mut router = Router::new(&schema);
+        for (priority, uuid) in keys {
+            router
+                .add_matcher(priority, Uuid::from_u128(uuid), r#"http.path ^= "/""#)
+                .unwrap();
+        }
+
+        let expected: Vec<_> = [
+            (2, 0x02),
+            (2, 0x01),
+            (1, 0xff << 64),
+            (1, 0x10),
+            (1, 0x0f),
+            (0, u128::MAX),
+        ]
+        .into_iter()
+        .map(|(p, u)| (p, Uuid::from_u128(u)))
+        .collect();
+        assert_eq!(keys_of(&router), expected);
+        assert_eq!(router.evaluation_order(), expected);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/".to_string().into());
+        let all: Vec<_> = router
+            .execute_all(&mut ctx)
+            .iter()
+            .map(|m| m.uuid)
+            .collect();
+        assert_eq!(all, expected.iter().map(|(_, u)| *u).collect::<Vec<_>>());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(0x02));
+
+        // insertion order does not matter
+        let mut router = Router::new(&schema);
+        for (priority, uuid) in keys.into_iter().rev() {
+            router
+                .add_matcher(priority, Uuid::from_u128(uuid), r#"http.path ^= "/""#)
+                .unwrap();
+        }
+        assert_eq!(router.evaluation_order(), expected);
+    }
+
+    /// Pins the evaluation order hosts rely on, see "Evaluation order" on
+    /// [`Router`]. Changing it is a breaking change.
+    #[test]
+    fn test_evaluation_order_is_stable() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        for (priority, uuid) in [
+            (Priority::new(1, 0), "00000000-0000-0000-0000-00000000000a"),
+            (Priority::new(1, 7), "00000000-0000-0000-0000-000000000001"),
+            (Priority::new(1, 0), "a0000000-0000-0000-0000-000000000000"),
+            (Priority::new(1, 0), "0a000000-0000-0000-0000-000000000000"),
+            (Priority::new(3, 0), "00000000-0000-0000-0000-000000000000"),
+            (Priority::new(1, 0), "ffffffff-ffff-ffff-ffff-fffffffffff0"),
+        ] {
+            router
+                .add_matcher_at(priority, Uuid::parse_str(uuid).unwrap(), "true")
+                .unwrap();
+        }
+
+        let order: Vec<_> = router
+            .evaluation_order()
+            .into_iter()
+            .map(|(p, u)| format!("{} {}", p, u))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "3 00000000-0000-0000-0000-000000000000",
+                // `minor` before the UUID
+                "1 00000000-0000-0000-0000-000000000001",
+                // then the UUID, as its lowercase hex form sorts
+                "1 ffffffff-ffff-ffff-ffff-fffffffffff0",
+                "1 a0000000-0000-0000-0000-000000000000",
+                "1 0a000000-0000-0000-0000-000000000000",
+                "1 00000000-0000-0000-0000-00000000000a",
+            ]
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_document_round_trip() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        for (i, atc) in [
+            r#"http.path ~ "^/document/(?<id>\\d+)$""#,
+            r#"http.path ~ "^/document/(?<id>\\d+)$" && net.port == 80"#,
+            r#"!(http.path ~ "^/document/other$") && net.port == 8080"#,
+        ]
+        .iter()
+        .enumerate()
+        {
+            router
+                .add_matcher(i, Uuid::from_u128(i as u128), atc)
+                .unwrap();
+        }
+
+        let doc = router.to_document();
+        assert_eq!(
+            doc.regexes,
+            ["^/document/(?<id>\\d+)$", "^/document/other$"]
+        );
+
+        let json = serde_json::to_string(&doc).unwrap();
+        assert!(json.starts_with(r#"{"version":1,"regexes":"#));
+
+        let doc: RouterDocument = serde_json::from_str(&json).unwrap();
+        let mut restored = Router::new(&schema);
+        restored.add_document(doc).unwrap();
+        assert_eq!(
+            restored
+                .matchers()
+                .map(|(p, u, e)| (p, u, e.to_string()))
+                .collect::<Vec<_>>(),
+            router
+                .matchers()
+                .map(|(p, u, e)| (p, u, e.to_string()))
+                .collect::<Vec<_>>()
+        );
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/document/42".to_string().into());
+        ctx.add_value("net.port", Value::Int(80));
+        assert!(restored.execute(&mut ctx));
+        let m = ctx.result.unwrap();
+        assert_eq!(m.uuid, Uuid::from_u128(1));
+        assert_eq!(m.captures["id"], "42");
+
+        let bad = json.replace("other$", "(");
+        assert!(serde_json::from_str::<RouterDocument>(&bad).is_err());
+
+        // unversioned documents are still accepted, newer ones are not
+        let unversioned = json.replace(r#""version":1,"#, "");
+        let doc: RouterDocument = serde_json::from_str(&unversioned).unwrap();
+        assert_eq!(doc.version, 0);
+        assert_eq!(doc.matchers.len(), 3);
+
+        let newer = json.replace(r#""version":1,"#, r#""version":2,"#);
+        let err = serde_json::from_str::<RouterDocument>(&newer).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unsupported router document version 2"));
+    }
+
+    #[test]
+    fn test_tenant_quota() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.set_tenant_quota(
+            "acme",
+            TenantQuota {
+                max_matchers: Some(2),
+                max_complexity: Some(4),
+            },
+        );
+        assert_eq!(router.tenant_quota("other"), TenantQuota::default());
+
+        router
+            .add_tenant_matcher("acme", 2, Uuid::from_u128(1), r#"http.path == "/a""#)
+            .unwrap();
+        // 4 more nodes would take acme to a complexity of 5
+        assert_eq!(
+            router.add_tenant_matcher(
+                "acme",
+                1,
+                Uuid::from_u128(2),
+                r#"http.path == "/b" && !(http.host == "x")"#,
+            ),
+            Err(RouterError::QuotaExceeded("acme".to_string()))
+        );
+        router
+            .add_tenant_matcher(
+                "acme",
+                1,
+                Uuid::from_u128(2),
+                r#"http.path == "/b" && http.host == "x""#,
+            )
+            .unwrap();
+        assert_eq!(
+            router.tenant_usage("acme"),
+            TenantUsage {
+                matchers: 2,
+                complexity: 4,
+            }
+        );
+        assert_eq!(
+            router
+                .add_tenant_matcher("acme", 0, Uuid::from_u128(3), "true")
+                .unwrap_err()
+                .to_string(),
+            r#"quota of tenant "acme" exceeded"#
+        );
+
+        // other tenants and plain matchers are not affected
+        router
+            .add_tenant_matcher("other", 0, Uuid::from_u128(3), "true")
+            .unwrap();
+        router
+            .add_matcher(0, Uuid::from_u128(4), r#"http.path == "/c""#)
+            .unwrap();
+        assert_eq!(router.tenant_of(1, Uuid::from_u128(2)), Some("acme"));
+        assert_eq!(router.tenant_of(0, Uuid::from_u128(4)), None);
+        assert_eq!(
+            router.tenants().collect::<Vec<_>>(),
+            [
+                (
+                    "acme",
+                    TenantUsage {
+                        matchers: 2,
+                        complexity: 4,
+                    }
+                ),
+                (
+                    "other",
+                    TenantUsage {
+                        matchers: 1,
+                        complexity: 1,
+                    }
+                ),
+            ]
+        );
+
+        // removing credits the tenant back
+        assert!(router.remove_matcher(2, Uuid::from_u128(1)));
+        assert_eq!(
+            router.tenant_usage("acme"),
+            TenantUsage {
+                matchers: 1,
+                complexity: 3,
+            }
+        );
+        router
+            .add_tenant_matcher("acme", 0, Uuid::from_u128(5), "true")
+            .unwrap();
+
+        assert!(router.remove_matcher(0, Uuid::from_u128(3)));
+        assert_eq!(router.tenants().count(), 1);
+        assert_eq!(router.tenant_usage("other"), TenantUsage::default());
+    }
+
+    #[test]
+    fn test_execute_deadline() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/a""#)
+            .unwrap();
+        router
+            .add_matcher(0, Uuid::from_u128(2), r#"http.path ^= "/""#)
+            .unwrap();
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/b".to_string().into());
+
+        let past = Instant::now();
+        assert_eq!(
+            router.execute_deadline(&mut ctx, past),
+            Err(DeadlineExceeded)
+        );
+        assert!(ctx.result.is_none());
+        assert_eq!(ctx.stats.matchers_evaluated, 0);
+
+        let future = Instant::now() + std::time::Duration::from_secs(60);
+        assert_eq!(router.execute_deadline(&mut ctx, future), Ok(true));
+        assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(2));
+
+        // nothing left to evaluate, nothing to time out
+        let empty = Router::new(&schema);
+        assert_eq!(empty.execute_deadline(&mut ctx, past), Ok(false));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(2, Uuid::from_u128(1), r#"http.path ~ "^/s/(?<id>\\d+)$""#)
+            .unwrap();
+        router
+            .add_matcher_at(
+                Priority::new(2, 5),
+                Uuid::from_u128(2),
+                r#"http.path ~ "^/s/(?<id>\\d+)$" && net.port == 80"#,
+            )
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(3), r#"lower(http.path) ^= "/s" || true"#)
+            .unwrap();
+
+        let bytes = router.serialize();
+        let restored = Router::deserialize(&schema, &bytes).unwrap();
+        assert_eq!(restored.fields, router.fields);
+        assert_eq!(
+            restored
+                .matchers()
+                .map(|(p, u, e)| (p, u, e.to_string()))
+                .collect::<Vec<_>>(),
+            router
+                .matchers()
+                .map(|(p, u, e)| (p, u, e.to_string()))
+                .collect::<Vec<_>>()
+        );
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/s/7".to_string().into());
+        ctx.add_value("net.port", Value::Int(80));
+        assert!(restored.execute(&mut ctx));
+        let m = ctx.result.unwrap();
+        assert_eq!(m.uuid, Uuid::from_u128(2));
+        assert_eq!(m.captures["id"], "7");
+
+        // corrupted, truncated and unknown versions are rejected
+        for bad in [
+            &bytes[..bytes.len() - 1],
+            &[bytes.as_slice(), &[0]].concat(),
+            &[0xff; 16][..],
+            &[],
+        ] {
+            assert!(matches!(
+                Router::deserialize(&schema, bad),
+                Err(RouterError::InvalidSnapshot(_))
+            ));
+        }
+
+        let mut newer = bytes.clone();
+        newer[0] = 3;
+        match Router::deserialize(&schema, &newer) {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "invalid router snapshot: unsupported version 3, expected 2"
+            ),
+            Ok(_) => panic!("newer snapshot accepted"),
+        }
+
+        // snapshots are validated against the schema they are loaded with
+        let mut other = Schema::default();
+        other.add_field("http.path", Type::String);
+        assert!(matches!(
+            Router::deserialize(&other, &bytes),
+            Err(RouterError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_compile_report() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        assert_eq!(router.compile_report(), CompileReport::default());
+
+        let atc = r#"http.path ^= "/a""#;
+        router.add_matcher(1, Uuid::from_u128(1), atc).unwrap();
+        assert!(router
+            .add_matcher(2, Uuid::from_u128(2), "http.path ==")
+            .is_err());
+        router
+            .add_matcher_expr(3, Uuid::from_u128(3), parse(atc).unwrap())
+            .unwrap();
+
+        let report = router.compile_report();
+        assert_eq!(report.expressions, 2);
+        assert_eq!(report.bytes, atc.len() + "http.path ==".len());
+        assert!(report.parse_time > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_matchers() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        assert_eq!(router.matchers().count(), 0);
+
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/a""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path ^= "/b""#)
+            .unwrap();
+
+        let listed: Vec<_> = router
+            .matchers()
+            .map(|(p, u, e)| (p, u, e.to_string()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                (2, Uuid::from_u128(2), r#"(http.path ^= "/b")"#.to_string()),
+                (1, Uuid::from_u128(1), r#"(http.path == "/a")"#.to_string()),
+            ]
+        );
+
+        assert!(router.contains(1, Uuid::from_u128(1)));
+        assert!(!router.contains(2, Uuid::from_u128(1)));
+        assert!(router.remove_matcher(1, Uuid::from_u128(1)));
+        assert!(!router.contains(1, Uuid::from_u128(1)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_from_routes() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let routes: Vec<RouteDoc> = serde_json::from_str(
+            r#"[
+                {"uuid": "00000000-0000-0000-0000-000000000001", "priority": 1,
+                 "expression": "http.path ^= \"/\"", "metadata": {"service": "a"}},
+                {"uuid": "00000000-0000-0000-0000-000000000002", "priority": 2,
+                 "expression": "http.path ^= \"/b\"", "enabled": false}
+            ]"#,
+        )
+        .unwrap();
+        assert!(routes[0].enabled);
+        assert_eq!(routes[0].metadata["service"], "a");
+
+        let router = Router::from_routes(&schema, &routes).unwrap();
+        assert_eq!(keys_of(&router), [(1, Uuid::from_u128(1))]);
+
+        let mut routes = routes;
+        routes[1].enabled = true;
+        routes[1].expression = "http.path ==".to_string();
+        let err = Router::from_routes(&schema, &routes).err().unwrap();
+        assert!(matches!(
+            &err,
+            RouterError::InvalidRoute(uuid, e)
+                if *uuid == Uuid::from_u128(2) && matches!(**e, RouterError::ParseError(_))
+        ));
+        assert!(err
+            .to_string()
+            .starts_with("route 00000000-0000-0000-0000-000000000002: "));
+    }
+
+    #[test]
+    fn test_add_matcher_multi() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher_multi(
+                1,
+                Uuid::from_u128(1),
+                &[
+                    r#"http.host == "a.com" && http.path ~ "^/(?<id>[0-9]+)$""#,
+                    r#"http.path ~ "^/b/(?<id>[0-9]+)$""#,
+                    r#"http.host == "c.com""#,
+                ],
+            )
+            .unwrap();
+        assert_eq!(router.len(), 1);
+        let joined = parse(
+            r#"(http.host == "a.com" && http.path ~ "^/(?<id>[0-9]+)$")
+                || http.path ~ "^/b/(?<id>[0-9]+)$" || http.host == "c.com""#,
+        )
+        .unwrap();
+        assert_eq!(
+            router.matchers().next().unwrap().2.to_string(),
+            joined.to_string()
+        );
+
+        let execute = |host: &str, path: &str| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.host", host);
+            ctx.add_value_str("http.path", path);
+            router.execute(&mut ctx);
+            ctx.result.map(|m| m.captures.get("id").cloned())
+        };
+        assert_eq!(execute("a.com", "/1"), Some(Some("1".to_string())));
+        assert_eq!(execute("b.com", "/b/2"), Some(Some("2".to_string())));
+        assert_eq!(execute("c.com", "/"), Some(None));
+        assert_eq!(execute("b.com", "/1"), None);
+
+        let err = router
+            .add_matcher_multi(
+                2,
+                Uuid::from_u128(2),
+                &[r#"http.path ^= "/""#, "http.path =="],
+            )
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            RouterError::InvalidExpression(1, e) if matches!(**e, RouterError::ParseError(_))
+        ));
+        assert!(err.to_string().starts_with("expression 1: "));
+
+        let err = router
+            .add_matcher_multi(2, Uuid::from_u128(2), &["http.path == 1"])
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            RouterError::InvalidExpression(0, e) if matches!(**e, RouterError::ValidationError(_))
+        ));
+
+        assert!(matches!(
+            router.add_matcher_multi(2, Uuid::from_u128(2), &[]),
+            Err(RouterError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            router.add_matcher_multi(1, Uuid::from_u128(1), &[r#"http.host == "d.com""#]),
+            Err(RouterError::DuplicateUuid(_))
+        ));
+        assert_eq!(router.len(), 1);
+    }
+
+    #[test]
+    fn test_execute_all() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        for (priority, uuid, atc) in [
+            (1, 1, r#"http.path ^= "/""#),
+            (3, 2, r#"http.path ^= "/a""#),
+            (3, 3, r#"http.path ^= "/a/b""#),
+            (2, 4, r#"http.path ^= "/z""#),
+            (5, 5, r#"http.host == "example.com""#),
+        ] {
+            router
+                .add_matcher(priority, Uuid::from_u128(uuid), atc)
+                .unwrap();
+        }
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/a/b/c".to_string().into());
+        let uuids: Vec<_> = router
+            .execute_all(&mut ctx)
+            .iter()
+            .map(|m| m.uuid.as_u128())
+            .collect();
+        assert_eq!(uuids, [3, 2, 1]);
+        assert!(ctx.result.is_none());
+        assert_eq!(ctx.stats.matchers_evaluated, 4);
+        assert_eq!(ctx.stats.matchers_skipped, 1);
+
+        // the first of execute_all is what execute picks
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(3));
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/nope".to_string().into());
+        assert_eq!(
+            router
+                .execute_all(&mut ctx)
+                .iter()
+                .map(|m| m.uuid.as_u128())
+                .collect::<Vec<_>>(),
+            [1]
+        );
+    }
+
+    #[test]
+    fn test_update() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.set_max_matchers(Some(3));
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/1""#)
+            .unwrap();
+
+        let mut update = router.begin_update();
+        update
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path == "/2""#)
+            .unwrap();
+        assert_eq!(
+            update.add_matcher(1, Uuid::from_u128(1), r#"http.path == "/1""#),
+            Err(RouterError::DuplicateUuid(Uuid::from_u128(1)))
+        );
+        assert!(matches!(
+            update.add_matcher(3, Uuid::from_u128(3), r#"http.path =="#),
+            Err(RouterError::ParseError(_))
+        ));
+        // a matcher removed earlier in the batch can be added again
+        assert!(update.remove_matcher(1, Uuid::from_u128(1)));
+        assert!(!update.remove_matcher(1, Uuid::from_u128(1)));
+        update
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/one""#)
+            .unwrap();
+        update
+            .add_matcher(3, Uuid::from_u128(3), r#"http.path == "/3""#)
+            .unwrap();
+        assert_eq!(update.len(), 3);
+        assert_eq!(
+            update.add_matcher(4, Uuid::from_u128(4), r#"http.path == "/4""#),
+            Err(RouterError::LimitExceeded(3))
+        );
+        update.rollback();
+        assert_eq!(keys_of(&router), [(1, Uuid::from_u128(1))]);
+
+        let mut update = router.begin_update();
+        update
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path == "/2""#)
+            .unwrap();
+        assert!(update.remove_matcher(1, Uuid::from_u128(1)));
+        update
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/one""#)
+            .unwrap();
+        assert!(update.remove_matcher(2, Uuid::from_u128(2)));
+        update.commit();
+
+        assert_eq!(keys_of(&router), [(1, Uuid::from_u128(1))]);
+        assert_eq!(router.fields["http.path"], 1);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/one".to_string().into());
+        assert!(router.execute(&mut ctx));
+    }
+
+    #[test]
+    fn test_update_indexes() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::builder(&schema).engine(Engine::Dag).build();
+        router.enable_prefilter("http.path");
+        router.enable_equality_index("http.host");
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/a""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.host == "x""#)
+            .unwrap();
+
+        let mut update = router.begin_update();
+        assert!(update.remove_matcher(1, Uuid::from_u128(1)));
+        update
+            .add_matcher(3, Uuid::from_u128(3), r#"http.path ^= "/b""#)
+            .unwrap();
+        update
+            .add_matcher(
+                4,
+                Uuid::from_u128(4),
+                r#"http.host == "y" && http.path ^= "/b""#,
+            )
+            .unwrap();
+        // added and removed again within the batch
+        update
+            .add_matcher(2, Uuid::from_u128(5), r#"http.host == "z""#)
+            .unwrap();
+        assert!(update.remove_matcher(2, Uuid::from_u128(5)));
+        update.commit();
+
+        assert_eq!(
+            keys_of(&router),
+            [
+                (4, Uuid::from_u128(4)),
+                (3, Uuid::from_u128(3)),
+                (2, Uuid::from_u128(2))
+            ]
+        );
+        // `http.path ^= "/b"`, `http.host == "x"`, `http.host == "y"` and
+        // the `&&`
+        assert_eq!(router.dag.len(), 4);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/b");
+        ctx.add_value_str("http.host", "y");
+        let matches = router.execute_all(&mut ctx);
+        assert_eq!(
+            matches.iter().map(|m| m.uuid.as_u128()).collect::<Vec<_>>(),
+            [4, 3]
+        );
+        // `http.host == "x"` ruled out by the equality index
+        assert_eq!(ctx.stats.matchers_prefiltered, 1);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/a");
+        assert!(!router.execute(&mut ctx));
+        assert_eq!(ctx.stats.matchers_evaluated, 0);
+    }
+
+    #[test]
+    fn test_update_tenants() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.set_tenant_quota(
+            "acme",
+            TenantQuota {
+                max_matchers: Some(2),
+                max_complexity: None,
+            },
+        );
+        router
+            .add_tenant_matcher("acme", 1, Uuid::from_u128(1), r#"http.path == "/a""#)
+            .unwrap();
+        router
+            .add_tenant_matcher("acme", 2, Uuid::from_u128(2), r#"http.path == "/b""#)
+            .unwrap();
+
+        let mut update = router.begin_update();
+        assert_eq!(
+            update.add_tenant_matcher("acme", 3, Uuid::from_u128(3), "true"),
+            Err(RouterError::QuotaExceeded("acme".to_string()))
+        );
+        // replacing a matcher of the tenant frees up its share of the quota
+        assert!(update.remove_matcher(1, Uuid::from_u128(1)));
+        update
+            .add_tenant_matcher("acme", 1, Uuid::from_u128(1), r#"http.path == "/one""#)
+            .unwrap();
+        assert_eq!(
+            update.add_tenant_matcher("acme", 3, Uuid::from_u128(3), "true"),
+            Err(RouterError::QuotaExceeded("acme".to_string()))
+        );
+        // moving a matcher to another tenant
+        assert!(update.remove_matcher(2, Uuid::from_u128(2)));
+        update
+            .add_tenant_matcher("other", 2, Uuid::from_u128(2), r#"http.path == "/b""#)
+            .unwrap();
+        update
+            .add_tenant_matcher("acme", 3, Uuid::from_u128(3), "true")
+            .unwrap();
+        update.commit();
+
+        assert_eq!(router.tenant_of(1, Uuid::from_u128(1)), Some("acme"));
+        assert_eq!(router.tenant_of(2, Uuid::from_u128(2)), Some("other"));
+        assert_eq!(router.tenant_of(3, Uuid::from_u128(3)), Some("acme"));
+        assert_eq!(
+            router.tenants().collect::<Vec<_>>(),
+            [
+                (
+                    "acme",
+                    TenantUsage {
+                        matchers: 2,
+                        complexity: 2,
+                    }
+                ),
+                (
+                    "other",
+                    TenantUsage {
+                        matchers: 1,
+                        complexity: 1,
+                    }
+                ),
+            ]
+        );
+
+        // a plain matcher replacing a tenant's one is not accounted to it
+        let mut update = router.begin_update();
+        assert!(update.remove_matcher(3, Uuid::from_u128(3)));
+        update.add_matcher(3, Uuid::from_u128(3), "true").unwrap();
+        update.commit();
+        assert_eq!(router.tenant_of(3, Uuid::from_u128(3)), None);
+        assert_eq!(router.tenant_usage("acme").matchers, 1);
+    }
+
+    #[test]
+    fn test_prefilter() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(4, Uuid::from_u128(4), r#"http.path ^= "/foo""#)
+            .unwrap();
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.path ~ "^/bar/\\d+$""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.host == "example.com""#)
+            .unwrap();
+
+        router.enable_prefilter("http.path");
+        assert_eq!(router.prefilter_field(), Some("http.path"));
+        // added after the prefilter was enabled
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path == "/foo/bar""#)
+            .unwrap();
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/bar/1".to_string().into());
+        ctx.add_value("http.host", "example.com".to_string().into());
+        let matches: Vec<_> = router
+            .execute_all(&mut ctx)
+            .into_iter()
+            .map(|m| m.uuid)
+            .collect();
+        assert_eq!(matches, [Uuid::from_u128(3), Uuid::from_u128(2)]);
+        assert_eq!(ctx.stats.matchers_prefiltered, 2);
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/foo/bar".to_string().into());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(4));
+        assert_eq!(ctx.stats.matchers_prefiltered, 0);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/foo/bar".to_string().into());
+        let keys: Vec<_> = router.candidate_keys(&mut ctx).collect();
+        assert_eq!(
+            keys,
+            [
+                (4, Uuid::from_u128(4)),
+                (2, Uuid::from_u128(2)),
+                (1, Uuid::from_u128(1))
+            ]
+        );
+        assert_eq!(ctx.stats.matchers_evaluated, 0);
+
+        assert!(router.remove_matcher(4, Uuid::from_u128(4)));
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/foo/bar".to_string().into());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(1));
+        assert_eq!(ctx.stats.matchers_prefiltered, 1);
+
+        router.disable_prefilter();
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/nope".to_string().into());
+        assert!(!router.execute(&mut ctx));
+        assert_eq!(ctx.stats.matchers_prefiltered, 0);
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+    }
+
+    #[test]
+    fn test_prefilter_missing_field() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.protocol", Type::String);
+
+        let mut router = Router::new(&schema);
+        router.enable_prefilter("http.path");
+        router
+            .add_matcher(4, Uuid::from_u128(4), r#"http.path ^= "/foo""#)
+            .unwrap();
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.path ~ "[0-9]$""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"!(http.path ^= "/bar")"#)
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"net.protocol == "tcp""#)
+            .unwrap();
+
+        // non-HTTP traffic only evaluates the matchers not requiring the path
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("net.protocol", "tcp".to_string().into());
+        let matches: Vec<_> = router
+            .execute_all(&mut ctx)
+            .into_iter()
+            .map(|m| m.uuid)
+            .collect();
+        assert_eq!(matches, [Uuid::from_u128(2), Uuid::from_u128(1)]);
+        assert_eq!(ctx.stats.matchers_missing_prefilter_field, 2);
+        assert_eq!(ctx.stats.matchers_skipped, 2);
+        assert_eq!(ctx.stats.matchers_prefiltered, 0);
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/bar/1".to_string().into());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(3));
+        assert_eq!(ctx.stats.matchers_missing_prefilter_field, 0);
+        assert_eq!(ctx.stats.matchers_prefiltered, 1);
+    }
+
+    #[test]
+    fn test_suffix_filter() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(
+                5,
+                Uuid::from_u128(5),
+                r##"http.host ~ r#"^[a-z]+\.example\.net$"#"##,
+            )
+            .unwrap();
+        router
+            .add_matcher(4, Uuid::from_u128(4), r#"http.host =^ ".example.com""#)
+            .unwrap();
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.host glob "*.example.org""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path ^= "/""#)
+            .unwrap();
+
+        router.enable_suffix_filter("http.host");
+        assert_eq!(router.suffix_filter_field(), Some("http.host"));
+        // added after the suffix filter was enabled
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.host == "api.example.org""#)
+            .unwrap();
+
+        let matches = |router: &Router, host: &str| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", "/".to_string().into());
+            ctx.add_value("http.host", host.to_string().into());
+            let uuids: Vec<_> = router
+                .execute_all(&mut ctx)
+                .into_iter()
+                .map(|m| m.uuid.as_u128())
+                .collect();
+            (uuids, ctx.stats.matchers_prefiltered)
+        };
+
+        assert_eq!(matches(&router, "api.example.org"), (vec![3, 2, 1], 2));
+        assert_eq!(matches(&router, "www.example.com"), (vec![4, 2], 3));
+        assert_eq!(matches(&router, "www.example.net"), (vec![5, 2], 3));
+        assert_eq!(matches(&router, "example.com"), (vec![2], 4));
+
+        assert!(router.remove_matcher(3, Uuid::from_u128(3)));
+        assert_eq!(matches(&router, "api.example.org"), (vec![2, 1], 2));
+
+        router.disable_suffix_filter();
+        assert_eq!(router.suffix_filter_field(), None);
+        assert_eq!(matches(&router, "example.com"), (vec![2], 0));
+    }
+
+    #[test]
+    fn test_equality_index() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        for i in 0..100 {
+            let atc = format!(r#"http.host == "{}.example.com""#, i);
+            router
+                .add_matcher(10 + i, Uuid::from_u128(10 + i as u128), &atc)
+                .unwrap();
+        }
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.host in ("a.com", "b.com")"#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path ^= "/""#)
+            .unwrap();
+
+        router.enable_equality_index("http.host");
+        assert_eq!(router.equality_index_field(), Some("http.host"));
+        // added after the equality index was enabled
+        router
+            .add_matcher(
+                1,
+                Uuid::from_u128(1),
+                r#"http.host == "b.com" && http.path == "/b""#,
+            )
+            .unwrap();
+
+        let matches = |router: &Router, host: &str| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("http.path", "/b".to_string().into());
+            ctx.add_value("http.host", host.to_string().into());
+            let uuids: Vec<_> = router
+                .execute_all(&mut ctx)
+                .into_iter()
+                .map(|m| m.uuid.as_u128())
+                .collect();
+            (uuids, ctx.stats.matchers_prefiltered)
+        };
+
+        assert_eq!(matches(&router, "42.example.com"), (vec![52, 2], 101));
+        assert_eq!(matches(&router, "b.com"), (vec![3, 2, 1], 100));
+        assert_eq!(matches(&router, "c.com"), (vec![2], 102));
+
+        assert!(router.remove_matcher(3, Uuid::from_u128(3)));
+        assert_eq!(matches(&router, "b.com"), (vec![2, 1], 100));
+
+        router.disable_equality_index();
+        assert_eq!(router.equality_index_field(), None);
+        assert_eq!(matches(&router, "c.com"), (vec![2], 0));
+    }
+
+    #[test]
+    fn test_cidr_index() {
+        let mut schema = Schema::default();
+        schema.add_field("net.src.ip", Type::IpAddr);
+        schema.add_field("net.dst.port", Type::Int);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(
+                4,
+                Uuid::from_u128(4),
+                "net.src.ip in (10.0.0.0/8, fd00::/8)",
+            )
+            .unwrap();
+        router
+            .add_matcher(3, Uuid::from_u128(3), "net.src.ip not in 10.1.0.0/16")
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), "net.dst.port == 80")
+            .unwrap();
+
+        router.enable_cidr_index("net.src.ip");
+        assert_eq!(router.cidr_index_field(), Some("net.src.ip"));
+        // added after the index was enabled
+        router
+            .add_matcher(1, Uuid::from_u128(1), "net.src.ip == 192.168.0.1")
+            .unwrap();
+
+        let matches = |router: &Router, ip: &str| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value("net.src.ip", Value::IpAddr(ip.parse().unwrap()));
+            ctx.add_value("net.dst.port", Value::Int(80));
+            let uuids: Vec<_> = router
+                .execute_all(&mut ctx)
+                .into_iter()
+                .map(|m| m.uuid.as_u128())
+                .collect();
+            (uuids, ctx.stats.matchers_prefiltered)
+        };
+
+        assert_eq!(matches(&router, "10.1.2.3"), (vec![4, 2], 2));
+        assert_eq!(matches(&router, "10.2.0.1"), (vec![4, 3, 2], 1));
+        assert_eq!(matches(&router, "192.168.0.1"), (vec![3, 2, 1], 1));
+        assert_eq!(matches(&router, "fd00::1"), (vec![4, 3, 2], 1));
+
+        assert!(router.remove_matcher(3, Uuid::from_u128(3)));
+        assert_eq!(matches(&router, "10.1.2.3"), (vec![4, 2], 1));
+
+        router.disable_cidr_index();
+        assert_eq!(matches(&router, "10.1.2.3"), (vec![4, 2], 0));
+    }
+
+    #[test]
+    fn test_context_provider() {
+        use std::sync::{Arc, Mutex};
+
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.headers.x == "1""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path ^= "/foo""#)
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.host == "example.com""#)
+            .unwrap();
+        router.enable_prefilter("http.path");
+
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.headers.x", "0".to_string().into());
+        let log = asked.clone();
+        ctx.set_provider(move |field| {
+            log.lock().unwrap().push(field.to_string());
+            match field {
+                "http.path" => Some(Value::String("/foo/bar".into())),
+                _ => None,
+            }
+        });
+
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(2));
+        // the prefilter field first, `http.host` is never needed
+        assert_eq!(*asked.lock().unwrap(), ["http.path"]);
+
+        assert_eq!(router.execute_all(&mut ctx).len(), 1);
+        assert_eq!(*asked.lock().unwrap(), ["http.path", "http.host"]);
+
+        // values added upfront are gone, the provider is asked again
+        ctx.reset();
+        assert!(ctx.value_of("http.path").is_none());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(
+            *asked.lock().unwrap(),
+            ["http.path", "http.host", "http.path", "http.headers.x"]
+        );
+    }
+
+    #[test]
+    fn test_capture_modes() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path ~ "^/a/(?P<id>\\d+)$""#)
+            .unwrap();
+        router
+            .add_matcher(
+                1,
+                Uuid::from_u128(1),
+                r#"http.path ~ "^/(\\w)/(?P<id>\\d+)$""#,
+            )
+            .unwrap();
+
+        let captures = |router: &Router, path: &str, mode| {
+            let mut ctx = Context::new(&schema);
+            ctx.set_capture_mode(mode);
+            ctx.add_value_str("http.path", path);
+            assert!(router.execute(&mut ctx));
+            assert_eq!(ctx.capture_mode(), mode);
+
+            let mut names: Vec<_> = ctx.result.unwrap().captures.into_keys().collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            captures(&router, "/a/1", CaptureMode::All),
+            ["0", "1", "id"]
+        );
+
+        assert!(router.set_capture_mode(2, Uuid::from_u128(2), Some(CaptureMode::None)));
+        assert!(!router.set_capture_mode(3, Uuid::from_u128(2), None));
+        assert!(captures(&router, "/a/1", CaptureMode::All).is_empty());
+        assert_eq!(
+            captures(&router, "/b/1", CaptureMode::All),
+            ["0", "1", "2", "id"]
+        );
+
+        router.set_default_capture_mode(CaptureMode::NamedOnly);
+        assert_eq!(captures(&router, "/b/1", CaptureMode::All), ["id"]);
+        // the context restricts further
+        assert!(captures(&router, "/b/1", CaptureMode::None).is_empty());
+
+        assert!(router.set_capture_mode(2, Uuid::from_u128(2), None));
+        assert_eq!(captures(&router, "/a/1", CaptureMode::All), ["id"]);
+    }
+
+    #[test]
+    fn test_two_level_priorities() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(2, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        router
+            .add_matcher_at(
+                Priority::new(1, 7),
+                Uuid::from_u128(2),
+                r#"http.path ^= "/""#,
+            )
+            .unwrap();
+        router
+            .add_matcher_at(
+                Priority::new(2, 1),
+                Uuid::from_u128(3),
+                r#"http.path ^= "/""#,
+            )
+            .unwrap();
+        router
+            .add_matcher_at(
+                Priority::new(1, 0),
+                Uuid::from_u128(4),
+                r#"http.path ^= "/""#,
+            )
+            .unwrap();
+
+        // a plain priority is a major with a minor of 0
+        assert_eq!(
+            router
+                .add_matcher_at(Priority::new(2, 0), Uuid::from_u128(1), "http.path == \"\"")
+                .unwrap_err(),
+            RouterError::DuplicateUuid(Uuid::from_u128(1))
+        );
+        assert!(router.contains(1, Uuid::from_u128(4)));
+        assert!(!router.contains(1, Uuid::from_u128(2)));
+        assert!(router.contains_at(Priority::new(1, 7), Uuid::from_u128(2)));
+
+        assert_eq!(
+            keys_of(&router),
+            [
+                (2, Uuid::from_u128(3)),
+                (2, Uuid::from_u128(1)),
+                (1, Uuid::from_u128(2)),
+                (1, Uuid::from_u128(4)),
+            ]
+        );
+        assert_eq!(
+            router.priority_of(Uuid::from_u128(2)).collect::<Vec<_>>(),
+            [Priority::new(1, 7)]
+        );
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/");
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(3));
+
+        assert!(!router.remove_matcher(2, Uuid::from_u128(3)));
+        assert!(router.remove_matcher_at(Priority::new(2, 1), Uuid::from_u128(3)));
+        assert_eq!(router.len(), 3);
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&router.to_document()).unwrap();
+            // only non-zero minors are written
+            assert_eq!(json.matches(r#""minor":7"#).count(), 1);
+            assert!(!json.contains(r#""minor":0"#));
+
+            let mut restored = Router::new(&schema);
+            restored
+                .add_document(serde_json::from_str(&json).unwrap())
+                .unwrap();
+            assert!(restored.contains_at(Priority::new(1, 7), Uuid::from_u128(2)));
+            assert_eq!(keys_of(&restored), keys_of(&router));
+        }
+    }
+
+    #[test]
+    fn test_optimize() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let atc =
+            r#"(http.host == "a" && http.path ^= "/x") || (http.path ^= "/y" && http.host == "a")"#;
+
+        let mut router = Router::new(&schema);
+        router.add_matcher(1, Uuid::from_u128(1), atc).unwrap();
+        router.set_optimize(true);
+        router.add_matcher(2, Uuid::from_u128(2), atc).unwrap();
+
+        let exprs: Vec<_> = router.matchers().map(|(_, _, e)| e.to_string()).collect();
+        assert_eq!(
+            exprs,
+            [
+                r#"((http.host == "a") && ((http.path ^= "/x") || (http.path ^= "/y")))"#,
+                r#"(((http.host == "a") && (http.path ^= "/x")) || ((http.path ^= "/y") && (http.host == "a")))"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_execute_range() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(usize::MAX, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        router
+            .add_matcher_at(
+                Priority::new(20, 3),
+                Uuid::from_u128(2),
+                r#"http.path ^= "/a""#,
+            )
+            .unwrap();
+        router
+            .add_matcher(20, Uuid::from_u128(3), r#"http.path ^= "/""#)
+            .unwrap();
+        router
+            .add_matcher(10, Uuid::from_u128(4), r#"http.path ^= "/""#)
+            .unwrap();
+
+        let winner = |priorities: RangeInclusive<usize>| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", "/a");
+            router.execute_range(&mut ctx, priorities).then(|| {
+                (
+                    ctx.result.unwrap().uuid.as_u128(),
+                    ctx.stats.matchers_evaluated,
+                )
+            })
+        };
+
+        assert_eq!(winner(0..=usize::MAX), Some((1, 1)));
+        assert_eq!(winner(usize::MAX..=usize::MAX), Some((1, 1)));
+        // every minor priority of the range's majors
+        assert_eq!(winner(0..=20), Some((2, 1)));
+        assert_eq!(winner(20..=20), Some((2, 1)));
+        assert_eq!(winner(0..=19), Some((4, 1)));
+        assert_eq!(winner(11..=19), None);
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty = 20..=10;
+        assert_eq!(winner(empty), None);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/b");
+        assert!(router.execute_range(&mut ctx, 11..=20));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(3));
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+    }
+
+    #[test]
+    fn test_rewrite_regexes() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let atc = r#"http.path ~ "^/api/v1/.*""#;
+
+        let mut router = Router::builder(&schema).rewrite_regexes(true).build();
+        // capture 0 of the whole match is in use
+        router.add_matcher(1, Uuid::from_u128(1), atc).unwrap();
+        router.set_default_capture_mode(CaptureMode::NamedOnly);
+        router.add_matcher(2, Uuid::from_u128(2), atc).unwrap();
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.path ~ "^/(?<v>v1)/.*""#)
+            .unwrap();
+
+        let exprs: Vec<_> = router.matchers().map(|(_, _, e)| e.to_string()).collect();
+        assert_eq!(
+            exprs,
+            [
+                r#"(http.path ~ "^/(?<v>v1)/.*")"#,
+                r#"(http.path ^= "/api/v1/")"#,
+                r#"(http.path ~ "^/api/v1/.*")"#,
+            ]
+        );
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/api/v1/users");
+        assert!(router.execute(&mut ctx));
+        let m = ctx.result.unwrap();
+        assert_eq!(m.uuid, Uuid::from_u128(2));
+        assert_eq!(m.matches["http.path"], Value::String("/api/v1/".into()));
+        assert!(m.captures.is_empty());
+    }
+
+    #[test]
+    fn test_trace_sampling() {
+        use crate::trace::TraceOutcome::*;
+
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(3, Uuid::from_u128(3), r#"http.host == "a""#)
+            .unwrap();
+        router
+            .add_matcher(2, Uuid::from_u128(2), r#"http.path == "/b""#)
+            .unwrap();
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        assert!(router.drain_traces().is_empty());
+
+        // every other execution, keeping the latest two
+        router.set_trace_sampling(0.5, 2);
+        for path in ["/a", "/b", "/c", "/d", "/e", "/f"] {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", path);
+            router.execute(&mut ctx);
+        }
+
+        let traces = router.drain_traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(
+            traces[0].values,
+            [("http.path".to_string(), vec![Value::String("/d".into())])]
+        );
+        assert_eq!(traces[0].matched, Some(Uuid::from_u128(1)));
+        let outcomes: Vec<_> = traces[0]
+            .steps
+            .iter()
+            .map(|s| (s.uuid.as_u128(), s.outcome))
+            .collect();
+        assert_eq!(outcomes, [(3, MissingField), (2, NoMatch), (1, Matched)]);
+        assert_eq!(traces[1].values[0].1[0], Value::String("/f".into()));
+        assert!(router.drain_traces().is_empty());
+
+        router.set_trace_sampling(1.0, 10);
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.host", "b");
+        assert!(!router.execute(&mut ctx));
+        let traces = router.drain_traces();
+        assert_eq!(traces[0].matched, None);
+        assert_eq!(traces[0].steps.len(), 3);
+
+        router.disable_trace_sampling();
+        router.execute(&mut ctx);
+        assert!(router.drain_traces().is_empty());
+    }
+
+    #[test]
+    fn test_fields_of() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+        schema.add_field("tls.sni", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+
+        let mut router = Router::new(&schema);
+        let uuid = Uuid::from_u128(1);
+        router
+            .add_matcher(
+                1,
+                uuid,
+                r#"http.path ^= "/" && !(http.host != tls.sni || http.headers.x_a == "b")"#,
+            )
+            .unwrap();
+        router.add_matcher(0, Uuid::from_u128(2), "true").unwrap();
+
+        assert_eq!(
+            router.fields_of(1, uuid),
+            Some(HashSet::from([
+                "http.path",
+                "http.host",
+                "tls.sni",
+                "http.headers.x_a"
+            ]))
+        );
+        assert_eq!(
+            router.fields_of(0, Uuid::from_u128(2)),
+            Some(HashSet::new())
+        );
+        assert_eq!(router.fields_of(0, uuid), None);
+    }
+
+    #[test]
+    fn test_engine() {
+        use crate::corpus::{Corpus, Rng, Shape};
+
+        let schema = Corpus::schema();
+        let mut corpus = Corpus::new(8, Shape::default());
+        let atcs = corpus.expressions(200);
+
+        let mut routers: Vec<_> = [
+            Engine::Cir,
+            Engine::Lir,
+            Engine::Ast,
+            Engine::Closure,
+            Engine::Dag,
+        ]
+        .into_iter()
+        .map(|engine| {
+            let mut router = Router::builder(&schema).engine(engine).build();
+            assert_eq!(router.engine(), engine);
+            for (i, atc) in atcs.iter().enumerate() {
+                router
+                    .add_matcher(i % 5, Uuid::from_u128(i as u128), atc)
+                    .unwrap();
+            }
+            router
+        })
+        .collect();
+        assert!(routers[0]
+            .matchers
+            .values()
+            .all(|m| matches!(m.program, Some(Program::Cir(_)))));
+        assert!(routers[1]
+            .matchers
+            .values()
+            .all(|m| matches!(m.program, Some(Program::Lir(_)))));
+        assert!(routers[2].matchers.values().all(|m| m.program.is_none()));
+        assert!(routers[3]
+            .matchers
+            .values()
+            .all(|m| matches!(m.program, Some(Program::Closure(_)))));
+        assert!(routers[4]
+            .matchers
+            .values()
+            .all(|m| matches!(m.program, Some(Program::Dag(_)))));
+        assert_eq!(Router::new(&schema).engine(), Engine::Cir);
+
+        let mut rng = Rng::new(9);
+        for i in 0..500 {
+            let mut ctx = Context::arbitrary_for(&schema, &mut rng);
+            if i % 2 == 0 {
+                corpus.fill_context(&mut ctx);
+            }
+
+            let results: Vec<_> = routers
+                .iter_mut()
+                .map(|router| {
+                    router.execute(&mut ctx);
+                    ctx.result.take().map(|m| (m.uuid, m.matches, m.captures))
+                })
+                .collect();
+            assert_eq!(results[0], results[1]);
+            assert_eq!(results[0], results[2]);
+            assert_eq!(results[0], results[3]);
+            assert_eq!(results[0], results[4]);
+        }
+
+        // nodes go away with the last matcher using them
+        let mut router = routers.pop().unwrap();
+        assert!(!router.dag.is_empty());
+        for i in 0..atcs.len() {
+            assert!(router.remove_matcher(i % 5, Uuid::from_u128(i as u128)));
+        }
+        assert!(router.dag.is_empty());
+    }
+
+    #[test]
+    fn test_tie_break() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        // complexity 4, 5 and 3, longest path prefix 4, 1 and 2
+        let matchers = [
+            (
+                2,
+                r#"http.path ^= "/" && http.host == "x" && http.host != "z""#,
+            ),
+            (3, r#"http.path == "/a" || http.path == "/b""#),
+            (1, r#"http.path ^= "/a/b" && !(http.host == "y")"#),
+        ];
+
+        for (tie_break, expected) in [
+            (TieBreak::Uuid, [3, 2, 1]),
+            (TieBreak::InsertionOrder, [2, 3, 1]),
+            (TieBreak::LongestPrefix("http.path".to_string()), [1, 3, 2]),
+            (TieBreak::MostSpecific, [2, 1, 3]),
+        ] {
+            let mut router = Router::builder(&schema)
+                .tie_break(tie_break.clone())
+                .build();
+            assert_eq!(router.tie_break(), &tie_break);
+
+            // a higher priority still comes first
+            router
+                .add_matcher(1, Uuid::from_u128(9), r#"http.path == "/none""#)
+                .unwrap();
+            for (uuid, atc) in matchers {
+                router.add_matcher(0, Uuid::from_u128(uuid), atc).unwrap();
+            }
+
+            let order: Vec<_> = router
+                .matchers()
+                .skip(1)
+                .map(|(_, uuid, _)| uuid.as_u128())
+                .collect();
+            assert_eq!(order, expected, "{:?}", tie_break);
+
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", "/a/b");
+            ctx.add_value_str("http.host", "x");
+            router.execute(&mut ctx);
+            let first_match = if expected[0] == 3 {
+                expected[1]
+            } else {
+                expected[0]
+            };
+            assert_eq!(ctx.result.unwrap().uuid.as_u128(), first_match);
+
+            assert!(router.contains(0, Uuid::from_u128(expected[0])));
+            assert!(router.remove_matcher(0, Uuid::from_u128(expected[0])));
+            assert!(!router.contains(0, Uuid::from_u128(expected[0])));
+            assert_eq!(router.matchers().count(), 3);
+        }
+    }
+
+    #[test]
+    fn test_eval_errors() {
+        use std::sync::Mutex;
+
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        for engine in [
+            Engine::Cir,
+            Engine::Lir,
+            Engine::Ast,
+            Engine::Closure,
+            Engine::Dag,
+        ] {
+            for (atc, expected) in [
+                (
+                    "http.path ^= 1",
+                    "operator ^= does not apply to String and Int",
+                ),
+                (
+                    "http.path > 1",
+                    "operator > does not apply to String and Int",
+                ),
+                (
+                    r#"net.port ~ "^1""#,
+                    "operator ~ does not apply to Int and Regex",
+                ),
+                (
+                    r#"http.path ^= "/" && net.port in 10.0.0.0/8"#,
+                    "operator in does not apply to Int and IpCidr",
+                ),
+                (
+                    r#"lower(net.port) == "1""#,
+                    "operator == does not apply to Int and String",
+                ),
+            ] {
+                let mut router = Router::builder(&schema).engine(engine).build();
+                // does not validate
+                router.insert_matcher(0.into(), Uuid::from_u128(1), parse(atc).unwrap());
+                let errors = Arc::new(Mutex::new(Vec::new()));
+                let hook_errors = errors.clone();
+                router.set_eval_error_hook(move |_, e| {
+                    hook_errors.lock().unwrap().push(e.to_string());
+                });
+
+                let mut ctx = Context::new(&schema);
+                ctx.add_value_str("http.path", "/a");
+                ctx.add_value_int("net.port", 1);
+                assert!(!router.execute(&mut ctx), "{:?} {}", engine, atc);
+                assert_eq!(ctx.stats.eval_errors, 1, "{:?} {}", engine, atc);
+                assert_eq!(*errors.lock().unwrap(), [expected], "{:?} {}", engine, atc);
+            }
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        use std::sync::Mutex;
+
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/""#)
+            .unwrap();
+        // does not validate, evaluating it fails
+        router.insert_matcher(
+            2.into(),
+            Uuid::from_u128(2),
+            parse("http.path ^= 1").unwrap(),
+        );
+
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let hook_errors = errors.clone();
+        router.set_quarantine_after(Some(2));
+        router.set_eval_error_hook(move |uuid, e| {
+            hook_errors.lock().unwrap().push((uuid, *e));
+        });
+
+        let execute = |router: &Router| {
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", "/a");
+            assert!(router.execute(&mut ctx));
+            assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(1));
+            ctx.stats
+        };
+
+        for _ in 0..2 {
+            let stats = execute(&router);
+            assert_eq!(stats.eval_errors, 1);
+            assert_eq!(stats.matchers_evaluated, 2);
+        }
+        assert_eq!(router.quarantined(), [(2, Uuid::from_u128(2))]);
+
+        let stats = execute(&router);
+        assert_eq!(stats.eval_errors, 0);
+        assert_eq!(stats.matchers_quarantined, 1);
+        assert_eq!(stats.matchers_evaluated, 1);
+
+        {
+            let errors = errors.lock().unwrap();
+            assert_eq!(errors.len(), 2);
+            assert_eq!(errors[0].0, Uuid::from_u128(2));
+            assert_eq!(
+                errors[0].1.to_string(),
+                "operator ^= does not apply to String and Int"
+            );
+        }
+
+        assert!(router.release_quarantine(2, Uuid::from_u128(2)));
+        assert!(router.quarantined().is_empty());
+        assert_eq!(execute(&router).eval_errors, 1);
+    }
+
+    #[test]
+    fn test_metrics_sink() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(bool, usize)>>);
+
+        impl MetricsSink for Recorder {
+            fn on_execute(&self, _: Duration, matched: bool, predicates_evaluated: usize) {
+                self.0.lock().unwrap().push((matched, predicates_evaluated));
+            }
+        }
+
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("net.port", Type::Int);
+
+        for engine in [
+            Engine::Cir,
+            Engine::Lir,
+            Engine::Ast,
+            Engine::Closure,
+            Engine::Dag,
+        ] {
+            let mut router = Router::builder(&schema).engine(engine).build();
+            router
+                .add_matcher(
+                    2,
+                    Uuid::from_u128(2),
+                    r#"http.path ^= "/a" && net.port == 80"#,
+                )
+                .unwrap();
+            router
+                .add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/" || net.port < 0"#)
+                .unwrap();
+
+            let recorder = Arc::new(Recorder::default());
+            router.set_metrics_sink(recorder.clone());
+
+            let context = |path: &str| {
+                let mut ctx = Context::new(&schema);
+                ctx.add_value_str("http.path", path);
+                ctx.add_value_int("net.port", 80);
+                ctx
+            };
+
+            let mut ctx = context("/a");
+            assert!(router.execute(&mut ctx));
+            assert_eq!(ctx.stats.predicates_evaluated, 2);
+            // `net.port == 80` is skipped
+            assert!(!router.execute(&mut context("x")));
+            // not reported
+            router.execute_all(&mut context("/a"));
+            let past = Instant::now();
+            assert!(router.execute_deadline(&mut context("/a"), past).is_err());
+
+            assert_eq!(
+                *recorder.0.lock().unwrap(),
+                [(true, 2), (false, 3)],
+                "{:?}",
+                engine
+            );
+
+            router.clear_metrics_sink();
+            router.execute(&mut context("/a"));
+            assert_eq!(recorder.0.lock().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        // only regexes of the `regex` crate are indexed
+        let mut router = Router::builder(&schema)
+            .regex_engine(Arc::new(RegexCrateEngine))
+            .build();
+        assert_eq!(
+            router.maintenance(Duration::ZERO),
+            MaintenanceProgress::Done
+        );
+
+        router.enable_regex_index();
+        for i in 0..10 {
+            router
+                .add_matcher(
+                    i,
+                    Uuid::from_u128(i as u128),
+                    &format!(r#"http.path ~ "^/{}/" && http.host ~ "^h{}$""#, i, i),
+                )
+                .unwrap();
+        }
+
+        // without budget every call does a single step: one per matcher,
+        // one to finish collecting, one per field and a last one
+        let calls = (1..)
+            .find(|_| router.maintenance(Duration::ZERO) == MaintenanceProgress::Done)
+            .unwrap();
+        assert_eq!(calls, 10 + 1 + 2 + 1);
+        assert!(router.regex_index.as_ref().unwrap().get().is_some());
+        assert_eq!(
+            router.maintenance(Duration::ZERO),
+            MaintenanceProgress::Done
+        );
+
+        // changes discard the work done so far
+        assert!(router.remove_matcher(3, Uuid::from_u128(3)));
+        assert_eq!(
+            router.maintenance(Duration::ZERO),
+            MaintenanceProgress::Pending
+        );
+        assert!(router.regex_index_builder.is_some());
+        assert!(router.remove_matcher(4, Uuid::from_u128(4)));
+        assert!(router.regex_index_builder.is_none());
+        assert_eq!(
+            router.maintenance(Duration::ZERO),
+            MaintenanceProgress::Pending
+        );
+        assert_eq!(router.maintenance(Duration::MAX), MaintenanceProgress::Done);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/7/a");
+        ctx.add_value_str("http.host", "h7");
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(7));
+        assert_eq!(ctx.stats.matchers_evaluated, 1);
+    }
+
+    #[test]
+    fn test_regex_index() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.host", Type::String);
+
+        // only regexes of the `regex` crate are indexed
+        let mut router = Router::builder(&schema)
+            .regex_engine(Arc::new(RegexCrateEngine))
+            .build();
+        router.enable_regex_index();
+        assert!(router.regex_index_enabled());
+
+        for i in 0..100 {
+            router
+                .add_matcher(
+                    i,
+                    Uuid::from_u128(i as u128),
+                    &format!(r#"http.path ~ "^/users/{}/(?<id>\\d+)$""#, i),
+                )
+                .unwrap();
+        }
+        // not required, always evaluated
+        router
+            .add_matcher(
+                100,
+                Uuid::from_u128(100),
+                r#"http.path ~ "^/none$" || http.host ~ "^none$""#,
+            )
+            .unwrap();
+        // two fields
+        router
+            .add_matcher(
+                101,
+                Uuid::from_u128(101),
+                r#"http.path ~ "^/users/" && any(http.host) ~ "^a\\.""#,
+            )
+            .unwrap();
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/users/42/7".to_string().into());
+        assert!(router.execute(&mut ctx));
+        let m = ctx.result.as_ref().unwrap();
+        assert_eq!(m.uuid, Uuid::from_u128(42));
+        assert_eq!(m.captures["id"], "7");
+        // 101 lacks http.host
+        assert_eq!(ctx.stats.matchers_skipped, 1);
+        assert_eq!(ctx.stats.matchers_evaluated, 2);
+        assert_eq!(ctx.stats.matchers_prefiltered, 57);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/users/42/7".to_string().into());
+        ctx.add_value("http.host", "b.com".to_string().into());
+        ctx.add_value("http.host", "a.com".to_string().into());
+        assert!(router.execute(&mut ctx));
+        assert_eq!(ctx.result.unwrap().uuid, Uuid::from_u128(101));
+
+        // the index follows removals
+        assert!(router.remove_matcher(42, Uuid::from_u128(42)));
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/users/42/7".to_string().into());
+        assert!(!router.execute(&mut ctx));
+        assert_eq!(ctx.stats.matchers_evaluated, 1);
+
+        router.disable_regex_index();
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/users/42/7".to_string().into());
+        assert!(!router.execute(&mut ctx));
+        assert_eq!(ctx.stats.matchers_evaluated, 100);
+    }
+
+    #[test]
+    fn test_interned_fields() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+
+        let mut router = Router::new(&schema);
+        router
+            .add_matcher(
+                0,
+                Uuid::default(),
+                r#"http.path ^= "/a" && http.headers.x == "y""#,
+            )
+            .unwrap();
+        let Expression::Logical(l) = &router.matchers.values().next().unwrap().expr else {
+            unreachable!();
+        };
+        let LogicalExpression::And(Expression::Predicate(l), Expression::Predicate(r)) = l.as_ref()
+        else {
+            unreachable!();
+        };
+        assert_eq!(l.lhs.var_index, schema.field_id("http.path"));
+        assert_eq!(r.lhs.var_index, None);
+
+        // the same fields declared in another order get other ids
+        let mut other = Schema::default();
+        other.add_field("http.headers.*", Type::String);
+        other.add_field("http.path", Type::String);
+        for schema in [&schema, &other] {
+            let mut ctx = Context::new(schema);
+            ctx.add_value_str("http.path", "/a/b");
+            ctx.add_value_str("http.headers.x", "y");
+            assert!(router.execute(&mut ctx));
+        }
+    }
+
+    #[test]
+    fn test_predicate_memo() {
+        use crate::corpus::{Corpus, Rng, Shape};
+
+        let schema = Corpus::schema();
+        let mut corpus = Corpus::new(3, Shape::default());
+        // few distinct predicates, so most of them are shared
+        let atcs = corpus.expressions(300);
+
+        for engine in [
+            Engine::Cir,
+            Engine::Lir,
+            Engine::Ast,
+            Engine::Closure,
+            Engine::Dag,
+        ] {
+            let mut router = Router::builder(&schema).engine(engine).build();
+            for (i, atc) in atcs.iter().enumerate() {
+                router
+                    .add_matcher(i % 5, Uuid::from_u128(i as u128), atc)
+                    .unwrap();
+            }
+
+            let mut rng = Rng::new(4);
+            let mut memoized = 0;
+            for _ in 0..200 {
+                let mut ctx = Context::arbitrary_for(&schema, &mut rng);
+                corpus.fill_context(&mut ctx);
+
+                let matches = router.execute_all(&mut ctx);
+                memoized += ctx.stats.predicates_memoized;
+
+                // every matcher evaluated on its own, outside of an execution
+                let expected: Vec<_> = router
+                    .matchers
+                    .iter()
+                    .rev()
+                    .filter_map(|(key, m)| {
+                        let mut mat = Match::new();
+                        m.execute(&router.dag, &mut ctx, &mut mat)
+                            .unwrap()
+                            .then(|| {
+                                mat.uuid = key.2;
+                                mat
+                            })
+                    })
+                    .collect();
+                let summary = |ms: &[Match]| -> Vec<_> {
+                    ms.iter()
+                        .map(|m| {
+                            (
+                                m.uuid,
+                                m.matches.clone(),
+                                m.captures.clone(),
+                                m.evidence.clone(),
+                            )
+                        })
+                        .collect()
+                };
+                assert_eq!(summary(&matches), summary(&expected), "{:?}", engine);
+            }
+            assert!(memoized > 0, "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_predicate_memo_invalidation() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::new(&schema);
+        let atc = r#"http.path ~ "^/(?<id>\\d+)$""#;
+        router.add_matcher(1, Uuid::from_u128(1), atc).unwrap();
+        router
+            .add_matcher(0, Uuid::from_u128(2), &format!(r#"{} && true"#, atc))
+            .unwrap();
+        assert_eq!(router.memo_slots.slots.len(), 1);
+
+        // the second matcher replays the outcome of the first one
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/42");
+        let matches = router.execute_all(&mut ctx);
+        assert_eq!(matches.len(), 2);
+        assert_eq!(ctx.stats.predicates_memoized, 1);
+        for m in &matches {
+            assert_eq!(m.captures["id"], "42");
+            assert_eq!(m.evidence.len(), 1);
+        }
+
+        // but not in another capture mode
+        router.set_capture_mode(0, Uuid::from_u128(2), Some(CaptureMode::None));
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/42");
+        let matches = router.execute_all(&mut ctx);
+        assert_eq!(ctx.stats.predicates_memoized, 0);
+        assert_eq!(matches[0].captures["id"], "42");
+        assert!(matches[1].captures.is_empty());
+
+        // nor once the values change
+        ctx.reset();
+        ctx.add_value_str("http.path", "/x");
+        assert!(!router.execute(&mut ctx));
+
+        // slots are shared until no matcher uses them anymore
+        assert!(router.remove_matcher(1, Uuid::from_u128(1)));
+        assert_eq!(router.memo_slots.slots.len(), 1);
+        assert!(router.remove_matcher(0, Uuid::from_u128(2)));
+        assert!(router.memo_slots.slots.is_empty());
+        assert_eq!(router.memo_slots.free, vec![0]);
+        router
+            .add_matcher(0, Uuid::from_u128(3), r#"http.path == "/""#)
+            .unwrap();
+        assert!(router.memo_slots.free.is_empty());
+    }
+
+    #[test]
+    fn test_map_field() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers", Type::Map);
+
+        for engine in [
+            Engine::Cir,
+            Engine::Lir,
+            Engine::Ast,
+            Engine::Closure,
+            Engine::Dag,
+        ] {
+            let mut router = Router::builder(&schema).engine(engine).build();
+            router.enable_regex_index();
+            for (priority, atc) in [
+                r#"http.headers["x-request-id"] == "abc""#,
+                r#"any(lower(http.headers["x-env"])) ~ "^(?<env>prod|staging)$""#,
+                // not compacted into a set, the keys differ
+                r#"http.headers["a"] == "1" || http.headers["b"] == "2""#,
+                r#"http.path == "/""#,
+            ]
+            .iter()
+            .rev()
+            .enumerate()
+            {
+                router
+                    .add_matcher(priority, Uuid::from_u128(priority as u128), atc)
+                    .unwrap();
+            }
+
+            let mut ctx = Context::new(&schema);
+            ctx.add_value_str("http.path", "/");
+            assert!(router.execute(&mut ctx));
+            assert_eq!(ctx.result.as_ref().unwrap().uuid, Uuid::from_u128(0));
+
+            ctx.reset();
+            ctx.add_map_value("http.headers", "b", "2");
+            ctx.add_map_value("http.headers", "x-env", "dev");
+            ctx.add_map_value("http.headers", "x-env", "Prod");
+            assert!(router.execute(&mut ctx));
+            let m = ctx.result.as_ref().unwrap();
+            assert_eq!(m.uuid, Uuid::from_u128(2), "{:?}", engine);
+            assert_eq!(m.captures["env"], "prod");
+            assert_eq!(
+                m.evidence[0].field, r#"http.headers["x-env"]"#,
+                "{:?}",
+                engine
+            );
+
+            ctx.reset();
+            ctx.add_map_value("http.headers", "x-request-id", "abc");
+            ctx.add_map_value("http.headers", "x-env", "prod");
+            assert!(router.execute(&mut ctx));
+            let m = ctx.result.as_ref().unwrap();
+            assert_eq!(m.uuid, Uuid::from_u128(3));
+            assert_eq!(
+                m.matches[r#"http.headers["x-request-id"]"#],
+                Value::String("abc".into())
+            );
+
+            // every value of the entry must match without `any()`
+            ctx.reset();
+            ctx.add_map_value("http.headers", "x-request-id", "abc");
+            ctx.add_map_value("http.headers", "x-request-id", "def");
+            ctx.add_map_value("http.headers", "a", "2");
+            assert!(!router.execute(&mut ctx));
+        }
+
+        let router = {
+            let mut router = Router::new(&schema);
+            router
+                .add_matcher(0, Uuid::default(), r#"http.headers["a"] == "1""#)
+                .unwrap();
+            router
+        };
+        assert_eq!(
+            router.check_remove_field("http.headers"),
+            [(0, Uuid::default())]
+        );
+    }
+
+    #[test]
+    fn test_regex_engine() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let mut router = Router::builder(&schema)
+            .regex_engine(Arc::new(Insensitive))
+            .build();
+        router.enable_regex_index();
+        router
+            .add_matcher(
+                1,
+                Uuid::from_u128(1),
+                r#"http.path ~ "^/users/(?<id>\\d+)$""#,
+            )
+            .unwrap();
+        // parsed beforehand, keeps the default engine
+        router
+            .add_matcher_expr(
+                0,
+                Uuid::from_u128(2),
+                parse(r#"http.path ~ "^/a$""#).unwrap(),
+            )
+            .unwrap();
+        assert!(matches!(
+            router.add_matcher(2, Uuid::from_u128(3), r#"http.path ~ "(""#),
+            Err(RouterError::ParseError(_))
+        ));
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/USERS/7".to_string().into());
+        assert!(router.execute(&mut ctx));
+        let m = ctx.result.as_ref().unwrap();
+        assert_eq!(m.uuid, Uuid::from_u128(1));
+        assert_eq!(m.captures["id"], "7");
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value("http.path", "/A".to_string().into());
+        assert!(!router.execute(&mut ctx));
+    }
+
+    #[test]
+    fn test_predicate_memo_regex_engines() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+
+        let atc = r#"http.path ~ "^/a$""#;
+        let mut router = Router::new(&schema);
+        router.add_matcher(1, Uuid::from_u128(1), atc).unwrap();
+        // printed the same, but matching ignoring case
+        router
+            .add_matcher_expr(
+                0,
+                Uuid::from_u128(2),
+                parse_with_engine(atc, &Insensitive).unwrap(),
+            )
+            .unwrap();
+        assert_eq!(router.memo_slots.slots.len(), 2);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/A");
+        let matches = router.execute_all(&mut ctx);
+        assert_eq!(ctx.stats.predicates_memoized, 0);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].uuid, Uuid::from_u128(2));
+
+        // regexes of the same engine still share their slot
+        router
+            .add_matcher_expr(2, Uuid::from_u128(3), parse(atc).unwrap())
+            .unwrap();
+        assert_eq!(router.memo_slots.slots.len(), 2);
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_str("http.path", "/a");
+        assert_eq!(router.execute_all(&mut ctx).len(), 3);
+        assert_eq!(ctx.stats.predicates_memoized, 1);
+
+        assert!(router.remove_matcher(0, Uuid::from_u128(2)));
+        assert_eq!(router.memo_slots.slots.len(), 1);
+    }
+
+    /// Case insensitive regexes, which a `RegexSet` of the patterns would
+    /// disagree with.
+    struct Insensitive;
+    struct InsensitiveRegex(regex::Regex);
+
+    impl RegexEngine for Insensitive {
+        fn compile(&self, pattern: &str) -> Result<Box<dyn CompiledRegex>, String> {
+            regex::Regex::new(&format!("(?i){}", pattern))
+                .map(|re| Box::new(InsensitiveRegex(re)) as Box<dyn CompiledRegex>)
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    impl CompiledRegex for InsensitiveRegex {
+        fn find(&self, haystack: &str) -> Option<Range<usize>> {
+            CompiledRegex::find(&self.0, haystack)
+        }
+
+        fn captures(&self, haystack: &str) -> Option<Vec<Option<Range<usize>>>> {
+            CompiledRegex::captures(&self.0, haystack)
+        }
+
+        fn capture_names(&self) -> Vec<Option<String>> {
+            CompiledRegex::capture_names(&self.0)
+        }
     }
 }
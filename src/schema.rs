@@ -1,20 +1,388 @@
 use crate::ast::Type;
+use crate::error::Error;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Arc;
 
-#[derive(Default)]
+/// Controls how the `lower()` transformation function lower-cases values.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum LowerPolicy {
+    /// Full Unicode lower-casing, as done by [`str::to_lowercase`].
+    #[default]
+    Unicode,
+    /// Only ASCII letters `A-Z` are lower-cased, every other character is kept
+    /// as is. This matches the behavior of Lua's `string.lower`.
+    Ascii,
+    /// Full Unicode case folding, which also maps `ß` to `ss` and final
+    /// sigmas to `σ`. `lower()` then makes comparisons case-insensitive: the
+    /// string literals compared with a `lower()`'d field are folded as well,
+    /// and its regexes and globs match ignoring case.
+    Fold,
+}
+
+impl LowerPolicy {
+    /// Every policy, in tag order.
+    pub const ALL: &'static [LowerPolicy] =
+        &[LowerPolicy::Unicode, LowerPolicy::Ascii, LowerPolicy::Fold];
+
+    /// The stable numeric tag of this policy, as used by the FFI.
+    pub fn tag(self) -> u32 {
+        self as u32
+    }
+
+    /// The policy with the given [`LowerPolicy::tag`], `None` for tags
+    /// unknown to this version of the library.
+    pub fn from_tag(tag: u32) -> Option<LowerPolicy> {
+        LowerPolicy::ALL.get(tag as usize).copied()
+    }
+}
+
+/// The fields expressions may reference and their types.
+///
+/// A [`Router`](crate::router::Router) borrows or shares its schema, see
+/// [`SchemaRef`], so fields can only be removed or retyped once every
+/// router using it is gone. Check the change against those routers first
+/// with
+/// [`Router::check_remove_field`](crate::router::Router::check_remove_field)
+/// and [`Router::check_replace_field_type`](crate::router::Router::check_replace_field_type).
+#[derive(Default, Clone)]
 pub struct Schema {
-    fields: HashMap<String, Type>,
+    /// Type and [id](Schema::field_id) of every declared field.
+    fields: HashMap<String, (Type, usize)>,
+    /// Name of every id handed out. Ids are not reused, a removed field
+    /// leaves its name here.
+    names: Vec<String>,
+    /// The types of wildcard fields again, keyed by prefix (without `.*`)
+    /// so [`Schema::type_of`] does not have to build their name.
+    wildcards: HashMap<String, Type>,
+    lower_policy: LowerPolicy,
+    method_field: Option<String>,
+}
+
+// Routers and contexts on every thread borrow the same schema.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Schema>();
+};
+
+/// A serializable copy of a [`Schema`], see [`Schema::to_document`].
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchemaDocument {
+    /// Wildcard fields by their `prefix.*` name.
+    pub fields: BTreeMap<String, Type>,
+    #[serde(default)]
+    pub lower_policy: LowerPolicy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method_field: Option<String>,
 }
 
 impl Schema {
     pub fn type_of(&self, field: &str) -> Option<&Type> {
-        self.fields.get(field).or_else(|| {
-            self.fields
-                .get(&format!("{}.*", &field[..field.rfind('.')?]))
-        })
+        self.fields
+            .get(field)
+            .map(|(typ, _)| typ)
+            .or_else(|| self.wildcards.get(&field[..field.rfind('.')?]))
     }
 
+    /// Declares `field` with the type `typ`, or changes its type if it is
+    /// already declared.
+    ///
+    /// # Panics
+    ///
+    /// If `typ` is not a [field type](Type::is_field_type), see
+    /// [`Schema::try_add_field`].
     pub fn add_field(&mut self, field: &str, typ: Type) {
-        self.fields.insert(field.to_string(), typ);
+        if let Err(e) = self.try_add_field(field, typ) {
+            panic!("{}", e);
+        }
+    }
+
+    /// Like [`Schema::add_field`], failing with
+    /// [`Error::InvalidArgument`] instead of panicking if `typ` is not a
+    /// [field type](Type::is_field_type).
+    pub fn try_add_field(&mut self, field: &str, typ: Type) -> Result<(), Error> {
+        check_field_type(typ)?;
+
+        if let Some(prefix) = field.strip_suffix(".*") {
+            self.wildcards.insert(prefix.to_string(), typ);
+        }
+        match self.fields.get_mut(field) {
+            Some((old, _)) => *old = typ,
+            None => {
+                self.fields
+                    .insert(field.to_string(), (typ, self.names.len()));
+                self.names.push(field.to_string());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The dense id of the declared `field`, so values can be kept in a
+    /// `Vec` instead of being looked up by name, see
+    /// [`Context::value_of_id`](crate::context::Context::value_of_id). Ids
+    /// are below [`Schema::field_id_bound`] and stay the same as long as
+    /// the field is declared.
+    ///
+    /// Names matched by a wildcard field, such as `http.headers.host`, have
+    /// no id of their own.
+    pub fn field_id(&self, field: &str) -> Option<usize> {
+        self.fields.get(field).map(|(_, id)| *id)
+    }
+
+    /// The name of the field with the id `id`, if it is still declared.
+    pub fn field_name(&self, id: usize) -> Option<&str> {
+        let name = self.names.get(id)?;
+        self.fields.contains_key(name).then_some(name.as_str())
+    }
+
+    /// Every id returned by [`Schema::field_id`] is below this.
+    pub fn field_id_bound(&self) -> usize {
+        self.names.len()
+    }
+
+    /// Removes `field`, returning its type if it was declared. Wildcard
+    /// fields are removed by their `prefix.*` name.
+    pub fn remove_field(&mut self, field: &str) -> Option<Type> {
+        if let Some(prefix) = field.strip_suffix(".*") {
+            self.wildcards.remove(prefix);
+        }
+        self.fields.remove(field).map(|(typ, _)| typ)
+    }
+
+    /// Changes the type of the declared `field` to `typ`, returning its
+    /// previous type. Undeclared fields are left undeclared and `None` is
+    /// returned.
+    ///
+    /// # Panics
+    ///
+    /// If `typ` is not a [field type](Type::is_field_type).
+    pub fn replace_field_type(&mut self, field: &str, typ: Type) -> Option<Type> {
+        if let Err(e) = check_field_type(typ) {
+            panic!("{}", e);
+        }
+        if let Some(prefix) = field.strip_suffix(".*") {
+            if let Some(old) = self.wildcards.get_mut(prefix) {
+                *old = typ;
+            }
+        }
+        self.fields
+            .get_mut(field)
+            .map(|(old, _)| std::mem::replace(old, typ))
+    }
+
+    /// Every declared field and its type, in no particular order. Wildcard
+    /// fields are listed by their `prefix.*` name.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &Type)> {
+        self.fields.iter().map(|(k, (v, _))| (k.as_str(), v))
+    }
+
+    /// Number of declared fields.
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Returns the fields and settings of the schema, fields sorted by
+    /// name, see [`Schema::from_document`].
+    #[cfg(feature = "serde")]
+    pub fn to_document(&self) -> SchemaDocument {
+        SchemaDocument {
+            fields: self
+                .fields()
+                .map(|(name, typ)| (name.to_string(), *typ))
+                .collect(),
+            lower_policy: self.lower_policy,
+            method_field: self.method_field.clone(),
+        }
+    }
+
+    /// Builds the schema `doc` describes, the inverse of
+    /// [`Schema::to_document`]. Fails if a field does not have a
+    /// [field type](Type::is_field_type).
+    #[cfg(feature = "serde")]
+    pub fn from_document(doc: SchemaDocument) -> Result<Schema, Error> {
+        let mut schema = Schema::default();
+        for (name, typ) in &doc.fields {
+            schema.try_add_field(name, *typ)?;
+        }
+        schema.lower_policy = doc.lower_policy;
+        schema.method_field = doc.method_field;
+        Ok(schema)
+    }
+
+    pub fn lower_policy(&self) -> LowerPolicy {
+        self.lower_policy
+    }
+
+    /// Sets the policy used by `lower()` for every router and context
+    /// created from this schema.
+    pub fn set_lower_policy(&mut self, policy: LowerPolicy) {
+        self.lower_policy = policy;
+    }
+
+    /// The field holding the HTTP method of requests, if designated.
+    pub fn method_field(&self) -> Option<&str> {
+        self.method_field.as_deref()
+    }
+
+    /// Designates the `String` field holding the HTTP method of requests,
+    /// such as `http.method`, so routers created from this schema match it
+    /// with bitmasks, see [`method`](crate::method).
+    pub fn set_method_field(&mut self, field: &str) {
+        self.method_field = Some(field.to_string());
+    }
+}
+
+/// The schema of a [`Router`](crate::router::Router), either borrowed for
+/// the lifetime of the router or shared with it.
+///
+/// Sharing suits hosts that can not tie the router to a borrow, such as
+/// bindings handing routers to a garbage collected language.
+#[derive(Clone)]
+pub enum SchemaRef<'a> {
+    Borrowed(&'a Schema),
+    Shared(Arc<Schema>),
+}
+
+impl Deref for SchemaRef<'_> {
+    type Target = Schema;
+
+    fn deref(&self) -> &Schema {
+        match self {
+            SchemaRef::Borrowed(schema) => schema,
+            SchemaRef::Shared(schema) => schema,
+        }
+    }
+}
+
+impl<'a> From<&'a Schema> for SchemaRef<'a> {
+    fn from(schema: &'a Schema) -> Self {
+        SchemaRef::Borrowed(schema)
+    }
+}
+
+impl From<Arc<Schema>> for SchemaRef<'_> {
+    fn from(schema: Arc<Schema>) -> Self {
+        SchemaRef::Shared(schema)
+    }
+}
+
+fn check_field_type(typ: Type) -> Result<(), Error> {
+    if typ.is_field_type() {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument(format!(
+            "{:?} is the type of literals only, not of fields",
+            typ
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_types() {
+        let mut schema = Schema::default();
+        for typ in [Type::List, Type::IntRange, Type::CidrList] {
+            assert_eq!(
+                schema.try_add_field("a", typ).unwrap_err().to_string(),
+                format!("{:?} is the type of literals only, not of fields", typ)
+            );
+        }
+        assert!(schema.is_empty());
+        schema.try_add_field("a", Type::IpCidr).unwrap();
+        assert_eq!(schema.type_of("a"), Some(&Type::IpCidr));
+    }
+
+    #[test]
+    #[should_panic(expected = "CidrList is the type of literals only")]
+    fn test_add_literal_type() {
+        Schema::default().add_field("a", Type::CidrList);
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut schema = Schema::default();
+        assert!(schema.is_empty());
+
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.port", Type::Int);
+        schema.add_field("net.port", Type::Int);
+        assert_eq!(schema.len(), 3);
+
+        let mut fields: Vec<_> = schema.fields().collect();
+        fields.sort_unstable_by_key(|(name, _)| *name);
+        assert_eq!(
+            fields,
+            [
+                ("http.headers.*", &Type::String),
+                ("http.path", &Type::String),
+                ("net.port", &Type::Int),
+            ]
+        );
+
+        schema.remove_field("http.headers.*");
+        assert_eq!(schema.len(), 2);
+    }
+
+    #[test]
+    fn test_field_ids() {
+        let mut schema = Schema::default();
+        schema.add_field("http.path", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("http.path", Type::String);
+        assert_eq!(schema.field_id("http.path"), Some(0));
+        assert_eq!(schema.field_id("http.headers.*"), Some(1));
+        assert_eq!(schema.field_id("http.headers.host"), None);
+        assert_eq!(schema.field_name(1), Some("http.headers.*"));
+        assert_eq!(schema.field_id_bound(), 2);
+
+        // ids are not reused, re-adding a field gives it a new one
+        schema.remove_field("http.path");
+        assert_eq!(schema.field_id("http.path"), None);
+        assert_eq!(schema.field_name(0), None);
+        schema.add_field("net.port", Type::Int);
+        schema.add_field("http.path", Type::String);
+        assert_eq!(schema.field_id("net.port"), Some(2));
+        assert_eq!(schema.field_id("http.path"), Some(3));
+        assert_eq!(schema.field_id_bound(), 4);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_document() {
+        let mut schema = Schema::default();
+        schema.add_field("http.method", Type::String);
+        schema.add_field("http.headers.*", Type::String);
+        schema.add_field("net.src.ip", Type::IpAddr);
+        schema.set_lower_policy(LowerPolicy::Ascii);
+        schema.set_method_field("http.method");
+
+        let json = serde_json::to_string(&schema.to_document()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"fields":{"http.headers.*":"String","http.method":"String","net.src.ip":"IpAddr"},"lower_policy":"Ascii","method_field":"http.method"}"#
+        );
+
+        let copy = Schema::from_document(serde_json::from_str(&json).unwrap()).unwrap();
+        assert_eq!(copy.to_document(), schema.to_document());
+        assert_eq!(copy.type_of("http.headers.x"), Some(&Type::String));
+
+        let minimal: SchemaDocument = serde_json::from_str(r#"{"fields":{}}"#).unwrap();
+        assert_eq!(minimal, SchemaDocument::default());
     }
 }
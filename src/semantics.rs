@@ -1,65 +1,516 @@
-use crate::ast::{BinaryOperator, Expression, LogicalExpression, Type, Value};
+use crate::ast::{
+    BinaryOperator, Expression, Lhs, LhsTransformations, LogicalExpression, TransformArg, Type,
+};
+use crate::error::ValidationError;
 use crate::schema::Schema;
+#[cfg(feature = "serde")]
+use serde::Serialize;
 use std::collections::HashMap;
+use std::ops::Index;
 
-type ValidationResult = Result<(), String>;
+type ValidationResult = Result<(), ValidationError>;
 
 pub trait Validate {
     fn validate(&self, schema: &Schema) -> ValidationResult;
 }
 
-pub trait FieldCounter {
-    fn add_to_counter(&self, map: &mut HashMap<String, usize>);
-    fn remove_from_counter(&self, map: &mut HashMap<String, usize>);
+/// Dense id a [`FieldCounter`] gives a field name, in the order fields are
+/// first counted. Ids are never reused, even once no expression references
+/// the field anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FieldId(pub(crate) usize);
+
+impl FieldId {
+    pub fn index(self) -> usize {
+        self.0
+    }
 }
 
-impl FieldCounter for Expression {
-    fn add_to_counter(&self, map: &mut HashMap<String, usize>) {
-        match self {
-            Expression::Logical(l) => match l.as_ref() {
-                LogicalExpression::And(l, r) => {
-                    l.add_to_counter(map);
-                    r.add_to_counter(map);
-                }
-                LogicalExpression::Or(l, r) => {
-                    l.add_to_counter(map);
-                    r.add_to_counter(map);
-                }
-                LogicalExpression::Not(r) => {
-                    r.add_to_counter(map);
-                }
-            },
-            Expression::Predicate(p) => {
-                *map.entry(p.lhs.var_name.clone()).or_default() += 1;
+/// How many times the counted expressions reference each field, as the
+/// [`Router::fields`](crate::router::Router::fields) of a router's matchers.
+///
+/// Names are interned into [`FieldId`]s once, counting an expression whose
+/// fields are all known allocates nothing. Fields are iterated in the order
+/// they were first counted, which does not change as expressions come and
+/// go.
+#[derive(Debug, Default, Clone)]
+pub struct FieldCounter {
+    names: Vec<String>,
+    ids: HashMap<String, FieldId>,
+    /// References of each field, indexed by id.
+    counts: Vec<usize>,
+    /// Fields with a non-zero count.
+    len: usize,
+}
+
+impl FieldCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The id of `field`, given one if it has none yet.
+    pub fn intern(&mut self, field: &str) -> FieldId {
+        if let Some(id) = self.ids.get(field) {
+            return *id;
+        }
+
+        let id = FieldId(self.names.len());
+        self.names.push(field.to_string());
+        self.ids.insert(field.to_string(), id);
+        self.counts.push(0);
+        id
+    }
+
+    pub fn id(&self, field: &str) -> Option<FieldId> {
+        self.ids.get(field).copied()
+    }
+
+    pub fn name(&self, id: FieldId) -> &str {
+        &self.names[id.0]
+    }
+
+    /// How many times `field` is referenced, `0` if it is not.
+    pub fn get(&self, field: &str) -> usize {
+        self.id(field).map_or(0, |id| self.counts[id.0])
+    }
+
+    /// Counts the fields `expr` references.
+    pub fn add(&mut self, expr: &Expression) {
+        for_each_field(expr, &mut |field| {
+            let id = self.intern(field);
+            self.counts[id.0] += 1;
+            if self.counts[id.0] == 1 {
+                self.len += 1;
             }
+        });
+    }
+
+    /// Stops counting the fields of `expr`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `expr` was not [added](FieldCounter::add).
+    pub fn remove(&mut self, expr: &Expression) {
+        for_each_field(expr, &mut |field| {
+            let id = self.id(field).expect("field is counted");
+            self.counts[id.0] -= 1;
+            if self.counts[id.0] == 0 {
+                self.len -= 1;
+            }
+        });
+    }
+
+    /// Number of referenced fields.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Referenced fields and their counts, in the order they were first
+    /// counted.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.ids().map(|id| (self.name(id), self.counts[id.0]))
+    }
+
+    /// Ids of the referenced fields, in increasing order.
+    pub fn ids(&self) -> impl Iterator<Item = FieldId> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| **n != 0)
+            .map(|(id, _)| FieldId(id))
+    }
+
+    /// Every id handed out so far is below this.
+    pub fn id_bound(&self) -> usize {
+        self.names.len()
+    }
+}
+
+/// Counters are equal when they count the same fields the same number of
+/// times, whatever ids they gave them.
+impl PartialEq for FieldCounter {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().all(|(f, n)| other.get(f) == n)
+    }
+}
+
+impl Eq for FieldCounter {}
+
+impl Index<&str> for FieldCounter {
+    type Output = usize;
+
+    /// # Panics
+    ///
+    /// Panics if `field` was never counted.
+    fn index(&self, field: &str) -> &usize {
+        &self.counts[self.id(field).expect("field is counted").0]
+    }
+}
+
+/// Calls `f` with the field of every predicate and both fields of every
+/// field comparison of `expr`.
+fn for_each_field(expr: &Expression, f: &mut impl FnMut(&str)) {
+    match expr {
+        Expression::Logical(l) => match l.as_ref() {
+            LogicalExpression::And(l, r) | LogicalExpression::Or(l, r) => {
+                for_each_field(l, f);
+                for_each_field(r, f);
+            }
+            LogicalExpression::Not(r) => for_each_field(r, f),
+        },
+        Expression::Predicate(p) => f(&p.lhs.var_name),
+        Expression::FieldComparison(c) => {
+            f(&c.lhs.var_name);
+            f(&c.rhs.var_name);
         }
+        Expression::Bool(_) => {}
     }
+}
+
+pub trait RequiredFields {
+    /// Returns the sorted set of fields that must have at least one value in
+    /// the context for the expression to possibly evaluate to `true`.
+    ///
+    /// A predicate whose field is missing never matches, so `And` requires
+    /// the fields of both sides, `Or` only the fields common to both sides,
+    /// and `Not` requires nothing since a missing field makes it `true`.
+    fn required_fields(&self) -> Vec<String>;
+}
 
-    fn remove_from_counter(&self, map: &mut HashMap<String, usize>) {
+impl RequiredFields for Expression {
+    fn required_fields(&self) -> Vec<String> {
         match self {
             Expression::Logical(l) => match l.as_ref() {
                 LogicalExpression::And(l, r) => {
-                    l.remove_from_counter(map);
-                    r.remove_from_counter(map);
+                    let mut fields = l.required_fields();
+                    fields.extend(r.required_fields());
+                    fields.sort_unstable();
+                    fields.dedup();
+                    fields
                 }
                 LogicalExpression::Or(l, r) => {
-                    l.remove_from_counter(map);
-                    r.remove_from_counter(map);
-                }
-                LogicalExpression::Not(r) => {
-                    r.remove_from_counter(map);
+                    let r = r.required_fields();
+                    l.required_fields()
+                        .into_iter()
+                        .filter(|f| r.binary_search(f).is_ok())
+                        .collect()
                 }
+                LogicalExpression::Not(_) => Vec::new(),
             },
-            Expression::Predicate(p) => {
-                let val = map.get_mut(&p.lhs.var_name).unwrap();
-                *val -= 1;
+            Expression::Predicate(p) => vec![p.lhs.var_name.clone()],
+            Expression::FieldComparison(c) => {
+                let mut fields = vec![c.lhs.var_name.clone(), c.rhs.var_name.clone()];
+                fields.sort_unstable();
+                fields.dedup();
+                fields
+            }
+            Expression::Bool(_) => Vec::new(),
+        }
+    }
+}
 
-                if *val == 0 {
-                    assert!(map.remove(&p.lhs.var_name).is_some());
-                }
+/// An allowed combination of operand types and operator, see
+/// [`PREDICATE_RULES`] and [`FIELD_COMPARISON_RULES`].
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OperatorRule {
+    /// Type of the field on the left hand side.
+    pub lhs: Type,
+    pub op: BinaryOperator,
+    /// Type of the literal, or of the field for field comparisons, on the
+    /// right hand side.
+    pub rhs: Type,
+    /// Whether `lower()`, and the other functions transforming strings
+    /// such as `trim()`, may be applied to the fields. `any()` and `all()`
+    /// are allowed everywhere.
+    pub lower: bool,
+}
+
+const fn rule(lhs: Type, op: BinaryOperator, rhs: Type, lower: bool) -> OperatorRule {
+    OperatorRule {
+        lhs,
+        op,
+        rhs,
+        lower,
+    }
+}
+
+/// Every `field <op> literal` combination [`Validate`] accepts. This is the
+/// single source of truth for the predicate semantics.
+pub const PREDICATE_RULES: &[OperatorRule] = {
+    use BinaryOperator::*;
+    use Type::{CidrList, Float, Int, IntRange, IpAddr, IpCidr, List, Regex as Re, String as Str};
+
+    &[
+        rule(Str, Equals, Str, true),
+        rule(Str, NotEquals, Str, true),
+        rule(Str, Regex, Re, true),
+        rule(Str, Prefix, Str, true),
+        rule(Str, Postfix, Str, true),
+        rule(Str, Contains, Str, true),
+        rule(Str, Glob, Re, true),
+        rule(Str, In, List, true),
+        rule(Str, NotIn, List, true),
+        rule(IpCidr, Equals, IpCidr, false),
+        rule(IpCidr, NotEquals, IpCidr, false),
+        rule(IpCidr, Contains, IpAddr, false),
+        rule(IpAddr, Equals, IpAddr, false),
+        rule(IpAddr, NotEquals, IpAddr, false),
+        rule(IpAddr, Greater, IpAddr, false),
+        rule(IpAddr, GreaterOrEqual, IpAddr, false),
+        rule(IpAddr, Less, IpAddr, false),
+        rule(IpAddr, LessOrEqual, IpAddr, false),
+        rule(IpAddr, In, IpCidr, false),
+        rule(IpAddr, NotIn, IpCidr, false),
+        rule(IpAddr, In, CidrList, false),
+        rule(IpAddr, NotIn, CidrList, false),
+        rule(Int, Equals, Int, false),
+        rule(Int, NotEquals, Int, false),
+        rule(Int, Greater, Int, false),
+        rule(Int, GreaterOrEqual, Int, false),
+        rule(Int, Less, Int, false),
+        rule(Int, LessOrEqual, Int, false),
+        rule(Int, In, IntRange, false),
+        rule(Int, NotIn, IntRange, false),
+        rule(Float, Equals, Float, false),
+        rule(Float, NotEquals, Float, false),
+        rule(Float, Greater, Float, false),
+        rule(Float, GreaterOrEqual, Float, false),
+        rule(Float, Less, Float, false),
+        rule(Float, LessOrEqual, Float, false),
+    ]
+};
+
+/// Every `field <op> field` combination [`Validate`] accepts, both fields
+/// always have the same type. Regexes can not be compared for equality, so
+/// `Regex` fields can not be compared at all.
+pub const FIELD_COMPARISON_RULES: &[OperatorRule] = {
+    use BinaryOperator::*;
+    use Type::{Float, Int, IpAddr, IpCidr, String as Str};
+
+    &[
+        rule(Str, Equals, Str, true),
+        rule(Str, NotEquals, Str, true),
+        rule(Str, Prefix, Str, true),
+        rule(Str, Postfix, Str, true),
+        rule(Str, Contains, Str, true),
+        rule(IpCidr, Equals, IpCidr, false),
+        rule(IpCidr, NotEquals, IpCidr, false),
+        rule(IpAddr, Equals, IpAddr, false),
+        rule(IpAddr, NotEquals, IpAddr, false),
+        rule(IpAddr, Greater, IpAddr, false),
+        rule(IpAddr, GreaterOrEqual, IpAddr, false),
+        rule(IpAddr, Less, IpAddr, false),
+        rule(IpAddr, LessOrEqual, IpAddr, false),
+        rule(Int, Equals, Int, false),
+        rule(Int, NotEquals, Int, false),
+        rule(Int, Greater, Int, false),
+        rule(Int, GreaterOrEqual, Int, false),
+        rule(Int, Less, Int, false),
+        rule(Int, LessOrEqual, Int, false),
+        rule(Float, Equals, Float, false),
+        rule(Float, NotEquals, Float, false),
+        rule(Float, Greater, Float, false),
+        rule(Float, GreaterOrEqual, Float, false),
+        rule(Float, Less, Float, false),
+        rule(Float, LessOrEqual, Float, false),
+    ]
+};
+
+fn find_rule(
+    rules: &'static [OperatorRule],
+    lhs: Type,
+    op: BinaryOperator,
+    rhs: Type,
+) -> Option<&'static OperatorRule> {
+    rules
+        .iter()
+        .find(|r| r.lhs == lhs && r.op == op && r.rhs == rhs)
+}
+
+/// Returns the rule allowing `lhs_field <op> rhs_literal`, if any.
+pub fn predicate_rule(lhs: Type, op: BinaryOperator, rhs: Type) -> Option<&'static OperatorRule> {
+    find_rule(PREDICATE_RULES, lhs, op, rhs)
+}
+
+/// Returns the rule allowing `field <op> field` on two fields of type `typ`,
+/// if any.
+pub fn field_comparison_rule(typ: Type, op: BinaryOperator) -> Option<&'static OperatorRule> {
+    find_rule(FIELD_COMPARISON_RULES, typ, op, typ)
+}
+
+/// Whether some rule allows `lower()` on fields of type `typ`.
+fn lower_allowed(rules: &[OperatorRule], typ: Type) -> bool {
+    rules.iter().any(|r| r.lhs == typ && r.lower)
+}
+
+const LOWER_ERROR: &str =
+    "lower-case transformation function only supported with String type fields";
+
+/// The error for transformations applied where the rules do not allow them,
+/// naming the first one.
+fn transformation_error(lhs: &Lhs) -> String {
+    match lhs.transformations.iter().find(|t| !t.is_quantifier()) {
+        Some(LhsTransformations::Lower) | None => LOWER_ERROR.to_string(),
+        Some(t) => format!(
+            "{}() transformation function only supported with String type fields",
+            t
+        ),
+    }
+}
+
+const ORDERING_PREFIX: &str = "Greater/GreaterOrEqual/Lesser/LesserOrEqual operators only support";
+
+const ORDERING_OPS: &[BinaryOperator] = &[
+    BinaryOperator::Greater,
+    BinaryOperator::GreaterOrEqual,
+    BinaryOperator::Less,
+    BinaryOperator::LessOrEqual,
+];
+
+/// The error for operators `ops` on operand types no rule allows, listing
+/// the operand types `rules` allow them on after `prefix`, e.g.
+/// `In/NotIn operators only support String in List and Int in IntRange`.
+fn operands_error(rules: &[OperatorRule], prefix: &str, ops: &[BinaryOperator]) -> String {
+    let mut pairs = Vec::new();
+    for r in rules.iter().filter(|r| ops.contains(&r.op)) {
+        if !pairs.contains(&(r.lhs, r.rhs)) {
+            pairs.push((r.lhs, r.rhs));
+        }
+    }
+
+    // `Int` rather than `Int > Int` when both sides always have one type
+    let same = pairs.iter().all(|(l, r)| l == r);
+    let mut operands: Vec<_> = pairs
+        .iter()
+        .map(|(l, r)| match same {
+            true => format!("{:?}", l),
+            false => format!("{:?} {} {:?}", l, ops[0], r),
+        })
+        .collect();
+    let last = operands.pop().unwrap_or_default();
+    let operands = match operands.is_empty() {
+        true => last,
+        false => format!("{} and {}", operands.join(", "), last),
+    };
+
+    match same {
+        true => format!("{} {} operands", prefix, operands),
+        false => format!("{} {}", prefix, operands),
+    }
+}
+
+/// A transformation function taking arguments after the field, see
+/// [`LhsTransformations::Custom`].
+pub struct TransformFunction {
+    pub name: &'static str,
+    /// The types of the arguments, the trailing ones past `required` may be
+    /// left out.
+    pub args: &'static [Type],
+    pub required: usize,
+    /// Checks the values of arguments already known to have the right types.
+    check: fn(&[TransformArg]) -> Result<(), String>,
+}
+
+/// Every transformation function taking arguments. All of them only apply to
+/// `String` fields, like `lower()`.
+pub const TRANSFORM_FUNCTIONS: &[TransformFunction] = &[
+    TransformFunction {
+        name: "substr",
+        args: &[Type::Int, Type::Int],
+        required: 1,
+        check: |args| {
+            if args
+                .iter()
+                .any(|a| matches!(a, TransformArg::Int(i) if *i < 0))
+            {
+                return Err("substr() arguments can not be negative".to_string());
+            }
+            Ok(())
+        },
+    },
+    TransformFunction {
+        name: "header_decode",
+        args: &[Type::String],
+        required: 1,
+        check: |args| match &args[0] {
+            TransformArg::String(s) if s == "base64" || s == "percent" => Ok(()),
+            _ => {
+                Err("header_decode() encoding must be one of \"base64\" or \"percent\"".to_string())
             }
+        },
+    },
+];
+
+/// Checks that `lhs` looks a key up if, and only if, its field is a
+/// [`Type::Map`].
+fn check_key(lhs: &Lhs, schema: &Schema) -> Result<(), String> {
+    match (schema.type_of(&lhs.var_name), &lhs.key) {
+        (Some(Type::Map), None) => {
+            Err("Map fields can only be compared by key, as in field[\"key\"]".to_string())
+        }
+        (Some(typ), Some(_)) if *typ != Type::Map => {
+            Err("Only Map fields can be looked up by key".to_string())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks that `lhs` has at most one of `any()` and `all()`, and the
+/// arguments given to its transformations.
+fn check_transformations(lhs: &Lhs) -> Result<(), String> {
+    if lhs
+        .transformations
+        .iter()
+        .filter(|t| t.is_quantifier())
+        .count()
+        > 1
+    {
+        return Err("any() and all() can only be applied once to a field".to_string());
+    }
+
+    for t in &lhs.transformations {
+        let LhsTransformations::Custom(name, args) = t else {
+            continue;
+        };
+        // the parser only produces `Custom` for builtins when given arguments
+        let Some(f) = TRANSFORM_FUNCTIONS.iter().find(|f| f.name == name) else {
+            return Err(format!("{}() takes no arguments after the field", name));
+        };
+
+        if args.len() < f.required || args.len() > f.args.len() {
+            return Err(if f.required == f.args.len() {
+                format!(
+                    "{}() takes {} argument(s) after the field",
+                    name, f.required
+                )
+            } else {
+                format!(
+                    "{}() takes {} to {} arguments after the field",
+                    name,
+                    f.required,
+                    f.args.len()
+                )
+            });
+        }
+
+        if let Some(i) = args.iter().zip(f.args).position(|(a, t)| a.my_type() != *t) {
+            return Err(format!(
+                "argument {} of {}() must be of type {:?}",
+                i + 1,
+                name,
+                f.args[i]
+            ));
         }
+
+        (f.check)(args)?;
     }
+
+    Ok(())
 }
 
 impl Validate for Expression {
@@ -82,77 +533,145 @@ impl Validate for Expression {
 
                 Ok(())
             }
-            Expression::Predicate(p) => {
-                // lhs and rhs must be the same type
-                let lhs_type = p.lhs.my_type(schema);
-                if lhs_type.is_none() {
-                    return Err("Unknown LHS field".to_string());
+            Expression::FieldComparison(c) => {
+                let fail = |field: &str, message: &str| ValidationError {
+                    message: message.to_string(),
+                    field: Some(field.to_string()),
+                    predicate: Some(self.to_string()),
+                };
+                let lhs = &c.lhs.var_name;
+
+                let lhs_type = *c
+                    .lhs
+                    .my_type(schema)
+                    .ok_or_else(|| fail(lhs, "Unknown LHS field"))?;
+                let rhs_type = *c
+                    .rhs
+                    .my_type(schema)
+                    .ok_or_else(|| fail(&c.rhs.var_name, "Unknown RHS field"))?;
+
+                for l in [&c.lhs, &c.rhs] {
+                    check_key(l, schema).map_err(|e| fail(&l.var_name, &e))?;
+                    check_transformations(l).map_err(|e| fail(&l.var_name, &e))?;
+                }
+
+                if lhs_type != rhs_type {
+                    return Err(fail(
+                        lhs,
+                        "Type mismatch between the LHS and RHS fields of comparison",
+                    ));
                 }
-                let lhs_type = lhs_type.unwrap();
 
-                if p.op != BinaryOperator::Regex // Regex RHS is always Regex, and LHS is always String
+                let rule = field_comparison_rule(lhs_type, c.op);
+                if let Some(t) = [&c.lhs, &c.rhs].into_iter().find(|l| l.is_transformed()) {
+                    if !lower_allowed(FIELD_COMPARISON_RULES, lhs_type)
+                        || rule.is_some_and(|r| !r.lower)
+                    {
+                        return Err(fail(lhs, &transformation_error(t)));
+                    }
+                }
+
+                match rule {
+                    Some(_) => Ok(()),
+                    None => Err(fail(
+                        lhs,
+                        &match c.op {
+                            BinaryOperator::Equals | BinaryOperator::NotEquals => {
+                                "Equals/NotEquals operators can not compare Regex fields"
+                                    .to_string()
+                            }
+                            BinaryOperator::Prefix
+                            | BinaryOperator::Postfix
+                            | BinaryOperator::Contains => {
+                                "Prefix/Postfix/Contains operators only supports string operands"
+                                    .to_string()
+                            }
+                            BinaryOperator::Greater
+                            | BinaryOperator::GreaterOrEqual
+                            | BinaryOperator::Less
+                            | BinaryOperator::LessOrEqual => operands_error(
+                                FIELD_COMPARISON_RULES,
+                                ORDERING_PREFIX,
+                                ORDERING_OPS,
+                            ),
+                            BinaryOperator::Regex | BinaryOperator::In | BinaryOperator::NotIn => {
+                                "Regex/In/NotIn operators can not compare two fields".to_string()
+                            }
+                            BinaryOperator::Glob => {
+                                "Glob operator can not compare two fields".to_string()
+                            }
+                        },
+                    )),
+                }
+            }
+            Expression::Bool(_) => Ok(()),
+            Expression::Predicate(p) => {
+                let fail = |message: &str| ValidationError {
+                    message: message.to_string(),
+                    field: Some(p.lhs.var_name.clone()),
+                    predicate: Some(self.to_string()),
+                };
+
+                let lhs_type = *p
+                    .lhs
+                    .my_type(schema)
+                    .ok_or_else(|| fail("Unknown LHS field"))?;
+                let rhs_type = p.rhs.my_type();
+
+                check_key(&p.lhs, schema).map_err(|e| fail(&e))?;
+                check_transformations(&p.lhs).map_err(|e| fail(&e))?;
+
+                if p.op != BinaryOperator::Regex // Regex RHS is always Regex
+                    && p.op != BinaryOperator::Glob // and so is Glob RHS
                     && p.op != BinaryOperator::In // In/NotIn supports IPAddr in IpCidr
                     && p.op != BinaryOperator::NotIn
-                    && lhs_type != &p.rhs.my_type()
+                    && p.op != BinaryOperator::Contains // and IpCidr contains IpAddr
+                    && lhs_type != rhs_type
                 {
-                    return Err(
-                        "Type mismatch between the LHS and RHS values of predicate".to_string()
-                    );
+                    return Err(fail(
+                        "Type mismatch between the LHS and RHS values of predicate",
+                    ));
                 }
 
-                let (lower, _any) = p.lhs.get_transformations();
-
-                // LHS transformations only makes sense with string fields
-                if lower && lhs_type != &Type::String {
-                    return Err(
-                        "lower-case transformation function only supported with String type fields"
-                            .to_string(),
-                    );
+                let transformed = p.lhs.is_transformed();
+                if transformed && !lower_allowed(PREDICATE_RULES, lhs_type) {
+                    return Err(fail(&transformation_error(&p.lhs)));
                 }
 
-                match p.op {
-                    BinaryOperator::Equals | BinaryOperator::NotEquals => { Ok(()) }
-                    BinaryOperator::Regex => {
-                        // unchecked path above
-                        if lhs_type == &Type::String {
-                            Ok(())
-                        } else {
-                            Err("Regex operators only supports string operands".to_string())
+                match predicate_rule(lhs_type, p.op, rhs_type) {
+                    Some(r) if transformed && !r.lower => Err(fail(&transformation_error(&p.lhs))),
+                    Some(_) => Ok(()),
+                    None => Err(fail(&match p.op {
+                        BinaryOperator::Equals | BinaryOperator::NotEquals => {
+                            "Type mismatch between the LHS and RHS values of predicate".to_string()
                         }
-                    },
-                    BinaryOperator::Prefix | BinaryOperator::Postfix => {
-                        match p.rhs {
-                            Value::String(_) => {
-                                Ok(())
-                            }
-                            _ => Err("Regex/Prefix/Postfix operators only supports string operands".to_string())
+                        BinaryOperator::Regex => {
+                            "Regex operators only supports string operands".to_string()
                         }
-                    },
-                    BinaryOperator::Greater | BinaryOperator::GreaterOrEqual | BinaryOperator::Less | BinaryOperator::LessOrEqual => {
-                        match p.rhs {
-                            Value::Int(_) => {
-                                Ok(())
-                            }
-                            _ => Err("Greater/GreaterOrEqual/Lesser/LesserOrEqual operators only supports integer operands".to_string())
+                        BinaryOperator::Prefix | BinaryOperator::Postfix => {
+                            "Regex/Prefix/Postfix operators only supports string operands"
+                                .to_string()
                         }
-                    },
-                    BinaryOperator::In | BinaryOperator::NotIn => {
-                        // unchecked path above
-                        match (lhs_type, &p.rhs,) {
-                            (Type::IpAddr, Value::IpCidr(_)) => {
-                                Ok(())
-                            }
-                            _ => Err("In/NotIn operators only supports IP in CIDR".to_string())
+                        BinaryOperator::Greater
+                        | BinaryOperator::GreaterOrEqual
+                        | BinaryOperator::Less
+                        | BinaryOperator::LessOrEqual => {
+                            operands_error(PREDICATE_RULES, ORDERING_PREFIX, ORDERING_OPS)
                         }
-                    },
-                    BinaryOperator::Contains => {
-                        match p.rhs {
-                            Value::String(_) => {
-                                Ok(())
-                            }
-                            _ => Err("Contains operator only supports string operands".to_string())
+                        BinaryOperator::In | BinaryOperator::NotIn => operands_error(
+                            PREDICATE_RULES,
+                            "In/NotIn operators only support",
+                            &[BinaryOperator::In, BinaryOperator::NotIn],
+                        ),
+                        BinaryOperator::Contains => operands_error(
+                            PREDICATE_RULES,
+                            "Contains operator only supports",
+                            &[BinaryOperator::Contains],
+                        ),
+                        BinaryOperator::Glob => {
+                            "Glob operator only supports string operands".to_string()
                         }
-                    }
+                    })),
                 }
             }
         }
@@ -162,6 +681,9 @@ impl Validate for Expression {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ast::{FieldComparison, Lhs, LhsTransformations, Predicate, Value};
+    use crate::context::{Context, Match};
+    use crate::interpreter::Execute;
     use crate::parser::parse;
     use lazy_static::lazy_static;
 
@@ -171,19 +693,245 @@ mod tests {
             s.add_field("string", Type::String);
             s.add_field("int", Type::Int);
             s.add_field("ipaddr", Type::IpAddr);
+            s.add_field("float", Type::Float);
+            s.add_field("string2", Type::String);
+            s.add_field("int2", Type::Int);
+            s.add_field("regex", Type::Regex);
+            s.add_field("regex2", Type::Regex);
             s
         };
     }
 
+    fn sample(typ: Type) -> Value {
+        match typ {
+            Type::String => Value::String("a".into()),
+            Type::IpCidr => Value::IpCidr("10.0.0.0/8".parse().unwrap()),
+            Type::IpAddr => Value::IpAddr("10.0.0.1".parse().unwrap()),
+            Type::Int => Value::Int(1),
+            Type::Regex => Value::Regex(crate::regex_engine::Regex::new("a").unwrap()),
+            Type::Float => Value::Float(1.0),
+            Type::List => Value::List(vec!["a".to_string()]),
+            Type::IntRange => Value::IntRange(0, 2),
+            Type::CidrList => Value::CidrList(vec!["10.0.0.0/8".parse().unwrap()].into()),
+            // there are no map values, only map entries, see `map_keys`
+            Type::Map => unreachable!(),
+        }
+    }
+
+    fn lhs(typ: Type, lower: bool) -> Lhs {
+        let mut transformations = vec![LhsTransformations::Any];
+        if lower {
+            transformations.push(LhsTransformations::Lower);
+        }
+
+        Lhs {
+            var_name: format!("f{}", typ.tag()),
+            transformations,
+            var_index: None,
+            key: None,
+        }
+    }
+
     #[test]
-    fn unknown_field() {
-        let expression = parse(r#"unkn == "abc""#).unwrap();
+    fn test_field_counter() {
+        let a = parse(r#"string == "a" && (int == 1 || string ~ "b")"#).unwrap();
+        let b = parse("int == 2 && string_too == string").unwrap();
+
+        let mut counter = FieldCounter::new();
+        counter.add(&a);
+        counter.add(&b);
+        assert_eq!(
+            counter.iter().collect::<Vec<_>>(),
+            [("string", 3), ("int", 2), ("string_too", 1)]
+        );
+        assert_eq!(counter.len(), 3);
+
+        // ids and the order stay, even for fields no longer counted
+        counter.remove(&a);
+        assert_eq!(
+            counter.iter().collect::<Vec<_>>(),
+            [("string", 1), ("int", 1), ("string_too", 1)]
+        );
+        counter.remove(&b);
+        assert!(counter.is_empty());
+        assert_eq!(counter.get("int"), 0);
+        counter.add(&b);
+        assert_eq!(
+            counter.iter().collect::<Vec<_>>(),
+            [("string", 1), ("int", 1), ("string_too", 1)]
+        );
+        assert_eq!(counter.id("int").map(FieldId::index), Some(1));
+        assert_eq!(counter.id_bound(), 3);
+
+        // equal whatever ids the fields got
+        let mut other = FieldCounter::new();
+        other.add(&b);
+        other.intern("unused");
+        assert_eq!(other.iter().next(), Some(("int", 1)));
+        assert_eq!(other, counter);
+        assert_eq!(other["string"], 1);
+        other.remove(&b);
+        assert_ne!(other, counter);
+    }
+
+    /// Every combination of operand types, operator and `lower()` validates
+    /// exactly when the rules allow it, and every allowed combination can be
+    /// evaluated.
+    #[test]
+    fn operator_matrix_conformance() {
+        let types: Vec<_> = Type::ALL.iter().filter(|t| **t != Type::Map).collect();
+        let field_types: Vec<_> = types
+            .iter()
+            .copied()
+            .filter(|t| t.is_field_type())
+            .collect();
+        let mut schema = Schema::default();
+        for typ in &field_types {
+            schema.add_field(&format!("f{}", typ.tag()), **typ);
+        }
+        let mut ctx = Context::new(&schema);
+        for typ in &field_types {
+            ctx.add_value(&format!("f{}", typ.tag()), sample(**typ));
+        }
+
+        let mut allowed = 0;
+        for lhs_type in &field_types {
+            for op in BinaryOperator::ALL {
+                for lower in [false, true] {
+                    for rhs_type in &types {
+                        let p = Expression::Predicate(Predicate {
+                            lhs: lhs(**lhs_type, lower),
+                            rhs: sample(**rhs_type),
+                            op: *op,
+                            memo: None,
+                        });
+                        let rule = predicate_rule(**lhs_type, *op, **rhs_type);
+                        let expected = rule.is_some_and(|r| r.lower || !lower);
+                        assert_eq!(p.validate(&schema).is_ok(), expected, "{}", p);
+                        if expected {
+                            allowed += 1;
+                            p.execute(&mut ctx, &mut Match::new());
+                        }
+                    }
+
+                    let c = Expression::FieldComparison(FieldComparison {
+                        lhs: lhs(**lhs_type, lower),
+                        rhs: lhs(**lhs_type, false),
+                        op: *op,
+                    });
+                    let rule = field_comparison_rule(**lhs_type, *op);
+                    let expected = rule.is_some_and(|r| r.lower || !lower);
+                    assert_eq!(c.validate(&schema).is_ok(), expected, "{}", c);
+                    if expected {
+                        allowed += 1;
+                        c.execute(&mut ctx, &mut Match::new());
+                    }
+                }
+            }
+        }
+
+        let lower_rules = PREDICATE_RULES
+            .iter()
+            .chain(FIELD_COMPARISON_RULES)
+            .filter(|r| r.lower)
+            .count();
         assert_eq!(
-            expression.validate(&SCHEMA).unwrap_err(),
-            "Unknown LHS field"
+            allowed,
+            PREDICATE_RULES.len() + FIELD_COMPARISON_RULES.len() + lower_rules
         );
     }
 
+    #[test]
+    fn required_fields() {
+        let tests = vec![
+            (r#"a == 1"#, vec!["a"]),
+            (r#"a == 1 && b == 1 && a == 2"#, vec!["a", "b"]),
+            (r#"a == 1 || b == 1"#, vec![]),
+            (r#"(a == 1 && b == 1) || (c == 1 && b == 2)"#, vec!["b"]),
+            (r#"a == 1 && !(b == 1)"#, vec!["a"]),
+            (r#"!(a == 1 && b == 1)"#, vec![]),
+        ];
+        for (input, expected) in tests {
+            let expression = parse(input).unwrap();
+            assert_eq!(expression.required_fields(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn map_keys() {
+        let mut schema = SCHEMA.clone();
+        schema.add_field("http.headers", Type::Map);
+
+        for atc in [
+            r#"http.headers["x-id"] == "abc""#,
+            r#"any(lower(http.headers["x-id"])) ~ "^a""#,
+            r#"http.headers["x-id"] in ("a", "b")"#,
+            r#"http.headers["a"] == http.headers["b"]"#,
+            r#"http.headers["a"] == string"#,
+        ] {
+            assert!(parse(atc).unwrap().validate(&schema).is_ok(), "{}", atc);
+        }
+
+        for (atc, message) in [
+            (
+                r#"http.headers == "abc""#,
+                r#"Map fields can only be compared by key, as in field["key"]"#,
+            ),
+            (
+                r#"http.headers == http.headers"#,
+                r#"Map fields can only be compared by key, as in field["key"]"#,
+            ),
+            (
+                r#"string["a"] == "abc""#,
+                "Only Map fields can be looked up by key",
+            ),
+            (
+                r#"http.headers["a"] == 1"#,
+                "Type mismatch between the LHS and RHS values of predicate",
+            ),
+            (
+                r#"http.headers["a"] == int"#,
+                "Type mismatch between the LHS and RHS fields of comparison",
+            ),
+        ] {
+            let err = parse(atc).unwrap().validate(&schema).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", atc);
+        }
+    }
+
+    #[test]
+    fn operand_errors() {
+        let in_error = "In/NotIn operators only support String in List, IpAddr in IpCidr, \
+                        IpAddr in CidrList and Int in IntRange";
+        for (atc, message) in [
+            ("int in 10.0.0.0/8", in_error),
+            (r#"string not in 1..2"#, in_error),
+            (r#"float in 1..2"#, in_error),
+            (
+                r#"string >= "a""#,
+                "Greater/GreaterOrEqual/Lesser/LesserOrEqual operators only support IpAddr, \
+                 Int and Float operands",
+            ),
+            (
+                "ipaddr contains 10.0.0.1",
+                "Contains operator only supports String contains String and IpCidr contains \
+                 IpAddr",
+            ),
+        ] {
+            let err = parse(atc).unwrap().validate(&SCHEMA).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", atc);
+        }
+    }
+
+    #[test]
+    fn unknown_field() {
+        let expression = parse(r#"unkn == "abc""#).unwrap();
+        let err = expression.validate(&SCHEMA).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown LHS field");
+        assert_eq!(err.field.as_deref(), Some("unkn"));
+        assert_eq!(err.predicate.as_deref(), Some(r#"(unkn == "abc")"#));
+    }
+
     #[test]
     fn string_lhs() {
         let tests = vec![
@@ -193,17 +941,35 @@ mod tests {
             r#"string ^= "abc""#,
             r#"string =^ "abc""#,
             r#"lower(string) =^ "abc""#,
+            r#"string in ("abc", "def")"#,
+            r#"lower(string) not in ("abc")"#,
+            r#"trim(string) == "abc""#,
+            r#"upper(lower(string)) ^= "ABC""#,
+            r#"any(path_normalize(string)) ~ "^/abc""#,
         ];
         for input in tests {
             let expression = parse(input).unwrap();
             expression.validate(&SCHEMA).unwrap();
         }
 
+        let err = parse("path_normalize(int) == 1")
+            .unwrap()
+            .validate(&SCHEMA)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "path_normalize() transformation function only supported with String type fields"
+        );
+
         let failing_tests = vec![
             r#"string == 192.168.0.1"#,
             r#"string == 192.168.0.0/24"#,
             r#"string == 123"#,
             r#"string in "abc""#,
+            r#"string == ("abc")"#,
+            r#"string ^= ("abc")"#,
+            r#"int in ("abc")"#,
+            r#"ipaddr not in ("abc")"#,
         ];
         for input in failing_tests {
             let expression = parse(input).unwrap();
@@ -265,4 +1031,193 @@ mod tests {
             assert!(expression.validate(&SCHEMA).is_err());
         }
     }
+
+    #[test]
+    fn float_lhs() {
+        let tests = vec![
+            r#"float == 12.5"#,
+            r#"float != -0.5"#,
+            r#"float >= 12.5"#,
+            r#"float <= 1e3"#,
+            r#"float > 1.5E-3"#,
+            r#"float < 0.0"#,
+        ];
+        for input in tests {
+            let expression = parse(input).unwrap();
+            expression.validate(&SCHEMA).unwrap();
+        }
+
+        let failing_tests = vec![
+            r#"float == 12"#,
+            r#"float == "abc""#,
+            r#"float ^= 1.5"#,
+            r#"float in 192.168.0.0/24"#,
+            r#"int > 1.5"#,
+            r#"lower(float) == 1.5"#,
+        ];
+        for input in failing_tests {
+            let expression = parse(input).unwrap();
+            assert!(expression.validate(&SCHEMA).is_err());
+        }
+
+        assert!(parse("float > 1e400").is_err());
+    }
+
+    #[test]
+    fn transformation_arguments() {
+        let tests = vec![
+            r#"substr(string, 1) == "bc""#,
+            r#"substr(lower(string), 0, 4) ^= "/api""#,
+            r#"header_decode(string, "base64") == "abc""#,
+            r##"any(header_decode(string, r#"percent"#)) contains "a b""##,
+            r#"substr(string, 0, 2) == header_decode(string2, "base64")"#,
+            r#"url_decode(string) ^= "/a b""#,
+            r#"any(lower(base64_decode(string))) ~ "^user:""#,
+        ];
+        for input in tests {
+            let expression = parse(input).unwrap();
+            expression.validate(&SCHEMA).unwrap();
+        }
+
+        let failing_tests = vec![
+            (
+                r#"substr(string) == "a""#,
+                "substr() takes 1 to 2 arguments after the field",
+            ),
+            (
+                r#"substr(string, 1, 2, 3) == "a""#,
+                "substr() takes 1 to 2 arguments after the field",
+            ),
+            (
+                r#"substr(string, "1") == "a""#,
+                "argument 1 of substr() must be of type Int",
+            ),
+            (
+                r#"substr(string, 0, -1) == "a""#,
+                "substr() arguments can not be negative",
+            ),
+            (
+                r#"header_decode(string) == "a""#,
+                "header_decode() takes 1 argument(s) after the field",
+            ),
+            (
+                r#"header_decode(string, "hex") == "a""#,
+                "header_decode() encoding must be one of \"base64\" or \"percent\"",
+            ),
+            (
+                r#"lower(string, 1) == "a""#,
+                "lower() takes no arguments after the field",
+            ),
+            (
+                r#"url_decode(string, "percent") == "a""#,
+                "url_decode() takes no arguments after the field",
+            ),
+            (
+                r#"base64_decode(int) == 1"#,
+                "base64_decode() transformation function only supported with String type fields",
+            ),
+            (
+                r#"substr(int, 1) == 1"#,
+                "substr() transformation function only supported with String type fields",
+            ),
+            (
+                r#"string == substr(string2, 1, "2")"#,
+                "argument 2 of substr() must be of type Int",
+            ),
+        ];
+        for (input, error) in failing_tests {
+            let expression = parse(input).unwrap();
+            assert_eq!(
+                expression.validate(&SCHEMA).unwrap_err().to_string(),
+                error,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn quantifiers() {
+        let tests = vec![
+            r#"all(string) == "abc""#,
+            r#"any(lower(string)) ^= "abc""#,
+            r#"lower(all(string)) ^= "abc""#,
+            r#"all(int) in 1..3"#,
+            r#"all(string) != any(string2)"#,
+        ];
+        for input in tests {
+            let expression = parse(input).unwrap();
+            expression.validate(&SCHEMA).unwrap();
+        }
+
+        let failing_tests = vec![
+            r#"any(any(string)) == "abc""#,
+            r#"all(lower(any(string))) == "abc""#,
+            r#"all(all(int)) == 1"#,
+            r#"string == any(all(string2))"#,
+        ];
+        for input in failing_tests {
+            let expression = parse(input).unwrap();
+            assert_eq!(
+                expression.validate(&SCHEMA).unwrap_err().to_string(),
+                "any() and all() can only be applied once to a field",
+                "{}",
+                input
+            );
+        }
+
+        let err = parse("lower(all(int)) == 1")
+            .unwrap()
+            .validate(&SCHEMA)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "lower-case transformation function only supported with String type fields"
+        );
+    }
+
+    #[test]
+    fn field_comparison() {
+        let tests = vec![
+            r#"string == string2"#,
+            r#"lower(string) != any(string2)"#,
+            r#"path_normalize(string) == upper(string2)"#,
+            r#"string ^= string2"#,
+            r#"string contains string2"#,
+            r#"int < int2"#,
+            r#"ipaddr == ipaddr"#,
+        ];
+        for input in tests {
+            let expression = parse(input).unwrap();
+            expression.validate(&SCHEMA).unwrap();
+        }
+
+        let failing_tests = vec![
+            (r#"string == int"#, "Type mismatch between the LHS and RHS fields of comparison"),
+            (r#"string == unkn"#, "Unknown RHS field"),
+            (r#"unkn == string"#, "Unknown LHS field"),
+            (r#"int ^= int2"#, "Prefix/Postfix/Contains operators only supports string operands"),
+            (r#"string > string2"#, "Greater/GreaterOrEqual/Lesser/LesserOrEqual operators only support IpAddr, Int and Float operands"),
+            (r#"string ~ string2"#, "Regex/In/NotIn operators can not compare two fields"),
+            (r#"regex == regex2"#, "Equals/NotEquals operators can not compare Regex fields"),
+            (r#"lower(int) == int2"#, "lower-case transformation function only supported with String type fields"),
+            (r#"int == trim(int2)"#, "trim() transformation function only supported with String type fields"),
+        ];
+        for (input, error) in failing_tests {
+            let expression = parse(input).unwrap();
+            assert_eq!(
+                expression.validate(&SCHEMA).unwrap_err().to_string(),
+                error,
+                "{}",
+                input
+            );
+        }
+
+        assert_eq!(
+            parse("string == string2 && int > 1")
+                .unwrap()
+                .required_fields(),
+            ["int", "string", "string2"]
+        );
+    }
 }
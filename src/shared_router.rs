@@ -0,0 +1,140 @@
+//! A router that can be replaced while other threads execute it.
+//!
+//! Updating a [`Router`] in place, one matcher at a time, lets executions
+//! running in between see a routing table that is neither the old nor the
+//! new one. Instead, build the new router on the side and
+//! [`replace`](SharedRouter::replace) the current one with it: every
+//! execution uses either the old or the new router, as a whole.
+//!
+//! ```
+//! use atc_router::ast::Type;
+//! use atc_router::context::Context;
+//! use atc_router::router::Router;
+//! use atc_router::schema::Schema;
+//! use atc_router::shared_router::SharedRouter;
+//! use uuid::Uuid;
+//!
+//! let mut schema = Schema::default();
+//! schema.add_field("http.path", Type::String);
+//!
+//! let shared = SharedRouter::new(Router::new(&schema));
+//!
+//! let mut next = Router::new(&schema);
+//! next.add_matcher(1, Uuid::from_u128(1), r#"http.path ^= "/""#).unwrap();
+//! shared.replace(next);
+//!
+//! let mut ctx = Context::new(&schema);
+//! ctx.add_value_str("http.path", "/foo");
+//! assert!(shared.execute(&mut ctx));
+//! ```
+
+use crate::context::Context;
+use crate::router::Router;
+use arc_swap::ArcSwap;
+use std::sync::Arc;
+
+/// Holds the current [`Router`], see the
+/// [module documentation](crate::shared_router).
+pub struct SharedRouter<'a> {
+    /// Loaded and swapped without taking a lock, so executions never wait
+    /// for each other or for a replacement.
+    current: ArcSwap<Router<'a>>,
+}
+
+impl<'a> SharedRouter<'a> {
+    pub fn new(router: Router<'a>) -> Self {
+        SharedRouter {
+            current: ArcSwap::from_pointee(router),
+        }
+    }
+
+    /// Returns the current router.
+    ///
+    /// It stays alive, unchanged, for as long as the returned `Arc` does,
+    /// even once it was replaced.
+    pub fn load(&self) -> Arc<Router<'a>> {
+        self.current.load_full()
+    }
+
+    /// Makes `router` the current router and returns the previous one.
+    ///
+    /// Executions already running finish with the previous router, the
+    /// next ones use `router`. The previous router is dropped once the
+    /// returned `Arc` and every one [`SharedRouter::load`] returned are.
+    pub fn replace(&self, router: Router<'a>) -> Arc<Router<'a>> {
+        self.current.swap(Arc::new(router))
+    }
+
+    /// Executes the current router, see [`Router::execute`].
+    pub fn execute(&self, context: &mut Context) -> bool {
+        self.current.load().execute(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Type;
+    use crate::schema::Schema;
+    use std::thread;
+    use uuid::Uuid;
+
+    fn router_with(schema: &Schema, n: usize) -> Router<'_> {
+        let mut router = Router::new(schema);
+        for i in 0..n {
+            let atc = format!("net.port == {}", i);
+            router
+                .add_matcher(i, Uuid::from_u128(i as u128), &atc)
+                .unwrap();
+        }
+        router
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut schema = Schema::default();
+        schema.add_field("net.port", Type::Int);
+
+        let shared = SharedRouter::new(router_with(&schema, 1));
+        let old = shared.load();
+
+        let previous = shared.replace(router_with(&schema, 2));
+        assert!(Arc::ptr_eq(&old, &previous));
+        assert_eq!(old.matchers().count(), 1);
+        assert_eq!(shared.load().matchers().count(), 2);
+
+        let mut ctx = Context::new(&schema);
+        ctx.add_value_int("net.port", 1);
+        assert!(shared.execute(&mut ctx));
+        assert!(!old.execute(&mut ctx));
+    }
+
+    /// Executors see every matcher of one router or of the other, never a
+    /// mix of both.
+    #[test]
+    fn test_concurrent_replace() {
+        let mut schema = Schema::default();
+        schema.add_field("net.port", Type::Int);
+        let shared = SharedRouter::new(router_with(&schema, 10));
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..200 {
+                        let router = shared.load();
+                        let n = router.matchers().count();
+                        assert!(n == 10 || n == 20, "{}", n);
+
+                        let mut ctx = Context::new(&schema);
+                        ctx.add_value_int("net.port", 15);
+                        assert_eq!(router.execute(&mut ctx), n == 20);
+                    }
+                });
+            }
+
+            for i in 0..50 {
+                shared.replace(router_with(&schema, if i % 2 == 0 { 20 } else { 10 }));
+            }
+        });
+    }
+}
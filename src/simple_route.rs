@@ -0,0 +1,209 @@
+//! Recognizes expressions following the canonical Kong route pattern.
+//!
+//! Most routes only select on hosts, paths and methods, and are written as
+//! `&&` of up to three `||` groups:
+//!
+//! ```text
+//! (http.host == "a.com" || http.host == "b.com")
+//!     && (http.path ^= "/api" || http.path ~ "^/v\d+/")
+//!     && (http.method == "GET" || http.method == "HEAD")
+//! ```
+//!
+//! [`Expression::as_simple_route`] turns those into a [`SimpleRoute`], so
+//! they can be dispatched, exported or displayed without walking the AST.
+
+use crate::ast::{BinaryOperator, Expression, LogicalExpression, Predicate, Value};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// How a [`SimpleRoute`] matches `http.path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum PathMatch {
+    /// `http.path == "..."`
+    Exact(String),
+    /// `http.path ^= "..."`
+    Prefix(String),
+    /// `http.path ~ "..."`, the pattern as written.
+    Regex(String),
+}
+
+/// A route matching on hosts, paths and methods only, see
+/// [`Expression::as_simple_route`].
+///
+/// An empty list puts no constraint on its field, a non-empty one needs the
+/// request to match any of its entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SimpleRoute {
+    /// Values `http.host` must be equal to.
+    pub hosts: Vec<String>,
+    pub paths: Vec<PathMatch>,
+    /// Values `http.method` must be equal to.
+    pub methods: Vec<String>,
+}
+
+impl Expression {
+    /// Returns the expression as a [`SimpleRoute`] if it is the `&&` of at
+    /// most one `||` group of predicates per field, see the
+    /// [module documentation](crate::simple_route).
+    ///
+    /// Host and method groups may use `==` and `in`, path groups `==`, `^=`
+    /// and `~`. Returns `None` for anything else, such as other fields,
+    /// `lower()`, negations or a field constrained by two groups, as well
+    /// as for `true` and `false`.
+    pub fn as_simple_route(&self) -> Option<SimpleRoute> {
+        let mut groups = Vec::new();
+        operands(self, true, &mut groups);
+
+        let mut route = SimpleRoute::default();
+        for group in groups {
+            let mut predicates = Vec::new();
+            operands(group, false, &mut predicates);
+
+            let predicates = predicates
+                .into_iter()
+                .map(|e| match e {
+                    Expression::Predicate(p) if p.lhs.is_plain() => Some(p),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()?;
+
+            let field = predicates[0].lhs.var_name.as_str();
+            if predicates.iter().any(|p| p.lhs.var_name != field) {
+                return None;
+            }
+
+            match field {
+                "http.host" if route.hosts.is_empty() => {
+                    route.hosts = equal_values(&predicates)?;
+                }
+                "http.method" if route.methods.is_empty() => {
+                    route.methods = equal_values(&predicates)?;
+                }
+                "http.path" if route.paths.is_empty() => {
+                    route.paths = path_matches(&predicates)?;
+                }
+                _ => return None,
+            }
+        }
+
+        Some(route)
+    }
+}
+
+/// Appends the operands of the `&&` chain (`||` chain unless `and`) rooted
+/// at `e` to `out`.
+fn operands<'e>(e: &'e Expression, and: bool, out: &mut Vec<&'e Expression>) {
+    if let Expression::Logical(l) = e {
+        match l.as_ref() {
+            LogicalExpression::And(l, r) if and => {
+                operands(l, and, out);
+                operands(r, and, out);
+                return;
+            }
+            LogicalExpression::Or(l, r) if !and => {
+                operands(l, and, out);
+                operands(r, and, out);
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    out.push(e);
+}
+
+/// The values of a group of `==` and `in` predicates, `None` if the group
+/// has other predicates or no value at all.
+fn equal_values(predicates: &[&Predicate]) -> Option<Vec<String>> {
+    let mut values = Vec::new();
+
+    for p in predicates {
+        let rhs = match &p.rhs {
+            Value::Methods(m) => m.original(),
+            rhs => rhs,
+        };
+        match (p.op, rhs) {
+            (BinaryOperator::Equals, Value::String(s)) => values.push(s.to_string()),
+            (BinaryOperator::In, Value::List(l)) => values.extend(l.iter().cloned()),
+            (BinaryOperator::Equals, Value::Set(set)) => {
+                values.extend(set.values().iter().cloned())
+            }
+            _ => return None,
+        }
+    }
+
+    (!values.is_empty()).then_some(values)
+}
+
+fn path_matches(predicates: &[&Predicate]) -> Option<Vec<PathMatch>> {
+    predicates
+        .iter()
+        .map(|p| match (p.op, &p.rhs) {
+            (BinaryOperator::Equals, Value::String(s)) => Some(PathMatch::Exact(s.to_string())),
+            (BinaryOperator::Prefix, Value::String(s)) => Some(PathMatch::Prefix(s.to_string())),
+            (BinaryOperator::Regex, Value::Regex(re)) => {
+                Some(PathMatch::Regex(re.as_str().to_string()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn simple(atc: &str) -> Option<SimpleRoute> {
+        parse(atc).unwrap().as_simple_route()
+    }
+
+    #[test]
+    fn test_simple_routes() {
+        assert_eq!(
+            simple(
+                r#"(http.host == "a.com" || http.host == "b.com")
+                    && (http.path ^= "/api" || http.path ~ "^/v\\d+/" || http.path == "/")
+                    && (http.method == "GET" || http.method == "HEAD")"#
+            ),
+            Some(SimpleRoute {
+                hosts: vec!["a.com".to_string(), "b.com".to_string()],
+                paths: vec![
+                    PathMatch::Prefix("/api".to_string()),
+                    PathMatch::Regex(r"^/v\d+/".to_string()),
+                    PathMatch::Exact("/".to_string()),
+                ],
+                methods: vec!["GET".to_string(), "HEAD".to_string()],
+            })
+        );
+
+        // groups in any order, any of them may be missing
+        assert_eq!(
+            simple(r#"http.method in ("POST", "GET") && http.path ^= "/""#),
+            Some(SimpleRoute {
+                hosts: vec![],
+                paths: vec![PathMatch::Prefix("/".to_string())],
+                methods: vec!["GET".to_string(), "POST".to_string()],
+            })
+        );
+    }
+
+    #[test]
+    fn test_not_simple_routes() {
+        for atc in [
+            "true",
+            r#"http.headers.x == "a""#,
+            r#"lower(http.host) == "a.com""#,
+            r#"!(http.path ^= "/")"#,
+            r#"http.host =^ ".com""#,
+            r#"http.path ^= "/" && http.path ^= "/a""#,
+            r#"http.path ^= "/" || http.method == "GET""#,
+            r#"http.host == "a.com" && (http.path ^= "/" || http.host == "b.com")"#,
+            r#"http.method == http.host"#,
+        ] {
+            assert_eq!(simple(atc), None, "{}", atc);
+        }
+    }
+}
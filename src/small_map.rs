@@ -0,0 +1,195 @@
+//! Small maps keyed by strings, the [`Match::matches`] and
+//! [`Match::captures`] of a match.
+//!
+//! A matched request records a handful of values and usually no more than
+//! two capture groups, for which hashing and a heap allocated table cost
+//! more than they save. A [`SmallMap`] keeps its first entries inline and
+//! finds keys by comparing them one after the other. It has the parts of the
+//! `HashMap` API the router uses, and iterates in insertion order.
+//!
+//! [`Match::matches`]: crate::context::Match::matches
+//! [`Match::captures`]: crate::context::Match::captures
+
+use smallvec::SmallVec;
+use std::fmt;
+use std::ops::Index;
+
+/// Entries kept inline before spilling to the heap.
+const INLINE: usize = 2;
+
+type Entries<V> = SmallVec<[(String, V); INLINE]>;
+
+/// A map from strings to `V`, see the [module documentation](self).
+#[derive(Clone)]
+pub struct SmallMap<V> {
+    entries: Entries<V>,
+}
+
+impl<V> SmallMap<V> {
+    pub fn new() -> Self {
+        SmallMap {
+            entries: SmallVec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Sets the value of `key`, returning its previous value. The entry
+    /// keeps its place in the iteration order.
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let i = self.entries.iter().position(|(k, _)| k == key)?;
+        Some(self.entries.remove(i).1)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Entries in insertion order.
+    pub fn iter(&self) -> Iter<'_, V> {
+        let entry: fn(&(String, V)) -> (&String, &V) = |(k, v)| (k, v);
+        self.entries.iter().map(entry)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|(_, v)| v)
+    }
+
+    pub fn into_keys(self) -> impl Iterator<Item = String> {
+        self.entries.into_iter().map(|(k, _)| k)
+    }
+}
+
+impl<V> Default for SmallMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Entries of a [`SmallMap`], in insertion order.
+pub type Iter<'a, V> =
+    std::iter::Map<std::slice::Iter<'a, (String, V)>, fn(&(String, V)) -> (&String, &V)>;
+
+impl<'a, V> IntoIterator for &'a SmallMap<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<V> IntoIterator for SmallMap<V> {
+    type Item = (String, V);
+    type IntoIter = smallvec::IntoIter<[(String, V); INLINE]>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<V> Extend<(String, V)> for SmallMap<V> {
+    fn extend<I: IntoIterator<Item = (String, V)>>(&mut self, iter: I) {
+        for (k, v) in iter {
+            self.insert(k, v);
+        }
+    }
+}
+
+impl<V> FromIterator<(String, V)> for SmallMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut map = SmallMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<V> Index<&str> for SmallMap<V> {
+    type Output = V;
+
+    /// # Panics
+    ///
+    /// Panics if `key` is not in the map.
+    fn index(&self, key: &str) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+/// Maps are equal when they have the same entries, in whatever order.
+impl<V: PartialEq> PartialEq for SmallMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
+    }
+}
+
+impl<V: Eq> Eq for SmallMap<V> {}
+
+impl<V: fmt::Debug> fmt::Debug for SmallMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_map() {
+        let mut m = SmallMap::new();
+        assert!(m.is_empty());
+        assert_eq!(m.insert("b".to_string(), 1), None);
+        assert_eq!(m.insert("a".to_string(), 2), None);
+        assert_eq!(m.insert("b".to_string(), 3), Some(1));
+        assert_eq!(m.len(), 2);
+        assert_eq!(m["b"], 3);
+        assert_eq!(m.get("c"), None);
+        assert!(m.contains_key("a"));
+        // replaced entries keep their place
+        assert_eq!(m.keys().collect::<Vec<_>>(), ["b", "a"]);
+        assert_eq!(format!("{:?}", m), r#"{"b": 3, "a": 2}"#);
+
+        // past the inline entries
+        m.extend((0..4).map(|i| (i.to_string(), i)));
+        assert_eq!(m.len(), 6);
+        assert_eq!(m["3"], 3);
+        assert_eq!(m.remove("b"), Some(3));
+        assert_eq!(m.remove("b"), None);
+        assert_eq!(m.values().sum::<i32>(), 2 + 6);
+
+        let reversed: SmallMap<_> = m.clone().into_iter().rev().collect();
+        assert_eq!(reversed, m);
+        assert_ne!(reversed, SmallMap::new());
+
+        m.clear();
+        assert!(m.is_empty());
+    }
+}
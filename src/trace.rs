@@ -0,0 +1,126 @@
+//! Sampled evaluation traces, see
+//! [`Router::set_trace_sampling`](crate::router::Router::set_trace_sampling).
+
+use crate::ast::Value;
+use crate::context::Context;
+use crate::router::Priority;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// What happened to one matcher during a traced execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOutcome {
+    /// Skipped, a field it requires has no value.
+    MissingField,
+    /// Skipped, the prefilter or regex index ruled it out.
+    Prefiltered,
+    /// Skipped, the matcher is quarantined.
+    Quarantined,
+    /// Evaluated to `false`.
+    NoMatch,
+    /// Evaluation failed, see
+    /// [`Router::set_quarantine_after`](crate::router::Router::set_quarantine_after).
+    Failed,
+    /// Evaluated to `true`.
+    Matched,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceStep {
+    pub priority: Priority,
+    pub uuid: Uuid,
+    pub outcome: TraceOutcome,
+}
+
+/// How one [`Router::execute`](crate::router::Router::execute) call reached
+/// its result.
+#[derive(Debug, Clone)]
+pub struct ExecutionTrace {
+    /// The values of the context once execution finished, including the
+    /// ones its provider supplied, sorted by field.
+    pub values: Vec<(String, Vec<Value>)>,
+    /// Every matcher looked at, in evaluation order. The last one is the
+    /// match, if any.
+    pub steps: Vec<TraceStep>,
+    /// UUID of the matching matcher.
+    pub matched: Option<Uuid>,
+}
+
+impl ExecutionTrace {
+    pub(crate) fn new(context: &Context, steps: Vec<TraceStep>) -> Self {
+        let mut values: Vec<_> = context
+            .fields()
+            .map(|(f, v)| (f.to_string(), v.to_vec()))
+            .collect();
+        values.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let matched = steps
+            .last()
+            .filter(|s| s.outcome == TraceOutcome::Matched)
+            .map(|s| s.uuid);
+
+        ExecutionTrace {
+            values,
+            steps,
+            matched,
+        }
+    }
+}
+
+/// Picks the executions to trace and keeps the latest traces.
+pub(crate) struct TraceSampler {
+    rate: f64,
+    capacity: usize,
+    executions: AtomicU64,
+    traces: Mutex<VecDeque<ExecutionTrace>>,
+}
+
+impl TraceSampler {
+    pub(crate) fn new(rate: f64, capacity: usize) -> Self {
+        TraceSampler {
+            rate: rate.clamp(0.0, 1.0),
+            capacity,
+            executions: AtomicU64::new(0),
+            traces: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Whether to trace the next execution. Sampled executions are evenly
+    /// spread, every `1 / rate`th one is traced.
+    pub(crate) fn sample(&self) -> bool {
+        let n = self.executions.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.rate).floor() > (n * self.rate).floor()
+    }
+
+    pub(crate) fn record(&self, trace: ExecutionTrace) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut traces = self.traces.lock().unwrap();
+        if traces.len() == self.capacity {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+    }
+
+    pub(crate) fn drain(&self) -> Vec<ExecutionTrace> {
+        self.traces.lock().unwrap().drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rate() {
+        for (rate, expected) in [(0.0, 0), (0.25, 25), (0.1, 10), (1.0, 100), (7.0, 100)] {
+            let sampler = TraceSampler::new(rate, 1);
+            let sampled = (0..100).filter(|_| sampler.sample()).count();
+            assert_eq!(sampled, expected, "{}", rate);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+//! JavaScript bindings for validating, formatting and matching expressions
+//! in the browser, built with the **wasm** feature on `wasm32` targets only.
+
+use crate::ast::{Type, Value};
+use crate::context::{Context, Match};
+use crate::interpreter::Execute;
+use crate::lint::{lint, LintWarning};
+use crate::parser::parse;
+use crate::router::Router;
+use crate::schema::{Schema, SchemaRef};
+use crate::semantics::Validate;
+use js_sys::{Array, Object, Reflect};
+use std::sync::Arc;
+use uuid::Uuid;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const MATCH_RESULT: &str = r#"
+/** A matcher that matched, values other than strings are written as in expressions. */
+export interface MatchResult {
+    uuid: string;
+    /** The value each field matched. */
+    matches: Record<string, string>;
+    /** Regex capture groups, by index and by name. */
+    captures: Record<string, string>;
+    /** Every predicate that held, in evaluation order. */
+    evidence: { field: string; op: string; value: string }[];
+}
+
+/** A problem found in a valid expression, see `Schema.lint`. */
+export interface LintWarning {
+    /** `always_false`, `always_true`, `duplicate_predicate`, `unanchored_regex` or `simplifiable_regex`. */
+    kind: string;
+    /** The part of the expression the warning is about, in canonical form. */
+    expression: string;
+    message: string;
+}
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "MatchResult")]
+    pub type MatchResult;
+
+    #[wasm_bindgen(typescript_type = "MatchResult[]")]
+    pub type MatchResults;
+
+    #[wasm_bindgen(typescript_type = "LintWarning[]")]
+    pub type LintWarnings;
+}
+
+/// Parses `atc` and returns it in canonical form, see
+/// [`Expression::to_atc_string`](crate::ast::Expression::to_atc_string).
+#[wasm_bindgen]
+pub fn format(atc: &str) -> Result<String, JsError> {
+    parse(atc)
+        .map(|expr| expr.to_atc_string())
+        .map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// Returns the crate features of this build, see [`crate::build_info`].
+#[wasm_bindgen(js_name = buildFeatures)]
+pub fn build_features() -> Vec<String> {
+    crate::build_info()
+        .features
+        .iter()
+        .map(|f| f.to_string())
+        .collect()
+}
+
+/// A [`Schema`] expressions are validated against.
+///
+/// Routers and contexts share the schema they are created with. Fields
+/// declared afterwards are added to a copy, which only later routers and
+/// contexts see.
+#[wasm_bindgen(js_name = Schema)]
+#[derive(Default)]
+pub struct WasmSchema(Arc<Schema>);
+
+#[wasm_bindgen(js_class = Schema)]
+impl WasmSchema {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares `field` with the type of the given [`Type::tag`].
+    #[wasm_bindgen(js_name = addField)]
+    pub fn add_field(&mut self, field: &str, tag: u32) -> Result<(), JsError> {
+        let typ = Type::from_tag(tag).ok_or_else(|| JsError::new("unknown type tag"))?;
+        Arc::make_mut(&mut self.0)
+            .try_add_field(field, typ)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Parses `atc` and type checks it against the schema.
+    pub fn validate(&self, atc: &str) -> Result<(), JsError> {
+        let expr = parse(atc).map_err(|e| JsError::new(&e.to_string()))?;
+        expr.validate(&self.0)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Parses `atc`, type checks it against the schema and returns what
+    /// looks wrong with it, see [`lint`].
+    pub fn lint(&self, atc: &str) -> Result<LintWarnings, JsError> {
+        let expr = parse(atc).map_err(|e| JsError::new(&e.to_string()))?;
+        expr.validate(&self.0)
+            .map_err(|e| JsError::new(&e.to_string()))?;
+        let warnings: Array = lint(&expr, &self.0).iter().map(warning_to_js).collect();
+        Ok(warnings.unchecked_into())
+    }
+}
+
+/// A [`Router`] sharing the schema it was created with.
+#[wasm_bindgen(js_name = Router)]
+pub struct WasmRouter {
+    router: Router<'static>,
+}
+
+#[wasm_bindgen(js_class = Router)]
+impl WasmRouter {
+    #[wasm_bindgen(constructor)]
+    pub fn new(schema: &WasmSchema) -> Self {
+        WasmRouter {
+            router: Router::with_schema(SchemaRef::Shared(schema.0.clone())),
+        }
+    }
+
+    #[wasm_bindgen(js_name = addMatcher)]
+    pub fn add_matcher(&mut self, priority: usize, uuid: &str, atc: &str) -> Result<(), JsError> {
+        self.router
+            .add_matcher(priority, parse_uuid(uuid)?, atc)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Returns `false` if there is no such matcher.
+    #[wasm_bindgen(js_name = removeMatcher)]
+    pub fn remove_matcher(&mut self, priority: usize, uuid: &str) -> Result<bool, JsError> {
+        Ok(self.router.remove_matcher(priority, parse_uuid(uuid)?))
+    }
+
+    /// Matches the values of `context`, whose result is then read with
+    /// [`WasmContext::get_result`].
+    pub fn execute(&self, context: &mut WasmContext) -> Result<bool, JsError> {
+        let mut ctx = context.to_context(self.router.schema())?;
+        let matched = self.router.execute(&mut ctx);
+        context.result = ctx.result;
+        Ok(matched)
+    }
+
+    /// Every matcher that matches the values of `context`, in evaluation
+    /// order. The first one is also the result of `context`.
+    #[wasm_bindgen(js_name = executeAll)]
+    pub fn execute_all(&self, context: &mut WasmContext) -> Result<MatchResults, JsError> {
+        let mut ctx = context.to_context(self.router.schema())?;
+        let matches = self.router.execute_all(&mut ctx);
+        let results: Array = matches.iter().map(match_to_js).collect();
+        context.result = matches.into_iter().next();
+        Ok(results.unchecked_into())
+    }
+
+    /// Evaluates the matcher with this `priority` and `uuid` alone against
+    /// the values of `context`, whatever the matchers before it do.
+    /// Returns `undefined` if it does not match, and leaves the result of
+    /// `context` untouched.
+    #[wasm_bindgen(js_name = tryMatch)]
+    pub fn try_match(
+        &self,
+        context: &WasmContext,
+        priority: usize,
+        uuid: &str,
+    ) -> Result<Option<MatchResult>, JsError> {
+        let uuid = parse_uuid(uuid)?;
+        let (_, _, expr) = self
+            .router
+            .matchers()
+            .find(|(p, u, _)| *p == priority && *u == uuid)
+            .ok_or_else(|| JsError::new("no such matcher"))?;
+
+        let mut ctx = context.to_context(self.router.schema())?;
+        let mut m = Match::new();
+        if !expr.execute(&mut ctx, &mut m) {
+            return Ok(None);
+        }
+        m.uuid = uuid;
+        Ok(Some(match_to_js(&m).unchecked_into()))
+    }
+
+    /// The fields used by the matchers, sorted, so pages only ask for the
+    /// values that matter.
+    #[wasm_bindgen(js_name = getFields)]
+    pub fn get_fields(&self) -> Vec<String> {
+        let mut fields: Vec<_> = self
+            .router
+            .fields
+            .iter()
+            .map(|(f, _)| f.to_string())
+            .collect();
+        fields.sort_unstable();
+        fields
+    }
+}
+
+/// The values a [`WasmRouter`] is executed against, and the result of the
+/// last execution.
+#[wasm_bindgen(js_name = Context)]
+pub struct WasmContext {
+    schema: Arc<Schema>,
+    values: Vec<(String, Value)>,
+    result: Option<Match>,
+}
+
+#[wasm_bindgen(js_class = Context)]
+impl WasmContext {
+    #[wasm_bindgen(constructor)]
+    pub fn new(schema: &WasmSchema) -> Self {
+        WasmContext {
+            schema: schema.0.clone(),
+            values: Vec::new(),
+            result: None,
+        }
+    }
+
+    /// Adds a value of `field`: a string for `String`, `IpAddr` and
+    /// `IpCidr` fields, a number for `Int` and `Float` ones.
+    #[wasm_bindgen(js_name = addValue)]
+    pub fn add_value(&mut self, field: &str, value: JsValue) -> Result<(), JsError> {
+        let typ = self
+            .schema
+            .type_of(field)
+            .ok_or_else(|| JsError::new(&format!("unknown field {}", field)))?;
+        let mismatch = || JsError::new(&format!("{}: expected a {:?} value", field, typ));
+
+        let value = match (typ, value.as_string(), value.as_f64()) {
+            (Type::String, Some(s), _) => Value::String(s),
+            (Type::IpAddr, Some(s), _) => Value::IpAddr(s.parse().map_err(|_| mismatch())?),
+            (Type::IpCidr, Some(s), _) => Value::IpCidr(s.parse().map_err(|_| mismatch())?),
+            (Type::Int, _, Some(n)) if n.fract() == 0.0 => Value::Int(n as i64),
+            (Type::Float, _, Some(n)) => Value::Float(n),
+            _ => return Err(mismatch()),
+        };
+        self.values.push((field.to_string(), value));
+        Ok(())
+    }
+
+    /// Removes every value and the result.
+    pub fn reset(&mut self) {
+        self.values.clear();
+        self.result = None;
+    }
+
+    /// The matcher that matched on the last execution, `undefined` if none
+    /// did.
+    #[wasm_bindgen(js_name = getResult)]
+    pub fn get_result(&self) -> Option<MatchResult> {
+        self.result
+            .as_ref()
+            .map(|m| match_to_js(m).unchecked_into())
+    }
+}
+
+impl WasmContext {
+    /// A [`Context`] of `schema` holding the values added so far.
+    fn to_context<'a>(&self, schema: &'a Schema) -> Result<Context<'a>, JsError> {
+        let mut ctx = Context::new(schema);
+        for (field, value) in &self.values {
+            if schema.type_of(field) != Some(&value.my_type()) {
+                return Err(JsError::new(&format!(
+                    "{} is not declared with the same type by the router's schema",
+                    field
+                )));
+            }
+            ctx.add_value(field, value.clone());
+        }
+
+        Ok(ctx)
+    }
+}
+
+fn parse_uuid(uuid: &str) -> Result<Uuid, JsError> {
+    Uuid::try_parse(uuid).map_err(|e| JsError::new(&e.to_string()))
+}
+
+/// Strings as they are, other values as written in expressions.
+fn value_to_js(value: &Value) -> JsValue {
+    match value {
+        Value::String(s) => s.into(),
+        v => v.to_string().into(),
+    }
+}
+
+fn set(obj: &Object, key: &str, value: &JsValue) {
+    // only fails on frozen objects or throwing proxies
+    Reflect::set(obj, &key.into(), value).unwrap();
+}
+
+/// A [`Match`] as a `MatchResult`, see the TypeScript declaration above.
+fn match_to_js(m: &Match) -> JsValue {
+    let matches = Object::new();
+    for (field, value) in &m.matches {
+        set(&matches, field, &value_to_js(value));
+    }
+    let captures = Object::new();
+    for (name, value) in &m.captures {
+        set(&captures, name, &value.into());
+    }
+    let evidence: Array = m
+        .evidence
+        .iter()
+        .map(|e| {
+            let obj = Object::new();
+            set(&obj, "field", &e.field.as_str().into());
+            set(&obj, "op", &e.op.as_str().into());
+            set(&obj, "value", &value_to_js(&e.value));
+            JsValue::from(obj)
+        })
+        .collect();
+
+    let obj = Object::new();
+    set(&obj, "uuid", &m.uuid.to_string().into());
+    set(&obj, "matches", &matches);
+    set(&obj, "captures", &captures);
+    set(&obj, "evidence", &evidence);
+    obj.into()
+}
+
+/// A [`LintWarning`] as declared in TypeScript above.
+fn warning_to_js(w: &LintWarning) -> JsValue {
+    let obj = Object::new();
+    set(&obj, "kind", &w.kind.as_str().into());
+    set(&obj, "expression", &w.expression.as_str().into());
+    set(&obj, "message", &w.message.as_str().into());
+    obj.into()
+}
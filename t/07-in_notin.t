@@ -49,9 +49,9 @@ __DATA__
 --- request
 GET /t
 --- response_body
-nilIn/NotIn operators only supports IP in CIDR
-nilIn/NotIn operators only supports IP in CIDR
-nilIn/NotIn operators only supports IP in CIDR
+nilIn/NotIn operators only support String in List, IpAddr in IpCidr, IpAddr in CidrList and Int in IntRange
+nilIn/NotIn operators only support String in List, IpAddr in IpCidr, IpAddr in CidrList and Int in IntRange
+nilIn/NotIn operators only support String in List, IpAddr in IpCidr, IpAddr in CidrList and Int in IntRange
 --- no_error_log
 [error]
 [warn]
@@ -105,3 +105,52 @@ true
 [error]
 [warn]
 [crit]
+
+
+
+=== TEST 3: in operator works with String and list operands
+--- http_config eval: $::HttpConfig
+--- config
+    location = /t {
+        content_by_lua_block {
+            local schema = require("resty.router.schema")
+            local router = require("resty.router.router")
+            local context = require("resty.router.context")
+
+            local s = schema.new()
+
+            s:add_field("http.method", "String")
+
+            local r = router.new(s)
+            assert(r:add_matcher(0, "a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c",
+                                 "http.method in (\"GET\", \"HEAD\", \"OPTIONS\")"))
+
+            local c = context.new(s)
+            c:add_value("http.method", "HEAD")
+
+            local matched = r:execute(c)
+            ngx.say(matched)
+
+            c = context.new(s)
+            c:add_value("http.method", "POST")
+
+            local matched = r:execute(c)
+            ngx.say(matched)
+
+            assert(r:remove_matcher("a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c"))
+            assert(r:add_matcher(0, "a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c",
+                                 "http.method not in (\"GET\", \"HEAD\")"))
+            local matched = r:execute(c)
+            ngx.say(matched)
+        }
+    }
+--- request
+GET /t
+--- response_body
+true
+false
+true
+--- no_error_log
+[error]
+[warn]
+[crit]
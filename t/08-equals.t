@@ -162,3 +162,49 @@ a921a9aa-ec0e-4cf3-a6cc-8aa5583d150cnilnil
 [error]
 [warn]
 [crit]
+
+
+
+=== TEST 4: Equals works with borrowed String values
+--- http_config eval: $::HttpConfig
+--- config
+    location = /t {
+        content_by_lua_block {
+            local schema = require("resty.router.schema")
+            local router = require("resty.router.router")
+            local context = require("resty.router.context")
+
+            local s = schema.new()
+
+            s:add_field("http.path", "String")
+
+            local r = router.new(s)
+            assert(r:add_matcher(0, "a921a9aa-ec0e-4cf3-a6cc-1aa5583d150c",
+                                 "http.path == \"/foo\""))
+
+            local c = context.new(s)
+            -- only the context keeps the string alive
+            assert(c:add_value_ref("http.path", table.concat({ "/f", "oo" })))
+            collectgarbage()
+
+            local matched = r:execute(c)
+            ngx.say(matched)
+            ngx.say(c:get_result())
+
+            c:reset()
+            assert(c:add_value_ref("http.path", "/bar"))
+
+            matched = r:execute(c)
+            ngx.say(matched)
+        }
+    }
+--- request
+GET /t
+--- response_body
+true
+a921a9aa-ec0e-4cf3-a6cc-1aa5583d150cnilnil
+false
+--- no_error_log
+[error]
+[warn]
+[crit]